@@ -0,0 +1,233 @@
+//! Adaptive padding in the style of WTF-PAD: instead of padding all the
+//! time, watch the gaps in real traffic and fill the ones that would stand
+//! out with dummy frames.
+//!
+//! After a real frame the machine is in *burst*: it samples how long to wait
+//! for the next real frame from the burst histogram. If the wait expires
+//! first, a dummy goes out and the machine moves to *gap*, where it keeps
+//! sending dummies at intervals from the gap histogram. Any real frame
+//! returns it to burst. Sampling the infinity bin stops padding until the
+//! next real frame.
+
+use std::time::{Duration, Instant};
+
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+
+use crate::config::{AdaptivePaddingConfig, PaddingHistogramConfig};
+
+/// Dummies sent by one `poll` after the caller fell behind.
+const MAX_DUMMIES_PER_POLL: usize = 8;
+
+/// Inter-frame delays as weighted bins. Bin `i` covers
+/// `[edges[i - 1], edges[i])`, with an implicit lower edge of zero; the
+/// `infinity` weight is the chance of not padding at all.
+#[derive(Debug, Clone)]
+pub struct PaddingHistogram {
+    edges_ns: Vec<u64>,
+    weights: Vec<u32>,
+    infinity: u32,
+    total: u64,
+}
+
+impl PaddingHistogram {
+    pub fn new(edges: &[Duration], weights: &[u32], infinity: u32) -> Result<Self, &'static str> {
+        if edges.is_empty() || edges.len() != weights.len() {
+            return Err("histogram needs one weight per bin edge");
+        }
+        let edges_ns = edges
+            .iter()
+            .map(|edge| u64::try_from(edge.as_nanos()).map_err(|_| "bin edge too large"))
+            .collect::<Result<Vec<_>, _>>()?;
+        if edges_ns[0] == 0 || edges_ns.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("bin edges must be > 0 and strictly increasing");
+        }
+        let total = weights.iter().map(|&w| u64::from(w)).sum::<u64>() + u64::from(infinity);
+        if total == 0 {
+            return Err("histogram weights are all zero");
+        }
+        Ok(Self {
+            edges_ns,
+            weights: weights.to_vec(),
+            infinity,
+            total,
+        })
+    }
+
+    pub fn from_config(config: &PaddingHistogramConfig) -> Result<Self, &'static str> {
+        Self::new(&config.edges, &config.weights, config.infinity)
+    }
+
+    /// A delay, or `None` when the infinity bin is drawn.
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<Duration> {
+        let mut pick = rng.next_u64() % self.total;
+        let mut lower = 0;
+        for (&upper, &weight) in self.edges_ns.iter().zip(&self.weights) {
+            if pick < u64::from(weight) {
+                return Some(Duration::from_nanos(lower + rng.next_u64() % (upper - lower)));
+            }
+            pick -= u64::from(weight);
+            lower = upper;
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PaddingState {
+    /// Waiting for real traffic; nothing scheduled.
+    Idle,
+    /// A real frame went out; a dummy follows unless another does first.
+    Burst { deadline: Instant },
+    /// Padding a gap; dummies continue until real traffic resumes.
+    Gap { deadline: Instant },
+}
+
+pub struct AdaptivePadding<R: RngCore + CryptoRng = OsRng> {
+    burst: PaddingHistogram,
+    gap: PaddingHistogram,
+    state: PaddingState,
+    /// Dummies copy the size of the latest real frame.
+    frame_len: usize,
+    rng: R,
+}
+
+impl AdaptivePadding<OsRng> {
+    pub fn new(burst: PaddingHistogram, gap: PaddingHistogram) -> Self {
+        Self::with_rng(burst, gap, OsRng)
+    }
+
+    pub fn from_config(config: &AdaptivePaddingConfig) -> Result<Self, &'static str> {
+        Ok(Self::new(
+            PaddingHistogram::from_config(&config.burst)?,
+            PaddingHistogram::from_config(&config.gap)?,
+        ))
+    }
+}
+
+impl<R: RngCore + CryptoRng> AdaptivePadding<R> {
+    pub fn with_rng(burst: PaddingHistogram, gap: PaddingHistogram, rng: R) -> Self {
+        Self {
+            burst,
+            gap,
+            state: PaddingState::Idle,
+            frame_len: 0,
+            rng,
+        }
+    }
+
+    /// A real frame of `len` bytes left the protocol engine.
+    pub fn on_real_frame(&mut self, now: Instant, len: usize) {
+        self.frame_len = len;
+        self.state = match self.burst.sample(&mut self.rng) {
+            Some(wait) => PaddingState::Burst { deadline: now + wait },
+            None => PaddingState::Idle,
+        };
+    }
+
+    /// Dummy frames due by `now`.
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let mut due = 0;
+        loop {
+            let deadline = match self.state {
+                PaddingState::Idle => break,
+                PaddingState::Burst { deadline } | PaddingState::Gap { deadline } => deadline,
+            };
+            if deadline > now {
+                break;
+            }
+            due += 1;
+            // Missed dummies are dropped rather than sent back to back.
+            let from = if due == MAX_DUMMIES_PER_POLL { now } else { deadline };
+            self.state = match self.gap.sample(&mut self.rng) {
+                Some(wait) => PaddingState::Gap { deadline: from + wait },
+                None => PaddingState::Idle,
+            };
+            if due == MAX_DUMMIES_PER_POLL {
+                break;
+            }
+        }
+        due
+    }
+
+    /// Payload length for the next dummy frame.
+    pub fn dummy_len(&self) -> usize {
+        self.frame_len
+    }
+
+    /// True while dummies are being sent to fill a gap.
+    pub fn in_gap(&self) -> bool {
+        matches!(self.state, PaddingState::Gap { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn padding(burst_infinity: u32, gap_infinity: u32) -> AdaptivePadding<StdRng> {
+        AdaptivePadding::with_rng(
+            PaddingHistogram::new(&[ms(10)], &[1], burst_infinity).unwrap(),
+            PaddingHistogram::new(&[ms(5)], &[1], gap_infinity).unwrap(),
+            StdRng::seed_from_u64(0x3A9),
+        )
+    }
+
+    #[test]
+    fn no_dummies_without_real_traffic() {
+        let mut padding = padding(0, 0);
+        assert_eq!(padding.poll(Instant::now() + ms(1_000)), 0);
+    }
+
+    #[test]
+    fn gap_after_burst_is_filled() {
+        let start = Instant::now();
+        let mut padding = padding(0, 0);
+        padding.on_real_frame(start, 300);
+        // Burst wait is under 10 ms, every gap wait under 5 ms.
+        assert!(padding.poll(start + ms(10)) >= 1);
+        assert!(padding.in_gap());
+        assert_eq!(padding.dummy_len(), 300);
+        assert!(padding.poll(start + ms(15)) >= 1);
+
+        padding.on_real_frame(start + ms(16), 120);
+        assert!(!padding.in_gap());
+        assert_eq!(padding.dummy_len(), 120);
+    }
+
+    #[test]
+    fn infinity_bin_stops_padding() {
+        let start = Instant::now();
+        let mut padding = AdaptivePadding::with_rng(
+            PaddingHistogram::new(&[ms(10)], &[1], 0).unwrap(),
+            // Only the infinity bin: a single dummy per gap.
+            PaddingHistogram::new(&[ms(5)], &[0], 1).unwrap(),
+            StdRng::seed_from_u64(1),
+        );
+        padding.on_real_frame(start, 64);
+        assert_eq!(padding.poll(start + ms(10)), 1);
+        assert_eq!(padding.poll(start + ms(1_000)), 0);
+    }
+
+    #[test]
+    fn falling_behind_does_not_burst() {
+        let start = Instant::now();
+        let mut padding = padding(0, 0);
+        padding.on_real_frame(start, 64);
+        assert_eq!(padding.poll(start + ms(10_000)), MAX_DUMMIES_PER_POLL);
+    }
+
+    #[test]
+    fn rejects_malformed_histograms() {
+        assert!(PaddingHistogram::new(&[], &[], 1).is_err());
+        assert!(PaddingHistogram::new(&[ms(5), ms(5)], &[1, 1], 0).is_err());
+        assert!(PaddingHistogram::new(&[ms(5)], &[1, 2], 0).is_err());
+        assert!(PaddingHistogram::new(&[ms(5)], &[0], 0).is_err());
+    }
+}
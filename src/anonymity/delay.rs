@@ -7,6 +7,7 @@ use rand::seq::SliceRandom;
 use rand::{CryptoRng, RngCore};
 
 use crate::anonymity::mixing::Frame;
+use crate::config::MixDelay;
 
 pub trait DelayDistribution {
     fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration;
@@ -44,6 +45,115 @@ impl DelayDistribution for UniformDelay {
     }
 }
 
+/// Uniform in (0, 1]; never 0, so its logarithm is finite.
+fn unit_interval(rng: &mut dyn RngCore) -> f64 {
+    ((rng.next_u64() >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+}
+
+fn clamped_nanos(value: f64, max_ns: u64) -> Duration {
+    if value.is_nan() {
+        return Duration::from_nanos(max_ns);
+    }
+    Duration::from_nanos(value.min(max_ns as f64) as u64)
+}
+
+/// Exponentially distributed delays: releases form a Poisson process, so
+/// a frame's departure time says nothing about when it arrived.
+#[derive(Debug, Clone)]
+pub struct PoissonDelay {
+    mean_ns: f64,
+    max_ns: u64,
+}
+
+impl PoissonDelay {
+    pub fn new(mean: Duration, max: Duration) -> Result<Self, &'static str> {
+        if mean.is_zero() {
+            return Err("mean delay must be > 0");
+        }
+        if max < mean {
+            return Err("max delay must be >= mean delay");
+        }
+        let max_ns = u64::try_from(max.as_nanos()).map_err(|_| "max delay too large")?;
+        Ok(Self {
+            mean_ns: mean.as_nanos() as f64,
+            max_ns,
+        })
+    }
+}
+
+impl DelayDistribution for PoissonDelay {
+    fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration {
+        clamped_nanos(-self.mean_ns * unit_interval(rng).ln(), self.max_ns)
+    }
+}
+
+/// Log-normal delays: most frames leave near the median, a few much later.
+#[derive(Debug, Clone)]
+pub struct LogNormalDelay {
+    mu: f64,
+    sigma: f64,
+    max_ns: u64,
+}
+
+impl LogNormalDelay {
+    pub fn new(median: Duration, sigma: f64, max: Duration) -> Result<Self, &'static str> {
+        if median.is_zero() {
+            return Err("median delay must be > 0");
+        }
+        if !sigma.is_finite() || sigma <= 0.0 {
+            return Err("sigma must be a positive number");
+        }
+        if max < median {
+            return Err("max delay must be >= median delay");
+        }
+        let max_ns = u64::try_from(max.as_nanos()).map_err(|_| "max delay too large")?;
+        Ok(Self {
+            mu: (median.as_nanos() as f64).ln(),
+            sigma,
+            max_ns,
+        })
+    }
+}
+
+impl DelayDistribution for LogNormalDelay {
+    fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration {
+        // Box-Muller: one standard normal sample from two uniforms.
+        let radius = (-2.0 * unit_interval(rng).ln()).sqrt();
+        let normal = radius * (std::f64::consts::TAU * unit_interval(rng)).cos();
+        clamped_nanos((self.mu + self.sigma * normal).exp(), self.max_ns)
+    }
+}
+
+/// The distribution selected by `MixDelay` in the configuration.
+#[derive(Debug, Clone)]
+pub enum ConfiguredDelay {
+    Uniform(UniformDelay),
+    Poisson(PoissonDelay),
+    LogNormal(LogNormalDelay),
+}
+
+impl ConfiguredDelay {
+    pub fn from_config(config: &MixDelay) -> Result<Self, &'static str> {
+        Ok(match *config {
+            MixDelay::Uniform { min, max } => ConfiguredDelay::Uniform(UniformDelay::new(min, max)?),
+            MixDelay::Poisson { mean, max } => ConfiguredDelay::Poisson(PoissonDelay::new(mean, max)?),
+            MixDelay::LogNormal { median, sigma, max } => {
+                ConfiguredDelay::LogNormal(LogNormalDelay::new(median, sigma, max)?)
+            }
+        })
+    }
+}
+
+impl DelayDistribution for ConfiguredDelay {
+    fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration {
+        match self {
+            ConfiguredDelay::Uniform(delay) => delay.sample_delay(rng),
+            ConfiguredDelay::Poisson(delay) => delay.sample_delay(rng),
+            ConfiguredDelay::LogNormal(delay) => delay.sample_delay(rng),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PendingFrame {
     ready_at: Instant,
@@ -153,3 +263,62 @@ impl<D: DelayDistribution, R: RngCore + CryptoRng> DelayQueue<D, R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const SAMPLES: usize = 20_000;
+
+    fn samples_ms(distribution: &mut dyn DelayDistribution) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(0xDE1A7);
+        let mut samples: Vec<f64> = (0..SAMPLES)
+            .map(|_| distribution.sample_delay(&mut rng).as_secs_f64() * 1_000.0)
+            .collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        samples
+    }
+
+    #[test]
+    fn poisson_delays_are_exponential() {
+        let mut delay = PoissonDelay::new(Duration::from_millis(100), Duration::from_secs(60)).unwrap();
+        let samples = samples_ms(&mut delay);
+        let mean = samples.iter().sum::<f64>() / SAMPLES as f64;
+        assert!((mean - 100.0).abs() < 5.0, "mean {}", mean);
+        // Median of an exponential is mean * ln 2.
+        let median = samples[SAMPLES / 2];
+        assert!((median - 69.3).abs() < 5.0, "median {}", median);
+    }
+
+    #[test]
+    fn log_normal_delays_center_on_the_median_with_a_heavy_tail() {
+        let mut delay = LogNormalDelay::new(Duration::from_millis(50), 1.0, Duration::from_secs(60)).unwrap();
+        let samples = samples_ms(&mut delay);
+        let median = samples[SAMPLES / 2];
+        assert!((median - 50.0).abs() < 3.0, "median {}", median);
+        // exp(sigma * 2.326) times the median at the 99th percentile.
+        let p99 = samples[SAMPLES * 99 / 100];
+        assert!(p99 > 400.0 && p99 < 650.0, "p99 {}", p99);
+    }
+
+    #[test]
+    fn samples_are_clamped_to_max() {
+        let max = Duration::from_millis(120);
+        let mut delay = PoissonDelay::new(Duration::from_millis(100), max).unwrap();
+        let samples = samples_ms(&mut delay);
+        assert!(samples[SAMPLES - 1] <= 120.0);
+    }
+
+    #[test]
+    fn configured_delay_rejects_invalid_parameters() {
+        let bad = MixDelay::LogNormal {
+            median: Duration::from_millis(50),
+            sigma: 0.0,
+            max: Duration::from_secs(1),
+        };
+        assert!(ConfiguredDelay::from_config(&bad).is_err());
+        assert!(ConfiguredDelay::from_config(&MixDelay::default()).is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+//! RTT feedback on the mix delay. Mixing trades latency for unlinkability;
+//! on a slow path the configured delays stack on top of an already long
+//! round trip and interactive browsing stalls. `DelayBudget` pings the
+//! tunnel, smooths the round trips it measures and scales the delay
+//! distribution down while they exceed the target, back up once they do
+//! not. The scale never exceeds the configured distribution, and
+//! `BudgetedDelay` never samples below the floor, whatever the scale.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::anonymity::delay::DelayDistribution;
+use crate::config::DelayBudgetConfig;
+use crate::core::observability;
+
+/// Scale of the configured distribution, in thousandths.
+const FULL_SCALE: u32 = 1_000;
+/// Additive recovery per round trip under target.
+const SCALE_STEP_UP: u32 = 50;
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// A ping unanswered this long counts as a round trip of this long.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Samples the wrapped distribution, scaled by the budget, and clamps the
+/// result to the floor.
+#[derive(Debug, Clone)]
+pub struct BudgetedDelay<D> {
+    inner: D,
+    floor: Duration,
+    scale: Arc<AtomicU32>,
+}
+
+impl<D: DelayDistribution> DelayDistribution for BudgetedDelay<D> {
+    fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration {
+        let scale = self.scale.load(Ordering::Relaxed).min(FULL_SCALE);
+        let scaled = self.inner.sample_delay(rng) * scale / FULL_SCALE;
+        scaled.max(self.floor)
+    }
+}
+
+/// The feedback controller: multiplicative decrease while the smoothed RTT
+/// is over `target_rtt`, additive increase while it is under.
+#[derive(Debug)]
+pub struct DelayBudget {
+    floor: Duration,
+    target_rtt: Duration,
+    scale: Arc<AtomicU32>,
+    srtt: Option<Duration>,
+    outstanding: Option<(u64, Instant)>,
+    next_ping: Option<Instant>,
+}
+
+impl DelayBudget {
+    pub fn new(floor: Duration, target_rtt: Duration) -> Result<Self, &'static str> {
+        if floor.is_zero() {
+            return Err("delay floor must be > 0");
+        }
+        if target_rtt.is_zero() {
+            return Err("target RTT must be > 0");
+        }
+        Ok(Self {
+            floor,
+            target_rtt,
+            scale: Arc::new(AtomicU32::new(FULL_SCALE)),
+            srtt: None,
+            outstanding: None,
+            next_ping: None,
+        })
+    }
+
+    pub fn from_config(config: &DelayBudgetConfig) -> Result<Self, &'static str> {
+        Self::new(config.floor, config.target_rtt)
+    }
+
+    /// Wraps the mix queue's distribution so that it follows this budget.
+    pub fn distribution<D: DelayDistribution>(&self, inner: D) -> BudgetedDelay<D> {
+        BudgetedDelay {
+            inner,
+            floor: self.floor,
+            scale: Arc::clone(&self.scale),
+        }
+    }
+
+    /// Current scale of the configured distribution, in thousandths.
+    pub fn scale_permille(&self) -> u32 {
+        self.scale.load(Ordering::Relaxed)
+    }
+
+    /// Smoothed round trip, once one has been measured.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Nonce of a ping to send now, if one is due. Only one ping is in
+    /// flight at a time; one unanswered for `PING_TIMEOUT` is given up as a
+    /// slow round trip.
+    pub fn poll_ping(&mut self, now: Instant) -> Option<u64> {
+        if let Some((_, sent_at)) = self.outstanding {
+            if now.saturating_duration_since(sent_at) < PING_TIMEOUT {
+                return None;
+            }
+            self.outstanding = None;
+            self.on_rtt_sample(PING_TIMEOUT);
+        }
+        if matches!(self.next_ping, Some(at) if now < at) {
+            return None;
+        }
+        let nonce = OsRng.next_u64();
+        self.outstanding = Some((nonce, now));
+        self.next_ping = Some(now + PING_INTERVAL);
+        Some(nonce)
+    }
+
+    /// Matches a pong to the ping in flight. Pongs for other nonces are
+    /// stale or forged and ignored.
+    pub fn on_pong(&mut self, nonce: u64, now: Instant) {
+        match self.outstanding {
+            Some((sent, sent_at)) if sent == nonce => {
+                self.outstanding = None;
+                self.on_rtt_sample(now.saturating_duration_since(sent_at));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        // RFC 6298 smoothing, alpha = 1/8.
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+        self.srtt = Some(srtt);
+
+        let scale = self.scale.load(Ordering::Relaxed);
+        let scale = if srtt > self.target_rtt {
+            scale / 2
+        } else {
+            (scale + SCALE_STEP_UP).min(FULL_SCALE)
+        };
+        self.scale.store(scale, Ordering::Relaxed);
+        observability::record_delay_budget(srtt, scale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymity::delay::UniformDelay;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn budget() -> DelayBudget {
+        DelayBudget::new(Duration::from_millis(5), Duration::from_millis(200)).unwrap()
+    }
+
+    fn max_sample(delay: &mut BudgetedDelay<UniformDelay>, rng: &mut StdRng) -> Duration {
+        (0..200).map(|_| delay.sample_delay(rng)).max().unwrap()
+    }
+
+    #[test]
+    fn slow_round_trips_shrink_delays_down_to_the_floor() {
+        let mut budget = budget();
+        let mut delay = budget.distribution(UniformDelay::new(Duration::from_millis(50), Duration::from_millis(100)).unwrap());
+        let mut rng = StdRng::seed_from_u64(0x2395);
+        assert!(max_sample(&mut delay, &mut rng) > Duration::from_millis(90));
+
+        for _ in 0..20 {
+            budget.on_rtt_sample(Duration::from_millis(800));
+        }
+        assert_eq!(budget.scale_permille(), 0);
+        for _ in 0..200 {
+            assert_eq!(delay.sample_delay(&mut rng), Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn fast_round_trips_restore_the_configured_delays() {
+        let mut budget = budget();
+        for _ in 0..4 {
+            budget.on_rtt_sample(Duration::from_millis(800));
+        }
+        assert!(budget.scale_permille() < FULL_SCALE);
+        for _ in 0..100 {
+            budget.on_rtt_sample(Duration::from_millis(20));
+        }
+        assert_eq!(budget.scale_permille(), FULL_SCALE);
+        assert!(budget.smoothed_rtt().unwrap() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn floor_holds_for_every_scale() {
+        let budget = budget();
+        let mut delay = budget.distribution(UniformDelay::new(Duration::from_millis(1), Duration::from_millis(30)).unwrap());
+        let mut rng = StdRng::seed_from_u64(7);
+        for scale in [0, 1, 100, 500, FULL_SCALE, u32::MAX] {
+            budget.scale.store(scale, Ordering::Relaxed);
+            for _ in 0..200 {
+                let sample = delay.sample_delay(&mut rng);
+                assert!(sample >= Duration::from_millis(5));
+                assert!(sample <= Duration::from_millis(30));
+            }
+        }
+    }
+
+    #[test]
+    fn pings_are_matched_to_pongs() {
+        let start = Instant::now();
+        let mut budget = budget();
+        let nonce = budget.poll_ping(start).unwrap();
+        assert_eq!(budget.poll_ping(start + Duration::from_secs(2)), None);
+
+        budget.on_pong(nonce ^ 1, start + Duration::from_millis(10));
+        assert_eq!(budget.smoothed_rtt(), None);
+        budget.on_pong(nonce, start + Duration::from_millis(40));
+        assert_eq!(budget.smoothed_rtt(), Some(Duration::from_millis(40)));
+
+        assert_eq!(budget.poll_ping(start + Duration::from_millis(500)), None);
+        assert!(budget.poll_ping(start + PING_INTERVAL).is_some());
+        // Unanswered: counted as a timeout once it expires, then replaced.
+        assert_eq!(budget.poll_ping(start + PING_INTERVAL + PING_TIMEOUT - Duration::from_millis(1)), None);
+        assert!(budget.poll_ping(start + PING_INTERVAL + PING_TIMEOUT).is_some());
+        assert!(budget.smoothed_rtt().unwrap() > Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,297 @@
+//! Runtime anonymity estimates over a sliding window, for judging mixing
+//! and delay settings on live traffic rather than only in the regression
+//! gate's simulation. Three views of the same frames:
+//!
+//! - mix release sizes, as a power-of-two histogram;
+//! - the entropy of each release: a batch of `k` frames is shuffled
+//!   uniformly, so each frame in it hides among `k`, `log2(k)` bits;
+//! - Pearson correlation of ingress and egress volume in fixed time
+//!   buckets, at the lag where it is strongest. This is what an observer
+//!   of both sides of the mix sees; near zero is good.
+//!
+//! Recording is a no-op outside OBS_DEV. Only counts and times are kept.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::core::observability::OBS_DEV;
+
+pub const BATCH_BUCKETS: usize = 12;
+const WINDOW: Duration = Duration::from_secs(60);
+const BUCKET: Duration = Duration::from_millis(100);
+/// Lags tried for the correlation, in buckets.
+const MAX_LAG_BUCKETS: usize = 20;
+const MAX_RELEASES: usize = 4_096;
+
+static ESTIMATOR: Mutex<Option<AnonymityEstimator>> = Mutex::new(None);
+
+/// Frames handed to the mix.
+pub fn record_ingress(now: Instant, frames: usize) {
+    with_global(now, |estimator| estimator.on_ingress(now, frames));
+}
+
+/// The mixing pool released a batch of `frames`.
+pub fn record_release(now: Instant, frames: usize) {
+    with_global(now, |estimator| estimator.on_release(now, frames));
+}
+
+/// Frames written to the transport.
+pub fn record_egress(now: Instant, frames: usize) {
+    with_global(now, |estimator| estimator.on_egress(now, frames));
+}
+
+/// Estimates over the minute up to now; `None` outside OBS_DEV or before
+/// any frame was recorded. Observability never reads clocks, so this does.
+pub fn report() -> Option<AnonymityReport> {
+    if !OBS_DEV {
+        return None;
+    }
+    let mut estimator = ESTIMATOR.lock().ok()?;
+    estimator.as_mut().map(|estimator| estimator.report(Instant::now()))
+}
+
+fn with_global(now: Instant, record: impl FnOnce(&mut AnonymityEstimator)) {
+    if !OBS_DEV {
+        return;
+    }
+    if let Ok(mut estimator) = ESTIMATOR.lock() {
+        record(estimator.get_or_insert_with(|| AnonymityEstimator::new(now)));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnonymityReport {
+    /// Bucket `i` counts releases of fewer than `2^(i+1)` frames; the last
+    /// bucket is open-ended.
+    pub batch_sizes: [u64; BATCH_BUCKETS],
+    pub mean_entropy_bits: f64,
+    pub min_entropy_bits: f64,
+    /// Strongest ingress/egress volume correlation, in `[-1, 1]`.
+    pub correlation: f64,
+    /// Egress lag at which `correlation` was found.
+    pub correlation_lag: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct VolumeBucket {
+    index: u64,
+    ingress: u64,
+    egress: u64,
+}
+
+#[derive(Debug)]
+pub struct AnonymityEstimator {
+    start: Instant,
+    releases: VecDeque<(Instant, usize)>,
+    volumes: VecDeque<VolumeBucket>,
+}
+
+impl AnonymityEstimator {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            releases: VecDeque::new(),
+            volumes: VecDeque::new(),
+        }
+    }
+
+    pub fn on_ingress(&mut self, now: Instant, frames: usize) {
+        self.volume_at(now).ingress += frames as u64;
+        self.prune(now);
+    }
+
+    pub fn on_egress(&mut self, now: Instant, frames: usize) {
+        self.volume_at(now).egress += frames as u64;
+        self.prune(now);
+    }
+
+    pub fn on_release(&mut self, now: Instant, frames: usize) {
+        if frames == 0 {
+            return;
+        }
+        if self.releases.len() == MAX_RELEASES {
+            self.releases.pop_front();
+        }
+        self.releases.push_back((now, frames));
+        self.prune(now);
+    }
+
+    pub fn report(&mut self, now: Instant) -> AnonymityReport {
+        self.prune(now);
+
+        let mut batch_sizes = [0u64; BATCH_BUCKETS];
+        let mut entropy_sum = 0.0;
+        let mut min_entropy_bits = f64::INFINITY;
+        for &(_, frames) in &self.releases {
+            batch_sizes[batch_bucket(frames)] += 1;
+            let bits = (frames as f64).log2();
+            entropy_sum += bits;
+            min_entropy_bits = min_entropy_bits.min(bits);
+        }
+        let releases = self.releases.len();
+        let (mean_entropy_bits, min_entropy_bits) = if releases == 0 {
+            (0.0, 0.0)
+        } else {
+            (entropy_sum / releases as f64, min_entropy_bits)
+        };
+
+        let (lag, correlation) = self.strongest_correlation(now);
+        AnonymityReport {
+            batch_sizes,
+            mean_entropy_bits,
+            min_entropy_bits,
+            correlation,
+            correlation_lag: BUCKET * lag as u32,
+        }
+    }
+
+    /// Dense per-bucket volumes over the window, then the lag with the
+    /// largest `|r|`.
+    fn strongest_correlation(&self, now: Instant) -> (usize, f64) {
+        let last = self.bucket_index(now);
+        let first = last.saturating_sub(window_buckets() - 1);
+        let len = (last - first + 1) as usize;
+        let mut ingress = vec![0.0; len];
+        let mut egress = vec![0.0; len];
+        for volume in self.volumes.iter().filter(|volume| (first..=last).contains(&volume.index)) {
+            let slot = (volume.index - first) as usize;
+            ingress[slot] = volume.ingress as f64;
+            egress[slot] = volume.egress as f64;
+        }
+
+        let mut best = (0, 0.0f64);
+        for lag in 0..=MAX_LAG_BUCKETS.min(len.saturating_sub(2)) {
+            let r = pearson(&ingress[..len - lag], &egress[lag..]);
+            if r.abs() > best.1.abs() {
+                best = (lag, r);
+            }
+        }
+        best
+    }
+
+    fn volume_at(&mut self, now: Instant) -> &mut VolumeBucket {
+        let index = self.bucket_index(now);
+        match self.volumes.iter().rposition(|volume| volume.index == index) {
+            Some(position) => &mut self.volumes[position],
+            None => {
+                // Out-of-order times land in a new bucket at the back; the
+                // dense rebuild in `strongest_correlation` sorts them out.
+                self.volumes.push_back(VolumeBucket {
+                    index,
+                    ingress: 0,
+                    egress: 0,
+                });
+                self.volumes.back_mut().expect("just pushed")
+            }
+        }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_nanos() / BUCKET.as_nanos()) as u64
+    }
+
+    /// Drops what fell out of the window, and anything recorded ahead of
+    /// `now` by more than the window, which only a clock jump produces.
+    fn prune(&mut self, now: Instant) {
+        let last = self.bucket_index(now);
+        let first = last.saturating_sub(window_buckets() - 1);
+        self.volumes.retain(|volume| volume.index >= first && volume.index <= last + window_buckets());
+        while let Some(&(at, _)) = self.releases.front() {
+            if now.saturating_duration_since(at) < WINDOW {
+                break;
+            }
+            self.releases.pop_front();
+        }
+    }
+}
+
+fn window_buckets() -> u64 {
+    (WINDOW.as_nanos() / BUCKET.as_nanos()) as u64
+}
+
+fn batch_bucket(frames: usize) -> usize {
+    let log2 = (usize::BITS - 1 - frames.max(1).leading_zeros()) as usize;
+    log2.min(BATCH_BUCKETS - 1)
+}
+
+/// Pearson correlation of two equally long series; zero when either is
+/// constant.
+pub fn pearson(xs: &[f64], ys: &[f64]) -> f64 {
+    let n = xs.len();
+    assert_eq!(n, ys.len());
+    if n == 0 {
+        return 0.0;
+    }
+    let n_f = n as f64;
+    let mean_x = xs.iter().sum::<f64>() / n_f;
+    let mean_y = ys.iter().sum::<f64>() / n_f;
+    let mut num = 0.0;
+    let mut denom_x = 0.0;
+    let mut denom_y = 0.0;
+    for (x, y) in xs.iter().zip(ys) {
+        let dx = x - mean_x;
+        let dy = y - mean_y;
+        num += dx * dy;
+        denom_x += dx * dx;
+        denom_y += dy * dy;
+    }
+    if denom_x == 0.0 || denom_y == 0.0 {
+        0.0
+    } else {
+        num / (denom_x.sqrt() * denom_y.sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn releases_give_batch_histogram_and_entropy() {
+        let start = Instant::now();
+        let mut estimator = AnonymityEstimator::new(start);
+        for frames in [1, 4, 8, 0] {
+            estimator.on_release(start, frames);
+        }
+        let report = estimator.report(start);
+        assert_eq!(&report.batch_sizes[..4], &[1, 0, 1, 1]);
+        assert!((report.mean_entropy_bits - 5.0 / 3.0).abs() < 1e-9);
+        assert_eq!(report.min_entropy_bits, 0.0);
+
+        // Releases older than the window are forgotten.
+        let report = estimator.report(start + WINDOW);
+        assert_eq!(report.batch_sizes, [0; BATCH_BUCKETS]);
+        assert_eq!(report.mean_entropy_bits, 0.0);
+    }
+
+    #[test]
+    fn delayed_copy_of_ingress_is_found_at_its_lag() {
+        let start = Instant::now();
+        let mut estimator = AnonymityEstimator::new(start);
+        let mut rng = StdRng::seed_from_u64(0x2396);
+        for bucket in 0..300u32 {
+            let frames = rng.gen_range(0..20);
+            estimator.on_ingress(start + BUCKET * bucket, frames);
+            estimator.on_egress(start + BUCKET * (bucket + 3), frames);
+        }
+        let report = estimator.report(start + BUCKET * 303);
+        assert!(report.correlation > 0.99, "r = {}", report.correlation);
+        assert_eq!(report.correlation_lag, BUCKET * 3);
+    }
+
+    #[test]
+    fn independent_volumes_are_uncorrelated() {
+        let start = Instant::now();
+        let mut estimator = AnonymityEstimator::new(start);
+        let mut rng = StdRng::seed_from_u64(7);
+        for bucket in 0..600u32 {
+            estimator.on_ingress(start + BUCKET * bucket, rng.gen_range(0..20));
+            estimator.on_egress(start + BUCKET * bucket, rng.gen_range(0..20));
+        }
+        let report = estimator.report(start + BUCKET * 599);
+        assert!(report.correlation.abs() < 0.2, "r = {}", report.correlation);
+    }
+}
@@ -0,0 +1,151 @@
+//! Entry guards. A client that draws a fresh entry hop for every path will
+//! sooner or later draw a malicious one, which then sees the client's
+//! address next to the traffic. Instead the entry is drawn once and kept,
+//! across epochs and restarts, for a lifetime counted in days; only the
+//! later hops rotate.
+
+use std::io;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::anonymity::state_store::{AnonymityState, StateStore};
+use crate::log;
+use crate::logging::LogLevel;
+
+/// The persisted guard. Times are Unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuardRecord {
+    /// Identity of the entry hop as passed to `EntryGuard::entry`.
+    pub id: String,
+    pub chosen_at: u64,
+    pub expires_at: u64,
+}
+
+pub struct EntryGuard {
+    /// `None` keeps the guard for this process only.
+    store: Option<StateStore>,
+    lifetime: Duration,
+    current: Option<GuardRecord>,
+}
+
+impl EntryGuard {
+    /// Resumes the guard saved in `store`, if any.
+    pub fn open(store: StateStore, lifetime: Duration) -> io::Result<Self> {
+        let current = store.load()?.guard;
+        Ok(Self {
+            store: Some(store),
+            lifetime,
+            current,
+        })
+    }
+
+    /// A guard that is never written to disk.
+    pub fn in_memory(lifetime: Duration) -> Self {
+        Self {
+            store: None,
+            lifetime,
+            current: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<&GuardRecord> {
+        self.current.as_ref()
+    }
+
+    /// Index of the guard among `candidates`, the identities of the hops
+    /// that may serve as entry. A new guard is drawn when there is none, it
+    /// expired, or it is no longer a candidate. `None` only when
+    /// `candidates` is empty.
+    pub fn entry(&mut self, candidates: &[String], now_unix: u64, rng: &mut impl Rng) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        if let Some(guard) = &self.current {
+            if now_unix < guard.expires_at {
+                if let Some(idx) = candidates.iter().position(|id| *id == guard.id) {
+                    return Some(idx);
+                }
+            }
+        }
+
+        let idx = rng.gen_range(0..candidates.len());
+        // Up to a quarter of the lifetime extra, so the expiry does not
+        // give away exactly when the guard was drawn.
+        let lifetime = self.lifetime.as_secs();
+        let expires_at = now_unix + lifetime + rng.gen_range(0..=lifetime / 4);
+        self.current = Some(GuardRecord {
+            id: candidates[idx].clone(),
+            chosen_at: now_unix,
+            expires_at,
+        });
+        if let Some(store) = &self.store {
+            let state = AnonymityState {
+                guard: self.current.clone(),
+            };
+            if let Err(e) = store.save(&state) {
+                log!(LogLevel::Error, "Entry guard not saved; it lasts until restart"; safe "error" => e);
+            }
+        }
+        Some(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::fs;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn store(name: &str) -> StateStore {
+        let path = std::env::temp_dir().join(format!("ebt-guard-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        StateStore::new(path)
+    }
+
+    fn candidates() -> Vec<String> {
+        (1..=6).map(|n| format!("203.0.113.{}:443", n)).collect()
+    }
+
+    #[test]
+    fn guard_survives_a_restart() {
+        let store = store("restart");
+        let lifetime = Duration::from_secs(30 * DAY);
+        let mut rng = StdRng::seed_from_u64(0x392);
+
+        let mut guard = EntryGuard::open(store.clone(), lifetime).unwrap();
+        let first = guard.entry(&candidates(), 1_000, &mut rng).unwrap();
+        for _ in 0..10 {
+            assert_eq!(guard.entry(&candidates(), 2_000, &mut rng), Some(first));
+        }
+
+        let mut reopened = EntryGuard::open(store.clone(), lifetime).unwrap();
+        assert_eq!(reopened.current(), guard.current());
+        assert_eq!(reopened.entry(&candidates(), 3_000, &mut rng), Some(first));
+        let _ = fs::remove_file(store.path());
+    }
+
+    #[test]
+    fn expired_or_removed_guard_is_redrawn() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut guard = EntryGuard::in_memory(Duration::from_secs(DAY));
+        let first = guard.entry(&candidates(), 0, &mut rng).unwrap();
+        let expires_at = guard.current().unwrap().expires_at;
+        assert!((DAY..=DAY + DAY / 4).contains(&expires_at));
+
+        guard.entry(&candidates(), expires_at, &mut rng);
+        assert_eq!(guard.current().unwrap().chosen_at, expires_at);
+
+        let mut without = candidates();
+        let id = without.remove(first);
+        for _ in 0..5 {
+            guard.entry(&without, expires_at + 1, &mut rng);
+            assert_ne!(guard.current().unwrap().id, id);
+        }
+        assert_eq!(guard.entry(&[], 0, &mut rng), None);
+    }
+}
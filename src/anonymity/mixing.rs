@@ -1,22 +1,145 @@
+use std::time::{Duration, Instant};
+
 use rand::rngs::OsRng;
 use rand::{CryptoRng, RngCore};
 use rand::seq::SliceRandom;
 
+use crate::anonymity::estimator;
+
 pub type Frame = Vec<u8>;
 
+/// Decides when buffered frames leave the pool and how many. The pool picks
+/// which frames at random; a strategy only ever sees counts.
+pub trait MixStrategy: Send {
+    /// Frames to release out of `buffered`, asked whenever the previous
+    /// release has been drained.
+    fn release_count(&mut self, buffered: usize, now: Instant, rng: &mut dyn RngCore) -> usize;
+}
+
+/// Releases everything buffered as soon as the previous batch is drained.
+/// Under low load a batch is a single frame, so batch boundaries leak.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContinuousMix;
+
+impl MixStrategy for ContinuousMix {
+    fn release_count(&mut self, buffered: usize, _now: Instant, _rng: &mut dyn RngCore) -> usize {
+        buffered
+    }
+}
+
+/// Holds frames until at least `threshold` are buffered, then releases them
+/// all.
+#[derive(Debug, Clone, Copy)]
+pub struct ThresholdMix {
+    threshold: usize,
+}
+
+impl ThresholdMix {
+    pub fn new(threshold: usize) -> Result<Self, &'static str> {
+        if threshold == 0 {
+            return Err("threshold must be > 0");
+        }
+        Ok(Self { threshold })
+    }
+}
+
+impl MixStrategy for ThresholdMix {
+    fn release_count(&mut self, buffered: usize, _now: Instant, _rng: &mut dyn RngCore) -> usize {
+        if buffered >= self.threshold {
+            buffered
+        } else {
+            0
+        }
+    }
+}
+
+/// Flushes every `interval`, but only when at least `min_batch` frames are
+/// buffered; smaller batches wait for the next flush.
+#[derive(Debug, Clone, Copy)]
+pub struct TimedMix {
+    interval: Duration,
+    min_batch: usize,
+    next_flush: Option<Instant>,
+}
+
+impl TimedMix {
+    pub fn new(interval: Duration, min_batch: usize) -> Result<Self, &'static str> {
+        if interval.is_zero() {
+            return Err("flush interval must be > 0");
+        }
+        Ok(Self {
+            interval,
+            min_batch,
+            next_flush: None,
+        })
+    }
+}
+
+impl MixStrategy for TimedMix {
+    fn release_count(&mut self, buffered: usize, now: Instant, _rng: &mut dyn RngCore) -> usize {
+        let next_flush = *self.next_flush.get_or_insert(now + self.interval);
+        if now < next_flush {
+            return 0;
+        }
+        self.next_flush = Some(now + self.interval);
+        if buffered >= self.min_batch.max(1) {
+            buffered
+        } else {
+            0
+        }
+    }
+}
+
+/// Every `interval`, each buffered frame leaves independently with
+/// probability `release_probability`, so neither the batch size nor a
+/// frame's time in the pool is fixed.
+#[derive(Debug, Clone, Copy)]
+pub struct BinomialMix {
+    interval: Duration,
+    release_probability: f64,
+    next_round: Option<Instant>,
+}
+
+impl BinomialMix {
+    pub fn new(interval: Duration, release_probability: f64) -> Result<Self, &'static str> {
+        if interval.is_zero() {
+            return Err("round interval must be > 0");
+        }
+        if !(release_probability > 0.0 && release_probability <= 1.0) {
+            return Err("release probability must be in (0, 1]");
+        }
+        Ok(Self {
+            interval,
+            release_probability,
+            next_round: None,
+        })
+    }
+}
+
+impl MixStrategy for BinomialMix {
+    fn release_count(&mut self, buffered: usize, now: Instant, rng: &mut dyn RngCore) -> usize {
+        let next_round = *self.next_round.get_or_insert(now + self.interval);
+        if now < next_round {
+            return 0;
+        }
+        self.next_round = Some(now + self.interval);
+        let cutoff = (self.release_probability * u64::MAX as f64) as u64;
+        (0..buffered).filter(|_| rng.next_u64() <= cutoff).count()
+    }
+}
+
 pub struct MixingPool<R: RngCore + CryptoRng = OsRng> {
+    /// Released and shuffled, waiting to be drained.
     current_epoch: Vec<Frame>,
+    /// Buffered until the strategy releases them.
     next_epoch: Vec<Frame>,
+    strategy: Box<dyn MixStrategy>,
     rng: R,
 }
 
 impl Default for MixingPool<OsRng> {
     fn default() -> Self {
-        Self {
-            current_epoch: Vec::new(),
-            next_epoch: Vec::new(),
-            rng: OsRng,
-        }
+        Self::with_rng(OsRng)
     }
 }
 
@@ -27,30 +150,43 @@ impl MixingPool<OsRng> {
 }
 
 impl<R: RngCore + CryptoRng> MixingPool<R> {
+    /// Uses `ContinuousMix` until `with_strategy` is called.
     pub fn with_rng(rng: R) -> Self {
         Self {
             current_epoch: Vec::new(),
             next_epoch: Vec::new(),
+            strategy: Box::new(ContinuousMix),
             rng,
         }
     }
 
+    pub fn with_strategy(mut self, strategy: impl MixStrategy + 'static) -> Self {
+        self.strategy = Box::new(strategy);
+        self
+    }
+
     pub fn enqueue(&mut self, frame: Frame) {
         self.next_epoch.push(frame);
     }
 
+    /// Frames waiting for the strategy to release them.
+    pub fn buffered(&self) -> usize {
+        self.next_epoch.len()
+    }
+
     pub fn drain_batch(&mut self, max_frames: usize) -> Vec<Frame> {
+        self.drain_batch_at(Instant::now(), max_frames)
+    }
+
+    pub fn drain_batch_at(&mut self, now: Instant, max_frames: usize) -> Vec<Frame> {
         if max_frames == 0 {
             return Vec::new();
         }
 
         let mut drained = Vec::new();
         while drained.len() < max_frames {
-            if self.current_epoch.is_empty() {
-                if self.next_epoch.is_empty() {
-                    break;
-                }
-                self.rotate_epoch();
+            if self.current_epoch.is_empty() && !self.rotate_epoch(now) {
+                break;
             }
 
             if let Some(frame) = self.current_epoch.pop() {
@@ -63,11 +199,95 @@ impl<R: RngCore + CryptoRng> MixingPool<R> {
         drained
     }
 
-    fn rotate_epoch(&mut self) {
+    /// Moves the frames the strategy releases into the current epoch.
+    fn rotate_epoch(&mut self, now: Instant) -> bool {
         if self.next_epoch.is_empty() {
-            return;
+            return false;
+        }
+        let buffered = self.next_epoch.len();
+        let count = self
+            .strategy
+            .release_count(buffered, now, &mut self.rng)
+            .min(buffered);
+        if count == 0 {
+            return false;
+        }
+        estimator::record_release(now, count);
+        if count == buffered {
+            std::mem::swap(&mut self.current_epoch, &mut self.next_epoch);
+        } else {
+            self.next_epoch.shuffle(&mut self.rng);
+            self.current_epoch = self.next_epoch.split_off(buffered - count);
         }
-        std::mem::swap(&mut self.current_epoch, &mut self.next_epoch);
         self.current_epoch.shuffle(&mut self.rng);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn pool(strategy: impl MixStrategy + 'static) -> MixingPool<StdRng> {
+        MixingPool::with_rng(StdRng::seed_from_u64(0x313C)).with_strategy(strategy)
+    }
+
+    fn enqueue(pool: &mut MixingPool<StdRng>, frames: u8) {
+        for n in 0..frames {
+            pool.enqueue(vec![n]);
+        }
+    }
+
+    #[test]
+    fn continuous_mix_releases_each_frame_immediately() {
+        let mut pool = MixingPool::with_rng(StdRng::seed_from_u64(1));
+        enqueue(&mut pool, 1);
+        assert_eq!(pool.drain_batch(64).len(), 1);
+    }
+
+    #[test]
+    fn threshold_mix_waits_for_k_frames() {
+        let mut pool = pool(ThresholdMix::new(4).unwrap());
+        enqueue(&mut pool, 3);
+        assert!(pool.drain_batch(64).is_empty());
+        pool.enqueue(vec![3]);
+        let mut released = pool.drain_batch(64);
+        released.sort();
+        assert_eq!(released, vec![vec![0], vec![1], vec![2], vec![3]]);
+        assert_eq!(pool.buffered(), 0);
+    }
+
+    #[test]
+    fn timed_mix_flushes_on_the_interval_with_a_minimum_batch() {
+        let start = Instant::now();
+        let mut pool = pool(TimedMix::new(Duration::from_millis(100), 3).unwrap());
+        enqueue(&mut pool, 2);
+        assert!(pool.drain_batch_at(start, 64).is_empty());
+        // Due, but below the minimum batch: held for another interval.
+        assert!(pool.drain_batch_at(start + Duration::from_millis(100), 64).is_empty());
+        enqueue(&mut pool, 1);
+        assert!(pool.drain_batch_at(start + Duration::from_millis(150), 64).is_empty());
+        assert_eq!(pool.drain_batch_at(start + Duration::from_millis(200), 64).len(), 3);
+    }
+
+    #[test]
+    fn binomial_mix_keeps_part_of_the_pool() {
+        let start = Instant::now();
+        let mut pool = pool(BinomialMix::new(Duration::from_millis(10), 0.3).unwrap());
+        enqueue(&mut pool, 200);
+        assert!(pool.drain_batch_at(start, 1_000).is_empty());
+        let released = pool.drain_batch_at(start + Duration::from_millis(10), 1_000).len();
+        assert!((35..=85).contains(&released), "released {}", released);
+        assert_eq!(pool.buffered(), 200 - released);
+    }
+
+    #[test]
+    fn released_batches_drain_across_calls() {
+        let mut pool = pool(ThresholdMix::new(5).unwrap());
+        enqueue(&mut pool, 5);
+        assert_eq!(pool.drain_batch(2).len(), 2);
+        assert_eq!(pool.drain_batch(10).len(), 3);
     }
 }
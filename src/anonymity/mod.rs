@@ -1,4 +1,10 @@
 pub mod invariants;
 pub mod mixing;
 pub mod delay;
+pub mod delay_budget;
+pub mod estimator;
 pub mod path_epoch;
+pub mod profile;
+pub mod adaptive_padding;
+pub mod guard;
+pub mod state_store;
@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
 use rand::rngs::OsRng;
@@ -39,8 +40,119 @@ impl EpochDurationDistribution for UniformEpochDuration {
     }
 }
 
+/// Where a hop runs, as far as diversity rules care.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopMetadata {
+    pub address: IpAddr,
+    /// Hosting provider or ASN tag, e.g. `"AS13335"`.
+    pub provider: Option<String>,
+    /// ISO country code.
+    pub country: Option<String>,
+}
+
+impl HopMetadata {
+    pub fn new(address: IpAddr) -> Self {
+        Self {
+            address,
+            provider: None,
+            country: None,
+        }
+    }
+
+    pub fn with_provider(mut self, provider: impl Into<String>) -> Self {
+        self.provider = Some(provider.into());
+        self
+    }
+
+    pub fn with_country(mut self, country: impl Into<String>) -> Self {
+        self.country = Some(country.into());
+        self
+    }
+
+    /// The /16 of an IPv4 address, or the /32 of an IPv6 one.
+    fn subnet(&self) -> (bool, u32) {
+        match self.address {
+            IpAddr::V4(v4) => (false, u32::from(v4) >> 16),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (false, u32::from(v4) >> 16),
+                None => (true, (u128::from(v6) >> 96) as u32),
+            },
+        }
+    }
+}
+
+/// A path whose hops can be checked against `PathConstraints`.
+pub trait PathHops {
+    fn hops(&self) -> &[HopMetadata];
+}
+
+impl PathHops for Vec<HopMetadata> {
+    fn hops(&self) -> &[HopMetadata] {
+        self
+    }
+}
+
+/// Hop diversity rules: no two hops of a path may share whatever is
+/// required to be distinct. Tags a hop does not carry never conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathConstraints {
+    distinct_subnets: bool,
+    distinct_providers: bool,
+    distinct_countries: bool,
+}
+
+impl Default for PathConstraints {
+    /// Distinct subnets and providers.
+    fn default() -> Self {
+        Self::none().distinct_subnets().distinct_providers()
+    }
+}
+
+impl PathConstraints {
+    pub fn none() -> Self {
+        Self {
+            distinct_subnets: false,
+            distinct_providers: false,
+            distinct_countries: false,
+        }
+    }
+
+    /// No two hops in the same IPv4 /16 or IPv6 /32.
+    pub fn distinct_subnets(mut self) -> Self {
+        self.distinct_subnets = true;
+        self
+    }
+
+    pub fn distinct_providers(mut self) -> Self {
+        self.distinct_providers = true;
+        self
+    }
+
+    pub fn distinct_countries(mut self) -> Self {
+        self.distinct_countries = true;
+        self
+    }
+
+    pub fn allows(&self, hops: &[HopMetadata]) -> bool {
+        let same_tag = |a: &Option<String>, b: &Option<String>| match (a, b) {
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            _ => false,
+        };
+        hops.iter().enumerate().all(|(idx, a)| {
+            hops[idx + 1..].iter().all(|b| {
+                !(self.distinct_subnets && a.subnet() == b.subnet()
+                    || self.distinct_providers && same_tag(&a.provider, &b.provider)
+                    || self.distinct_countries && same_tag(&a.country, &b.country))
+            })
+        })
+    }
+}
+
 pub struct PathEpoch<P, D: EpochDurationDistribution, R: RngCore + CryptoRng = OsRng> {
     paths: Vec<P>,
+    /// Indices of the paths selection may pick; all of them unless
+    /// `with_constraints` ruled some out.
+    eligible: Vec<usize>,
     distribution: D,
     rng: R,
     current_index: usize,
@@ -69,6 +181,7 @@ impl<P, D: EpochDurationDistribution, R: RngCore + CryptoRng> PathEpoch<P, D, R>
         let next_rotation = Instant::now() + duration;
         let epoch_nonce = rng.next_u64();
         Ok(Self {
+            eligible: (0..paths.len()).collect(),
             paths,
             distribution,
             rng,
@@ -125,13 +238,84 @@ impl<P, D: EpochDurationDistribution, R: RngCore + CryptoRng> PathEpoch<P, D, R>
     }
 
     fn select_next_index(&mut self) -> usize {
-        if self.paths.len() == 1 {
-            return 0;
+        if self.eligible.len() == 1 {
+            return self.eligible[0];
+        }
+        let mut pick = (self.rng.next_u64() as usize) % self.eligible.len();
+        if self.eligible[pick] == self.current_index {
+            pick = (pick + 1) % self.eligible.len();
+        }
+        self.eligible[pick]
+    }
+}
+
+impl<P: PathHops, D: EpochDurationDistribution, R: RngCore + CryptoRng> PathEpoch<P, D, R> {
+    /// Restricts selection to paths that satisfy `constraints`, moving off
+    /// the current path right away if it does not.
+    pub fn with_constraints(mut self, constraints: PathConstraints) -> Result<Self, &'static str> {
+        self.eligible = (0..self.paths.len())
+            .filter(|&idx| constraints.allows(self.paths[idx].hops()))
+            .collect();
+        if self.eligible.is_empty() {
+            return Err("no path satisfies the hop constraints");
         }
-        let mut idx = (self.rng.next_u64() as usize) % self.paths.len();
-        if idx == self.current_index {
-            idx = (idx + 1) % self.paths.len();
+        if !self.eligible.contains(&self.current_index) {
+            self.current_index = self.select_next_index();
         }
-        idx
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn hop(address: &str, provider: &str) -> HopMetadata {
+        HopMetadata::new(address.parse().unwrap()).with_provider(provider)
+    }
+
+    fn epoch(paths: Vec<Vec<HopMetadata>>) -> PathEpoch<Vec<HopMetadata>, UniformEpochDuration, StdRng> {
+        let duration = UniformEpochDuration::new(Duration::from_millis(1), Duration::from_millis(1)).unwrap();
+        PathEpoch::with_rng(paths, duration, StdRng::seed_from_u64(0x391)).unwrap()
+    }
+
+    #[test]
+    fn default_constraints_reject_shared_subnets_and_providers() {
+        let constraints = PathConstraints::default();
+        assert!(constraints.allows(&[hop("198.51.100.1", "AS1"), hop("203.0.113.1", "AS2")]));
+        assert!(!constraints.allows(&[hop("198.51.100.1", "AS1"), hop("198.51.7.1", "AS2")]));
+        assert!(!constraints.allows(&[hop("198.51.100.1", "as1"), hop("203.0.113.1", "AS1")]));
+        assert!(!constraints.allows(&[hop("2001:db8:1::1", "AS1"), hop("2001:db8:2::1", "AS2")]));
+        assert!(constraints.allows(&[
+            HopMetadata::new("198.51.100.1".parse().unwrap()).with_country("DE"),
+            HopMetadata::new("203.0.113.1".parse().unwrap()).with_country("DE"),
+        ]));
+        assert!(!PathConstraints::none().distinct_countries().allows(&[
+            HopMetadata::new("198.51.100.1".parse().unwrap()).with_country("DE"),
+            HopMetadata::new("203.0.113.1".parse().unwrap()).with_country("de"),
+        ]));
+    }
+
+    #[test]
+    fn rotation_only_picks_paths_that_satisfy_the_constraints() {
+        let good = vec![hop("198.51.100.1", "AS1"), hop("203.0.113.1", "AS2")];
+        let same_subnet = vec![hop("198.51.100.1", "AS1"), hop("198.51.0.9", "AS2")];
+        let other_good = vec![hop("192.0.2.1", "AS3"), hop("203.0.113.1", "AS2")];
+        let mut epoch = epoch(vec![same_subnet.clone(), good, same_subnet, other_good])
+            .with_constraints(PathConstraints::default())
+            .unwrap();
+        let start = Instant::now();
+        for step in 1..50 {
+            assert!(PathConstraints::default().allows(epoch.current_path()));
+            epoch.rotate_if_due(start + Duration::from_millis(10 * step));
+        }
+    }
+
+    #[test]
+    fn constraints_nothing_satisfies_are_an_error() {
+        let path = vec![hop("198.51.100.1", "AS1"), hop("198.51.100.2", "AS2")];
+        assert!(epoch(vec![path]).with_constraints(PathConstraints::default()).is_err());
     }
 }
@@ -0,0 +1,164 @@
+//! Runtime switching between shaping profiles. `ShapingControl` holds the
+//! active anonymity settings; the admin API swaps them and the mix delay
+//! and path epochs of running pumps follow on their next sample. Constant
+//! rate and adaptive padding are fixed when a pump starts, so a switch
+//! reaches those with the next pump.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use rand::RngCore;
+
+use crate::anonymity::delay::{ConfiguredDelay, DelayDistribution};
+use crate::anonymity::path_epoch::{EpochDurationDistribution, UniformEpochDuration};
+use crate::config::{AnonymityConfig, ShapingProfile};
+
+/// Path epoch of settings not started from a profile.
+const DEFAULT_EPOCH: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub struct ShapingControl {
+    active: ArcSwap<AnonymityConfig>,
+}
+
+impl ShapingControl {
+    pub fn new(anonymity: AnonymityConfig) -> Result<Self, &'static str> {
+        ConfiguredDelay::from_config(&anonymity.mix_delay)?;
+        Ok(Self {
+            active: ArcSwap::from_pointee(anonymity),
+        })
+    }
+
+    pub fn current(&self) -> AnonymityConfig {
+        AnonymityConfig::clone(&self.active.load())
+    }
+
+    /// `None` when the settings were not started from a profile.
+    pub fn profile(&self) -> Option<ShapingProfile> {
+        self.active.load().profile
+    }
+
+    pub fn set_profile(&self, profile: ShapingProfile) {
+        self.active.store(Arc::new(profile.anonymity()));
+    }
+
+    /// Replaces the settings outright, e.g. after a configuration reload.
+    pub fn set(&self, anonymity: AnonymityConfig) -> Result<(), &'static str> {
+        ConfiguredDelay::from_config(&anonymity.mix_delay)?;
+        self.active.store(Arc::new(anonymity));
+        Ok(())
+    }
+
+    /// Longest path epoch of the active settings.
+    pub fn epoch(&self) -> Duration {
+        self.profile().map_or(DEFAULT_EPOCH, |profile| profile.epoch())
+    }
+
+    /// A mix delay that follows the active settings.
+    pub fn delay(self: &Arc<Self>) -> ProfileDelay {
+        let applied = self.active.load_full();
+        let delay = ConfiguredDelay::from_config(&applied.mix_delay).expect("validated when stored");
+        ProfileDelay {
+            control: Arc::clone(self),
+            applied,
+            delay,
+        }
+    }
+
+    /// Path epochs drawn from the second half of the active epoch, so
+    /// rotations do not fall on a fixed period.
+    pub fn epochs(self: &Arc<Self>) -> ProfileEpochs {
+        ProfileEpochs {
+            control: Arc::clone(self),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProfileDelay {
+    control: Arc<ShapingControl>,
+    applied: Arc<AnonymityConfig>,
+    delay: ConfiguredDelay,
+}
+
+impl DelayDistribution for ProfileDelay {
+    fn sample_delay(&mut self, rng: &mut dyn RngCore) -> Duration {
+        let active = self.control.active.load();
+        if !Arc::ptr_eq(&active, &self.applied) {
+            self.applied = Arc::clone(&active);
+            self.delay = ConfiguredDelay::from_config(&active.mix_delay).expect("validated when stored");
+        }
+        self.delay.sample_delay(rng)
+    }
+}
+
+#[derive(Debug)]
+pub struct ProfileEpochs {
+    control: Arc<ShapingControl>,
+}
+
+impl EpochDurationDistribution for ProfileEpochs {
+    fn sample_duration(&mut self, rng: &mut dyn RngCore) -> Duration {
+        let epoch = self.control.epoch();
+        UniformEpochDuration::new(epoch / 2, epoch)
+            .expect("every epoch is > 0")
+            .sample_duration(rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MixDelay;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn control(profile: ShapingProfile) -> Arc<ShapingControl> {
+        Arc::new(ShapingControl::new(profile.anonymity()).unwrap())
+    }
+
+    #[test]
+    fn running_delay_follows_a_profile_switch() {
+        let control = control(ShapingProfile::Off);
+        let mut delay = control.delay();
+        let mut rng = StdRng::seed_from_u64(0x2397);
+        assert!((0..200).all(|_| delay.sample_delay(&mut rng) <= Duration::from_millis(5)));
+
+        control.set_profile(ShapingProfile::Paranoid);
+        assert_eq!(control.profile(), Some(ShapingProfile::Paranoid));
+        assert!((0..200).any(|_| delay.sample_delay(&mut rng) > Duration::from_millis(5)));
+        assert!((0..200).all(|_| delay.sample_delay(&mut rng) <= Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn epochs_follow_the_active_profile() {
+        let control = control(ShapingProfile::Off);
+        let mut epochs = control.epochs();
+        let mut rng = StdRng::seed_from_u64(7);
+        let off = ShapingProfile::Off.epoch();
+        assert!((0..100).all(|_| (off / 2..=off).contains(&epochs.sample_duration(&mut rng))));
+
+        control.set_profile(ShapingProfile::Paranoid);
+        let paranoid = ShapingProfile::Paranoid.epoch();
+        assert!((0..100).all(|_| (paranoid / 2..=paranoid).contains(&epochs.sample_duration(&mut rng))));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let control = control(ShapingProfile::Balanced);
+        let anonymity = AnonymityConfig {
+            mix_delay: MixDelay::Poisson {
+                mean: Duration::ZERO,
+                max: Duration::from_millis(10),
+            },
+            ..AnonymityConfig::default()
+        };
+        assert!(control.set(anonymity).is_err());
+        assert_eq!(control.profile(), Some(ShapingProfile::Balanced));
+
+        control.set(AnonymityConfig::default()).unwrap();
+        assert_eq!(control.profile(), None);
+        assert_eq!(control.epoch(), DEFAULT_EPOCH);
+    }
+}
@@ -0,0 +1,68 @@
+//! State of the anonymity layer that has to outlive a restart, such as the
+//! entry guard. One small bincode file, replaced atomically on every save.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::anonymity::guard::GuardRecord;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnonymityState {
+    pub guard: Option<GuardRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    state: AnonymityState,
+}
+
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved state; a missing file is an empty one.
+    pub fn load(&self) -> io::Result<AnonymityState> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(AnonymityState::default()),
+            Err(e) => return Err(e),
+        };
+        let file: StateFile =
+            bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if file.version != FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported anonymity state version {}", file.version),
+            ));
+        }
+        Ok(file.state)
+    }
+
+    pub fn save(&self, state: &AnonymityState) -> io::Result<()> {
+        let file = StateFile {
+            version: FORMAT_VERSION,
+            state: state.clone(),
+        };
+        let bytes = bincode::serialize(&file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -4,13 +4,22 @@ use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::anonymity::adaptive_padding::AdaptivePadding;
 use crate::anonymity::delay::{DelayDistribution, DelayQueue};
+use crate::anonymity::delay_budget::DelayBudget;
+use crate::anonymity::estimator;
 use crate::anonymity::path_epoch::{EpochDurationDistribution, PathEpoch};
 use crate::anonymity_protocol::AnonymityProtocolEngine;
+use crate::core::observability;
+use crate::traffic_shaping::ConstantRateShaper;
 use crate::transport_adapter::{TransportAdapter, TransportError};
 
 const MAX_MIX_BATCH: usize = 64;
 const MAX_RELEASE_BATCH: usize = 64;
+/// Wait before retrying a path rotation whose transport failed to open.
+const ROTATION_RETRY: Duration = Duration::from_secs(1);
+/// How long a replaced transport stays open for replies to frames it sent.
+const RETIRED_TRANSPORT_LINGER: Duration = Duration::from_secs(5);
 
 pub trait EpochTransportFactory<P>: Send {
     fn open_transport(&mut self, path: &P) -> Result<Box<dyn TransportAdapter>, TransportError>;
@@ -26,6 +35,9 @@ where
     delay: Option<DelayQueue<DD>>,
     path_epoch: Option<PathEpoch<P, ED>>,
     factory: Option<F>,
+    padding: Option<AdaptivePadding>,
+    constant_rate: Option<ConstantRateShaper>,
+    budget: Option<DelayBudget>,
     running: Arc<Mutex<bool>>,
 }
 
@@ -46,66 +58,70 @@ where
             delay: Some(delay),
             path_epoch: Some(path_epoch),
             factory: Some(factory),
+            padding: None,
+            constant_rate: None,
+            budget: None,
             running: Arc::new(Mutex::new(false)),
         }
     }
 
+    /// Fills gaps in the protocol engine's output with padding frames,
+    /// which then go through the delay queue like real frames.
+    pub fn with_adaptive_padding(mut self, padding: AdaptivePadding) -> Self {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// Sends released frames as constant-rate cells instead of as-is. The
+    /// relay at the far end of the path must decode cells.
+    pub fn with_constant_rate(mut self, shaper: ConstantRateShaper) -> Self {
+        self.constant_rate = Some(shaper);
+        self
+    }
+
+    /// Pings the path and feeds the round trips to `budget`. The delay
+    /// queue only follows the budget if its distribution came from
+    /// `DelayBudget::distribution`.
+    pub fn with_delay_budget(mut self, budget: DelayBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
     pub fn start(&mut self) {
         *self.running.lock().unwrap() = true;
 
-        let protocol = Arc::clone(&self.protocol);
         let running = Arc::clone(&self.running);
-        let mut delay = self.delay.take().expect("delay queue missing");
-        let mut path_epoch = self.path_epoch.take().expect("path epoch missing");
+        let path_epoch = self.path_epoch.take().expect("path epoch missing");
         let mut factory = self.factory.take().expect("transport factory missing");
-        let mut transport = match factory.open_transport(path_epoch.current_path()) {
+        let transport = match factory.open_transport(path_epoch.current_path()) {
             Ok(t) => t,
             Err(_) => {
                 *running.lock().unwrap() = false;
                 return;
             }
         };
+        let mut pump = PumpLoop {
+            protocol: Arc::clone(&self.protocol),
+            delay: self.delay.take().expect("delay queue missing"),
+            path_epoch,
+            factory,
+            padding: self.padding.take(),
+            constant_rate: self.constant_rate.take(),
+            budget: self.budget.take(),
+            transport,
+            retiring: Vec::new(),
+            rotation_retry_at: None,
+        };
 
         thread::spawn(move || {
             while *running.lock().unwrap() {
-                let now = Instant::now();
-
-                let ready = delay.drain_ready_at(now, MAX_RELEASE_BATCH);
-
-                if path_epoch.rotate_if_due(now) {
-                    if let Ok(new_transport) = factory.open_transport(path_epoch.current_path()) {
-                        transport = new_transport;
-                    } else {
-                        for frame in ready {
-                            if transport.send_bytes(&frame).is_err() {
-                                break;
-                            }
-                        }
-                        *running.lock().unwrap() = false;
-                        break;
-                    }
+                if !pump.tick(Instant::now()) {
+                    *running.lock().unwrap() = false;
+                    break;
                 }
-
-                for frame in ready {
-                    if transport.send_bytes(&frame).is_err() {
-                        *running.lock().unwrap() = false;
-                        break;
-                    }
-                }
-
-                let mixed = {
-                    if let Ok(mut engine) = protocol.lock() {
-                        engine.drain_batch(MAX_MIX_BATCH)
-                    } else {
-                        Vec::new()
-                    }
-                };
-                for frame in mixed {
-                    delay.enqueue_at(now, frame);
-                }
-
                 thread::sleep(Duration::from_millis(1));
             }
+            pump.close();
         });
     }
 
@@ -113,3 +129,307 @@ where
         *self.running.lock().unwrap() = false;
     }
 }
+
+/// The pump thread's state; one `tick` per pass.
+struct PumpLoop<P, DD: DelayDistribution, ED: EpochDurationDistribution, F> {
+    protocol: Arc<Mutex<AnonymityProtocolEngine>>,
+    delay: DelayQueue<DD>,
+    path_epoch: PathEpoch<P, ED>,
+    factory: F,
+    padding: Option<AdaptivePadding>,
+    constant_rate: Option<ConstantRateShaper>,
+    budget: Option<DelayBudget>,
+    transport: Box<dyn TransportAdapter>,
+    /// Replaced transports and when to close them.
+    retiring: Vec<(Instant, Box<dyn TransportAdapter>)>,
+    /// Set while a failed rotation waits to be retried.
+    rotation_retry_at: Option<Instant>,
+}
+
+impl<P, DD, ED, F> PumpLoop<P, DD, ED, F>
+where
+    DD: DelayDistribution,
+    ED: EpochDurationDistribution,
+    F: EpochTransportFactory<P>,
+{
+    /// Sends what the delay queue released, rotates the path when due and
+    /// moves newly mixed frames into the delay queue. `false` once the
+    /// current transport has failed.
+    fn tick(&mut self, now: Instant) -> bool {
+        let mut ready = self.delay.drain_ready_at(now, MAX_RELEASE_BATCH);
+        for frame in &ready {
+            observability::record_frame_bytes_sent(AnonymityProtocolEngine::is_cover_frame(frame), frame.len());
+        }
+        if let Some(shaper) = self.constant_rate.as_mut() {
+            for frame in &ready {
+                shaper.push(frame);
+            }
+            ready = shaper.cells_due(now);
+        }
+        // Frames released before a switch leave on the transport they were
+        // released for.
+        let sent = ready.len();
+        for frame in ready {
+            if self.transport.send_bytes(&frame).is_err() {
+                return false;
+            }
+        }
+        estimator::record_egress(now, sent);
+
+        if !self.probe(now) {
+            return false;
+        }
+        self.rotate_if_due(now);
+        self.close_retired(now);
+
+        let mixed = {
+            if let Ok(mut engine) = self.protocol.lock() {
+                engine.drain_batch(MAX_MIX_BATCH)
+            } else {
+                Vec::new()
+            }
+        };
+        if let Some(padding) = self.padding.as_mut() {
+            for frame in &mixed {
+                padding.on_real_frame(now, frame.len());
+            }
+            let dummies = padding.poll(now);
+            for _ in 0..dummies {
+                self.delay.enqueue_at(now, AnonymityProtocolEngine::padding_frame(padding.dummy_len()));
+            }
+            observability::record_adaptive_padding(dummies);
+        }
+        for frame in mixed {
+            self.delay.enqueue_at(now, frame);
+        }
+        true
+    }
+
+    /// Answers the peer's pings and, with a delay budget, sends our own
+    /// and times the pongs. Probes skip the mix and delay queue so they
+    /// measure the path alone.
+    fn probe(&mut self, now: Instant) -> bool {
+        let (mut out, pongs) = match self.protocol.lock() {
+            Ok(mut engine) => (engine.take_control_frames(), engine.take_pongs()),
+            Err(_) => (Vec::new(), Vec::new()),
+        };
+        if let Some(budget) = self.budget.as_mut() {
+            for nonce in pongs {
+                budget.on_pong(nonce, now);
+            }
+            if let Some(nonce) = budget.poll_ping(now) {
+                out.push(AnonymityProtocolEngine::ping_frame(nonce));
+            }
+        }
+        out.iter().all(|frame| self.transport.send_bytes(frame).is_ok())
+    }
+
+    /// Make-before-break: the next path's transport is opened before the
+    /// current one is replaced, and the epoch only advances once it is up.
+    /// A failed open keeps the current path and is retried after
+    /// `ROTATION_RETRY`, so delayed frames are never stranded.
+    fn rotate_if_due(&mut self, now: Instant) {
+        if !self.path_epoch.is_due(now) || matches!(self.rotation_retry_at, Some(at) if now < at) {
+            return;
+        }
+        let next = self.path_epoch.next_index();
+        match self.factory.open_transport(self.path_epoch.path_at(next)) {
+            Ok(transport) => {
+                let old = std::mem::replace(&mut self.transport, transport);
+                self.retiring.push((now + RETIRED_TRANSPORT_LINGER, old));
+                self.path_epoch.commit_rotation(next, now);
+                self.rotation_retry_at = None;
+                observability::record_path_rotation();
+            }
+            Err(_) => {
+                self.rotation_retry_at = Some(now + ROTATION_RETRY);
+                observability::record_path_rotation_failure();
+            }
+        }
+    }
+
+    fn close_retired(&mut self, now: Instant) {
+        self.retiring.retain_mut(|(close_at, transport)| {
+            if now < *close_at {
+                return true;
+            }
+            transport.close_transport();
+            false
+        });
+    }
+
+    fn close(&mut self) {
+        for (_, transport) in &mut self.retiring {
+            transport.close_transport();
+        }
+        self.retiring.clear();
+        self.transport.close_transport();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymity::delay::UniformDelay;
+    use crate::anonymity::path_epoch::UniformEpochDuration;
+    use crate::transport_adapter::TransportCallbacks;
+
+    /// Frames sent, tagged with the transport that sent them.
+    type SendLog = Arc<Mutex<Vec<(usize, Vec<u8>)>>>;
+
+    struct RecordingTransport {
+        id: usize,
+        sent: SendLog,
+        closed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl TransportAdapter for RecordingTransport {
+        fn send_bytes(&mut self, data: &[u8]) -> Result<(), TransportError> {
+            if self.closed.lock().unwrap().contains(&self.id) {
+                return Err(TransportError::ConnectionLost);
+            }
+            self.sent.lock().unwrap().push((self.id, data.to_vec()));
+            Ok(())
+        }
+
+        fn close_transport(&mut self) {
+            self.closed.lock().unwrap().push(self.id);
+        }
+
+        fn start_reading(&mut self, _callbacks: Arc<Mutex<dyn TransportCallbacks>>) {}
+    }
+
+    /// Every `fail_every`-th open fails.
+    struct Factory {
+        opened: usize,
+        fail_every: usize,
+        sent: SendLog,
+        closed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl EpochTransportFactory<u8> for Factory {
+        fn open_transport(&mut self, _path: &u8) -> Result<Box<dyn TransportAdapter>, TransportError> {
+            self.opened += 1;
+            if self.opened.is_multiple_of(self.fail_every) {
+                return Err(TransportError::ConnectionLost);
+            }
+            Ok(Box::new(RecordingTransport {
+                id: self.opened,
+                sent: Arc::clone(&self.sent),
+                closed: Arc::clone(&self.closed),
+            }))
+        }
+    }
+
+    type TestPump = PumpLoop<u8, UniformDelay, UniformEpochDuration, Factory>;
+
+    fn pump(fail_every: usize) -> (TestPump, SendLog, Arc<Mutex<Vec<usize>>>) {
+        let sent = SendLog::default();
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let mut factory = Factory {
+            opened: 0,
+            fail_every,
+            sent: Arc::clone(&sent),
+            closed: Arc::clone(&closed),
+        };
+        let epoch = UniformEpochDuration::new(Duration::from_millis(20), Duration::from_millis(20)).unwrap();
+        let path_epoch = PathEpoch::new(vec![0u8, 1, 2], epoch).unwrap();
+        let transport = factory.open_transport(path_epoch.current_path()).unwrap();
+        let delay = UniformDelay::new(Duration::from_millis(1), Duration::from_millis(8)).unwrap();
+        let pump = PumpLoop {
+            protocol: Arc::new(Mutex::new(AnonymityProtocolEngine::new())),
+            delay: DelayQueue::new(delay),
+            path_epoch,
+            factory,
+            padding: None,
+            constant_rate: None,
+            budget: None,
+            transport,
+            retiring: Vec::new(),
+            rotation_retry_at: None,
+        };
+        (pump, sent, closed)
+    }
+
+    /// Enqueues `per_ms` frames every millisecond for `load_ms`, then keeps
+    /// ticking until the delay queue has drained. Returns the last tick.
+    fn run_under_load(pump: &mut TestPump, load_ms: u64, per_ms: u8) -> Instant {
+        let start = Instant::now();
+        let end = load_ms + 2_000;
+        for ms in 0..end {
+            if ms < load_ms {
+                let mut engine = pump.protocol.lock().unwrap();
+                for n in 0..per_ms {
+                    engine.enqueue(vec![n; 32]);
+                }
+            }
+            assert!(pump.tick(start + Duration::from_millis(ms)));
+        }
+        start + Duration::from_millis(end)
+    }
+
+    #[test]
+    fn rotation_under_load_loses_no_frames() {
+        let (mut pump, sent, _) = pump(usize::MAX);
+        run_under_load(&mut pump, 500, 4);
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2_000);
+        let transports: std::collections::BTreeSet<usize> = sent.iter().map(|(id, _)| *id).collect();
+        assert!(transports.len() > 10, "used {} transports", transports.len());
+    }
+
+    #[test]
+    fn failed_open_keeps_the_current_transport() {
+        let (mut pump, sent, closed) = pump(2);
+        let last = run_under_load(&mut pump, 500, 4);
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2_000);
+        // The first rotation failed and is only retried after the load, so
+        // every frame stayed on the first transport.
+        assert!(sent.iter().all(|(id, _)| *id == 1));
+
+        // Retries opened 3 and 5; the replaced transports linger, then close.
+        assert!(closed.lock().unwrap().is_empty());
+        pump.close_retired(last + RETIRED_TRANSPORT_LINGER);
+        assert_eq!(*closed.lock().unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn cover_frames_are_recognized_and_dropped() {
+        let (mut pump, sent, _) = pump(usize::MAX);
+        run_under_load(&mut pump, 10, 2);
+        let mut wire = Vec::new();
+        for (_, frame) in sent.lock().unwrap().iter() {
+            assert!(!AnonymityProtocolEngine::is_cover_frame(frame));
+            let padding = AnonymityProtocolEngine::padding_frame(frame.len());
+            assert!(AnonymityProtocolEngine::is_cover_frame(&padding));
+            wire.extend_from_slice(&padding);
+            wire.extend_from_slice(frame);
+        }
+        assert!(!AnonymityProtocolEngine::is_cover_frame(&AnonymityProtocolEngine::ping_frame(1)));
+
+        let mut peer = AnonymityProtocolEngine::new();
+        let frames = peer.on_transport_bytes(&wire);
+        assert_eq!(frames.len(), 20);
+        assert!(frames.iter().all(|frame| frame.payload.len() == 32));
+    }
+
+    #[test]
+    fn pongs_feed_the_delay_budget() {
+        let (mut pump, sent, _) = pump(usize::MAX);
+        pump.budget = Some(DelayBudget::new(Duration::from_millis(1), Duration::from_millis(100)).unwrap());
+        let start = Instant::now();
+        assert!(pump.tick(start));
+        let (_, ping) = sent.lock().unwrap().pop().unwrap();
+
+        // The peer's engine answers the ping unmixed.
+        let mut peer = AnonymityProtocolEngine::new();
+        assert!(peer.on_transport_bytes(&ping).is_empty());
+        let pongs = peer.take_control_frames();
+        assert_eq!(pongs.len(), 1);
+
+        pump.protocol.lock().unwrap().on_transport_bytes(&pongs[0]);
+        assert!(pump.tick(start + Duration::from_millis(30)));
+        assert_eq!(pump.budget.as_ref().unwrap().smoothed_rtt(), Some(Duration::from_millis(30)));
+    }
+}
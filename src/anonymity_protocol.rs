@@ -1,15 +1,25 @@
 #![deny(deprecated)]
 
 use std::io::Cursor;
+use std::time::Instant;
 
+use crate::anonymity::estimator;
 use crate::anonymity::mixing::MixingPool;
+use crate::core::observability;
 use crate::relay_protocol::{DataFrame, FrameDecoder, FrameEncoder, FrameType, ProtocolVersion};
 
 const ANONYMITY_PROTOCOL_VERSION: ProtocolVersion = 2;
+/// Length, version and type bytes in front of every frame.
+const FRAME_HEADER_LEN: usize = 6;
 
 pub struct AnonymityProtocolEngine {
     outbound_pool: MixingPool,
     inbound_buffer: Vec<u8>,
+    /// Pongs owed to the peer. They bypass the mix so the peer measures the
+    /// tunnel, not the mixing delay.
+    control_out: Vec<Vec<u8>>,
+    /// Nonces of pongs received since the last `take_pongs`.
+    pongs: Vec<u64>,
 }
 
 impl Default for AnonymityProtocolEngine {
@@ -17,6 +27,8 @@ impl Default for AnonymityProtocolEngine {
         Self {
             outbound_pool: MixingPool::default(),
             inbound_buffer: Vec::new(),
+            control_out: Vec::new(),
+            pongs: Vec::new(),
         }
     }
 }
@@ -39,9 +51,53 @@ impl AnonymityProtocolEngine {
         .is_ok()
         {
             self.outbound_pool.enqueue(buffer);
+            estimator::record_ingress(Instant::now(), 1);
         }
     }
 
+    /// A padding frame of `frame_len` bytes, header included, so it matches
+    /// the size of the data frame it imitates.
+    pub fn padding_frame(frame_len: usize) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let _ = FrameEncoder::encode_frame(
+            &mut buffer,
+            ANONYMITY_PROTOCOL_VERSION,
+            FrameType::Padding,
+            &vec![0u8; frame_len.saturating_sub(FRAME_HEADER_LEN)],
+        );
+        buffer
+    }
+
+    /// Whether an encoded frame is cover traffic: a `Padding` frame of this
+    /// protocol version. Anything else, including frames too short to
+    /// tell, counts as real.
+    pub fn is_cover_frame(frame: &[u8]) -> bool {
+        frame.len() >= FRAME_HEADER_LEN
+            && frame[4] == ANONYMITY_PROTOCOL_VERSION
+            && frame[5] == FrameType::Padding as u8
+    }
+
+    /// A round-trip probe carrying `nonce`.
+    pub fn ping_frame(nonce: u64) -> Vec<u8> {
+        Self::probe_frame(FrameType::Ping, nonce)
+    }
+
+    fn probe_frame(frame_type: FrameType, nonce: u64) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let _ = FrameEncoder::encode_frame(&mut buffer, ANONYMITY_PROTOCOL_VERSION, frame_type, &nonce.to_be_bytes());
+        buffer
+    }
+
+    /// Replies to pings, to be sent as-is rather than through the mix.
+    pub fn take_control_frames(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.control_out)
+    }
+
+    /// Nonces of the pongs received so far.
+    pub fn take_pongs(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.pongs)
+    }
+
     pub fn drain_batch(&mut self, max_frames: usize) -> Vec<Vec<u8>> {
         self.outbound_pool.drain_batch(max_frames)
     }
@@ -51,7 +107,7 @@ impl AnonymityProtocolEngine {
 
         let mut frames = Vec::new();
         loop {
-            if self.inbound_buffer.len() < 6 {
+            if self.inbound_buffer.len() < FRAME_HEADER_LEN {
                 break;
             }
 
@@ -61,12 +117,34 @@ impl AnonymityProtocolEngine {
                     let consumed = cursor.position() as usize;
                     self.inbound_buffer.drain(..consumed);
 
-                    if version != ANONYMITY_PROTOCOL_VERSION || frame_type != FrameType::Data {
+                    if version != ANONYMITY_PROTOCOL_VERSION {
                         continue;
                     }
 
-                    if let Ok(frame) = DataFrame::decode(&payload) {
-                        frames.push(frame);
+                    match frame_type {
+                        FrameType::Data => {
+                            observability::record_frame_bytes_received(false, consumed);
+                            if let Ok(frame) = DataFrame::decode(&payload) {
+                                frames.push(frame);
+                            }
+                        }
+                        FrameType::Ping | FrameType::Pong => {
+                            let Ok(nonce) = <[u8; 8]>::try_from(payload.as_slice()) else {
+                                continue;
+                            };
+                            let nonce = u64::from_be_bytes(nonce);
+                            if frame_type == FrameType::Ping {
+                                self.control_out.push(Self::probe_frame(FrameType::Pong, nonce));
+                            } else {
+                                self.pongs.push(nonce);
+                            }
+                        }
+                        FrameType::Padding => observability::record_frame_bytes_received(true, consumed),
+                        FrameType::Control
+                        | FrameType::Onion
+                        | FrameType::Circuit
+                        | FrameType::Datagram
+                        | FrameType::Dns => {}
                     }
                 }
                 Err(_) => break,
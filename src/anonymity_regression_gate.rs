@@ -4,6 +4,7 @@ use std::time::{Duration, Instant};
 use rand::{CryptoRng, RngCore};
 
 use crate::anonymity::delay::{DelayQueue, UniformDelay};
+use crate::anonymity::estimator::pearson;
 use crate::anonymity::mixing::MixingPool;
 
 const INGRESS_WINDOW_TICKS: u64 = 5_000;
@@ -127,30 +128,7 @@ fn run_simulation(users: usize, total_frames: usize) -> f64 {
         egress_times.push(*egress.get(&id).expect("missing egress time"));
     }
 
-    pearson_corr(&ingress_times, &egress_times)
-}
-
-fn pearson_corr(xs: &[f64], ys: &[f64]) -> f64 {
-    let n = xs.len();
-    assert_eq!(n, ys.len());
-    let n_f = n as f64;
-    let mean_x = xs.iter().sum::<f64>() / n_f;
-    let mean_y = ys.iter().sum::<f64>() / n_f;
-    let mut num = 0.0;
-    let mut denom_x = 0.0;
-    let mut denom_y = 0.0;
-    for i in 0..n {
-        let dx = xs[i] - mean_x;
-        let dy = ys[i] - mean_y;
-        num += dx * dy;
-        denom_x += dx * dx;
-        denom_y += dy * dy;
-    }
-    if denom_x == 0.0 || denom_y == 0.0 {
-        0.0
-    } else {
-        num / (denom_x.sqrt() * denom_y.sqrt())
-    }
+    pearson(&ingress_times, &egress_times)
 }
 
 #[test]
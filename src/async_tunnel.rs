@@ -1,12 +1,24 @@
 use tokio::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use std::io::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use crate::core::observability;
+use crate::memory_profile::MemoryProfile;
+use crate::traffic_shaping::OutboundShaper;
 
-const BUFFER_SIZE: usize = 65536; // 64KB
+const BUFFER_SIZE: usize = MemoryProfile::active().tunnel_buffer;
 
-pub async fn tunnel_connect(mut client: TcpStream, mut target: TcpStream) -> Result<()> {
+pub async fn tunnel_connect(
+    mut client: TcpStream,
+    mut target: TcpStream,
+    client_to_target_bytes: &AtomicU64,
+    target_to_client_bytes: &AtomicU64,
+    mut shaper: OutboundShaper,
+) -> Result<()> {
     let (mut client_read, mut client_write) = client.split();
     let (mut target_read, mut target_write) = target.split();
+    let started = Instant::now();
     
     let client_to_target = async {
         let mut buf = vec![0u8; BUFFER_SIZE];
@@ -17,9 +29,12 @@ pub async fn tunnel_connect(mut client: TcpStream, mut target: TcpStream) -> Res
                     break;
                 }
                 Ok(n) => {
-                    if target_write.write_all(&buf[..n]).await.is_err() {
+                    let shaped = shaper.shape(&buf[..n]);
+                    if target_write.write_all(&shaped).await.is_err() {
                         break;
                     }
+                    client_to_target_bytes.fetch_add(shaped.len() as u64, Ordering::Relaxed);
+                    observability::record_tunnel_bytes(true, shaped.len() as u64);
                     tokio::task::yield_now().await;
                 }
                 Err(_) => break,
@@ -29,6 +44,7 @@ pub async fn tunnel_connect(mut client: TcpStream, mut target: TcpStream) -> Res
     
     let target_to_client = async {
         let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut first_byte_from = Some(started);
         loop {
             match target_read.read(&mut buf).await {
                 Ok(0) => {
@@ -36,9 +52,14 @@ pub async fn tunnel_connect(mut client: TcpStream, mut target: TcpStream) -> Res
                     break;
                 }
                 Ok(n) => {
+                    if let Some(started) = first_byte_from.take() {
+                        observability::record_first_byte_latency(started.elapsed());
+                    }
                     if client_write.write_all(&buf[..n]).await.is_err() {
                         break;
                     }
+                    target_to_client_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    observability::record_tunnel_bytes(false, n as u64);
                     tokio::task::yield_now().await;
                 }
                 Err(_) => break,
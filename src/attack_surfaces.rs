@@ -1,18 +1,56 @@
+//! Known ways each threat-model invariant could be broken, with how far
+//! each is mitigated and which test fails if the mitigation regresses.
+//! `ebt threat-report` prints the coverage report built from this list, and
+//! compares it against a saved report to catch mitigations that slipped.
+
+use serde::{Deserialize, Serialize};
+
 use crate::threat_invariants::InvariantId;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Severity {
     Low,
     Medium,
     High,
 }
 
+/// Ordered from best to worst, so a higher status is a regression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MitigationStatus {
+    /// Closed in every build and configuration.
+    Mitigated,
+    /// Closed by default, or only in some builds or configurations.
+    Partial,
+    /// Not closed.
+    Open,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mitigation {
+    pub status: MitigationStatus,
+    /// Test, as a path under `src`, that fails if the mitigation regresses.
+    pub guarded_by: Option<&'static str>,
+}
+
+impl Mitigation {
+    pub const fn guarded(status: MitigationStatus, test: &'static str) -> Self {
+        Self { status, guarded_by: Some(test) }
+    }
+
+    pub const fn unguarded(status: MitigationStatus) -> Self {
+        Self { status, guarded_by: None }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AttackSurface {
     pub invariant_id: InvariantId,
     pub violation_path: String,
     pub severity: Severity,
     pub component: String,
+    pub mitigation: Mitigation,
 }
 
 pub struct AttackSurfaceEnumeration {
@@ -29,18 +67,24 @@ impl AttackSurfaceEnumeration {
                     violation_path: "OS resolver fallback in DoH failure".to_string(),
                     severity: Severity::High,
                     component: "dns_resolver".to_string(),
+                    mitigation: Mitigation::unguarded(MitigationStatus::Partial),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::DnsResolutionAtExitOnly,
                     violation_path: "System DNS via ToSocketAddrs".to_string(),
                     severity: Severity::High,
                     component: "transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "threat_model_tests::dns_sealed_to_the_exit_is_resolved_only_there",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::DnsResolutionAtExitOnly,
                     violation_path: "Browser DNS prefetch bypass".to_string(),
                     severity: Severity::Medium,
                     component: "proxy".to_string(),
+                    mitigation: Mitigation::unguarded(MitigationStatus::Open),
                 },
 
                 // No Source Destination Correlation
@@ -49,18 +93,30 @@ impl AttackSurfaceEnumeration {
                     violation_path: "Proxy logs client IP with CONNECT target".to_string(),
                     severity: Severity::High,
                     component: "real_proxy".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "logging::tests::sensitive_fields_dropped_unless_included",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::NoSourceDestinationCorrelation,
                     violation_path: "Transport stores both source and destination".to_string(),
                     severity: Severity::High,
                     component: "real_transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "crypto_transport_tests::crypto_transport_tests::test_source_destination_correlation_blocked",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::NoSourceDestinationCorrelation,
                     violation_path: "Session state correlation".to_string(),
                     severity: Severity::Medium,
                     component: "session".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "tunnel_registry::tests::json_omits_destinations_outside_obs_dev",
+                    ),
                 },
 
                 // ISP Traffic Encrypted
@@ -69,18 +125,47 @@ impl AttackSurfaceEnumeration {
                     violation_path: "Plaintext CONNECT before TLS".to_string(),
                     severity: Severity::High,
                     component: "relay_transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "threat_model_tests::relay_tls_hides_the_open_request_from_the_isp",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::IspTrafficEncrypted,
                     violation_path: "DNS queries in plaintext".to_string(),
                     severity: Severity::High,
                     component: "dns_resolver".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "exit_dns::tests::hostname_is_not_visible_in_sealed_request",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::IspTrafficEncrypted,
                     violation_path: "Control channel metadata leak".to_string(),
                     severity: Severity::Medium,
                     component: "control_channel".to_string(),
+                    mitigation: Mitigation::unguarded(MitigationStatus::Partial),
+                },
+                AttackSurface {
+                    invariant_id: InvariantId::IspTrafficEncrypted,
+                    violation_path: "Application traffic bypassing the proxy".to_string(),
+                    severity: Severity::High,
+                    component: "kill_switch".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "kill_switch::tests::nftables_swaps_one_table_allowing_only_relays",
+                    ),
+                },
+                AttackSurface {
+                    invariant_id: InvariantId::IspTrafficEncrypted,
+                    violation_path: "WebRTC UDP egress outside the proxy".to_string(),
+                    severity: Severity::High,
+                    component: "kill_switch".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "kill_switch::tests::nftables_swaps_one_table_allowing_only_relays",
+                    ),
                 },
 
                 // Entry Node Blind To Destination
@@ -89,18 +174,30 @@ impl AttackSurfaceEnumeration {
                     violation_path: "SNI visible to entry relay".to_string(),
                     severity: Severity::High,
                     component: "relay_transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "crypto_transport_tests::crypto_transport_tests::test_middle_relay_layer_hides_destination",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::EntryNodeBlindToDestination,
                     violation_path: "CONNECT target in relay protocol".to_string(),
                     severity: Severity::High,
                     component: "control_channel".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "threat_model_tests::no_hop_of_a_circuit_holds_source_and_destination",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::EntryNodeBlindToDestination,
                     violation_path: "Traffic analysis correlation".to_string(),
                     severity: Severity::Medium,
                     component: "async_tunnel".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "anonymity_regression_gate::anonymity_regression_gate_multi_user",
+                    ),
                 },
 
                 // Exit Node Blind To Source
@@ -109,18 +206,27 @@ impl AttackSurfaceEnumeration {
                     violation_path: "Source IP forwarded in headers".to_string(),
                     severity: Severity::High,
                     component: "real_transport".to_string(),
+                    mitigation: Mitigation::unguarded(MitigationStatus::Mitigated),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::ExitNodeBlindToSource,
                     violation_path: "Relay chain metadata exposure".to_string(),
                     severity: Severity::Medium,
                     component: "relay_transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "threat_model_tests::exit_behind_a_middle_relay_sees_only_the_middle",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::ExitNodeBlindToSource,
                     violation_path: "Session correlation via timing".to_string(),
                     severity: Severity::Low,
                     component: "tunnel_stats".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Partial,
+                        "anonymity_correlation_tests::correlation_multi_user_below_random_chance",
+                    ),
                 },
 
                 // Logging Opt In
@@ -129,18 +235,27 @@ impl AttackSurfaceEnumeration {
                     violation_path: "Default println! statements".to_string(),
                     severity: Severity::Medium,
                     component: "real_proxy".to_string(),
+                    mitigation: Mitigation::unguarded(MitigationStatus::Open),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::LoggingOptIn,
                     violation_path: "Error logging with sensitive data".to_string(),
                     severity: Severity::High,
                     component: "real_transport".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "threat_model_tests::default_logging_drops_sensitive_fields",
+                    ),
                 },
                 AttackSurface {
                     invariant_id: InvariantId::LoggingOptIn,
                     violation_path: "Debug logs in release builds".to_string(),
                     severity: Severity::Low,
                     component: "logging".to_string(),
+                    mitigation: Mitigation::guarded(
+                        MitigationStatus::Mitigated,
+                        "threat_model_tests::default_logging_drops_sensitive_fields",
+                    ),
                 },
             ],
         }
@@ -163,10 +278,139 @@ impl AttackSurfaceEnumeration {
             .filter(|surface| surface.component == component)
             .collect()
     }
+
+    pub fn coverage_report(&self) -> CoverageReport {
+        let count = |status| self.surfaces.iter().filter(|s| s.mitigation.status == status).count();
+        CoverageReport {
+            mitigated: count(MitigationStatus::Mitigated),
+            partial: count(MitigationStatus::Partial),
+            open: count(MitigationStatus::Open),
+            unguarded: self.surfaces.iter()
+                .filter(|s| s.mitigation.status != MitigationStatus::Open && s.mitigation.guarded_by.is_none())
+                .count(),
+            surfaces: self.surfaces.iter().map(SurfaceCoverage::from).collect(),
+        }
+    }
+}
+
+/// Machine-readable mitigation coverage, as printed by `ebt threat-report`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoverageReport {
+    pub mitigated: usize,
+    pub partial: usize,
+    pub open: usize,
+    /// Mitigated or partial surfaces no test guards.
+    pub unguarded: usize,
+    pub surfaces: Vec<SurfaceCoverage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SurfaceCoverage {
+    pub invariant: InvariantId,
+    pub violation_path: String,
+    pub severity: Severity,
+    pub component: String,
+    pub status: MitigationStatus,
+    pub guarded_by: Option<String>,
+}
+
+impl From<&AttackSurface> for SurfaceCoverage {
+    fn from(surface: &AttackSurface) -> Self {
+        Self {
+            invariant: surface.invariant_id.clone(),
+            violation_path: surface.violation_path.clone(),
+            severity: surface.severity.clone(),
+            component: surface.component.clone(),
+            status: surface.mitigation.status,
+            guarded_by: surface.mitigation.guarded_by.map(str::to_string),
+        }
+    }
+}
+
+impl CoverageReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("coverage report serializes to JSON")
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Surfaces of `baseline` that are now worse mitigated, lost their
+    /// guarding test, or are no longer enumerated, one line each.
+    pub fn regressions_since(&self, baseline: &CoverageReport) -> Vec<String> {
+        let mut regressions = Vec::new();
+        for before in &baseline.surfaces {
+            let now = self.surfaces.iter()
+                .find(|s| s.invariant == before.invariant && s.violation_path == before.violation_path);
+            let Some(now) = now else {
+                regressions.push(format!("{:?}: {} is no longer enumerated", before.invariant, before.violation_path));
+                continue;
+            };
+            if now.status > before.status {
+                regressions.push(format!(
+                    "{:?}: {} went from {:?} to {:?}",
+                    now.invariant, now.violation_path, before.status, now.status
+                ));
+            }
+            if let (Some(test), None) = (&before.guarded_by, &now.guarded_by) {
+                regressions.push(format!(
+                    "{:?}: {} is no longer guarded by {}",
+                    now.invariant, now.violation_path, test
+                ));
+            }
+        }
+        regressions
+    }
 }
 
 impl Default for AttackSurfaceEnumeration {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_guard_names_a_test_in_its_module() {
+        let src = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src");
+        for surface in &AttackSurfaceEnumeration::new().surfaces {
+            let Some(test) = surface.mitigation.guarded_by else {
+                continue;
+            };
+            assert_ne!(surface.mitigation.status, MitigationStatus::Open, "{} is open yet guarded", test);
+            let (module, name) = (test.split("::").next().unwrap(), test.rsplit("::").next().unwrap());
+            let source = std::fs::read_to_string(src.join(format!("{}.rs", module))).unwrap();
+            let declared = source.lines().collect::<Vec<_>>().windows(2).any(|lines| {
+                lines[0].trim_start().starts_with("#[")
+                    && lines[0].contains("test")
+                    && lines[1].contains(&format!("fn {}()", name))
+            });
+            assert!(declared, "{} is not a test", test);
+        }
+    }
+
+    #[test]
+    fn regressions_are_reported_against_a_baseline() {
+        let baseline = AttackSurfaceEnumeration::new().coverage_report();
+        let report = CoverageReport::from_json(&baseline.to_json()).unwrap();
+        assert_eq!(report, baseline);
+        assert_eq!(report.mitigated + report.partial + report.open, report.surfaces.len());
+        assert!(report.regressions_since(&baseline).is_empty());
+
+        let mut enumeration = AttackSurfaceEnumeration::new();
+        let plaintext = enumeration.surfaces.iter_mut()
+            .find(|s| s.violation_path == "DNS queries in plaintext")
+            .unwrap();
+        plaintext.mitigation = Mitigation::unguarded(MitigationStatus::Partial);
+        enumeration.surfaces.retain(|s| s.violation_path != "Default println! statements");
+        let regressions = enumeration.coverage_report().regressions_since(&baseline);
+        assert_eq!(regressions.len(), 3, "{:?}", regressions);
+        assert!(regressions[0].contains("went from Mitigated to Partial"));
+        assert!(regressions[1].contains("no longer guarded by exit_dns::tests::"));
+        assert!(regressions[2].contains("Default println! statements is no longer enumerated"));
+    }
+}
@@ -0,0 +1,238 @@
+//! Strict parser for the request line of an HTTP `CONNECT`, whose target
+//! is in authority-form (RFC 7231 §4.3.6, RFC 3986 §3.2): `host:port` with
+//! the port required, the host a hostname, an IPv4 address or a bracketed
+//! IPv6 literal, and no userinfo. Anything else is refused with a
+//! `ConnectParseError`, which the proxy answers with 400. Errors never
+//! carry the request's text, so they are safe to log. Hostnames come back
+//! in `hostname::canonicalize` form.
+
+use std::net::Ipv6Addr;
+
+use crate::hostname;
+
+/// Longest request line accepted; a CONNECT line with the longest
+/// hostname fits several times over.
+pub const MAX_REQUEST_LINE: usize = 2048;
+/// RFC 1035 limit on a name in text form, without a trailing dot.
+const MAX_HOSTNAME: usize = 253;
+const MAX_LABEL: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ConnectParseError {
+    #[error("request line is longer than {MAX_REQUEST_LINE} bytes")]
+    LineTooLong,
+    #[error("request line is not `CONNECT <authority> HTTP/1.x`")]
+    MalformedRequestLine,
+    #[error("request method is not CONNECT")]
+    NotConnect,
+    #[error("HTTP version is not 1.0 or 1.1")]
+    UnsupportedVersion,
+    #[error("authority carries userinfo")]
+    Userinfo,
+    #[error("authority has no port")]
+    MissingPort,
+    #[error("port is not a number from 1 to 65535")]
+    InvalidPort,
+    #[error("IPv6 literal is malformed")]
+    InvalidIpv6,
+    #[error("host is not a valid hostname or IPv4 address")]
+    InvalidHost,
+}
+
+/// The target of a CONNECT. IPv6 hosts are kept without brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Parses `CONNECT <authority> HTTP/1.x`, fields separated by single spaces.
+pub fn parse_connect_line(line: &str) -> Result<Authority, ConnectParseError> {
+    if line.len() > MAX_REQUEST_LINE {
+        return Err(ConnectParseError::LineTooLong);
+    }
+    let mut fields = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(ConnectParseError::MalformedRequestLine);
+    };
+    if method != "CONNECT" {
+        return Err(ConnectParseError::NotConnect);
+    }
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(ConnectParseError::UnsupportedVersion);
+    }
+    parse_authority(target)
+}
+
+/// Parses an authority-form target, `host:port`.
+pub fn parse_authority(authority: &str) -> Result<Authority, ConnectParseError> {
+    if authority.is_empty() {
+        return Err(ConnectParseError::MalformedRequestLine);
+    }
+    if authority.contains('@') {
+        return Err(ConnectParseError::Userinfo);
+    }
+    let (host, port) = if let Some(literal) = authority.strip_prefix('[') {
+        let (literal, rest) = literal.split_once(']').ok_or(ConnectParseError::InvalidIpv6)?;
+        // Zone identifiers (`%25`) and IPvFuture are not dialable targets
+        let ip: Ipv6Addr = literal.parse().map_err(|_| ConnectParseError::InvalidIpv6)?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port,
+            None if rest.is_empty() => return Err(ConnectParseError::MissingPort),
+            None => return Err(ConnectParseError::InvalidIpv6),
+        };
+        (ip.to_string(), port)
+    } else {
+        let (host, port) = authority.rsplit_once(':').ok_or(ConnectParseError::MissingPort)?;
+        if host.contains(':') {
+            // An IPv6 address without brackets
+            return Err(ConnectParseError::InvalidHost);
+        }
+        if !is_hostname(host) {
+            return Err(ConnectParseError::InvalidHost);
+        }
+        (hostname::canonicalize(host).map_err(|_| ConnectParseError::InvalidHost)?, port)
+    };
+    Ok(Authority {
+        host,
+        port: parse_port(port)?,
+    })
+}
+
+fn parse_port(port: &str) -> Result<u16, ConnectParseError> {
+    if port.is_empty() {
+        return Err(ConnectParseError::MissingPort);
+    }
+    // `parse` alone would take a leading `+`
+    if !port.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ConnectParseError::InvalidPort);
+    }
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => Err(ConnectParseError::InvalidPort),
+        Ok(port) => Ok(port),
+    }
+}
+
+/// A DNS name or dotted IPv4 address, optionally ending in a dot. Labels
+/// may hold underscores, which some service names use.
+fn is_hostname(host: &str) -> bool {
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty()
+        && name.len() <= MAX_HOSTNAME
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority(host: &str, port: u16) -> Result<Authority, ConnectParseError> {
+        Ok(Authority {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    #[test]
+    fn accepts_hostnames_ipv4_and_bracketed_ipv6() {
+        let cases = [
+            ("CONNECT example.com:443 HTTP/1.1", authority("example.com", 443)),
+            ("CONNECT Example.COM.:8443 HTTP/1.0", authority("example.com", 8443)),
+            ("CONNECT XN--BCHER-KVA.example:443 HTTP/1.1", authority("xn--bcher-kva.example", 443)),
+            ("CONNECT _sip._tcp.example.com:5061 HTTP/1.1", authority("_sip._tcp.example.com", 5061)),
+            ("CONNECT 203.0.113.7:443 HTTP/1.1", authority("203.0.113.7", 443)),
+            ("CONNECT [2001:db8::1]:443 HTTP/1.1", authority("2001:db8::1", 443)),
+            ("CONNECT [::ffff:192.0.2.1]:65535 HTTP/1.1", authority("::ffff:192.0.2.1", 65535)),
+            ("CONNECT [2001:DB8:0:0:0:0:0:1]:1 HTTP/1.1", authority("2001:db8::1", 1)),
+            ("CONNECT localhost:22 HTTP/1.1", authority("localhost", 22)),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_connect_line(line), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        use ConnectParseError::*;
+        let cases = [
+            ("", MalformedRequestLine),
+            ("CONNECT", MalformedRequestLine),
+            ("CONNECT example.com:443", MalformedRequestLine),
+            ("CONNECT example.com:443 HTTP/1.1 extra", MalformedRequestLine),
+            ("CONNECT  example.com:443 HTTP/1.1", MalformedRequestLine),
+            ("CONNECT\texample.com:443\tHTTP/1.1", MalformedRequestLine),
+            ("connect example.com:443 HTTP/1.1", NotConnect),
+            ("GET example.com:443 HTTP/1.1", NotConnect),
+            ("CONNECT example.com:443 HTTP/2.0", UnsupportedVersion),
+            ("CONNECT example.com:443 http/1.1", UnsupportedVersion),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(parse_connect_line(line), Err(expected), "{:?}", line);
+        }
+        let long_host = "a.".repeat(MAX_REQUEST_LINE / 2);
+        assert_eq!(parse_connect_line(&format!("CONNECT {}:443 HTTP/1.1", long_host)), Err(LineTooLong));
+    }
+
+    #[test]
+    fn rejects_malformed_authorities() {
+        use ConnectParseError::*;
+        let label = "a".repeat(MAX_LABEL);
+        let too_long = [label.as_str(); 4].join(".");
+        let too_long_with_port = format!("{}:443", too_long);
+        let label_too_long = format!("{}a.example:443", label);
+        let cases = [
+            ("user:pass@example.com:443", Userinfo),
+            ("user@example.com:443", Userinfo),
+            ("[2001:db8::1]@example.com:443", Userinfo),
+            ("example.com", MissingPort),
+            ("example.com:", MissingPort),
+            ("[2001:db8::1]", MissingPort),
+            ("[2001:db8::1]:", MissingPort),
+            ("example.com:0", InvalidPort),
+            ("example.com:65536", InvalidPort),
+            ("example.com:+443", InvalidPort),
+            ("example.com:44 3", InvalidPort),
+            ("example.com:https", InvalidPort),
+            ("[2001:db8::1:443", InvalidIpv6),
+            ("[2001:db8::g]:443", InvalidIpv6),
+            ("[192.0.2.1]:443", InvalidIpv6),
+            ("[fe80::1%25eth0]:443", InvalidIpv6),
+            ("[v1.future]:443", InvalidIpv6),
+            ("[2001:db8::1]443", InvalidIpv6),
+            ("2001:db8::1:443", InvalidHost),
+            (":443", InvalidHost),
+            ("exa mple.com:443", InvalidHost),
+            ("example..com:443", InvalidHost),
+            (".example.com:443", InvalidHost),
+            ("-example.com:443", InvalidHost),
+            ("example-.com:443", InvalidHost),
+            ("exam%70le.com:443", InvalidHost),
+            ("example.com/path:443", InvalidHost),
+            ("bücher.example:443", InvalidHost),
+            ("xn--a.example:443", InvalidHost),
+            (too_long.as_str(), MissingPort),
+            (too_long_with_port.as_str(), InvalidHost),
+            (label_too_long.as_str(), InvalidHost),
+            ("", MalformedRequestLine),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(parse_authority(target), Err(expected), "{:?}", target);
+        }
+        let longest = [label.as_str(), label.as_str(), label.as_str(), &"a".repeat(61)].join(".");
+        assert_eq!(parse_authority(&format!("{}.:443", longest)), authority(&longest, 443));
+    }
+
+    #[test]
+    fn errors_never_echo_the_request() {
+        let secret = "CONNECT secret.example.com:99999 HTTP/1.1";
+        let message = parse_connect_line(secret).unwrap_err().to_string();
+        assert!(!message.contains("secret") && !message.contains("99999"), "{}", message);
+    }
+}
@@ -0,0 +1,465 @@
+//! Multi-hop circuits, built telescopically. The client opens TLS to the
+//! first relay and, for each middle on the path:
+//!
+//! 1. `SessionInit` carries a fresh X25519 key and the circuit's session
+//!    id; the middle answers `KeyExchange` with its own key, and both
+//!    derive the layer key for this circuit (`onion::LayerKeyExchange`).
+//! 2. `RouteSetup` names the next relay under that layer; the middle dials
+//!    it and answers with an empty backward layer.
+//! 3. Onion cells through the middle now carry the client's own TLS
+//!    session with the next relay, where the steps repeat.
+//!
+//! The entry may be a command relay (`relay_stdio`), checksummed if its
+//! endpoint sets `checksums`; later relays are always dialed by the relay
+//! before them. The last relay is the exit, reached as in single-hop mode
+//! through `relay_client`. Each middle learns only its neighbours; only the exit
+//! learns the destination, and only the entry learns the client.
+//!
+//! These messages are `crypto_transport_design::ControlMessage`, sent in
+//! `FrameType::Circuit` frames. Teardown runs from the exit outward: when
+//! a hop's inner session ends, the client sends `SessionTeardown` to it
+//! and drops its session, which ends the leg before it in turn.
+//!
+//! Wire format: a tag byte, then
+//! - `SessionInit`: session id (32), public key (32), route length (u8)
+//! - `KeyExchange`: hop index (u8), public key
+//! - `RouteSetup`: layer count (u8), sealed next hop
+//! - `SessionTeardown`: session id (32)
+#![allow(deprecated)]
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::anonymity::path_epoch::{EpochDurationDistribution, PathEpoch};
+use crate::config::RelayEndpoint;
+use crate::crypto_transport_design::ControlMessage;
+use crate::log;
+use crate::logging::LogLevel;
+use crate::onion::{LayerKey, LayerKeyExchange, NextHop};
+use crate::memory_profile::MemoryProfile;
+use crate::relay_client::{self, RelayChannel, RelayClientError, RelayIo, CONNECT_TIMEOUT};
+use crate::relay_directory::DirectoryPath;
+use crate::relay_protocol::{FrameType, LegacyControlMessage, ProtocolError, ProtocolVersion};
+use crate::relay_server::{encode_frame, read_session_frame};
+
+const SESSION_INIT: u8 = 0x01;
+const KEY_EXCHANGE: u8 = 0x02;
+const ROUTE_SETUP: u8 = 0x03;
+const SESSION_TEARDOWN: u8 = 0x04;
+
+/// Largest inner chunk per onion cell.
+const CELL_CHUNK: usize = 16 * 1024;
+/// Buffered between a leg and the session running inside it.
+const LEG_BUFFER: usize = MemoryProfile::active().circuit_leg_buffer;
+
+pub fn encode(message: &ControlMessage) -> Vec<u8> {
+    let mut out = Vec::new();
+    match message {
+        ControlMessage::SessionInit { session_id, public_key, route_length } => {
+            out.push(SESSION_INIT);
+            out.extend_from_slice(session_id);
+            out.extend_from_slice(public_key);
+            out.push(*route_length);
+        }
+        ControlMessage::KeyExchange { encrypted_key, hop_index } => {
+            out.push(KEY_EXCHANGE);
+            out.push(*hop_index);
+            out.extend_from_slice(encrypted_key);
+        }
+        ControlMessage::RouteSetup { encrypted_next_hop, layer_count } => {
+            out.push(ROUTE_SETUP);
+            out.push(*layer_count);
+            out.extend_from_slice(encrypted_next_hop);
+        }
+        ControlMessage::SessionTeardown { session_id } => {
+            out.push(SESSION_TEARDOWN);
+            out.extend_from_slice(session_id);
+        }
+    }
+    out
+}
+
+pub fn decode(payload: &[u8]) -> Result<ControlMessage, ProtocolError> {
+    let too_short = ProtocolError::Malformed("circuit message too short");
+    let (&tag, body) = payload.split_first().ok_or(ProtocolError::Malformed("empty circuit message"))?;
+    let array = |bytes: &[u8]| -> Result<[u8; 32], ProtocolError> {
+        bytes.try_into().map_err(|_| ProtocolError::Malformed("circuit message too short"))
+    };
+    match tag {
+        SESSION_INIT if body.len() == 65 => Ok(ControlMessage::SessionInit {
+            session_id: array(&body[..32])?,
+            public_key: array(&body[32..64])?,
+            route_length: body[64],
+        }),
+        KEY_EXCHANGE => {
+            let (&hop_index, key) = body.split_first().ok_or(too_short)?;
+            Ok(ControlMessage::KeyExchange {
+                encrypted_key: key.to_vec(),
+                hop_index,
+            })
+        }
+        ROUTE_SETUP => {
+            let (&layer_count, sealed) = body.split_first().ok_or(too_short)?;
+            Ok(ControlMessage::RouteSetup {
+                encrypted_next_hop: sealed.to_vec(),
+                layer_count,
+            })
+        }
+        SESSION_TEARDOWN if body.len() == 32 => Ok(ControlMessage::SessionTeardown {
+            session_id: array(body)?,
+        }),
+        SESSION_INIT | SESSION_TEARDOWN => Err(too_short),
+        _ => Err(ProtocolError::Malformed("unknown circuit message")),
+    }
+}
+
+/// A built circuit, ready to open its connection at the exit.
+pub struct Circuit {
+    exit: RelayChannel<Box<dyn RelayIo>>,
+    legs: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for Circuit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Circuit").field("legs", &self.legs.len()).finish()
+    }
+}
+
+impl Circuit {
+    /// Builds a circuit along `path`, exit last. A one-relay path is a
+    /// plain single-hop session.
+    pub async fn build(path: &[RelayEndpoint]) -> Result<Self, RelayClientError> {
+        let (entry, rest) = path.split_first().ok_or(RelayClientError::EmptyPath)?;
+        let session_id = {
+            let mut id = [0u8; 32];
+            OsRng.fill_bytes(&mut id);
+            id
+        };
+        let mut channel = relay_client::handshake(relay_client::dial(entry).await?, entry.checksums).await?;
+        let mut legs = Vec::new();
+        for (idx, next) in rest.iter().enumerate() {
+            if next.command.is_some() {
+                return Err(RelayClientError::CommandNotFirst);
+            }
+            let (inner, leg) = extend(channel, session_id, next, (rest.len() - idx) as u8).await?;
+            legs.push(leg);
+            let tls = relay_client::start_tls(next, inner).await?;
+            channel = relay_client::handshake(Box::new(tls) as Box<dyn RelayIo>, next.checksums).await?;
+        }
+        Ok(Self { exit: channel, legs })
+    }
+
+    /// Relays between entry and exit.
+    pub fn middles(&self) -> usize {
+        self.legs.len()
+    }
+
+    pub fn into_exit(self) -> RelayChannel<Box<dyn RelayIo>> {
+        self.exit
+    }
+}
+
+/// Agrees a layer key with the middle at the far end of `channel`, has it
+/// dial `next`, and pumps cells for the session with `next` in a task.
+async fn extend(
+    channel: RelayChannel<Box<dyn RelayIo>>,
+    session_id: [u8; 32],
+    next: &RelayEndpoint,
+    route_length: u8,
+) -> Result<(DuplexStream, JoinHandle<()>), RelayClientError> {
+    let (mut stream, version, checksums, mut buffer) = channel.into_parts();
+    let exchange = LayerKeyExchange::new()?;
+    let init = ControlMessage::SessionInit {
+        session_id,
+        public_key: exchange.public_key(),
+        route_length,
+    };
+    stream.write_all(&encode_frame(version, checksums, FrameType::Circuit, &encode(&init))?).await?;
+    let key = match expect_circuit(&mut stream, &mut buffer, checksums).await? {
+        ControlMessage::KeyExchange { encrypted_key, .. } => exchange.finish(&encrypted_key, &session_id)?,
+        _ => return Err(ProtocolError::Malformed("expected KeyExchange").into()),
+    };
+
+    let next_hop = NextHop {
+        host: next.host.clone(),
+        port: next.port,
+    };
+    let setup = ControlMessage::RouteSetup {
+        encrypted_next_hop: key.seal_forward(0, &next_hop, &[])?,
+        layer_count: route_length,
+    };
+    stream.write_all(&encode_frame(version, checksums, FrameType::Circuit, &encode(&setup))?).await?;
+    match expect_circuit(&mut stream, &mut buffer, checksums).await? {
+        ControlMessage::RouteSetup { encrypted_next_hop, .. } if key.open_backward(0, &encrypted_next_hop)?.is_empty() => {}
+        _ => return Err(ProtocolError::Malformed("expected RouteSetup").into()),
+    }
+
+    let (inner, local) = tokio::io::duplex(LEG_BUFFER);
+    let leg = Leg {
+        stream,
+        version,
+        checksums,
+        buffer,
+        key,
+        next_hop,
+        session_id,
+        forward_seq: 1,
+        backward_seq: 1,
+    };
+    let task = tokio::spawn(async move {
+        if let Err(e) = leg.run(local).await {
+            log!(LogLevel::Debug, "Circuit leg ended"; safe "error" => e);
+        }
+    });
+    Ok((inner, task))
+}
+
+/// The next circuit message, failing on refusals the relay sends instead.
+async fn expect_circuit(
+    stream: &mut Box<dyn RelayIo>,
+    buffer: &mut Vec<u8>,
+    checksums: bool,
+) -> Result<ControlMessage, RelayClientError> {
+    timeout(CONNECT_TIMEOUT, async {
+        loop {
+            let Some((_, frame_type, payload)) = read_session_frame(stream, buffer, checksums).await? else {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            };
+            match frame_type {
+                FrameType::Circuit => return Ok(decode(&payload)?),
+                FrameType::Control => match LegacyControlMessage::decode(&payload)? {
+                    LegacyControlMessage::Error { code, .. } => {
+                        return Err(match crate::relay_protocol::OpenReject::from_code(code) {
+                            Some(reject) => RelayClientError::Refused(reject),
+                            None => RelayClientError::Failed(code),
+                        });
+                    }
+                    LegacyControlMessage::GoAway { code } => return Err(RelayClientError::GoAway(code)),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| RelayClientError::Timeout)?
+}
+
+/// One middle's part of a circuit: seals what the inner session writes
+/// into cells for the middle and opens the cells it sends back.
+struct Leg {
+    stream: Box<dyn RelayIo>,
+    version: ProtocolVersion,
+    checksums: bool,
+    buffer: Vec<u8>,
+    key: LayerKey,
+    next_hop: NextHop,
+    session_id: [u8; 32],
+    forward_seq: u64,
+    backward_seq: u64,
+}
+
+impl Leg {
+    async fn run(mut self, local: DuplexStream) -> Result<(), RelayClientError> {
+        let (mut local_reader, mut local_writer) = tokio::io::split(local);
+        let (mut reader, mut writer) = tokio::io::split(self.stream);
+        let mut chunk = vec![0u8; CELL_CHUNK];
+        let result = loop {
+            tokio::select! {
+                read = local_reader.read(&mut chunk) => {
+                    let read = read?;
+                    if read == 0 {
+                        let teardown = encode(&ControlMessage::SessionTeardown { session_id: self.session_id });
+                        let teardown = encode_frame(self.version, self.checksums, FrameType::Circuit, &teardown)?;
+                        writer.write_all(&teardown).await?;
+                        break Ok(());
+                    }
+                    let cell = self.key.seal_forward(self.forward_seq, &self.next_hop, &chunk[..read])?;
+                    self.forward_seq += 1;
+                    writer.write_all(&encode_frame(self.version, self.checksums, FrameType::Onion, &cell)?).await?;
+                }
+                relayed = read_session_frame(&mut reader, &mut self.buffer, self.checksums) => {
+                    let Some((_, frame_type, payload)) = relayed? else {
+                        break Ok(());
+                    };
+                    match frame_type {
+                        FrameType::Onion => {
+                            let inner = self.key.open_backward(self.backward_seq, &payload)?;
+                            self.backward_seq += 1;
+                            if local_writer.write_all(&inner).await.is_err() {
+                                break Ok(());
+                            }
+                        }
+                        FrameType::Control => match LegacyControlMessage::decode(&payload)? {
+                            LegacyControlMessage::Error { code, .. } => break Err(RelayClientError::Failed(code)),
+                            LegacyControlMessage::GoAway { code } => break Err(RelayClientError::GoAway(code)),
+                            _ => {}
+                        },
+                        FrameType::Ping => {
+                            let pong = encode_frame(self.version, self.checksums, FrameType::Pong, &payload)?;
+                            writer.write_all(&pong).await?;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        };
+        let _ = local_writer.shutdown().await;
+        let _ = writer.shutdown().await;
+        result
+    }
+}
+
+/// The relays of a path, in connection order.
+pub trait CircuitPath {
+    fn relays(&self) -> &[RelayEndpoint];
+}
+
+impl CircuitPath for Vec<RelayEndpoint> {
+    fn relays(&self) -> &[RelayEndpoint] {
+        self
+    }
+}
+
+impl CircuitPath for DirectoryPath {
+    fn relays(&self) -> &[RelayEndpoint] {
+        &self.endpoints
+    }
+}
+
+/// Builds circuits along the current path of a `PathEpoch`, rotating it
+/// first when due. Circuits already built keep their path until torn
+/// down; new ones move to the next path.
+pub struct EpochCircuits<P, D: EpochDurationDistribution> {
+    epoch: Mutex<PathEpoch<P, D>>,
+}
+
+impl<P: CircuitPath, D: EpochDurationDistribution> EpochCircuits<P, D> {
+    pub fn new(epoch: PathEpoch<P, D>) -> Self {
+        Self {
+            epoch: Mutex::new(epoch),
+        }
+    }
+
+    /// The path new circuits use, and the nonce of its epoch.
+    pub fn current(&self, now: Instant) -> (Vec<RelayEndpoint>, u64) {
+        let mut epoch = self.epoch.lock().unwrap_or_else(|e| e.into_inner());
+        if epoch.rotate_if_due(now) {
+            log!(LogLevel::Debug, "Circuit path rotated");
+        }
+        (epoch.current_path().relays().to_vec(), epoch.epoch_nonce())
+    }
+
+    pub async fn build(&self) -> Result<Circuit, RelayClientError> {
+        let (path, _) = self.current(Instant::now());
+        Circuit::build(&path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn circuit_messages_round_trip() {
+        let messages = [
+            ControlMessage::SessionInit {
+                session_id: [1; 32],
+                public_key: [2; 32],
+                route_length: 3,
+            },
+            ControlMessage::KeyExchange {
+                encrypted_key: vec![4; 32],
+                hop_index: 0,
+            },
+            ControlMessage::RouteSetup {
+                encrypted_next_hop: vec![5; 40],
+                layer_count: 2,
+            },
+            ControlMessage::SessionTeardown { session_id: [6; 32] },
+        ];
+        for message in &messages {
+            assert_eq!(format!("{:?}", decode(&encode(message)).unwrap()), format!("{:?}", message));
+        }
+        assert!(decode(&[SESSION_INIT, 0, 1]).is_err());
+        assert!(decode(&[0x7f]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn epoch_rotation_moves_new_circuits_to_the_next_path() {
+        use crate::anonymity::path_epoch::UniformEpochDuration;
+        use std::time::Duration;
+
+        let paths = vec![
+            vec![RelayEndpoint::new("198.51.100.1", 9001), RelayEndpoint::new("203.0.113.1", 9001)],
+            vec![RelayEndpoint::new("198.51.100.2", 9001), RelayEndpoint::new("203.0.113.2", 9001)],
+        ];
+        let duration = UniformEpochDuration::new(Duration::from_secs(60), Duration::from_secs(60)).unwrap();
+        let circuits = EpochCircuits::new(PathEpoch::new(paths, duration).unwrap());
+        let now = Instant::now();
+        let (first, nonce) = circuits.current(now);
+        assert_eq!(circuits.current(now), (first.clone(), nonce));
+        let (second, rotated) = circuits.current(now + Duration::from_secs(61));
+        assert_ne!(second, first);
+        assert_ne!(rotated, nonce);
+    }
+
+    #[tokio::test]
+    async fn middle_relays_only_see_their_neighbours() {
+        use crate::exit_policy::ExitPolicy;
+        use crate::onion::TEST_LAYER_KEY;
+        use crate::relay_quota::SessionQuotas;
+        use crate::relay_server::{default_limits, serve_session, RelayRole};
+        use tokio::net::TcpListener;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+        });
+
+        // Plain TCP relays: the onion and relay protocol are under test,
+        // TLS is `relay_client`'s.
+        let exit = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let exit_addr = exit.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = exit.accept().await.unwrap();
+            let role = RelayRole::Exit(Arc::new(ExitPolicy::permissive()));
+            serve_session(stream, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), Arc::default()).await
+        });
+        let (client, middle) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Middle {
+            layer_key: Arc::new(LayerKey::new(&TEST_LAYER_KEY)),
+            next_hops: Arc::new(ExitPolicy::permissive()),
+        };
+        let middle_task = tokio::spawn(serve_session(middle, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), Arc::default()));
+
+        let channel = relay_client::handshake(Box::new(client) as Box<dyn RelayIo>, true).await.unwrap();
+        let (inner, leg) = extend(channel, [7; 32], &RelayEndpoint::from(exit_addr), 1).await.unwrap();
+        let exit_channel = relay_client::handshake(Box::new(inner) as Box<dyn RelayIo>, false).await.unwrap();
+        let connection = exit_channel.open("127.0.0.1", target_port).await.unwrap();
+        let (mut tunnel, relay_end) = relay_client::loopback_pair().await.unwrap();
+        let splice = tokio::spawn(connection.splice(relay_end));
+        tunnel.write_all(b"ping").await.unwrap();
+        let mut reply = Vec::new();
+        tunnel.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"pong");
+
+        // The exit closing ends the inner session; the leg then tears the
+        // middle down.
+        assert!(splice.await.unwrap().is_ok());
+        leg.await.unwrap();
+        assert!(middle_task.await.unwrap().is_ok());
+    }
+}
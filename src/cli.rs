@@ -0,0 +1,1098 @@
+//! Command line. `ebt` with no subcommand behaves like `ebt run`, so
+//! existing launch scripts keep working. Flags given to `run` override the
+//! matching config file values and are validated the same way.
+
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::attack_surfaces::{AttackSurfaceEnumeration, CoverageReport};
+use crate::config::{ConfigError, RelayEndpoint, RelaySelection, TunnelConfig};
+use crate::content_policy_bootstrap::ClientSubnet;
+use crate::dns_resolver::{self, DnsResolver, DohResolver};
+use crate::event_bus::{self, EbtEvent};
+use crate::logging::{self, LogConfig, LogFormat};
+use crate::proxy_builder::EbtProxyBuilder;
+use crate::control_socket::ControlRequest;
+use crate::exit_dns::ExitDnsService;
+use crate::exit_policy::{self, DestinationLimiter, ExitPolicy};
+use crate::kill_switch::{self, KillSwitch};
+use crate::onion::{self, LayerKey};
+use crate::relay_accounting;
+use crate::relay_directory::{self, DescriptorRole, DescriptorSigner, DirectoryClient, DirectoryStore, RelayDescriptor};
+use crate::relay_server::{self, RelayRole, RelayServer, RelayServerConfig};
+use crate::relay_health;
+use crate::relay_protocol::RelayLimits;
+use crate::relay_quota::SessionQuotas;
+use crate::relay_stdio;
+use crate::system_proxy::SystemProxy;
+use crate::{core, ctl, doctor, frame_capture, init_wizard, leak_test, stats_cli, traffic_accounting, traffic_shaping};
+
+const RELAY_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time given to sessions to send `GoAway` before the relay exits.
+const RESTART_NOTICE: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Parser)]
+#[command(name = "ebt", version, about = "Encrypted browser tunnel")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the local proxy.
+    Run(RunArgs),
+    /// Answer a few questions and write a starter config file.
+    Init(InitArgs),
+    /// Parse and validate a config file without starting anything.
+    CheckConfig(CheckConfigArgs),
+    /// Check the port, DNS, relays, trust store and clock a proxy needs.
+    Doctor(ConfigArgs),
+    /// Probe a running proxy and this machine for DNS, direct-connection and UDP leaks.
+    Leaktest(LeakTestArgs),
+    /// Print how far each known attack surface is mitigated, as JSON.
+    ThreatReport {
+        /// Earlier report to compare against; fails if any mitigation regressed.
+        #[arg(long)]
+        baseline: Option<String>,
+    },
+    /// Configuration tooling.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Inspect the configured relays, or run one.
+    #[command(subcommand)]
+    Relay(RelayCommand),
+    /// Run a relay directory.
+    #[command(subcommand)]
+    Directory(DirectoryCommand),
+    /// Relay frame captures written by OBS_DEV builds.
+    #[command(subcommand)]
+    Capture(CaptureCommand),
+    /// Print the observability snapshot of a running proxy (OBS_DEV builds).
+    Stats {
+        /// Proxy address.
+        #[arg(default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+    /// Print traffic accounting totals per month, or per day for one month.
+    Usage {
+        /// Accounting file written by the proxy.
+        path: String,
+        /// `YYYY-MM`.
+        month: Option<String>,
+    },
+    /// Resolve a hostname through DNS-over-HTTPS, as tunnels do.
+    Resolve { host: String },
+    /// Query or manage a running proxy through its control socket.
+    #[command(name = "ctl", alias = "control")]
+    Control(ControlArgs),
+    /// Manage the firewall rules of `proxy.kill_switch`.
+    #[command(subcommand)]
+    KillSwitch(KillSwitchCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct LeakTestArgs {
+    /// Address of the running proxy.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub proxy: SocketAddr,
+    /// STUN server for the UDP probe, `host:port`.
+    #[arg(long, default_value = leak_test::DEFAULT_STUN_SERVER)]
+    pub stun: String,
+    /// Public address a connection that skips the proxy tries to reach.
+    #[arg(long, default_value = leak_test::DEFAULT_DIRECT_TARGET)]
+    pub direct: SocketAddr,
+}
+
+#[derive(Debug, Args)]
+pub struct ControlArgs {
+    /// The running proxy's `proxy.control_socket`.
+    #[arg(long, env = "EBT_CONTROL_SOCKET")]
+    pub socket: String,
+    /// Print the proxy's JSON result instead of text.
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub request: ControlCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ControlCommand {
+    /// Listener, health, open tunnels and content policy state.
+    Status,
+    /// Usage totals since the proxy started.
+    Stats,
+    /// Re-read the config file.
+    Reload,
+    /// Replace the log levels, e.g. `info,real_proxy=debug`.
+    LogLevel { levels: String },
+    /// Turn the content policy on or off.
+    Policy { state: Switch },
+    /// List live tunnels.
+    Tunnels,
+    /// Content policy matches per rule since the proxy started.
+    PolicyHits,
+    /// Hostnames the proxy has cached DoH answers for.
+    DnsCache,
+    /// Stop the proxy.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+impl ControlCommand {
+    fn request(&self) -> ControlRequest {
+        match self {
+            ControlCommand::Status => ControlRequest::Status,
+            ControlCommand::Stats => ControlRequest::Stats,
+            ControlCommand::Reload => ControlRequest::Reload,
+            ControlCommand::LogLevel { levels } => ControlRequest::SetLogLevel { levels: levels.clone() },
+            ControlCommand::Policy { state } => ControlRequest::SetContentPolicy {
+                enabled: *state == Switch::On,
+            },
+            ControlCommand::Tunnels => ControlRequest::ListTunnels,
+            ControlCommand::PolicyHits => ControlRequest::PolicyHits,
+            ControlCommand::DnsCache => ControlRequest::DnsCache,
+            ControlCommand::Shutdown => ControlRequest::Shutdown,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KillSwitchCommand {
+    /// Remove the rules left by a proxy that crashed or was killed.
+    Off,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// TOML config file; defaults and `EBT_<SECTION>__<KEY>` overrides apply when omitted.
+    #[arg(long, env = "EBT_CONFIG")]
+    pub config: Option<String>,
+    /// Named `[profiles.<name>]` layered over the file's base sections.
+    #[arg(long, env = "EBT_PROFILE")]
+    pub profile: Option<String>,
+}
+
+impl ConfigArgs {
+    /// `EBT_CONFIG` and `EBT_PROFILE` only; used when no subcommand is given.
+    pub fn from_env() -> Self {
+        Self {
+            config: std::env::var("EBT_CONFIG").ok(),
+            profile: std::env::var("EBT_PROFILE").ok(),
+        }
+    }
+
+    pub fn load(&self) -> Result<TunnelConfig, ConfigError> {
+        match (&self.config, &self.profile) {
+            (Some(path), profile) => TunnelConfig::from_file_with_profile(path, profile.as_deref()),
+            (None, Some(_)) => Err(ConfigError::Invalid {
+                field: "--profile".to_string(),
+                reason: "profiles are defined in a config file; pass --config".to_string(),
+            }),
+            (None, None) => TunnelConfig::from_env(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Listen address, overriding `proxy.bind_address`.
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Listen port, overriding `proxy.bind_port`.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Rules list path or https URL; enables the content policy.
+    #[arg(long)]
+    pub rules: Option<String>,
+    /// Record would-be blocks but allow every request.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Log levels, e.g. `info,real_proxy=debug`; overrides the config and `EBT_LOG`.
+    #[arg(long)]
+    pub log: Option<String>,
+}
+
+impl RunArgs {
+    /// Loads the config file, then applies flags on top.
+    pub fn resolve_config(&self) -> Result<TunnelConfig, ConfigError> {
+        let mut config = self.config.load()?;
+        let proxy = &mut config.proxy_policy;
+        if let Some(bind) = self.bind {
+            proxy.bind_address = bind.to_string();
+        }
+        if let Some(port) = self.port {
+            if port == 0 {
+                return Err(ConfigError::Invalid {
+                    field: "--port".to_string(),
+                    reason: "port 0 is not allowed".to_string(),
+                });
+            }
+            proxy.bind_port = port;
+        }
+        if let Some(rules) = &self.rules {
+            proxy.content_policy_enabled = true;
+            proxy.content_policy_rules = Some(rules.clone());
+        }
+        if self.dry_run {
+            if !proxy.content_policy_enabled {
+                return Err(ConfigError::Invalid {
+                    field: "--dry-run".to_string(),
+                    reason: "requires a content policy; pass --rules or enable [policy]".to_string(),
+                });
+            }
+            proxy.content_policy_dry_run = true;
+        }
+        if let Some(levels) = &self.log {
+            let format = config
+                .observability
+                .log
+                .as_ref()
+                .map_or(LogFormat::Text, |log| log.format);
+            config.observability.log = Some(LogConfig::parse(levels, format));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InitArgs {
+    /// Where to write the config file.
+    #[arg(long, default_value = "ebt.toml")]
+    pub output: String,
+    /// Overwrite an existing file.
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckConfigArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// `strict` also fails on warnings, e.g. misspelled `EBT_` variables.
+    #[arg(long, value_enum, default_value_t = Strictness::Normal)]
+    pub strictness: Strictness,
+}
+
+/// How `check-config` treats warnings. Unknown keys in the file are
+/// errors at every level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strictness {
+    /// Print warnings; fail only on errors.
+    Normal,
+    /// Fail on warnings too.
+    Strict,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the JSON Schema of the config file.
+    Schema,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CaptureCommand {
+    /// Summarise a capture and report window stalls and framing problems.
+    Analyze {
+        /// File named by `EBT_FRAME_CAPTURE_FILE`.
+        path: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RelayCommand {
+    /// List the relay mode and endpoints from the config.
+    List(ConfigArgs),
+    /// Open a TCP connection to each relay and report the connect time.
+    Probe(ConfigArgs),
+    /// Run a relay: accept relay-protocol clients over TLS and dial their targets.
+    Serve(Box<RelayServeArgs>),
+    /// Fetch and verify the relays a directory lists.
+    Fetch(RelayFetchArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DirectoryCommand {
+    /// Accept signed relay descriptors and serve them to clients.
+    Serve(DirectoryServeArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct DirectoryServeArgs {
+    /// Only the capability policy is read from the config.
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Listen address.
+    #[arg(long, default_value = "0.0.0.0:9030")]
+    pub listen: SocketAddr,
+}
+
+#[derive(Debug, Args)]
+pub struct RelayFetchArgs {
+    /// Directory base URL.
+    pub directory: String,
+    /// Relay identity to accept (hex); repeatable. Any validly signed
+    /// descriptor is accepted when omitted.
+    #[arg(long)]
+    pub pin: Vec<String>,
+    /// Cache file, used when the directory is unreachable.
+    #[arg(long)]
+    pub cache: Option<String>,
+    /// Oldest cache accepted, in hours.
+    #[arg(long, default_value_t = 24)]
+    pub max_cache_age_hours: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct RelayServeArgs {
+    /// Only the capability policy is read from the config.
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Listen address.
+    #[arg(long, default_value = "0.0.0.0:9001")]
+    pub listen: SocketAddr,
+    /// PEM certificate chain presented to clients.
+    #[arg(long, required_unless_present = "stdio")]
+    pub cert: Option<String>,
+    /// PEM private key of the certificate.
+    #[arg(long, required_unless_present = "stdio")]
+    pub key: Option<String>,
+    /// Serve one session over stdin and stdout instead of listening, for
+    /// carriers such as an SSH `ForceCommand`. No TLS is added: the
+    /// carrier must secure the session.
+    #[arg(long, conflicts_with_all = ["listen", "directory", "metrics_listen", "state_file"])]
+    pub stdio: bool,
+    /// Concurrent connections per client session; by default sized to
+    /// the memory available at start.
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Connections per client session still being dialed at once.
+    #[arg(long)]
+    pub max_inflight_opens: Option<usize>,
+    /// KiB a connection may have waiting on a slow target before it is cut.
+    #[arg(long)]
+    pub max_buffered_kib: Option<usize>,
+    /// KiB a client may send on a connection before the relay grants more;
+    /// 64 at least.
+    #[arg(long)]
+    pub initial_window_kib: Option<u32>,
+    /// Target ports to allow, e.g. `80,443,8000-8999`; every port when omitted.
+    #[arg(long)]
+    pub exit_ports: Option<String>,
+    /// Target network to refuse, in CIDR form; repeatable.
+    #[arg(long)]
+    pub exit_deny: Vec<String>,
+    /// Also allow loopback, private and link-local targets.
+    #[arg(long)]
+    pub allow_local_targets: bool,
+    /// Relayed KiB per second per client session.
+    #[arg(long)]
+    pub bandwidth_cap_kib: Option<u64>,
+    /// MiB a client session may relay per hour; over it the session is closed.
+    #[arg(long)]
+    pub quota_mib_per_hour: Option<u64>,
+    /// New connections a client session may open per minute.
+    #[arg(long)]
+    pub max_opens_per_minute: Option<u32>,
+    /// New connections per minute to any one destination host or address,
+    /// across all client sessions.
+    #[arg(long)]
+    pub max_opens_per_destination: Option<u32>,
+    /// Run as a middle relay with the onion layer key in this file (64 hex
+    /// digits); the exit flags then apply to next-hop relays.
+    #[arg(long)]
+    pub layer_key_file: Option<String>,
+    /// Answer clients' sealed DNS lookups with the key in this file (64 hex
+    /// digits), shared with clients as their exit endpoint's `dns_key`.
+    #[arg(long, conflicts_with = "layer_key_file")]
+    pub dns_key_file: Option<String>,
+    /// Directory to publish this relay's signed descriptor to.
+    #[arg(long, requires = "advertise")]
+    pub directory: Option<String>,
+    /// Ed25519 identity key (PKCS#8) signing the descriptor; created if missing.
+    #[arg(long, default_value = "relay-identity.key")]
+    pub identity_key: String,
+    /// Address clients should dial, as published in the descriptor.
+    #[arg(long)]
+    pub advertise: Option<SocketAddr>,
+    /// Hosting provider or ASN published for path diversity.
+    #[arg(long)]
+    pub provider: Option<String>,
+    /// ISO country code published for path diversity.
+    #[arg(long)]
+    pub country: Option<String>,
+    /// Serve aggregate totals at `GET /metrics` on this loopback address;
+    /// off when omitted.
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Save sessions' connection state here so clients cut by a restart
+    /// can resume; loaded again on start. Holds no targets or payloads.
+    #[arg(long)]
+    pub state_file: Option<String>,
+    /// Seconds between saves of `--state-file`.
+    #[arg(long, default_value_t = 30, requires = "state_file")]
+    pub state_interval_secs: u64,
+    /// Load balancer address or CIDR block that sends a PROXY v2 header on
+    /// every connection; repeatable. Connections from elsewhere are refused.
+    #[arg(long, conflicts_with = "stdio")]
+    pub proxy_protocol: Vec<String>,
+}
+
+impl RelayServeArgs {
+    pub fn exit_policy(&self) -> Result<ExitPolicy, ConfigError> {
+        let mut policy = if self.allow_local_targets {
+            ExitPolicy::permissive()
+        } else {
+            ExitPolicy::default()
+        };
+        if let Some(ports) = &self.exit_ports {
+            policy.allowed_ports = exit_policy::parse_port_ranges(ports)?;
+        }
+        for cidr in &self.exit_deny {
+            policy.denied_networks.push(exit_policy::parse_denied_network(cidr)?);
+        }
+        policy.bandwidth_cap = self.bandwidth_cap_kib.map(|kib| kib * 1024);
+        policy.destination_opens = self
+            .max_opens_per_destination
+            .map(|opens| Arc::new(DestinationLimiter::new(opens)));
+        Ok(policy)
+    }
+
+    /// `default_relay_limits()` with any limits given on the command line.
+    pub fn limits(&self) -> Result<RelayLimits, ConfigError> {
+        let mut builder = RelayLimits::builder();
+        if let Some(max_connections) = self.max_connections {
+            builder = builder.max_connections(max_connections);
+        }
+        if let Some(max_inflight_opens) = self.max_inflight_opens {
+            builder = builder.max_inflight_opens(max_inflight_opens);
+        }
+        if let Some(kib) = self.max_buffered_kib {
+            builder = builder.max_buffered_bytes(kib.saturating_mul(1024));
+        }
+        if let Some(kib) = self.initial_window_kib {
+            builder = builder.initial_window(kib.saturating_mul(1024));
+        }
+        builder.build()
+    }
+
+    pub fn quotas(&self) -> SessionQuotas {
+        SessionQuotas {
+            bytes_per_hour: self.quota_mib_per_hour.map(|mib| mib * 1024 * 1024),
+            opens_per_minute: self.max_opens_per_minute,
+        }
+    }
+
+    /// The metrics address, which must be loopback: the totals are for the
+    /// operator, not for clients.
+    pub fn metrics_listen(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        match self.metrics_listen {
+            Some(addr) if !addr.ip().is_loopback() => Err(ConfigError::Invalid {
+                field: "--metrics-listen".to_string(),
+                reason: "must be a loopback address".to_string(),
+            }),
+            addr => Ok(addr),
+        }
+    }
+
+    pub fn proxy_protocol(&self) -> Result<Vec<ClientSubnet>, ConfigError> {
+        self.proxy_protocol
+            .iter()
+            .map(|cidr| {
+                ClientSubnet::parse(cidr).map_err(|e| ConfigError::Invalid {
+                    field: "--proxy-protocol".to_string(),
+                    reason: e.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn role(&self) -> Result<RelayRole, ConfigError> {
+        let mut policy = self.exit_policy()?;
+        let Some(path) = &self.layer_key_file else {
+            if let Some(path) = &self.dns_key_file {
+                let key = read_key(path, "--dns-key-file")?;
+                let lookups = ExitDnsService::new(&key).map_err(|e| ConfigError::Invalid {
+                    field: "--dns-key-file".to_string(),
+                    reason: e.to_string(),
+                })?;
+                policy.lookups = Some(Arc::new(lookups));
+            }
+            return Ok(RelayRole::Exit(Arc::new(policy)));
+        };
+        let key = read_key(path, "--layer-key-file")?;
+        Ok(RelayRole::Middle {
+            layer_key: Arc::new(LayerKey::new(&key)),
+            next_hops: Arc::new(policy),
+        })
+    }
+
+    /// What to publish about this relay; timestamps and identity are
+    /// filled in when signing.
+    pub fn descriptor(&self, advertise: SocketAddr) -> Result<RelayDescriptor, ConfigError> {
+        Ok(RelayDescriptor {
+            address: advertise,
+            role: if self.layer_key_file.is_some() { DescriptorRole::Middle } else { DescriptorRole::Exit },
+            identity: String::new(),
+            tls_fingerprint: match &self.cert {
+                Some(cert) => Some(relay_server::certificate_fingerprint(cert)?),
+                None => None,
+            },
+            provider: self.provider.clone(),
+            country: self.country.clone(),
+            published_at: 0,
+            expires_at: 0,
+        })
+    }
+}
+
+/// A 32-byte key stored as 64 hex digits, as the relay key files are.
+fn read_key(path: &str, field: &str) -> Result<[u8; 32], ConfigError> {
+    let hex = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_string(),
+        source,
+    })?;
+    onion::parse_layer_key(&hex).ok_or_else(|| ConfigError::Invalid {
+        field: field.to_string(),
+        reason: "expected 64 hex digits".to_string(),
+    })
+}
+
+/// Entry point of the `ebt` binary.
+pub async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    logging::init(LogConfig::from_env());
+    core::observability::trace::init()?;
+
+    match cli.command {
+        None => {
+            let args = RunArgs {
+                config: ConfigArgs::from_env(),
+                ..RunArgs::default()
+            };
+            run_proxy(args).await
+        }
+        Some(Command::Run(args)) => run_proxy(args).await,
+        Some(Command::Init(args)) => init_wizard::run(&args.output, args.force),
+        Some(Command::CheckConfig(args)) => check_config(&args),
+        Some(Command::Doctor(args)) => doctor::run(&args.load()?).await,
+        Some(Command::Leaktest(args)) => leak_test::run(args.proxy, &args.stun, args.direct).await,
+        Some(Command::ThreatReport { baseline }) => threat_report(baseline.as_deref()),
+        Some(Command::Config(ConfigCommand::Schema)) => {
+            println!("{}", crate::config_file::schema_json());
+            Ok(())
+        }
+        Some(Command::Relay(command)) => relay(&command).await,
+        Some(Command::Directory(DirectoryCommand::Serve(args))) => serve_directory(&args).await,
+        Some(Command::Capture(CaptureCommand::Analyze { path })) => analyze_capture(&path),
+        Some(Command::Stats { addr }) => stats_cli::run(Some(&addr)),
+        Some(Command::Usage { path, month }) => traffic_accounting::run(Some(&path), month.as_deref()),
+        Some(Command::Resolve { host }) => resolve(&host).await,
+        Some(Command::Control(args)) => ctl::run(&args.socket, &args.request.request(), args.json).await,
+        Some(Command::KillSwitch(KillSwitchCommand::Off)) => {
+            kill_switch::lift()?;
+            println!("Kill switch rules removed");
+            Ok(())
+        }
+    }
+}
+
+fn analyze_capture(path: &str) -> Result<(), Box<dyn Error>> {
+    let analyses = frame_capture::analyze(&std::fs::read_to_string(path)?);
+    if analyses.is_empty() {
+        return Err(format!("{} holds no captured connections", path).into());
+    }
+    for (idx, analysis) in analyses.iter().enumerate() {
+        println!("connection {}:", idx + 1);
+        print!("{}", analysis);
+    }
+    Ok(())
+}
+
+fn threat_report(baseline: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let report = AttackSurfaceEnumeration::new().coverage_report();
+    println!("{}", report.to_json());
+    let Some(path) = baseline else {
+        return Ok(());
+    };
+    let baseline = CoverageReport::from_json(&std::fs::read_to_string(path)?)?;
+    let regressions = report.regressions_since(&baseline);
+    for regression in &regressions {
+        eprintln!("{}", regression);
+    }
+    if regressions.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} mitigation regression(s) since {}", regressions.len(), path).into())
+    }
+}
+
+async fn run_proxy(args: RunArgs) -> Result<(), Box<dyn Error>> {
+    let config = args.resolve_config()?;
+    println!("=== DIRECT CONNECT MODE (NO SSH) ===");
+
+    // Phase 5 feature gate check
+    if traffic_shaping::PHASE_5_ENABLED {
+        println!("Phase 5 traffic shaping: ENABLED");
+        traffic_shaping::initialize_traffic_shaping();
+    } else {
+        println!("Phase 5 traffic shaping: DISABLED (Phase 4 invariants enforced)");
+    }
+
+    println!("\n=== Starting Real Network Mode ===");
+    let configure_system = config.proxy_policy.configure_system;
+    // Resolved before the firewall closes; hostnames need DoH to answer.
+    let relays = config.relay.endpoints.clone();
+    let kill_switch_relays = if config.proxy_policy.kill_switch {
+        Some(kill_switch::allowed_addresses(&relays, &config.dns_policy.doh_providers).await?)
+    } else {
+        None
+    };
+    // SIGHUP and POST /ebt/reload re-read the same file with the same flags.
+    let proxy = EbtProxyBuilder::new(config)
+        .reload_from(Arc::new(move || args.resolve_config()))
+        .bind()
+        .await?;
+
+    // Optional transport warm-up (no DNS, no destinations)
+    if std::env::var("EBT_TRANSPORT_WARMUP").ok().as_deref() == Some("1") {
+        crate::relay_transport::warm_up_transport_resources();
+    }
+
+    println!("\nReal proxy server ready!");
+    println!("Configure your browser to use proxy: {}", proxy.local_addr()?);
+    let system_proxy = if configure_system {
+        let system_proxy = SystemProxy::enable(proxy.local_addr()?)?;
+        println!("System proxy settings now point here; they are restored on exit");
+        Some(system_proxy)
+    } else {
+        None
+    };
+    // Last, so a failure to start leaves no firewall rules behind.
+    let kill_switch = match &kill_switch_relays {
+        Some(relays) => {
+            let kill_switch = KillSwitch::engage(relays)?;
+            println!("Kill switch engaged: only the {} relay address(es) are reachable", relays.len());
+            Some(kill_switch)
+        }
+        None => None,
+    };
+    println!("Press Ctrl+C to stop the server");
+
+    // Accept connections until Ctrl+C, then persist traffic accounting
+    let served = proxy
+        .run_until(async {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = refresh_kill_switch(kill_switch.as_ref(), &relays, kill_switch_relays.unwrap_or_default()) => {}
+            }
+        })
+        .await;
+    // Each undone whether or not the other could be.
+    let released = kill_switch.map(KillSwitch::release).transpose();
+    if matches!(released, Ok(Some(()))) {
+        println!("Kill switch released");
+    }
+    let restored = system_proxy.map(SystemProxy::restore).transpose();
+    if matches!(restored, Ok(Some(_))) {
+        println!("System proxy settings restored");
+    }
+    released?;
+    restored?;
+    served?;
+    Ok(())
+}
+
+/// How often relay hostnames are looked up again while the kill switch
+/// is engaged, so a relay that moves stays reachable.
+const KILL_SWITCH_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// Keeps the kill switch's allow rules on the addresses `relays` resolve
+/// to now: after a reload, which may change the DoH providers, after a
+/// network change, and every `KILL_SWITCH_REFRESH`. A failed lookup keeps
+/// the rules already in place. Never returns.
+async fn refresh_kill_switch(kill_switch: Option<&KillSwitch>, relays: &[RelayEndpoint], mut allowed: Vec<SocketAddr>) {
+    let Some(kill_switch) = kill_switch else {
+        return std::future::pending().await;
+    };
+    let mut events = event_bus::subscribe();
+    let mut interval = tokio::time::interval(KILL_SWITCH_REFRESH);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(EbtEvent::ConfigReloaded | EbtEvent::NetworkChanged { .. }) | Err(RecvError::Lagged(_)) => {}
+                Ok(_) => continue,
+                Err(RecvError::Closed) => return std::future::pending().await,
+            },
+            _ = interval.tick() => {}
+        }
+        let refreshed = kill_switch::allowed_addresses(relays, &dns_resolver::doh_providers()).await;
+        match refreshed {
+            Ok(next) if next == allowed => {}
+            Ok(next) => match kill_switch.refresh(&next) {
+                Ok(()) => {
+                    println!("Kill switch now allows {} relay address(es)", next.len());
+                    allowed = next;
+                }
+                Err(e) => eprintln!("Kill switch refresh failed; keeping the current rules: {}", e),
+            },
+            Err(e) => eprintln!("Kill switch refresh failed; keeping the current rules: {}", e),
+        }
+    }
+}
+
+pub fn check_config(args: &CheckConfigArgs) -> Result<(), Box<dyn Error>> {
+    let config = args.config.load()?;
+    let warnings = crate::config_file::warnings(&config, std::env::vars());
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    if args.strictness == Strictness::Strict && !warnings.is_empty() {
+        return Err(format!("{} warning(s) in strict mode", warnings.len()).into());
+    }
+    let proxy = &config.proxy_policy;
+    println!("config ok");
+    if let Some(profile) = &args.config.profile {
+        println!("  profile         {}", profile);
+    }
+    println!("  listen          {}:{}", proxy.bind_address, proxy.bind_port);
+    println!("  relay mode      {}", config.relay.mode.as_str());
+    println!("  relays          {}", config.relay.endpoints.len());
+    println!(
+        "  content policy  {}",
+        match (proxy.content_policy_enabled, proxy.content_policy_dry_run) {
+            (false, _) => "disabled",
+            (true, false) => "enabled",
+            (true, true) => "dry run",
+        }
+    );
+    Ok(())
+}
+
+pub async fn relay(command: &RelayCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        RelayCommand::List(args) => {
+            let config = args.load()?;
+            let relay = &config.relay;
+            println!("mode {}", relay.mode.as_str());
+            println!("selection {}", relay.selection.as_str());
+            if let Some(guard) = &relay.guard {
+                println!("guard {} (lifetime {} days)", guard.state_path, guard.lifetime.as_secs() / (24 * 60 * 60));
+            }
+            let label = if relay.selection == RelaySelection::FixedChain { "hop" } else { "relay" };
+            for (idx, endpoint) in relay.endpoints.iter().enumerate() {
+                match &endpoint.fingerprint {
+                    Some(fingerprint) => println!("  {} {}  {}  sha256:{}", label, idx + 1, endpoint, fingerprint),
+                    None => println!("  {} {}  {}", label, idx + 1, endpoint),
+                }
+            }
+        }
+        RelayCommand::Probe(args) => {
+            let config = args.load()?;
+            if config.relay.endpoints.is_empty() {
+                return Err("no relays configured".into());
+            }
+            let mut unreachable = 0;
+            for endpoint in &config.relay.endpoints {
+                match relay_health::probe(endpoint, RELAY_PROBE_TIMEOUT).await {
+                    Ok(elapsed) => println!("  {}  ok  {} ms", endpoint, elapsed.as_millis()),
+                    Err(e) => {
+                        unreachable += 1;
+                        println!("  {}  unreachable  {}", endpoint, e);
+                    }
+                }
+            }
+            if unreachable > 0 {
+                return Err(format!("{} relay(s) unreachable", unreachable).into());
+            }
+        }
+        RelayCommand::Serve(args) => {
+            let network = args.config.load()?.capabilities.network_token()?;
+            let metrics_listen = args.metrics_listen()?;
+            let limits = args.limits()?;
+            if args.stdio {
+                logging::init(LogConfig {
+                    to_stderr: true,
+                    ..LogConfig::from_env()
+                });
+                relay_stdio::serve_stdio(limits, args.quotas(), args.role()?, Arc::default()).await?;
+                return Ok(());
+            }
+            let (Some(cert), Some(key)) = (&args.cert, &args.key) else {
+                unreachable!("clap requires --cert and --key without --stdio")
+            };
+            let server = RelayServer::bind(
+                RelayServerConfig {
+                    listen: args.listen,
+                    cert_path: cert.clone(),
+                    key_path: key.clone(),
+                    limits,
+                    quotas: args.quotas(),
+                    role: args.role()?,
+                    state_file: args.state_file.clone(),
+                    proxy_protocol: args.proxy_protocol()?,
+                },
+                network,
+            )
+            .await?;
+            println!("Relay listening on {}", server.local_addr()?);
+            let publish = match (&args.directory, args.advertise) {
+                (Some(directory), Some(advertise)) => {
+                    let signer = DescriptorSigner::load_or_create(&args.identity_key)?;
+                    println!("Relay identity {}", signer.identity());
+                    let descriptor = args.descriptor(advertise)?;
+                    Some(tokio::spawn(relay_directory::publish_periodically(directory.clone(), signer, descriptor)))
+                }
+                _ => None,
+            };
+            let metrics = match metrics_listen {
+                Some(addr) => {
+                    let listener = tokio::net::TcpListener::bind(addr)
+                        .await
+                        .map_err(|source| ConfigError::Bind {
+                            addr: addr.to_string(),
+                            source,
+                        })?;
+                    println!("Relay metrics on http://{}/metrics", listener.local_addr()?);
+                    Some(tokio::spawn(relay_accounting::serve_metrics(listener, server.accounting())))
+                }
+                None => None,
+            };
+            let resume = server.resume();
+            let save = args.state_file.clone().map(|path| {
+                let interval = Duration::from_secs(args.state_interval_secs.max(1));
+                tokio::spawn(Arc::clone(&resume).persist_periodically(path, interval))
+            });
+            let result = tokio::select! {
+                result = server.serve() => result,
+                _ = tokio::signal::ctrl_c() => Ok(()),
+            };
+            if let Some(save) = save {
+                save.abort();
+            }
+            // Saved before the GoAways, which end the sessions being saved.
+            if let Some(path) = &args.state_file {
+                resume.persist(path, relay_directory::unix_now())?;
+                resume.begin_restart();
+                tokio::time::sleep(RESTART_NOTICE).await;
+            }
+            if let Some(publish) = publish {
+                publish.abort();
+            }
+            if let Some(metrics) = metrics {
+                metrics.abort();
+            }
+            result?;
+        }
+        RelayCommand::Fetch(args) => {
+            let mut client = DirectoryClient::new(args.directory.clone()).with_pinned(args.pin.iter().cloned());
+            if let Some(cache) = &args.cache {
+                client = client.with_cache(cache.clone(), Duration::from_secs(args.max_cache_age_hours * 60 * 60));
+            }
+            let descriptors = client.fetch().await?;
+            if descriptors.is_empty() {
+                return Err("directory lists no acceptable relays".into());
+            }
+            for descriptor in descriptors {
+                let role = match descriptor.role {
+                    DescriptorRole::Exit => "exit",
+                    DescriptorRole::Middle => "middle",
+                };
+                println!("  {}  {}  {}", role, descriptor.endpoint(), descriptor.identity);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve_directory(args: &DirectoryServeArgs) -> Result<(), Box<dyn Error>> {
+    let _network = args.config.load()?.capabilities.network_token()?;
+    let listener = tokio::net::TcpListener::bind(args.listen)
+        .await
+        .map_err(|source| ConfigError::Bind {
+            addr: args.listen.to_string(),
+            source,
+        })?;
+    println!("Directory listening on {}", listener.local_addr()?);
+    let store = Arc::new(std::sync::Mutex::new(DirectoryStore::default()));
+    tokio::select! {
+        result = relay_directory::serve_directory(listener, store) => result?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    Ok(())
+}
+
+pub async fn resolve(host: &str) -> Result<(), Box<dyn Error>> {
+    let addrs = DohResolver::new().resolve(host).await?;
+    for addr in addrs {
+        println!("{}", addr);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("ebt").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert!(parse(&[]).command.is_none());
+        assert!(matches!(
+            parse(&["relay", "probe", "--config", "ebt.toml"]).command,
+            Some(Command::Relay(RelayCommand::Probe(ConfigArgs { config: Some(_), profile: None })))
+        ));
+        assert!(matches!(
+            parse(&["relay", "serve", "--cert", "relay.pem", "--key", "relay.key", "--max-connections", "64"]).command,
+            Some(Command::Relay(RelayCommand::Serve(args)))
+                if args.listen.port() == 9001 && args.max_connections == Some(64)
+        ));
+        let Some(Command::Relay(RelayCommand::Serve(args))) = parse(&[
+            "relay", "serve", "--stdio", "--max-inflight-opens", "4", "--max-buffered-kib", "512",
+            "--initial-window-kib", "256",
+        ])
+        .command
+        else {
+            panic!("expected relay serve");
+        };
+        let limits = args.limits().unwrap();
+        assert_eq!(
+            (limits.max_inflight_opens, limits.max_buffered_bytes, limits.initial_window),
+            (4, 512 * 1024, 256 * 1024)
+        );
+        assert!(parse(&["relay", "serve", "--stdio", "--initial-window-kib", "32"]).command.is_some_and(
+            |command| matches!(command, Command::Relay(RelayCommand::Serve(args)) if args.limits().is_err())
+        ));
+        let Some(Command::Relay(RelayCommand::Serve(args))) = parse(&[
+            "relay", "serve", "--cert", "c", "--key", "k", "--exit-ports", "443", "--exit-deny", "203.0.113.0/24",
+        ])
+        .command
+        else {
+            panic!("expected relay serve");
+        };
+        let policy = args.exit_policy().unwrap();
+        assert!(policy.destination_opens.is_none());
+        assert!(args.directory.is_none());
+        assert_eq!(args.quotas(), SessionQuotas::unlimited());
+        assert_eq!(args.metrics_listen().unwrap(), None);
+        let metrics = |addr: &str| match parse(&["relay", "serve", "--cert", "c", "--key", "k", "--metrics-listen", addr]).command {
+            Some(Command::Relay(RelayCommand::Serve(args))) => args.metrics_listen(),
+            _ => panic!("expected relay serve"),
+        };
+        assert!(metrics("127.0.0.1:9101").unwrap().is_some());
+        assert!(metrics("0.0.0.0:9101").is_err());
+        assert!(Cli::try_parse_from(["ebt", "relay", "serve", "--cert", "c", "--key", "k", "--directory", "http://d"]).is_err());
+        assert!(matches!(
+            parse(&["relay", "serve", "--stdio", "--max-opens-per-minute", "30"]).command,
+            Some(Command::Relay(RelayCommand::Serve(args))) if args.stdio && args.cert.is_none()
+        ));
+        assert!(Cli::try_parse_from(["ebt", "relay", "serve"]).is_err());
+        assert!(Cli::try_parse_from(["ebt", "relay", "serve", "--stdio", "--listen", "0.0.0.0:9001"]).is_err());
+        assert!(Cli::try_parse_from(["ebt", "relay", "serve", "--stdio", "--state-file", "relay.state"]).is_err());
+        assert!(matches!(
+            parse(&["relay", "serve", "--cert", "c.pem", "--key", "k.pem", "--state-file", "relay.state"]).command,
+            Some(Command::Relay(RelayCommand::Serve(args))) if args.state_interval_secs == 30
+        ));
+        assert!(matches!(
+            parse(&["relay", "fetch", "http://dir.example:9030", "--pin", "ab", "--pin", "cd"]).command,
+            Some(Command::Relay(RelayCommand::Fetch(RelayFetchArgs { pin, max_cache_age_hours: 24, .. }))) if pin.len() == 2
+        ));
+        assert!(matches!(
+            parse(&["directory", "serve"]).command,
+            Some(Command::Directory(DirectoryCommand::Serve(DirectoryServeArgs { listen, .. }))) if listen.port() == 9030
+        ));
+        assert!(matches!(parse(&["kill-switch", "off"]).command, Some(Command::KillSwitch(KillSwitchCommand::Off))));
+        match parse(&["control", "--socket", "/run/ebt.sock", "policy", "off"]).command {
+            Some(Command::Control(args)) => {
+                assert_eq!(args.socket, "/run/ebt.sock");
+                assert!(!args.json);
+                assert_eq!(args.request.request(), ControlRequest::SetContentPolicy { enabled: false });
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            parse(&["ctl", "--socket", "/run/ebt.sock", "dns-cache", "--json"]).command,
+            Some(Command::Control(args)) if args.json && args.request.request() == ControlRequest::DnsCache
+        ));
+        assert_eq!(policy.allowed_ports, vec![443..=443]);
+        assert!(policy.check_addr("203.0.113.9".parse().unwrap()).is_err());
+        assert!(policy.check_addr("127.0.0.1".parse().unwrap()).is_err());
+        assert!(matches!(
+            parse(&["check-config", "--config", "ebt.toml", "--profile", "lan-shared", "--strictness", "strict"])
+                .command,
+            Some(Command::CheckConfig(CheckConfigArgs {
+                config: ConfigArgs { profile: Some(p), .. },
+                strictness: Strictness::Strict,
+            })) if p == "lan-shared"
+        ));
+        assert!(matches!(
+            parse(&["doctor", "--config", "ebt.toml"]).command,
+            Some(Command::Doctor(ConfigArgs { config: Some(path), profile: None })) if path == "ebt.toml"
+        ));
+        assert!(matches!(
+            parse(&["leaktest", "--stun", "192.0.2.3:3478"]).command,
+            Some(Command::Leaktest(args)) if args.stun == "192.0.2.3:3478" && args.proxy.port() == 8080
+        ));
+        assert!(matches!(
+            parse(&["threat-report", "--baseline", "coverage.json"]).command,
+            Some(Command::ThreatReport { baseline: Some(path) }) if path == "coverage.json"
+        ));
+        assert!(matches!(
+            parse(&["capture", "analyze", "frames.log"]).command,
+            Some(Command::Capture(CaptureCommand::Analyze { path })) if path == "frames.log"
+        ));
+        assert!(matches!(
+            parse(&["init"]).command,
+            Some(Command::Init(InitArgs { output, force: false })) if output == "ebt.toml"
+        ));
+        assert!(matches!(
+            parse(&["config", "schema"]).command,
+            Some(Command::Config(ConfigCommand::Schema))
+        ));
+        assert!(matches!(
+            parse(&["resolve", "example.com"]).command,
+            Some(Command::Resolve { host }) if host == "example.com"
+        ));
+        assert!(Cli::try_parse_from(["ebt", "run", "--port", "not-a-port"]).is_err());
+    }
+
+    #[test]
+    fn run_flags_override_config_values() {
+        let args = RunArgs {
+            bind: Some("0.0.0.0".parse().unwrap()),
+            port: Some(3128),
+            rules: Some("ads.txt".to_string()),
+            dry_run: true,
+            ..RunArgs::default()
+        };
+        let config = args.resolve_config().unwrap();
+        assert_eq!(config.proxy_policy.bind_address, "0.0.0.0");
+        assert_eq!(config.proxy_policy.bind_port, 3128);
+        assert!(config.proxy_policy.content_policy_enabled);
+        assert!(config.proxy_policy.content_policy_dry_run);
+
+        let args = RunArgs {
+            dry_run: true,
+            ..RunArgs::default()
+        };
+        assert!(matches!(
+            args.resolve_config(),
+            Err(ConfigError::Invalid { field, .. }) if field == "--dry-run"
+        ));
+    }
+}
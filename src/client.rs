@@ -1,3 +1,5 @@
+use crate::transport::TransportError;
+
 /// Client device component - represents the browser/application side
 #[derive(Clone)]
 pub struct Client {
@@ -25,7 +27,7 @@ impl Client {
         }
     }
     
-    pub async fn connect(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn connect(&self) -> Result<(), TransportError> {
         println!("Client connecting via {:?}", self.proxy_config.proxy_type);
         Ok(())
     }
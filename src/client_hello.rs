@@ -0,0 +1,200 @@
+//! Server name of a tunneled TLS connection, read from the client's
+//! ClientHello before the tunnel is spliced. A CONNECT to an IP literal
+//! names no host, so domain rules would never see it; with
+//! `policy.inspect_sni` the proxy edge peeks at the first bytes the client
+//! sends, without consuming or decrypting anything, and evaluates the
+//! policy again for the name in the `server_name` extension (RFC 6066).
+//! Only that name is read, and only at the proxy edge (Phase 7.5).
+
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+const RECORD_HEADER_LEN: usize = 5;
+/// Largest TLS plaintext record, RFC 8446 §5.1, with its header.
+const MAX_RECORD: usize = RECORD_HEADER_LEN + (1 << 14);
+/// Between peeks at a ClientHello that has only partly arrived.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientHello {
+    /// The `server_name` extension's host name.
+    ServerName(String),
+    /// A complete ClientHello without a host name.
+    NoServerName,
+    /// More bytes are needed.
+    Incomplete,
+    /// The bytes are not a TLS ClientHello.
+    NotTls,
+}
+
+/// Reads the server name from the start of a client's first TLS record.
+pub fn parse(bytes: &[u8]) -> ClientHello {
+    let mut record = Reader(bytes);
+    let Some(header) = record.take(RECORD_HEADER_LEN) else {
+        return ClientHello::Incomplete;
+    };
+    if header[0] != CONTENT_TYPE_HANDSHAKE || header[1] != 0x03 {
+        return ClientHello::NotTls;
+    }
+    let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let Some(fragment) = record.take(length) else {
+        return ClientHello::Incomplete;
+    };
+    // A ClientHello spanning records is only read as far as the first goes;
+    // the server name comes early, after the ciphers.
+    let mut hello = Reader(fragment);
+    match hello.u8() {
+        Some(HANDSHAKE_CLIENT_HELLO) => {}
+        _ => return ClientHello::NotTls,
+    }
+    server_name(&mut hello).unwrap_or(ClientHello::NotTls)
+}
+
+/// `None` where the ClientHello ends early.
+fn server_name(hello: &mut Reader) -> Option<ClientHello> {
+    hello.take(3)?; // handshake length
+    hello.take(2 + 32)?; // legacy version, random
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let ciphers = hello.u16()? as usize;
+    hello.take(ciphers)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+    let Some(length) = hello.u16() else {
+        return Some(ClientHello::NoServerName);
+    };
+    let mut extensions = Reader(hello.take(length as usize)?);
+    while let Some(kind) = extensions.u16() {
+        let length = extensions.u16()? as usize;
+        let body = extensions.take(length)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(body);
+        let length = list.u16()? as usize;
+        let mut names = Reader(list.take(length)?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(|name| ClientHello::ServerName(name.to_string()));
+            }
+        }
+    }
+    Some(ClientHello::NoServerName)
+}
+
+/// Peeks at `stream` until its first TLS record has arrived or `timeout`
+/// passes, leaving every byte for the tunnel. Restores the stream's read
+/// timeout.
+pub fn peek_server_name(stream: &TcpStream, timeout: Duration) -> Option<String> {
+    let previous = stream.read_timeout().ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0u8; MAX_RECORD];
+    let name = loop {
+        let Ok(n) = stream.peek(&mut buffer) else {
+            break None;
+        };
+        match parse(&buffer[..n]) {
+            ClientHello::ServerName(name) => break Some(name),
+            // peek returns at once while any bytes are queued
+            ClientHello::Incomplete if n > 0 && Instant::now() < deadline => std::thread::sleep(PEEK_INTERVAL),
+            _ => break None,
+        }
+    };
+    let _ = stream.set_read_timeout(previous);
+    name
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// A ClientHello record with `extensions` after one cipher suite.
+#[cfg(test)]
+pub(crate) fn client_hello_record(extensions: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[7; 32]);
+    body.push(0); // session id
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]); // null compression
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(extensions);
+    let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// A `server_name` extension for `name`.
+#[cfg(test)]
+pub(crate) fn server_name_extension(name: &str) -> Vec<u8> {
+    let mut entry = vec![NAME_TYPE_HOST_NAME];
+    entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    entry.extend_from_slice(name.as_bytes());
+    let mut extension = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+    extension.extend_from_slice(&(entry.len() as u16 + 2).to_be_bytes());
+    extension.extend_from_slice(&(entry.len() as u16).to_be_bytes());
+    extension.extend_from_slice(&entry);
+    extension
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_server_name_among_other_extensions() {
+        // supported_versions, then server_name
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&server_name_extension("ads.example.com"));
+        let record = client_hello_record(&extensions);
+        assert_eq!(parse(&record), ClientHello::ServerName("ads.example.com".to_string()));
+
+        let mut with_data = record.clone();
+        with_data.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x01, 0xff]);
+        assert_eq!(parse(&with_data), ClientHello::ServerName("ads.example.com".to_string()));
+        assert_eq!(parse(&client_hello_record(&[0x00, 0x2b, 0x00, 0x00])), ClientHello::NoServerName);
+    }
+
+    #[test]
+    fn waits_for_partial_records_and_refuses_other_protocols() {
+        let record = client_hello_record(&server_name_extension("example.com"));
+        for cut in [0, 3, RECORD_HEADER_LEN, record.len() - 1] {
+            assert_eq!(parse(&record[..cut]), ClientHello::Incomplete, "cut at {}", cut);
+        }
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n"), ClientHello::NotTls);
+        let mut server_hello = record.clone();
+        server_hello[RECORD_HEADER_LEN] = 0x02;
+        assert_eq!(parse(&server_hello), ClientHello::NotTls);
+        // A server_name list longer than its extension
+        let mut broken = server_name_extension("example.com");
+        broken[5] += 1;
+        assert_eq!(parse(&client_hello_record(&broken)), ClientHello::NotTls);
+    }
+}
@@ -1,6 +1,19 @@
 
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::content_policy::ReasonCode;
+use crate::content_policy_bootstrap::ClientSubnet;
+use crate::logging::LogConfig;
+use crate::memory_profile::MemoryProfile;
+
 /// Execution mode controlling what the program is allowed to do
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum ExecutionMode {
     Conceptual,
     RealNetwork,
@@ -13,50 +26,180 @@ pub enum Capability {
     RealNetworking,
 }
 
+/// Configuration or capability mismatch detected at runtime.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Required capability {0:?} not available")]
+    MissingCapability(Capability),
+    #[error("cannot bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Proxy server not bound")]
+    NotBound,
+    #[error("cannot read config file {path}: {source}")]
+    Read {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("cannot parse config file {path}: {source}")]
+    Parse {
+        path: String,
+        #[source]
+        source: toml::de::Error,
+    },
+    /// A value that parsed but is out of range or conflicts with another.
+    #[error("{field}: {reason}")]
+    Invalid { field: String, reason: String },
+    /// A reload changed settings that only take effect at start-up.
+    #[error("{settings} changed; restart to apply (rebinding would drop established tunnels)")]
+    RequiresRestart { settings: String },
+}
+
 /// Policy binding execution mode to allowed capabilities
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CapabilityPolicy {
     pub execution_mode: ExecutionMode,
     pub allowed_capabilities: Vec<Capability>,
 }
 
+impl CapabilityPolicy {
+    /// No networking; sessions only exercise the conceptual flow.
+    pub fn conceptual() -> Self {
+        Self {
+            execution_mode: ExecutionMode::Conceptual,
+            allowed_capabilities: vec![Capability::NoNetworking],
+        }
+    }
+
+    /// Real connections, proxies and DNS lookups.
+    pub fn real_network() -> Self {
+        Self {
+            execution_mode: ExecutionMode::RealNetwork,
+            allowed_capabilities: vec![Capability::RealNetworking],
+        }
+    }
+
+    /// The token every socket-opening constructor requires. Only issued in
+    /// `RealNetwork` mode with `RealNetworking` allowed.
+    pub fn network_token(&self) -> Result<NetworkToken, ConfigError> {
+        let allowed = self.execution_mode == ExecutionMode::RealNetwork
+            && self.allowed_capabilities.contains(&Capability::RealNetworking);
+        if allowed {
+            Ok(NetworkToken { _private: () })
+        } else {
+            Err(ConfigError::MissingCapability(Capability::RealNetworking))
+        }
+    }
+}
+
+/// Proof that the capability policy allows real networking. It cannot be
+/// built outside this module, so holding one means the policy check ran.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkToken {
+    _private: (),
+}
+
+/// Builder state for a required field that has not been set; `build` is
+/// only available once every required field is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unset;
+
 /// Top-level tunnel configuration for production deployment
 #[derive(Debug, Clone)]
 pub struct TunnelConfig {
+    /// Whether this process may touch the network at all.
+    pub capabilities: CapabilityPolicy,
     pub transport: TransportConfig,
     pub dns_policy: DnsPolicy,
     pub proxy_policy: ProxyPolicy,
+    pub relay: RelayConfig,
+    pub anonymity: AnonymityConfig,
+    pub observability: ObservabilityConfig,
 }
 
 impl TunnelConfig {
+    /// Loads and validates a TOML configuration file. Omitted sections and
+    /// keys keep their `ssh_socks_profile` values.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::from_file_with_profile(path, None)
+    }
+
+    /// `from_file` with the named `[profiles.<name>]` layered over the base
+    /// sections; `None` applies the `default` profile if the file has one.
+    pub fn from_file_with_profile(path: impl AsRef<Path>, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let path = path.as_ref().display().to_string();
+        let text = std::fs::read_to_string(&path).map_err(|source| ConfigError::Read {
+            path: path.clone(),
+            source,
+        })?;
+        crate::config_file::parse_profile(&text, &path, profile)
+    }
+
+    /// `ssh_socks_profile` with `EBT_<SECTION>__<KEY>` overrides applied.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        crate::config_file::parse("", "environment")
+    }
+
+
     /// Creates a TunnelConfig matching current conceptual behavior
     pub fn ssh_socks_profile() -> Self {
         Self {
+            capabilities: CapabilityPolicy::real_network(),
             transport: TransportConfig {
                 kind: TransportKind::Ssh,
                 proxy_host: "relay.example.com".to_string(),
                 proxy_port: 22,
                 target_host: "target.example.com".to_string(),
                 target_port: 443,
+                connect_retry: ConnectRetry::default(),
             },
             dns_policy: DnsPolicy {
                 resolution_location: ResolutionLocation::Remote,
                 leak_detection: LeakDetection::Warn,
+                doh_providers: vec![crate::dns_resolver::DEFAULT_DOH_PROVIDER.to_string()],
+                prefetch: Vec::new(),
             },
             proxy_policy: ProxyPolicy {
                 mode: ProxyMode::Application,
+                configure_system: false,
+                kill_switch: false,
+                control_socket: None,
                 bind_address: "127.0.0.1".to_string(),
                 bind_port: 8080,
                 authentication: None,
                 content_policy_enabled: false,
                 content_policy_rules: None,
+                content_policy_custom_rules: None,
+                content_policy_refresh_interval: None,
+                content_policy_rulesets: Vec::new(),
+                content_policy_assignments: Vec::new(),
+                content_policy_block_behavior: BlockBehavior::default(),
+                content_policy_time_zone: None,
+                content_policy_dry_run: false,
+                content_policy_inspect_sni: false,
+                usage_summary: None,
+                traffic_accounting: None,
+                // Restore higher global concurrency for asset-heavy sites
+                max_concurrent_tunnels: MemoryProfile::active().max_concurrent_tunnels,
+                adaptive_tunnel_limit: None,
+                header_limits: HeaderLimits::default(),
+                proxy_protocol: Vec::new(),
+                traffic_shaping: false,
+                udp: UdpMode::default(),
             },
+            relay: RelayConfig::default(),
+            anonymity: AnonymityConfig::default(),
+            observability: ObservabilityConfig::default(),
         }
     }
 }
 
 /// Transport configuration describing encrypted transport intent
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TransportConfig {
     pub kind: TransportKind,
     
@@ -67,10 +210,40 @@ pub struct TransportConfig {
     // target endpoint
     pub target_host: String,
     pub target_port: u16,
+
+    /// How hard each CONNECT tries to reach its destination.
+    pub connect_retry: ConnectRetry,
+}
+
+/// Connect attempts one CONNECT may spend on each of its destination's
+/// addresses. A timed-out attempt is retried after a jittered, doubling backoff; a
+/// refused or reset one moves on to the next address at once, since
+/// retrying an answer rarely changes it. Applies to direct connections;
+/// relay builds dial their relay instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRetry {
+    /// Attempts on each of a destination's addresses, the first included.
+    pub attempts: u32,
+    pub attempt_timeout: Duration,
+    /// Delay before the first retry; each later one doubles it.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            attempt_timeout: Duration::from_secs(2),
+            backoff: Duration::from_millis(150),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
 }
 
 /// Transport kinds matching existing Transport enum variants
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum TransportKind {
     Ssh,
     Tls,
@@ -82,17 +255,31 @@ pub enum TransportKind {
 pub struct DnsPolicy {
     pub resolution_location: ResolutionLocation,
     pub leak_detection: LeakDetection,
+    /// DNS-over-HTTPS endpoints, tried in order.
+    pub doh_providers: Vec<String>,
+    /// Hosts whose CONNECTs warm DNS for their companions; none by default.
+    pub prefetch: Vec<PrefetchHint>,
+}
+
+/// Companions of `host`, e.g. its CDNs, resolved in the background when a
+/// CONNECT for `host` or one of its subdomains is allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchHint {
+    pub host: String,
+    pub companions: Vec<String>,
 }
 
 /// Where DNS resolution should occur
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum ResolutionLocation {
     Local,
     Remote,
 }
 
 /// DNS leak detection enforcement level
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum LeakDetection {
     Strict,
     Warn,
@@ -103,38 +290,725 @@ pub enum LeakDetection {
 #[derive(Debug, Clone)]
 pub struct ProxyPolicy {
     pub mode: ProxyMode,
+    /// Point the OS proxy settings at this listener while it runs;
+    /// only with `ProxyMode::System`.
+    pub configure_system: bool,
+    /// Firewall off everything but the relays while this listener runs;
+    /// see `kill_switch`.
+    pub kill_switch: bool,
+    /// Unix socket path, or `\\.\pipe\<name>` on Windows, serving the
+    /// JSON control protocol; see `control_socket`.
+    pub control_socket: Option<String>,
     pub bind_address: String,
     pub bind_port: u16,
     pub authentication: Option<AuthenticationPlaceholder>,
     /// Phase 7.5 FROZEN: no auto-enablement, no learning/inference, proxy-edge only.
     pub content_policy_enabled: bool,
-    /// Phase 7.5 FROZEN: no auto-enablement, no dynamic reloads, proxy-edge only.
+    /// Phase 7.5 FROZEN: no auto-enablement, proxy-edge only.
+    /// Local path or https URL of an EasyList-format rules list.
     pub content_policy_rules: Option<String>,
+    /// Local rules file in the native format; reloaded when it changes on disk.
+    pub content_policy_custom_rules: Option<String>,
+    /// Re-download interval for an https `content_policy_rules` source; `None` disables refresh.
+    pub content_policy_refresh_interval: Option<Duration>,
+    /// Named rules lists, each loaded once and shared by assignments.
+    pub content_policy_rulesets: Vec<NamedRuleset>,
+    /// Which named rulesets apply to this listener and to specific client subnets.
+    pub content_policy_assignments: Vec<RulesetAssignment>,
+    /// How blocked requests are answered, per reason code.
+    pub content_policy_block_behavior: BlockBehavior,
+    /// IANA zone, e.g. `Europe/Berlin`, for rules with active hours; the
+    /// machine's own zone when unset.
+    pub content_policy_time_zone: Option<String>,
+    /// Evaluate rules and record would-be blocks, but allow every request.
+    pub content_policy_dry_run: bool,
+    /// Evaluate the policy again for the TLS server name of CONNECTs to IP
+    /// literals, read from the ClientHello before splicing.
+    pub content_policy_inspect_sni: bool,
+    /// Periodic aggregate usage summary; `None` (the default) writes nothing.
+    pub usage_summary: Option<UsageSummaryConfig>,
+    /// Per-day traffic totals kept across restarts; `None` (the default) keeps none.
+    pub traffic_accounting: Option<TrafficAccountingConfig>,
+    /// Tunnels forwarded at once; further connections wait for a slot.
+    pub max_concurrent_tunnels: usize,
+    /// Moves the tunnel limit with load, starting from
+    /// `max_concurrent_tunnels`; `None` keeps it fixed.
+    pub adaptive_tunnel_limit: Option<AdaptiveTunnelLimit>,
+    /// Caps on each HTTP request head this listener reads.
+    pub header_limits: HeaderLimits,
+    /// Load balancers whose connections start with a PROXY v2 header, by
+    /// address block. Their client address stands for the peer's; other
+    /// peers are refused. Empty disables the header.
+    pub proxy_protocol: Vec<ClientSubnet>,
+    /// Phase 5 size bucketing on this listener's client-to-upstream writes.
+    /// Padding changes the byte stream, so it only suits upstreams that
+    /// strip it; requires the `phase_5_traffic_shaping` feature.
+    pub traffic_shaping: bool,
+    /// SOCKS5 `UDP ASSOCIATE` on this listener; see `socks5`.
+    pub udp: UdpMode,
 }
 
 impl Default for ProxyPolicy {
     fn default() -> Self {
         Self {
             mode: ProxyMode::Application,
+            configure_system: false,
+            kill_switch: false,
+            control_socket: None,
             bind_address: "127.0.0.1".to_string(),
             bind_port: 8080,
             authentication: None,
             content_policy_enabled: false,
             content_policy_rules: None,
+            content_policy_custom_rules: None,
+            content_policy_refresh_interval: None,
+            content_policy_rulesets: Vec::new(),
+            content_policy_assignments: Vec::new(),
+            content_policy_block_behavior: BlockBehavior::default(),
+            content_policy_time_zone: None,
+            content_policy_dry_run: false,
+            content_policy_inspect_sni: false,
+            usage_summary: None,
+            traffic_accounting: None,
+            max_concurrent_tunnels: MemoryProfile::active().max_concurrent_tunnels,
+            adaptive_tunnel_limit: None,
+            header_limits: HeaderLimits::default(),
+            proxy_protocol: Vec::new(),
+            traffic_shaping: false,
+            udp: UdpMode::default(),
+        }
+    }
+}
+
+/// Caps on an HTTP request head, checked as it is read so a client cannot
+/// make the proxy buffer without bound before `\r\n\r\n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    /// Longest request line, without its CRLF; longer ones get 414. At most
+    /// `MAX_REQUEST_LINE`, the most the CONNECT parser accepts.
+    pub max_request_line: usize,
+    /// Most header fields after the request line; more get 431.
+    pub max_header_count: usize,
+    /// Most bytes in the whole head, final blank line included; more get 431.
+    pub max_header_bytes: usize,
+    /// Time for the whole head to arrive, however steadily it trickles in.
+    pub header_read_timeout: Duration,
+    /// Slowest average a head may arrive at once `header_rate_grace` has
+    /// passed; 0 disables the check.
+    pub min_header_rate: usize,
+    pub header_rate_grace: Duration,
+}
+
+impl HeaderLimits {
+    pub const MAX_REQUEST_LINE: usize = crate::authority::MAX_REQUEST_LINE;
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: Self::MAX_REQUEST_LINE,
+            max_header_count: 64,
+            max_header_bytes: 16 * 1024,
+            header_read_timeout: Duration::from_secs(30),
+            min_header_rate: 64,
+            header_rate_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Bounds and signals of an adaptive tunnel limit. Each `interval` the
+/// limit shrinks by a tenth while available memory is under
+/// `min_available_memory` or more than `max_failure_percent` of the tunnels
+/// closed in the interval failed upstream, and grows by about its square
+/// root while nearly every slot is taken; otherwise it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveTunnelLimit {
+    pub min: usize,
+    pub max: usize,
+    pub interval: Duration,
+    /// Bytes the OS should still report as available; Linux only.
+    pub min_available_memory: usize,
+    pub max_failure_percent: u8,
+}
+
+impl AdaptiveTunnelLimit {
+    /// Bounds from a quarter of `start` to four times it.
+    pub fn around(start: usize) -> Self {
+        Self {
+            min: (start / 4).max(1),
+            max: start.saturating_mul(4),
+            interval: Duration::from_secs(5),
+            min_available_memory: if cfg!(feature = "embedded") { 8 } else { 64 } * 1024 * 1024,
+            max_failure_percent: 20,
+        }
+    }
+}
+
+/// Response sent to the client when the content policy blocks a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockResponse {
+    /// Bare `403 Forbidden`.
+    Forbidden,
+    /// `403 Forbidden` whose `X-EBT-Reason` names the reason code, as
+    /// `policy-blocked; category=ads`.
+    ForbiddenWithReason,
+    /// `ForbiddenWithReason` with a small local HTML page, for plain HTTP
+    /// requests; CONNECT gets no page, as browsers hide its body.
+    BlockPage,
+    /// Close the connection with a TCP reset and no response.
+    Reset,
+}
+
+/// Block response selected per reason code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockBehavior {
+    pub ads: BlockResponse,
+    pub tracking: BlockResponse,
+    pub malware: BlockResponse,
+    pub custom: BlockResponse,
+    pub unknown: BlockResponse,
+}
+
+impl BlockBehavior {
+    pub fn uniform(response: BlockResponse) -> Self {
+        Self {
+            ads: response,
+            tracking: response,
+            malware: response,
+            custom: response,
+            unknown: response,
+        }
+    }
+}
+
+impl Default for BlockBehavior {
+    fn default() -> Self {
+        Self::uniform(BlockResponse::Forbidden)
+    }
+}
+
+/// Where and how often aggregate usage lines are appended. Lines carry
+/// counts only, never destinations or client addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageSummaryConfig {
+    pub path: String,
+    pub interval: Duration,
+    /// The file is rotated to `<path>.1` before a line would push it past this size.
+    pub max_file_bytes: u64,
+    /// Rotated files kept as `<path>.1` .. `<path>.N`; older ones are deleted.
+    pub keep_files: usize,
+}
+
+impl UsageSummaryConfig {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            interval: Duration::from_secs(15 * 60),
+            max_file_bytes: 1024 * 1024,
+            keep_files: 4,
+        }
+    }
+}
+
+/// Where cumulative per-day tunnel and byte counts are stored, and how
+/// often they are saved. Days are UTC; no destinations are recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficAccountingConfig {
+    pub path: String,
+    /// Counts are also saved at shutdown.
+    pub save_interval: Duration,
+    /// Days older than this are dropped when the store is saved.
+    pub keep_days: u32,
+}
+
+impl TrafficAccountingConfig {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            save_interval: Duration::from_secs(5 * 60),
+            keep_days: 400,
+        }
+    }
+}
+
+/// Relay topology. Must match the relay features the binary was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayMode {
+    Direct,
+    SingleHop,
+    MultiHop,
+}
+
+impl RelayMode {
+    /// The mode selected by this build's relay features.
+    pub const fn compiled() -> Self {
+        if cfg!(feature = "multi_hop_relay") {
+            RelayMode::MultiHop
+        } else if cfg!(feature = "single_hop_relay") {
+            RelayMode::SingleHop
+        } else {
+            RelayMode::Direct
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayMode::Direct => "direct",
+            RelayMode::SingleHop => "single_hop",
+            RelayMode::MultiHop => "multi_hop",
+        }
+    }
+}
+
+/// One relay. Hostnames are resolved over DoH, never the system resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub transport: TransportKind,
+    /// Expected SHA-256 fingerprint of the relay's key, lowercase hex.
+    pub fingerprint: Option<String>,
+    /// Launched instead of dialing `host:port`; the relay protocol then
+    /// runs over its stdin and stdout. See `relay_stdio`.
+    pub command: Option<Vec<String>>,
+    /// Offers `CAP_CHECKSUM`, so frames carry a CRC32C both ways when the
+    /// relay agrees. Meant for `command` carriers, which add no integrity.
+    pub checksums: bool,
+    /// The exit's DNS key, 64 lowercase hex digits. With it destinations
+    /// are resolved at this relay, when it is the exit, over a lookup
+    /// sealed to the key, and opened by address. See `exit_dns`.
+    pub dns_key: Option<String>,
+}
+
+impl RelayEndpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            transport: TransportKind::Tls,
+            fingerprint: None,
+            command: None,
+            checksums: false,
+            dns_key: None,
+        }
+    }
+}
+
+impl From<SocketAddr> for RelayEndpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl std::fmt::Display for RelayEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// How each session's hops are drawn from `RelayConfig::endpoints`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RelaySelection {
+    /// Every session uses the endpoints in configured order.
+    #[default]
+    FixedChain,
+    /// Every session draws its own hops.
+    RandomPerSession,
+    /// Hops are drawn once per `RelayConfig::epoch` and shared by the
+    /// sessions opened during it.
+    PerEpoch,
+}
+
+impl RelaySelection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelaySelection::FixedChain => "fixed_chain",
+            RelaySelection::RandomPerSession => "random_per_session",
+            RelaySelection::PerEpoch => "per_epoch",
+        }
+    }
+}
+
+/// What keeps sessions apart under `RelaySelection::PerEpoch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IsolateBy {
+    /// Each CONNECT `host:port` gets its own chain, so no exit sees more
+    /// than one destination of the user.
+    Destination,
+    /// Each listener port gets its own chain, e.g. one per browser
+    /// container when listeners share a `RelaySelector`.
+    Listener,
+}
+
+/// Sessions share a per-epoch chain only if they agree on every enabled
+/// key. Nothing enabled means one chain per epoch for everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamIsolation {
+    pub destination: bool,
+    pub listener: bool,
+}
+
+impl StreamIsolation {
+    pub fn by(keys: &[IsolateBy]) -> Self {
+        Self {
+            destination: keys.contains(&IsolateBy::Destination),
+            listener: keys.contains(&IsolateBy::Listener),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.destination || self.listener
+    }
+}
+
+/// Relay endpoints, in hop order for a fixed multi-hop chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayConfig {
+    pub mode: RelayMode,
+    pub endpoints: Vec<RelayEndpoint>,
+    pub selection: RelaySelection,
+    /// Relays per session in multi-hop mode; single-hop always uses one.
+    pub hops: usize,
+    /// Only used by `RelaySelection::PerEpoch`.
+    pub epoch: Duration,
+    /// Keeps the first hop of random selections; `None` draws it each time.
+    pub guard: Option<GuardConfig>,
+    /// Only used by `RelaySelection::PerEpoch`.
+    pub isolation: StreamIsolation,
+    /// Probes relays and keeps failing ones out of random selections;
+    /// `None` disables probing.
+    pub health_check: Option<RelayHealthConfig>,
+}
+
+/// See `relay_health`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayHealthConfig {
+    pub interval: Duration,
+    /// Per probe connect timeout.
+    pub timeout: Duration,
+    /// Consecutive failed probes that exclude a relay.
+    pub failure_threshold: u32,
+    /// How long an excluded relay sits out, unless a probe succeeds first.
+    pub exclusion: Duration,
+}
+
+impl Default for RelayHealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            failure_threshold: 3,
+            exclusion: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Entry guard for randomly selected relays; see `anonymity::guard`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuardConfig {
+    /// File the guard is kept in across restarts.
+    pub state_path: String,
+    /// How long a guard is kept before a new one is drawn.
+    pub lifetime: Duration,
+}
+
+impl RelayConfig {
+    /// Number of relays each session goes through.
+    pub fn chain_length(&self) -> usize {
+        match self.mode {
+            RelayMode::Direct => 0,
+            RelayMode::SingleHop => 1,
+            RelayMode::MultiHop => self.hops,
         }
     }
 }
 
-/// How the proxy should be exposed
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            mode: RelayMode::compiled(),
+            endpoints: Vec::new(),
+            selection: RelaySelection::FixedChain,
+            hops: 0,
+            epoch: Duration::from_secs(10 * 60),
+            guard: None,
+            isolation: StreamIsolation::default(),
+            health_check: None,
+        }
+    }
+}
+
+/// Per-frame delay of the mix queue; see `anonymity::delay`. Uniform
+/// delays are easy to tell apart from natural traffic; `Poisson` and
+/// `LogNormal` look more like it. Samples above `max` are clamped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixDelay {
+    Uniform { min: Duration, max: Duration },
+    /// Exponential delays around `mean`, i.e. Poisson-process releases.
+    Poisson { mean: Duration, max: Duration },
+    /// Heavy-tailed delays; `sigma` is the standard deviation of the
+    /// delay's natural logarithm.
+    LogNormal { median: Duration, sigma: f64, max: Duration },
+}
+
+impl MixDelay {
+    /// Upper clamp of every sample.
+    pub fn max(&self) -> Duration {
+        match *self {
+            MixDelay::Uniform { max, .. } | MixDelay::Poisson { max, .. } | MixDelay::LogNormal { max, .. } => max,
+        }
+    }
+}
+
+impl Default for MixDelay {
+    fn default() -> Self {
+        MixDelay::Uniform {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Constant-rate shaping: one `cell_size`-byte cell every `interval`
+/// whether or not there is data to send. Bandwidth is spent on padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConstantRate {
+    pub cell_size: usize,
+    pub interval: Duration,
+}
+
+/// Weighted delay bins for adaptive padding. Bin `i` spans
+/// `edges[i - 1]..edges[i]`, starting from zero; `infinity` weighs the
+/// choice of sending no dummy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaddingHistogramConfig {
+    pub edges: Vec<Duration>,
+    pub weights: Vec<u32>,
+    pub infinity: u32,
+}
+
+/// WTF-PAD style padding; see `anonymity::adaptive_padding`. `burst` draws
+/// how long to wait for real traffic before sending a dummy, `gap` the
+/// spacing of the dummies that follow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdaptivePaddingConfig {
+    pub burst: PaddingHistogramConfig,
+    pub gap: PaddingHistogramConfig,
+}
+
+impl Default for AdaptivePaddingConfig {
+    fn default() -> Self {
+        let ms = |values: &[u64]| values.iter().map(|&n| Duration::from_millis(n)).collect();
+        Self {
+            burst: PaddingHistogramConfig {
+                edges: ms(&[5, 20, 50, 200]),
+                weights: vec![2, 6, 6, 2],
+                infinity: 4,
+            },
+            gap: PaddingHistogramConfig {
+                edges: ms(&[10, 50, 100]),
+                weights: vec![4, 8, 4],
+                infinity: 8,
+            },
+        }
+    }
+}
+
+/// RTT feedback on the mix delay; see `anonymity::delay_budget`. Delays
+/// shrink while the measured round trip exceeds `target_rtt` and recover
+/// once it is back under, but no delay is ever below `floor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelayBudgetConfig {
+    pub floor: Duration,
+    pub target_rtt: Duration,
+}
+
+/// Named bundles of the anonymity settings, from no shaping to the most
+/// cover traffic and delay. Each level trades latency and bandwidth for
+/// resistance to timing correlation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ShapingProfile {
+    /// Token mixing delays only; no cover traffic.
+    Off,
+    /// Short Poisson delays and adaptive padding, budgeted against the RTT.
+    LowLatency,
+    /// Heavy-tailed delays and adaptive padding, budgeted against the RTT.
+    Balanced,
+    /// Long heavy-tailed delays behind constant-rate cells, short path
+    /// epochs, and no delay budget.
+    Paranoid,
+}
+
+impl ShapingProfile {
+    pub const ALL: [ShapingProfile; 4] = [
+        ShapingProfile::Off,
+        ShapingProfile::LowLatency,
+        ShapingProfile::Balanced,
+        ShapingProfile::Paranoid,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShapingProfile::Off => "off",
+            ShapingProfile::LowLatency => "low_latency",
+            ShapingProfile::Balanced => "balanced",
+            ShapingProfile::Paranoid => "paranoid",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|profile| profile.as_str() == name)
+    }
+
+    /// The bundled settings, tagged with this profile.
+    pub fn anonymity(&self) -> AnonymityConfig {
+        let ms = Duration::from_millis;
+        let (mix_delay, constant_rate, adaptive_padding, delay_budget) = match self {
+            ShapingProfile::Off => (MixDelay::Uniform { min: ms(1), max: ms(5) }, None, None, None),
+            ShapingProfile::LowLatency => (
+                MixDelay::Poisson { mean: ms(10), max: ms(50) },
+                None,
+                Some(AdaptivePaddingConfig::default()),
+                Some(DelayBudgetConfig { floor: ms(2), target_rtt: ms(250) }),
+            ),
+            ShapingProfile::Balanced => (
+                MixDelay::LogNormal { median: ms(40), sigma: 0.8, max: ms(500) },
+                None,
+                Some(AdaptivePaddingConfig::default()),
+                Some(DelayBudgetConfig { floor: ms(10), target_rtt: ms(600) }),
+            ),
+            ShapingProfile::Paranoid => (
+                MixDelay::LogNormal { median: ms(150), sigma: 1.0, max: ms(2_000) },
+                Some(ConstantRate { cell_size: 512, interval: ms(20) }),
+                None,
+                None,
+            ),
+        };
+        AnonymityConfig {
+            profile: Some(*self),
+            mix_delay,
+            constant_rate,
+            adaptive_padding,
+            delay_budget,
+        }
+    }
+
+    /// Longest lifetime of an anonymity path before it is rotated; see
+    /// `anonymity::profile::ProfileEpochs`.
+    pub fn epoch(&self) -> Duration {
+        match self {
+            ShapingProfile::Off | ShapingProfile::LowLatency => Duration::from_secs(10 * 60),
+            ShapingProfile::Balanced => Duration::from_secs(5 * 60),
+            ShapingProfile::Paranoid => Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// Anonymity-phase tuning.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnonymityConfig {
+    /// Profile the settings below started from, if any; individual
+    /// settings may since have been overridden.
+    pub profile: Option<ShapingProfile>,
+    pub mix_delay: MixDelay,
+    /// Off unless set; see `traffic_shaping::ConstantRateShaper`.
+    pub constant_rate: Option<ConstantRate>,
+    /// Off unless set.
+    pub adaptive_padding: Option<AdaptivePaddingConfig>,
+    /// Off unless set: `mix_delay` is then used as configured.
+    pub delay_budget: Option<DelayBudgetConfig>,
+}
+
+/// Runtime observability overrides. The observability level itself is fixed
+/// at compile time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObservabilityConfig {
+    /// Replaces the `EBT_LOG`/`EBT_LOG_FORMAT` configuration when set.
+    pub log: Option<LogConfig>,
+    /// Desktop notification classes; all off by default.
+    pub notifications: NotificationConfig,
+}
+
+/// Which events raise a desktop notification; see `desktop_notifications`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotificationConfig {
+    /// Health reaching FAULTED.
+    pub health_faulted: bool,
+    /// Relay reconnects arriving faster than a healthy connection would.
+    pub reconnect_storm: bool,
+    /// The kill switch engaging.
+    pub kill_switch: bool,
+}
+
+impl NotificationConfig {
+    pub fn any(&self) -> bool {
+        self.health_faulted || self.reconnect_storm || self.kill_switch
+    }
+}
+
+/// A rules list referenced by name, e.g. "ads", "tracking", "corporate-custom".
+#[derive(Debug, Clone)]
+pub struct NamedRuleset {
+    pub name: String,
+    /// Local path or https URL of an EasyList-format rules list.
+    pub location: String,
+    /// Reason code the list's block rules report, and so which
+    /// `BlockBehavior` response they get.
+    pub category: ReasonCode,
+}
+
+/// Connections a `RulesetAssignment` applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RulesetScope {
+    /// Every connection accepted on this listener.
+    Listener,
+    /// A single client address ("192.168.1.20") or a CIDR block
+    /// ("192.168.1.0/24"). The most specific matching scope wins.
+    ClientSubnet(String),
+}
+
+/// Named rulesets evaluated in order for a scope; an empty list disables filtering.
 #[derive(Debug, Clone)]
+pub struct RulesetAssignment {
+    pub scope: RulesetScope,
+    pub rulesets: Vec<String>,
+}
+
+/// How the proxy should be exposed: to applications configured to use
+/// it, or to the whole system through the OS proxy settings.
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum ProxyMode {
     System,
     Application,
 }
 
+/// What the proxy does with SOCKS5 `UDP ASSOCIATE`, which browsers use
+/// for QUIC and so HTTP/3.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpMode {
+    /// Carry datagrams the way tunnels go: directly, or through the relays.
+    #[default]
+    Tunnel,
+    /// Refuse associations, so browsers fall back to TCP.
+    Block,
+}
+
 /// Authentication configuration placeholder
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AuthenticationPlaceholder {
     pub enabled: bool,
     pub method: String,
+    /// Expected `Proxy-Authorization` header value; compared in constant time.
+    pub credential: Option<String>,
 }
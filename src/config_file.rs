@@ -0,0 +1,2152 @@
+//! TOML configuration file behind `TunnelConfig::from_file`. The file is
+//! read into the raw sections below, then validated and applied on top of
+//! `TunnelConfig::ssh_socks_profile`. Unknown keys are rejected so a typo
+//! fails loudly instead of silently keeping a default.
+//!
+//! Every key can be overridden from the environment as `EBT_<SECTION>__<KEY>`,
+//! e.g. `EBT_PROXY__BIND_PORT=9090` or `EBT_PROXY__AUTHENTICATION__CREDENTIAL`.
+//! Values are read as TOML (`9090`, `true`, `["203.0.113.7:443"]`) unless the
+//! key already holds a string; anything that does not parse is a string, so
+//! quote values like `"12345"` that must stay strings.
+//!
+//! Named profiles live under `[profiles.<name>]` and hold the same sections.
+//! The selected profile (`--profile`, `EBT_PROFILE`) is merged key by key
+//! over the base sections; a profile named `default` applies when none is
+//! selected. Environment overrides apply on top of the merged result.
+//!
+//! Any string may reference secrets instead of holding them: `${env:VAR}` is
+//! replaced by the variable's value and `${file:path}` by the file contents
+//! without the trailing newline. Errors name the key, never the value.
+//!
+//! ```toml
+//! [proxy]
+//! bind_address = "127.0.0.1"
+//! bind_port = 8080
+//!
+//! [relay]
+//! mode = "single_hop"
+//! selection = "random_per_session"
+//! endpoints = ["203.0.113.7:443", { host = "relay.example.net", port = 443 }]
+//!
+//! [policy]
+//! enabled = true
+//! rules = "https://easylist.to/easylist/easylist.txt"
+//!
+//! [observability]
+//! log = "info,real_proxy=debug"
+//!
+//! [profiles.strict-privacy.policy]
+//! block_response = { default = "reset" }
+//!
+//! [profiles.lan-shared.proxy]
+//! bind_address = "0.0.0.0"
+//! authentication = { credential = "${env:EBT_PROXY_CREDENTIAL}" }
+//! ```
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::config::{
+    AdaptivePaddingConfig, AdaptiveTunnelLimit, AuthenticationPlaceholder, BlockBehavior, BlockResponse,
+    CapabilityPolicy, ConfigError, ConstantRate, DelayBudgetConfig, ExecutionMode, GuardConfig, HeaderLimits,
+    IsolateBy, LeakDetection, MixDelay, NamedRuleset, PaddingHistogramConfig, PrefetchHint, ProxyMode,
+    RelayConfig, RelayEndpoint, RelayHealthConfig, RelayMode, RelaySelection, ResolutionLocation, RulesetAssignment, RulesetScope,
+    ShapingProfile, StreamIsolation, TrafficAccountingConfig, TransportKind, TunnelConfig, UdpMode,
+    UsageSummaryConfig,
+};
+use crate::anonymity::adaptive_padding::PaddingHistogram;
+use crate::anonymity::delay::ConfiguredDelay;
+use crate::anonymity::delay_budget::DelayBudget;
+use crate::content_policy::{ReasonCode, TimeZone};
+use crate::content_policy_bootstrap::ClientSubnet;
+use crate::core::observability::{ObservabilityLevel, OBS_LEVEL};
+use crate::kill_switch;
+use crate::logging::{LogConfig, LogFormat};
+use crate::onion;
+use crate::traffic_shaping::{self, ConstantRateShaper};
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    execution: Option<ExecutionSection>,
+    transport: Option<TransportSection>,
+    dns: Option<DnsSection>,
+    proxy: Option<ProxySection>,
+    relay: Option<RelaySection>,
+    policy: Option<PolicySection>,
+    anonymity: Option<AnonymitySection>,
+    observability: Option<ObservabilitySection>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ExecutionSection {
+    /// `conceptual` never opens sockets; the proxy refuses to start.
+    mode: Option<ExecutionMode>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct TransportSection {
+    kind: Option<TransportKind>,
+    proxy_host: Option<String>,
+    proxy_port: Option<u16>,
+    target_host: Option<String>,
+    target_port: Option<u16>,
+    connect_attempts: Option<u32>,
+    connect_timeout_ms: Option<u64>,
+    connect_backoff_ms: Option<u64>,
+    connect_max_backoff_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DnsSection {
+    resolution: Option<ResolutionLocation>,
+    leak_detection: Option<LeakDetection>,
+    doh_providers: Option<Vec<String>>,
+    /// Not in relay builds, which resolve targets at the exit.
+    prefetch: Option<Vec<PrefetchHintSection>>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct PrefetchHintSection {
+    /// Also matches its subdomains.
+    host: String,
+    /// Hostnames resolved when `host` is connected to.
+    companions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ProxySection {
+    mode: Option<ProxyMode>,
+    /// Sets the OS proxy while running; needs `mode = "system"`.
+    configure_system: Option<bool>,
+    /// Needs relays not launched by `command`; relays given by hostname
+    /// also need DoH providers given by IP address.
+    kill_switch: Option<bool>,
+    /// Unix socket path; a `\\.\pipe\` name on Windows.
+    control_socket: Option<String>,
+    bind_address: Option<String>,
+    bind_port: Option<u16>,
+    max_concurrent_tunnels: Option<usize>,
+    /// Present means the tunnel limit adapts, from `max_concurrent_tunnels`.
+    adaptive_tunnel_limit: Option<AdaptiveTunnelLimitSection>,
+    /// At most 2048; requests with longer lines get 414.
+    max_request_line: Option<usize>,
+    max_header_count: Option<usize>,
+    /// Requests with larger heads get 431.
+    max_header_bytes: Option<usize>,
+    /// Defaults to 30.
+    header_read_timeout_secs: Option<u64>,
+    /// Average a request head must arrive at after its first 5 seconds; 0 disables.
+    min_header_bytes_per_sec: Option<usize>,
+    /// Load balancer addresses or CIDR blocks that send PROXY v2 on every
+    /// connection; connections from anywhere else are refused.
+    proxy_protocol: Option<Vec<String>>,
+    /// Needs a build with `phase_5_traffic_shaping`.
+    traffic_shaping: Option<bool>,
+    /// SOCKS5 UDP associations, for QUIC: "tunnel" or "block".
+    udp: Option<UdpMode>,
+    /// Present means enabled.
+    authentication: Option<AuthenticationSection>,
+}
+
+/// Defaults to a quarter to four times `max_concurrent_tunnels`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AdaptiveTunnelLimitSection {
+    min_tunnels: Option<usize>,
+    max_tunnels: Option<usize>,
+    interval_secs: Option<u64>,
+    min_available_mib: Option<usize>,
+    max_failure_percent: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AuthenticationSection {
+    method: Option<String>,
+    credential: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RelaySection {
+    mode: RelayMode,
+    #[serde(default)]
+    endpoints: Vec<RelayEndpointEntry>,
+    selection: Option<RelaySelection>,
+    /// Multi-hop only; defaults to every endpoint.
+    hops: Option<usize>,
+    /// `per_epoch` only; defaults to 600.
+    epoch_secs: Option<u64>,
+    /// Enables an entry guard kept in this file; random selections only.
+    guard_state: Option<String>,
+    /// Defaults to 90.
+    guard_lifetime_days: Option<u64>,
+    /// `per_epoch` only; e.g. `["destination"]`.
+    isolation: Option<Vec<IsolateBy>>,
+    /// Present means enabled.
+    health_check: Option<RelayHealthSection>,
+}
+
+/// Omitted fields keep their defaults.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RelayHealthSection {
+    /// Defaults to 30.
+    interval_secs: Option<u64>,
+    /// Defaults to 5000.
+    timeout_ms: Option<u64>,
+    /// Defaults to 3.
+    failure_threshold: Option<u32>,
+    /// Defaults to 300.
+    exclusion_secs: Option<u64>,
+}
+
+/// `"host:port"`, or a table for the optional settings.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum RelayEndpointEntry {
+    Address(String),
+    Detailed(RelayEndpointSection),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RelayEndpointSection {
+    host: String,
+    port: u16,
+    /// Only `tls` relays are supported.
+    transport: Option<TransportKind>,
+    /// SHA-256, hex with optional `:` separators.
+    fingerprint: Option<String>,
+    /// Program and arguments that reach the relay, e.g.
+    /// `["ssh", "relay.example.net", "ebt", "relay", "serve", "--stdio"]`.
+    /// The relay protocol runs over its stdin and stdout without TLS, so
+    /// the command must secure the carrier itself.
+    command: Option<Vec<String>>,
+    /// Checksum every frame when the relay agrees. Defaults to false.
+    checksums: Option<bool>,
+    /// The exit's DNS key (its `--dns-key-file`), 64 hex digits. Names are
+    /// then resolved at the exit and never sent in the clear to it.
+    dns_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct PolicySection {
+    /// Defaults to true when the section is present.
+    enabled: Option<bool>,
+    rules: Option<String>,
+    custom_rules: Option<String>,
+    refresh_interval_secs: Option<u64>,
+    dry_run: Option<bool>,
+    inspect_sni: Option<bool>,
+    /// IANA zone name; defaults to the machine's zone.
+    time_zone: Option<String>,
+    block_response: Option<BlockResponseSection>,
+    #[serde(default)]
+    rulesets: Vec<RulesetSection>,
+    #[serde(default)]
+    assignments: Vec<AssignmentSection>,
+}
+
+/// `default` applies to every reason code not listed.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct BlockResponseSection {
+    default: Option<BlockResponse>,
+    ads: Option<BlockResponse>,
+    tracking: Option<BlockResponse>,
+    malware: Option<BlockResponse>,
+    custom: Option<BlockResponse>,
+    unknown: Option<BlockResponse>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RulesetSection {
+    name: String,
+    location: String,
+    /// `"ads"`, `"tracking"`, `"malware"` or `"custom"`. Defaults to the
+    /// ruleset's name when that is one of these, otherwise `"ads"`.
+    category: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AssignmentSection {
+    /// `"listener"` or a client address / CIDR block.
+    scope: String,
+    rulesets: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AnonymitySection {
+    /// Starting point for the keys below; see `ShapingProfile`.
+    profile: Option<ShapingProfile>,
+    mix_delay: Option<MixDelaySection>,
+    constant_rate: Option<ConstantRateSection>,
+    adaptive_padding: Option<AdaptivePaddingSection>,
+    delay_budget: Option<DelayBudgetSection>,
+}
+
+/// `{ floor_ms = 10, target_rtt_ms = 400 }`: mix delays shrink while the
+/// tunnel round trip exceeds the target, never below the floor.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DelayBudgetSection {
+    floor_ms: u64,
+    target_rtt_ms: u64,
+}
+
+/// `{ cell_bytes = 512, interval_ms = 20 }`: one cell per interval, padded
+/// when idle.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ConstantRateSection {
+    cell_bytes: usize,
+    interval_ms: u64,
+}
+
+/// `{ distribution = "poisson", mean_ms = 40, max_ms = 500 }`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+enum MixDelaySection {
+    Uniform { min_ms: u64, max_ms: u64 },
+    Poisson { mean_ms: u64, max_ms: u64 },
+    LogNormal { median_ms: u64, sigma: f64, max_ms: u64 },
+}
+
+/// Omitted histograms keep their defaults.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AdaptivePaddingSection {
+    burst: Option<HistogramSection>,
+    gap: Option<HistogramSection>,
+}
+
+/// `{ edges_ms = [5, 20, 50], weights = [2, 6, 2], infinity = 4 }`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HistogramSection {
+    edges_ms: Vec<u64>,
+    weights: Vec<u32>,
+    #[serde(default)]
+    infinity: u32,
+}
+
+impl HistogramSection {
+    fn into_config(self) -> PaddingHistogramConfig {
+        PaddingHistogramConfig {
+            edges: self.edges_ms.into_iter().map(Duration::from_millis).collect(),
+            weights: self.weights,
+            infinity: self.infinity,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ObservabilitySection {
+    /// Checked against the compiled level; it cannot be changed at runtime.
+    level: Option<String>,
+    log: Option<String>,
+    log_format: Option<String>,
+    usage_summary: Option<UsageSummarySection>,
+    traffic_accounting: Option<TrafficAccountingSection>,
+    notifications: Option<NotificationsSection>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct NotificationsSection {
+    health_faulted: Option<bool>,
+    reconnect_storm: Option<bool>,
+    kill_switch: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct UsageSummarySection {
+    path: String,
+    interval_secs: Option<u64>,
+    max_file_bytes: Option<u64>,
+    keep_files: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct TrafficAccountingSection {
+    path: String,
+    save_interval_secs: Option<u64>,
+    keep_days: Option<u32>,
+}
+
+/// Schema-only view of a file: the base sections plus named profiles of
+/// the same shape.
+#[derive(JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SchemaRoot {
+    #[serde(flatten)]
+    sections: FileConfig,
+    profiles: Option<BTreeMap<String, FileConfig>>,
+}
+
+/// JSON Schema of the config file, for editor completion and CI linting.
+/// Generated from the same structures the parser uses.
+pub fn schema_json() -> String {
+    let schema = schemars::schema_for!(SchemaRoot);
+    serde_json::to_string_pretty(&schema).expect("schema serializes to JSON")
+}
+
+/// `EBT_` variables read directly rather than as `EBT_<SECTION>__<KEY>`.
+const KNOWN_ENV_VARS: &[&str] = &[
+    "EBT_CONFIG",
+    "EBT_PROFILE",
+    "EBT_LOG",
+    "EBT_LOG_FORMAT",
+    "EBT_TRACE",
+    "EBT_OTLP_ENDPOINT",
+    "EBT_PROXY_AUTHORIZATION",
+    "EBT_TRANSPORT_WARMUP",
+    "EBT_CONTROL_SOCKET",
+];
+
+/// Findings that do not stop the proxy from starting but usually mean a
+/// mistake. Unknown file keys are already errors; this catches what the
+/// parser cannot see, such as `EBT_POLICY_ENABLED` (one underscore), which
+/// is silently ignored.
+pub fn warnings(config: &TunnelConfig, vars: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut unknown: Vec<String> = vars
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| {
+            name.starts_with(ENV_PREFIX) && !name.contains(ENV_SEPARATOR) && !KNOWN_ENV_VARS.contains(&name.as_str())
+        })
+        .collect();
+    unknown.sort();
+    for name in unknown {
+        warnings.push(format!(
+            "{} is not a recognized variable; overrides are spelled EBT_<SECTION>__<KEY>",
+            name
+        ));
+    }
+
+    let proxy = &config.proxy_policy;
+    if !proxy.content_policy_enabled {
+        let ignored: Vec<&str> = [
+            ("policy.rules", proxy.content_policy_rules.is_some()),
+            ("policy.custom_rules", proxy.content_policy_custom_rules.is_some()),
+            ("policy.rulesets", !proxy.content_policy_rulesets.is_empty()),
+            ("policy.assignments", !proxy.content_policy_assignments.is_empty()),
+        ]
+        .into_iter()
+        .filter_map(|(key, set)| set.then_some(key))
+        .collect();
+        if !ignored.is_empty() {
+            warnings.push(format!("{} ignored while policy.enabled = false", ignored.join(", ")));
+        }
+    }
+    if proxy.mode == ProxyMode::System && !proxy.configure_system {
+        warnings.push(
+            "proxy.mode = \"system\" changes no OS settings without proxy.configure_system = true".to_string(),
+        );
+    }
+    let exposed = proxy.bind_address.parse::<IpAddr>().is_ok_and(|ip| !ip.is_loopback());
+    if exposed && proxy.authentication.is_none() {
+        warnings.push(format!(
+            "proxy.bind_address {} accepts other hosts without proxy.authentication",
+            proxy.bind_address
+        ));
+    }
+    warnings
+}
+
+/// The line of `text`, counting from 1, that sets `key` to the string
+/// `value`; `None` when the value came from the environment.
+fn line_setting(text: &str, key: &str, value: &str) -> Option<usize> {
+    let sets = |line: &str| match line.split_once('=') {
+        Some((name, set)) => name.trim() == key && set.trim().trim_matches(['"', '\'']) == value,
+        None => false,
+    };
+    text.lines().position(sets).map(|idx| idx + 1)
+}
+
+fn invalid(field: impl Into<String>, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field: field.into(),
+        reason: reason.into(),
+    }
+}
+
+fn port(field: &str, value: u16) -> Result<u16, ConfigError> {
+    if value == 0 {
+        return Err(invalid(field, "port 0 is not allowed"));
+    }
+    Ok(value)
+}
+
+const DEFAULT_GUARD_LIFETIME_DAYS: u64 = 90;
+
+fn interval(field: &str, secs: u64) -> Result<Duration, ConfigError> {
+    if secs == 0 {
+        return Err(invalid(field, "must be at least 1 second"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+const ENV_PREFIX: &str = "EBT_";
+/// Separates nested keys; single underscores belong to key names.
+const ENV_SEPARATOR: &str = "__";
+const PROFILES_KEY: &str = "profiles";
+/// Applied when no profile is selected, if the file defines it.
+const DEFAULT_PROFILE: &str = "default";
+
+/// `path` is only used in error messages.
+pub fn parse(text: &str, path: &str) -> Result<TunnelConfig, ConfigError> {
+    parse_profile(text, path, None)
+}
+
+/// Like `parse`, with the named profile layered over the base sections.
+pub fn parse_profile(text: &str, path: &str, profile: Option<&str>) -> Result<TunnelConfig, ConfigError> {
+    parse_with_env(text, path, profile, std::env::vars())
+}
+
+fn parse_with_env(
+    text: &str,
+    path: &str,
+    profile: Option<&str>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<TunnelConfig, ConfigError> {
+    let parse_error = |source| ConfigError::Parse {
+        path: path.to_string(),
+        source,
+    };
+    let mut table: Table = text.parse().map_err(parse_error)?;
+    apply_profile(&mut table, profile)?;
+    apply_env_overrides(&mut table, vars)?;
+    for (key, value) in table.iter_mut() {
+        resolve_references(value, key)?;
+    }
+    let file: FileConfig = table.try_into().map_err(parse_error)?;
+    let mut config = TunnelConfig::ssh_socks_profile();
+
+    if let Some(mode) = file.execution.and_then(|execution| execution.mode) {
+        config.capabilities = match mode {
+            ExecutionMode::Conceptual => CapabilityPolicy::conceptual(),
+            ExecutionMode::RealNetwork => CapabilityPolicy::real_network(),
+        };
+    }
+    if let Some(transport) = file.transport {
+        apply_transport(&mut config, transport)?;
+    }
+    if let Some(dns) = file.dns {
+        apply_dns(&mut config, dns)?;
+    }
+    if let Some(proxy) = file.proxy {
+        apply_proxy(&mut config, proxy)?;
+    }
+    if let Some(relay) = file.relay {
+        apply_relay(&mut config, relay)?;
+    }
+    if let Some(policy) = file.policy {
+        apply_policy(&mut config, policy, text)?;
+    }
+    if let Some(anonymity) = file.anonymity {
+        apply_anonymity(&mut config, anonymity)?;
+    }
+    if let Some(observability) = file.observability {
+        apply_observability(&mut config, observability)?;
+    }
+    if config.proxy_policy.kill_switch {
+        check_kill_switch(&config.relay, &config.dns_policy.doh_providers)?;
+    }
+    Ok(config)
+}
+
+/// The kill switch allows relay addresses only, so it needs no carrier
+/// whose traffic it cannot see, and, to look up relays given by hostname,
+/// DoH providers it can allow without a lookup of their own.
+fn check_kill_switch(relay: &RelayConfig, doh_providers: &[String]) -> Result<(), ConfigError> {
+    if relay.mode == RelayMode::Direct {
+        return Err(invalid("proxy.kill_switch", "direct mode connects to every destination itself; use relays"));
+    }
+    for (idx, endpoint) in relay.endpoints.iter().enumerate() {
+        if endpoint.command.is_some() {
+            return Err(invalid(
+                format!("relay.endpoints[{}].command", idx),
+                "the kill switch cannot allow a launched carrier's traffic",
+            ));
+        }
+    }
+    if relay.endpoints.iter().all(|endpoint| endpoint.host.parse::<IpAddr>().is_ok()) {
+        return Ok(());
+    }
+    match doh_providers.iter().find(|provider| kill_switch::doh_provider_address(provider).is_none()) {
+        Some(provider) => Err(invalid(
+            "dns.doh_providers",
+            format!("{:?} needs an IP address; the kill switch blocks the lookup of its hostname", provider),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Removes `[profiles]` and merges the selected profile into `table`.
+fn apply_profile(table: &mut Table, profile: Option<&str>) -> Result<(), ConfigError> {
+    let mut profiles = match table.remove(PROFILES_KEY) {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(invalid(PROFILES_KEY, "must be a table of named profiles")),
+    };
+    let name = profile.unwrap_or(DEFAULT_PROFILE);
+    match profiles.remove(name) {
+        Some(Value::Table(overlay)) => merge_tables(table, overlay),
+        Some(_) => return Err(invalid(format!("{}.{}", PROFILES_KEY, name), "must be a table")),
+        None if profile.is_none() => {}
+        None => {
+            let mut available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            available.sort();
+            let reason = if available.is_empty() {
+                format!("no profile named {:?}; the file defines none", name)
+            } else {
+                format!("no profile named {:?}; available: {}", name, available.join(", "))
+            };
+            return Err(invalid(PROFILES_KEY, reason));
+        }
+    }
+    Ok(())
+}
+
+/// Tables merge key by key; any other value, arrays included, replaces the base.
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(nested)), Value::Table(overlay)) => merge_tables(nested, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn apply_env_overrides(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ConfigError> {
+    let mut overrides: Vec<(String, String)> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.contains(ENV_SEPARATOR))
+        .collect();
+    overrides.sort();
+    for (name, raw) in overrides {
+        let keys: Vec<String> = name[ENV_PREFIX.len()..]
+            .split(ENV_SEPARATOR)
+            .map(str::to_ascii_lowercase)
+            .collect();
+        if keys.iter().any(String::is_empty) {
+            return Err(invalid(name, "empty key between separators"));
+        }
+        let (last, parents) = keys.split_last().expect("split yields at least one key");
+        let mut current = &mut *table;
+        for key in parents {
+            current = match current.entry(key.as_str()).or_insert(Value::Table(Table::new())) {
+                Value::Table(nested) => nested,
+                _ => return Err(invalid(name, format!("{} is not a section", key))),
+            };
+        }
+        let keep_string = matches!(current.get(last), Some(Value::String(_)));
+        current.insert(last.clone(), env_value(&raw, keep_string));
+    }
+    Ok(())
+}
+
+fn env_value(raw: &str, keep_string: bool) -> Value {
+    if !keep_string {
+        if let Ok(mut parsed) = format!("value = {}", raw).parse::<Table>() {
+            if let Some(value) = parsed.remove("value") {
+                return value;
+            }
+        }
+    }
+    Value::String(raw.to_string())
+}
+
+fn resolve_references(value: &mut Value, field: &str) -> Result<(), ConfigError> {
+    match value {
+        Value::String(text) if text.contains("${") => *text = expand_references(text, field)?,
+        Value::Array(items) => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                resolve_references(item, &format!("{}[{}]", field, idx))?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, nested) in table.iter_mut() {
+                resolve_references(nested, &format!("{}.{}", field, key))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_references(text: &str, field: &str) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| invalid(field, "unterminated ${ reference"))?;
+        match after[..end].split_once(':') {
+            Some(("env", var)) => {
+                let value = std::env::var(var)
+                    .map_err(|_| invalid(field, format!("environment variable {} is not set", var)))?;
+                out.push_str(&value);
+            }
+            Some(("file", path)) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| invalid(field, format!("cannot read secret file {}: {}", path, e)))?;
+                out.push_str(contents.trim_end_matches(['\r', '\n']));
+            }
+            _ => {
+                return Err(invalid(
+                    field,
+                    format!("unknown reference ${{{}}}; use ${{env:VAR}} or ${{file:path}}", &after[..end]),
+                ));
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn apply_transport(config: &mut TunnelConfig, section: TransportSection) -> Result<(), ConfigError> {
+    let transport = &mut config.transport;
+    if let Some(kind) = section.kind {
+        transport.kind = kind;
+    }
+    if let Some(host) = section.proxy_host {
+        transport.proxy_host = host;
+    }
+    if let Some(value) = section.proxy_port {
+        transport.proxy_port = port("transport.proxy_port", value)?;
+    }
+    if let Some(host) = section.target_host {
+        transport.target_host = host;
+    }
+    if let Some(value) = section.target_port {
+        transport.target_port = port("transport.target_port", value)?;
+    }
+    let retry = &mut transport.connect_retry;
+    if let Some(attempts) = section.connect_attempts {
+        if attempts == 0 {
+            return Err(invalid("transport.connect_attempts", "must be at least 1"));
+        }
+        retry.attempts = attempts;
+    }
+    if let Some(ms) = section.connect_timeout_ms {
+        if ms == 0 {
+            return Err(invalid("transport.connect_timeout_ms", "must be at least 1"));
+        }
+        retry.attempt_timeout = Duration::from_millis(ms);
+    }
+    if let Some(ms) = section.connect_backoff_ms {
+        retry.backoff = Duration::from_millis(ms);
+    }
+    if let Some(ms) = section.connect_max_backoff_ms {
+        retry.max_backoff = Duration::from_millis(ms);
+    }
+    if retry.max_backoff < retry.backoff {
+        return Err(invalid("transport.connect_max_backoff_ms", "must be at least transport.connect_backoff_ms"));
+    }
+    Ok(())
+}
+
+fn apply_dns(config: &mut TunnelConfig, section: DnsSection) -> Result<(), ConfigError> {
+    let dns = &mut config.dns_policy;
+    if let Some(resolution) = section.resolution {
+        dns.resolution_location = resolution;
+    }
+    if let Some(leak_detection) = section.leak_detection {
+        dns.leak_detection = leak_detection;
+    }
+    if let Some(providers) = section.doh_providers {
+        if providers.is_empty() {
+            return Err(invalid("dns.doh_providers", "at least one provider is required"));
+        }
+        if let Some(provider) = providers.iter().find(|p| !p.starts_with("https://")) {
+            return Err(invalid("dns.doh_providers", format!("{:?} is not an https URL", provider)));
+        }
+        dns.doh_providers = providers;
+    }
+    if let Some(hints) = section.prefetch {
+        dns.prefetch = hints.into_iter().map(prefetch_hint).collect::<Result<_, _>>()?;
+    }
+    if dns.resolution_location == ResolutionLocation::Local && dns.leak_detection == LeakDetection::Strict {
+        return Err(invalid(
+            "dns.leak_detection",
+            "strict leak detection conflicts with local resolution; use remote resolution or warn",
+        ));
+    }
+    Ok(())
+}
+
+/// Companions a single CONNECT may prefetch.
+const MAX_PREFETCH_COMPANIONS: usize = 8;
+
+fn prefetch_hint(section: PrefetchHintSection) -> Result<PrefetchHint, ConfigError> {
+    if cfg!(any(feature = "single_hop_relay", feature = "multi_hop_relay")) {
+        return Err(invalid("dns.prefetch", "relay builds resolve targets at the exit; there is nothing to prefetch"));
+    }
+    let hostname = |name: &str| {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let valid = !name.is_empty()
+            && name.parse::<IpAddr>().is_err()
+            && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.');
+        valid.then_some(name).ok_or_else(|| invalid("dns.prefetch", "hosts and companions must be hostnames"))
+    };
+    if section.companions.is_empty() || section.companions.len() > MAX_PREFETCH_COMPANIONS {
+        return Err(invalid(
+            "dns.prefetch",
+            format!("each host needs 1 to {} companions", MAX_PREFETCH_COMPANIONS),
+        ));
+    }
+    Ok(PrefetchHint {
+        host: hostname(&section.host)?,
+        companions: section.companions.iter().map(|name| hostname(name)).collect::<Result<_, _>>()?,
+    })
+}
+
+fn apply_proxy(config: &mut TunnelConfig, section: ProxySection) -> Result<(), ConfigError> {
+    let proxy = &mut config.proxy_policy;
+    if let Some(mode) = section.mode {
+        proxy.mode = mode;
+    }
+    if let Some(enabled) = section.configure_system {
+        if enabled && proxy.mode != ProxyMode::System {
+            return Err(invalid("proxy.configure_system", "requires mode = \"system\""));
+        }
+        proxy.configure_system = enabled;
+    }
+    if let Some(enabled) = section.kill_switch {
+        proxy.kill_switch = enabled;
+    }
+    if let Some(path) = section.control_socket {
+        if path.is_empty() || (cfg!(windows) && !path.starts_with(r"\\.\pipe\")) {
+            let expected = if cfg!(windows) { r"a \\.\pipe\ name" } else { "a socket path" };
+            return Err(invalid("proxy.control_socket", format!("{:?} is not {}", path, expected)));
+        }
+        proxy.control_socket = Some(path);
+    }
+    if let Some(address) = section.bind_address {
+        if address.parse::<IpAddr>().is_err() {
+            return Err(invalid("proxy.bind_address", format!("{:?} is not an IP address", address)));
+        }
+        proxy.bind_address = address;
+    }
+    if let Some(value) = section.bind_port {
+        proxy.bind_port = port("proxy.bind_port", value)?;
+    }
+    if let Some(limit) = section.max_concurrent_tunnels {
+        if limit == 0 {
+            return Err(invalid("proxy.max_concurrent_tunnels", "must be at least 1"));
+        }
+        proxy.max_concurrent_tunnels = limit;
+    }
+    if let Some(adaptive) = section.adaptive_tunnel_limit {
+        let start = proxy.max_concurrent_tunnels;
+        let mut limit = AdaptiveTunnelLimit::around(start);
+        limit.min = adaptive.min_tunnels.unwrap_or(limit.min.min(start));
+        limit.max = adaptive.max_tunnels.unwrap_or(limit.max.max(start));
+        if limit.min == 0 || limit.min > start || start > limit.max {
+            return Err(invalid(
+                "proxy.adaptive_tunnel_limit",
+                "needs 1 <= min_tunnels <= max_concurrent_tunnels <= max_tunnels",
+            ));
+        }
+        if let Some(secs) = adaptive.interval_secs {
+            limit.interval = interval("proxy.adaptive_tunnel_limit.interval_secs", secs)?;
+        }
+        if let Some(mib) = adaptive.min_available_mib {
+            limit.min_available_memory = mib.saturating_mul(1024 * 1024);
+        }
+        if let Some(percent) = adaptive.max_failure_percent {
+            if percent > 100 {
+                return Err(invalid("proxy.adaptive_tunnel_limit.max_failure_percent", "must be at most 100"));
+            }
+            limit.max_failure_percent = percent;
+        }
+        proxy.adaptive_tunnel_limit = Some(limit);
+    }
+    let limits = &mut proxy.header_limits;
+    if let Some(length) = section.max_request_line {
+        if length == 0 || length > HeaderLimits::MAX_REQUEST_LINE {
+            return Err(invalid(
+                "proxy.max_request_line",
+                format!("must be from 1 to {}", HeaderLimits::MAX_REQUEST_LINE),
+            ));
+        }
+        limits.max_request_line = length;
+    }
+    if let Some(count) = section.max_header_count {
+        limits.max_header_count = count;
+    }
+    if let Some(bytes) = section.max_header_bytes {
+        limits.max_header_bytes = bytes;
+    }
+    if limits.max_header_bytes < limits.max_request_line {
+        return Err(invalid("proxy.max_header_bytes", "must be at least proxy.max_request_line"));
+    }
+    if let Some(secs) = section.header_read_timeout_secs {
+        if secs == 0 {
+            return Err(invalid("proxy.header_read_timeout_secs", "must be at least 1"));
+        }
+        limits.header_read_timeout = Duration::from_secs(secs);
+    }
+    if let Some(rate) = section.min_header_bytes_per_sec {
+        limits.min_header_rate = rate;
+    }
+    if let Some(balancers) = &section.proxy_protocol {
+        proxy.proxy_protocol = balancers
+            .iter()
+            .map(|cidr| ClientSubnet::parse(cidr).map_err(|e| invalid("proxy.proxy_protocol", e.to_string())))
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(enabled) = section.traffic_shaping {
+        if enabled && !traffic_shaping::PHASE_5_ENABLED {
+            return Err(invalid(
+                "proxy.traffic_shaping",
+                "this build has no traffic shaping; rebuild with the phase_5_traffic_shaping feature",
+            ));
+        }
+        proxy.traffic_shaping = enabled;
+    }
+    if let Some(udp) = section.udp {
+        proxy.udp = udp;
+    }
+    if let Some(auth) = section.authentication {
+        let Some(credential) = auth.credential.filter(|c| !c.is_empty()) else {
+            return Err(invalid(
+                "proxy.authentication.credential",
+                "required when authentication is configured",
+            ));
+        };
+        proxy.authentication = Some(AuthenticationPlaceholder {
+            enabled: true,
+            method: auth.method.unwrap_or_else(|| "basic".to_string()),
+            credential: Some(credential),
+        });
+    }
+    Ok(())
+}
+
+fn apply_relay(config: &mut TunnelConfig, section: RelaySection) -> Result<(), ConfigError> {
+    let compiled = RelayMode::compiled();
+    if section.mode != compiled {
+        return Err(invalid(
+            "relay.mode",
+            format!(
+                "{} does not match this build, which was compiled for {} relays",
+                section.mode.as_str(),
+                compiled.as_str()
+            ),
+        ));
+    }
+    let mut endpoints = Vec::with_capacity(section.endpoints.len());
+    for (idx, entry) in section.endpoints.into_iter().enumerate() {
+        endpoints.push(relay_endpoint(&format!("relay.endpoints[{}]", idx), entry)?);
+    }
+    let selection = section.selection.unwrap_or_default();
+    let fixed = selection == RelaySelection::FixedChain;
+    match (section.mode, endpoints.len()) {
+        (RelayMode::Direct, 0) | (RelayMode::SingleHop, 1) => {}
+        (RelayMode::SingleHop, n) if n > 1 && !fixed => {}
+        (RelayMode::MultiHop, n) if n >= 2 => {}
+        (RelayMode::Direct, _) => {
+            return Err(invalid("relay.endpoints", "direct mode does not use relay endpoints"));
+        }
+        (RelayMode::SingleHop, 0) | (RelayMode::MultiHop, 0) => {
+            return Err(invalid("relay.endpoints", format!("{} mode requires a relay", section.mode.as_str())));
+        }
+        (RelayMode::SingleHop, n) => {
+            return Err(invalid(
+                "relay.endpoints",
+                format!("single_hop mode with a fixed_chain takes one relay, got {}", n),
+            ));
+        }
+        (RelayMode::MultiHop, _) => {
+            return Err(invalid("relay.endpoints", "multi_hop mode needs at least two relays"));
+        }
+    }
+    if section.mode == RelayMode::Direct && !fixed {
+        return Err(invalid("relay.selection", "direct mode has no relays to select"));
+    }
+
+    let hops = match (section.mode, section.hops) {
+        (_, None) => endpoints.len(),
+        (RelayMode::MultiHop, Some(hops)) if fixed && hops != endpoints.len() => {
+            return Err(invalid("relay.hops", "a fixed_chain uses every endpoint; remove hops or pick a random selection"));
+        }
+        (RelayMode::MultiHop, Some(hops)) if hops < 2 || hops > endpoints.len() => {
+            return Err(invalid(
+                "relay.hops",
+                format!("must be between 2 and the {} configured endpoints", endpoints.len()),
+            ));
+        }
+        (RelayMode::MultiHop, Some(hops)) => hops,
+        (_, Some(_)) => return Err(invalid("relay.hops", "only multi_hop mode takes a hop count")),
+    };
+    if let Some(secs) = section.epoch_secs {
+        if selection != RelaySelection::PerEpoch {
+            return Err(invalid("relay.epoch_secs", "only used with per_epoch selection"));
+        }
+        config.relay.epoch = interval("relay.epoch_secs", secs)?;
+    }
+    if let Some(keys) = section.isolation {
+        if selection != RelaySelection::PerEpoch {
+            return Err(invalid(
+                "relay.isolation",
+                "only used with per_epoch selection; random_per_session already isolates every session",
+            ));
+        }
+        config.relay.isolation = StreamIsolation::by(&keys);
+    }
+    match (section.guard_state, section.guard_lifetime_days) {
+        (None, None) => {}
+        (None, Some(_)) => {
+            return Err(invalid("relay.guard_lifetime_days", "only used with relay.guard_state"));
+        }
+        (Some(_), _) if fixed => {
+            return Err(invalid(
+                "relay.guard_state",
+                "a fixed_chain always enters through its first relay; guards need a random selection",
+            ));
+        }
+        (Some(path), _) if path.is_empty() => {
+            return Err(invalid("relay.guard_state", "must be a file path"));
+        }
+        (Some(state_path), days) => {
+            let days = days.unwrap_or(DEFAULT_GUARD_LIFETIME_DAYS);
+            if days == 0 {
+                return Err(invalid("relay.guard_lifetime_days", "must be at least 1 day"));
+            }
+            config.relay.guard = Some(GuardConfig {
+                state_path,
+                lifetime: Duration::from_secs(days * 24 * 60 * 60),
+            });
+        }
+    }
+    if let Some(health) = section.health_check {
+        if section.mode == RelayMode::Direct {
+            return Err(invalid("relay.health_check", "direct mode has no relays to check"));
+        }
+        let mut health_check = RelayHealthConfig::default();
+        if let Some(secs) = health.interval_secs {
+            health_check.interval = interval("relay.health_check.interval_secs", secs)?;
+        }
+        if let Some(ms) = health.timeout_ms {
+            if ms == 0 {
+                return Err(invalid("relay.health_check.timeout_ms", "must be at least 1 ms"));
+            }
+            health_check.timeout = Duration::from_millis(ms);
+        }
+        if let Some(threshold) = health.failure_threshold {
+            if threshold == 0 {
+                return Err(invalid("relay.health_check.failure_threshold", "must be at least 1"));
+            }
+            health_check.failure_threshold = threshold;
+        }
+        if let Some(secs) = health.exclusion_secs {
+            health_check.exclusion = interval("relay.health_check.exclusion_secs", secs)?;
+        }
+        config.relay.health_check = Some(health_check);
+    }
+    config.relay.mode = section.mode;
+    config.relay.endpoints = endpoints;
+    config.relay.selection = selection;
+    config.relay.hops = hops;
+    Ok(())
+}
+
+/// One `relay.endpoints` string, validated as the file loader would.
+pub(crate) fn parse_relay_address(field: &str, address: &str) -> Result<RelayEndpoint, ConfigError> {
+    relay_endpoint(field, RelayEndpointEntry::Address(address.to_string()))
+}
+
+fn relay_endpoint(field: &str, entry: RelayEndpointEntry) -> Result<RelayEndpoint, ConfigError> {
+    let section = match entry {
+        RelayEndpointEntry::Address(address) => {
+            if let Ok(addr) = address.parse::<SocketAddr>() {
+                port(field, addr.port())?;
+                return Ok(RelayEndpoint::from(addr));
+            }
+            let parsed = address
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host.to_string(), port.parse::<u16>().ok()?)));
+            let Some((host, port)) = parsed else {
+                return Err(invalid(field, format!("{:?} is not a host:port address", address)));
+            };
+            RelayEndpointSection {
+                host,
+                port,
+                transport: None,
+                fingerprint: None,
+                command: None,
+                checksums: None,
+                dns_key: None,
+            }
+        }
+        RelayEndpointEntry::Detailed(section) => section,
+    };
+
+    let host = section.host.trim_start_matches('[').trim_end_matches(']');
+    let is_hostname = !host.is_empty()
+        && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if host.parse::<IpAddr>().is_err() && !is_hostname {
+        return Err(invalid(format!("{}.host", field), format!("{:?} is not an IP address or hostname", section.host)));
+    }
+    let transport = section.transport.unwrap_or(TransportKind::Tls);
+    if transport != TransportKind::Tls {
+        return Err(invalid(format!("{}.transport", field), "only tls relays are supported"));
+    }
+    let fingerprint = match section.fingerprint {
+        Some(fingerprint) => {
+            let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+            if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("{}.fingerprint", field), "expected a SHA-256 fingerprint in hex"));
+            }
+            Some(hex.to_ascii_lowercase())
+        }
+        None => None,
+    };
+    match &section.command {
+        Some(command) if command.first().is_none_or(|program| program.trim().is_empty()) => {
+            return Err(invalid(format!("{}.command", field), "must name a program"));
+        }
+        Some(_) if fingerprint.is_some() => {
+            return Err(invalid(format!("{}.fingerprint", field), "command relays are not reached over TLS"));
+        }
+        _ => {}
+    }
+    let dns_key = match section.dns_key {
+        Some(key) if onion::parse_layer_key(&key).is_none() => {
+            return Err(invalid(format!("{}.dns_key", field), "expected 64 hex digits"));
+        }
+        key => key.map(|key| key.trim().to_ascii_lowercase()),
+    };
+    Ok(RelayEndpoint {
+        host: host.to_string(),
+        port: port(&format!("{}.port", field), section.port)?,
+        transport,
+        fingerprint,
+        command: section.command,
+        checksums: section.checksums.unwrap_or(false),
+        dns_key,
+    })
+}
+
+/// `text` is the file, to point at the lines of rejected values.
+fn apply_policy(config: &mut TunnelConfig, section: PolicySection, text: &str) -> Result<(), ConfigError> {
+    let proxy = &mut config.proxy_policy;
+    let enabled = section.enabled.unwrap_or(true);
+    proxy.content_policy_enabled = enabled;
+    if !enabled && section.dry_run == Some(true) {
+        return Err(invalid("policy.dry_run", "requires policy.enabled = true"));
+    }
+    if !enabled && section.inspect_sni == Some(true) {
+        return Err(invalid("policy.inspect_sni", "requires policy.enabled = true"));
+    }
+    proxy.content_policy_rules = section.rules;
+    proxy.content_policy_custom_rules = section.custom_rules;
+    if let Some(secs) = section.refresh_interval_secs {
+        if proxy.content_policy_rules.as_deref().is_none_or(|rules| !rules.starts_with("https://")) {
+            return Err(invalid(
+                "policy.refresh_interval_secs",
+                "only applies when policy.rules is an https URL",
+            ));
+        }
+        proxy.content_policy_refresh_interval = Some(interval("policy.refresh_interval_secs", secs)?);
+    }
+    if let Some(dry_run) = section.dry_run {
+        proxy.content_policy_dry_run = dry_run;
+    }
+    if let Some(inspect_sni) = section.inspect_sni {
+        proxy.content_policy_inspect_sni = inspect_sni;
+    }
+    if let Some(name) = section.time_zone {
+        if TimeZone::named(&name).is_none() {
+            let line = line_setting(text, "time_zone", &name).map(|line| format!(" (line {line})"));
+            return Err(invalid(
+                "policy.time_zone",
+                format!("{:?} is not an IANA zone in the zoneinfo database{}", name, line.unwrap_or_default()),
+            ));
+        }
+        proxy.content_policy_time_zone = Some(name);
+    }
+    if let Some(block) = section.block_response {
+        let default = block.default.unwrap_or(BlockResponse::Forbidden);
+        proxy.content_policy_block_behavior = BlockBehavior {
+            ads: block.ads.unwrap_or(default),
+            tracking: block.tracking.unwrap_or(default),
+            malware: block.malware.unwrap_or(default),
+            custom: block.custom.unwrap_or(default),
+            unknown: block.unknown.unwrap_or(default),
+        };
+    }
+
+    for (idx, ruleset) in section.rulesets.iter().enumerate() {
+        if section.rulesets[..idx].iter().any(|earlier| earlier.name == ruleset.name) {
+            return Err(invalid(
+                format!("policy.rulesets[{}].name", idx),
+                format!("duplicate ruleset {:?}", ruleset.name),
+            ));
+        }
+    }
+    let mut rulesets = Vec::with_capacity(section.rulesets.len());
+    for (idx, ruleset) in section.rulesets.into_iter().enumerate() {
+        let category = match ruleset.category.as_deref() {
+            Some(category) => ruleset_category(category).ok_or_else(|| {
+                invalid(
+                    format!("policy.rulesets[{}].category", idx),
+                    format!("unknown category {:?}; expected ads, tracking, malware or custom", category),
+                )
+            })?,
+            None => ruleset_category(&ruleset.name).unwrap_or(ReasonCode::Ads),
+        };
+        rulesets.push(NamedRuleset {
+            name: ruleset.name,
+            location: ruleset.location,
+            category,
+        });
+    }
+    proxy.content_policy_rulesets = rulesets;
+
+    let mut assignments = Vec::with_capacity(section.assignments.len());
+    for (idx, assignment) in section.assignments.into_iter().enumerate() {
+        let field = format!("policy.assignments[{}]", idx);
+        let scope = if assignment.scope == "listener" {
+            if proxy.content_policy_rules.is_some() {
+                return Err(invalid(
+                    format!("{}.scope", field),
+                    "a listener assignment conflicts with policy.rules; set only one",
+                ));
+            }
+            RulesetScope::Listener
+        } else {
+            ClientSubnet::parse(&assignment.scope).map_err(|e| invalid(format!("{}.scope", field), e.to_string()))?;
+            RulesetScope::ClientSubnet(assignment.scope)
+        };
+        if assignments.iter().any(|earlier: &RulesetAssignment| earlier.scope == scope) {
+            return Err(invalid(format!("{}.scope", field), "scope is assigned more than once"));
+        }
+        if let Some(unknown) = assignment
+            .rulesets
+            .iter()
+            .find(|name| !proxy.content_policy_rulesets.iter().any(|ruleset| &ruleset.name == *name))
+        {
+            return Err(invalid(
+                format!("{}.rulesets", field),
+                format!("unknown ruleset {:?}", unknown),
+            ));
+        }
+        assignments.push(RulesetAssignment {
+            scope,
+            rulesets: assignment.rulesets,
+        });
+    }
+    proxy.content_policy_assignments = assignments;
+    Ok(())
+}
+
+fn ruleset_category(name: &str) -> Option<ReasonCode> {
+    match name {
+        "ads" => Some(ReasonCode::Ads),
+        "tracking" => Some(ReasonCode::Tracking),
+        "malware" => Some(ReasonCode::Malware),
+        "custom" => Some(ReasonCode::Custom),
+        _ => None,
+    }
+}
+
+fn level_name(level: ObservabilityLevel) -> &'static str {
+    match level {
+        ObservabilityLevel::OBS_NONE => "none",
+        ObservabilityLevel::OBS_SAFE => "safe",
+        ObservabilityLevel::OBS_DEV => "dev",
+    }
+}
+
+fn apply_anonymity(config: &mut TunnelConfig, section: AnonymitySection) -> Result<(), ConfigError> {
+    if let Some(profile) = section.profile {
+        config.anonymity = profile.anonymity();
+    }
+    if let Some(delay) = section.mix_delay {
+        let ms = Duration::from_millis;
+        let mix_delay = match delay {
+            MixDelaySection::Uniform { min_ms, max_ms } => MixDelay::Uniform { min: ms(min_ms), max: ms(max_ms) },
+            MixDelaySection::Poisson { mean_ms, max_ms } => MixDelay::Poisson { mean: ms(mean_ms), max: ms(max_ms) },
+            MixDelaySection::LogNormal { median_ms, sigma, max_ms } => MixDelay::LogNormal {
+                median: ms(median_ms),
+                sigma,
+                max: ms(max_ms),
+            },
+        };
+        ConfiguredDelay::from_config(&mix_delay).map_err(|reason| invalid("anonymity.mix_delay", reason))?;
+        config.anonymity.mix_delay = mix_delay;
+    }
+    if let Some(rate) = section.constant_rate {
+        let constant_rate = ConstantRate {
+            cell_size: rate.cell_bytes,
+            interval: Duration::from_millis(rate.interval_ms),
+        };
+        ConstantRateShaper::new(constant_rate).map_err(|reason| invalid("anonymity.constant_rate", reason))?;
+        config.anonymity.constant_rate = Some(constant_rate);
+    }
+    if let Some(padding) = section.adaptive_padding {
+        let mut adaptive = AdaptivePaddingConfig::default();
+        if let Some(burst) = padding.burst {
+            adaptive.burst = burst.into_config();
+            PaddingHistogram::from_config(&adaptive.burst)
+                .map_err(|reason| invalid("anonymity.adaptive_padding.burst", reason))?;
+        }
+        if let Some(gap) = padding.gap {
+            adaptive.gap = gap.into_config();
+            PaddingHistogram::from_config(&adaptive.gap)
+                .map_err(|reason| invalid("anonymity.adaptive_padding.gap", reason))?;
+        }
+        config.anonymity.adaptive_padding = Some(adaptive);
+    }
+    if let Some(budget) = section.delay_budget {
+        let delay_budget = DelayBudgetConfig {
+            floor: Duration::from_millis(budget.floor_ms),
+            target_rtt: Duration::from_millis(budget.target_rtt_ms),
+        };
+        DelayBudget::from_config(&delay_budget).map_err(|reason| invalid("anonymity.delay_budget", reason))?;
+        config.anonymity.delay_budget = Some(delay_budget);
+    }
+    // Checked last: a profile's budget must also fit an overridden delay.
+    if let Some(budget) = config.anonymity.delay_budget {
+        if budget.floor > config.anonymity.mix_delay.max() {
+            return Err(invalid("anonymity.delay_budget", "floor is above the mix delay's max"));
+        }
+    }
+    Ok(())
+}
+
+fn apply_observability(config: &mut TunnelConfig, section: ObservabilitySection) -> Result<(), ConfigError> {
+    if let Some(level) = section.level {
+        if level != level_name(OBS_LEVEL) {
+            return Err(invalid(
+                "observability.level",
+                format!(
+                    "{:?} does not match this build's level \"{}\"; the level is fixed at compile time",
+                    level,
+                    level_name(OBS_LEVEL)
+                ),
+            ));
+        }
+    }
+    let format = match section.log_format.as_deref() {
+        None | Some("text") => LogFormat::Text,
+        Some("json") => LogFormat::Json,
+        Some(other) => {
+            return Err(invalid("observability.log_format", format!("{:?} is not text or json", other)));
+        }
+    };
+    if section.log.is_some() || section.log_format.is_some() {
+        config.observability.log = Some(LogConfig::parse(section.log.as_deref().unwrap_or(""), format));
+    }
+
+    let counts_available = OBS_LEVEL != ObservabilityLevel::OBS_NONE;
+    if let Some(summary) = section.usage_summary {
+        if !counts_available {
+            return Err(invalid("observability.usage_summary", "not available in an OBS_NONE build"));
+        }
+        let mut usage = UsageSummaryConfig::new(summary.path);
+        if let Some(secs) = summary.interval_secs {
+            usage.interval = interval("observability.usage_summary.interval_secs", secs)?;
+        }
+        if let Some(max_file_bytes) = summary.max_file_bytes {
+            usage.max_file_bytes = max_file_bytes;
+        }
+        if let Some(keep_files) = summary.keep_files {
+            usage.keep_files = keep_files;
+        }
+        config.proxy_policy.usage_summary = Some(usage);
+    }
+    if let Some(accounting) = section.traffic_accounting {
+        if !counts_available {
+            return Err(invalid("observability.traffic_accounting", "not available in an OBS_NONE build"));
+        }
+        let mut traffic = TrafficAccountingConfig::new(accounting.path);
+        if let Some(secs) = accounting.save_interval_secs {
+            traffic.save_interval = interval("observability.traffic_accounting.save_interval_secs", secs)?;
+        }
+        if let Some(keep_days) = accounting.keep_days {
+            traffic.keep_days = keep_days;
+        }
+        config.proxy_policy.traffic_accounting = Some(traffic);
+    }
+    if let Some(notifications) = section.notifications {
+        let enabled = &mut config.observability.notifications;
+        enabled.health_faulted = notifications.health_faulted.unwrap_or(enabled.health_faulted);
+        enabled.reconnect_storm = notifications.reconnect_storm.unwrap_or(enabled.reconnect_storm);
+        enabled.kill_switch = notifications.kill_switch.unwrap_or(enabled.kill_switch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConnectRetry;
+
+    fn field_of(result: Result<TunnelConfig, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn conceptual_execution_mode_withholds_the_network_token() {
+        let config = parse("[execution]\nmode = \"conceptual\"\n", "ebt.toml").unwrap();
+        assert!(matches!(
+            config.capabilities.network_token(),
+            Err(ConfigError::MissingCapability(crate::config::Capability::RealNetworking))
+        ));
+        assert!(parse("", "ebt.toml").unwrap().capabilities.network_token().is_ok());
+    }
+
+    #[test]
+    fn applies_sections_over_the_default_profile() {
+        let config = parse(
+            r#"
+            [proxy]
+            bind_port = 9090
+            udp = "block"
+            authentication = { credential = "Basic dXNlcjpwYXNz" }
+
+            [dns]
+            leak_detection = "strict"
+
+            [policy]
+            block_response = { default = "forbidden_with_reason", ads = "reset" }
+            rulesets = [{ name = "ads", location = "ads.txt" }]
+            assignments = [
+                { scope = "listener", rulesets = ["ads"] },
+                { scope = "192.168.1.0/24", rulesets = [] },
+            ]
+
+            [observability]
+            log = "info,real_proxy=debug"
+            traffic_accounting = { path = "usage.bin", keep_days = 62 }
+            notifications = { health_faulted = true, kill_switch = true }
+            "#,
+            "test.toml",
+        )
+        .unwrap();
+
+        let proxy = &config.proxy_policy;
+        assert_eq!(proxy.bind_port, 9090);
+        assert_eq!(proxy.bind_address, "127.0.0.1");
+        assert_eq!(proxy.udp, UdpMode::Block);
+        assert!(proxy.authentication.as_ref().is_some_and(|auth| auth.enabled));
+        assert_eq!(config.dns_policy.leak_detection, LeakDetection::Strict);
+        assert!(proxy.content_policy_enabled);
+        assert_eq!(proxy.content_policy_block_behavior.ads, BlockResponse::Reset);
+        assert_eq!(proxy.content_policy_block_behavior.custom, BlockResponse::ForbiddenWithReason);
+        assert_eq!(proxy.content_policy_assignments.len(), 2);
+        assert_eq!(proxy.traffic_accounting.as_ref().map(|t| t.keep_days), Some(62));
+        assert_eq!(config.relay.mode, RelayMode::compiled());
+        let notifications = config.observability.notifications;
+        assert!(notifications.health_faulted && notifications.kill_switch && !notifications.reconnect_storm);
+        let log = config.observability.log.unwrap();
+        assert_eq!(log.module_levels.len(), 1);
+    }
+
+    #[test]
+    fn ruleset_categories_come_from_the_key_or_the_name() {
+        let config = parse(
+            r#"
+            [policy]
+            block_response = { malware = "block_page" }
+            rulesets = [
+                { name = "malware", location = "malware.txt" },
+                { name = "easyprivacy", location = "easyprivacy.txt", category = "tracking" },
+                { name = "corporate", location = "corporate.txt" },
+            ]
+            "#,
+            "t",
+        )
+        .unwrap();
+        let proxy = &config.proxy_policy;
+        let categories: Vec<_> = proxy.content_policy_rulesets.iter().map(|ruleset| ruleset.category).collect();
+        assert_eq!(categories, [ReasonCode::Malware, ReasonCode::Tracking, ReasonCode::Ads]);
+        assert_eq!(proxy.content_policy_block_behavior.malware, BlockResponse::BlockPage);
+        assert_eq!(proxy.content_policy_block_behavior.ads, BlockResponse::Forbidden);
+        assert_eq!(
+            field_of(parse("[policy]\nrulesets = [{ name = \"a\", location = \"a\", category = \"spam\" }]", "t")),
+            "policy.rulesets[0].category"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_ports_and_unknown_keys() {
+        assert_eq!(field_of(parse("[proxy]\nbind_port = 0", "t")), "proxy.bind_port");
+        assert!(matches!(
+            parse("[proxy]\nbind_port = 70000", "t"),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(parse("[proxy]\nbind_prot = 8080", "t"), Err(ConfigError::Parse { .. })));
+        assert_eq!(
+            field_of(parse("[proxy]\nmax_concurrent_tunnels = 0", "t")),
+            "proxy.max_concurrent_tunnels"
+        );
+        assert_eq!(field_of(parse("[proxy]\ncontrol_socket = \"\"", "t")), "proxy.control_socket");
+        assert_eq!(field_of(parse("[proxy]\nmax_request_line = 4096", "t")), "proxy.max_request_line");
+        assert_eq!(field_of(parse("[proxy]\nmax_header_bytes = 1024", "t")), "proxy.max_header_bytes");
+        let limits = parse("[proxy]\nmax_request_line = 512\nmax_header_bytes = 1024", "t").unwrap();
+        assert_eq!(limits.proxy_policy.header_limits.max_header_bytes, 1024);
+        assert_eq!(
+            field_of(parse("[proxy]\nheader_read_timeout_secs = 0", "t")),
+            "proxy.header_read_timeout_secs"
+        );
+        assert_eq!(field_of(parse("[proxy]\nproxy_protocol = [\"10.0.0.0/33\"]", "t")), "proxy.proxy_protocol");
+        let balancers = parse("[proxy]\nproxy_protocol = [\"10.0.0.0/24\", \"192.0.2.9\"]", "t").unwrap();
+        assert_eq!(balancers.proxy_policy.proxy_protocol.len(), 2);
+        assert!(!parse("[proxy]\ntraffic_shaping = false", "t").unwrap().proxy_policy.traffic_shaping);
+        match parse("[proxy]\ntraffic_shaping = true", "t") {
+            Ok(config) => assert!(traffic_shaping::PHASE_5_ENABLED && config.proxy_policy.traffic_shaping),
+            Err(e) => assert_eq!(field_of(Err(e)), "proxy.traffic_shaping"),
+        }
+        assert_eq!(
+            field_of(parse("[dns]\ndoh_providers = [\"http://dns.example/dns-query\"]", "t")),
+            "dns.doh_providers"
+        );
+    }
+
+    #[test]
+    fn relay_endpoints_must_fit_the_mode() {
+        let compiled = RelayMode::compiled().as_str();
+        let missing = format!("[relay]\nmode = \"{}\"\nendpoints = []", compiled);
+        let with_one = format!("[relay]\nmode = \"{}\"\nendpoints = [\"203.0.113.7:443\"]", compiled);
+        match RelayMode::compiled() {
+            RelayMode::Direct => {
+                assert!(parse(&missing, "t").is_ok());
+                assert_eq!(field_of(parse(&with_one, "t")), "relay.endpoints");
+                assert_eq!(
+                    field_of(parse("[relay]\nmode = \"single_hop\"\nendpoints = [\"203.0.113.7:443\"]", "t")),
+                    "relay.mode"
+                );
+            }
+            RelayMode::SingleHop | RelayMode::MultiHop => {
+                assert_eq!(field_of(parse(&missing, "t")), "relay.endpoints");
+            }
+        }
+        let bad = format!("[relay]\nmode = \"{}\"\nendpoints = [\"relay.example.com\"]", compiled);
+        assert_eq!(field_of(parse(&bad, "t")), "relay.endpoints[0]");
+    }
+
+    fn endpoint_error(entry: RelayEndpointEntry) -> String {
+        match relay_endpoint("relay.endpoints[0]", entry) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    fn detailed(host: &str, transport: Option<TransportKind>, fingerprint: Option<&str>) -> RelayEndpointEntry {
+        RelayEndpointEntry::Detailed(RelayEndpointSection {
+            host: host.to_string(),
+            port: 443,
+            transport,
+            fingerprint: fingerprint.map(str::to_string),
+            command: None,
+            checksums: None,
+            dns_key: None,
+        })
+    }
+
+    #[test]
+    fn relay_endpoints_take_hostnames_and_fingerprints() {
+        let address = |text: &str| RelayEndpointEntry::Address(text.to_string());
+        assert_eq!(
+            relay_endpoint("e", address("relay.example.net:8443")).unwrap(),
+            RelayEndpoint::new("relay.example.net", 8443)
+        );
+        assert_eq!(relay_endpoint("e", address("[2001:db8::7]:443")).unwrap().host, "2001:db8::7");
+        let fingerprint = format!("{}AB", "AB:".repeat(31));
+        let pinned = relay_endpoint("e", detailed("relay.example.net", None, Some(&fingerprint))).unwrap();
+        assert_eq!(pinned.fingerprint, Some("ab".repeat(32)));
+        assert_eq!(pinned.transport, TransportKind::Tls);
+
+        assert_eq!(endpoint_error(address("relay.example.net")), "relay.endpoints[0]");
+        assert_eq!(endpoint_error(address("bad host:443")), "relay.endpoints[0].host");
+        assert_eq!(
+            endpoint_error(detailed("relay.example.net", Some(TransportKind::Quic), None)),
+            "relay.endpoints[0].transport"
+        );
+        assert_eq!(
+            endpoint_error(detailed("relay.example.net", None, Some("abcd"))),
+            "relay.endpoints[0].fingerprint"
+        );
+
+        let with_dns_key = |key: &str| {
+            let RelayEndpointEntry::Detailed(mut section) = detailed("relay.example.net", None, None) else {
+                unreachable!()
+            };
+            section.dns_key = Some(key.to_string());
+            RelayEndpointEntry::Detailed(section)
+        };
+        let keyed = relay_endpoint("e", with_dns_key(&"5A".repeat(32))).unwrap();
+        assert_eq!(keyed.dns_key, Some("5a".repeat(32)));
+        assert_eq!(endpoint_error(with_dns_key("5a5a")), "relay.endpoints[0].dns_key");
+    }
+
+    #[test]
+    fn command_relays_need_a_program_and_no_pin() {
+        let with_command = |command: &[&str], fingerprint: Option<&str>| {
+            let RelayEndpointEntry::Detailed(mut section) = detailed("relay.example.net", None, fingerprint) else {
+                unreachable!()
+            };
+            section.command = Some(command.iter().map(|arg| arg.to_string()).collect());
+            RelayEndpointEntry::Detailed(section)
+        };
+        let ssh = ["ssh", "relay.example.net", "ebt", "relay", "serve", "--stdio"];
+        let endpoint = relay_endpoint("e", with_command(&ssh, None)).unwrap();
+        assert!(!endpoint.checksums);
+        assert_eq!(endpoint.command.unwrap().len(), ssh.len());
+        let RelayEndpointEntry::Detailed(mut section) = with_command(&ssh, None) else { unreachable!() };
+        section.checksums = Some(true);
+        assert!(relay_endpoint("e", RelayEndpointEntry::Detailed(section)).unwrap().checksums);
+
+        assert_eq!(endpoint_error(with_command(&[], None)), "relay.endpoints[0].command");
+        assert_eq!(endpoint_error(with_command(&[" "], None)), "relay.endpoints[0].command");
+        assert_eq!(
+            endpoint_error(with_command(&ssh, Some(&"ab".repeat(32)))),
+            "relay.endpoints[0].fingerprint"
+        );
+    }
+
+    #[test]
+    fn relay_selection_settings_fit_the_mode() {
+        let relay = |extra: &str| {
+            format!(
+                "[relay]\nmode = \"{}\"\nendpoints = [\"203.0.113.1:443\", \"203.0.113.2:443\", \"203.0.113.3:443\"]\n{}",
+                RelayMode::compiled().as_str(),
+                extra
+            )
+        };
+        match RelayMode::compiled() {
+            RelayMode::Direct => {
+                assert_eq!(
+                    field_of(parse("[relay]\nmode = \"direct\"\nselection = \"per_epoch\"", "t")),
+                    "relay.selection"
+                );
+            }
+            RelayMode::SingleHop => {
+                let config = parse(&relay("selection = \"random_per_session\""), "t").unwrap();
+                assert_eq!(config.relay.chain_length(), 1);
+                assert_eq!(field_of(parse(&relay(""), "t")), "relay.endpoints");
+                assert_eq!(field_of(parse(&relay("selection = \"per_epoch\"\nhops = 1"), "t")), "relay.hops");
+            }
+            RelayMode::MultiHop => {
+                let config = parse(&relay("selection = \"per_epoch\"\nhops = 2\nepoch_secs = 30"), "t").unwrap();
+                assert_eq!(config.relay.selection, RelaySelection::PerEpoch);
+                assert_eq!(config.relay.chain_length(), 2);
+                assert_eq!(config.relay.epoch, Duration::from_secs(30));
+                assert_eq!(parse(&relay(""), "t").unwrap().relay.chain_length(), 3);
+                assert_eq!(field_of(parse(&relay("hops = 2"), "t")), "relay.hops");
+                assert_eq!(
+                    field_of(parse(&relay("selection = \"random_per_session\"\nhops = 4"), "t")),
+                    "relay.hops"
+                );
+                assert_eq!(
+                    field_of(parse(&relay("selection = \"random_per_session\"\nepoch_secs = 30"), "t")),
+                    "relay.epoch_secs"
+                );
+                let config = parse(&relay("selection = \"per_epoch\"\nisolation = [\"destination\"]"), "t").unwrap();
+                assert!(config.relay.isolation.destination && !config.relay.isolation.listener);
+                assert_eq!(field_of(parse(&relay("isolation = [\"listener\"]"), "t")), "relay.isolation");
+                assert!(parse(&relay("selection = \"per_epoch\"\nisolation = [\"origin\"]"), "t").is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn guard_needs_a_random_selection() {
+        if RelayMode::compiled() == RelayMode::Direct {
+            return;
+        }
+        let relay = |extra: &str| {
+            format!(
+                "[relay]\nmode = \"{}\"\nendpoints = [\"203.0.113.1:443\", \"203.0.113.2:443\"]\n{}",
+                RelayMode::compiled().as_str(),
+                extra
+            )
+        };
+        let config = parse(&relay("selection = \"random_per_session\"\nguard_state = \"guard.bin\""), "t").unwrap();
+        assert_eq!(
+            config.relay.guard,
+            Some(GuardConfig {
+                state_path: "guard.bin".to_string(),
+                lifetime: Duration::from_secs(90 * 24 * 60 * 60),
+            })
+        );
+        assert_eq!(
+            field_of(parse(&relay("selection = \"random_per_session\"\nguard_lifetime_days = 30"), "t")),
+            "relay.guard_lifetime_days"
+        );
+        assert_eq!(
+            field_of(parse(
+                &relay("selection = \"per_epoch\"\nguard_state = \"guard.bin\"\nguard_lifetime_days = 0"),
+                "t"
+            )),
+            "relay.guard_lifetime_days"
+        );
+        if RelayMode::compiled() == RelayMode::MultiHop {
+            assert_eq!(field_of(parse(&relay("guard_state = \"guard.bin\""), "t")), "relay.guard_state");
+        }
+    }
+
+    #[test]
+    fn kill_switch_needs_doh_providers_by_address_for_relay_hostnames() {
+        if RelayMode::compiled() == RelayMode::Direct {
+            assert_eq!(field_of(parse("[proxy]\nkill_switch = true", "t")), "proxy.kill_switch");
+            return;
+        }
+        let relay = |endpoint: &str| {
+            format!(
+                "[proxy]\nkill_switch = true\n[relay]\nmode = \"{}\"\nselection = \"random_per_session\"\n\
+                 endpoints = [\"203.0.113.1:443\", {}]",
+                RelayMode::compiled().as_str(),
+                endpoint
+            )
+        };
+        assert!(parse(&relay("\"203.0.113.2:443\""), "t").unwrap().proxy_policy.kill_switch);
+        // Hostnames resolve through the default provider, given by address.
+        assert!(parse(&relay("\"relay.example:443\""), "t").unwrap().proxy_policy.kill_switch);
+        let named_provider = format!(
+            "{}\n[dns]\ndoh_providers = [\"https://dns.example/dns-query\"]",
+            relay("\"relay.example:443\"")
+        );
+        assert_eq!(field_of(parse(&named_provider, "t")), "dns.doh_providers");
+        assert_eq!(
+            field_of(parse(&relay("{ host = \"203.0.113.2\", port = 443, command = [\"ssh\", \"r\"] }"), "t")),
+            "relay.endpoints[1].command"
+        );
+    }
+
+    #[test]
+    fn relay_health_check_is_optional() {
+        if RelayMode::compiled() == RelayMode::Direct {
+            assert_eq!(
+                field_of(parse("[relay]\nmode = \"direct\"\n[relay.health_check]", "t")),
+                "relay.health_check"
+            );
+            return;
+        }
+        let relay = |extra: &str| {
+            format!(
+                "[relay]\nmode = \"{}\"\nselection = \"random_per_session\"\n\
+                 endpoints = [\"203.0.113.1:443\", \"203.0.113.2:443\"]\n{}",
+                RelayMode::compiled().as_str(),
+                extra
+            )
+        };
+        assert_eq!(parse(&relay(""), "t").unwrap().relay.health_check, None);
+        let config = parse(&relay("[relay.health_check]\ninterval_secs = 10\nfailure_threshold = 2"), "t").unwrap();
+        assert_eq!(
+            config.relay.health_check,
+            Some(RelayHealthConfig {
+                interval: Duration::from_secs(10),
+                failure_threshold: 2,
+                ..RelayHealthConfig::default()
+            })
+        );
+        assert_eq!(
+            field_of(parse(&relay("[relay.health_check]\nfailure_threshold = 0"), "t")),
+            "relay.health_check.failure_threshold"
+        );
+        assert_eq!(
+            field_of(parse(&relay("[relay.health_check]\ntimeout_ms = 0"), "t")),
+            "relay.health_check.timeout_ms"
+        );
+    }
+
+    #[test]
+    fn mix_delay_distribution_is_selectable() {
+        let config = parse(
+            "[anonymity]\nmix_delay = { distribution = \"log_normal\", median_ms = 40, sigma = 0.8, max_ms = 2000 }",
+            "t",
+        )
+        .unwrap();
+        assert_eq!(
+            config.anonymity.mix_delay,
+            MixDelay::LogNormal {
+                median: Duration::from_millis(40),
+                sigma: 0.8,
+                max: Duration::from_secs(2),
+            }
+        );
+        assert!(matches!(
+            parse("[anonymity]\nmix_delay = { distribution = \"poisson\", mean_ms = 40, max_ms = 500 }", "t")
+                .unwrap()
+                .anonymity
+                .mix_delay,
+            MixDelay::Poisson { .. }
+        ));
+        assert_eq!(
+            field_of(parse("[anonymity]\nmix_delay = { distribution = \"poisson\", mean_ms = 0, max_ms = 500 }", "t")),
+            "anonymity.mix_delay"
+        );
+        assert!(parse("[anonymity]\nmix_delay = { distribution = \"gamma\", mean_ms = 40, max_ms = 500 }", "t").is_err());
+    }
+
+    #[test]
+    fn constant_rate_is_off_unless_configured() {
+        assert_eq!(parse("", "t").unwrap().anonymity.constant_rate, None);
+        let config = parse("[anonymity]\nconstant_rate = { cell_bytes = 512, interval_ms = 20 }", "t").unwrap();
+        assert_eq!(
+            config.anonymity.constant_rate,
+            Some(ConstantRate {
+                cell_size: 512,
+                interval: Duration::from_millis(20),
+            })
+        );
+        assert_eq!(
+            field_of(parse("[anonymity]\nconstant_rate = { cell_bytes = 512, interval_ms = 0 }", "t")),
+            "anonymity.constant_rate"
+        );
+        assert_eq!(
+            field_of(parse("[anonymity]\nconstant_rate = { cell_bytes = 2, interval_ms = 20 }", "t")),
+            "anonymity.constant_rate"
+        );
+    }
+
+    #[test]
+    fn adaptive_padding_histograms_are_validated() {
+        assert_eq!(parse("", "t").unwrap().anonymity.adaptive_padding, None);
+        let config = parse(
+            "[anonymity.adaptive_padding]\ngap = { edges_ms = [10, 40], weights = [3, 1], infinity = 2 }",
+            "t",
+        )
+        .unwrap();
+        let padding = config.anonymity.adaptive_padding.unwrap();
+        assert_eq!(padding.burst, AdaptivePaddingConfig::default().burst);
+        assert_eq!(padding.gap.edges, vec![Duration::from_millis(10), Duration::from_millis(40)]);
+        assert_eq!(padding.gap.infinity, 2);
+        assert_eq!(
+            field_of(parse("[anonymity.adaptive_padding]\nburst = { edges_ms = [10, 5], weights = [1, 1] }", "t")),
+            "anonymity.adaptive_padding.burst"
+        );
+        assert_eq!(
+            field_of(parse("[anonymity.adaptive_padding]\ngap = { edges_ms = [10], weights = [1, 2] }", "t")),
+            "anonymity.adaptive_padding.gap"
+        );
+    }
+
+    #[test]
+    fn delay_budget_floor_must_fit_the_mix_delay() {
+        let config = parse("[anonymity]\ndelay_budget = { floor_ms = 10, target_rtt_ms = 400 }", "t").unwrap();
+        assert_eq!(
+            config.anonymity.delay_budget,
+            Some(DelayBudgetConfig {
+                floor: Duration::from_millis(10),
+                target_rtt: Duration::from_millis(400),
+            })
+        );
+        assert_eq!(
+            field_of(parse("[anonymity]\ndelay_budget = { floor_ms = 0, target_rtt_ms = 400 }", "t")),
+            "anonymity.delay_budget"
+        );
+        assert_eq!(
+            field_of(parse(
+                "[anonymity]\nmix_delay = { distribution = \"uniform\", min_ms = 5, max_ms = 50 }\n\
+                 delay_budget = { floor_ms = 60, target_rtt_ms = 400 }",
+                "t"
+            )),
+            "anonymity.delay_budget"
+        );
+    }
+
+    #[test]
+    fn shaping_profile_is_a_starting_point() {
+        for profile in ShapingProfile::ALL {
+            let config = parse(&format!("[anonymity]\nprofile = \"{}\"", profile.as_str()), "t").unwrap();
+            assert_eq!(config.anonymity, profile.anonymity());
+        }
+
+        let config = parse(
+            "[anonymity]\nprofile = \"paranoid\"\nconstant_rate = { cell_bytes = 1024, interval_ms = 50 }",
+            "t",
+        )
+        .unwrap();
+        assert_eq!(config.anonymity.profile, Some(ShapingProfile::Paranoid));
+        assert_eq!(config.anonymity.mix_delay, ShapingProfile::Paranoid.anonymity().mix_delay);
+        assert_eq!(config.anonymity.constant_rate.unwrap().cell_size, 1024);
+
+        // The profile's budget floor (10ms) no longer fits.
+        assert_eq!(
+            field_of(parse(
+                "[anonymity]\nprofile = \"balanced\"\nmix_delay = { distribution = \"uniform\", min_ms = 1, max_ms = 5 }",
+                "t"
+            )),
+            "anonymity.delay_budget"
+        );
+        assert!(parse("[anonymity]\nprofile = \"fast\"", "t").is_err());
+    }
+
+    fn env(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn environment_overrides_file_values() {
+        let config = parse_with_env(
+            "[proxy]\nbind_port = 8081\nbind_address = \"127.0.0.1\"",
+            "t",
+            None,
+            env(&[
+                ("EBT_PROXY__BIND_PORT", "9090"),
+                ("EBT_PROXY__BIND_ADDRESS", "0.0.0.0"),
+                // Quoted, or it would be read as an integer.
+                ("EBT_PROXY__AUTHENTICATION__CREDENTIAL", "\"12345\""),
+                ("EBT_POLICY__DRY_RUN", "true"),
+                ("EBT_LOG", "debug"),
+            ]),
+        )
+        .unwrap();
+        let proxy = &config.proxy_policy;
+        assert_eq!(proxy.bind_port, 9090);
+        assert_eq!(proxy.bind_address, "0.0.0.0");
+        assert!(proxy.content_policy_dry_run);
+        assert_eq!(
+            proxy.authentication.as_ref().and_then(|auth| auth.credential.as_deref()),
+            Some("12345")
+        );
+
+        assert!(matches!(
+            parse_with_env("", "t", None, env(&[("EBT_PROXY__BIND_PROT", "1")])),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    const PROFILES: &str = r#"
+        [proxy]
+        bind_port = 8081
+        authentication = { credential = "Basic dXNlcjpwYXNz" }
+
+        [policy]
+        enabled = false
+
+        [profiles.default.proxy]
+        bind_port = 8082
+
+        [profiles.strict-privacy.policy]
+        enabled = true
+        block_response = { default = "reset" }
+
+        [profiles.lan-shared.proxy]
+        bind_address = "0.0.0.0"
+        "#;
+
+    #[test]
+    fn profiles_layer_over_base_sections() {
+        let default = parse_with_env(PROFILES, "t", None, Vec::new()).unwrap();
+        assert_eq!(default.proxy_policy.bind_port, 8082);
+        assert!(!default.proxy_policy.content_policy_enabled);
+
+        let strict = parse_with_env(PROFILES, "t", Some("strict-privacy"), Vec::new()).unwrap();
+        assert_eq!(strict.proxy_policy.bind_port, 8081);
+        assert!(strict.proxy_policy.content_policy_enabled);
+        assert_eq!(strict.proxy_policy.content_policy_block_behavior.ads, BlockResponse::Reset);
+
+        // Merged key by key: authentication from the base section survives.
+        let lan = parse_with_env(
+            PROFILES,
+            "t",
+            Some("lan-shared"),
+            env(&[("EBT_PROXY__BIND_PORT", "3128")]),
+        )
+        .unwrap();
+        assert_eq!(lan.proxy_policy.bind_address, "0.0.0.0");
+        assert_eq!(lan.proxy_policy.bind_port, 3128);
+        assert!(lan.proxy_policy.authentication.is_some());
+
+        match parse_with_env(PROFILES, "t", Some("travel"), Vec::new()) {
+            Err(ConfigError::Invalid { field, reason }) => {
+                assert_eq!(field, "profiles");
+                assert!(reason.ends_with("available: default, lan-shared, strict-privacy"));
+            }
+            other => panic!("expected an unknown profile error, got {:?}", other),
+        }
+        assert!(matches!(
+            parse_with_env("[profiles.x.proxy]\nbind_prot = 1", "t", Some("x"), Vec::new()),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn expands_env_and_file_references() {
+        let var = format!("EBT_TEST_SECRET_{}", std::process::id());
+        std::env::set_var(&var, "dXNlcjpwYXNz");
+        let secret_path = std::env::temp_dir().join(format!("ebt-secret-{}", std::process::id()));
+        std::fs::write(&secret_path, "from-file\n").unwrap();
+
+        let text = format!(
+            "[proxy]\nauthentication = {{ credential = \"Basic ${{env:{}}}\" }}\n\
+             [observability]\nlog = \"${{file:{}}}\"",
+            var,
+            secret_path.display()
+        );
+        let config = parse_with_env(&text, "t", None, Vec::new()).unwrap();
+        assert_eq!(
+            config.proxy_policy.authentication.and_then(|auth| auth.credential),
+            Some("Basic dXNlcjpwYXNz".to_string())
+        );
+        assert!(config.observability.log.is_some());
+
+        let missing = "[proxy]\nauthentication = { credential = \"${env:EBT_TEST_UNSET_SECRET}\" }";
+        assert_eq!(
+            field_of(parse_with_env(missing, "t", None, Vec::new())),
+            "proxy.authentication.credential"
+        );
+        std::env::remove_var(&var);
+        let _ = std::fs::remove_file(&secret_path);
+    }
+
+    #[test]
+    fn warns_about_ignored_settings_and_misspelled_variables() {
+        let config = parse_with_env(
+            "[proxy]\nmode = \"system\"\nbind_address = \"0.0.0.0\"\n[policy]\nenabled = false\nrules = \"ads.txt\"",
+            "t",
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        let warnings = warnings(
+            &config,
+            env(&[("EBT_POLICY_ENABLED", "true"), ("EBT_LOG", "info"), ("EBT_PROXY__BIND_PORT", "1")]),
+        );
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+        assert!(warnings[0].starts_with("EBT_POLICY_ENABLED is not a recognized variable"));
+        assert_eq!(warnings[1], "policy.rules ignored while policy.enabled = false");
+        assert!(warnings[2].starts_with("proxy.mode = \"system\" changes no OS settings"));
+        assert!(warnings[3].starts_with("proxy.bind_address 0.0.0.0"));
+    }
+
+    #[test]
+    fn schema_covers_sections_and_profiles() {
+        let schema: serde_json::Value = serde_json::from_str(&schema_json()).unwrap();
+        let properties = &schema["properties"];
+        for section in ["execution", "transport", "dns", "proxy", "relay", "policy", "anonymity", "observability", "profiles"] {
+            assert!(properties.get(section).is_some(), "missing {}", section);
+        }
+        assert_eq!(schema["additionalProperties"], serde_json::Value::Bool(false));
+    }
+
+    #[test]
+    fn dns_prefetch_hints_are_normalised_and_bounded() {
+        let text = "[dns]\nprefetch = [{ host = \"News.example.\", companions = [\"cdn.example.net\"] }]";
+        if cfg!(any(feature = "single_hop_relay", feature = "multi_hop_relay")) {
+            assert_eq!(field_of(parse(text, "t")), "dns.prefetch");
+            return;
+        }
+        assert!(parse("", "t").unwrap().dns_policy.prefetch.is_empty());
+        let config = parse(text, "t").unwrap();
+        assert_eq!(
+            config.dns_policy.prefetch,
+            vec![PrefetchHint { host: "news.example".to_string(), companions: vec!["cdn.example.net".to_string()] }]
+        );
+        assert_eq!(
+            field_of(parse("[dns]\nprefetch = [{ host = \"a.example\", companions = [] }]", "t")),
+            "dns.prefetch"
+        );
+        assert_eq!(
+            field_of(parse("[dns]\nprefetch = [{ host = \"a.example\", companions = [\"10.0.0.1\"] }]", "t")),
+            "dns.prefetch"
+        );
+    }
+
+    #[test]
+    fn adaptive_tunnel_limit_brackets_the_starting_limit() {
+        assert_eq!(parse("", "t").unwrap().proxy_policy.adaptive_tunnel_limit, None);
+        let text = "[proxy]\nmax_concurrent_tunnels = 64\n[proxy.adaptive_tunnel_limit]\nmax_tunnels = 512";
+        let limit = parse(text, "t").unwrap().proxy_policy.adaptive_tunnel_limit.unwrap();
+        assert_eq!((limit.min, limit.max), (16, 512));
+        let text = "[proxy]\nmax_concurrent_tunnels = 64\n[proxy.adaptive_tunnel_limit]\nmin_tunnels = 100";
+        assert_eq!(field_of(parse(text, "t")), "proxy.adaptive_tunnel_limit");
+    }
+
+    #[test]
+    fn connect_retry_budget_is_configurable() {
+        assert_eq!(parse("", "t").unwrap().transport.connect_retry, ConnectRetry::default());
+        let text = "[transport]\nconnect_attempts = 5\nconnect_timeout_ms = 800\nconnect_backoff_ms = 50";
+        let retry = parse(text, "t").unwrap().transport.connect_retry;
+        assert_eq!((retry.attempts, retry.attempt_timeout), (5, Duration::from_millis(800)));
+        assert_eq!(retry.backoff, Duration::from_millis(50));
+        assert_eq!(field_of(parse("[transport]\nconnect_attempts = 0", "t")), "transport.connect_attempts");
+        assert_eq!(
+            field_of(parse("[transport]\nconnect_backoff_ms = 5000", "t")),
+            "transport.connect_max_backoff_ms"
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        assert_eq!(field_of(parse("[proxy]\nconfigure_system = true", "t")), "proxy.configure_system");
+        let system = parse("[proxy]\nmode = \"system\"\nconfigure_system = true", "t").unwrap();
+        assert!(system.proxy_policy.configure_system);
+        assert_eq!(
+            field_of(parse("[dns]\nresolution = \"local\"\nleak_detection = \"strict\"", "t")),
+            "dns.leak_detection"
+        );
+        assert_eq!(
+            field_of(parse("[policy]\nenabled = false\ndry_run = true", "t")),
+            "policy.dry_run"
+        );
+        assert_eq!(
+            field_of(parse("[policy]\nenabled = false\ninspect_sni = true", "t")),
+            "policy.inspect_sni"
+        );
+        assert_eq!(field_of(parse("[policy]\ntime_zone = \"../../etc/passwd\"", "t")), "policy.time_zone");
+        let zone = parse("[policy]\nenabled = true\ntime_zone = \"/etc/localtime\"", "t").unwrap_err();
+        assert!(zone.to_string().ends_with("zone in the zoneinfo database (line 3)"), "{zone}");
+        assert_eq!(
+            field_of(parse(
+                "[policy]\nrules = \"ads.txt\"\nrulesets = [{ name = \"ads\", location = \"a\" }]\n\
+                 assignments = [{ scope = \"listener\", rulesets = [\"ads\"] }]",
+                "t"
+            )),
+            "policy.assignments[0].scope"
+        );
+        assert_eq!(
+            field_of(parse("[policy]\nassignments = [{ scope = \"10.0.0.0/8\", rulesets = [\"nope\"] }]", "t")),
+            "policy.assignments[0].rulesets"
+        );
+        assert_eq!(
+            field_of(parse("[proxy]\nauthentication = { method = \"basic\" }", "t")),
+            "proxy.authentication.credential"
+        );
+    }
+}
@@ -1,14 +1,33 @@
+//! Binds browser sockets to `ProtocolEngine` logical connections. Each
+//! mapped socket gets a reader thread that turns what the browser sends
+//! into `Data` frames, held to the connection's send window;
+//! `deliver_inbound` writes what the peer sent back to the socket.
+//!
+//! Either side may close. A browser EOF closes the logical connection
+//! toward the peer; a peer `Close` or `Error` shuts the socket. Both reach
+//! `deliver_inbound` as `InboundEvent::Closed`, which drops the mapping.
+
 use std::collections::HashMap;
-use std::net::TcpStream;
+use std::io::Read;
+use std::net::{Shutdown, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::marker::PhantomData;
+use std::thread;
+use std::time::Duration;
 use crate::anonymity::invariants::{
     AllowsPerUserConnectionOwnership,
     AllowsRelayLocalLinkability,
     AllowsStableSocketMapping,
 };
 use crate::transport_adapter::{TcpTransportAdapter, TransportAdapter};
-use crate::protocol_engine::ProtocolEngine;
+use crate::protocol_engine::{InboundEvent, ProtocolEngine};
+use crate::relay_protocol::{ConnectionState, ConnectionTable};
+use crate::core::observability;
+
+/// Largest `Data` payload read from a browser socket at once.
+const READ_CHUNK: usize = 4096;
+/// How long a reader waits before asking again for send credits.
+const CREDIT_WAIT: Duration = Duration::from_millis(1);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BrowserSocketId(usize);
@@ -29,7 +48,9 @@ pub struct ConnectionMapping<Phase: AllowsPerUserConnectionOwnership
 
 impl<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
-    + AllowsRelayLocalLinkability> ConnectionMapping<Phase> {
+    + AllowsRelayLocalLinkability
+    + Send
+    + 'static> ConnectionMapping<Phase> {
     pub fn new() -> Self {
         Self {
             socket_to_logical: HashMap::new(),
@@ -45,13 +66,23 @@ impl<Phase: AllowsPerUserConnectionOwnership
     pub fn create_mapping(
         &mut self, 
         browser_socket: TcpStream,
-        _protocol_engine: &Arc<Mutex<ProtocolEngine<Phase>>>
+        target_host: String,
+        target_port: u16,
+        protocol_engine: &Arc<Mutex<ProtocolEngine<Phase>>>
     ) -> Result<(BrowserSocketId, LogicalConnectionId), &'static str> {
-        let socket_id = BrowserSocketId(self.next_socket_id);
-        self.next_socket_id += 1;
-        
+        let reader = browser_socket.try_clone().map_err(|_| "Browser socket cannot be read")?;
         let logical_id = LogicalConnectionId(self.next_logical_id);
+        let table = {
+            let mut engine = protocol_engine.lock().map_err(|_| "Protocol engine poisoned")?;
+            engine
+                .open_logical_connection(logical_id.0, target_host, target_port)
+                .map_err(|_| "Protocol engine refused the connection")?;
+            engine.connection_table()
+        };
         self.next_logical_id += 1;
+
+        let socket_id = BrowserSocketId(self.next_socket_id);
+        self.next_socket_id += 1;
         
         // Create transport adapter for this connection
         let transport = Box::new(TcpTransportAdapter::new(browser_socket));
@@ -61,9 +92,7 @@ impl<Phase: AllowsPerUserConnectionOwnership
         self.logical_to_socket.insert(logical_id, socket_id);
         self.logical_to_transport.insert(logical_id, transport);
         
-        // Register with protocol engine
-        // Note: ProtocolEngine no longer has add_transport method
-        // Transport registration handled by binding layer
+        spawn_socket_reader(reader, logical_id, Arc::clone(protocol_engine), table);
         
         Ok((socket_id, logical_id))
     }
@@ -83,12 +112,12 @@ impl<Phase: AllowsPerUserConnectionOwnership
         socket_id: BrowserSocketId,
         protocol_engine: &Arc<Mutex<ProtocolEngine<Phase>>>
     ) {
-        if let Some(_logical_id) = self.socket_to_logical.get(&socket_id) {
+        if let Some(logical_id) = self.socket_to_logical.get(&socket_id) {
             // Notify protocol engine of socket close - do NOT destroy state
-            // Protocol engine decides cleanup policy
-            if let Ok(_engine) = protocol_engine.lock() {
-                // Protocol receives notification but maintains state authority
-                // Transport is closed but protocol connection may remain
+            // Protocol engine decides cleanup policy; its Closed event
+            // reaches deliver_inbound, which drops the rest.
+            if let Ok(mut engine) = protocol_engine.lock() {
+                engine.close_logical_connection(logical_id.0);
             }
             
             // Remove socket mapping but keep logical connection
@@ -109,7 +138,39 @@ impl<Phase: AllowsPerUserConnectionOwnership
         
         // Close transport via binding layer (not protocol engine)
         // Protocol engine no longer manages transports directly
-        self.logical_to_transport.remove(&logical_id);
+        if let Some(mut transport) = self.logical_to_transport.remove(&logical_id) {
+            transport.close_transport();
+        }
+    }
+
+    /// Writes what the peer sent to the mapped browser sockets and drops
+    /// the mappings of closed connections. Returns the events handled.
+    pub fn deliver_inbound(&mut self, protocol_engine: &Arc<Mutex<ProtocolEngine<Phase>>>) -> usize {
+        let events = match protocol_engine.lock() {
+            Ok(mut engine) => engine.poll_inbound_events(),
+            Err(_) => return 0,
+        };
+        for event in &events {
+            match event {
+                InboundEvent::Data { conn_id, payload } => {
+                    let logical_id = LogicalConnectionId(*conn_id);
+                    let Some(transport) = self.logical_to_transport.get_mut(&logical_id) else {
+                        continue;
+                    };
+                    if transport.send_bytes(payload).is_err() {
+                        observability::record_error(observability::ErrorClass::TRANSPORT_IO);
+                        // The Closed this queues drops the mapping next time
+                        if let Ok(mut engine) = protocol_engine.lock() {
+                            engine.close_logical_connection(*conn_id);
+                        }
+                    }
+                }
+                InboundEvent::Closed { conn_id } => {
+                    self.protocol_close_connection(LogicalConnectionId(*conn_id), protocol_engine);
+                }
+            }
+        }
+        events.len()
     }
     
     #[deprecated(note = "Phase 9 forbids exposing full socket/logical mappings; relay-local linkability is disallowed.")]
@@ -120,31 +181,110 @@ impl<Phase: AllowsPerUserConnectionOwnership
     }
 }
 
+/// Carries what the browser sends on `socket` into `Data` frames, waiting
+/// for send credits as needed, until EOF closes the logical connection or
+/// the engine forgets it.
+fn spawn_socket_reader<Phase: AllowsRelayLocalLinkability + Send + 'static>(
+    mut socket: TcpStream,
+    logical_id: LogicalConnectionId,
+    protocol_engine: Arc<Mutex<ProtocolEngine<Phase>>>,
+    table: Arc<ConnectionTable>,
+) {
+    let conn_id = logical_id.0;
+    thread::spawn(move || {
+        let mut buffer = [0u8; READ_CHUNK];
+        loop {
+            let read = match socket.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            // Credits are awaited on the table alone, leaving the engine
+            // to the connections that have some.
+            loop {
+                match table.get_state(conn_id) {
+                    // Closed by the peer; deliver_inbound shuts the socket
+                    None => return,
+                    Some(ConnectionState::Open) if table.can_send_data(conn_id, read as u32) => {
+                        let Ok(mut engine) = protocol_engine.lock() else {
+                            return;
+                        };
+                        if engine.queue_data_frame(conn_id, &buffer[..read]).is_ok() {
+                            break;
+                        }
+                    }
+                    Some(_) => {}
+                }
+                thread::sleep(CREDIT_WAIT);
+            }
+        }
+        if let Ok(mut engine) = protocol_engine.lock() {
+            engine.close_logical_connection(conn_id);
+        }
+        let _ = socket.shutdown(Shutdown::Read);
+    });
+}
+
 pub struct ConnectionManager<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
     + AllowsRelayLocalLinkability> {
     mapping: Arc<Mutex<ConnectionMapping<Phase>>>,
     protocol_engine: Arc<Mutex<ProtocolEngine<Phase>>>,
+    running: Arc<Mutex<bool>>,
     _phase: PhantomData<Phase>,
 }
 
 impl<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
-    + AllowsRelayLocalLinkability> ConnectionManager<Phase> {
+    + AllowsRelayLocalLinkability
+    + Send
+    + 'static> ConnectionManager<Phase> {
     pub fn new(protocol_engine: Arc<Mutex<ProtocolEngine<Phase>>>) -> Self {
         Self {
             mapping: Arc::new(Mutex::new(ConnectionMapping::new())),
             protocol_engine,
+            running: Arc::new(Mutex::new(false)),
             _phase: PhantomData,
         }
     }
     
+    /// Maps `browser_socket` to a new logical connection toward
+    /// `target_host:target_port` and starts reading it.
     pub fn handle_new_browser_connection(
         &self,
-        browser_socket: TcpStream
+        browser_socket: TcpStream,
+        target_host: String,
+        target_port: u16,
     ) -> Result<(BrowserSocketId, LogicalConnectionId), &'static str> {
         let mut mapping = self.mapping.lock().unwrap();
-        mapping.create_mapping(browser_socket, &self.protocol_engine)
+        mapping.create_mapping(browser_socket, target_host, target_port, &self.protocol_engine)
+    }
+
+    pub fn deliver_inbound(&self) -> usize {
+        let mut mapping = self.mapping.lock().unwrap();
+        mapping.deliver_inbound(&self.protocol_engine)
+    }
+
+    /// Runs `deliver_inbound` on its own thread until `stop`.
+    pub fn start(&self) {
+        *self.running.lock().unwrap() = true;
+
+        let mapping = Arc::clone(&self.mapping);
+        let protocol_engine = Arc::clone(&self.protocol_engine);
+        let running = Arc::clone(&self.running);
+
+        thread::spawn(move || {
+            while *running.lock().unwrap() {
+                if let Ok(mut mapping) = mapping.lock() {
+                    mapping.deliver_inbound(&protocol_engine);
+                }
+                // Small yield to prevent busy loop
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        *self.running.lock().unwrap() = false;
     }
     
     pub fn notify_browser_socket_closed(&self, socket_id: BrowserSocketId) {
@@ -157,3 +297,92 @@ impl<Phase: AllowsPerUserConnectionOwnership
         mapping.protocol_close_connection(logical_id, &self.protocol_engine);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use crate::anonymity::invariants::LegacyPhase;
+    use crate::relay_protocol::{FrameDecoder, FrameEncoder, FrameType, LegacyControlMessage, LegacyDataFrame};
+    use crate::relay_server::default_limits;
+
+    type Engine = Arc<Mutex<ProtocolEngine<LegacyPhase>>>;
+
+    /// The proxy's end of a browser connection, and the browser's.
+    fn browser_socket() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let browser = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        browser.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (listener.accept().unwrap().0, browser)
+    }
+
+    fn from_peer(engine: &Engine, frame_type: FrameType, payload: &[u8]) {
+        let mut frame = Vec::new();
+        FrameEncoder::encode_frame(&mut frame, 1, frame_type, payload).unwrap();
+        engine.lock().unwrap().on_transport_bytes(0, &frame);
+    }
+
+    /// The next frame queued toward the peer for `conn_id`, waiting for it.
+    fn to_peer(engine: &Engine, conn_id: u32) -> (FrameType, Vec<u8>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(frame) = engine.lock().unwrap().next_outbound_frame(conn_id) {
+                let (_, frame_type, payload) = FrameDecoder::decode_frame(&mut Cursor::new(frame)).unwrap();
+                return (frame_type, payload);
+            }
+            assert!(Instant::now() < deadline, "nothing queued for the peer");
+            thread::sleep(CREDIT_WAIT);
+        }
+    }
+
+    fn opened(engine: &Engine, manager: &ConnectionManager<LegacyPhase>) -> (TcpStream, u32) {
+        let (proxy_end, browser) = browser_socket();
+        let (_, logical_id) = manager.handle_new_browser_connection(proxy_end, "example.com".to_string(), 443).unwrap();
+        let conn_id = logical_id.0;
+        let (frame_type, open) = to_peer(engine, conn_id);
+        assert_eq!(frame_type, FrameType::Control);
+        let open = LegacyControlMessage::decode(&open).unwrap();
+        assert!(matches!(open, LegacyControlMessage::Open { target_port: 443, .. }));
+        let ack = LegacyControlMessage::WindowUpdate { conn_id, credits: 0 };
+        from_peer(engine, FrameType::Control, &ack.encode());
+        (browser, conn_id)
+    }
+
+    #[test]
+    fn bytes_flow_both_ways_until_the_peer_closes() {
+        let engine: Engine = Arc::new(Mutex::new(ProtocolEngine::new(default_limits())));
+        let manager = ConnectionManager::new(Arc::clone(&engine));
+        let (mut browser, conn_id) = opened(&engine, &manager);
+
+        browser.write_all(b"request").unwrap();
+        let (frame_type, data) = to_peer(&engine, conn_id);
+        assert_eq!(frame_type, FrameType::Data);
+        assert_eq!(LegacyDataFrame::decode(&data).unwrap(), LegacyDataFrame::new(conn_id, b"request".to_vec()));
+
+        from_peer(&engine, FrameType::Data, &LegacyDataFrame::new(conn_id, b"response".to_vec()).encode());
+        from_peer(&engine, FrameType::Control, &LegacyControlMessage::Close { conn_id, reason: 0 }.encode());
+        assert_eq!(manager.deliver_inbound(), 2);
+        let mut received = Vec::new();
+        browser.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"response");
+        assert!(manager.mapping.lock().unwrap().logical_to_transport.is_empty());
+        assert_eq!(engine.lock().unwrap().connection_state(conn_id), None);
+    }
+
+    #[test]
+    fn browser_eof_closes_toward_the_peer_and_drops_the_mapping() {
+        let engine: Engine = Arc::new(Mutex::new(ProtocolEngine::new(default_limits())));
+        let manager = ConnectionManager::new(Arc::clone(&engine));
+        let (browser, conn_id) = opened(&engine, &manager);
+
+        browser.shutdown(Shutdown::Write).unwrap();
+        let (frame_type, close) = to_peer(&engine, conn_id);
+        assert_eq!(frame_type, FrameType::Control);
+        assert!(matches!(LegacyControlMessage::decode(&close).unwrap(), LegacyControlMessage::Close { .. }));
+        assert_eq!(manager.deliver_inbound(), 1);
+        let mapping = manager.mapping.lock().unwrap();
+        assert!(mapping.logical_to_socket.is_empty() && mapping.logical_to_transport.is_empty());
+    }
+}
@@ -1,12 +1,62 @@
-use super::{ReasonCode, Rule, RuleAction, RuleSet};
+use super::{ReasonCode, Rule, RuleAction, RuleOptions, RuleSet};
 
 const EASYLIST_MAX_RULES: usize = 50_000;
 const EASYLIST_MAX_LINE_LEN: usize = 1024;
 
+/// Line-numbered parse failure from `try_ruleset_from_easylist`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EasyListError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for EasyListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "filter list line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for EasyListError {}
+
+/// Lenient parse: malformed lines are skipped like unsupported ones. Block
+/// rules are reported as ads; see `ruleset_from_easylists` for other lists.
 pub fn ruleset_from_easylist(text: &str) -> RuleSet {
+    ruleset_from_easylists(&[(ReasonCode::Ads, text)])
+}
+
+/// Lenient parse of several lists into one set, in the order given. Each
+/// list's block rules carry the reason code it is tagged with, so a malware
+/// list blocks as malware rather than ads. The rule cap covers all lists.
+pub fn ruleset_from_easylists(lists: &[(ReasonCode, &str)]) -> RuleSet {
     let mut rules = Vec::new();
+    for (reason, text) in lists {
+        let (parsed, _) = parse_rules(text, *reason);
+        rules.extend(parsed);
+    }
+    rules.truncate(EASYLIST_MAX_RULES);
+    RuleSet::new(rules)
+}
 
-    for raw_line in text.lines() {
+/// Strict parse: unsupported syntax is still skipped, but malformed rules and
+/// non-filter-list content (e.g. an HTML error page) are rejected.
+pub fn try_ruleset_from_easylist(text: &str) -> Result<RuleSet, EasyListError> {
+    try_ruleset_from_easylist_as(text, ReasonCode::Ads)
+}
+
+/// Strict parse of a list whose block rules carry `reason`.
+pub fn try_ruleset_from_easylist_as(text: &str, reason: ReasonCode) -> Result<RuleSet, EasyListError> {
+    match parse_rules(text, reason) {
+        (_, Some(err)) => Err(err),
+        (rules, None) => Ok(RuleSet::new(rules)),
+    }
+}
+
+fn parse_rules(text: &str, reason: ReasonCode) -> (Vec<Rule>, Option<EasyListError>) {
+    let mut rules = Vec::new();
+    let mut first_error = None;
+    let mut seen_content = false;
+
+    for (line_index, raw_line) in text.lines().enumerate() {
         if rules.len() >= EASYLIST_MAX_RULES {
             break;
         }
@@ -15,6 +65,15 @@ pub fn ruleset_from_easylist(text: &str) -> RuleSet {
         if line.is_empty() {
             continue;
         }
+        if first_error.is_none() {
+            if let Some(reason) = malformed_reason(line, seen_content) {
+                first_error = Some(EasyListError {
+                    line: line_index + 1,
+                    reason,
+                });
+            }
+        }
+        seen_content = true;
         if line.len() > EASYLIST_MAX_LINE_LEN {
             continue;
         }
@@ -24,23 +83,65 @@ pub fn ruleset_from_easylist(text: &str) -> RuleSet {
         if is_cosmetic_or_element_hiding(line) {
             continue;
         }
-        if line.contains('$') {
-            continue;
-        }
+        // Rules with options outside the supported subset are skipped whole.
+        let (line, options) = match line.rsplit_once('$') {
+            Some((body, options)) => match parse_options(options) {
+                Some(options) => (body, Some(options)),
+                None => continue,
+            },
+            None => (line, None),
+        };
         if is_regex_rule(line) {
             continue;
         }
 
-        if let Some(rule) = parse_domain_rule(line) {
-            rules.push(rule);
+        if let Some(rule) = parse_domain_rule(line, reason) {
+            rules.push(match options {
+                Some(options) => Rule::Constrained {
+                    options,
+                    rule: Box::new(rule),
+                },
+                None => rule,
+            });
         }
     }
 
-    RuleSet::new(rules)
+    (rules, first_error)
 }
 
-fn parse_domain_rule(line: &str) -> Option<Rule> {
-    let (action, body) = parse_action(line)?;
+fn malformed_reason(line: &str, seen_content: bool) -> Option<&'static str> {
+    if line.contains('\0') {
+        return Some("binary content");
+    }
+    if !seen_content && line.starts_with('<') {
+        return Some("markup instead of a filter list");
+    }
+    if is_comment_or_header(line) || is_cosmetic_or_element_hiding(line) {
+        return None;
+    }
+    let body = line.strip_prefix("@@").unwrap_or(line);
+    if body.is_empty() {
+        return Some("empty exception rule");
+    }
+    let target = body
+        .strip_prefix("||")
+        .or_else(|| body.strip_prefix("|http://"))
+        .or_else(|| body.strip_prefix("|https://"))?;
+    if target.starts_with('*') {
+        return None;
+    }
+    let end = target
+        .find(|ch: char| !is_domain_char(ch))
+        .unwrap_or(target.len());
+    if is_simple_domain(&target[..end]) {
+        None
+    } else {
+        Some("invalid domain")
+    }
+}
+
+fn parse_domain_rule(line: &str, reason: ReasonCode) -> Option<Rule> {
+    let (action, body) = parse_action(line, reason)?;
 
     if let Some(suffix) = parse_domain_suffix(body) {
         return Some(Rule::DomainSuffix { suffix, action });
@@ -53,11 +154,38 @@ fn parse_domain_rule(line: &str) -> Option<Rule> {
     None
 }
 
-fn parse_action(line: &str) -> Option<(RuleAction, &str)> {
+/// Supports `third-party`, `~third-party` and `domain=a.com|~b.com`; these
+/// need a `Referer`, so in practice they only apply to plain-HTTP requests.
+fn parse_options(text: &str) -> Option<RuleOptions> {
+    let mut options = RuleOptions::default();
+    for option in text.split(',') {
+        match option.trim() {
+            "third-party" => options.third_party = Some(true),
+            "~third-party" => options.third_party = Some(false),
+            option => {
+                let domains = option.strip_prefix("domain=")?;
+                for domain in domains.split('|') {
+                    match domain.strip_prefix('~') {
+                        Some(excluded) if is_simple_domain(excluded) => {
+                            options.exclude_domains.push(excluded.to_ascii_lowercase());
+                        }
+                        None if is_simple_domain(domain) => {
+                            options.include_domains.push(domain.to_ascii_lowercase());
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+        }
+    }
+    Some(options)
+}
+
+fn parse_action(line: &str, reason: ReasonCode) -> Option<(RuleAction, &str)> {
     if let Some(body) = line.strip_prefix("@@") {
         Some((RuleAction::Allow, body))
     } else {
-        Some((RuleAction::Block(ReasonCode::Ads), line))
+        Some((RuleAction::Block(reason), line))
     }
 }
 
@@ -170,3 +298,72 @@ fn is_simple_domain(domain: &str) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_options_wrap_domain_rules() {
+        let ruleset = ruleset_from_easylist(
+            "||ads.example.com^$third-party\n\
+             @@||cdn.example.com^$~third-party,domain=example.com|~m.example.com\n\
+             ||track.example.net^$script,third-party\n\
+             ||pixel.example.org^$domain=\n",
+        );
+        assert_eq!(
+            ruleset.rules(),
+            &[
+                Rule::Constrained {
+                    options: RuleOptions {
+                        third_party: Some(true),
+                        ..RuleOptions::default()
+                    },
+                    rule: Box::new(Rule::DomainSuffix {
+                        suffix: "ads.example.com".to_string(),
+                        action: RuleAction::Block(ReasonCode::Ads),
+                    }),
+                },
+                Rule::Constrained {
+                    options: RuleOptions {
+                        third_party: Some(false),
+                        include_domains: vec!["example.com".to_string()],
+                        exclude_domains: vec!["m.example.com".to_string()],
+                    },
+                    rule: Box::new(Rule::DomainSuffix {
+                        suffix: "cdn.example.com".to_string(),
+                        action: RuleAction::Allow,
+                    }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tagged_lists_block_with_their_own_reason() {
+        let ruleset = ruleset_from_easylists(&[
+            (ReasonCode::Ads, "||ads.example.com^\n"),
+            (ReasonCode::Malware, "||payload.example.net^\n@@||safe.example.net^\n"),
+            (ReasonCode::Tracking, "||pixel.example.org^\n"),
+        ]);
+        let actions: Vec<_> = ruleset
+            .rules()
+            .iter()
+            .map(|rule| match rule {
+                Rule::DomainSuffix { action, .. } => *action,
+                other => panic!("unexpected rule {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                RuleAction::Block(ReasonCode::Ads),
+                RuleAction::Block(ReasonCode::Malware),
+                RuleAction::Allow,
+                RuleAction::Block(ReasonCode::Tracking),
+            ]
+        );
+        let strict = try_ruleset_from_easylist_as("||payload.example.net^\n", ReasonCode::Malware).unwrap();
+        assert_eq!(strict.rules(), &ruleset.rules()[1..2]);
+    }
+}
@@ -4,11 +4,31 @@
 //! Any change here requires explicit Phase 7.5 review.
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
 
 mod easylist;
+mod index;
+mod native;
+mod pattern;
+mod schedule;
+mod time_zone;
 
 #[allow(unused_imports)]
-pub use easylist::ruleset_from_easylist;
+pub use easylist::{
+    ruleset_from_easylist, ruleset_from_easylists, try_ruleset_from_easylist, try_ruleset_from_easylist_as,
+    EasyListError,
+};
+#[allow(unused_imports)]
+pub use native::{parse_native_rules, to_native_rules, NativeRulesError};
+#[allow(unused_imports)]
+pub use pattern::PatternError;
+#[allow(unused_imports)]
+pub use schedule::{ActiveHours, PolicyClock, SystemClock};
+pub use time_zone::TimeZone;
+
+use crate::hostname;
+use index::RuleIndex;
+use pattern::{compile_regex, CompiledPattern, WildcardPattern};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RequestMetadata {
@@ -17,6 +37,7 @@ pub struct RequestMetadata {
     pub host: String,
     pub port: u16,
     headers: BTreeMap<String, String>,
+    referer_host: Option<String>,
 }
 
 impl RequestMetadata {
@@ -27,18 +48,55 @@ impl RequestMetadata {
         port: u16,
         headers: BTreeMap<String, String>,
     ) -> Self {
+        let referer_host = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("referer"))
+            .and_then(|(_, value)| url_host(value));
         Self {
             method,
             full_url,
             host,
             port,
             headers,
+            referer_host,
         }
     }
 
     pub fn headers(&self) -> &BTreeMap<String, String> {
         &self.headers
     }
+
+    /// Host of the page that issued the request, taken from `Referer`;
+    /// usually absent on CONNECT.
+    pub fn referer_host(&self) -> Option<&str> {
+        self.referer_host.as_deref()
+    }
+
+    /// `None` when there is no referer to compare against. Parties are
+    /// compared by their last two labels, without a public suffix list.
+    pub fn is_third_party(&self) -> Option<bool> {
+        let referer = self.referer_host.as_deref()?;
+        Some(base_domain(referer) != base_domain(&self.host.to_ascii_lowercase()))
+    }
+}
+
+fn url_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host_port = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host_port.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host_port,
+    };
+    Some(host.to_ascii_lowercase()).filter(|host| !host.is_empty())
+}
+
+fn base_domain(host: &str) -> &str {
+    let host = host.trim_end_matches('.');
+    match host.rmatch_indices('.').nth(1) {
+        Some((idx, _)) => &host[idx + 1..],
+        None => host,
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,10 +109,23 @@ pub enum Decision {
 pub enum ReasonCode {
     Ads,
     Tracking,
+    Malware,
     Custom,
     Unknown,
 }
 
+impl ReasonCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasonCode::Ads => "ads",
+            ReasonCode::Tracking => "tracking",
+            ReasonCode::Malware => "malware",
+            ReasonCode::Custom => "custom",
+            ReasonCode::Unknown => "unknown",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Rule {
     DomainExact {
@@ -74,6 +145,124 @@ pub enum Rule {
         value: String,
         action: RuleAction,
     },
+    /// Glob over the full URL: `*` any run, `^` separator. Unanchored.
+    UrlWildcard {
+        pattern: String,
+        action: RuleAction,
+    },
+    /// Case-insensitive regex over the full URL. Size- and nesting-bounded.
+    UrlRegex {
+        pattern: String,
+        action: RuleAction,
+    },
+    /// `rule` is only in effect while the local time falls within `hours`.
+    Scheduled {
+        hours: ActiveHours,
+        rule: Box<Rule>,
+    },
+    /// `rule` restricted by request context, from EasyList `$` options.
+    Constrained {
+        options: RuleOptions,
+        rule: Box<Rule>,
+    },
+}
+
+impl Rule {
+    /// What the rule matches on; used as its label in hit telemetry.
+    pub fn target(&self) -> &str {
+        match self {
+            Rule::DomainExact { domain, .. } => domain,
+            Rule::DomainSuffix { suffix, .. } => suffix,
+            Rule::UrlPrefix { prefix, .. } => prefix,
+            Rule::HeaderEquals { name, .. } => name,
+            Rule::UrlWildcard { pattern, .. } | Rule::UrlRegex { pattern, .. } => pattern,
+            Rule::Scheduled { rule, .. } | Rule::Constrained { rule, .. } => rule.target(),
+        }
+    }
+
+    /// True when matching looks only at the request host and URL, so the
+    /// outcome is the same for every request to the same target.
+    pub fn is_target_only(&self) -> bool {
+        matches!(
+            self,
+            Rule::DomainExact { .. }
+                | Rule::DomainSuffix { .. }
+                | Rule::UrlPrefix { .. }
+                | Rule::UrlWildcard { .. }
+                | Rule::UrlRegex { .. }
+        )
+    }
+
+    /// Domain rules with their domain in `hostname::canonicalize` form, the
+    /// form the proxy gives request hosts in.
+    fn canonical(self) -> Rule {
+        match self {
+            Rule::DomainExact { domain, action } => Rule::DomainExact {
+                domain: hostname::canonical_or_lowercase(&domain),
+                action,
+            },
+            Rule::DomainSuffix { suffix, action } => Rule::DomainSuffix {
+                suffix: hostname::canonical_or_lowercase(&suffix),
+                action,
+            },
+            Rule::Scheduled { hours, rule } => Rule::Scheduled {
+                hours,
+                rule: Box::new(rule.canonical()),
+            },
+            Rule::Constrained { options, rule } => Rule::Constrained {
+                options,
+                rule: Box::new(rule.canonical()),
+            },
+            rule => rule,
+        }
+    }
+
+    /// The rule with any scheduling or context wrappers removed.
+    pub fn base(&self) -> &Rule {
+        match self {
+            Rule::Scheduled { rule, .. } | Rule::Constrained { rule, .. } => rule.base(),
+            rule => rule,
+        }
+    }
+}
+
+/// Context constraints supported from EasyList options: `$third-party`,
+/// `$~third-party` and `$domain=a.com|~b.com`.
+///
+/// They compare against the proxy request's `Referer`, which browsers send
+/// with plain-HTTP requests but almost never with CONNECT. For HTTPS, then,
+/// rules that need a referer never fire, and only `~domain` exclusions
+/// alone still match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleOptions {
+    /// `Some(true)` for third-party requests only, `Some(false)` for first-party only.
+    pub third_party: Option<bool>,
+    /// Referring page must be on one of these domains, if any are listed.
+    pub include_domains: Vec<String>,
+    /// Referring page must not be on any of these domains.
+    pub exclude_domains: Vec<String>,
+}
+
+impl RuleOptions {
+    /// Constraints that need a referer never match requests without one;
+    /// exclusions alone do.
+    pub fn applies_to(&self, request: &RequestMetadata) -> bool {
+        if let Some(third_party) = self.third_party {
+            if request.is_third_party() != Some(third_party) {
+                return false;
+            }
+        }
+        let on_any = |domains: &[String]| match request.referer_host() {
+            Some(referer) => domains
+                .iter()
+                .any(|domain| host_matches_suffix(referer, domain)),
+            None => false,
+        };
+        if !self.include_domains.is_empty() && !on_any(&self.include_domains) {
+            return false;
+        }
+        !on_any(&self.exclude_domains)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -82,27 +271,79 @@ pub enum RuleAction {
     Block(ReasonCode),
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default)]
 pub struct RuleSet {
     rules: Vec<Rule>,
+    /// Pre-compiled URL patterns, index-aligned with `rules`.
+    compiled: Vec<Option<CompiledPattern>>,
 }
 
+impl PartialEq for RuleSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+
+impl Eq for RuleSet {}
+
 impl RuleSet {
+    /// Patterns that fail to compile or exceed the complexity bound never match.
     pub fn new(rules: Vec<Rule>) -> Self {
-        Self { rules }
+        let rules: Vec<Rule> = rules.into_iter().map(Rule::canonical).collect();
+        let compiled = rules
+            .iter()
+            .map(|rule| compile_rule(rule).ok().flatten())
+            .collect();
+        Self { rules, compiled }
+    }
+
+    /// Like `new`, but rejects the whole set if any pattern is invalid.
+    pub fn try_new(rules: Vec<Rule>) -> Result<Self, PatternError> {
+        let rules: Vec<Rule> = rules.into_iter().map(Rule::canonical).collect();
+        let compiled = rules
+            .iter()
+            .map(compile_rule)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules, compiled })
     }
 
     pub fn rules(&self) -> &[Rule] {
         &self.rules
     }
 
-    pub fn evaluate(&self, request: &RequestMetadata) -> Option<Decision> {
-        for rule in &self.rules {
-            if rule_matches(rule, request) {
-                return Some(rule_action_to_decision(rule_action(rule)));
-            }
+    /// Linear first-match-wins scan; reference semantics for `RuleIndex`.
+    /// Scheduled rules are checked against `minute_of_day` in local time.
+    pub fn evaluate(&self, request: &RequestMetadata, minute_of_day: u16) -> Option<Decision> {
+        (0..self.rules.len())
+            .find(|&idx| self.matches_at(idx, request, minute_of_day))
+            .map(|idx| self.decision_at(idx))
+    }
+
+    fn matches_at(&self, idx: usize, request: &RequestMetadata, minute_of_day: u16) -> bool {
+        if !rule_applies(&self.rules[idx], request, minute_of_day) {
+            return false;
+        }
+        match &self.compiled[idx] {
+            Some(pattern) => pattern.is_match(&request.full_url),
+            None => rule_matches(&self.rules[idx], request),
         }
-        None
+    }
+
+    fn decision_at(&self, idx: usize) -> Decision {
+        rule_action_to_decision(rule_action(&self.rules[idx]))
+    }
+}
+
+fn compile_rule(rule: &Rule) -> Result<Option<CompiledPattern>, PatternError> {
+    match rule {
+        Rule::UrlWildcard { pattern, .. } => {
+            WildcardPattern::compile(pattern).map(|p| Some(CompiledPattern::Wildcard(p)))
+        }
+        Rule::UrlRegex { pattern, .. } => {
+            compile_regex(pattern).map(|r| Some(CompiledPattern::Regex(r)))
+        }
+        Rule::Scheduled { rule, .. } | Rule::Constrained { rule, .. } => compile_rule(rule),
+        _ => Ok(None),
     }
 }
 
@@ -112,6 +353,9 @@ fn rule_action(rule: &Rule) -> RuleAction {
         Rule::DomainSuffix { action, .. } => *action,
         Rule::UrlPrefix { action, .. } => *action,
         Rule::HeaderEquals { action, .. } => *action,
+        Rule::UrlWildcard { action, .. } => *action,
+        Rule::UrlRegex { action, .. } => *action,
+        Rule::Scheduled { rule, .. } | Rule::Constrained { rule, .. } => rule_action(rule),
     }
 }
 
@@ -133,6 +377,24 @@ fn rule_matches(rule: &Rule, request: &RequestMetadata) -> bool {
                 None => false,
             }
         }
+        // Pattern rules match only through their compiled form.
+        Rule::UrlWildcard { .. } | Rule::UrlRegex { .. } => false,
+        Rule::Scheduled { rule, .. } | Rule::Constrained { rule, .. } => {
+            rule_matches(rule, request)
+        }
+    }
+}
+
+/// Checks the schedule and context wrappers; the base rule is matched separately.
+fn rule_applies(rule: &Rule, request: &RequestMetadata, minute_of_day: u16) -> bool {
+    match rule {
+        Rule::Scheduled { hours, rule } => {
+            hours.contains(minute_of_day) && rule_applies(rule, request, minute_of_day)
+        }
+        Rule::Constrained { options, rule } => {
+            options.applies_to(request) && rule_applies(rule, request, minute_of_day)
+        }
+        _ => true,
     }
 }
 
@@ -152,19 +414,74 @@ fn host_matches_suffix(host: &str, suffix: &str) -> bool {
     host.as_bytes().get(dot_index) == Some(&b'.')
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A decision together with the index of the rule that produced it.
+/// `matched_rule` is `None` when no rule matched and the default applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evaluation {
+    pub decision: Decision,
+    pub matched_rule: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
 pub struct ContentPolicyEngine {
     rules: RuleSet,
+    index: Arc<RuleIndex>,
+    clock: Arc<dyn PolicyClock>,
 }
 
+impl PartialEq for ContentPolicyEngine {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+
+impl Eq for ContentPolicyEngine {}
+
 impl ContentPolicyEngine {
     /// Phase 7.5 FROZEN: proxy-edge only. Do not invoke below the proxy edge.
     pub fn new(rules: RuleSet) -> Self {
-        Self { rules }
+        let index = Arc::new(RuleIndex::build(&rules));
+        Self {
+            rules,
+            index,
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    /// Replaces the UTC wall clock that scheduled rules are checked against.
+    pub fn with_clock(mut self, clock: Arc<dyn PolicyClock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> Arc<dyn PolicyClock> {
+        Arc::clone(&self.clock)
+    }
+
+    pub fn rules(&self) -> &RuleSet {
+        &self.rules
     }
 
     pub fn evaluate(&self, request: &RequestMetadata) -> Decision {
-        self.rules.evaluate(request).unwrap_or(Decision::Allow)
+        self.evaluate_detailed(request).decision
+    }
+
+    pub fn evaluate_detailed(&self, request: &RequestMetadata) -> Evaluation {
+        let minute_of_day = self.clock.minute_of_day();
+        match self.index.first_match(&self.rules, request, minute_of_day) {
+            Some(idx) => Evaluation {
+                decision: self.rules.decision_at(idx),
+                matched_rule: Some(idx),
+            },
+            None => Evaluation {
+                decision: Decision::Allow,
+                matched_rule: None,
+            },
+        }
+    }
+
+    pub fn matched_rule(&self, evaluation: &Evaluation) -> Option<&Rule> {
+        evaluation.matched_rule.and_then(|idx| self.rules.rules.get(idx))
     }
 }
 
@@ -225,6 +542,159 @@ mod tests {
         );
     }
 
+    #[test]
+    fn wildcard_and_regex_rules_keep_first_match_wins() {
+        let rules = RuleSet::new(vec![
+            Rule::UrlWildcard {
+                pattern: "/banner*".to_string(),
+                action: RuleAction::Allow,
+            },
+            Rule::UrlRegex {
+                pattern: r"^https://ads\.[a-z]+\.com/".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            },
+        ]);
+        let engine = ContentPolicyEngine::new(rules);
+
+        assert_eq!(engine.evaluate(&sample_request()), Decision::Allow);
+
+        let mut request = sample_request();
+        request.full_url = "https://ads.example.com/pixel".to_string();
+        assert_eq!(
+            engine.evaluate(&request),
+            Decision::Block {
+                reason: ReasonCode::Ads
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_never_match_and_fail_try_new() {
+        let rules = vec![Rule::UrlRegex {
+            pattern: "(".to_string(),
+            action: RuleAction::Block(ReasonCode::Custom),
+        }];
+        assert_eq!(RuleSet::try_new(rules.clone()), Err(PatternError::Invalid));
+        let engine = ContentPolicyEngine::new(RuleSet::new(rules));
+        assert_eq!(engine.evaluate(&sample_request()), Decision::Allow);
+    }
+
+    #[test]
+    fn detailed_evaluation_reports_matched_rule() {
+        let rules = RuleSet::new(vec![
+            Rule::DomainExact {
+                domain: "cdn.example.com".to_string(),
+                action: RuleAction::Allow,
+            },
+            Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            },
+        ]);
+        let engine = ContentPolicyEngine::new(rules);
+
+        let evaluation = engine.evaluate_detailed(&sample_request());
+        assert_eq!(evaluation.matched_rule, Some(1));
+        assert_eq!(engine.matched_rule(&evaluation).map(Rule::target), Some("example.com"));
+
+        let mut request = sample_request();
+        request.host = "other.net".to_string();
+        let evaluation = engine.evaluate_detailed(&request);
+        assert_eq!(evaluation.decision, Decision::Allow);
+        assert_eq!(engine.matched_rule(&evaluation), None);
+    }
+
+    #[derive(Debug)]
+    struct FixedClock(u16);
+
+    impl PolicyClock for FixedClock {
+        fn minute_of_day(&self) -> u16 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn scheduled_rules_apply_only_within_active_hours() {
+        let rules = RuleSet::new(vec![
+            Rule::Scheduled {
+                hours: ActiveHours::parse("09:00-17:00").unwrap(),
+                rule: Box::new(Rule::DomainSuffix {
+                    suffix: "example.com".to_string(),
+                    action: RuleAction::Block(ReasonCode::Custom),
+                }),
+            },
+            Rule::UrlRegex {
+                pattern: "/banner$".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            },
+        ]);
+        let at = |minute| {
+            ContentPolicyEngine::new(rules.clone()).with_clock(Arc::new(FixedClock(minute)))
+        };
+        let request = sample_request();
+
+        let during = at(9 * 60).evaluate_detailed(&request);
+        assert_eq!(during.decision, Decision::Block { reason: ReasonCode::Custom });
+        assert_eq!(during.matched_rule, Some(0));
+
+        let after = at(17 * 60).evaluate_detailed(&request);
+        assert_eq!(after.decision, Decision::Block { reason: ReasonCode::Ads });
+        assert_eq!(after.matched_rule, Some(1));
+
+        assert_eq!(
+            rules.evaluate(&request, 8 * 60 + 59),
+            Some(Decision::Block {
+                reason: ReasonCode::Ads
+            })
+        );
+    }
+
+    #[test]
+    fn constrained_rules_check_referer_context() {
+        let third_party_only = Rule::Constrained {
+            options: RuleOptions {
+                third_party: Some(true),
+                ..RuleOptions::default()
+            },
+            rule: Box::new(Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }),
+        };
+        let engine = ContentPolicyEngine::new(RuleSet::new(vec![third_party_only]));
+        let with_referer = |referer: &str| {
+            let mut headers = BTreeMap::new();
+            headers.insert("referer".to_string(), referer.to_string());
+            RequestMetadata::new(
+                "GET".to_string(),
+                "https://ads.example.com/banner".to_string(),
+                "ads.example.com".to_string(),
+                443,
+                headers,
+            )
+        };
+
+        let cross_site = with_referer("https://news.site.org:8443/article?id=1");
+        assert_eq!(cross_site.referer_host(), Some("news.site.org"));
+        assert_eq!(cross_site.is_third_party(), Some(true));
+        assert_eq!(engine.evaluate(&cross_site), Decision::Block { reason: ReasonCode::Ads });
+
+        let same_site = with_referer("https://www.example.com/");
+        assert_eq!(same_site.is_third_party(), Some(false));
+        assert_eq!(engine.evaluate(&same_site), Decision::Allow);
+        assert_eq!(engine.evaluate(&sample_request()), Decision::Allow);
+
+        let options = RuleOptions {
+            include_domains: vec!["site.org".to_string()],
+            exclude_domains: vec!["private.site.org".to_string()],
+            ..RuleOptions::default()
+        };
+        assert!(options.applies_to(&cross_site));
+        assert!(!options.applies_to(&with_referer("https://private.site.org/")));
+        assert!(!options.applies_to(&same_site));
+        assert!(!options.applies_to(&sample_request()));
+    }
+
     #[test]
     fn deterministic_multiple_evaluations_same_result() {
         let rules = RuleSet::new(vec![Rule::UrlPrefix {
@@ -0,0 +1,268 @@
+//! Native rules format: one rule per line, whitespace-separated.
+//!
+//! ```text
+//! # comment
+//! block suffix example.com ads
+//! allow exact foo.com
+//! block prefix https://cdn.example.net/ads/ tracking
+//! block wildcard */pixel*.gif
+//! block regex ^https://[a-z]+\.ads\.example\.org/
+//! block header user-agent BadBot custom
+//! block suffix social.example custom @09:00-17:00
+//! ```
+//!
+//! Block reasons are `ads`, `tracking`, `malware`, `custom` or `unknown`
+//! and default to `custom`. Targets cannot contain whitespace. A trailing
+//! `@HH:MM-HH:MM` limits the rule to those local hours; windows may wrap
+//! past midnight.
+
+use super::{compile_rule, ActiveHours, ReasonCode, Rule, RuleAction, RuleSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeRulesError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for NativeRulesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rules line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for NativeRulesError {}
+
+pub fn parse_native_rules(text: &str) -> Result<RuleSet, NativeRulesError> {
+    let mut rules = Vec::new();
+    for (line_index, raw_line) in text.lines().enumerate() {
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rule = parse_line(line).map_err(|reason| NativeRulesError {
+            line: line_index + 1,
+            reason,
+        })?;
+        if compile_rule(&rule).is_err() {
+            return Err(NativeRulesError {
+                line: line_index + 1,
+                reason: "invalid pattern",
+            });
+        }
+        rules.push(rule);
+    }
+    Ok(RuleSet::new(rules))
+}
+
+fn parse_line(line: &str) -> Result<Rule, &'static str> {
+    let mut tokens: Vec<&str> = line.split_whitespace().collect();
+    let hours = match tokens.last().and_then(|token| token.strip_prefix('@')) {
+        Some(window) => {
+            let hours = ActiveHours::parse(window).ok_or("invalid active hours")?;
+            tokens.pop();
+            Some(hours)
+        }
+        None => None,
+    };
+
+    let (verb, kind) = match tokens.as_slice() {
+        [verb, kind, ..] => (*verb, *kind),
+        _ => return Err("expected `<block|allow> <kind> <target>`"),
+    };
+    let operands = if kind == "header" { 2 } else { 1 };
+    let rest = &tokens[2..];
+    if rest.len() < operands {
+        return Err("missing rule target");
+    }
+    let (targets, trailing) = rest.split_at(operands);
+
+    let action = match (verb, trailing) {
+        ("allow", []) => RuleAction::Allow,
+        ("allow", _) => return Err("allow rules take no reason"),
+        ("block", []) => RuleAction::Block(ReasonCode::Custom),
+        ("block", [reason]) => RuleAction::Block(parse_reason(reason)?),
+        ("block", _) => return Err("unexpected trailing tokens"),
+        _ => return Err("action must be `block` or `allow`"),
+    };
+
+    let target = targets[0].to_string();
+    let rule = match kind {
+        "exact" => Rule::DomainExact {
+            domain: target,
+            action,
+        },
+        "suffix" => Rule::DomainSuffix {
+            suffix: target,
+            action,
+        },
+        "prefix" => Rule::UrlPrefix {
+            prefix: target,
+            action,
+        },
+        "wildcard" => Rule::UrlWildcard {
+            pattern: target,
+            action,
+        },
+        "regex" => Rule::UrlRegex {
+            pattern: target,
+            action,
+        },
+        "header" => Rule::HeaderEquals {
+            name: target,
+            value: targets[1].to_string(),
+            action,
+        },
+        _ => return Err("unknown rule kind"),
+    };
+    Ok(match hours {
+        Some(hours) => Rule::Scheduled {
+            hours,
+            rule: Box::new(rule),
+        },
+        None => rule,
+    })
+}
+
+fn parse_reason(reason: &str) -> Result<ReasonCode, &'static str> {
+    match reason {
+        "ads" => Ok(ReasonCode::Ads),
+        "tracking" => Ok(ReasonCode::Tracking),
+        "malware" => Ok(ReasonCode::Malware),
+        "custom" => Ok(ReasonCode::Custom),
+        "unknown" => Ok(ReasonCode::Unknown),
+        _ => Err("unknown block reason"),
+    }
+}
+
+/// Serializes a ruleset so that `parse_native_rules` yields the same rules.
+/// Rules whose targets contain whitespace cannot be represented and are
+/// emitted as comments.
+pub fn to_native_rules(ruleset: &RuleSet) -> String {
+    let mut out = String::new();
+    for rule in ruleset.rules() {
+        let (hours, rule) = match rule {
+            Rule::Scheduled { hours, rule } => (Some(hours), rule.as_ref()),
+            rule => (None, rule),
+        };
+        let (kind, operands, action) = match rule {
+            Rule::DomainExact { domain, action } => ("exact", vec![domain], action),
+            Rule::DomainSuffix { suffix, action } => ("suffix", vec![suffix], action),
+            Rule::UrlPrefix { prefix, action } => ("prefix", vec![prefix], action),
+            Rule::UrlWildcard { pattern, action } => ("wildcard", vec![pattern], action),
+            Rule::UrlRegex { pattern, action } => ("regex", vec![pattern], action),
+            Rule::HeaderEquals {
+                name,
+                value,
+                action,
+            } => ("header", vec![name, value], action),
+            Rule::Scheduled { .. } | Rule::Constrained { .. } => {
+                out.push_str("# unrepresentable rule skipped\n");
+                continue;
+            }
+        };
+        if operands
+            .iter()
+            .any(|operand| operand.is_empty() || operand.contains(char::is_whitespace))
+        {
+            out.push_str("# unrepresentable rule skipped\n");
+            continue;
+        }
+        let verb = match action {
+            RuleAction::Allow => "allow",
+            RuleAction::Block(_) => "block",
+        };
+        out.push_str(verb);
+        out.push(' ');
+        out.push_str(kind);
+        for operand in operands {
+            out.push(' ');
+            out.push_str(operand);
+        }
+        if let RuleAction::Block(reason) = action {
+            out.push(' ');
+            out.push_str(reason.as_str());
+        }
+        if let Some(hours) = hours {
+            out.push_str(" @");
+            out.push_str(&hours.to_string());
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+# family overrides
+block suffix example.com ads
+allow exact foo.com
+block prefix https://cdn.example.net/ads/ tracking
+block wildcard */pixel*.gif
+block regex ^https://[a-z]+\\.ads\\.example\\.org/ unknown
+block header user-agent BadBot
+allow exact social.example @22:00-06:00
+";
+
+    #[test]
+    fn parses_every_rule_kind() {
+        let ruleset = parse_native_rules(SAMPLE).unwrap();
+        assert_eq!(ruleset.rules().len(), 7);
+        assert_eq!(
+            ruleset.rules()[0],
+            Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }
+        );
+        assert_eq!(
+            ruleset.rules()[1],
+            Rule::DomainExact {
+                domain: "foo.com".to_string(),
+                action: RuleAction::Allow,
+            }
+        );
+        assert_eq!(
+            ruleset.rules()[5],
+            Rule::HeaderEquals {
+                name: "user-agent".to_string(),
+                value: "BadBot".to_string(),
+                action: RuleAction::Block(ReasonCode::Custom),
+            }
+        );
+        assert_eq!(
+            ruleset.rules()[6],
+            Rule::Scheduled {
+                hours: ActiveHours::parse("22:00-06:00").unwrap(),
+                rule: Box::new(Rule::DomainExact {
+                    domain: "social.example".to_string(),
+                    action: RuleAction::Allow,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_through_serialization() {
+        let ruleset = parse_native_rules(SAMPLE).unwrap();
+        let exported = to_native_rules(&ruleset);
+        assert_eq!(parse_native_rules(&exported).unwrap(), ruleset);
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        let err = parse_native_rules("block suffix ok.com\nblock nonsense x.com\n").unwrap_err();
+        assert_eq!(err, NativeRulesError { line: 2, reason: "unknown rule kind" });
+
+        let err = parse_native_rules("allow exact foo.com ads\n").unwrap_err();
+        assert_eq!(err.reason, "allow rules take no reason");
+
+        let err = parse_native_rules("block suffix x.com @9-17\n").unwrap_err();
+        assert_eq!(err.reason, "invalid active hours");
+
+        let err = parse_native_rules("\n\nblock regex (\n").unwrap_err();
+        assert_eq!(err, NativeRulesError { line: 3, reason: "invalid pattern" });
+    }
+}
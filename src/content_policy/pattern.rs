@@ -0,0 +1,213 @@
+use regex::{Regex, RegexBuilder};
+
+const MAX_PATTERN_LEN: usize = 512;
+const MAX_WILDCARDS: usize = 16;
+const MAX_REGEX_LEN: usize = 256;
+const REGEX_SIZE_LIMIT: usize = 64 * 1024;
+const REGEX_NEST_LIMIT: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternError {
+    TooLong,
+    TooManyWildcards,
+    TooComplex,
+    Invalid,
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatternError::TooLong => write!(f, "pattern exceeds maximum length"),
+            PatternError::TooManyWildcards => write!(f, "pattern has too many wildcards"),
+            PatternError::TooComplex => write!(f, "pattern exceeds complexity bound"),
+            PatternError::Invalid => write!(f, "pattern is not valid"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+#[derive(Debug, Clone)]
+pub(crate) enum CompiledPattern {
+    Wildcard(WildcardPattern),
+    Regex(Regex),
+}
+
+impl CompiledPattern {
+    pub(crate) fn is_match(&self, url: &str) -> bool {
+        match self {
+            CompiledPattern::Wildcard(pattern) => pattern.is_match(url),
+            CompiledPattern::Regex(regex) => regex.is_match(url),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Literal(u8),
+    /// `^`: a separator character or end of input (EasyList semantics).
+    Separator,
+    /// `*`: any run of characters.
+    Any,
+}
+
+/// Glob-style URL pattern. Unanchored like EasyList filters, so `/ads/*`
+/// matches any URL containing `/ads/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WildcardPattern {
+    tokens: Vec<Token>,
+}
+
+impl WildcardPattern {
+    pub(crate) fn compile(pattern: &str) -> Result<Self, PatternError> {
+        if pattern.is_empty() {
+            return Err(PatternError::Invalid);
+        }
+        if pattern.len() > MAX_PATTERN_LEN {
+            return Err(PatternError::TooLong);
+        }
+
+        let mut tokens = vec![Token::Any];
+        let mut wildcards = 0;
+        for byte in pattern.bytes() {
+            let token = match byte {
+                b'*' => {
+                    wildcards += 1;
+                    Token::Any
+                }
+                b'^' => Token::Separator,
+                other => Token::Literal(other.to_ascii_lowercase()),
+            };
+            // Collapse `**` runs; they add backtracking without changing meaning.
+            if token == Token::Any && tokens.last() == Some(&Token::Any) {
+                continue;
+            }
+            tokens.push(token);
+        }
+        if wildcards > MAX_WILDCARDS {
+            return Err(PatternError::TooManyWildcards);
+        }
+        if tokens.last() != Some(&Token::Any) {
+            tokens.push(Token::Any);
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Iterative glob match with single-star backtracking: O(pattern * input).
+    pub(crate) fn is_match(&self, input: &str) -> bool {
+        let input = input.as_bytes();
+        let tokens = &self.tokens;
+        let (mut t, mut i) = (0usize, 0usize);
+        let mut star: Option<(usize, usize)> = None;
+
+        loop {
+            if t < tokens.len() {
+                match tokens[t] {
+                    Token::Any => {
+                        star = Some((t, i));
+                        t += 1;
+                        continue;
+                    }
+                    Token::Separator if i == input.len() => {
+                        t += 1;
+                        continue;
+                    }
+                    Token::Separator if is_separator(input[i]) => {
+                        t += 1;
+                        i += 1;
+                        continue;
+                    }
+                    Token::Literal(expected) if i < input.len() && input[i].to_ascii_lowercase() == expected => {
+                        t += 1;
+                        i += 1;
+                        continue;
+                    }
+                    _ => {}
+                }
+            } else if i == input.len() {
+                return true;
+            }
+
+            match star {
+                Some((star_t, star_i)) if star_i < input.len() => {
+                    star = Some((star_t, star_i + 1));
+                    t = star_t + 1;
+                    i = star_i + 1;
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+fn is_separator(byte: u8) -> bool {
+    !(byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'.' | b'%'))
+}
+
+pub(crate) fn compile_regex(pattern: &str) -> Result<Regex, PatternError> {
+    if pattern.is_empty() {
+        return Err(PatternError::Invalid);
+    }
+    if pattern.len() > MAX_REGEX_LEN {
+        return Err(PatternError::TooLong);
+    }
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .nest_limit(REGEX_NEST_LIMIT)
+        .build()
+        .map_err(|err| match err {
+            regex::Error::CompiledTooBig(_) => PatternError::TooComplex,
+            _ => PatternError::Invalid,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_path_patterns_match_unanchored() {
+        let ads = WildcardPattern::compile("/ads/*").unwrap();
+        assert!(ads.is_match("https://example.com/ads/banner.png"));
+        assert!(!ads.is_match("https://example.com/uploads/banner.png"));
+
+        let tracker = WildcardPattern::compile("*tracker*.js").unwrap();
+        assert!(tracker.is_match("https://cdn.example.com/lib/tracker-v2.min.js"));
+        assert!(tracker.is_match("https://cdn.example.com/TRACKER.js"));
+        assert!(!tracker.is_match("https://cdn.example.com/tracker.css"));
+    }
+
+    #[test]
+    fn wildcard_separator_matches_boundary_or_end() {
+        let pattern = WildcardPattern::compile("example.com^").unwrap();
+        assert!(pattern.is_match("https://example.com/path"));
+        assert!(pattern.is_match("https://example.com"));
+        assert!(!pattern.is_match("https://example.community/"));
+    }
+
+    #[test]
+    fn wildcard_complexity_is_bounded() {
+        let too_many = "*a".repeat(MAX_WILDCARDS + 1);
+        assert_eq!(
+            WildcardPattern::compile(&too_many),
+            Err(PatternError::TooManyWildcards)
+        );
+        assert_eq!(
+            WildcardPattern::compile(&"a".repeat(MAX_PATTERN_LEN + 1)),
+            Err(PatternError::TooLong)
+        );
+    }
+
+    #[test]
+    fn regex_complexity_is_bounded() {
+        assert!(compile_regex(r"^https://[^/]+/ads/\d+").is_ok());
+        assert_eq!(compile_regex("(").unwrap_err(), PatternError::Invalid);
+        assert_eq!(
+            compile_regex(&"a".repeat(MAX_REGEX_LEN + 1)).unwrap_err(),
+            PatternError::TooLong
+        );
+        assert_eq!(compile_regex(r"\w{1000}{1000}").unwrap_err(), PatternError::TooComplex);
+    }
+}
@@ -0,0 +1,136 @@
+//! Active-hours constraints for rules, evaluated against an injectable clock.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::time_zone::TimeZone;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Daily window in local minutes since midnight, `start` inclusive and `end`
+/// exclusive. A window whose end precedes its start wraps past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveHours {
+    start: u16,
+    end: u16,
+}
+
+impl ActiveHours {
+    /// `end` may be 1440 (24:00); empty windows are rejected.
+    pub fn new(start: u16, end: u16) -> Option<Self> {
+        if start >= MINUTES_PER_DAY || end > MINUTES_PER_DAY || start == end {
+            return None;
+        }
+        Some(Self { start, end })
+    }
+
+    /// Parses `HH:MM-HH:MM`, e.g. `09:00-17:00` or `22:00-06:00`.
+    pub fn parse(window: &str) -> Option<Self> {
+        let (start, end) = window.split_once('-')?;
+        Self::new(parse_clock_time(start)?, parse_clock_time(end)?)
+    }
+
+    pub fn contains(&self, minute_of_day: u16) -> bool {
+        if self.start < self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+fn parse_clock_time(text: &str) -> Option<u16> {
+    let (hours, minutes) = text.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes).filter(|&total| total <= MINUTES_PER_DAY)
+}
+
+/// Source of the current local time of day for scheduled rules.
+pub trait PolicyClock: std::fmt::Debug + Send + Sync {
+    /// Minutes since local midnight, in `0..1440`.
+    fn minute_of_day(&self) -> u16;
+}
+
+/// Wall clock in a time zone, daylight saving time included; UTC by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemClock {
+    zone: TimeZone,
+}
+
+impl SystemClock {
+    pub fn new(zone: TimeZone) -> Self {
+        Self { zone }
+    }
+}
+
+impl PolicyClock for SystemClock {
+    fn minute_of_day(&self) -> u16 {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0);
+        self.zone.minute_of_day_at(secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_windows() {
+        let hours = ActiveHours::parse("09:00-17:30").unwrap();
+        assert_eq!(hours, ActiveHours::new(540, 1050).unwrap());
+        assert_eq!(hours.to_string(), "09:00-17:30");
+        assert_eq!(ActiveHours::parse("18:00-24:00").unwrap().to_string(), "18:00-24:00");
+
+        for invalid in ["9:00-17:00", "09:00", "09:60-10:00", "24:00-01:00", "08:00-08:00"] {
+            assert_eq!(ActiveHours::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn windows_are_half_open_and_wrap_midnight() {
+        let office = ActiveHours::parse("09:00-17:00").unwrap();
+        assert!(!office.contains(539));
+        assert!(office.contains(540));
+        assert!(office.contains(1019));
+        assert!(!office.contains(1020));
+
+        let overnight = ActiveHours::parse("22:00-06:00").unwrap();
+        assert!(overnight.contains(1320));
+        assert!(overnight.contains(0));
+        assert!(overnight.contains(359));
+        assert!(!overnight.contains(360));
+        assert!(!overnight.contains(720));
+    }
+
+    #[test]
+    fn windows_follow_daylight_saving_time() {
+        let berlin = TimeZone::posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let office = ActiveHours::parse("09:00-17:00").unwrap();
+        // 07:30 UTC is 08:30 in Berlin on 2026-03-28, and 09:30 a day later.
+        let before = 1_774_683_000;
+        assert!(!office.contains(berlin.minute_of_day_at(before)));
+        assert!(office.contains(berlin.minute_of_day_at(before + 86_400)));
+    }
+}
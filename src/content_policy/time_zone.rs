@@ -0,0 +1,151 @@
+//! Local time for scheduled rules: an IANA zone from the system's
+//! zoneinfo database or a POSIX `TZ` rule, so active hours follow
+//! daylight saving time instead of a fixed offset. Parsing TZif files and
+//! rules, and finding the offset in effect, is left to `tz-rs`.
+
+use std::path::Path;
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+const SECS_PER_DAY: i64 = 86_400;
+
+/// UTC offsets over time for one zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone(tz::TimeZone);
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl TimeZone {
+    /// Coordinated Universal Time.
+    pub fn utc() -> Self {
+        Self(tz::TimeZone::utc())
+    }
+
+    /// The machine's zone: `TZ` if set, else `/etc/localtime`, else UTC.
+    /// `TZ` is trusted with any form the C library accepts, file paths
+    /// included.
+    pub fn local() -> Self {
+        std::env::var("TZ")
+            .ok()
+            .and_then(|tz| tz::TimeZone::from_posix_tz(&tz).ok())
+            .or_else(|| tz::TimeZone::local().ok())
+            .map_or_else(Self::utc, Self)
+    }
+
+    /// An IANA zone such as `Europe/Berlin`, read from the zoneinfo
+    /// database. Anything but an identifier, such as a path, is `None`.
+    pub fn named(name: &str) -> Option<Self> {
+        let identifier = |part: &str| {
+            part.starts_with(|ch: char| ch.is_ascii_alphanumeric())
+                && part.chars().all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '+'))
+        };
+        if !name.split('/').all(identifier) {
+            return None;
+        }
+        Self::from_tzif(&std::fs::read(Path::new(ZONEINFO_DIR).join(name)).ok()?)
+    }
+
+    /// A POSIX `TZ` rule such as `CET-1CEST,M3.5.0,M10.5.0/3`.
+    pub fn posix(rule: &str) -> Option<Self> {
+        if rule.starts_with([':', '/']) {
+            return None;
+        }
+        // No directories and no files, so a rule is never read as a zone.
+        let rules_only = tz::TimeZoneSettings::new(&[], |_| Err("only rules are parsed".into()));
+        rules_only.parse_posix_tz(rule).ok().map(Self)
+    }
+
+    /// Parses a TZif file (RFC 8536).
+    pub fn from_tzif(data: &[u8]) -> Option<Self> {
+        tz::TimeZone::from_tz_data(data).ok().map(Self)
+    }
+
+    /// Offset from UTC, in seconds, at `unix_secs`.
+    pub fn offset_at(&self, unix_secs: i64) -> i32 {
+        self.0.find_local_time_type(unix_secs).map_or(0, tz::LocalTimeType::ut_offset)
+    }
+
+    /// Minutes since local midnight at `unix_secs`, in `0..1440`.
+    pub fn minute_of_day_at(&self, unix_secs: i64) -> u16 {
+        let local = unix_secs + i64::from(self.offset_at(unix_secs));
+        (local.rem_euclid(SECS_PER_DAY) / 60) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-03-29 01:00 UTC, when central Europe moves to summer time.
+    const BERLIN_SPRING_FORWARD: i64 = 1_774_746_000;
+    /// 2026-10-25 01:00 UTC, when it moves back.
+    const BERLIN_FALL_BACK: i64 = 1_792_890_000;
+    /// 2026-01-15 and 2026-07-15, 00:00 UTC.
+    const MID_JANUARY: i64 = 1_768_435_200;
+    const MID_JULY: i64 = 1_784_073_600;
+
+    #[test]
+    fn posix_rules_follow_daylight_saving_time() {
+        let berlin = TimeZone::posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(berlin.minute_of_day_at(BERLIN_SPRING_FORWARD - 60), 60 + 59);
+        assert_eq!(berlin.minute_of_day_at(BERLIN_SPRING_FORWARD), 3 * 60);
+        assert_eq!(berlin.minute_of_day_at(BERLIN_FALL_BACK - 60), 2 * 60 + 59);
+        assert_eq!(berlin.minute_of_day_at(BERLIN_FALL_BACK), 2 * 60);
+
+        // Southern hemisphere: summer time spans the new year.
+        let sydney = TimeZone::posix("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.offset_at(MID_JANUARY), 11 * 3600);
+        assert_eq!(sydney.offset_at(MID_JULY), 10 * 3600);
+
+        assert_eq!(TimeZone::posix("<+0530>-5:30").unwrap().offset_at(0), 5 * 3600 + 30 * 60);
+        for invalid in ["", "CE", "CET", "CET-1CEST,M13.5.0,M10.5.0", "CET-1CEST,M3.5.0"] {
+            assert_eq!(TimeZone::posix(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn tzif_transitions_then_footer_rule() {
+        // Version 2: an empty 32-bit block, then one transition into CET
+        // in 1980 and a footer for the years after it.
+        let mut tzif = Vec::new();
+        let header = |tzif: &mut Vec<u8>, timecnt: u32, typecnt: u32, charcnt: u32| {
+            tzif.extend_from_slice(b"TZif2");
+            tzif.extend_from_slice(&[0; 15]);
+            for count in [0, 0, 0, timecnt, typecnt, charcnt] {
+                tzif.extend_from_slice(&u32::to_be_bytes(count));
+            }
+        };
+        header(&mut tzif, 0, 1, 4);
+        tzif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        tzif.extend_from_slice(b"UTC\0");
+        header(&mut tzif, 1, 2, 8);
+        tzif.extend_from_slice(&315_532_800i64.to_be_bytes());
+        tzif.push(1);
+        tzif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        tzif.extend_from_slice(&[0, 0, 0x0e, 0x10, 0, 4]);
+        tzif.extend_from_slice(b"UTC\0CET\0");
+        tzif.extend_from_slice(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+
+        let zone = TimeZone::from_tzif(&tzif).unwrap();
+        assert_eq!(zone.offset_at(0), 0);
+        assert_eq!(zone.offset_at(315_532_800), 3600);
+        assert_eq!(zone.offset_at(BERLIN_SPRING_FORWARD), 7200);
+        assert_eq!(TimeZone::from_tzif(&tzif[..60]), None);
+    }
+
+    #[test]
+    fn only_iana_identifiers_name_zones() {
+        for path in ["../etc/passwd", "/etc/localtime", "Europe/../../etc/passwd", "Europe//Berlin", "", ":UTC"] {
+            assert_eq!(TimeZone::named(path), None, "{path}");
+        }
+        assert_eq!(TimeZone::named("Mars/Olympus_Mons"), None);
+        if Path::new(ZONEINFO_DIR).join("Europe/Berlin").exists() {
+            let berlin = TimeZone::named("Europe/Berlin").unwrap();
+            assert_eq!(berlin.minute_of_day_at(BERLIN_SPRING_FORWARD), 3 * 60);
+        }
+        assert_eq!(TimeZone::posix("/etc/localtime"), None);
+    }
+}
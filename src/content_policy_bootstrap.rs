@@ -1,26 +1,564 @@
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::config::ProxyPolicy;
-use crate::content_policy::{ruleset_from_easylist, ContentPolicyEngine, RuleSet};
+use crate::config::{NamedRuleset, ProxyPolicy, RulesetScope};
+use crate::content_policy::{
+    parse_native_rules, try_ruleset_from_easylist_as, ContentPolicyEngine, EasyListError,
+    NativeRulesError, PolicyClock, ReasonCode, RuleSet, SystemClock, TimeZone,
+};
 
-pub fn build_content_policy_engine(policy: &ProxyPolicy) -> (ContentPolicyEngine, bool) {
-    // Phase 7.5 FROZEN: no auto-enablement, no dynamic reloads, no learning/inference.
-    // Policy remains proxy-edge only; this is a one-time startup snapshot.
-    if !policy.content_policy_enabled {
-        return (ContentPolicyEngine::new(RuleSet::default()), false);
+const RULES_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const RULES_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Failure to load the configured content-policy rules at startup.
+#[derive(Debug)]
+pub enum RuleSourceError {
+    Read { path: String, error: std::io::Error },
+    InsecureUrl(String),
+    Fetch { url: String, reason: String },
+    TooLarge(String),
+    Parse { source: String, error: EasyListError },
+    Native { path: String, error: NativeRulesError },
+    Empty(String),
+    DuplicateRuleset(String),
+    UnknownRuleset(String),
+    InvalidSubnet(String),
+    InvalidTimeZone(String),
+}
+
+impl std::fmt::Display for RuleSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleSourceError::Read { path, error } => {
+                write!(f, "cannot read content policy rules from {}: {}", path, error)
+            }
+            RuleSourceError::InsecureUrl(url) => {
+                write!(f, "content policy rules URL must use https: {}", url)
+            }
+            RuleSourceError::Fetch { url, reason } => {
+                write!(f, "cannot fetch content policy rules from {}: {}", url, reason)
+            }
+            RuleSourceError::TooLarge(source) => {
+                write!(f, "content policy rules from {} exceed {} bytes", source, RULES_MAX_BYTES)
+            }
+            RuleSourceError::Parse { source, error } => {
+                write!(f, "invalid content policy rules in {}: {}", source, error)
+            }
+            RuleSourceError::Native { path, error } => {
+                write!(f, "invalid custom rules in {}: {}", path, error)
+            }
+            RuleSourceError::Empty(source) => {
+                write!(f, "content policy rules from {} contain no usable rules", source)
+            }
+            RuleSourceError::DuplicateRuleset(name) => {
+                write!(f, "content policy ruleset {} is defined more than once", name)
+            }
+            RuleSourceError::UnknownRuleset(name) => {
+                write!(f, "content policy ruleset {} is assigned but not defined", name)
+            }
+            RuleSourceError::InvalidSubnet(cidr) => {
+                write!(f, "invalid client subnet {}", cidr)
+            }
+            RuleSourceError::InvalidTimeZone(name) => {
+                write!(f, "content policy time zone {} is not an IANA zone in the zoneinfo database", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuleSourceError {}
+
+/// Where `ProxyPolicy::content_policy_rules` points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleSource {
+    File(String),
+    Url(String),
+}
+
+impl RuleSource {
+    pub fn parse(location: &str) -> Result<Self, RuleSourceError> {
+        let lower = location.to_ascii_lowercase();
+        if lower.starts_with("https://") {
+            Ok(RuleSource::Url(location.to_string()))
+        } else if lower.starts_with("http://") {
+            Err(RuleSourceError::InsecureUrl(location.to_string()))
+        } else {
+            Ok(RuleSource::File(location.to_string()))
+        }
+    }
+
+    fn describe(&self) -> &str {
+        match self {
+            RuleSource::File(path) => path,
+            RuleSource::Url(url) => url,
+        }
     }
 
-    let Some(path) = policy.content_policy_rules.as_ref() else {
-        return (ContentPolicyEngine::new(RuleSet::default()), true);
+    async fn fetch_text(&self) -> Result<String, RuleSourceError> {
+        match self {
+            RuleSource::File(_) => self.read_file(),
+            RuleSource::Url(url) => fetch_url(url).await,
+        }
+    }
+
+    fn read_file(&self) -> Result<String, RuleSourceError> {
+        let path = self.describe();
+        let read_error = |error| RuleSourceError::Read {
+            path: path.to_string(),
+            error,
+        };
+        let metadata = fs::metadata(path).map_err(read_error)?;
+        if metadata.len() > RULES_MAX_BYTES as u64 {
+            return Err(RuleSourceError::TooLarge(path.to_string()));
+        }
+        fs::read_to_string(path).map_err(read_error)
+    }
+}
+
+async fn fetch_url(url: &str) -> Result<String, RuleSourceError> {
+    let fetch_error = |reason: String| RuleSourceError::Fetch {
+        url: url.to_string(),
+        reason,
     };
+    let client = reqwest::Client::builder()
+        .https_only(true)
+        .timeout(RULES_FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| fetch_error(e.to_string()))?;
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| fetch_error(e.to_string()))?;
+    if response.content_length().unwrap_or(0) > RULES_MAX_BYTES as u64 {
+        return Err(RuleSourceError::TooLarge(url.to_string()));
+    }
 
-    let rules_text = match fs::read_to_string(path) {
-        Ok(text) => text,
-        Err(_) => {
-            return (ContentPolicyEngine::new(RuleSet::default()), true);
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| fetch_error(e.to_string()))? {
+        if body.len() + chunk.len() > RULES_MAX_BYTES {
+            return Err(RuleSourceError::TooLarge(url.to_string()));
         }
+        body.extend_from_slice(&chunk);
+    }
+    String::from_utf8(body).map_err(|_| fetch_error("response is not UTF-8".to_string()))
+}
+
+/// Loads and strictly parses a rules list from a local path or https URL.
+pub async fn load_ruleset(location: &str, category: ReasonCode) -> Result<RuleSet, RuleSourceError> {
+    let source = RuleSource::parse(location)?;
+    let text = source.fetch_text().await?;
+    try_ruleset_from_easylist_as(&text, category).map_err(|error| RuleSourceError::Parse {
+        source: source.describe().to_string(),
+        error,
+    })
+}
+
+/// Loads a local rules file in the native format.
+pub fn load_custom_rules(path: &str) -> Result<RuleSet, RuleSourceError> {
+    let text = RuleSource::File(path.to_string()).read_file()?;
+    parse_native_rules(&text).map_err(|error| RuleSourceError::Native {
+        path: path.to_string(),
+        error,
+    })
+}
+
+/// Refresh target for `policy`: only an https `content_policy_rules` source is
+/// re-downloaded, and only while no listener-wide assignment overrides it.
+pub fn refresh_schedule(policy: &ProxyPolicy) -> Option<(String, Duration)> {
+    if !policy.content_policy_enabled || listener_assignment(policy).is_some() {
+        return None;
+    }
+    let interval = policy.content_policy_refresh_interval?;
+    let location = policy.content_policy_rules.as_ref()?;
+    match RuleSource::parse(location) {
+        Ok(RuleSource::Url(url)) => Some((url, interval)),
+        _ => None,
+    }
+}
+
+/// Re-downloads a rules list for an atomic swap. An empty result is rejected
+/// so a truncated or emptied upstream list cannot silently disable blocking.
+pub async fn refresh_ruleset(location: &str) -> Result<RuleSet, RuleSourceError> {
+    let ruleset = load_ruleset(location, ReasonCode::Ads).await?;
+    if ruleset.rules().is_empty() {
+        return Err(RuleSourceError::Empty(location.to_string()));
+    }
+    Ok(ruleset)
+}
+
+/// Address block parsed from `RulesetScope::ClientSubnet`; a bare address is
+/// a single-host block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl ClientSubnet {
+    pub fn parse(cidr: &str) -> Result<Self, RuleSourceError> {
+        let invalid = || RuleSourceError::InvalidSubnet(cidr.to_string());
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len: u8 = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Named rulesets, loaded once and shared by every listener that references them.
+#[derive(Debug, Clone, Default)]
+pub struct RulesetLibrary {
+    rulesets: HashMap<String, RuleSet>,
+}
+
+impl RulesetLibrary {
+    pub async fn load(named: &[NamedRuleset]) -> Result<Self, RuleSourceError> {
+        let mut rulesets = HashMap::new();
+        for entry in named {
+            if rulesets.contains_key(&entry.name) {
+                return Err(RuleSourceError::DuplicateRuleset(entry.name.clone()));
+            }
+            let ruleset = load_ruleset(&entry.location, entry.category).await?;
+            rulesets.insert(entry.name.clone(), ruleset);
+        }
+        Ok(Self { rulesets })
+    }
+
+    /// Concatenates the named rulesets in order; first match wins across them.
+    fn engine_for(&self, names: &[String]) -> Result<ContentPolicyEngine, RuleSourceError> {
+        let mut rules = Vec::new();
+        for name in names {
+            let ruleset = self
+                .rulesets
+                .get(name)
+                .ok_or_else(|| RuleSourceError::UnknownRuleset(name.clone()))?;
+            rules.extend_from_slice(ruleset.rules());
+        }
+        Ok(ContentPolicyEngine::new(RuleSet::new(rules)))
+    }
+}
+
+/// Content-policy engines for one listener: a listener-wide default plus
+/// per-client profiles keyed by address or subnet. `custom`
+/// rules are evaluated ahead of `default` on the listener-wide path.
+#[derive(Debug, Clone)]
+pub struct ListenerPolicies {
+    pub enabled: bool,
+    pub custom: RuleSet,
+    pub default: ContentPolicyEngine,
+    pub by_subnet: Vec<(ClientSubnet, ContentPolicyEngine)>,
+}
+
+impl ListenerPolicies {
+    pub fn single(engine: ContentPolicyEngine, enabled: bool) -> Self {
+        Self {
+            enabled,
+            custom: RuleSet::default(),
+            default: engine,
+            by_subnet: Vec::new(),
+        }
+    }
+}
+
+fn listener_assignment(policy: &ProxyPolicy) -> Option<&[String]> {
+    policy
+        .content_policy_assignments
+        .iter()
+        .find(|assignment| assignment.scope == RulesetScope::Listener)
+        .map(|assignment| assignment.rulesets.as_slice())
+}
+
+pub async fn build_listener_policies(
+    policy: &ProxyPolicy,
+    library: &RulesetLibrary,
+) -> Result<ListenerPolicies, RuleSourceError> {
+    // Phase 7.5 FROZEN: no auto-enablement, no learning/inference.
+    // Policy remains proxy-edge only; later refreshes replace this snapshot whole.
+    if !policy.content_policy_enabled {
+        return Ok(ListenerPolicies::single(ContentPolicyEngine::new(RuleSet::default()), false));
+    }
+
+    let zone = match policy.content_policy_time_zone.as_deref() {
+        Some(name) => TimeZone::named(name).ok_or_else(|| RuleSourceError::InvalidTimeZone(name.to_string()))?,
+        None => TimeZone::local(),
+    };
+    let clock: Arc<dyn PolicyClock> = Arc::new(SystemClock::new(zone));
+    let default = match (listener_assignment(policy), policy.content_policy_rules.as_ref()) {
+        (Some(names), _) => library.engine_for(names)?,
+        (None, Some(location)) => ContentPolicyEngine::new(load_ruleset(location, ReasonCode::Ads).await?),
+        (None, None) => ContentPolicyEngine::new(RuleSet::default()),
+    }
+    .with_clock(Arc::clone(&clock));
+
+    let custom = match policy.content_policy_custom_rules.as_ref() {
+        Some(path) => load_custom_rules(path)?,
+        None => RuleSet::default(),
     };
 
-    let ruleset = ruleset_from_easylist(&rules_text);
-    (ContentPolicyEngine::new(ruleset), true)
+    let mut by_subnet = Vec::new();
+    for assignment in &policy.content_policy_assignments {
+        if let RulesetScope::ClientSubnet(cidr) = &assignment.scope {
+            let engine = library
+                .engine_for(&assignment.rulesets)?
+                .with_clock(Arc::clone(&clock));
+            by_subnet.push((ClientSubnet::parse(cidr)?, engine));
+        }
+    }
+
+    Ok(ListenerPolicies {
+        enabled: true,
+        custom,
+        default,
+        by_subnet,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_policy::{Rule, RuleAction};
+
+    fn write_rules(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ebt-rules-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn enabled_policy(location: &str) -> ProxyPolicy {
+        ProxyPolicy {
+            content_policy_enabled: true,
+            content_policy_rules: Some(location.to_string()),
+            ..ProxyPolicy::default()
+        }
+    }
+
+    #[test]
+    fn rule_source_requires_https_for_urls() {
+        assert_eq!(
+            RuleSource::parse("https://lists.example.com/easylist.txt").unwrap(),
+            RuleSource::Url("https://lists.example.com/easylist.txt".to_string())
+        );
+        assert!(matches!(
+            RuleSource::parse("http://lists.example.com/easylist.txt"),
+            Err(RuleSourceError::InsecureUrl(_))
+        ));
+        assert_eq!(
+            RuleSource::parse("/etc/ebt/rules.txt").unwrap(),
+            RuleSource::File("/etc/ebt/rules.txt".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_rules_from_file() {
+        let path = write_rules("ok", "! comment\n||ads.example.com^\n@@||good.example.com^\n");
+        let policies = build_listener_policies(&enabled_policy(&path), &RulesetLibrary::default())
+            .await
+            .unwrap();
+        fs::remove_file(&path).ok();
+
+        assert!(policies.enabled);
+        assert_ne!(policies.default, ContentPolicyEngine::new(RuleSet::default()));
+    }
+
+    #[tokio::test]
+    async fn parse_errors_name_the_source_and_line() {
+        let path = write_rules("bad", "||ads.example.com^\n||..broken^\n");
+        let err = build_listener_policies(&enabled_policy(&path), &RulesetLibrary::default())
+            .await
+            .unwrap_err();
+        fs::remove_file(&path).ok();
+
+        match &err {
+            RuleSourceError::Parse { error, .. } => assert_eq!(error.line, 2),
+            other => panic!("unexpected error: {other}"),
+        }
+        assert!(err.to_string().contains(&path));
+    }
+
+    #[test]
+    fn refresh_only_scheduled_for_https_sources() {
+        let mut policy = enabled_policy("https://lists.example.com/easylist.txt");
+        assert_eq!(refresh_schedule(&policy), None);
+
+        policy.content_policy_refresh_interval = Some(Duration::from_secs(3600));
+        assert_eq!(
+            refresh_schedule(&policy),
+            Some((
+                "https://lists.example.com/easylist.txt".to_string(),
+                Duration::from_secs(3600)
+            ))
+        );
+
+        policy.content_policy_rules = Some("/etc/ebt/rules.txt".to_string());
+        assert_eq!(refresh_schedule(&policy), None);
+    }
+
+    #[tokio::test]
+    async fn refresh_rejects_empty_lists() {
+        let path = write_rules("empty", "! only comments\n");
+        let err = refresh_ruleset(&path).await.unwrap_err();
+        fs::remove_file(&path).ok();
+        assert!(matches!(err, RuleSourceError::Empty(_)));
+    }
+
+    #[test]
+    fn client_subnet_matches_prefix() {
+        let subnet = ClientSubnet::parse("192.168.1.0/24").unwrap();
+        assert!(subnet.contains("192.168.1.77".parse().unwrap()));
+        assert!(subnet.contains("::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!subnet.contains("192.168.2.1".parse().unwrap()));
+
+        let everything = ClientSubnet::parse("::/0").unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+
+        let host = ClientSubnet::parse("192.168.1.20").unwrap();
+        assert_eq!(host.prefix_len(), 32);
+        assert!(host.contains("192.168.1.20".parse().unwrap()));
+        assert!(!host.contains("192.168.1.21".parse().unwrap()));
+
+        assert!(ClientSubnet::parse("10.0.0.0/33").is_err());
+        assert!(ClientSubnet::parse("10.0.0/8").is_err());
+    }
+
+    #[tokio::test]
+    async fn named_rulesets_assigned_per_listener_and_subnet() {
+        use crate::config::RulesetAssignment;
+
+        let ads = write_rules("named-ads", "||ads.example.com^\n");
+        let tracking = write_rules("named-tracking", "||tracker.example.net^\n");
+        let named = vec![
+            NamedRuleset { name: "ads".to_string(), location: ads.clone(), category: ReasonCode::Ads },
+            NamedRuleset {
+                name: "tracking".to_string(),
+                location: tracking.clone(),
+                category: ReasonCode::Tracking,
+            },
+        ];
+        let library = RulesetLibrary::load(&named).await.unwrap();
+        fs::remove_file(&ads).ok();
+        fs::remove_file(&tracking).ok();
+
+        let strict = ProxyPolicy {
+            content_policy_enabled: true,
+            content_policy_assignments: vec![
+                RulesetAssignment {
+                    scope: RulesetScope::Listener,
+                    rulesets: vec!["ads".to_string(), "tracking".to_string()],
+                },
+                RulesetAssignment {
+                    scope: RulesetScope::ClientSubnet("10.0.0.0/8".to_string()),
+                    rulesets: Vec::new(),
+                },
+            ],
+            ..ProxyPolicy::default()
+        };
+        let open = ProxyPolicy {
+            bind_port: 8081,
+            content_policy_enabled: true,
+            content_policy_assignments: vec![RulesetAssignment {
+                scope: RulesetScope::Listener,
+                rulesets: Vec::new(),
+            }],
+            ..ProxyPolicy::default()
+        };
+
+        let strict = build_listener_policies(&strict, &library).await.unwrap();
+        let open = build_listener_policies(&open, &library).await.unwrap();
+
+        assert_eq!(strict.default.rules().rules().len(), 2);
+        assert!(matches!(
+            strict.default.rules().rules()[1],
+            Rule::DomainSuffix { action: RuleAction::Block(ReasonCode::Tracking), .. }
+        ));
+        assert_eq!(strict.by_subnet.len(), 1);
+        assert!(strict.by_subnet[0].1.rules().rules().is_empty());
+        assert!(open.default.rules().rules().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_ruleset_names_are_rejected() {
+        use crate::config::RulesetAssignment;
+
+        let policy = ProxyPolicy {
+            content_policy_enabled: true,
+            content_policy_assignments: vec![RulesetAssignment {
+                scope: RulesetScope::Listener,
+                rulesets: vec!["corporate-custom".to_string()],
+            }],
+            ..ProxyPolicy::default()
+        };
+        let err = build_listener_policies(&policy, &RulesetLibrary::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RuleSourceError::UnknownRuleset(name) if name == "corporate-custom"));
+    }
+
+    #[tokio::test]
+    async fn custom_rules_loaded_in_native_format() {
+        let path = write_rules("custom", "allow exact foo.com\nblock suffix example.com ads\n");
+        let policy = ProxyPolicy {
+            content_policy_enabled: true,
+            content_policy_custom_rules: Some(path.clone()),
+            ..ProxyPolicy::default()
+        };
+        let policies = build_listener_policies(&policy, &RulesetLibrary::default()).await.unwrap();
+        assert_eq!(policies.custom.rules().len(), 2);
+
+        fs::write(&path, "block bogus foo.com\n").unwrap();
+        let err = load_custom_rules(&path).unwrap_err();
+        fs::remove_file(&path).ok();
+        assert!(matches!(err, RuleSourceError::Native { ref error, .. } if error.line == 1));
+    }
+
+    #[tokio::test]
+    async fn unknown_time_zones_are_rejected_not_replaced() {
+        let policy = ProxyPolicy {
+            content_policy_enabled: true,
+            content_policy_time_zone: Some("/etc/localtime".to_string()),
+            ..ProxyPolicy::default()
+        };
+        let err = build_listener_policies(&policy, &RulesetLibrary::default()).await.unwrap_err();
+        assert!(matches!(err, RuleSourceError::InvalidTimeZone(name) if name == "/etc/localtime"));
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_an_error() {
+        let err = load_ruleset("/nonexistent/ebt-rules.txt", ReasonCode::Ads).await.unwrap_err();
+        assert!(matches!(err, RuleSourceError::Read { .. }));
+    }
 }
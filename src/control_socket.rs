@@ -0,0 +1,419 @@
+//! Local control channel for CLIs and GUIs managing a running proxy: a
+//! Unix domain socket, or a named pipe (`\\.\pipe\...`) on Windows, set by
+//! `proxy.control_socket`.
+//!
+//! The protocol is one JSON object per line each way. A request names its
+//! `command`; the answer is `{"ok":true,"result":...}` or
+//! `{"ok":false,"error":"..."}`:
+//!
+//! ```text
+//! {"command":"status"}
+//! {"command":"stats"}
+//! {"command":"reload"}
+//! {"command":"set_log_level","levels":"info,real_proxy=debug"}
+//! {"command":"set_content_policy","enabled":false}
+//! {"command":"list_tunnels"}
+//! {"command":"policy_hits"}
+//! {"command":"dns_cache"}
+//! {"command":"shutdown"}
+//! ```
+//!
+//! Unlike the `/ebt/...` HTTP endpoints there are no credentials: access
+//! is whoever may open the socket. The Unix socket is made owner-only;
+//! the pipe refuses remote clients and keeps Windows' default ACL.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+
+use crate::core::observability;
+use crate::dns_resolver;
+use crate::log;
+use crate::logging::{self, LogLevel};
+use crate::real_proxy::ProxyControl;
+
+/// Longest request line; anything longer ends the connection.
+const MAX_REQUEST_BYTES: u64 = 4096;
+/// Pause after a failed accept, so a full descriptor table is not spun on.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ControlRequest {
+    Status,
+    /// Process-wide usage totals since start.
+    Stats,
+    /// Re-reads the config file, as SIGHUP does.
+    Reload,
+    /// Levels in `EBT_LOG` syntax; a config reload puts the file's back.
+    SetLogLevel {
+        levels: String,
+    },
+    SetContentPolicy {
+        enabled: bool,
+    },
+    ListTunnels,
+    /// Content policy matches per rule since start.
+    PolicyHits,
+    /// Unexpired DoH answers; relay builds resolve at the exit and keep none.
+    /// Only OBS_DEV builds name the hostnames; others report counts and TTLs.
+    DnsCache,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ControlResponse {
+    fn success(result: Value) -> Self {
+        Self {
+            ok: true,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn failure(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            result: None,
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// Sends one request to the proxy serving `path` and waits for its answer.
+pub async fn request(path: &str, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let stream = platform::connect(path).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = serde_json::to_string(request).map_err(io::Error::other)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    let mut answer = String::new();
+    BufReader::new(reader).read_line(&mut answer).await?;
+    serde_json::from_str(&answer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) use platform::ControlListener;
+
+/// Answers clients until the task is aborted; the socket goes with it.
+pub(crate) async fn serve(mut listener: ControlListener, control: Arc<ProxyControl>, shutdown: Arc<Notify>) {
+    let started = Instant::now();
+    loop {
+        match listener.accept().await {
+            Ok(stream) => {
+                tokio::spawn(session(stream, Arc::clone(&control), Arc::clone(&shutdown), started));
+            }
+            Err(e) => {
+                log!(LogLevel::Error, "Control socket accept failed"; safe "error" => e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn session<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    control: Arc<ProxyControl>,
+    shutdown: Arc<Notify>,
+    started: Instant,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = Vec::new();
+        match (&mut reader).take(MAX_REQUEST_BYTES).read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if !line.ends_with(b"\n") => {
+                let _ = write_response(&mut writer, &ControlResponse::failure("request too long")).await;
+                return;
+            }
+            Ok(_) => {}
+        }
+        let parsed = serde_json::from_slice::<ControlRequest>(&line);
+        let stopping = matches!(parsed, Ok(ControlRequest::Shutdown));
+        let response = match parsed {
+            Ok(request) => answer(&control, request, started, &shutdown).await,
+            Err(e) => ControlResponse::failure(format!("bad request: {}", e)),
+        };
+        if write_response(&mut writer, &response).await.is_err() || stopping {
+            return;
+        }
+    }
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &ControlResponse) -> io::Result<()> {
+    let mut line = serde_json::to_vec(response).map_err(io::Error::other)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+async fn answer(
+    control: &ProxyControl,
+    request: ControlRequest,
+    started: Instant,
+    shutdown: &Notify,
+) -> ControlResponse {
+    match request {
+        ControlRequest::Status => {
+            let mut status = control.status();
+            status["uptime_secs"] = started.elapsed().as_secs().into();
+            ControlResponse::success(status)
+        }
+        ControlRequest::Stats => match observability::usage_totals() {
+            Some(totals) => ControlResponse::success(serde_json::json!({
+                "tunnels": totals.tunnels,
+                "policy_allowed": totals.policy_allowed,
+                "policy_blocked": totals.policy_blocked,
+                "policy_blocked_ads": totals.policy_blocked_ads,
+                "policy_blocked_tracking": totals.policy_blocked_tracking,
+                "policy_blocked_malware": totals.policy_blocked_malware,
+                "policy_blocked_custom": totals.policy_blocked_custom,
+                "bytes_client_to_upstream": totals.tunnel_bytes_client_to_upstream,
+                "bytes_upstream_to_client": totals.tunnel_bytes_upstream_to_client,
+            })),
+            None => ControlResponse::failure("this build keeps no statistics"),
+        },
+        ControlRequest::Reload => match control.reload().await {
+            Some(Ok(())) => ControlResponse::success(Value::Null),
+            Some(Err(e)) => ControlResponse::failure(e.to_string()),
+            None => ControlResponse::failure("started without a config source to reload"),
+        },
+        ControlRequest::SetLogLevel { levels } => {
+            if let Some(bad) = levels.split(',').map(str::trim).find(|entry| {
+                let level = entry.rsplit('=').next().unwrap_or(entry);
+                !entry.is_empty() && LogLevel::parse(level).is_none()
+            }) {
+                return ControlResponse::failure(format!("{:?} is not a log level", bad));
+            }
+            logging::set_levels(&levels);
+            ControlResponse::success(Value::Null)
+        }
+        ControlRequest::SetContentPolicy { enabled } => {
+            control.set_content_policy_enabled(enabled);
+            ControlResponse::success(Value::Null)
+        }
+        ControlRequest::ListTunnels => match serde_json::from_str(&control.tunnels_json()) {
+            Ok(tunnels) => ControlResponse::success(tunnels),
+            Err(e) => ControlResponse::failure(e.to_string()),
+        },
+        ControlRequest::PolicyHits if !observability::OBS_DEV => {
+            ControlResponse::failure("this build keeps no rule hits")
+        }
+        ControlRequest::PolicyHits => {
+            let rules: Vec<Value> = observability::policy_rule_hits()
+                .into_iter()
+                .map(|(rule, blocked, hits)| serde_json::json!({"rule": rule, "blocked": blocked, "hits": hits}))
+                .collect();
+            ControlResponse::success(serde_json::json!({ "rules": rules }))
+        }
+        ControlRequest::DnsCache => {
+            let cache = dns_resolver::doh_cache();
+            let ttls = cache.iter().map(|lookup| lookup.expires_in.as_secs());
+            let mut summary = serde_json::json!({
+                "count": cache.len(),
+                "min_expires_in_secs": ttls.clone().min(),
+                "max_expires_in_secs": ttls.max(),
+            });
+            // Cached hostnames are browsing history.
+            if observability::OBS_DEV {
+                let entries: Vec<Value> = cache
+                    .into_iter()
+                    .map(|lookup| {
+                        serde_json::json!({
+                            "hostname": lookup.hostname,
+                            "addrs": lookup.addrs,
+                            "expires_in_secs": lookup.expires_in.as_secs(),
+                        })
+                    })
+                    .collect();
+                summary["entries"] = entries.into();
+            }
+            ControlResponse::success(summary)
+        }
+        ControlRequest::Shutdown => {
+            log!(LogLevel::Info, "Shutdown requested over the control socket");
+            // Kept until `run_until` waits for it.
+            shutdown.notify_one();
+            ControlResponse::success(Value::Null)
+        }
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::path::PathBuf;
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// The socket file is removed when the listener is dropped.
+    pub(crate) struct ControlListener {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl ControlListener {
+        /// Replaces a socket file left by a process that is gone, but not
+        /// one another instance still answers on.
+        pub(crate) fn bind(path: &str) -> io::Result<Self> {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("control socket {} is in use by another instance", path),
+                ));
+            }
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            // Bound inside a directory only this user can enter, then moved
+            // into place, so nobody can connect before the mode is narrowed.
+            let staging = PathBuf::from(format!("{}.{}.d", path, std::process::id()));
+            let _ = std::fs::remove_dir_all(&staging);
+            std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+            let staged = staging.join("s");
+            let bound = UnixListener::bind(&staged).and_then(|listener| {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&staged, path)?;
+                Ok(listener)
+            });
+            let _ = std::fs::remove_dir_all(&staging);
+            let listener = bound?;
+            Ok(Self {
+                listener,
+                path: PathBuf::from(path),
+            })
+        }
+
+        pub(crate) async fn accept(&mut self) -> io::Result<UnixStream> {
+            Ok(self.listener.accept().await?.0)
+        }
+    }
+
+    impl Drop for ControlListener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub(super) async fn connect(path: &str) -> io::Result<UnixStream> {
+        UnixStream::connect(path).await
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::time::Duration;
+
+    use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+
+    /// `ERROR_PIPE_BUSY`: every instance is taken, retry shortly.
+    const PIPE_BUSY: i32 = 231;
+
+    /// Always holds one unconnected pipe instance for the next client.
+    pub(crate) struct ControlListener {
+        path: String,
+        next: NamedPipeServer,
+    }
+
+    impl ControlListener {
+        /// Fails when another instance already serves `path`.
+        pub(crate) fn bind(path: &str) -> io::Result<Self> {
+            let next = ServerOptions::new()
+                .first_pipe_instance(true)
+                .reject_remote_clients(true)
+                .create(path)?;
+            Ok(Self {
+                path: path.to_string(),
+                next,
+            })
+        }
+
+        pub(crate) async fn accept(&mut self) -> io::Result<NamedPipeServer> {
+            self.next.connect().await?;
+            let next = ServerOptions::new().reject_remote_clients(true).create(&self.path)?;
+            Ok(std::mem::replace(&mut self.next, next))
+        }
+    }
+
+    pub(super) async fn connect(path: &str) -> io::Result<NamedPipeClient> {
+        loop {
+            match ClientOptions::new().open(path) {
+                Err(e) if e.raw_os_error() == Some(PIPE_BUSY) => tokio::time::sleep(Duration::from_millis(50)).await,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::real_proxy::RealProxyServer;
+
+    #[tokio::test]
+    async fn answers_requests_and_relays_shutdown() {
+        let path = std::env::temp_dir().join(format!("ebt-control-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let network = crate::config::CapabilityPolicy::real_network().network_token().unwrap();
+        let server = RealProxyServer::builder().bind("127.0.0.1:0").build(network).unwrap();
+        let shutdown = Arc::new(Notify::new());
+        let task = server.spawn_control_socket(path, Arc::clone(&shutdown)).unwrap();
+        assert!(ControlListener::bind(path).is_err(), "a live socket must not be replaced");
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!std::path::Path::new(&format!("{}.{}.d", path, std::process::id())).exists());
+
+        let status = request(path, &ControlRequest::Status).await.unwrap().result.unwrap();
+        assert_eq!(status["content_policy"]["enabled"], false);
+        assert_eq!(status["open_tunnels"], 0);
+        request(path, &ControlRequest::SetContentPolicy { enabled: true }).await.unwrap();
+        let status = request(path, &ControlRequest::Status).await.unwrap().result.unwrap();
+        assert_eq!(status["content_policy"]["enabled"], true);
+
+        let tunnels = request(path, &ControlRequest::ListTunnels).await.unwrap();
+        assert_eq!(tunnels.result.unwrap()["open"], 0);
+        // Rule hits are only kept by OBS_DEV builds.
+        let hits = request(path, &ControlRequest::PolicyHits).await.unwrap();
+        assert_eq!(hits.ok, observability::OBS_DEV);
+        // Hostnames only leave OBS_DEV builds.
+        let cache = request(path, &ControlRequest::DnsCache).await.unwrap().result.unwrap();
+        assert!(cache["count"].is_u64(), "{}", cache);
+        assert_eq!(cache["entries"].is_array(), observability::OBS_DEV, "{}", cache);
+        let bad_level = ControlRequest::SetLogLevel {
+            levels: "info,real_proxy=loud".to_string(),
+        };
+        assert_eq!(
+            request(path, &bad_level).await.unwrap().error.as_deref(),
+            Some("\"real_proxy=loud\" is not a log level")
+        );
+        assert!(!request(path, &ControlRequest::Reload).await.unwrap().ok);
+
+        let mut raw = tokio::net::UnixStream::connect(path).await.unwrap();
+        raw.write_all(b"{\"command\":\"launch\"}\n").await.unwrap();
+        let mut answer = String::new();
+        BufReader::new(&mut raw).read_line(&mut answer).await.unwrap();
+        assert!(answer.starts_with("{\"ok\":false,\"error\":\"bad request: unknown variant `launch`"), "{}", answer);
+
+        assert!(request(path, &ControlRequest::Shutdown).await.unwrap().ok);
+        tokio::time::timeout(Duration::from_secs(1), shutdown.notified()).await.unwrap();
+        task.abort();
+        let _ = task.await;
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
@@ -0,0 +1,118 @@
+//! Derives `HealthState` from counter deltas over a sliding window. The
+//! caller supplies samples on its own schedule; this module never reads clocks.
+
+use std::collections::VecDeque;
+
+use super::HealthState;
+
+/// Cumulative counters the evaluator watches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthCounters {
+    pub errors: u64,
+    pub dns_failovers: u64,
+    pub transport_retries: u64,
+}
+
+impl HealthCounters {
+    fn delta_since(&self, earlier: &HealthCounters) -> HealthCounters {
+        HealthCounters {
+            errors: self.errors.saturating_sub(earlier.errors),
+            dns_failovers: self.dns_failovers.saturating_sub(earlier.dns_failovers),
+            transport_retries: self.transport_retries.saturating_sub(earlier.transport_retries),
+        }
+    }
+}
+
+/// Per-window event counts at which health degrades or faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthThresholds {
+    pub degraded: HealthCounters,
+    pub faulted: HealthCounters,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        Self {
+            degraded: HealthCounters {
+                errors: 10,
+                dns_failovers: 1,
+                transport_retries: 20,
+            },
+            faulted: HealthCounters {
+                errors: 100,
+                dns_failovers: 10,
+                transport_retries: 200,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthEvaluator {
+    window: usize,
+    thresholds: HealthThresholds,
+    samples: VecDeque<HealthCounters>,
+}
+
+impl HealthEvaluator {
+    /// `window` is the number of sample intervals the rates are measured over.
+    pub fn new(window: usize, thresholds: HealthThresholds) -> Self {
+        Self {
+            window: window.max(1),
+            thresholds,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn observe(&mut self, counters: HealthCounters) -> HealthState {
+        self.samples.push_back(counters);
+        while self.samples.len() > self.window + 1 {
+            self.samples.pop_front();
+        }
+        let oldest = self.samples.front().copied().unwrap_or_default();
+        let delta = counters.delta_since(&oldest);
+        if reaches(&delta, &self.thresholds.faulted) {
+            HealthState::FAULTED
+        } else if reaches(&delta, &self.thresholds.degraded) {
+            HealthState::DEGRADED
+        } else {
+            HealthState::OK
+        }
+    }
+}
+
+fn reaches(delta: &HealthCounters, threshold: &HealthCounters) -> bool {
+    delta.errors >= threshold.errors
+        || delta.dns_failovers >= threshold.dns_failovers
+        || delta.transport_retries >= threshold.transport_retries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(errors: u64, dns_failovers: u64, transport_retries: u64) -> HealthCounters {
+        HealthCounters {
+            errors,
+            dns_failovers,
+            transport_retries,
+        }
+    }
+
+    #[test]
+    fn degrades_and_recovers_as_events_leave_the_window() {
+        let mut evaluator = HealthEvaluator::new(2, HealthThresholds::default());
+        assert_eq!(evaluator.observe(counters(5, 0, 0)), HealthState::OK);
+        assert_eq!(evaluator.observe(counters(5, 1, 0)), HealthState::DEGRADED);
+        assert_eq!(evaluator.observe(counters(5, 1, 0)), HealthState::DEGRADED);
+        assert_eq!(evaluator.observe(counters(5, 1, 0)), HealthState::OK);
+    }
+
+    #[test]
+    fn any_signal_past_the_fault_threshold_faults() {
+        let mut evaluator = HealthEvaluator::new(3, HealthThresholds::default());
+        evaluator.observe(counters(0, 0, 0));
+        assert_eq!(evaluator.observe(counters(50, 0, 0)), HealthState::DEGRADED);
+        assert_eq!(evaluator.observe(counters(50, 0, 250)), HealthState::FAULTED);
+    }
+}
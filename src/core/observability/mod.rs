@@ -26,6 +26,16 @@ pub enum HealthState {
     _Private,
 }
 
+impl HealthState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthState::OK => "ok",
+            HealthState::DEGRADED => "degraded",
+            _ => "faulted",
+        }
+    }
+}
+
 #[cfg(feature = "obs_none")]
 pub const OBS_LEVEL: ObservabilityLevel = ObservabilityLevel::OBS_NONE;
 
@@ -35,11 +45,22 @@ pub const OBS_LEVEL: ObservabilityLevel = ObservabilityLevel::OBS_DEV;
 #[cfg(all(not(feature = "obs_none"), not(feature = "obs_dev")))]
 pub const OBS_LEVEL: ObservabilityLevel = ObservabilityLevel::OBS_SAFE;
 
+#[macro_use]
+mod registry;
+pub mod health;
+pub mod report;
+pub mod trace;
+
 pub const OBS_NONE: bool = matches!(OBS_LEVEL, ObservabilityLevel::OBS_NONE);
 pub const OBS_SAFE: bool = matches!(OBS_LEVEL, ObservabilityLevel::OBS_SAFE);
 pub const OBS_DEV: bool = matches!(OBS_LEVEL, ObservabilityLevel::OBS_DEV);
 
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::anonymity::estimator::{self, AnonymityReport};
 
 const ERROR_CLASS_COUNT: usize = 4;
 static ERROR_COUNTS: [AtomicU64; ERROR_CLASS_COUNT] = [const { AtomicU64::new(0) }; ERROR_CLASS_COUNT];
@@ -68,81 +89,332 @@ pub fn get_health() -> HealthState {
     }
 }
 
-static TOTAL_CONNECTIONS_OPENED: AtomicU64 = AtomicU64::new(0);
-static TOTAL_CONNECTIONS_CLOSED: AtomicU64 = AtomicU64::new(0);
-static FRAMES_SENT: AtomicU64 = AtomicU64::new(0);
-static FRAMES_RECEIVED: AtomicU64 = AtomicU64::new(0);
-static HEADER_DISCARD_COUNT: AtomicU64 = AtomicU64::new(0);
-static POLICY_TOTAL_ALLOWED: AtomicU64 = AtomicU64::new(0);
-static POLICY_TOTAL_BLOCKED: AtomicU64 = AtomicU64::new(0);
-static POLICY_BLOCKED_ADS: AtomicU64 = AtomicU64::new(0);
-static POLICY_BLOCKED_TRACKING: AtomicU64 = AtomicU64::new(0);
-static POLICY_BLOCKED_CUSTOM: AtomicU64 = AtomicU64::new(0);
+counters! {
+    TotalConnectionsOpened = "total_connections_opened" => record_connection_opened;
+    TotalConnectionsClosed = "total_connections_closed" => record_connection_closed;
+    FramesSent = "frames_sent" => record_frame_sent;
+    FramesReceived = "frames_received" => record_frame_received;
+    /// Relay frames that failed their CRC32C; see `CAP_CHECKSUM`.
+    FramesCorrupt = "frames_corrupt" => record_frame_corrupt;
+    HeaderDiscards = "header_discards" => record_header_discard;
+    PolicyTotalAllowed = "policy_total_allowed" => record_policy_allowed;
+    PolicyTotalBlocked = "policy_total_blocked" => record_policy_blocked;
+    PolicyBlockedAds = "policy_blocked_ads" => record_policy_blocked_ads;
+    PolicyBlockedTracking = "policy_blocked_tracking" => record_policy_blocked_tracking;
+    PolicyBlockedMalware = "policy_blocked_malware" => record_policy_blocked_malware;
+    PolicyBlockedCustom = "policy_blocked_custom" => record_policy_blocked_custom;
+    PolicyRuleCount = "policy_rule_count";
+    PolicyLastRefreshUnixSecs = "policy_last_refresh_unix_secs";
+    PolicyRefreshFailures = "policy_refresh_failures" => record_policy_refresh_failed;
+    PolicyCacheHits = "policy_cache_hits" => record_policy_cache_hit;
+    PolicyCacheMisses = "policy_cache_misses" => record_policy_cache_miss;
+    PolicyDryRunWouldBlock = "policy_dry_run_would_block" => record_policy_would_block;
+    /// Every DoH attempt for a lookup failed.
+    DnsFailovers = "dns_failovers" => record_dns_failover;
+    TransportRetries = "transport_retries" => record_transport_retry;
+    TunnelsOpened = "tunnels_opened" => record_tunnel_opened;
+    /// Payload bytes forwarded by CONNECT tunnels, summed over all tunnels.
+    TunnelBytesClientToUpstream = "tunnel_bytes_client_to_upstream";
+    TunnelBytesUpstreamToClient = "tunnel_bytes_upstream_to_client";
+    /// Constant-rate shaping: payload carried, and cell bytes on the wire
+    /// including padding and headers.
+    ConstantRatePayloadBytes = "constant_rate_payload_bytes";
+    ConstantRateWireBytes = "constant_rate_wire_bytes";
+    /// Measured goodput overhead, `1 - payload / wire`, in thousandths.
+    ConstantRateOverheadPermille = "constant_rate_overhead_permille";
+    /// Dummy frames injected by adaptive padding.
+    AdaptivePaddingFrames = "adaptive_padding_frames";
+    /// Anonymity frame bytes, headers included, split into data and
+    /// padding frames. Constant-rate cell padding is counted above.
+    DataFrameBytesSent = "data_frame_bytes_sent";
+    CoverFrameBytesSent = "cover_frame_bytes_sent";
+    DataFrameBytesReceived = "data_frame_bytes_received";
+    CoverFrameBytesReceived = "cover_frame_bytes_received";
+    /// Share of sent frame bytes that were cover, in thousandths.
+    CoverOverheadPermille = "cover_overhead_permille";
+    /// Anonymity path switches, and switches put off because the next
+    /// path's transport did not open.
+    PathRotations = "path_rotations" => record_path_rotation;
+    PathRotationFailures = "path_rotation_failures" => record_path_rotation_failure;
+    /// Smoothed tunnel round trip seen by the delay budget, and the scale
+    /// it applies to the mix delay, in thousandths.
+    TunnelRttMicros = "tunnel_rtt_micros";
+    DelayBudgetScalePermille = "delay_budget_scale_permille";
+    /// Client TLS handshakes completed through `TlsWrapper::connect`, those
+    /// that resumed a cached session instead of verifying a certificate,
+    /// and the resumed share in thousandths.
+    TlsHandshakes = "tls_handshakes";
+    TlsResumedHandshakes = "tls_resumed_handshakes";
+    TlsResumptionPermille = "tls_resumption_permille";
+}
+
+/// Per-rule hit counts keyed by (rule target, blocked). Keys come from the
+/// configured rules, never from request destinations. Only populated under
+/// OBS_DEV and never leaves the process.
+static POLICY_RULE_HITS: Mutex<BTreeMap<(String, bool), u64>> = Mutex::new(BTreeMap::new());
+pub const POLICY_TOP_RULES: usize = 10;
+
+/// Most recent (host, reason) pairs a dry run would have blocked. Unlike rule
+/// hits these name destinations, so they are only kept under OBS_DEV and
+/// never leave the process.
+static POLICY_DRY_RUN_SAMPLES: Mutex<VecDeque<(String, &'static str)>> =
+    Mutex::new(VecDeque::new());
+pub const POLICY_DRY_RUN_SAMPLE_LIMIT: usize = 64;
 
 const BYTE_BUCKETS: usize = 21;
 static BYTES_SENT_COARSE: [AtomicU64; BYTE_BUCKETS] = [const { AtomicU64::new(0) }; BYTE_BUCKETS];
 static BYTES_RECEIVED_COARSE: [AtomicU64; BYTE_BUCKETS] = [const { AtomicU64::new(0) }; BYTE_BUCKETS];
 
+/// Millisecond latencies in power-of-two buckets, like the byte counters.
+const LATENCY_BUCKETS: usize = 17;
+static CONNECT_LATENCY_MS: [AtomicU64; LATENCY_BUCKETS] = [const { AtomicU64::new(0) }; LATENCY_BUCKETS];
+static DNS_LATENCY_MS: [AtomicU64; LATENCY_BUCKETS] = [const { AtomicU64::new(0) }; LATENCY_BUCKETS];
+static FIRST_BYTE_LATENCY_MS: [AtomicU64; LATENCY_BUCKETS] = [const { AtomicU64::new(0) }; LATENCY_BUCKETS];
+
 #[inline]
-pub fn record_connection_opened() {
-    TOTAL_CONNECTIONS_OPENED.fetch_add(1, Ordering::Relaxed);
+pub fn record_bytes_sent_coarse(byte_len: usize) {
+    let idx = coarse_bucket_index(byte_len);
+    BYTES_SENT_COARSE[idx].fetch_add(1, Ordering::Relaxed);
 }
 
 #[inline]
-pub fn record_connection_closed() {
-    TOTAL_CONNECTIONS_CLOSED.fetch_add(1, Ordering::Relaxed);
+pub fn record_bytes_received_coarse(byte_len: usize) {
+    let idx = coarse_bucket_index(byte_len);
+    BYTES_RECEIVED_COARSE[idx].fetch_add(1, Ordering::Relaxed);
 }
 
+/// Bytes written by a tunnel forwarding loop in one direction.
 #[inline]
-pub fn record_frame_sent() {
-    FRAMES_SENT.fetch_add(1, Ordering::Relaxed);
+pub fn record_tunnel_bytes(client_to_upstream: bool, byte_len: u64) {
+    let counter = if client_to_upstream {
+        Counter::TunnelBytesClientToUpstream
+    } else {
+        Counter::TunnelBytesUpstreamToClient
+    };
+    add(counter, byte_len);
 }
 
+/// One constant-rate cell of `cell_len` bytes carrying `payload_len` bytes.
 #[inline]
-pub fn record_frame_received() {
-    FRAMES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+pub fn record_constant_rate_cell(payload_len: usize, cell_len: usize) {
+    add(Counter::ConstantRatePayloadBytes, payload_len as u64);
+    add(Counter::ConstantRateWireBytes, cell_len as u64);
+    let payload = load(Counter::ConstantRatePayloadBytes);
+    let wire = load(Counter::ConstantRateWireBytes).max(1);
+    store(Counter::ConstantRateOverheadPermille, 1_000 - payload.min(wire) * 1_000 / wire);
 }
 
+/// A client TLS handshake completed; `resumed` if it skipped the certificate.
 #[inline]
-pub fn record_bytes_sent_coarse(byte_len: usize) {
-    let idx = coarse_bucket_index(byte_len);
-    BYTES_SENT_COARSE[idx].fetch_add(1, Ordering::Relaxed);
+pub fn record_tls_handshake(resumed: bool) {
+    increment(Counter::TlsHandshakes);
+    if resumed {
+        increment(Counter::TlsResumedHandshakes);
+    }
+    let handshakes = load(Counter::TlsHandshakes).max(1);
+    let resumed = load(Counter::TlsResumedHandshakes);
+    store(Counter::TlsResumptionPermille, resumed.min(handshakes) * 1_000 / handshakes);
 }
 
+/// An anonymity frame of `frame_len` bytes went out; `cover` for padding.
 #[inline]
-pub fn record_bytes_received_coarse(byte_len: usize) {
-    let idx = coarse_bucket_index(byte_len);
-    BYTES_RECEIVED_COARSE[idx].fetch_add(1, Ordering::Relaxed);
+pub fn record_frame_bytes_sent(cover: bool, frame_len: usize) {
+    let counter = if cover {
+        Counter::CoverFrameBytesSent
+    } else {
+        Counter::DataFrameBytesSent
+    };
+    add(counter, frame_len as u64);
+    let cover = load(Counter::CoverFrameBytesSent);
+    let total = (cover + load(Counter::DataFrameBytesSent)).max(1);
+    store(Counter::CoverOverheadPermille, cover.min(total) * 1_000 / total);
 }
 
+/// An anonymity frame of `frame_len` bytes arrived; padding is dropped
+/// after being counted.
 #[inline]
-pub fn record_header_discard() {
-    HEADER_DISCARD_COUNT.fetch_add(1, Ordering::Relaxed);
+pub fn record_frame_bytes_received(cover: bool, frame_len: usize) {
+    let counter = if cover {
+        Counter::CoverFrameBytesReceived
+    } else {
+        Counter::DataFrameBytesReceived
+    };
+    add(counter, frame_len as u64);
 }
 
+/// The delay budget measured `srtt` and set the mix delay scale.
 #[inline]
-pub fn record_policy_allowed() {
-    POLICY_TOTAL_ALLOWED.fetch_add(1, Ordering::Relaxed);
+pub fn record_delay_budget(srtt: Duration, scale_permille: u32) {
+    store(Counter::TunnelRttMicros, u64::try_from(srtt.as_micros()).unwrap_or(u64::MAX));
+    store(Counter::DelayBudgetScalePermille, u64::from(scale_permille));
 }
 
+/// `count` adaptive-padding dummies were queued.
 #[inline]
-pub fn record_policy_blocked() {
-    POLICY_TOTAL_BLOCKED.fetch_add(1, Ordering::Relaxed);
+pub fn record_adaptive_padding(count: usize) {
+    add(Counter::AdaptivePaddingFrames, count as u64);
 }
 
+/// Time to open the upstream TCP connection, excluding DNS.
 #[inline]
-pub fn record_policy_blocked_ads() {
-    POLICY_BLOCKED_ADS.fetch_add(1, Ordering::Relaxed);
+pub fn record_connect_latency(elapsed: Duration) {
+    CONNECT_LATENCY_MS[latency_bucket_index(elapsed)].fetch_add(1, Ordering::Relaxed);
 }
 
+/// Time for a DoH lookup that went to the network; cache hits are not recorded.
 #[inline]
-pub fn record_policy_blocked_tracking() {
-    POLICY_BLOCKED_TRACKING.fetch_add(1, Ordering::Relaxed);
+pub fn record_dns_latency(elapsed: Duration) {
+    DNS_LATENCY_MS[latency_bucket_index(elapsed)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Time from tunnel start until the first upstream byte reaches the client.
+#[inline]
+pub fn record_first_byte_latency(elapsed: Duration) {
+    FIRST_BYTE_LATENCY_MS[latency_bucket_index(elapsed)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Health inputs are read at every level; they are counts only.
+pub fn health_counters() -> health::HealthCounters {
+    health::HealthCounters {
+        errors: ERROR_COUNTS.iter().map(|count| count.load(Ordering::Relaxed)).sum(),
+        dns_failovers: load(Counter::DnsFailovers),
+        transport_retries: load(Counter::TransportRetries),
+    }
+}
+
+/// Cumulative aggregate counts for the usage summary and traffic accounting.
+/// Read at OBS_SAFE and above; nothing here identifies a destination or client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    pub tunnels: u64,
+    pub policy_allowed: u64,
+    pub policy_blocked: u64,
+    pub policy_blocked_ads: u64,
+    pub policy_blocked_tracking: u64,
+    pub policy_blocked_malware: u64,
+    pub policy_blocked_custom: u64,
+    pub bytes_sent_coarse: [u64; BYTE_BUCKETS],
+    pub bytes_received_coarse: [u64; BYTE_BUCKETS],
+    pub tunnel_bytes_client_to_upstream: u64,
+    pub tunnel_bytes_upstream_to_client: u64,
 }
 
+impl UsageTotals {
+    pub fn delta_since(&self, earlier: &UsageTotals) -> UsageTotals {
+        let mut bytes_sent_coarse = [0u64; BYTE_BUCKETS];
+        let mut bytes_received_coarse = [0u64; BYTE_BUCKETS];
+        for i in 0..BYTE_BUCKETS {
+            bytes_sent_coarse[i] = self.bytes_sent_coarse[i].saturating_sub(earlier.bytes_sent_coarse[i]);
+            bytes_received_coarse[i] =
+                self.bytes_received_coarse[i].saturating_sub(earlier.bytes_received_coarse[i]);
+        }
+        UsageTotals {
+            tunnels: self.tunnels.saturating_sub(earlier.tunnels),
+            policy_allowed: self.policy_allowed.saturating_sub(earlier.policy_allowed),
+            policy_blocked: self.policy_blocked.saturating_sub(earlier.policy_blocked),
+            policy_blocked_ads: self.policy_blocked_ads.saturating_sub(earlier.policy_blocked_ads),
+            policy_blocked_tracking: self
+                .policy_blocked_tracking
+                .saturating_sub(earlier.policy_blocked_tracking),
+            policy_blocked_malware: self.policy_blocked_malware.saturating_sub(earlier.policy_blocked_malware),
+            policy_blocked_custom: self.policy_blocked_custom.saturating_sub(earlier.policy_blocked_custom),
+            bytes_sent_coarse,
+            bytes_received_coarse,
+            tunnel_bytes_client_to_upstream: self
+                .tunnel_bytes_client_to_upstream
+                .saturating_sub(earlier.tunnel_bytes_client_to_upstream),
+            tunnel_bytes_upstream_to_client: self
+                .tunnel_bytes_upstream_to_client
+                .saturating_sub(earlier.tunnel_bytes_upstream_to_client),
+        }
+    }
+}
+
+/// `None` under OBS_NONE.
+pub fn usage_totals() -> Option<UsageTotals> {
+    if OBS_NONE {
+        return None;
+    }
+    let mut totals = UsageTotals {
+        tunnels: load(Counter::TunnelsOpened),
+        policy_allowed: load(Counter::PolicyTotalAllowed),
+        policy_blocked: load(Counter::PolicyTotalBlocked),
+        policy_blocked_ads: load(Counter::PolicyBlockedAds),
+        policy_blocked_tracking: load(Counter::PolicyBlockedTracking),
+        policy_blocked_malware: load(Counter::PolicyBlockedMalware),
+        policy_blocked_custom: load(Counter::PolicyBlockedCustom),
+        tunnel_bytes_client_to_upstream: load(Counter::TunnelBytesClientToUpstream),
+        tunnel_bytes_upstream_to_client: load(Counter::TunnelBytesUpstreamToClient),
+        ..UsageTotals::default()
+    };
+    for i in 0..BYTE_BUCKETS {
+        totals.bytes_sent_coarse[i] = BYTES_SENT_COARSE[i].load(Ordering::Relaxed);
+        totals.bytes_received_coarse[i] = BYTES_RECEIVED_COARSE[i].load(Ordering::Relaxed);
+    }
+    Some(totals)
+}
+
+/// Timestamp is supplied by the caller; this module never reads clocks.
 #[inline]
-pub fn record_policy_blocked_custom() {
-    POLICY_BLOCKED_CUSTOM.fetch_add(1, Ordering::Relaxed);
+pub fn record_policy_refresh(rule_count: u64, refreshed_at_unix_secs: u64) {
+    store(Counter::PolicyRuleCount, rule_count);
+    store(Counter::PolicyLastRefreshUnixSecs, refreshed_at_unix_secs);
+}
+
+pub fn record_policy_dry_run_sample(host: &str, reason: &'static str) {
+    if !OBS_DEV {
+        return;
+    }
+    if let Ok(mut samples) = POLICY_DRY_RUN_SAMPLES.lock() {
+        if samples.len() >= POLICY_DRY_RUN_SAMPLE_LIMIT {
+            samples.pop_front();
+        }
+        samples.push_back((host.to_string(), reason));
+    }
+}
+
+/// Dry-run samples, oldest first.
+pub fn policy_dry_run_samples() -> Vec<(String, &'static str)> {
+    POLICY_DRY_RUN_SAMPLES
+        .lock()
+        .map(|samples| samples.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// `rule_target` is the matched rule's configured pattern, not the request.
+pub fn record_policy_rule_hit(rule_target: &str, blocked: bool) {
+    if !OBS_DEV {
+        return;
+    }
+    if let Ok(mut hits) = POLICY_RULE_HITS.lock() {
+        *hits.entry((rule_target.to_string(), blocked)).or_insert(0) += 1;
+    }
+}
+
+/// Most-hit blocking rules, highest first; ties ordered by rule target.
+pub fn top_blocked_rules(limit: usize) -> Vec<(String, u64)> {
+    let Ok(hits) = POLICY_RULE_HITS.lock() else {
+        return Vec::new();
+    };
+    let mut blocked: Vec<(String, u64)> = hits
+        .iter()
+        .filter(|((_, blocked), _)| *blocked)
+        .map(|((target, _), count)| (target.clone(), *count))
+        .collect();
+    blocked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    blocked.truncate(limit);
+    blocked
+}
+
+/// Every rule's hits as (rule target, blocked, count), highest first.
+pub fn policy_rule_hits() -> Vec<(String, bool, u64)> {
+    let Ok(hits) = POLICY_RULE_HITS.lock() else {
+        return Vec::new();
+    };
+    let mut all: Vec<(String, bool, u64)> =
+        hits.iter().map(|((target, blocked), count)| (target.clone(), *blocked, *count)).collect();
+    all.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    all
 }
 
 #[inline]
@@ -159,21 +431,33 @@ const fn coarse_bucket_index(byte_len: usize) -> usize {
     idx
 }
 
+#[inline]
+fn latency_bucket_index(elapsed: Duration) -> usize {
+    let millis = elapsed.as_millis().min(usize::MAX as u128) as usize;
+    coarse_bucket_index(millis).min(LATENCY_BUCKETS - 1)
+}
+
 #[derive(Debug, Clone)]
 pub struct ObservabilitySnapshot {
-    pub total_connections_opened: u64,
-    pub total_connections_closed: u64,
-    pub frames_sent: u64,
-    pub frames_received: u64,
+    /// Indexed by `Counter`; read with `counter`.
+    pub counters: [u64; COUNTER_COUNT],
     pub bytes_sent_coarse: [u64; BYTE_BUCKETS],
     pub bytes_received_coarse: [u64; BYTE_BUCKETS],
-    pub header_discards: u64,
+    pub connect_latency_ms: [u64; LATENCY_BUCKETS],
+    pub dns_latency_ms: [u64; LATENCY_BUCKETS],
+    pub first_byte_latency_ms: [u64; LATENCY_BUCKETS],
     pub error_class_counts: [u64; ERROR_CLASS_COUNT],
-    pub policy_total_allowed: u64,
-    pub policy_total_blocked: u64,
-    pub policy_blocked_ads: u64,
-    pub policy_blocked_tracking: u64,
-    pub policy_blocked_custom: u64,
+    pub policy_top_blocked_rules: Vec<(String, u64)>,
+    /// (host, reason) pairs a dry run would have blocked, oldest first.
+    pub policy_dry_run_samples: Vec<(String, &'static str)>,
+    /// Mix estimates over the last minute, once frames have been mixed.
+    pub anonymity: Option<AnonymityReport>,
+}
+
+impl ObservabilitySnapshot {
+    pub fn counter(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize]
+    }
 }
 
 pub fn snapshot() -> Option<ObservabilitySnapshot> {
@@ -187,24 +471,29 @@ pub fn snapshot() -> Option<ObservabilitySnapshot> {
         bytes_sent_coarse[i] = BYTES_SENT_COARSE[i].load(Ordering::Relaxed);
         bytes_received_coarse[i] = BYTES_RECEIVED_COARSE[i].load(Ordering::Relaxed);
     }
+    let mut connect_latency_ms = [0u64; LATENCY_BUCKETS];
+    let mut dns_latency_ms = [0u64; LATENCY_BUCKETS];
+    let mut first_byte_latency_ms = [0u64; LATENCY_BUCKETS];
+    for i in 0..LATENCY_BUCKETS {
+        connect_latency_ms[i] = CONNECT_LATENCY_MS[i].load(Ordering::Relaxed);
+        dns_latency_ms[i] = DNS_LATENCY_MS[i].load(Ordering::Relaxed);
+        first_byte_latency_ms[i] = FIRST_BYTE_LATENCY_MS[i].load(Ordering::Relaxed);
+    }
     let mut error_class_counts = [0u64; ERROR_CLASS_COUNT];
     for i in 0..ERROR_CLASS_COUNT {
         error_class_counts[i] = ERROR_COUNTS[i].load(Ordering::Relaxed);
     }
 
     Some(ObservabilitySnapshot {
-        total_connections_opened: TOTAL_CONNECTIONS_OPENED.load(Ordering::Relaxed),
-        total_connections_closed: TOTAL_CONNECTIONS_CLOSED.load(Ordering::Relaxed),
-        frames_sent: FRAMES_SENT.load(Ordering::Relaxed),
-        frames_received: FRAMES_RECEIVED.load(Ordering::Relaxed),
+        counters: load_all(),
         bytes_sent_coarse,
         bytes_received_coarse,
-        header_discards: HEADER_DISCARD_COUNT.load(Ordering::Relaxed),
+        connect_latency_ms,
+        dns_latency_ms,
+        first_byte_latency_ms,
         error_class_counts,
-        policy_total_allowed: POLICY_TOTAL_ALLOWED.load(Ordering::Relaxed),
-        policy_total_blocked: POLICY_TOTAL_BLOCKED.load(Ordering::Relaxed),
-        policy_blocked_ads: POLICY_BLOCKED_ADS.load(Ordering::Relaxed),
-        policy_blocked_tracking: POLICY_BLOCKED_TRACKING.load(Ordering::Relaxed),
-        policy_blocked_custom: POLICY_BLOCKED_CUSTOM.load(Ordering::Relaxed),
+        policy_top_blocked_rules: top_blocked_rules(POLICY_TOP_RULES),
+        policy_dry_run_samples: policy_dry_run_samples(),
+        anonymity: estimator::report(),
     })
 }
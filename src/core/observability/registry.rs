@@ -0,0 +1,64 @@
+//! Counter registry. `counters!` declares every scalar counter once: the
+//! `Counter` enum, its exported name, the backing atomics and any
+//! `record_*` shorthand are all generated from that single list, so a
+//! counter cannot be recorded without being snapshotted and reported.
+
+/// `Variant = "exported_name" => record_fn;` declares a counter incremented
+/// by `record_fn()`. Omit `=> record_fn` for gauges written with `store`.
+macro_rules! counters {
+    ($( $(#[$meta:meta])* $variant:ident = $name:literal $(=> $record:ident)? ; )+) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Counter {
+            $( $(#[$meta])* $variant, )+
+        }
+
+        impl Counter {
+            /// Declaration order, which is also the report order.
+            pub const ALL: &'static [Counter] = &[$( Counter::$variant, )+];
+
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $( Counter::$variant => $name, )+
+                }
+            }
+        }
+
+        pub const COUNTER_COUNT: usize = Counter::ALL.len();
+        static COUNTERS: [AtomicU64; COUNTER_COUNT] = [const { AtomicU64::new(0) }; COUNTER_COUNT];
+
+        #[inline]
+        pub fn increment(counter: Counter) {
+            COUNTERS[counter as usize].fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[inline]
+        pub fn add(counter: Counter, value: u64) {
+            COUNTERS[counter as usize].fetch_add(value, Ordering::Relaxed);
+        }
+
+        #[inline]
+        pub fn store(counter: Counter, value: u64) {
+            COUNTERS[counter as usize].store(value, Ordering::Relaxed);
+        }
+
+        #[inline]
+        pub fn load(counter: Counter) -> u64 {
+            COUNTERS[counter as usize].load(Ordering::Relaxed)
+        }
+
+        fn load_all() -> [u64; COUNTER_COUNT] {
+            let mut values = [0u64; COUNTER_COUNT];
+            for (value, counter) in values.iter_mut().zip(&COUNTERS) {
+                *value = counter.load(Ordering::Relaxed);
+            }
+            values
+        }
+
+        $($(
+            #[inline]
+            pub fn $record() {
+                increment(Counter::$variant);
+            }
+        )?)+
+    };
+}
@@ -0,0 +1,245 @@
+//! Line-oriented snapshot encoding served at `/debug/obs`, and the
+//! human-readable rendering used by the `stats` subcommand.
+//!
+//! Each line is `name value...`: counters carry one value, histograms one
+//! value per coarse bucket, `policy_top_blocked_rule` lines carry a
+//! count followed by the rule target, and `policy_dry_run_sample` lines a
+//! reason code followed by the host a dry run let through. `anonymity_*`
+//! lines are present once the mix has seen traffic.
+
+use super::{Counter, ObservabilitySnapshot};
+
+const HISTOGRAMS: [&str; 7] = [
+    "bytes_sent_coarse",
+    "bytes_received_coarse",
+    "connect_latency_ms",
+    "dns_latency_ms",
+    "first_byte_latency_ms",
+    "error_class_counts",
+    "anonymity_batch_sizes",
+];
+const ERROR_CLASS_NAMES: [&str; 4] = [
+    "protocol_violation",
+    "transport_io",
+    "resource_limit",
+    "internal_assert",
+];
+const BAR_WIDTH: u64 = 40;
+
+pub fn encode(snapshot: &ObservabilitySnapshot) -> String {
+    let mut out = String::new();
+    for counter in Counter::ALL {
+        out.push_str(&format!("{} {}\n", counter.name(), snapshot.counter(*counter)));
+    }
+    let histograms: [(&str, &[u64]); 6] = [
+        (HISTOGRAMS[0], &snapshot.bytes_sent_coarse),
+        (HISTOGRAMS[1], &snapshot.bytes_received_coarse),
+        (HISTOGRAMS[2], &snapshot.connect_latency_ms),
+        (HISTOGRAMS[3], &snapshot.dns_latency_ms),
+        (HISTOGRAMS[4], &snapshot.first_byte_latency_ms),
+        (HISTOGRAMS[5], &snapshot.error_class_counts),
+    ];
+    for (name, buckets) in histograms {
+        out.push_str(name);
+        for count in buckets {
+            out.push_str(&format!(" {}", count));
+        }
+        out.push('\n');
+    }
+    for (target, count) in &snapshot.policy_top_blocked_rules {
+        out.push_str(&format!("policy_top_blocked_rule {} {}\n", count, target));
+    }
+    for (host, reason) in &snapshot.policy_dry_run_samples {
+        out.push_str(&format!("policy_dry_run_sample {} {}\n", reason, host));
+    }
+    if let Some(anonymity) = &snapshot.anonymity {
+        out.push_str(&format!("anonymity_entropy_bits_mean {:.2}\n", anonymity.mean_entropy_bits));
+        out.push_str(&format!("anonymity_entropy_bits_min {:.2}\n", anonymity.min_entropy_bits));
+        out.push_str(&format!("anonymity_correlation {:.3}\n", anonymity.correlation));
+        out.push_str(&format!("anonymity_correlation_lag_ms {}\n", anonymity.correlation_lag.as_millis()));
+        out.push_str(HISTOGRAMS[6]);
+        for count in anonymity.batch_sizes {
+            out.push_str(&format!(" {}", count));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Pretty-prints an `encode`d snapshot. Unrecognized lines are listed with
+/// the scalars so an older CLI still displays newer counters.
+pub fn render(encoded: &str) -> String {
+    let mut scalars = Vec::new();
+    let mut histograms = Vec::new();
+    let mut top_rules = Vec::new();
+    let mut dry_run_samples = Vec::new();
+    for line in encoded.lines() {
+        let Some((name, rest)) = line.split_once(' ') else {
+            continue;
+        };
+        if name == "policy_top_blocked_rule" {
+            top_rules.push(rest.split_once(' ').unwrap_or((rest, "")));
+        } else if name == "policy_dry_run_sample" {
+            dry_run_samples.push(rest.split_once(' ').unwrap_or((rest, "")));
+        } else if HISTOGRAMS.contains(&name) {
+            let buckets: Vec<u64> = rest
+                .split_whitespace()
+                .map(|count| count.parse().unwrap_or(0))
+                .collect();
+            histograms.push((name, buckets));
+        } else {
+            scalars.push((name, rest));
+        }
+    }
+
+    let mut out = String::new();
+    let width = scalars.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, value) in &scalars {
+        out.push_str(&format!("{:<width$}  {}\n", name, value, width = width));
+    }
+    for (name, buckets) in &histograms {
+        out.push_str(&format!("\n{}\n", name));
+        let labels: Vec<String> = (0..buckets.len())
+            .map(|idx| bucket_label(name, idx, buckets.len()))
+            .collect();
+        let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+        let max = buckets.iter().copied().max().unwrap_or(0).max(1);
+        for (label, count) in labels.iter().zip(buckets) {
+            if *count == 0 {
+                continue;
+            }
+            let bar = "#".repeat(((count * BAR_WIDTH).div_ceil(max)) as usize);
+            out.push_str(&format!("  {:>label_width$}  {:>8}  {}\n", label, count, bar));
+        }
+    }
+    if !top_rules.is_empty() {
+        out.push_str("\npolicy_top_blocked_rules\n");
+        for (count, target) in top_rules {
+            out.push_str(&format!("  {:>8}  {}\n", count, target));
+        }
+    }
+    if !dry_run_samples.is_empty() {
+        out.push_str("\npolicy_dry_run_samples\n");
+        for (reason, host) in dry_run_samples {
+            out.push_str(&format!("  {:>8}  {}\n", reason, host));
+        }
+    }
+    out
+}
+
+/// Coarse bucket `i` holds values below `2^(i+1)` (bucket 0 also holds zero);
+/// the last bucket is open-ended.
+fn bucket_label(histogram: &str, idx: usize, bucket_count: usize) -> String {
+    if histogram == "error_class_counts" {
+        return ERROR_CLASS_NAMES.get(idx).copied().unwrap_or("other").to_string();
+    }
+    let unit = if histogram.ends_with("_ms") {
+        "ms"
+    } else if histogram == "anonymity_batch_sizes" {
+        "frames"
+    } else {
+        "B"
+    };
+    if idx + 1 == bucket_count {
+        return format!(">= {} {}", 1u64 << idx.min(63), unit);
+    }
+    match 1u64.checked_shl(idx as u32 + 1) {
+        Some(limit) => format!("< {} {}", limit, unit),
+        None => format!("bucket {}", idx),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::anonymity::estimator::{AnonymityReport, BATCH_BUCKETS};
+    use crate::core::observability::COUNTER_COUNT;
+
+    fn sample() -> ObservabilitySnapshot {
+        let mut bytes_sent_coarse = [0; 21];
+        bytes_sent_coarse[0] = 1;
+        bytes_sent_coarse[10] = 4;
+        let mut counters = [0; COUNTER_COUNT];
+        for (counter, value) in [
+            (Counter::TotalConnectionsOpened, 5),
+            (Counter::TotalConnectionsClosed, 4),
+            (Counter::PolicyTotalAllowed, 3),
+            (Counter::PolicyTotalBlocked, 2),
+            (Counter::PolicyBlockedAds, 2),
+            (Counter::PolicyRuleCount, 10),
+            (Counter::PolicyCacheHits, 1),
+            (Counter::PolicyCacheMisses, 4),
+        ] {
+            counters[counter as usize] = value;
+        }
+        ObservabilitySnapshot {
+            counters,
+            bytes_sent_coarse,
+            bytes_received_coarse: [0; 21],
+            connect_latency_ms: [0; 17],
+            dns_latency_ms: [0; 17],
+            first_byte_latency_ms: [0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            error_class_counts: [0, 2, 0, 0],
+            policy_top_blocked_rules: vec![("ads.example.com".to_string(), 2)],
+            policy_dry_run_samples: Vec::new(),
+            anonymity: None,
+        }
+    }
+
+    #[test]
+    fn encodes_scalars_histograms_and_rules() {
+        let encoded = encode(&sample());
+        assert!(encoded.starts_with("total_connections_opened 5\ntotal_connections_closed 4\n"));
+        assert!(encoded.contains("transport_retries 0\n"));
+        assert!(encoded.contains("bytes_sent_coarse 1 0 0 0 0 0 0 0 0 0 4 0"));
+        assert!(encoded.contains("error_class_counts 0 2 0 0\n"));
+        assert!(encoded.ends_with("policy_top_blocked_rule 2 ads.example.com\n"));
+    }
+
+    #[test]
+    fn renders_histogram_bars_for_non_empty_buckets() {
+        let report = render(&encode(&sample()));
+        assert!(report.contains("policy_rule_count"));
+        assert!(report.contains(&format!("< 2048 B         4  {}\n", "#".repeat(40))));
+        assert!(report.contains(&format!("< 2 B         1  {}\n", "#".repeat(10))));
+        assert!(report.contains("< 128 ms"));
+        assert!(report.contains(">= 65536 ms"));
+        assert!(report.contains("transport_io"));
+        assert!(!report.contains("protocol_violation"));
+        assert!(report.contains("       2  ads.example.com\n"));
+    }
+
+    #[test]
+    fn dry_run_samples_follow_the_top_rules() {
+        let mut snapshot = sample();
+        snapshot.policy_dry_run_samples = vec![("tracker.example.net".to_string(), "tracking")];
+        let encoded = encode(&snapshot);
+        assert!(encoded.ends_with(
+            "policy_top_blocked_rule 2 ads.example.com\npolicy_dry_run_sample tracking tracker.example.net\n"
+        ));
+        assert!(render(&encoded).ends_with("\npolicy_dry_run_samples\n  tracking  tracker.example.net\n"));
+    }
+
+    #[test]
+    fn anonymity_estimates_are_reported_when_present() {
+        assert!(!encode(&sample()).contains("anonymity_"));
+
+        let mut snapshot = sample();
+        let mut batch_sizes = [0; BATCH_BUCKETS];
+        batch_sizes[3] = 5;
+        snapshot.anonymity = Some(AnonymityReport {
+            batch_sizes,
+            mean_entropy_bits: 3.25,
+            min_entropy_bits: 3.0,
+            correlation: 0.0421,
+            correlation_lag: Duration::from_millis(300),
+        });
+        let encoded = encode(&snapshot);
+        assert!(encoded.contains("anonymity_entropy_bits_mean 3.25\n"));
+        assert!(encoded.contains("anonymity_correlation 0.042\nanonymity_correlation_lag_ms 300\n"));
+        assert!(encoded.ends_with("anonymity_batch_sizes 0 0 0 5 0 0 0 0 0 0 0 0\n"));
+        assert!(render(&encoded).contains("< 16 frames         5"));
+    }
+}
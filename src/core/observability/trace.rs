@@ -0,0 +1,152 @@
+//! `tracing` spans for per-connection timelines. Spans exist only in
+//! `obs_dev` builds; elsewhere `ObsSpan` is a zero-sized no-op and the
+//! `tracing` crate is not linked.
+//!
+//! Nothing is printed by default. `EBT_TRACE=stderr` writes each span as it
+//! closes, and with the `otlp` feature `EBT_OTLP_ENDPOINT` (e.g.
+//! `http://127.0.0.1:4317`) exports spans to an OTLP collector such as Jaeger.
+//! Spans may name destinations, so like sensitive log fields they never
+//! leave a non-OBS_DEV build.
+
+use std::future::Future;
+
+#[derive(Debug, Clone)]
+pub struct ObsSpan {
+    #[cfg(feature = "obs_dev")]
+    inner: tracing::Span,
+}
+
+/// A disabled span.
+impl Default for ObsSpan {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::Span::none(),
+        }
+    }
+}
+
+/// Keeps its span current until dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct ObsEntered {
+    #[cfg(feature = "obs_dev")]
+    _inner: tracing::span::EnteredSpan,
+}
+
+impl ObsSpan {
+    /// The span the caller is currently inside, if any.
+    pub fn current() -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::Span::current(),
+        }
+    }
+
+    /// One accepted client connection, from header read to close.
+    pub fn connection() -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::info_span!(
+                "connection",
+                destination = tracing::field::Empty,
+                outcome = tracing::field::Empty
+            ),
+        }
+    }
+
+    pub fn dns_resolution(_hostname: &str) -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::info_span!("dns_resolution", hostname = _hostname),
+        }
+    }
+
+    pub fn policy_evaluation() -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::info_span!("policy_evaluation", decision = tracing::field::Empty),
+        }
+    }
+
+    /// `direction` is `"encode"` or `"decode"`.
+    pub fn frame(_direction: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "obs_dev")]
+            inner: tracing::trace_span!("frame", direction = _direction, bytes = tracing::field::Empty),
+        }
+    }
+
+    /// Sets a field declared when the span was created; unknown names are ignored.
+    pub fn record(&self, _field: &'static str, _value: &dyn std::fmt::Display) {
+        #[cfg(feature = "obs_dev")]
+        self.inner.record(_field, tracing::field::display(_value));
+    }
+
+    pub fn entered(self) -> ObsEntered {
+        ObsEntered {
+            #[cfg(feature = "obs_dev")]
+            _inner: self.inner.entered(),
+        }
+    }
+
+    /// Runs `future` inside this span, re-entering it on every poll.
+    pub fn instrument<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "obs_dev")]
+        {
+            tracing::Instrument::instrument(future, self.inner)
+        }
+        #[cfg(not(feature = "obs_dev"))]
+        {
+            future
+        }
+    }
+}
+
+/// Installs the span subscriber. Must run inside the Tokio runtime when the
+/// OTLP exporter is enabled. A no-op outside `obs_dev` builds.
+pub fn init() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "obs_dev")]
+    {
+        use tracing_subscriber::fmt::format::FmtSpan;
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::util::SubscriberInitExt;
+
+        let stderr = (std::env::var("EBT_TRACE").ok().as_deref() == Some("stderr")).then(|| {
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_span_events(FmtSpan::CLOSE)
+        });
+        let registry = tracing_subscriber::registry().with(stderr);
+        #[cfg(feature = "otlp")]
+        let registry = registry.with(otlp_layer()?);
+        registry.try_init()?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>() -> Result<
+    Option<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>,
+    Box<dyn std::error::Error>,
+>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry_otlp::WithExportConfig;
+
+    let Ok(endpoint) = std::env::var("EBT_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
@@ -74,6 +74,12 @@ pub struct EncryptedMessage {
     pub authentication_tag: [u8; 16],
 }
 
+impl EncryptedMessage {
+    pub fn verify_tag(&self, expected: &[u8; 16]) -> bool {
+        crate::crypto_util::constant_time_eq_array(&self.authentication_tag, expected)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum PayloadMessage {
     TunnelData {
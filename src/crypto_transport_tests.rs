@@ -31,7 +31,7 @@ mod crypto_transport_tests {
 
     #[tokio::test]
     async fn test_exit_cannot_access_source_ip() {
-        let exit_interface = ExitZoneInterface::new().unwrap();
+        let exit_interface = ExitZoneInterface::new([0x5a; 32]).unwrap();
         
         assert!(!exit_interface.has_source_ip());
         
@@ -205,4 +205,36 @@ mod crypto_transport_tests {
         assert!(invalid_result.is_err());
         assert!(matches!(invalid_result.unwrap_err(), KeyError::InvalidZone));
     }
+
+    #[tokio::test]
+    async fn test_middle_relay_layer_hides_destination() {
+        use crate::onion::{LayerKey, NextHop};
+
+        let middle_key = LayerKey::new(&[7u8; 32]);
+        let exit_key = LayerKey::new(&[8u8; 32]);
+        let exit = NextHop { host: "exit.relay.example".to_string(), port: 9001 };
+        let next_relay = NextHop { host: "middle-2.relay.example".to_string(), port: 9001 };
+        let destination = b"destination.example";
+
+        let inner = exit_key.seal_forward(0, &exit, destination).unwrap();
+        let cell = middle_key.seal_forward(0, &next_relay, &inner).unwrap();
+
+        // The relay zone learns its next hop and nothing it could correlate.
+        let (next_hop, forwarded) = middle_key.peel_forward(0, &cell).unwrap();
+        assert_eq!(next_hop, next_relay);
+        assert!(!forwarded.windows(destination.len()).any(|window| window == destination));
+        assert!(!forwarded.windows(exit.host.len()).any(|window| window == exit.host.as_bytes()));
+        assert!(middle_key.peel_forward(0, &forwarded).is_err());
+
+        let relay_interface = RelayZoneInterface::new();
+        let context = InvariantContext {
+            component_name: "relay_zone".to_string(),
+            has_source_ip: relay_interface.has_source_ip(),
+            has_destination_hostname: relay_interface.has_destination_hostname(),
+            traffic_encrypted: true,
+            dns_resolution_attempted: false,
+            logging_enabled: false,
+        };
+        assert!(ThreatInvariants::new().check_context(&context).is_empty());
+    }
 }
@@ -0,0 +1,60 @@
+//! Constant-time helpers for comparing authentication material.
+//!
+//! Tags, tickets and proxy credentials must never be compared with `==`:
+//! short-circuiting comparisons leak the length of the matching prefix
+//! through timing. All such comparisons go through this module.
+
+use std::hint::black_box;
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+///
+/// The length check is not constant-time; lengths of tags and credentials
+/// are public by construction.
+#[inline(never)]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff: u8 = 0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= black_box(x ^ y);
+    }
+    black_box(diff) == 0
+}
+
+/// Fixed-size variant for authentication tags and keys.
+#[inline]
+pub fn constant_time_eq_array<const N: usize>(a: &[u8; N], b: &[u8; N]) -> bool {
+    constant_time_eq(a, b)
+}
+
+/// Compares a presented credential string against the expected one.
+#[inline]
+pub fn constant_time_eq_str(presented: &str, expected: &str) -> bool {
+    constant_time_eq(presented.as_bytes(), expected.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_inputs_match() {
+        assert!(constant_time_eq(b"ticket-0001", b"ticket-0001"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq_array(&[7u8; 16], &[7u8; 16]));
+        assert!(constant_time_eq_str("Basic dXNlcjpwYXNz", "Basic dXNlcjpwYXNz"));
+    }
+
+    #[test]
+    fn differing_inputs_do_not_match() {
+        assert!(!constant_time_eq(b"ticket-0001", b"ticket-0002"));
+        assert!(!constant_time_eq(b"ticket-0001", b"ticket-000"));
+        assert!(!constant_time_eq(b"", b"x"));
+
+        let mut tag = [7u8; 16];
+        tag[0] ^= 1;
+        assert!(!constant_time_eq_array(&[7u8; 16], &tag));
+        assert!(!constant_time_eq_str("Basic dXNlcjpwYXNz", "Basic dXNlcjpwYXN6"));
+    }
+}
@@ -0,0 +1,190 @@
+//! `ebt ctl <command>`: sends one request to a running proxy's control
+//! socket and prints the answer as aligned text, or as the raw JSON result
+//! with `--json`. Requests without a result print `ok`.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use crate::control_socket::{self, ControlRequest};
+use crate::traffic_accounting::format_bytes;
+
+pub async fn run(socket: &str, request: &ControlRequest, json: bool) -> Result<(), Box<dyn Error>> {
+    let response = control_socket::request(socket, request).await?;
+    if !response.ok {
+        return Err(response.error.unwrap_or_else(|| "request failed".to_string()).into());
+    }
+    match response.result {
+        None | Some(Value::Null) => println!("ok"),
+        Some(result) if json => println!("{}", serde_json::to_string_pretty(&result)?),
+        Some(result) => print!("{}", render(request, &result)),
+    }
+    Ok(())
+}
+
+/// Text for `result`; fields a newer proxy adds are ignored, and a shape
+/// this client does not know falls back to JSON.
+fn render(request: &ControlRequest, result: &Value) -> String {
+    let text = match request {
+        ControlRequest::Status => render_status(result),
+        ControlRequest::Stats => render_stats(result),
+        ControlRequest::ListTunnels => render_tunnels(result),
+        ControlRequest::PolicyHits => render_policy_hits(result),
+        ControlRequest::DnsCache => render_dns_cache(result),
+        _ => None,
+    };
+    text.unwrap_or_else(|| format!("{}\n", serde_json::to_string_pretty(result).unwrap_or_default()))
+}
+
+fn render_status(status: &Value) -> Option<String> {
+    let policy = &status["content_policy"];
+    let policy = match (policy["enabled"].as_bool()?, policy["dry_run"].as_bool()?) {
+        (false, _) => "disabled",
+        (true, false) => "enabled",
+        (true, true) => "dry run",
+    };
+    let mut out = String::new();
+    let _ = writeln!(out, "listen          {}", status["listen"].as_str().unwrap_or("-"));
+    let _ = writeln!(out, "health          {}", status["health"].as_str()?);
+    let _ = writeln!(out, "relay mode      {}", status["relay_mode"].as_str()?);
+    let _ = writeln!(out, "open tunnels    {}", status["open_tunnels"].as_u64()?);
+    let _ = writeln!(out, "content policy  {}", policy);
+    let _ = writeln!(out, "shaping         {}", status["shaping_profile"].as_str()?);
+    let _ = writeln!(out, "uptime          {}", format_duration(status["uptime_secs"].as_u64()?));
+    Some(out)
+}
+
+fn render_stats(stats: &Value) -> Option<String> {
+    let count = |key: &str| stats[key].as_u64();
+    let mut out = String::new();
+    let _ = writeln!(out, "tunnels         {}", count("tunnels")?);
+    let _ = writeln!(out, "policy allowed  {}", count("policy_allowed")?);
+    let _ = writeln!(
+        out,
+        "policy blocked  {} (ads {}, tracking {}, malware {}, custom {})",
+        count("policy_blocked")?,
+        count("policy_blocked_ads")?,
+        count("policy_blocked_tracking")?,
+        count("policy_blocked_malware")?,
+        count("policy_blocked_custom")?
+    );
+    let _ = writeln!(out, "sent            {}", format_bytes(count("bytes_client_to_upstream")?));
+    let _ = writeln!(out, "received        {}", format_bytes(count("bytes_upstream_to_client")?));
+    Some(out)
+}
+
+fn render_tunnels(tunnels: &Value) -> Option<String> {
+    let mut out = format!("{:<18} {:<12} {:>10} {:>10} {:>9}  destination\n", "id", "state", "sent", "received", "age");
+    for tunnel in tunnels["tunnels"].as_array()? {
+        let _ = writeln!(
+            out,
+            "{:<18} {:<12} {:>10} {:>10} {:>9}  {}",
+            tunnel["id"].as_str()?,
+            tunnel["state"].as_str()?,
+            format_bytes(tunnel["client_to_upstream_bytes"].as_u64()?),
+            format_bytes(tunnel["upstream_to_client_bytes"].as_u64()?),
+            format_duration(tunnel["duration_ms"].as_u64()? / 1000),
+            tunnel["destination"].as_str().unwrap_or("-")
+        );
+    }
+    let _ = writeln!(out, "{} open", tunnels["open"].as_u64()?);
+    Some(out)
+}
+
+fn render_policy_hits(hits: &Value) -> Option<String> {
+    let rules = hits["rules"].as_array()?;
+    if rules.is_empty() {
+        return Some("no rule has matched yet\n".to_string());
+    }
+    let mut out = format!("{:>10}  {:<7}  rule\n", "hits", "action");
+    for rule in rules {
+        let action = if rule["blocked"].as_bool()? { "block" } else { "allow" };
+        let _ = writeln!(out, "{:>10}  {:<7}  {}", rule["hits"].as_u64()?, action, rule["rule"].as_str()?);
+    }
+    Some(out)
+}
+
+fn render_dns_cache(cache: &Value) -> Option<String> {
+    if cache["count"].as_u64() == Some(0) {
+        return Some("DNS cache is empty\n".to_string());
+    }
+    // Builds below OBS_DEV do not name hosts.
+    let Some(entries) = cache["entries"].as_array() else {
+        return Some(format!(
+            "{} cached answers, expiring in {} to {}\n",
+            cache["count"].as_u64()?,
+            format_duration(cache["min_expires_in_secs"].as_u64()?),
+            format_duration(cache["max_expires_in_secs"].as_u64()?)
+        ));
+    };
+    let mut out = format!("{:>9}  {:<40}  addresses\n", "expires", "hostname");
+    for entry in entries {
+        let addrs: Vec<&str> = entry["addrs"].as_array()?.iter().filter_map(Value::as_str).collect();
+        let _ = writeln!(
+            out,
+            "{:>9}  {:<40}  {}",
+            format_duration(entry["expires_in_secs"].as_u64()?),
+            entry["hostname"].as_str()?,
+            addrs.join(", ")
+        );
+    }
+    Some(out)
+}
+
+/// `1h02m`, `3m05s` or `42s`.
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_known_results_and_falls_back_to_json() {
+        let status = json!({
+            "listen": "127.0.0.1:8080",
+            "health": "healthy",
+            "relay_mode": "direct",
+            "open_tunnels": 2,
+            "content_policy": {"enabled": true, "dry_run": true},
+            "shaping_profile": "custom",
+            "uptime_secs": 3725,
+        });
+        let text = render(&ControlRequest::Status, &status);
+        assert!(text.contains("content policy  dry run\n"), "{}", text);
+        assert!(text.ends_with("uptime          1h02m\n"), "{}", text);
+
+        let tunnels = json!({"open": 1, "tunnels": [{
+            "id": "t-1", "state": "streaming", "client_to_upstream_bytes": 2048,
+            "upstream_to_client_bytes": 10, "duration_ms": 65_000,
+        }]});
+        let text = render(&ControlRequest::ListTunnels, &tunnels);
+        let row = text.lines().nth(1).unwrap();
+        assert!(row.starts_with("t-1 ") && row.contains(" 2.0 KiB ") && row.ends_with("1m05s  -"), "{}", row);
+        assert!(text.ends_with("1 open\n"));
+
+        let hits = json!({"rules": [{"rule": "ads.example", "blocked": true, "hits": 7}]});
+        assert_eq!(
+            render(&ControlRequest::PolicyHits, &hits).lines().nth(1),
+            Some("         7  block    ads.example")
+        );
+        let cache = json!({"count": 1, "min_expires_in_secs": 42, "max_expires_in_secs": 42,
+            "entries": [{"hostname": "a.example", "addrs": ["192.0.2.1", "2001:db8::1"], "expires_in_secs": 42}]});
+        let text = render(&ControlRequest::DnsCache, &cache);
+        assert!(text.lines().nth(1).unwrap().ends_with("192.0.2.1, 2001:db8::1"), "{}", text);
+        let summary = json!({"count": 3, "min_expires_in_secs": 42, "max_expires_in_secs": 65});
+        assert_eq!(render(&ControlRequest::DnsCache, &summary), "3 cached answers, expiring in 42s to 1m05s\n");
+        let empty = json!({"count": 0, "min_expires_in_secs": null, "max_expires_in_secs": null});
+        assert_eq!(render(&ControlRequest::DnsCache, &empty), "DNS cache is empty\n");
+
+        // An older or newer proxy's shape is still shown, as JSON.
+        assert_eq!(render(&ControlRequest::Status, &json!({"health": "healthy"})), "{\n  \"health\": \"healthy\"\n}\n");
+    }
+}
@@ -1,6 +1,7 @@
 use crate::trust_boundaries::*;
 use crate::control_plane::{SessionId, HopKey};
 use std::collections::HashMap;
+use crate::crypto_util::constant_time_eq_array;
 
 #[derive(Debug, Clone)]
 pub struct EncryptedPayload(pub Vec<u8>);
@@ -8,6 +9,17 @@ pub struct EncryptedPayload(pub Vec<u8>);
 #[derive(Debug, Clone)]
 pub struct AuthenticationTag([u8; 16]);
 
+impl AuthenticationTag {
+    pub fn new(tag: [u8; 16]) -> Self {
+        Self(tag)
+    }
+
+    /// Tag checks must stay constant-time; see `crypto_util`.
+    pub fn verify(&self, expected: &[u8; 16]) -> bool {
+        constant_time_eq_array(&self.0, expected)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SequenceNumber(u64);
 
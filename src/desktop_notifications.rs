@@ -0,0 +1,185 @@
+//! Desktop notifications for events an operator should see without
+//! watching the logs: health reaching FAULTED, a storm of relay
+//! reconnects, and the kill switch engaging. Each class is opted into
+//! under `[observability.notifications]`; all are off by default.
+//!
+//! Notifications are raised with the desktop's own tool, `notify-send`
+//! on Linux, `osascript` on macOS and a PowerShell toast on Windows. Like
+//! the events they come from, they never name destinations or clients.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::config::NotificationConfig;
+use crate::core::observability::HealthState;
+use crate::event_bus::{self, EbtEvent};
+use crate::log;
+use crate::logging::LogLevel;
+use crate::os_command::{command, run};
+
+/// Reconnects within `RECONNECT_STORM_WINDOW` that make a storm.
+pub const RECONNECT_STORM_THRESHOLD: usize = 5;
+pub const RECONNECT_STORM_WINDOW: Duration = Duration::from_secs(60);
+const APP_NAME: &str = "Encrypted Browser Tunnel";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Notification {
+    summary: &'static str,
+    body: String,
+}
+
+/// Picks the bus events that warrant a notification.
+#[derive(Debug)]
+struct NotificationFilter {
+    config: NotificationConfig,
+    /// Reconnects inside the storm window, oldest first.
+    reconnects: VecDeque<Instant>,
+}
+
+impl NotificationFilter {
+    fn new(config: NotificationConfig) -> Self {
+        Self {
+            config,
+            reconnects: VecDeque::new(),
+        }
+    }
+
+    fn observe(&mut self, event: &EbtEvent, now: Instant) -> Option<Notification> {
+        match event {
+            EbtEvent::HealthChanged { from, to: HealthState::FAULTED } if self.config.health_faulted => {
+                Some(Notification {
+                    summary: "Proxy health is FAULTED",
+                    body: format!("Health went from {} to faulted; tunnels may be failing.", from.as_str()),
+                })
+            }
+            EbtEvent::TransportReconnected { .. } if self.config.reconnect_storm => {
+                while self.reconnects.front().is_some_and(|&at| now.duration_since(at) > RECONNECT_STORM_WINDOW) {
+                    self.reconnects.pop_front();
+                }
+                self.reconnects.push_back(now);
+                if self.reconnects.len() < RECONNECT_STORM_THRESHOLD {
+                    return None;
+                }
+                // One notification per storm: counting starts over.
+                let count = std::mem::take(&mut self.reconnects).len();
+                Some(Notification {
+                    summary: "Relay connections keep dropping",
+                    body: format!("{} reconnects within {} seconds.", count, RECONNECT_STORM_WINDOW.as_secs()),
+                })
+            }
+            EbtEvent::KillSwitchEngaged { relays } if self.config.kill_switch => Some(Notification {
+                summary: "Kill switch engaged",
+                body: format!("Only traffic to {} relay address(es) can leave this machine.", relays),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Notifies on bus events of the enabled classes; `None` when none is.
+pub(crate) fn spawn(config: NotificationConfig) -> Option<JoinHandle<()>> {
+    if !config.any() {
+        return None;
+    }
+    let mut filter = NotificationFilter::new(config);
+    let mut events = event_bus::subscribe();
+    Some(tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let Some(notification) = filter.observe(&event, Instant::now()) else {
+                continue;
+            };
+            let Some(command) = notification_command(&notification) else {
+                log!(LogLevel::Error, "Desktop notifications are not supported on this platform");
+                break;
+            };
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = run(&command) {
+                    log!(LogLevel::Error, "Desktop notification failed"; safe "error" => e);
+                }
+            });
+        }
+    }))
+}
+
+fn notification_command(notification: &Notification) -> Option<Vec<String>> {
+    let Notification { summary, body } = notification;
+    if cfg!(windows) {
+        let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+        let script = format!(
+            "$m = [Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, \
+             ContentType = WindowsRuntime]; \
+             $t = $m::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+             $x = $t.GetElementsByTagName('text'); \
+             $x.Item(0).AppendChild($t.CreateTextNode({})) > $null; \
+             $x.Item(1).AppendChild($t.CreateTextNode({})) > $null; \
+             $m::CreateToastNotifier({}).Show([Windows.UI.Notifications.ToastNotification]::new($t))",
+            quote(summary),
+            quote(body),
+            quote(APP_NAME)
+        );
+        Some(command(&["powershell", "-NoProfile", "-NonInteractive", "-Command", &script]))
+    } else if cfg!(target_os = "macos") {
+        let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+        let script = format!(
+            "display notification {} with title {} subtitle {}",
+            quote(body),
+            quote(APP_NAME),
+            quote(summary)
+        );
+        Some(command(&["osascript", "-e", &script]))
+    } else if cfg!(unix) {
+        Some(command(&["notify-send", "--app-name", APP_NAME, summary, body]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_only_enabled_classes_and_once_per_reconnect_storm() {
+        let faulted = EbtEvent::HealthChanged {
+            from: HealthState::DEGRADED,
+            to: HealthState::FAULTED,
+        };
+        let engaged = EbtEvent::KillSwitchEngaged { relays: 2 };
+        let start = Instant::now();
+        let mut off = NotificationFilter::new(NotificationConfig::default());
+        assert_eq!(off.observe(&faulted, start), None);
+        assert_eq!(off.observe(&engaged, start), None);
+
+        let mut filter = NotificationFilter::new(NotificationConfig {
+            health_faulted: true,
+            reconnect_storm: true,
+            kill_switch: false,
+        });
+        assert_eq!(filter.observe(&faulted, start).unwrap().summary, "Proxy health is FAULTED");
+        let recovered = EbtEvent::HealthChanged {
+            from: HealthState::FAULTED,
+            to: HealthState::OK,
+        };
+        assert_eq!(filter.observe(&recovered, start), None);
+        assert_eq!(filter.observe(&engaged, start), None);
+
+        let reconnect = EbtEvent::TransportReconnected { attempts: 2 };
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        // Spread out, reconnects never add up to a storm.
+        for i in 0..10 {
+            assert_eq!(filter.observe(&reconnect, at(i * 20)), None);
+        }
+        let burst: Vec<_> = (0..12).map(|i| filter.observe(&reconnect, at(1000 + i))).collect();
+        let raised: Vec<usize> = burst.iter().enumerate().filter_map(|(i, n)| n.as_ref().map(|_| i)).collect();
+        assert_eq!(raised, [4, 9]);
+        assert_eq!(burst[4].as_ref().unwrap().body, "5 reconnects within 60 seconds.");
+    }
+}
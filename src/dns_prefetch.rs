@@ -0,0 +1,86 @@
+//! DNS prefetch for hosts that are rarely visited alone. When a CONNECT
+//! for a hinted host (or one of its subdomains) is allowed, its companions
+//! are resolved in the background through the same DoH resolver tunnels
+//! use, so the shared DoH cache already holds them when the browser opens
+//! those tunnels. The lookups go out exactly as a tunnel's own would; no
+//! hint, no lookup. Each companion is prefetched at most once per interval.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::PrefetchHint;
+use crate::dns_resolver::{DnsResolver, DohResolver};
+
+/// Most answers outlive this, so prefetching sooner would only hit the cache.
+const PREFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct DnsPrefetcher {
+    hints: Vec<PrefetchHint>,
+    last_prefetch: Mutex<HashMap<String, Instant>>,
+}
+
+impl DnsPrefetcher {
+    pub fn new(hints: Vec<PrefetchHint>) -> Self {
+        Self { hints, last_prefetch: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.hints.is_empty()
+    }
+
+    /// Resolves the companions of `host` that are due, without waiting.
+    pub fn on_connect(&self, host: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        for companion in self.due(host, Instant::now()) {
+            // A failed prefetch leaves the lookup to the companion's own tunnel
+            tokio::spawn(async move {
+                let _ = DohResolver::new().resolve(&companion).await;
+            });
+        }
+    }
+
+    fn due(&self, host: &str, now: Instant) -> Vec<String> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let Ok(mut last_prefetch) = self.last_prefetch.lock() else {
+            return Vec::new();
+        };
+        last_prefetch.retain(|_, at| now.duration_since(*at) < PREFETCH_INTERVAL);
+        let mut due = Vec::new();
+        for hint in self.hints.iter().filter(|hint| matches_host(&host, &hint.host)) {
+            for companion in &hint.companions {
+                if *companion != host && !last_prefetch.contains_key(companion) {
+                    last_prefetch.insert(companion.clone(), now);
+                    due.push(companion.clone());
+                }
+            }
+        }
+        due
+    }
+}
+
+fn matches_host(host: &str, hinted: &str) -> bool {
+    host == hinted || host.strip_suffix(hinted).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn companions_are_due_once_per_interval_for_hinted_hosts() {
+        let prefetcher = DnsPrefetcher::new(vec![PrefetchHint {
+            host: "news.example".to_string(),
+            companions: vec!["cdn.example.net".to_string(), "img.example.net".to_string()],
+        }]);
+        let start = Instant::now();
+        assert!(prefetcher.due("othernews.example", start).is_empty());
+        assert_eq!(prefetcher.due("WWW.News.Example.", start), vec!["cdn.example.net", "img.example.net"]);
+        assert!(prefetcher.due("news.example", start + Duration::from_secs(1)).is_empty());
+        assert_eq!(prefetcher.due("news.example", start + PREFETCH_INTERVAL).len(), 2);
+        assert!(!DnsPrefetcher::default().is_enabled());
+    }
+}
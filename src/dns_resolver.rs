@@ -1,16 +1,67 @@
 use std::net::IpAddr;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
+use arc_swap::ArcSwap;
 use serde::Deserialize;
+use crate::core::observability;
+use crate::memory_profile::MemoryProfile;
 
+pub const DEFAULT_DOH_PROVIDER: &str = "https://1.1.1.1/dns-query";
+
+lazy_static::lazy_static! {
+    static ref DOH_PROVIDERS: ArcSwap<Vec<String>> =
+        ArcSwap::from_pointee(vec![DEFAULT_DOH_PROVIDER.to_string()]);
+}
+
+/// Answers shared by every `DohResolver`: tunnels each build their own
+/// resolver, so a per-resolver cache would die with its tunnel.
+static DOH_CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+const DOH_CACHE_ENTRIES: usize = MemoryProfile::active().doh_cache_entries;
+
+/// Replaces the DoH endpoints used by every `DohResolver`; lookups already
+/// in flight finish against the previous list.
+pub fn set_doh_providers(providers: Vec<String>) {
+    DOH_PROVIDERS.store(Arc::new(providers));
+}
+
+/// The DoH endpoints `DohResolver` currently queries.
+pub fn doh_providers() -> Arc<Vec<String>> {
+    DOH_PROVIDERS.load_full()
+}
+
+// Resolvers are used through concrete types, so callers never need the
+// returned futures to be `Send`-bounded.
+#[allow(async_fn_in_trait)]
 pub trait DnsResolver {
     async fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>, DnsError>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum DnsError {
+    #[error("DNS resolution failed")]
     ResolutionFailed,
+    #[error("DNS query timeout")]
+    Timeout,
+    #[error("Invalid domain name")]
+    InvalidDomain,
+    #[error(transparent)]
+    Policy(#[from] crate::real_dns::DnsPolicyViolation),
+}
+
+impl From<crate::dns::DnsError> for DnsError {
+    fn from(error: crate::dns::DnsError) -> Self {
+        match error {
+            crate::dns::DnsError::ResolutionFailed => DnsError::ResolutionFailed,
+            crate::dns::DnsError::Timeout => DnsError::Timeout,
+            crate::dns::DnsError::InvalidDomain => DnsError::InvalidDomain,
+        }
+    }
+}
+
+/// Names are resolved and cached in one spelling; see `hostname`.
+pub(crate) fn canonical_hostname(hostname: &str) -> Result<String, DnsError> {
+    crate::hostname::canonicalize(hostname).map_err(|_| DnsError::InvalidDomain)
 }
 
 pub struct SystemDnsResolver;
@@ -19,6 +70,7 @@ impl DnsResolver for SystemDnsResolver {
     async fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>, DnsError> {
         use std::net::ToSocketAddrs;
         
+        let hostname = canonical_hostname(hostname)?;
         let addrs: Vec<IpAddr> = format!("{}:0", hostname)
             .to_socket_addrs()
             .map_err(|_| DnsError::ResolutionFailed)?
@@ -58,9 +110,40 @@ struct CacheEntry {
     expires: Instant,
 }
 
+/// One unexpired DoH answer, as listed by `doh_cache`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedLookup {
+    pub hostname: String,
+    pub addrs: Vec<IpAddr>,
+    pub expires_in: Duration,
+}
+
+/// Unexpired DoH answers by hostname; expired ones are dropped on the way.
+pub fn doh_cache() -> Vec<CachedLookup> {
+    let Ok(mut cache) = DOH_CACHE.lock() else {
+        return Vec::new();
+    };
+    let now = Instant::now();
+    cache.retain(|_, entry| entry.expires > now);
+    cache
+        .iter()
+        .map(|(hostname, entry)| CachedLookup {
+            hostname: hostname.clone(),
+            addrs: entry.ips.clone(),
+            expires_in: entry.expires - now,
+        })
+        .collect()
+}
+
+/// Forgets every DoH answer, e.g. after a network change made them stale.
+pub fn clear_doh_cache() {
+    if let Ok(mut cache) = DOH_CACHE.lock() {
+        cache.clear();
+    }
+}
+
 pub struct DohResolver {
     client: reqwest::Client,
-    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
     #[cfg(feature = "doh_fallback")]
     fallback: SystemDnsResolver,
 }
@@ -69,14 +152,13 @@ impl DohResolver {
     pub fn new() -> Self {
         Self {
             client: reqwest::Client::new(),
-            cache: Arc::new(Mutex::new(HashMap::new())),
             #[cfg(feature = "doh_fallback")]
             fallback: SystemDnsResolver,
         }
     }
     
     fn get_cached(&self, hostname: &str) -> Option<Vec<IpAddr>> {
-        let cache = self.cache.lock().ok()?;
+        let cache = DOH_CACHE.lock().ok()?;
         let entry = cache.get(hostname)?;
         if entry.expires > Instant::now() {
             Some(entry.ips.clone())
@@ -86,60 +168,120 @@ impl DohResolver {
     }
     
     fn cache_result(&self, hostname: &str, ips: Vec<IpAddr>, ttl: u32) {
-        if let Ok(mut cache) = self.cache.lock() {
-            let expires = Instant::now() + Duration::from_secs(ttl as u64);
-            cache.insert(hostname.to_string(), CacheEntry { ips, expires });
+        if let Ok(mut cache) = DOH_CACHE.lock() {
+            let now = Instant::now();
+            let expires = now + Duration::from_secs(ttl as u64);
+            insert_bounded(&mut cache, hostname, CacheEntry { ips, expires }, now, DOH_CACHE_ENTRIES);
         }
     }
 }
 
+/// Adds an answer without growing past `capacity`: expired answers make
+/// room first, then the one closest to expiring.
+fn insert_bounded(
+    cache: &mut BTreeMap<String, CacheEntry>,
+    hostname: &str,
+    entry: CacheEntry,
+    now: Instant,
+    capacity: usize,
+) {
+    if cache.len() >= capacity && !cache.contains_key(hostname) {
+        cache.retain(|_, entry| entry.expires > now);
+        let soonest = cache.iter().min_by_key(|(_, entry)| entry.expires).map(|(name, _)| name.clone());
+        if let (true, Some(soonest)) = (cache.len() >= capacity, soonest) {
+            cache.remove(&soonest);
+        }
+    }
+    cache.insert(hostname.to_string(), entry);
+}
+
+/// One uncached query to `provider`, for `ebt doctor`.
+pub(crate) struct DohProbe {
+    pub addrs: Vec<IpAddr>,
+    pub elapsed: Duration,
+    /// The provider's `Date` header.
+    pub server_date: Option<String>,
+}
+
+pub(crate) async fn probe_doh_provider(provider: &str, hostname: &str) -> Result<DohProbe, reqwest::Error> {
+    let started = Instant::now();
+    let response = reqwest::Client::new()
+        .get(format!("{}?name={}&type=A", provider, hostname))
+        .header("Accept", "application/dns-json")
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?;
+    let server_date = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .map(str::to_string);
+    let answers = response.json::<DohResponse>().await?.answer.unwrap_or_default();
+    Ok(DohProbe {
+        addrs: answers.iter().filter_map(|answer| answer.data.parse().ok()).collect(),
+        elapsed: started.elapsed(),
+        server_date,
+    })
+}
+
+impl Default for DohResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsResolver for DohResolver {
     async fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>, DnsError> {
+        // Also keeps the name from adding parameters to the query URL
+        let hostname = &canonical_hostname(hostname)?;
         if let Some(cached) = self.get_cached(hostname) {
             return Ok(cached);
         }
         
-        let url = format!(
-            "https://1.1.1.1/dns-query?name={}&type=A",
-            hostname
-        );
-        
-        // Attempt DoH resolution with timeout and retry
-        for _attempt in 0..2 {
-            let response_result = self.client
-                .get(&url)
-                .header("Accept", "application/dns-json")
-                .timeout(Duration::from_secs(5))
-                .send()
-                .await;
+        // Attempt DoH resolution with timeout and retry, provider by provider
+        let started = Instant::now();
+        let providers = DOH_PROVIDERS.load_full();
+        for provider in providers.iter() {
+            let url = format!("{}?name={}&type=A", provider, hostname);
+            for _attempt in 0..2 {
+                let response_result = self.client
+                    .get(&url)
+                    .header("Accept", "application/dns-json")
+                    .timeout(Duration::from_secs(5))
+                    .send()
+                    .await;
             
-            let response = match response_result {
-                Ok(resp) => match resp.json::<DohResponse>().await {
-                    Ok(json) => json,
+                let response = match response_result {
+                    Ok(resp) => match resp.json::<DohResponse>().await {
+                        Ok(json) => json,
+                        Err(_e) => continue,
+                    },
                     Err(_e) => continue,
-                },
-                Err(_e) => continue,
-            };
+                };
             
-            let mut ips = Vec::new();
-            let mut min_ttl = 300u32;
+                let mut ips = Vec::new();
+                let mut min_ttl = 300u32;
             
-            if let Some(answers) = response.answer {
-                for answer in answers {
-                    if let Ok(ip) = answer.data.parse::<IpAddr>() {
-                        ips.push(ip);
-                        min_ttl = min_ttl.min(answer.ttl);
+                if let Some(answers) = response.answer {
+                    for answer in answers {
+                        if let Ok(ip) = answer.data.parse::<IpAddr>() {
+                            ips.push(ip);
+                            min_ttl = min_ttl.min(answer.ttl);
+                        }
                     }
                 }
-            }
             
-            if !ips.is_empty() {
-                self.cache_result(hostname, ips.clone(), min_ttl);
-                return Ok(ips);
+                if !ips.is_empty() {
+                    self.cache_result(hostname, ips.clone(), min_ttl);
+                    observability::record_dns_latency(started.elapsed());
+                    return Ok(ips);
+                }
             }
         }
         
         // All attempts failed
+        observability::record_dns_failover();
         #[cfg(feature = "doh_fallback")]
         {
             self.fallback.resolve(hostname).await
@@ -150,3 +292,30 @@ impl DnsResolver for DohResolver {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_never_grows_past_its_capacity() {
+        let mut cache = BTreeMap::new();
+        let now = Instant::now();
+        let answer = |ttl_secs: u64| CacheEntry {
+            ips: vec![IpAddr::from([192, 0, 2, 1])],
+            expires: now + Duration::from_secs(ttl_secs),
+        };
+        for i in 0..100u64 {
+            insert_bounded(&mut cache, &format!("host{}.example", i), answer(1000 + i), now, 8);
+            assert!(cache.len() <= 8);
+        }
+        // The answers closest to expiring made room for the newest.
+        assert!(cache.contains_key("host99.example") && cache.contains_key("host92.example"));
+        assert!(!cache.contains_key("host91.example"));
+
+        // Refreshing a cached name replaces it without evicting another.
+        insert_bounded(&mut cache, "host92.example", answer(5000), now, 8);
+        assert_eq!(cache.len(), 8);
+        assert!(cache.contains_key("host93.example"));
+    }
+}
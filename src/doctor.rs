@@ -0,0 +1,300 @@
+//! `ebt doctor`: checks what a proxy needs from the machine and network
+//! before it starts, and says what to change when something is off. A
+//! misconfigured proxy otherwise starts fine and only fails per tunnel,
+//! with a log line at best.
+//!
+//! Checked, in order: config warnings, the listen address, the platform
+//! trust store, each DoH provider, each relay up to its Hello, and the
+//! local clock against a DoH provider's `Date` header.
+
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{RelayEndpoint, RelayMode, TunnelConfig};
+use crate::dns_resolver::probe_doh_provider;
+use crate::relay_client::{self, RelayClientError};
+
+/// Looked up through each DoH provider.
+const PROBE_HOSTNAME: &str = "example.com";
+/// Dial, TLS and Hello together.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(15);
+/// Clock skew worth a warning, and skew that breaks certificate checks
+/// and signed relay directories.
+const CLOCK_SKEW_WARN: Duration = Duration::from_secs(30);
+const CLOCK_SKEW_FAIL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Finding {
+    check: &'static str,
+    outcome: Outcome,
+    detail: String,
+    /// What to do about a warning or failure.
+    hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            check,
+            outcome: Outcome::Ok,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(check: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            outcome: Outcome::Warn,
+            hint: Some(hint.into()),
+            ..Self::ok(check, detail)
+        }
+    }
+
+    fn fail(check: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            outcome: Outcome::Fail,
+            ..Self::warn(check, detail, hint)
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = match self.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        write!(f, "{:<5} {:<12} {}", outcome, self.check, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n{:<18} {}", "", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints every finding; fails when any check did.
+pub async fn run(config: &TunnelConfig) -> Result<(), Box<dyn Error>> {
+    let findings = diagnose(config).await;
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let count = |outcome| findings.iter().filter(|finding| finding.outcome == outcome).count();
+    match (count(Outcome::Fail), count(Outcome::Warn)) {
+        (0, 0) => println!("\nall checks passed"),
+        (0, warnings) => println!("\nno problems found; {} warning(s)", warnings),
+        (failed, _) => return Err(format!("{} check(s) failed", failed).into()),
+    }
+    Ok(())
+}
+
+async fn diagnose(config: &TunnelConfig) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = crate::config_file::warnings(config, std::env::vars())
+        .into_iter()
+        .map(|warning| Finding::warn("config", warning, "`ebt check-config` lists config problems"))
+        .collect();
+    let proxy = &config.proxy_policy;
+    findings.push(check_listen(&proxy.bind_address, proxy.bind_port));
+    findings.push(check_trust_store());
+
+    let relayed = RelayMode::compiled() != RelayMode::Direct;
+    let mut server_date = None;
+    for provider in &config.dns_policy.doh_providers {
+        let (finding, date) = check_doh(provider, relayed).await;
+        findings.push(finding);
+        server_date = server_date.or(date.map(|date| (provider.as_str(), date)));
+    }
+    for endpoint in &config.relay.endpoints {
+        findings.push(check_relay(endpoint).await);
+    }
+    findings.push(match server_date {
+        Some((provider, date)) => check_clock(SystemTime::now(), date, provider),
+        None => Finding::warn(
+            "clock",
+            "not checked: no DoH provider answered",
+            "certificates and relay directories fail to verify when the clock is off; make sure it is synced",
+        ),
+    });
+    findings
+}
+
+fn check_listen(address: &str, port: u16) -> Finding {
+    let addr = format!("{}:{}", address, port);
+    match TcpListener::bind((address, port)) {
+        Ok(_) => Finding::ok("listen", format!("{} is free", addr)),
+        Err(e) => {
+            let hint = match e.kind() {
+                ErrorKind::AddrInUse => "another program, or a running proxy, holds this port; stop it or set \
+                                         proxy.bind_port (or pass --port)"
+                    .to_string(),
+                ErrorKind::PermissionDenied => "ports below 1024 need elevated privileges; choose a higher \
+                                                proxy.bind_port"
+                    .to_string(),
+                ErrorKind::AddrNotAvailable => {
+                    format!("{} is not an address of this machine; fix proxy.bind_address", address)
+                }
+                _ => "check proxy.bind_address and proxy.bind_port".to_string(),
+            };
+            Finding::fail("listen", format!("cannot listen on {}: {}", addr, e), hint)
+        }
+    }
+}
+
+fn check_trust_store() -> Finding {
+    let hint = "relays without a fingerprint pin are verified against these roots; install the system CA \
+                bundle (e.g. ca-certificates) or set SSL_CERT_FILE";
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) if certs.is_empty() => Finding::fail("trust store", "no root certificates found", hint),
+        Ok(certs) => Finding::ok("trust store", format!("{} root certificates", certs.len())),
+        Err(e) => Finding::fail("trust store", format!("cannot load root certificates: {}", e), hint),
+    }
+}
+
+/// Also returns the provider's `Date`, for the clock check.
+async fn check_doh(provider: &str, relayed: bool) -> (Finding, Option<SystemTime>) {
+    let problem = |detail: String, hint: &str| {
+        if relayed {
+            let hint = format!("{}; relays resolve destinations, so only relay hostnames need DoH", hint);
+            Finding::warn("doh", detail, hint)
+        } else {
+            Finding::fail("doh", detail, hint)
+        }
+    };
+    match probe_doh_provider(provider, PROBE_HOSTNAME).await {
+        Ok(probe) => {
+            let date = probe.server_date.as_deref().and_then(parse_http_date);
+            let finding = if probe.addrs.is_empty() {
+                problem(
+                    format!("{} answered without addresses for {}", provider, PROBE_HOSTNAME),
+                    "make sure the URL is a JSON DoH endpoint (application/dns-json)",
+                )
+            } else {
+                Finding::ok("doh", format!("{} answered in {} ms", provider, probe.elapsed.as_millis()))
+            };
+            (finding, date)
+        }
+        Err(e) => {
+            let hint = if e.to_string().contains("certificate") {
+                "the provider's certificate did not verify; something may be intercepting HTTPS (a captive \
+                 portal or inspecting proxy), or the clock is wrong"
+            } else if e.is_timeout() || e.is_connect() {
+                "outbound HTTPS to the provider is blocked or the network is down; try another \
+                 dns.doh_providers entry"
+            } else if e.is_status() {
+                "the provider refused the query; check the URL in dns.doh_providers"
+            } else {
+                "check the URL in dns.doh_providers"
+            };
+            (problem(format!("{}: {}", provider, e), hint), None)
+        }
+    }
+}
+
+async fn check_relay(endpoint: &RelayEndpoint) -> Finding {
+    let started = std::time::Instant::now();
+    let handshake = async { relay_client::handshake(relay_client::dial(endpoint).await?, endpoint.checksums).await };
+    let result = match tokio::time::timeout(RELAY_TIMEOUT, handshake).await {
+        Ok(result) => result.map(|_| ()),
+        Err(_) => Err(RelayClientError::Timeout),
+    };
+    match result {
+        Ok(()) => Finding::ok("relay", format!("{} said Hello in {} ms", endpoint, started.elapsed().as_millis())),
+        Err(e) => Finding::fail("relay", format!("{}: {}", endpoint, e), relay_hint(endpoint, &e)),
+    }
+}
+
+fn relay_hint(endpoint: &RelayEndpoint, error: &RelayClientError) -> String {
+    match error {
+        RelayClientError::Io(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            "nothing listens there; is `ebt relay serve` running on that host and port?".to_string()
+        }
+        RelayClientError::Timeout => {
+            "no answer; a firewall may drop traffic to the relay, or the host is down".to_string()
+        }
+        RelayClientError::Io(e) if e.to_string().contains("pin") => {
+            "the relay's certificate changed; update its fingerprint, e.g. with `ebt relay fetch`".to_string()
+        }
+        RelayClientError::Io(e) if e.kind() == ErrorKind::InvalidData && endpoint.fingerprint.is_none() => {
+            "TLS failed; a relay with a self-signed certificate needs its fingerprint pinned".to_string()
+        }
+        RelayClientError::Protocol(_) => "something answered, but not an EBT relay; check the port".to_string(),
+        RelayClientError::UnsupportedTransport(_) => "this build reaches relays over TLS only".to_string(),
+        _ => "check the relay's host, port and fingerprint; `ebt relay probe` tests the TCP connect".to_string(),
+    }
+}
+
+fn check_clock(now: SystemTime, server: SystemTime, provider: &str) -> Finding {
+    let (skew, direction) = match now.duration_since(server) {
+        Ok(ahead) => (ahead, "ahead of"),
+        Err(behind) => (behind.duration(), "behind"),
+    };
+    let detail = format!("local clock is {}s {} {}", skew.as_secs(), direction, provider);
+    let hint = "certificates and relay directories fail to verify on a wrong clock; enable time sync (NTP)";
+    if skew > CLOCK_SKEW_FAIL {
+        Finding::fail("clock", detail, hint)
+    } else if skew > CLOCK_SKEW_WARN {
+        Finding::warn("clock", detail, hint)
+    } else {
+        Finding::ok("clock", format!("within {}s of {}", CLOCK_SKEW_WARN.as_secs(), provider))
+    }
+}
+
+/// An RFC 9110 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let [_weekday, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let mut hms = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    // Days since the epoch, proleptic Gregorian calendar.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn findings_name_the_problem_and_what_to_do() {
+        let held = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = held.local_addr().unwrap().port();
+        let busy = check_listen("127.0.0.1", port);
+        assert_eq!(busy.outcome, Outcome::Fail);
+        assert!(busy.hint.as_deref().unwrap().contains("proxy.bind_port"), "{}", busy);
+        drop(held);
+        assert_eq!(check_listen("127.0.0.1", port).outcome, Outcome::Ok);
+
+        let date = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date.duration_since(UNIX_EPOCH).unwrap().as_secs(), 784_111_777);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        let ahead = |secs| check_clock(date + Duration::from_secs(secs), date, "doh.example").outcome;
+        assert_eq!([ahead(5), ahead(90), ahead(3600)], [Outcome::Ok, Outcome::Warn, Outcome::Fail]);
+        let behind = check_clock(date, date + Duration::from_secs(600), "doh.example");
+        assert_eq!(behind.detail, "local clock is 600s behind doh.example");
+        assert_eq!(
+            behind.to_string(),
+            "FAIL  clock        local clock is 600s behind doh.example\n                   certificates and \
+             relay directories fail to verify on a wrong clock; enable time sync (NTP)"
+        );
+    }
+}
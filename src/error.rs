@@ -0,0 +1,96 @@
+//! Crate-wide error type. Each layer keeps its own error enum next to the
+//! code that produces it; `EbtError` groups them by layer so callers can
+//! propagate with `?` and still match on the cause, and maps every error to
+//! an observability `ErrorClass`.
+
+use crate::config::ConfigError;
+use crate::content_policy_bootstrap::RuleSourceError;
+use crate::core::observability::{self, ErrorClass};
+use crate::dns_resolver::DnsError;
+use crate::relay_protocol::ProtocolError;
+use crate::transport::TransportError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EbtError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Dns(#[from] DnsError),
+    #[error(transparent)]
+    Policy(#[from] RuleSourceError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+pub type EbtResult<T> = Result<T, EbtError>;
+
+/// Socket I/O outside a framed protocol is a transport failure.
+impl From<std::io::Error> for EbtError {
+    fn from(error: std::io::Error) -> Self {
+        EbtError::Transport(TransportError::Io(error))
+    }
+}
+
+impl From<crate::dns::DnsError> for EbtError {
+    fn from(error: crate::dns::DnsError) -> Self {
+        EbtError::Dns(error.into())
+    }
+}
+
+impl From<crate::real_dns::DnsPolicyViolation> for EbtError {
+    fn from(error: crate::real_dns::DnsPolicyViolation) -> Self {
+        EbtError::Dns(error.into())
+    }
+}
+
+impl EbtError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            EbtError::Transport(_) | EbtError::Dns(_) => ErrorClass::TRANSPORT_IO,
+            EbtError::Protocol(error) => error.class(),
+            EbtError::Policy(error) => match error {
+                RuleSourceError::Read { .. } | RuleSourceError::Fetch { .. } => ErrorClass::TRANSPORT_IO,
+                RuleSourceError::TooLarge(_) => ErrorClass::RESOURCE_LIMIT,
+                _ => ErrorClass::PROTOCOL_VIOLATION,
+            },
+            EbtError::Config(_) => ErrorClass::INTERNAL_ASSERT,
+        }
+    }
+
+    /// Counts this error under its class.
+    pub fn record(&self) {
+        observability::record_error(self.class());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_observability_classes() {
+        let io = EbtError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert_eq!(io.class(), ErrorClass::TRANSPORT_IO);
+        assert_eq!(
+            EbtError::from(ProtocolError::ConnectionLimit).class(),
+            ErrorClass::RESOURCE_LIMIT
+        );
+        assert_eq!(
+            EbtError::from(ProtocolError::InvalidFrameType(9)).class(),
+            ErrorClass::PROTOCOL_VIOLATION
+        );
+        assert_eq!(
+            EbtError::from(RuleSourceError::Empty("rules.txt".to_string())).class(),
+            ErrorClass::PROTOCOL_VIOLATION
+        );
+        assert_eq!(EbtError::from(ConfigError::NotBound).class(), ErrorClass::INTERNAL_ASSERT);
+    }
+
+    #[test]
+    fn display_is_the_underlying_error() {
+        let error = EbtError::from(TransportError::ConnectionFailed);
+        assert_eq!(error.to_string(), "Transport connection failed");
+    }
+}
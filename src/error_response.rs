@@ -0,0 +1,141 @@
+//! Replies the proxy sends in place of a tunnel. Each cause has its own
+//! status and an `X-EBT-Reason` code, so a browser error page or a script
+//! can tell a policy block from an upstream that refused the connection
+//! or never answered. A code may carry a detail after `; `, e.g.
+//! `policy-blocked; category=ads`. Every reply closes the connection.
+
+use std::fmt::Write;
+
+/// The header naming a reply's cause.
+pub const REASON_HEADER: &str = "X-EBT-Reason";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorReason {
+    /// The request line or CONNECT target could not be parsed.
+    MalformedRequest,
+    /// Credentials were missing or wrong.
+    AuthRequired,
+    /// Only CONNECT is proxied.
+    MethodNotAllowed,
+    /// The request line exceeded `max_request_line`.
+    RequestLineTooLong,
+    /// The head exceeded `max_header_count` or `max_header_bytes`.
+    HeadersTooLarge,
+    /// The content policy blocked the destination.
+    PolicyBlocked,
+    /// The destination, or the relay in front of it, refused or failed.
+    UpstreamUnreachable,
+    /// The destination did not answer in time.
+    UpstreamTimeout,
+}
+
+impl ErrorReason {
+    pub fn status(&self) -> &'static str {
+        match self {
+            ErrorReason::MalformedRequest => "400 Bad Request",
+            ErrorReason::AuthRequired => "407 Proxy Authentication Required",
+            ErrorReason::MethodNotAllowed => "405 Method Not Allowed",
+            ErrorReason::RequestLineTooLong => "414 URI Too Long",
+            ErrorReason::HeadersTooLarge => "431 Request Header Fields Too Large",
+            ErrorReason::PolicyBlocked => "403 Forbidden",
+            ErrorReason::UpstreamUnreachable => "502 Bad Gateway",
+            ErrorReason::UpstreamTimeout => "504 Gateway Timeout",
+        }
+    }
+
+    /// The `X-EBT-Reason` value; stable, for automation to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorReason::MalformedRequest => "malformed-request",
+            ErrorReason::AuthRequired => "auth-required",
+            ErrorReason::MethodNotAllowed => "method-not-allowed",
+            ErrorReason::RequestLineTooLong => "request-line-too-long",
+            ErrorReason::HeadersTooLarge => "headers-too-large",
+            ErrorReason::PolicyBlocked => "policy-blocked",
+            ErrorReason::UpstreamUnreachable => "upstream-unreachable",
+            ErrorReason::UpstreamTimeout => "upstream-timeout",
+        }
+    }
+}
+
+/// Builds the reply for an `ErrorReason`, with any extra headers and body.
+#[derive(Debug, Clone)]
+pub struct ErrorResponse {
+    reason: ErrorReason,
+    detail: Option<String>,
+    headers: Vec<(&'static str, String)>,
+    body: Option<(&'static str, String)>,
+}
+
+impl ErrorResponse {
+    pub fn new(reason: ErrorReason) -> Self {
+        Self {
+            reason,
+            detail: None,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    /// Appended to the `X-EBT-Reason` code as `; <detail>`.
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, body: impl Into<String>) -> Self {
+        self.body = Some((content_type, body.into()));
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\n{}: {}", self.reason.status(), REASON_HEADER, self.reason.code());
+        if let Some(detail) = &self.detail {
+            let _ = write!(response, "; {}", detail);
+        }
+        response.push_str("\r\n");
+        for (name, value) in &self.headers {
+            let _ = write!(response, "{}: {}\r\n", name, value);
+        }
+        let body = match &self.body {
+            Some((content_type, body)) => {
+                let _ = write!(response, "Content-Type: {}\r\n", content_type);
+                body.as_str()
+            }
+            None => "",
+        };
+        let _ = write!(response, "Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        response.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_carry_their_status_and_reason_code() {
+        let reply = String::from_utf8(ErrorResponse::new(ErrorReason::UpstreamTimeout).to_bytes()).unwrap();
+        assert_eq!(
+            reply,
+            "HTTP/1.1 504 Gateway Timeout\r\nX-EBT-Reason: upstream-timeout\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        let reply = ErrorResponse::new(ErrorReason::PolicyBlocked)
+            .detail("category=ads")
+            .header("Cache-Control", "no-store")
+            .body("text/plain", "blocked\n")
+            .to_bytes();
+        let reply = String::from_utf8(reply).unwrap();
+        let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\nX-EBT-Reason: policy-blocked; category=ads\r\n"));
+        assert!(head.contains("\r\nCache-Control: no-store\r\nContent-Type: text/plain\r\nContent-Length: 8\r\n"));
+        assert_eq!(body, "blocked\n");
+    }
+}
@@ -0,0 +1,206 @@
+//! Process-wide broadcast of typed events between subsystems. Producers
+//! publish without blocking and without knowing who listens; consumers
+//! (the admin API, stats, future UI integrations) subscribe and may lag.
+//! A lagging receiver skips the oldest events rather than slowing producers.
+//!
+//! Events carry identifiers and reason codes only, never destinations.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use crate::content_policy::ReasonCode;
+use crate::core::observability::HealthState;
+use crate::memory_profile::MemoryProfile;
+use crate::tunnel_registry::TunnelId;
+
+const EVENT_BUS_CAPACITY: usize = MemoryProfile::active().event_bus_capacity;
+
+/// Why a tunnel left the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Forwarding ran to completion.
+    Completed,
+    /// The upstream connection could not be established.
+    UpstreamFailed,
+    /// Dropped before forwarding started, e.g. a client write failed.
+    Aborted,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Completed => "completed",
+            CloseReason::UpstreamFailed => "upstream_failed",
+            CloseReason::Aborted => "aborted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EbtEvent {
+    TunnelOpened { id: TunnelId },
+    TunnelClosed { id: TunnelId, reason: CloseReason },
+    PolicyBlocked { reason: ReasonCode },
+    /// A transport connect succeeded after at least one retry; `attempts`
+    /// includes the successful one.
+    TransportReconnected { attempts: usize },
+    HealthChanged { from: HealthState, to: HealthState },
+    /// Outbound traffic is now limited to `relays` relay addresses.
+    KillSwitchEngaged { relays: usize },
+    /// The route to the internet moved; `tunnels_reset` tunnels were closed.
+    NetworkChanged { tunnels_reset: usize },
+    /// A configuration reload was accepted and is now in effect.
+    ConfigReloaded,
+}
+
+impl EbtEvent {
+    /// One-line JSON object for the admin API.
+    pub fn to_json(&self) -> String {
+        match self {
+            EbtEvent::TunnelOpened { id } => {
+                format!("{{\"event\":\"tunnel_opened\",\"id\":\"{}\"}}", id)
+            }
+            EbtEvent::TunnelClosed { id, reason } => format!(
+                "{{\"event\":\"tunnel_closed\",\"id\":\"{}\",\"reason\":\"{}\"}}",
+                id,
+                reason.as_str()
+            ),
+            EbtEvent::PolicyBlocked { reason } => format!(
+                "{{\"event\":\"policy_blocked\",\"reason\":\"{}\"}}",
+                reason.as_str()
+            ),
+            EbtEvent::TransportReconnected { attempts } => format!(
+                "{{\"event\":\"transport_reconnected\",\"attempts\":{}}}",
+                attempts
+            ),
+            EbtEvent::HealthChanged { from, to } => format!(
+                "{{\"event\":\"health_changed\",\"from\":\"{}\",\"to\":\"{}\"}}",
+                from.as_str(),
+                to.as_str()
+            ),
+            EbtEvent::KillSwitchEngaged { relays } => format!(
+                "{{\"event\":\"kill_switch_engaged\",\"relays\":{}}}",
+                relays
+            ),
+            EbtEvent::NetworkChanged { tunnels_reset } => format!(
+                "{{\"event\":\"network_changed\",\"tunnels_reset\":{}}}",
+                tunnels_reset
+            ),
+            EbtEvent::ConfigReloaded => "{\"event\":\"config_reloaded\"}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<EbtEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Never blocks; the event is dropped when nobody is subscribed.
+    pub fn publish(&self, event: EbtEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EbtEvent> {
+        self.sender.subscribe()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref EVENT_BUS: EventBus = EventBus::new(EVENT_BUS_CAPACITY);
+}
+
+pub fn publish(event: EbtEvent) {
+    EVENT_BUS.publish(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<EbtEvent> {
+    EVENT_BUS.subscribe()
+}
+
+/// Bounded history of recent events, fed by a subscriber task.
+#[derive(Debug)]
+pub struct RecentEvents {
+    limit: usize,
+    state: Mutex<RecentEventsState>,
+}
+
+#[derive(Debug, Default)]
+struct RecentEventsState {
+    events: VecDeque<EbtEvent>,
+    /// Events skipped because the subscriber lagged behind producers.
+    missed: u64,
+}
+
+impl RecentEvents {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(RecentEventsState::default()),
+        }
+    }
+
+    pub fn push(&self, event: EbtEvent) {
+        if let Ok(mut state) = self.state.lock() {
+            if state.events.len() >= self.limit {
+                state.events.pop_front();
+            }
+            state.events.push_back(event);
+        }
+    }
+
+    pub fn record_missed(&self, count: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.missed += count;
+        }
+    }
+
+    /// JSON body for the admin endpoint, oldest event first.
+    pub fn to_json(&self) -> String {
+        let Ok(state) = self.state.lock() else {
+            return "{\"missed\":0,\"events\":[]}".to_string();
+        };
+        let events: Vec<String> = state.events.iter().map(EbtEvent::to_json).collect();
+        format!("{{\"missed\":{},\"events\":[{}]}}", state.missed, events.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_receive_published_events() {
+        let bus = EventBus::new(8);
+        bus.publish(EbtEvent::TransportReconnected { attempts: 1 });
+        let mut receiver = bus.subscribe();
+        bus.publish(EbtEvent::PolicyBlocked { reason: ReasonCode::Ads });
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            EbtEvent::PolicyBlocked { reason: ReasonCode::Ads }
+        );
+    }
+
+    #[test]
+    fn recent_events_are_bounded_and_report_misses() {
+        let recent = RecentEvents::new(2);
+        for attempts in 1..=3 {
+            recent.push(EbtEvent::TransportReconnected { attempts });
+        }
+        recent.record_missed(4);
+        assert_eq!(
+            recent.to_json(),
+            "{\"missed\":4,\"events\":[\
+             {\"event\":\"transport_reconnected\",\"attempts\":2},\
+             {\"event\":\"transport_reconnected\",\"attempts\":3}]}"
+        );
+    }
+}
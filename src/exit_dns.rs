@@ -0,0 +1,413 @@
+//! Exit-zone DNS round trip for relay mode.
+//!
+//! The client seals the hostname to the exit's `dns_encryption_key`
+//! (`PayloadMessage::DnsRequest`). Only the exit can open it, resolve it and
+//! seal the IP list back (`PayloadMessage::DnsResponse`). Entry and relay
+//! hops forward opaque bytes and never see the hostname.
+//!
+//! Relay sessions carry the exchange in `FrameType::Dns` frames once both
+//! sides set `CAP_EXIT_DNS`: clients whose exit endpoint has a `dns_key`
+//! resolve there (`RelayChannel::resolve`) and open by address, and exits
+//! started with `--dns-key-file` answer (`ExitPolicy::lookups`). Without a
+//! key the hostname is sent in `Open` instead. There is no default key.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use async_trait::async_trait;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+
+use crate::crypto_transport_design::PayloadMessage;
+use crate::data_plane::ExitZoneDnsResolver;
+use crate::dns_resolver::{canonical_hostname, DnsError, DnsResolver};
+
+const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_IPS: usize = 32;
+
+const MSG_DNS_REQUEST: u8 = 0x01;
+const MSG_DNS_RESPONSE: u8 = 0x02;
+
+const AAD_REQUEST: &[u8] = b"ebt-dns-req";
+const AAD_RESPONSE: &[u8] = b"ebt-dns-resp";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitDnsError {
+    InvalidHostname,
+    SealFailed,
+    OpenFailed,
+    Malformed,
+    ResolutionFailed,
+    Transport,
+}
+
+impl std::fmt::Display for ExitDnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitDnsError::InvalidHostname => write!(f, "Invalid hostname for exit DNS"),
+            ExitDnsError::SealFailed => write!(f, "Exit DNS payload encryption failed"),
+            ExitDnsError::OpenFailed => write!(f, "Exit DNS payload authentication failed"),
+            ExitDnsError::Malformed => write!(f, "Malformed exit DNS message"),
+            ExitDnsError::ResolutionFailed => write!(f, "Exit DNS resolution failed"),
+            ExitDnsError::Transport => write!(f, "Exit DNS transport failed"),
+        }
+    }
+}
+
+impl std::error::Error for ExitDnsError {}
+
+/// AEAD box keyed with the exit's `dns_encryption_key`.
+struct DnsKey {
+    key: LessSafeKey,
+}
+
+impl DnsKey {
+    fn new(key_bytes: &[u8; 32]) -> Self {
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, key_bytes)
+            .expect("chacha20-poly1305 accepts 32-byte keys");
+        Self {
+            key: LessSafeKey::new(unbound),
+        }
+    }
+
+    fn seal(&self, label: &[u8], request_id: u32, plaintext: &[u8]) -> Result<Vec<u8>, ExitDnsError> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+
+        let mut in_out = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(aad(label, request_id)), &mut in_out)
+            .map_err(|_| ExitDnsError::SealFailed)?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + in_out.len());
+        sealed.extend_from_slice(&nonce_bytes);
+        sealed.extend_from_slice(&in_out);
+        Ok(sealed)
+    }
+
+    fn open(&self, label: &[u8], request_id: u32, sealed: &[u8]) -> Result<Vec<u8>, ExitDnsError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(ExitDnsError::OpenFailed);
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| ExitDnsError::OpenFailed)?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce, Aad::from(aad(label, request_id)), &mut in_out)
+            .map_err(|_| ExitDnsError::OpenFailed)?
+            .len();
+        in_out.truncate(plaintext_len);
+        Ok(in_out)
+    }
+}
+
+fn aad(label: &[u8], request_id: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(label.len() + 4);
+    aad.extend_from_slice(label);
+    aad.extend_from_slice(&request_id.to_be_bytes());
+    aad
+}
+
+/// Client side: seals hostnames and opens IP lists.
+pub struct ExitDnsClient {
+    key: DnsKey,
+    next_request_id: u32,
+}
+
+impl ExitDnsClient {
+    pub fn new(dns_encryption_key: &[u8; 32]) -> Self {
+        Self {
+            key: DnsKey::new(dns_encryption_key),
+            next_request_id: OsRng.next_u32(),
+        }
+    }
+
+    pub fn seal_request(&mut self, hostname: &str) -> Result<PayloadMessage, ExitDnsError> {
+        if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+            return Err(ExitDnsError::InvalidHostname);
+        }
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        let encrypted_hostname = self.key.seal(AAD_REQUEST, request_id, hostname.as_bytes())?;
+        Ok(PayloadMessage::DnsRequest {
+            encrypted_hostname,
+            request_id,
+        })
+    }
+
+    pub fn open_response(&self, request_id: u32, response: &PayloadMessage) -> Result<Vec<IpAddr>, ExitDnsError> {
+        match response {
+            PayloadMessage::DnsResponse {
+                encrypted_ip_list,
+                request_id: response_id,
+            } if *response_id == request_id => {
+                let plaintext = self.key.open(AAD_RESPONSE, request_id, encrypted_ip_list)?;
+                decode_ip_list(&plaintext)
+            }
+            _ => Err(ExitDnsError::Malformed),
+        }
+    }
+}
+
+/// Exit side: opens hostnames, resolves them in the exit zone and seals the answer.
+pub struct ExitDnsService {
+    key: DnsKey,
+    resolver: ExitZoneDnsResolver,
+}
+
+impl std::fmt::Debug for ExitDnsService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExitDnsService").finish_non_exhaustive()
+    }
+}
+
+impl ExitDnsService {
+    pub fn new(dns_encryption_key: &[u8; 32]) -> Result<Self, ExitDnsError> {
+        Ok(Self {
+            key: DnsKey::new(dns_encryption_key),
+            resolver: ExitZoneDnsResolver::new().map_err(|_| ExitDnsError::ResolutionFailed)?,
+        })
+    }
+
+    pub fn open_request(&self, request: &PayloadMessage) -> Result<(u32, String), ExitDnsError> {
+        match request {
+            PayloadMessage::DnsRequest {
+                encrypted_hostname,
+                request_id,
+            } => {
+                let plaintext = self.key.open(AAD_REQUEST, *request_id, encrypted_hostname)?;
+                let hostname = String::from_utf8(plaintext).map_err(|_| ExitDnsError::InvalidHostname)?;
+                if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+                    return Err(ExitDnsError::InvalidHostname);
+                }
+                Ok((*request_id, hostname))
+            }
+            _ => Err(ExitDnsError::Malformed),
+        }
+    }
+
+    pub fn seal_response(&self, request_id: u32, ips: &[IpAddr]) -> Result<PayloadMessage, ExitDnsError> {
+        let encrypted_ip_list = self.key.seal(AAD_RESPONSE, request_id, &encode_ip_list(ips))?;
+        Ok(PayloadMessage::DnsResponse {
+            encrypted_ip_list,
+            request_id,
+        })
+    }
+
+    pub async fn handle_request(&self, request: &PayloadMessage) -> Result<PayloadMessage, ExitDnsError> {
+        let (request_id, hostname) = self.open_request(request)?;
+        let ips = self
+            .resolver
+            .resolve_hostname(&hostname)
+            .await
+            .map_err(|_| ExitDnsError::ResolutionFailed)?;
+        self.seal_response(request_id, &ips)
+    }
+}
+
+/// Carries sealed DNS messages from the client to the exit and back.
+#[async_trait]
+pub trait ExitDnsExchange: Send + Sync {
+    async fn exchange(&self, request: PayloadMessage) -> Result<PayloadMessage, ExitDnsError>;
+}
+
+/// Client-side resolver used in relay mode in place of client-side DoH.
+pub struct ExitDnsResolver<E: ExitDnsExchange> {
+    client: std::sync::Mutex<ExitDnsClient>,
+    exchange: E,
+}
+
+impl<E: ExitDnsExchange> ExitDnsResolver<E> {
+    pub fn new(dns_encryption_key: &[u8; 32], exchange: E) -> Self {
+        Self {
+            client: std::sync::Mutex::new(ExitDnsClient::new(dns_encryption_key)),
+            exchange,
+        }
+    }
+
+    pub async fn resolve_via_exit(&self, hostname: &str) -> Result<Vec<IpAddr>, ExitDnsError> {
+        let request = {
+            let mut client = self.client.lock().map_err(|_| ExitDnsError::SealFailed)?;
+            client.seal_request(hostname)?
+        };
+        let request_id = match &request {
+            PayloadMessage::DnsRequest { request_id, .. } => *request_id,
+            _ => return Err(ExitDnsError::Malformed),
+        };
+        let response = self.exchange.exchange(request).await?;
+        let client = self.client.lock().map_err(|_| ExitDnsError::OpenFailed)?;
+        client.open_response(request_id, &response)
+    }
+}
+
+impl<E: ExitDnsExchange> DnsResolver for ExitDnsResolver<E> {
+    async fn resolve(&self, hostname: &str) -> Result<Vec<IpAddr>, DnsError> {
+        let hostname = canonical_hostname(hostname)?;
+        match self.resolve_via_exit(&hostname).await {
+            Ok(ips) if !ips.is_empty() => Ok(ips),
+            _ => Err(DnsError::ResolutionFailed),
+        }
+    }
+}
+
+pub fn encode_message(message: &PayloadMessage) -> Result<Vec<u8>, ExitDnsError> {
+    let (kind, request_id, body) = match message {
+        PayloadMessage::DnsRequest {
+            encrypted_hostname,
+            request_id,
+        } => (MSG_DNS_REQUEST, *request_id, encrypted_hostname),
+        PayloadMessage::DnsResponse {
+            encrypted_ip_list,
+            request_id,
+        } => (MSG_DNS_RESPONSE, *request_id, encrypted_ip_list),
+        PayloadMessage::TunnelData { .. } => return Err(ExitDnsError::Malformed),
+    };
+    let mut buf = Vec::with_capacity(5 + body.len());
+    buf.push(kind);
+    buf.extend_from_slice(&request_id.to_be_bytes());
+    buf.extend_from_slice(body);
+    Ok(buf)
+}
+
+pub fn decode_message(payload: &[u8]) -> Result<PayloadMessage, ExitDnsError> {
+    if payload.len() < 5 {
+        return Err(ExitDnsError::Malformed);
+    }
+    let request_id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+    let body = payload[5..].to_vec();
+    match payload[0] {
+        MSG_DNS_REQUEST => Ok(PayloadMessage::DnsRequest {
+            encrypted_hostname: body,
+            request_id,
+        }),
+        MSG_DNS_RESPONSE => Ok(PayloadMessage::DnsResponse {
+            encrypted_ip_list: body,
+            request_id,
+        }),
+        _ => Err(ExitDnsError::Malformed),
+    }
+}
+
+fn encode_ip_list(ips: &[IpAddr]) -> Vec<u8> {
+    let ips = &ips[..ips.len().min(MAX_IPS)];
+    let mut buf = Vec::with_capacity(1 + ips.len() * 17);
+    buf.push(ips.len() as u8);
+    for ip in ips {
+        match ip {
+            IpAddr::V4(v4) => {
+                buf.push(4);
+                buf.extend_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                buf.push(6);
+                buf.extend_from_slice(&v6.octets());
+            }
+        }
+    }
+    buf
+}
+
+fn decode_ip_list(buf: &[u8]) -> Result<Vec<IpAddr>, ExitDnsError> {
+    let (&count, mut rest) = buf.split_first().ok_or(ExitDnsError::Malformed)?;
+    if count as usize > MAX_IPS {
+        return Err(ExitDnsError::Malformed);
+    }
+    let mut ips = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (&family, tail) = rest.split_first().ok_or(ExitDnsError::Malformed)?;
+        match family {
+            4 if tail.len() >= 4 => {
+                let octets: [u8; 4] = tail[..4].try_into().map_err(|_| ExitDnsError::Malformed)?;
+                ips.push(IpAddr::V4(Ipv4Addr::from(octets)));
+                rest = &tail[4..];
+            }
+            6 if tail.len() >= 16 => {
+                let octets: [u8; 16] = tail[..16].try_into().map_err(|_| ExitDnsError::Malformed)?;
+                ips.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                rest = &tail[16..];
+            }
+            _ => return Err(ExitDnsError::Malformed),
+        }
+    }
+    if !rest.is_empty() {
+        return Err(ExitDnsError::Malformed);
+    }
+    Ok(ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DNS_KEY: [u8; 32] = [0x5a; 32];
+
+    struct LoopbackExchange {
+        service: ExitDnsService,
+        answer: Vec<IpAddr>,
+    }
+
+    #[async_trait]
+    impl ExitDnsExchange for LoopbackExchange {
+        async fn exchange(&self, request: PayloadMessage) -> Result<PayloadMessage, ExitDnsError> {
+            let wire = encode_message(&request)?;
+            let request = decode_message(&wire)?;
+            let (request_id, hostname) = self.service.open_request(&request)?;
+            assert_eq!(hostname, "example.com");
+            self.service.seal_response(request_id, &self.answer)
+        }
+    }
+
+    #[test]
+    fn hostname_is_not_visible_in_sealed_request() {
+        let mut client = ExitDnsClient::new(&DNS_KEY);
+        let request = client.seal_request("secret-destination.example").unwrap();
+        let wire = encode_message(&request).unwrap();
+        assert!(!wire
+            .windows(b"secret-destination".len())
+            .any(|w| w == b"secret-destination"));
+    }
+
+    #[test]
+    fn wrong_key_cannot_open_request() {
+        let mut client = ExitDnsClient::new(&DNS_KEY);
+        let request = client.seal_request("example.com").unwrap();
+        let other_exit = ExitDnsService::new(&[0x11; 32]).unwrap();
+        assert_eq!(other_exit.open_request(&request), Err(ExitDnsError::OpenFailed));
+    }
+
+    #[test]
+    fn response_bound_to_request_id() {
+        let mut client = ExitDnsClient::new(&DNS_KEY);
+        let service = ExitDnsService::new(&DNS_KEY).unwrap();
+        let request = client.seal_request("example.com").unwrap();
+        let (request_id, _) = service.open_request(&request).unwrap();
+        let response = service
+            .seal_response(request_id, &[IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))])
+            .unwrap();
+
+        assert!(client.open_response(request_id.wrapping_add(1), &response).is_err());
+        assert_eq!(
+            client.open_response(request_id, &response).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]
+        );
+    }
+
+    #[tokio::test]
+    async fn resolver_round_trip_through_exit() {
+        let answer = vec![
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        let resolver = ExitDnsResolver::new(
+            &DNS_KEY,
+            LoopbackExchange {
+                service: ExitDnsService::new(&DNS_KEY).unwrap(),
+                answer: answer.clone(),
+            },
+        );
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), answer);
+    }
+}
@@ -0,0 +1,337 @@
+//! What an exit relay agrees to connect to. Targets are resolved here at
+//! the exit, never by the client or earlier hops, and every resolved
+//! address is checked, so a hostname cannot smuggle a dial into a denied
+//! network. Refusals are typed `OpenReject` codes sent back to the client.
+//!
+//! An exit may also cap how often it connects to any one destination,
+//! across all of its sessions, so clients cannot use it to hammer a site.
+//! The hostname is charged before it is resolved and each address after.
+//!
+//! With a DNS key the exit also answers lookups sealed to it (`exit_dns`),
+//! listing only the addresses the policy would dial, so clients can open
+//! by address and keep the name out of `Open`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::ConfigError;
+use crate::content_policy_bootstrap::ClientSubnet;
+use crate::exit_dns::ExitDnsService;
+use crate::memory_profile::MemoryProfile;
+use crate::relay_protocol::OpenReject;
+
+/// Loopback, private, shared, link-local and unspecified space: an exit
+/// must not become a way into its own network.
+pub const LOCAL_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+#[derive(Debug, Clone)]
+pub struct ExitPolicy {
+    /// Empty allows every port.
+    pub allowed_ports: Vec<RangeInclusive<u16>>,
+    pub denied_networks: Vec<ClientSubnet>,
+    /// Bytes per second a client session may relay, both directions together.
+    pub bandwidth_cap: Option<u64>,
+    /// Connections per minute to any one destination, shared by every
+    /// session under this policy.
+    pub destination_opens: Option<Arc<DestinationLimiter>>,
+    /// Opens and answers sealed lookups; `None` leaves `CAP_EXIT_DNS` off.
+    pub lookups: Option<Arc<ExitDnsService>>,
+}
+
+impl Default for ExitPolicy {
+    /// Any port, no cap, nothing in `LOCAL_NETWORKS`.
+    fn default() -> Self {
+        Self {
+            allowed_ports: Vec::new(),
+            denied_networks: LOCAL_NETWORKS
+                .iter()
+                .map(|cidr| ClientSubnet::parse(cidr).expect("valid built-in CIDR"))
+                .collect(),
+            bandwidth_cap: None,
+            destination_opens: None,
+            lookups: None,
+        }
+    }
+}
+
+impl ExitPolicy {
+    /// Allows everything; for relays inside a private deployment.
+    pub fn permissive() -> Self {
+        Self {
+            denied_networks: Vec::new(),
+            ..Self::default()
+        }
+    }
+
+    pub fn check_port(&self, port: u16) -> Result<(), OpenReject> {
+        if self.allowed_ports.is_empty() || self.allowed_ports.iter().any(|range| range.contains(&port)) {
+            Ok(())
+        } else {
+            Err(OpenReject::PortNotAllowed)
+        }
+    }
+
+    pub fn check_addr(&self, addr: IpAddr) -> Result<(), OpenReject> {
+        if self.denied_networks.iter().any(|network| network.contains(addr)) {
+            Err(OpenReject::AddressDenied)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The addresses of `host` the policy allows, for a sealed lookup.
+    /// Nothing is charged until one of them is opened.
+    pub async fn lookup(&self, host: &str) -> Vec<IpAddr> {
+        match tokio::net::lookup_host((host, 0)).await {
+            Ok(resolved) => resolved
+                .map(|addr| addr.ip())
+                .filter(|ip| self.check_addr(*ip).is_ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Resolves `host` and keeps the addresses the policy allows.
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, OpenReject> {
+        self.check_port(port)?;
+        if let Some(limiter) = &self.destination_opens {
+            // A literal address is charged once, below.
+            if host.parse::<IpAddr>().is_err() {
+                limiter.admit(&host.trim_end_matches('.').to_ascii_lowercase())?;
+            }
+        }
+        let resolved: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| OpenReject::ResolveFailed)?
+            .collect();
+        if resolved.is_empty() {
+            return Err(OpenReject::ResolveFailed);
+        }
+        let allowed: Vec<SocketAddr> = resolved
+            .into_iter()
+            .filter(|addr| self.check_addr(addr.ip()).is_ok())
+            .collect();
+        if allowed.is_empty() {
+            return Err(OpenReject::AddressDenied);
+        }
+        let Some(limiter) = &self.destination_opens else {
+            return Ok(allowed);
+        };
+        let admitted: Vec<SocketAddr> = allowed
+            .into_iter()
+            .filter(|addr| limiter.admit(&addr.ip().to_string()).is_ok())
+            .collect();
+        if admitted.is_empty() {
+            return Err(OpenReject::DestinationRateLimited);
+        }
+        Ok(admitted)
+    }
+}
+
+/// `80,443,8000-8999`.
+pub fn parse_port_ranges(spec: &str) -> Result<Vec<RangeInclusive<u16>>, ConfigError> {
+    let invalid = || ConfigError::Invalid {
+        field: "--exit-ports".to_string(),
+        reason: format!("`{}` is not a list of ports or port ranges", spec),
+    };
+    spec.split(',')
+        .map(|part| {
+            let part = part.trim();
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+            let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+pub fn parse_denied_network(cidr: &str) -> Result<ClientSubnet, ConfigError> {
+    ClientSubnet::parse(cidr).map_err(|e| ConfigError::Invalid {
+        field: "--exit-deny".to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Token bucket over a session's relayed bytes, holding up to one second
+/// of traffic. Charges may overdraw it; the caller waits out the debt.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            bucket: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` and returns how long to pause before relaying more.
+    pub fn charge(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let level = self.refill(&mut bucket) - bytes as f64;
+        bucket.0 = level;
+        if level >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-level / self.bytes_per_sec as f64)
+        }
+    }
+
+    /// The bucket is overdrawn, so new connections would only queue.
+    pub fn saturated(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        self.refill(&mut bucket) < 0.0
+    }
+
+    fn refill(&self, bucket: &mut (f64, Instant)) -> f64 {
+        let now = Instant::now();
+        let earned = now.duration_since(bucket.1).as_secs_f64() * self.bytes_per_sec as f64;
+        bucket.0 = (bucket.0 + earned).min(self.bytes_per_sec as f64);
+        bucket.1 = now;
+        bucket.0
+    }
+}
+
+/// Most destinations tracked before idle ones are forgotten.
+const MAX_TRACKED_DESTINATIONS: usize = MemoryProfile::active().exit_tracked_destinations;
+
+/// A token bucket per destination, each holding a minute of opens.
+#[derive(Debug)]
+pub struct DestinationLimiter {
+    opens_per_minute: u32,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl DestinationLimiter {
+    pub fn new(opens_per_minute: u32) -> Self {
+        Self {
+            opens_per_minute: opens_per_minute.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one open toward `destination`, a hostname or an address.
+    pub fn admit(&self, destination: &str) -> Result<(), OpenReject> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_DESTINATIONS && !buckets.contains_key(destination) {
+            // A full bucket is indistinguishable from a fresh one.
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.opens_per_minute as f64);
+        }
+        let bucket = buckets
+            .entry(destination.to_string())
+            .or_insert((self.opens_per_minute as f64, now));
+        if self.refill(bucket, now) < 1.0 {
+            return Err(OpenReject::DestinationRateLimited);
+        }
+        bucket.0 -= 1.0;
+        Ok(())
+    }
+
+    fn refill(&self, bucket: &mut (f64, Instant), now: Instant) -> f64 {
+        let per_sec = self.opens_per_minute as f64 / 60.0;
+        let earned = now.duration_since(bucket.1).as_secs_f64() * per_sec;
+        bucket.0 = (bucket.0 + earned).min(self.opens_per_minute as f64);
+        bucket.1 = now;
+        bucket.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy_denies_local_targets() {
+        let policy = ExitPolicy::default();
+        for local in ["127.0.0.1", "10.1.2.3", "192.168.1.1", "::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert_eq!(policy.check_addr(local.parse().unwrap()), Err(OpenReject::AddressDenied), "{local}");
+        }
+        assert!(policy.check_addr("93.184.216.34".parse().unwrap()).is_ok());
+        assert!(ExitPolicy::permissive().check_addr("127.0.0.1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn port_ranges_parse_and_apply() {
+        let policy = ExitPolicy {
+            allowed_ports: parse_port_ranges("80, 443,8000-8999").unwrap(),
+            ..ExitPolicy::default()
+        };
+        assert!(policy.check_port(443).is_ok());
+        assert!(policy.check_port(8500).is_ok());
+        assert_eq!(policy.check_port(25), Err(OpenReject::PortNotAllowed));
+        assert!(parse_port_ranges("9000-8000").is_err());
+        assert!(parse_port_ranges("http").is_err());
+    }
+
+    #[tokio::test]
+    async fn resolved_addresses_are_checked() {
+        let policy = ExitPolicy::default();
+        assert_eq!(policy.resolve("localhost", 80).await, Err(OpenReject::AddressDenied));
+        let addrs = ExitPolicy::permissive().resolve("127.0.0.1", 80).await.unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:80".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn destinations_are_limited_by_name_and_address() {
+        let policy = ExitPolicy {
+            destination_opens: Some(Arc::new(DestinationLimiter::new(2))),
+            ..ExitPolicy::permissive()
+        };
+        let shared = policy.clone();
+        assert!(policy.resolve("127.0.0.1", 80).await.is_ok());
+        assert!(shared.resolve("127.0.0.1", 443).await.is_ok());
+        assert_eq!(policy.resolve("127.0.0.1", 80).await, Err(OpenReject::DestinationRateLimited));
+        // Other destinations keep their own budget; names are charged before lookup.
+        assert!(policy.resolve("127.0.0.2", 80).await.is_ok());
+        let limiter = policy.destination_opens.as_ref().unwrap();
+        assert!(limiter.admit("victim.example").is_ok());
+        assert!(limiter.admit("victim.example").is_ok());
+        assert_eq!(policy.resolve("Victim.Example.", 80).await, Err(OpenReject::DestinationRateLimited));
+    }
+
+    #[test]
+    fn destination_budgets_refill_over_the_minute() {
+        let limiter = DestinationLimiter::new(600);
+        for _ in 0..600 {
+            assert!(limiter.admit("203.0.113.9").is_ok());
+        }
+        assert_eq!(limiter.admit("203.0.113.9"), Err(OpenReject::DestinationRateLimited));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(limiter.admit("203.0.113.9").is_ok());
+    }
+
+    #[test]
+    fn limiter_overdraws_then_recovers() {
+        let limiter = BandwidthLimiter::new(10_000);
+        assert_eq!(limiter.charge(6_000), Duration::ZERO);
+        let pause = limiter.charge(9_000);
+        assert!(pause > Duration::from_millis(400) && pause <= Duration::from_millis(500), "{pause:?}");
+        assert!(limiter.saturated());
+        std::thread::sleep(pause + Duration::from_millis(50));
+        assert!(!limiter.saturated());
+    }
+}
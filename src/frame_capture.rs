@@ -0,0 +1,434 @@
+//! Frame-header capture, for debugging flow-control stalls and framing
+//! bugs in the field. OBS_DEV builds only: with `EBT_FRAME_CAPTURE` set to
+//! a host, or `host:port`, every relay connection opened to it appends to
+//! the file named by `EBT_FRAME_CAPTURE_FILE` a header line and then one
+//! line per frame either way: microseconds since the Open, direction,
+//! frame type and payload length, and for control, data and datagram
+//! frames the connection id, opcode and credits or code. Payloads are
+//! never written, nor is the Open's target.
+//!
+//! ```text
+//! # open v1 window=65536 max_window=131072
+//! 0 out control 21 op=open conn=1
+//! 48210 in control 9 op=window_update conn=1 credits=0
+//! 48511 out data 16388 conn=1
+//! ```
+//!
+//! `ebt capture analyze` reads a capture back offline; see `analyze`.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::core::observability::OBS_DEV;
+use crate::relay_protocol::{ControlOpcode, FrameType, ProtocolVersion};
+
+const TARGET_VAR: &str = "EBT_FRAME_CAPTURE";
+const FILE_VAR: &str = "EBT_FRAME_CAPTURE_FILE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Outbound,
+    Inbound,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Outbound => "out",
+            Direction::Inbound => "in",
+        }
+    }
+}
+
+/// Capture of one relay connection; records nothing unless selected.
+#[derive(Debug, Default)]
+pub struct FrameCapture {
+    sink: Option<Mutex<CaptureSink>>,
+}
+
+#[derive(Debug)]
+struct CaptureSink {
+    file: File,
+    opened: Instant,
+}
+
+/// `(target, file)` from the environment, read once.
+fn armed() -> Option<&'static (String, String)> {
+    static ARMED: OnceLock<Option<(String, String)>> = OnceLock::new();
+    ARMED
+        .get_or_init(|| Some((std::env::var(TARGET_VAR).ok()?, std::env::var(FILE_VAR).ok()?)))
+        .as_ref()
+}
+
+fn target_matches(target: &str, host: &str, port: u16) -> bool {
+    match target.rsplit_once(':') {
+        Some((target_host, target_port)) if target_port.parse() == Ok(port) => {
+            target_host.eq_ignore_ascii_case(host)
+        }
+        Some(_) => false,
+        None => target.eq_ignore_ascii_case(host),
+    }
+}
+
+impl FrameCapture {
+    /// The capture of a connection about to be opened to `host:port`,
+    /// which records only in OBS_DEV builds with the capture armed for it.
+    /// The window limits are the client's, for the analyzer.
+    pub fn select(host: &str, port: u16, version: ProtocolVersion, initial_window: u32, max_window: u32) -> Self {
+        if !OBS_DEV {
+            return Self::default();
+        }
+        match armed() {
+            Some((target, path)) if target_matches(target, host, port) => {
+                // A capture that cannot be written must not fail the connection
+                Self::to_file(path, version, initial_window, max_window).unwrap_or_default()
+            }
+            _ => Self::default(),
+        }
+    }
+
+    /// Appends this connection's capture to `path`.
+    pub fn to_file(
+        path: &str,
+        version: ProtocolVersion,
+        initial_window: u32,
+        max_window: u32,
+    ) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "# open v{} window={} max_window={}", version, initial_window, max_window)?;
+        Ok(Self {
+            sink: Some(Mutex::new(CaptureSink {
+                file,
+                opened: Instant::now(),
+            })),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Writes the header of a frame with `payload`; the payload itself is
+    /// only read for the fields above.
+    pub fn record(&self, direction: Direction, frame_type: FrameType, payload: &[u8]) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let Ok(mut sink) = sink.lock() else {
+            return;
+        };
+        let line = format!(
+            "{} {} {} {}{}\n",
+            sink.opened.elapsed().as_micros(),
+            direction.as_str(),
+            frame_type_name(frame_type),
+            payload.len(),
+            header_fields(frame_type, payload)
+        );
+        // Unbuffered, so a capture survives the crash it is meant to explain
+        let _ = sink.file.write_all(line.as_bytes());
+    }
+}
+
+fn frame_type_name(frame_type: FrameType) -> &'static str {
+    match frame_type {
+        FrameType::Control => "control",
+        FrameType::Data => "data",
+        FrameType::Padding => "padding",
+        FrameType::Ping => "ping",
+        FrameType::Pong => "pong",
+        FrameType::Onion => "onion",
+        FrameType::Circuit => "circuit",
+        FrameType::Datagram => "datagram",
+        FrameType::Dns => "dns",
+    }
+}
+
+fn be32(payload: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(payload.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn header_fields(frame_type: FrameType, payload: &[u8]) -> String {
+    let conn = |at| be32(payload, at).map_or(String::new(), |id| format!(" conn={}", id));
+    match frame_type {
+        FrameType::Data | FrameType::Datagram => conn(0),
+        FrameType::Control => {
+            let Some(&opcode) = payload.first() else {
+                return String::new();
+            };
+            let byte = |at: usize| payload.get(at).map_or(String::new(), |b| b.to_string());
+            match opcode {
+                x if x == ControlOpcode::Hello as u8 => " op=hello".to_string(),
+                x if x == ControlOpcode::Open as u8 => format!(" op=open{}", conn(1)),
+                x if x == ControlOpcode::Close as u8 => format!(" op=close{} reason={}", conn(1), byte(5)),
+                x if x == ControlOpcode::WindowUpdate as u8 => format!(
+                    " op=window_update{} credits={}",
+                    conn(1),
+                    be32(payload, 5).map_or(String::new(), |credits| credits.to_string())
+                ),
+                x if x == ControlOpcode::Error as u8 => format!(" op=error{} code={}", conn(1), byte(5)),
+                x if x == ControlOpcode::GoAway as u8 => format!(" op=go_away code={}", byte(1)),
+                x if x == ControlOpcode::Resume as u8 => " op=resume".to_string(),
+                other => format!(" op={:#04x}", other),
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+/// What one captured connection did, as read back by `analyze`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConnectionAnalysis {
+    pub frames_out: u64,
+    pub frames_in: u64,
+    /// Data frame payloads less their connection id.
+    pub data_bytes_out: u64,
+    pub data_bytes_in: u64,
+    /// Times the send window ran out before the relay granted more.
+    pub window_stalls: u64,
+    pub longest_stall: Duration,
+    pub longest_gap: Duration,
+    /// Framing and flow-control violations, with their line numbers.
+    pub problems: Vec<String>,
+}
+
+struct ConnectionState {
+    analysis: ConnectionAnalysis,
+    initial_window: u64,
+    max_window: u64,
+    /// Unknown until the relay acknowledges the Open.
+    window: Option<u64>,
+    stalled_since: Option<u64>,
+    last_at: u64,
+    closed: bool,
+}
+
+impl ConnectionState {
+    fn from_header(header: &str) -> Option<Self> {
+        let field = |name: &str| {
+            header
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))?
+                .parse::<u64>()
+                .ok()
+        };
+        Some(Self {
+            analysis: ConnectionAnalysis::default(),
+            initial_window: field("window")?,
+            max_window: field("max_window")?,
+            window: None,
+            stalled_since: None,
+            last_at: 0,
+            closed: false,
+        })
+    }
+
+    fn frame(&mut self, number: usize, line: &str) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields.as_slice() {
+            [at, direction, frame_type, len, ..] => at
+                .parse::<u64>()
+                .ok()
+                .zip(len.parse::<u64>().ok())
+                .map(|(at, len)| (at, *direction, *frame_type, len)),
+            _ => None,
+        };
+        let Some((at, direction, frame_type, len)) = parsed else {
+            self.problem(number, "unreadable frame line".to_string());
+            return;
+        };
+        let named = |name: &str| fields.iter().find_map(|field| field.strip_prefix(name)?.strip_prefix('='));
+        if at < self.last_at {
+            self.problem(number, "time went backwards".to_string());
+        }
+        let gap = Duration::from_micros(at.saturating_sub(self.last_at));
+        self.analysis.longest_gap = self.analysis.longest_gap.max(gap);
+        self.last_at = self.last_at.max(at);
+        if self.closed {
+            self.problem(number, format!("{} frame after Close", frame_type));
+        }
+
+        match (direction, frame_type) {
+            ("out", "data") => {
+                self.analysis.frames_out += 1;
+                let bytes = self.data_bytes(number, len);
+                self.analysis.data_bytes_out += bytes;
+                match self.window {
+                    None => self.problem(number, "data sent before the Open was acknowledged".to_string()),
+                    Some(window) if bytes > window => {
+                        self.problem(number, format!("sent {} bytes with {} left in the window", bytes, window));
+                        self.window = Some(0);
+                    }
+                    Some(window) => self.window = Some(window - bytes),
+                }
+                if self.window == Some(0) && self.stalled_since.is_none() {
+                    self.analysis.window_stalls += 1;
+                    self.stalled_since = Some(at);
+                }
+            }
+            ("in", "data") => {
+                self.analysis.frames_in += 1;
+                self.analysis.data_bytes_in += self.data_bytes(number, len);
+            }
+            (direction, _) => {
+                if direction == "out" {
+                    self.analysis.frames_out += 1;
+                } else {
+                    self.analysis.frames_in += 1;
+                }
+                match (direction, named("op")) {
+                    ("in", Some("window_update")) => {
+                        let credits = named("credits").and_then(|c| c.parse::<u64>().ok()).unwrap_or(0);
+                        let window = self.window.unwrap_or(self.initial_window);
+                        self.window = Some((window + credits).min(self.max_window));
+                        if let Some(since) = self.stalled_since.filter(|_| credits > 0) {
+                            let stall = Duration::from_micros(at.saturating_sub(since));
+                            self.analysis.longest_stall = self.analysis.longest_stall.max(stall);
+                            self.stalled_since = None;
+                        }
+                    }
+                    (_, Some("close")) => self.closed = true,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn data_bytes(&mut self, number: usize, len: u64) -> u64 {
+        if len < 4 {
+            self.problem(number, "data frame shorter than its connection id".to_string());
+        }
+        len.saturating_sub(4)
+    }
+
+    fn problem(&mut self, number: usize, problem: String) {
+        self.analysis.problems.push(format!("line {}: {}", number, problem));
+    }
+
+    fn finish(mut self) -> ConnectionAnalysis {
+        if let Some(since) = self.stalled_since {
+            let stall = Duration::from_micros(self.last_at.saturating_sub(since));
+            self.analysis.longest_stall = self.analysis.longest_stall.max(stall);
+            self.analysis.problems.push("capture ends with the send window exhausted".to_string());
+        }
+        self.analysis
+    }
+}
+
+/// Reads back a capture file, one analysis per captured connection in
+/// the order they were opened. Lines before the first header are reported
+/// on the first connection.
+pub fn analyze(capture: &str) -> Vec<ConnectionAnalysis> {
+    let mut connections = Vec::new();
+    let mut current: Option<ConnectionState> = None;
+    let mut orphans = Vec::new();
+    for (idx, line) in capture.lines().enumerate() {
+        let number = idx + 1;
+        if let Some(header) = line.strip_prefix("# open") {
+            connections.extend(current.take().map(ConnectionState::finish));
+            current = ConnectionState::from_header(header);
+            if current.is_none() {
+                orphans.push(format!("line {}: unreadable header", number));
+            }
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match current.as_mut() {
+            Some(connection) => connection.frame(number, line),
+            None => orphans.push(format!("line {}: frame outside a connection", number)),
+        }
+    }
+    connections.extend(current.map(ConnectionState::finish));
+    if !orphans.is_empty() {
+        if connections.is_empty() {
+            connections.push(ConnectionAnalysis::default());
+        }
+        let first = &mut connections[0].problems;
+        orphans.append(first);
+        *first = orphans;
+    }
+    connections
+}
+
+impl fmt::Display for ConnectionAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  frames: {} out, {} in; data bytes: {} out, {} in",
+            self.frames_out, self.frames_in, self.data_bytes_out, self.data_bytes_in
+        )?;
+        writeln!(
+            f,
+            "  window stalls: {}, longest {:?}; longest gap between frames {:?}",
+            self.window_stalls, self.longest_stall, self.longest_gap
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(deprecated)]
+
+    use super::*;
+    use crate::relay_protocol::{LegacyControlMessage, LegacyDataFrame};
+
+    #[test]
+    fn captures_headers_only_and_analyzes_window_stalls() {
+        let path = std::env::temp_dir().join(format!("ebt-frame-capture-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let capture = FrameCapture::to_file(path, 1, 8, 16).unwrap();
+        let control = |message: LegacyControlMessage| message.encode();
+        let data = |bytes: usize| LegacyDataFrame::new(1, vec![0x5a; bytes]).encode();
+        let open = LegacyControlMessage::Open {
+            conn_id: 1,
+            target_host: "secret.example".to_string(),
+            target_port: 443,
+        };
+        capture.record(Direction::Outbound, FrameType::Control, &control(open));
+        let acknowledged = LegacyControlMessage::WindowUpdate { conn_id: 1, credits: 0 };
+        capture.record(Direction::Inbound, FrameType::Control, &control(acknowledged));
+        capture.record(Direction::Outbound, FrameType::Data, &data(8));
+        let granted = LegacyControlMessage::WindowUpdate { conn_id: 1, credits: 4 };
+        capture.record(Direction::Inbound, FrameType::Control, &control(granted));
+        capture.record(Direction::Outbound, FrameType::Data, &data(6));
+        capture.record(Direction::Inbound, FrameType::Data, &data(100));
+        capture.record(Direction::Inbound, FrameType::Control, &control(LegacyControlMessage::Close {
+            conn_id: 1,
+            reason: 0,
+        }));
+        drop(capture);
+
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(!written.contains("secret") && !written.contains("ZZZZ"));
+        assert!(written.starts_with("# open v1 window=8 max_window=16\n"));
+        assert!(written.contains(" in control 9 op=window_update conn=1 credits=4\n"));
+
+        let analyses = analyze(&written);
+        assert_eq!(analyses.len(), 1);
+        let analysis = &analyses[0];
+        assert_eq!((analysis.frames_out, analysis.frames_in), (3, 4));
+        assert_eq!((analysis.data_bytes_out, analysis.data_bytes_in), (14, 100));
+        assert_eq!(analysis.window_stalls, 2);
+        assert_eq!(
+            analysis.problems,
+            vec![
+                "line 6: sent 6 bytes with 4 left in the window".to_string(),
+                "capture ends with the send window exhausted".to_string(),
+            ]
+        );
+
+        let broken = analyze("0 out data 8 conn=1\n# open v1 window=8 max_window=16\nnot a frame\n");
+        assert_eq!(broken[0].problems, vec!["line 1: frame outside a connection", "line 3: unreadable frame line"]);
+    }
+}
@@ -0,0 +1,68 @@
+//! One spelling per hostname. The content policy compares names as
+//! strings and the DNS cache keys on them, so `EXAMPLE.com.` must not slip
+//! past a rule for `example.com`, nor `bücher.example` past one written as
+//! `xn--bcher-kva.example`. Names are mapped as browsers map them (UTS #46:
+//! case folding, Unicode normalization, Punycode) and lose one trailing
+//! dot; IP literals come back in their standard text form.
+
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum HostnameError {
+    #[error("hostname is empty")]
+    Empty,
+    #[error("hostname is not a valid domain name")]
+    Invalid,
+}
+
+/// The lowercase ASCII form of `host`, without a trailing dot.
+pub fn canonicalize(host: &str) -> Result<String, HostnameError> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+    let ascii = idna::domain_to_ascii(host).map_err(|_| HostnameError::Invalid)?;
+    let name = ascii.strip_suffix('.').unwrap_or(&ascii);
+    if name.is_empty() {
+        return Err(HostnameError::Empty);
+    }
+    // UTS #46 leaves ASCII other than letters, digits and `-` to the caller;
+    // `_` stays for service names such as `_sip._tcp`.
+    if !name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')) {
+        return Err(HostnameError::Invalid);
+    }
+    Ok(name.to_string())
+}
+
+/// `canonicalize`, falling back to a lowercase form for names it rejects,
+/// where a name must still be compared rather than refused.
+pub fn canonical_or_lowercase(host: &str) -> String {
+    canonicalize(host).unwrap_or_else(|_| host.trim_end_matches('.').to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_of_one_name_agree() {
+        for spelling in ["example.com", "EXAMPLE.com.", "Example.COM", "example\u{ff0e}com"] {
+            assert_eq!(canonicalize(spelling).unwrap(), "example.com", "{:?}", spelling);
+        }
+        for spelling in ["bücher.example", "BÜCHER.example.", "xn--bcher-kva.example", "XN--BCHER-KVA.example"] {
+            assert_eq!(canonicalize(spelling).unwrap(), "xn--bcher-kva.example", "{:?}", spelling);
+        }
+        assert_eq!(canonicalize("_sip._tcp.example.com").unwrap(), "_sip._tcp.example.com");
+        assert_eq!(canonicalize("2001:DB8:0:0:0:0:0:1").unwrap(), "2001:db8::1");
+        assert_eq!(canonicalize("203.0.113.7").unwrap(), "203.0.113.7");
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_domains() {
+        assert_eq!(canonicalize(""), Err(HostnameError::Empty));
+        assert_eq!(canonicalize("."), Err(HostnameError::Empty));
+        assert_eq!(canonicalize("xn--a.example"), Err(HostnameError::Invalid));
+        assert_eq!(canonicalize("exa mple.com"), Err(HostnameError::Invalid));
+        assert_eq!(canonicalize("example.com&type=TXT"), Err(HostnameError::Invalid));
+        assert_eq!(canonical_or_lowercase("xn--a.EXAMPLE."), "xn--a.example");
+    }
+}
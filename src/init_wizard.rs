@@ -0,0 +1,273 @@
+//! `ebt init`: asks a few questions and writes a commented config file, so
+//! a first run does not start from the TOML reference. Every answer has a
+//! default; the generated file is validated with the regular loader before
+//! it is written.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
+use std::path::Path;
+
+use toml::Value;
+
+use crate::config::{RelayEndpoint, RelayMode};
+use crate::config_file;
+use crate::dns_resolver::DEFAULT_DOH_PROVIDER;
+
+const PREFERRED_PORT: u16 = 8080;
+const DEFAULT_RULES: &str = "https://easylist.to/easylist/easylist.txt";
+
+/// DoH providers offered by the wizard, by IP so that reaching them needs
+/// no system DNS lookup.
+const DOH_CHOICES: [(&str, &str); 2] = [
+    ("Cloudflare", DEFAULT_DOH_PROVIDER),
+    ("Google", "https://8.8.8.8/resolve"),
+];
+
+/// What the user picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answers {
+    pub port: u16,
+    pub doh_provider: String,
+    /// Rules list path or URL; `None` leaves content filtering off.
+    pub rules: Option<String>,
+    pub relays: Vec<RelayEndpoint>,
+}
+
+/// `ebt init [--output <path>] [--force]`.
+pub fn run(output: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    if Path::new(output).exists() && !force {
+        return Err(format!("{} already exists; pass --force to overwrite it", output).into());
+    }
+    let stdin = io::stdin();
+    let answers = ask(&mut stdin.lock(), &mut io::stdout(), free_port())?;
+    let text = render(&answers);
+    config_file::parse(&text, output)?;
+    fs::write(output, text)?;
+    println!("\nWrote {}. Start the proxy with:", output);
+    println!("  ebt run --config {}", output);
+    Ok(())
+}
+
+/// 8080 when it is free, otherwise a port picked by the OS.
+fn free_port() -> u16 {
+    TcpListener::bind(("127.0.0.1", PREFERRED_PORT))
+        .or_else(|_| TcpListener::bind(("127.0.0.1", 0)))
+        .and_then(|listener| listener.local_addr())
+        .map_or(PREFERRED_PORT, |addr| addr.port())
+}
+
+/// Asks every question on `output`, reading answers from `input`. An empty
+/// answer takes the default shown in brackets.
+pub fn ask(input: &mut impl BufRead, output: &mut impl Write, free_port: u16) -> io::Result<Answers> {
+    let mut prompt = Prompt { input, output };
+    writeln!(prompt.output, "Press Enter to accept the default in brackets.\n")?;
+
+    let port = loop {
+        let answer = prompt.line("Local proxy port", &free_port.to_string())?;
+        match answer.parse::<u16>() {
+            Ok(port) if port != 0 => break port,
+            _ => writeln!(prompt.output, "  enter a port between 1 and 65535")?,
+        }
+    };
+
+    writeln!(prompt.output, "DNS-over-HTTPS provider:")?;
+    for (idx, (name, url)) in DOH_CHOICES.iter().enumerate() {
+        writeln!(prompt.output, "  {}) {} ({})", idx + 1, name, url)?;
+    }
+    writeln!(prompt.output, "  or an https:// URL of your own")?;
+    let doh_provider = loop {
+        let answer = prompt.line("Provider", "1")?;
+        if let Some((_, url)) = answer
+            .parse::<usize>()
+            .ok()
+            .and_then(|choice| DOH_CHOICES.get(choice.wrapping_sub(1)))
+        {
+            break url.to_string();
+        }
+        if answer.starts_with("https://") {
+            break answer;
+        }
+        writeln!(prompt.output, "  pick a number from the list or enter an https:// URL")?;
+    };
+
+    let rules = if prompt.yes_no("Block ads and trackers", false)? {
+        Some(prompt.line("Rules list path or URL", DEFAULT_RULES)?)
+    } else {
+        None
+    };
+
+    let relays = match RelayMode::compiled() {
+        RelayMode::Direct => Vec::new(),
+        mode => {
+            let (needed, hint) = match mode {
+                RelayMode::MultiHop => (2..usize::MAX, "two or more relays"),
+                _ => (1..2, "one relay"),
+            };
+            writeln!(prompt.output, "This build sends traffic through {}; enter them in hop order.", hint)?;
+            loop {
+                let answer = prompt.line("Relays, comma separated host:port", "")?;
+                match relay_list(&answer) {
+                    Ok(relays) if needed.contains(&relays.len()) => break relays,
+                    Ok(_) => writeln!(prompt.output, "  enter {}", hint)?,
+                    Err(e) => writeln!(prompt.output, "  {}", e)?,
+                }
+            }
+        }
+    };
+
+    Ok(Answers {
+        port,
+        doh_provider,
+        rules,
+        relays,
+    })
+}
+
+fn relay_list(answer: &str) -> Result<Vec<RelayEndpoint>, String> {
+    answer
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| config_file::parse_relay_address("relay", address).map_err(|e| e.to_string()))
+        .collect()
+}
+
+struct Prompt<'a, R, W> {
+    input: &'a mut R,
+    output: &'a mut W,
+}
+
+impl<R: BufRead, W: Write> Prompt<'_, R, W> {
+    fn line(&mut self, question: &str, default: &str) -> io::Result<String> {
+        if default.is_empty() {
+            write!(self.output, "{}: ", question)?;
+        } else {
+            write!(self.output, "{} [{}]: ", question, default)?;
+        }
+        self.output.flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input closed before setup finished"));
+        }
+        let answer = answer.trim();
+        Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+    }
+
+    fn yes_no(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.line(question, hint)?;
+            if answer == hint {
+                return Ok(default);
+            }
+            match answer.to_ascii_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "  answer y or n")?,
+            }
+        }
+    }
+}
+
+fn quoted(text: &str) -> String {
+    Value::String(text.to_string()).to_string()
+}
+
+/// The config file for `answers`, with comments for the settings users
+/// most often change next.
+pub fn render(answers: &Answers) -> String {
+    let mut out = String::from(
+        "# Generated by `ebt init`. Check edits with `ebt check-config --config <file>`;\n\
+         # `ebt config schema` lists every setting.\n\n",
+    );
+
+    out.push_str("[proxy]\n");
+    out.push_str("# Only this machine can use the proxy. Set an authentication credential\n");
+    out.push_str("# before listening on a LAN address.\n");
+    out.push_str("bind_address = \"127.0.0.1\"\n");
+    out.push_str(&format!("bind_port = {}\n\n", answers.port));
+
+    out.push_str("[dns]\n");
+    out.push_str("# Tunnel destinations are resolved over DNS-over-HTTPS, never the system\n");
+    out.push_str("# resolver. Providers are tried in order.\n");
+    out.push_str(&format!("doh_providers = [{}]\n\n", quoted(&answers.doh_provider)));
+
+    match &answers.rules {
+        Some(rules) => {
+            out.push_str("[policy]\n");
+            out.push_str(&format!("rules = {}\n", quoted(rules)));
+            out.push_str("# Log would-be blocks without blocking anything:\n");
+            out.push_str("# dry_run = true\n");
+        }
+        None => {
+            out.push_str("# Content filtering is off. To block ads and trackers:\n");
+            out.push_str("# [policy]\n");
+            out.push_str(&format!("# rules = {}\n", quoted(DEFAULT_RULES)));
+        }
+    }
+
+    let mode = RelayMode::compiled();
+    if mode != RelayMode::Direct {
+        let endpoints: Vec<String> = answers.relays.iter().map(|relay| quoted(&relay.to_string())).collect();
+        out.push_str("\n[relay]\n");
+        out.push_str("# Relays in hop order. `selection = \"random_per_session\"` draws them\n");
+        out.push_str("# per session instead.\n");
+        out.push_str(&format!("mode = {}\n", quoted(mode.as_str())));
+        out.push_str(&format!("endpoints = [{}]\n", endpoints.join(", ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relays_answer() -> &'static str {
+        match RelayMode::compiled() {
+            RelayMode::Direct => "",
+            RelayMode::SingleHop => "203.0.113.7:443\n",
+            RelayMode::MultiHop => "203.0.113.7:443, relay.example.net:443\n",
+        }
+    }
+
+    fn answer(script: &str) -> Answers {
+        let mut output = Vec::new();
+        ask(&mut script.as_bytes(), &mut output, 8081).unwrap()
+    }
+
+    #[test]
+    fn defaults_produce_a_loadable_config() {
+        let answers = answer(&format!("\n\n\n{}", relays_answer()));
+        assert_eq!(answers.port, 8081);
+        assert_eq!(answers.doh_provider, DEFAULT_DOH_PROVIDER);
+        assert_eq!(answers.rules, None);
+
+        let config = config_file::parse(&render(&answers), "ebt.toml").unwrap();
+        assert_eq!(config.proxy_policy.bind_port, 8081);
+        assert!(!config.proxy_policy.content_policy_enabled);
+        assert_eq!(config.relay.endpoints, answers.relays);
+    }
+
+    #[test]
+    fn invalid_answers_are_asked_again() {
+        let script = format!("0\n9090\n7\nhttp://dns.example\n2\nmaybe\ny\nads.txt\n{}", relays_answer());
+        let answers = answer(&script);
+        assert_eq!(answers.port, 9090);
+        assert_eq!(answers.doh_provider, "https://8.8.8.8/resolve");
+        assert_eq!(answers.rules.as_deref(), Some("ads.txt"));
+
+        let config = config_file::parse(&render(&answers), "ebt.toml").unwrap();
+        assert!(config.proxy_policy.content_policy_enabled);
+        assert_eq!(config.proxy_policy.content_policy_rules.as_deref(), Some("ads.txt"));
+        assert_eq!(config.dns_policy.doh_providers, vec!["https://8.8.8.8/resolve".to_string()]);
+    }
+
+    #[test]
+    fn closed_input_stops_the_wizard() {
+        let mut output = Vec::new();
+        let result = ask(&mut "8080\n".as_bytes(), &mut output, 8080);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
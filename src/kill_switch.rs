@@ -0,0 +1,514 @@
+//! Kill switch: while `ebt run` is up with `proxy.kill_switch = true`, the
+//! host firewall drops every outbound packet except loopback, DHCP and
+//! IPv6 neighbour discovery, and TCP to the configured relays. Traffic
+//! that bypasses the proxy, or any traffic at all after the proxy dies,
+//! goes nowhere instead of leaking onto the network.
+//!
+//! Relays given by hostname are resolved over DoH before the rules go up,
+//! and the DoH providers are then allowed too, so the proxy can keep
+//! resolving them; `refresh` swaps in the addresses of a later lookup.
+//!
+//! Linux gets a dedicated nftables table, replaced as one transaction,
+//! whose output and forward chains both drop by default.
+//! Windows gets WFP filters through Windows Firewall: an allow rule per
+//! relay and outbound blocked by default on every profile. Any enabled
+//! outbound allow rule would still beat that default, so the kill switch
+//! disables the others while engaged; a disabled marker rule per name
+//! records them, so `lift` can enable them again after a crash.
+//!
+//! The rules are removed on a clean exit only. A proxy that crashes or is
+//! killed leaves them in place, which is the point; `ebt kill-switch off`
+//! removes them afterwards.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::RelayEndpoint;
+use crate::dns_resolver::{DnsResolver, DohResolver};
+use crate::event_bus::{self, EbtEvent};
+use crate::os_command::{command, run, run_all, CommandError, Query};
+
+const NFT_TABLE: &str = "inet ebt_kill_switch";
+/// Windows Firewall rule name of every relay allow rule.
+const FIREWALL_RULE: &str = "ebt-kill-switch";
+/// Windows Firewall rule name of the markers whose descriptions name the
+/// outbound allow rules the kill switch disabled.
+const DISABLED_MARKER: &str = "ebt-kill-switch-disabled";
+
+#[derive(Debug, thiserror::Error)]
+pub enum KillSwitchError {
+    #[error("A kill switch is not supported on this platform")]
+    Unsupported,
+    #[error("A kill switch needs at least one relay address to allow")]
+    NoRelays,
+    #[error("Relay {host} did not resolve: {reason}")]
+    Resolve { host: String, reason: String },
+    #[error(transparent)]
+    Command(#[from] CommandError),
+}
+
+/// Firewall rules that allow only the relays, until `release`. Dropping
+/// it leaves them in place.
+#[derive(Debug)]
+#[must_use = "dropping a KillSwitch keeps its rules; call release on exit"]
+pub struct KillSwitch {
+    release: Vec<Vec<String>>,
+}
+
+impl KillSwitch {
+    /// Blocks outbound traffic except to `relays`, replacing the rules of
+    /// a kill switch that was never released.
+    pub fn engage(relays: &[SocketAddr]) -> Result<Self, KillSwitchError> {
+        if relays.is_empty() {
+            return Err(KillSwitchError::NoRelays);
+        }
+        let platform = Platform::current().ok_or(KillSwitchError::Unsupported)?;
+        run_all(&platform.lift(&run))?;
+        let plan = platform.plan(relays, &run)?;
+        for command in &plan.engage {
+            if let Err(e) = run(command) {
+                let _ = run_all(&plan.release);
+                return Err(e.into());
+            }
+        }
+        event_bus::publish(EbtEvent::KillSwitchEngaged { relays: relays.len() });
+        Ok(Self { release: plan.release })
+    }
+
+    /// Allows `relays` instead of the addresses allowed so far; outbound
+    /// stays blocked throughout.
+    pub fn refresh(&self, relays: &[SocketAddr]) -> Result<(), KillSwitchError> {
+        if relays.is_empty() {
+            return Err(KillSwitchError::NoRelays);
+        }
+        let platform = Platform::current().ok_or(KillSwitchError::Unsupported)?;
+        Ok(run_all(&platform.refresh(relays))?)
+    }
+
+    /// Removes the rules, attempting every step.
+    pub fn release(self) -> Result<(), KillSwitchError> {
+        Ok(run_all(&self.release)?)
+    }
+}
+
+/// What the kill switch lets through for `relays`: each relay's address,
+/// every address its hostname resolves to over DoH, and, when any relay
+/// has a hostname, the `doh_providers` given by IP address.
+pub async fn allowed_addresses(
+    relays: &[RelayEndpoint],
+    doh_providers: &[String],
+) -> Result<Vec<SocketAddr>, KillSwitchError> {
+    let mut allowed = Vec::new();
+    let mut resolved = false;
+    for relay in relays {
+        if let Ok(ip) = relay.host.parse::<IpAddr>() {
+            allowed.push(SocketAddr::new(ip, relay.port));
+            continue;
+        }
+        let ips = DohResolver::default().resolve(&relay.host).await.map_err(|e| KillSwitchError::Resolve {
+            host: relay.host.clone(),
+            reason: e.to_string(),
+        })?;
+        allowed.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, relay.port)));
+        resolved = true;
+    }
+    if resolved {
+        allowed.extend(doh_providers.iter().filter_map(|provider| doh_provider_address(provider)));
+    }
+    allowed.sort();
+    allowed.dedup();
+    Ok(allowed)
+}
+
+/// The address of an `https://IP[:port]/...` provider; `None` for one
+/// given by hostname.
+pub fn doh_provider_address(provider: &str) -> Option<SocketAddr> {
+    let authority = provider.strip_prefix("https://")?.split('/').next()?;
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let host = authority.trim_start_matches('[').trim_end_matches(']');
+    Some(SocketAddr::new(host.parse().ok()?, 443))
+}
+
+/// Removes the rules of a kill switch whose process exited without
+/// releasing them. Does nothing when there are none.
+pub fn lift() -> Result<(), KillSwitchError> {
+    let platform = Platform::current().ok_or(KillSwitchError::Unsupported)?;
+    Ok(run_all(&platform.lift(&run))?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Platform {
+    Nftables,
+    WindowsFirewall,
+}
+
+/// Commands that engage the kill switch, and those that release it.
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    engage: Vec<Vec<String>>,
+    release: Vec<Vec<String>>,
+}
+
+impl Platform {
+    fn current() -> Option<Self> {
+        if cfg!(windows) {
+            Some(Self::WindowsFirewall)
+        } else if cfg!(target_os = "linux") {
+            Some(Self::Nftables)
+        } else {
+            None
+        }
+    }
+
+    fn plan(self, relays: &[SocketAddr], query: Query) -> Result<Plan, KillSwitchError> {
+        match self {
+            Self::Nftables => Ok(nftables_plan(relays)),
+            Self::WindowsFirewall => firewall_plan(relays, query),
+        }
+    }
+
+    /// Commands that replace the allowed relays of an engaged kill switch.
+    /// nftables swaps the whole table again; Windows keeps its blocking
+    /// policies and only trades the allow rules.
+    fn refresh(self, relays: &[SocketAddr]) -> Vec<Vec<String>> {
+        match self {
+            Self::Nftables => nftables_plan(relays).engage,
+            Self::WindowsFirewall => {
+                let mut commands = vec![delete_rules()];
+                commands.extend(relays.iter().map(firewall_allow_rule));
+                commands
+            }
+        }
+    }
+
+    /// Commands that remove leftover rules, with none when there are none.
+    fn lift(self, query: Query) -> Vec<Vec<String>> {
+        match self {
+            Self::Nftables => vec![nft(&[format!("add table {NFT_TABLE}"), format!("delete table {NFT_TABLE}")])],
+            Self::WindowsFirewall => firewall_lift(query),
+        }
+    }
+}
+
+/// One `nft` invocation runs as one transaction, so the table is swapped
+/// whole: there is no moment with a half-built chain. Forwarded packets,
+/// from containers or VMs routed through this host, are dropped too.
+fn nftables_plan(relays: &[SocketAddr]) -> Plan {
+    let rule = |rule: &str| format!("add rule {NFT_TABLE} output {rule}");
+    let mut statements = vec![
+        // Adding first makes the delete succeed when there is no table.
+        format!("add table {NFT_TABLE}"),
+        format!("delete table {NFT_TABLE}"),
+        format!("add table {NFT_TABLE}"),
+        format!("add chain {NFT_TABLE} output {{ type filter hook output priority 0 ; policy drop ; }}"),
+        format!("add chain {NFT_TABLE} forward {{ type filter hook forward priority 0 ; policy drop ; }}"),
+        rule("oif lo accept"),
+        rule("udp sport 68 udp dport 67 accept"),
+        rule("udp sport 546 udp dport 547 accept"),
+        rule("icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept"),
+    ];
+    for relay in relays {
+        let family = if relay.is_ipv4() { "ip" } else { "ip6" };
+        statements.push(rule(&format!("{family} daddr {} tcp dport {} accept", relay.ip(), relay.port())));
+    }
+    Plan {
+        engage: vec![nft(&statements)],
+        release: vec![nft(&[format!("delete table {NFT_TABLE}")])],
+    }
+}
+
+fn nft(statements: &[String]) -> Vec<String> {
+    vec!["nft".to_string(), statements.join("; ")]
+}
+
+/// The allow rules go in before outbound is blocked, so relay tunnels
+/// never see a gap, and the markers before any other allow rule is
+/// disabled; the policies and the disabled rules come back before our
+/// rules go.
+fn firewall_plan(relays: &[SocketAddr], query: Query) -> Result<Plan, KillSwitchError> {
+    let policies = firewall_policies(&query(&show_policies())?);
+    let others = outbound_allow_rules(&query(&show_outbound_rules())?);
+    let mut plan = Plan::default();
+    plan.engage.extend(relays.iter().map(firewall_allow_rule));
+    plan.engage.extend(others.iter().map(|name| disabled_marker(name)));
+    for (profile, policy) in &policies {
+        let inbound = policy.split(',').next().unwrap_or("BlockInbound");
+        plan.engage.push(set_policy(profile, &format!("{inbound},BlockOutbound")));
+        plan.release.push(set_policy(profile, policy));
+    }
+    plan.engage.extend(others.iter().map(|name| enable_rule(name, false)));
+    plan.release.extend(others.iter().map(|name| enable_rule(name, true)));
+    plan.release.push(delete_rules());
+    plan.release.push(delete_markers());
+    Ok(plan)
+}
+
+fn firewall_allow_rule(relay: &SocketAddr) -> Vec<String> {
+    let (ip, port) = (relay.ip().to_string(), relay.port().to_string());
+    command(&[
+        "netsh",
+        "advfirewall",
+        "firewall",
+        "add",
+        "rule",
+        &format!("name={FIREWALL_RULE}"),
+        "dir=out",
+        "action=allow",
+        "protocol=TCP",
+        &format!("remoteip={ip}"),
+        &format!("remoteport={port}"),
+    ])
+}
+
+/// Outbound allowed again on the profiles that block it, but only when
+/// our rules show a kill switch was left behind, and the rules named by
+/// leftover markers enabled again.
+fn firewall_lift(query: Query) -> Vec<Vec<String>> {
+    let mut lift = Vec::new();
+    // `show rule` fails when no rule has the name.
+    let rules = command(&["netsh", "advfirewall", "firewall", "show", "rule", &format!("name={FIREWALL_RULE}")]);
+    let left_behind = match (query(&rules), query(&show_policies())) {
+        (Ok(_), Ok(output)) => Some(firewall_policies(&output)),
+        _ => None,
+    };
+    for (profile, policy) in left_behind.iter().flatten() {
+        let Some((inbound, outbound)) = policy.split_once(',') else { continue };
+        if outbound.eq_ignore_ascii_case("BlockOutbound") {
+            lift.push(set_policy(profile, &format!("{inbound},AllowOutbound")));
+        }
+    }
+    let markers = format!("name={DISABLED_MARKER}");
+    let disabled = query(&command(&["netsh", "advfirewall", "firewall", "show", "rule", &markers, "verbose"])).ok();
+    if let Some(output) = &disabled {
+        lift.extend(marked_rules(output).iter().map(|name| enable_rule(name, true)));
+    }
+    if left_behind.is_some() {
+        lift.push(delete_rules());
+    }
+    if disabled.is_some() {
+        lift.push(delete_markers());
+    }
+    lift
+}
+
+/// Names of the enabled rules in `netsh advfirewall firewall show rule
+/// name=all dir=out` that let traffic through, other than ours, once
+/// each: `set rule` acts on every rule with a name.
+fn outbound_allow_rules(output: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut rule: Option<(String, bool)> = None;
+    let field = |line: &str, name: &str| Some(line.strip_prefix(name)?.trim().to_string());
+    for line in output.lines().map(str::trim) {
+        if let Some(name) = field(line, "Rule Name:") {
+            rule = Some((name, false));
+        } else if let Some(enabled) = field(line, "Enabled:") {
+            if let Some((_, rule_enabled)) = &mut rule {
+                *rule_enabled = enabled.eq_ignore_ascii_case("Yes");
+            }
+        } else if let Some(action) = field(line, "Action:") {
+            let Some((name, enabled)) = rule.take() else { continue };
+            let allows = !action.eq_ignore_ascii_case("Block");
+            if enabled && allows && name != FIREWALL_RULE && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// The rule names recorded in the descriptions of our markers.
+fn marked_rules(output: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for line in output.lines().map(str::trim) {
+        let Some(name) = line.strip_prefix("Description:").map(str::trim) else { continue };
+        if !name.is_empty() && !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+fn show_outbound_rules() -> Vec<String> {
+    command(&["netsh", "advfirewall", "firewall", "show", "rule", "name=all", "dir=out"])
+}
+
+/// A disabled rule whose description names a rule the kill switch
+/// disabled. It blocks rather than allows, should someone enable it.
+fn disabled_marker(name: &str) -> Vec<String> {
+    command(&[
+        "netsh",
+        "advfirewall",
+        "firewall",
+        "add",
+        "rule",
+        &format!("name={DISABLED_MARKER}"),
+        "dir=out",
+        "action=block",
+        "enable=no",
+        &format!("description={name}"),
+    ])
+}
+
+fn enable_rule(name: &str, enable: bool) -> Vec<String> {
+    let enable = if enable { "enable=yes" } else { "enable=no" };
+    command(&["netsh", "advfirewall", "firewall", "set", "rule", &format!("name={name}"), "dir=out", "new", enable])
+}
+
+fn delete_markers() -> Vec<String> {
+    command(&["netsh", "advfirewall", "firewall", "delete", "rule", &format!("name={DISABLED_MARKER}")])
+}
+
+/// `(profile, policy)` pairs from `netsh advfirewall show allprofiles
+/// firewallpolicy`, e.g. `("domainprofile", "BlockInbound,AllowOutbound")`.
+fn firewall_policies(output: &str) -> Vec<(String, String)> {
+    let mut policies = Vec::new();
+    let mut profile = None;
+    for line in output.lines().map(str::trim) {
+        if let Some(name) = line.strip_suffix("Profile Settings:") {
+            profile = Some(format!("{}profile", name.trim().to_ascii_lowercase()));
+        } else if let Some(policy) = line.strip_prefix("Firewall Policy") {
+            if let Some(profile) = profile.take() {
+                policies.push((profile, policy.trim().to_string()));
+            }
+        }
+    }
+    policies
+}
+
+fn show_policies() -> Vec<String> {
+    command(&["netsh", "advfirewall", "show", "allprofiles", "firewallpolicy"])
+}
+
+fn set_policy(profile: &str, policy: &str) -> Vec<String> {
+    command(&["netsh", "advfirewall", "set", profile, "firewallpolicy", policy])
+}
+
+fn delete_rules() -> Vec<String> {
+    command(&["netsh", "advfirewall", "firewall", "delete", "rule", &format!("name={FIREWALL_RULE}")])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICIES: &str = "\r\nDomain Profile Settings: \r\n\
+        ----------------------------------------------------------------------\r\n\
+        Firewall Policy                       BlockInbound,AllowOutbound\r\n\r\n\
+        Private Profile Settings: \r\n\
+        ----------------------------------------------------------------------\r\n\
+        Firewall Policy                       AllowInbound,AllowOutbound\r\n\r\nOk.\r\n";
+
+    const OUTBOUND_RULES: &str = "\r\nRule Name:                            Core Networking - DNS (UDP-Out)\r\n\
+        ----------------------------------------------------------------------\r\n\
+        Enabled:                              Yes\r\nDirection:                            Out\r\n\
+        Action:                               Allow\r\n\r\n\
+        Rule Name:                            Core Networking - DNS (UDP-Out)\r\n\
+        Enabled:                              Yes\r\nAction:                               Allow\r\n\r\n\
+        Rule Name:                            Old VPN\r\n\
+        Enabled:                              No\r\nAction:                               Allow\r\n\r\n\
+        Rule Name:                            Telemetry\r\n\
+        Enabled:                              Yes\r\nAction:                               Block\r\n\r\nOk.\r\n";
+
+    /// Answers the queries of `firewall_plan` on a host in the state
+    /// `POLICIES` and `OUTBOUND_RULES` describe.
+    fn host(command: &[String]) -> Result<String, CommandError> {
+        Ok(match command.join(" ").as_str() {
+            "netsh advfirewall show allprofiles firewallpolicy" => POLICIES.to_string(),
+            "netsh advfirewall firewall show rule name=all dir=out" => OUTBOUND_RULES.to_string(),
+            other => unreachable!("{other}"),
+        })
+    }
+
+    fn relays() -> Vec<SocketAddr> {
+        vec!["203.0.113.5:443".parse().unwrap(), "[2001:db8::7]:8443".parse().unwrap()]
+    }
+
+    #[test]
+    fn nftables_swaps_one_table_allowing_only_relays() {
+        let plan = Platform::Nftables.plan(&relays(), &|_: &[String]| unreachable!()).unwrap();
+        let [engage] = &plan.engage[..] else { panic!("{:?}", plan.engage) };
+        assert_eq!(engage[0], "nft");
+        let statements: Vec<&str> = engage[1].split("; ").collect();
+        let table = "inet ebt_kill_switch";
+        let swap = [format!("add table {table}"), format!("delete table {table}"), format!("add table {table}")];
+        assert_eq!(statements[..3], swap);
+        assert!(engage[1].contains("output { type filter hook output priority 0 ; policy drop ; }"));
+        assert!(engage[1].contains("forward { type filter hook forward priority 0 ; policy drop ; }"));
+        assert!(statements.contains(&"add rule inet ebt_kill_switch output oif lo accept"));
+        for relay in ["ip daddr 203.0.113.5 tcp dport 443", "ip6 daddr 2001:db8::7 tcp dport 8443"] {
+            assert!(statements.contains(&format!("add rule {table} output {relay} accept").as_str()), "{relay}");
+        }
+        assert_eq!(plan.release, [command(&["nft", "delete table inet ebt_kill_switch"])]);
+    }
+
+    #[test]
+    fn windows_firewall_blocks_outbound_per_profile_and_puts_it_back() {
+        let plan = Platform::WindowsFirewall.plan(&relays(), &host).unwrap();
+        let engage: Vec<String> = plan.engage.iter().map(|command| command.join(" ")).collect();
+        assert_eq!(
+            engage,
+            [
+                "netsh advfirewall firewall add rule name=ebt-kill-switch dir=out action=allow protocol=TCP remoteip=203.0.113.5 remoteport=443",
+                "netsh advfirewall firewall add rule name=ebt-kill-switch dir=out action=allow protocol=TCP remoteip=2001:db8::7 remoteport=8443",
+                "netsh advfirewall firewall add rule name=ebt-kill-switch-disabled dir=out action=block enable=no description=Core Networking - DNS (UDP-Out)",
+                "netsh advfirewall set domainprofile firewallpolicy BlockInbound,BlockOutbound",
+                "netsh advfirewall set privateprofile firewallpolicy AllowInbound,BlockOutbound",
+                "netsh advfirewall firewall set rule name=Core Networking - DNS (UDP-Out) dir=out new enable=no",
+            ]
+        );
+        let dns = "Core Networking - DNS (UDP-Out)";
+        assert_eq!(
+            plan.release,
+            [
+                set_policy("domainprofile", "BlockInbound,AllowOutbound"),
+                set_policy("privateprofile", "AllowInbound,AllowOutbound"),
+                enable_rule(dns, true),
+                delete_rules(),
+                delete_markers(),
+            ]
+        );
+
+        // Lifting after a crash only touches what the kill switch blocked
+        // and the rules its markers name.
+        let blocked = POLICIES.replacen("BlockInbound,AllowOutbound", "BlockInbound,BlockOutbound", 1);
+        let left_behind = |command: &[String]| match command.last().map(String::as_str) {
+            Some("verbose") => Ok(format!("Rule Name: {DISABLED_MARKER}\r\nDescription: {dns}\r\nEnabled: No\r\n")),
+            _ => Ok(blocked.clone()),
+        };
+        assert_eq!(
+            Platform::WindowsFirewall.lift(&left_behind),
+            [
+                set_policy("domainprofile", "BlockInbound,AllowOutbound"),
+                enable_rule(dns, true),
+                delete_rules(),
+                delete_markers(),
+            ]
+        );
+        let no_rules = |command: &[String]| {
+            Err(CommandError {
+                command: command.join(" "),
+                reason: "exit status: 1".to_string(),
+            })
+        };
+        assert!(Platform::WindowsFirewall.lift(&no_rules).is_empty());
+
+        // A refresh trades the allow rules and leaves the policies alone.
+        let refresh = Platform::WindowsFirewall.refresh(&relays()[..1]);
+        assert_eq!(refresh, [delete_rules(), plan.engage[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn relays_by_address_need_no_lookup_and_no_doh_provider() {
+        let relays = [RelayEndpoint::new("203.0.113.5", 443), RelayEndpoint::new("2001:db8::7", 8443)];
+        let providers = ["https://1.1.1.1/dns-query".to_string()];
+        let mut expected = self::relays();
+        expected.sort();
+        assert_eq!(allowed_addresses(&relays, &providers).await.unwrap(), expected);
+
+        assert_eq!(doh_provider_address("https://1.1.1.1/dns-query"), Some("1.1.1.1:443".parse().unwrap()));
+        let v6 = "https://[2606:4700::1111]:8443/q";
+        assert_eq!(doh_provider_address(v6), Some("[2606:4700::1111]:8443".parse().unwrap()));
+        assert_eq!(doh_provider_address("https://dns.example/dns-query"), None);
+    }
+}
@@ -0,0 +1,425 @@
+//! `ebt leaktest`: probes a running proxy and this machine for the leaks
+//! a user would not notice, and reports pass or fail per attack surface
+//! in `attack_surfaces`:
+//!
+//! - plaintext DNS: a CONNECT for a fresh hostname goes through the proxy
+//!   while port 53 is watched for that name, with `tcpdump` when it can
+//!   capture (root), otherwise by sampling the proxy process's sockets on
+//!   Linux, which can miss a query that opens and closes between samples;
+//! - connections that skip the proxy: a direct TCP connect to a public
+//!   address, which only `proxy.kill_switch` stops;
+//! - WebRTC-style UDP egress: a STUN binding request, which a browser
+//!   sends around any HTTP proxy and which reveals the real address.
+//!
+//! Surfaces inside the process, such as logging, are not observable from
+//! outside and are left to the invariant tests.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+use crate::attack_surfaces::AttackSurfaceEnumeration;
+use crate::dns_resolver::{DnsResolver, DohResolver};
+use crate::os_command::{command, spawn};
+
+pub const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+pub const DEFAULT_DIRECT_TARGET: &str = "1.1.1.1:443";
+/// Parent of the probe hostnames; never resolves, so nothing is cached.
+const PROBE_DOMAIN: &str = "leaktest.example.com";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time for `tcpdump` to open its capture, and to flush after the probe.
+const CAPTURE_SETTLE: Duration = Duration::from_secs(1);
+/// CONNECTs per run: each is another chance for a socket audit to catch
+/// a short-lived query.
+const PROBE_ROUNDS: usize = 3;
+
+/// Attack surfaces each probe exercises, by `violation_path`.
+const DNS_SURFACES: [&str; 3] = [
+    "DNS queries in plaintext",
+    "OS resolver fallback in DoH failure",
+    "System DNS via ToSocketAddrs",
+];
+const BYPASS_SURFACE: &str = "Application traffic bypassing the proxy";
+const UDP_SURFACE: &str = "WebRTC UDP egress outside the proxy";
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Pass,
+    Fail,
+    /// The probe could not run here; says nothing either way.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Evidence {
+    verdict: Verdict,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+impl Evidence {
+    fn pass(detail: impl Into<String>) -> Self {
+        Self {
+            verdict: Verdict::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            verdict: Verdict::Fail,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn unknown(detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            verdict: Verdict::Unknown,
+            ..Self::fail(detail, hint)
+        }
+    }
+}
+
+/// Runs every probe and prints the verdict per attack surface; fails when
+/// any surface leaks.
+pub async fn run(proxy: SocketAddr, stun: &str, direct: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let dns = probe_dns(proxy).await;
+    let bypass = probe_direct(direct).await;
+    let udp = probe_udp(stun).await;
+    let evidence_for = |path: &str| match path {
+        _ if DNS_SURFACES.contains(&path) => Some(&dns),
+        BYPASS_SURFACE => Some(&bypass),
+        UDP_SURFACE => Some(&udp),
+        _ => None,
+    };
+
+    let surfaces = AttackSurfaceEnumeration::new().surfaces;
+    let (mut failed, mut unobserved) = (0, 0);
+    for surface in &surfaces {
+        let Some(evidence) = evidence_for(&surface.violation_path) else {
+            unobserved += 1;
+            continue;
+        };
+        let verdict = match evidence.verdict {
+            Verdict::Pass => "pass",
+            Verdict::Fail => "FAIL",
+            Verdict::Unknown => "?",
+        };
+        failed += usize::from(evidence.verdict == Verdict::Fail);
+        let severity = format!("{:?}", surface.severity).to_lowercase();
+        println!("{:<5} {:<7} {} ({})", verdict, severity, surface.violation_path, surface.component);
+        println!("{:<13} {}", "", evidence.detail);
+        if let Some(hint) = evidence.hint {
+            println!("{:<13} {}", "", hint);
+        }
+    }
+    println!("\n{} other attack surface(s) are not observable from outside the proxy", unobserved);
+    if failed > 0 {
+        return Err(format!("{} attack surface(s) leak", failed).into());
+    }
+    Ok(())
+}
+
+async fn probe_dns(proxy: SocketAddr) -> Evidence {
+    let token = format!("{:016x}", rand::random::<u64>());
+    let watch = match DnsWatch::start(proxy.port()) {
+        Ok(watch) => watch,
+        Err(reason) => {
+            return Evidence::unknown(
+                format!("port 53 cannot be watched: {}", reason),
+                "run as root so tcpdump can capture, on the machine the proxy runs on",
+            )
+        }
+    };
+    let mut reached = Ok(());
+    for round in 0..PROBE_ROUNDS {
+        reached = connect_through(proxy, &format!("{}-{}.{}", token, round, PROBE_DOMAIN)).await;
+        if reached.is_err() {
+            break;
+        }
+    }
+    let (method, leaked) = watch.finish(&token);
+    match (reached, leaked) {
+        (Err(e), _) => Evidence::unknown(
+            format!("the proxy at {} did not take the probe: {}", proxy, e),
+            "start the proxy, or pass --proxy with its address",
+        ),
+        (Ok(()), 0) => Evidence::pass(format!("no port 53 traffic for the probe ({})", method)),
+        (Ok(()), leaked) => Evidence::fail(
+            format!("{} port 53 packet(s) or socket(s) for the probe ({})", leaked, method),
+            "hostnames reach the network in plaintext; build without doh_fallback and check dns.doh_providers",
+        ),
+    }
+}
+
+/// Sends `CONNECT hostname:443` and waits until the proxy gives up on it:
+/// the point is only that the proxy tried to resolve the name. It may
+/// answer `200` first and resolve afterwards.
+async fn connect_through(proxy: SocketAddr, hostname: &str) -> Result<(), Box<dyn Error>> {
+    let mut stream = timeout(PROBE_TIMEOUT, TcpStream::connect(proxy)).await.map_err(|_| "no answer")??;
+    let request = format!("CONNECT {host}:443 HTTP/1.1\r\nHost: {host}:443\r\n\r\n", host = hostname);
+    stream.write_all(request.as_bytes()).await?;
+    let mut status = [0u8; 12];
+    timeout(PROBE_TIMEOUT, stream.read_exact(&mut status)).await.map_err(|_| "no answer")??;
+    if status.ends_with(b" 407") {
+        return Err("the proxy requires authentication".into());
+    }
+    let mut rest = Vec::new();
+    let _ = timeout(PROBE_TIMEOUT * 4, stream.read_to_end(&mut rest)).await;
+    Ok(())
+}
+
+/// Watches port 53 while the DNS probe runs.
+enum DnsWatch {
+    Capture(std::process::Child),
+    /// Counts distinct port 53 sockets of the proxy process.
+    Audit {
+        stop: Arc<AtomicBool>,
+        sampler: JoinHandle<usize>,
+    },
+}
+
+impl DnsWatch {
+    fn start(proxy_port: u16) -> Result<Self, String> {
+        let interface: &[&str] = if cfg!(target_os = "linux") { &["-i", "any"] } else { &[] };
+        let capture = [&["tcpdump", "-l", "-n"][..], interface, &["port", "53"]].concat();
+        if let Ok(mut child) = spawn(&command(&capture)) {
+            thread::sleep(CAPTURE_SETTLE);
+            // Without privileges tcpdump exits at once.
+            if matches!(child.try_wait(), Ok(None)) {
+                return Ok(Self::Capture(child));
+            }
+        }
+        if !cfg!(target_os = "linux") {
+            return Err("tcpdump could not capture and sockets can only be audited on Linux".to_string());
+        }
+        let pid = listener_pid(proxy_port).ok_or("tcpdump could not capture and the proxy process was not found")?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let sampler = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let mut seen = HashSet::new();
+                // Back to back: a query's socket lives for one round trip,
+                // often about a millisecond.
+                while !stop.load(Ordering::Relaxed) {
+                    let owned = socket_inodes(pid);
+                    for socket in ["udp", "udp6", "tcp", "tcp6"].iter().flat_map(|table| proc_net(table)) {
+                        if socket.remote_port == 53 && owned.contains(&socket.inode) {
+                            seen.insert(socket.inode);
+                        }
+                    }
+                    thread::yield_now();
+                }
+                seen.len()
+            }
+        });
+        Ok(Self::Audit { stop, sampler })
+    }
+
+    /// How the port was watched, and what it saw of the probe names
+    /// containing `token`.
+    fn finish(self, token: &str) -> (&'static str, usize) {
+        thread::sleep(CAPTURE_SETTLE);
+        match self {
+            Self::Capture(mut child) => {
+                let _ = child.kill();
+                let mut output = String::new();
+                if let Some(mut stdout) = child.stdout.take() {
+                    let _ = stdout.read_to_string(&mut output);
+                }
+                let _ = child.wait();
+                ("tcpdump", output.lines().filter(|line| line.contains(token)).count())
+            }
+            Self::Audit { stop, sampler } => {
+                stop.store(true, Ordering::Relaxed);
+                ("socket audit", sampler.join().unwrap_or(0))
+            }
+        }
+    }
+}
+
+/// A row of `/proc/net/{tcp,udp}[6]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProcSocket {
+    local_port: u16,
+    remote_port: u16,
+    listening: bool,
+    inode: u64,
+}
+
+fn proc_net(table: &str) -> Vec<ProcSocket> {
+    std::fs::read_to_string(format!("/proc/net/{}", table))
+        .map(|contents| parse_proc_net(&contents))
+        .unwrap_or_default()
+}
+
+fn parse_proc_net(contents: &str) -> Vec<ProcSocket> {
+    let port = |addr: &str| u16::from_str_radix(addr.rsplit(':').next()?, 16).ok();
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            Some(ProcSocket {
+                local_port: port(fields.get(1)?)?,
+                remote_port: port(fields.get(2)?)?,
+                // TCP_LISTEN
+                listening: *fields.get(3)? == "0A",
+                inode: fields.get(9)?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Socket inodes among the open descriptors of `pid`.
+fn socket_inodes(pid: u32) -> HashSet<u64> {
+    let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return HashSet::new();
+    };
+    fds.flatten()
+        .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+        .filter_map(|target| {
+            let target = target.to_str()?;
+            target.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+        })
+        .collect()
+}
+
+/// The process listening on TCP `port`, if this user can see it.
+fn listener_pid(port: u16) -> Option<u32> {
+    let listeners: HashSet<u64> = ["tcp", "tcp6"]
+        .iter()
+        .flat_map(|table| proc_net(table))
+        .filter(|socket| socket.listening && socket.local_port == port)
+        .map(|socket| socket.inode)
+        .collect();
+    std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .find(|&pid| !socket_inodes(pid).is_disjoint(&listeners))
+}
+
+async fn probe_direct(target: SocketAddr) -> Evidence {
+    match timeout(PROBE_TIMEOUT, TcpStream::connect(target)).await {
+        Ok(Ok(_)) => Evidence::fail(
+            format!("connected to {} without the proxy", target),
+            "apps that ignore the proxy reach the network; set proxy.kill_switch = true",
+        ),
+        Ok(Err(e)) => Evidence::pass(format!("{} is unreachable without the proxy: {}", target, e)),
+        Err(_) => Evidence::pass(format!("{} did not answer without the proxy", target)),
+    }
+}
+
+async fn probe_udp(stun: &str) -> Evidence {
+    let server = match stun_address(stun).await {
+        Ok(server) => server,
+        Err(e) => {
+            return Evidence::unknown(
+                format!("cannot resolve {}: {}", stun, e),
+                "pass --stun with an ip:port STUN server",
+            )
+        }
+    };
+    let transaction: [u8; 12] = rand::random();
+    let exchange = async {
+        let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().expect("valid");
+        let socket = UdpSocket::bind(bind).await?;
+        socket.send_to(&stun_binding_request(transaction), server).await?;
+        let mut response = [0u8; 512];
+        loop {
+            let (len, from) = socket.recv_from(&mut response).await?;
+            if from == server && is_stun_success(&response[..len], transaction) {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    };
+    match timeout(PROBE_TIMEOUT, exchange).await {
+        Ok(Ok(())) => Evidence::fail(
+            format!("STUN server {} answered over UDP", server),
+            "a browser's WebRTC can reveal your address this way; set proxy.kill_switch = true or turn \
+             off WebRTC in the browser",
+        ),
+        Ok(Err(e)) => Evidence::pass(format!("UDP to {} is blocked: {}", server, e)),
+        Err(_) => Evidence::pass(format!("no UDP answer from {}", server)),
+    }
+}
+
+/// `stun` as given, or resolved over DoH so the probe leaks no DNS itself.
+async fn stun_address(stun: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    if let Ok(addr) = stun.parse() {
+        return Ok(addr);
+    }
+    let (host, port) = stun.rsplit_once(':').ok_or("expected host:port")?;
+    let port: u16 = port.parse()?;
+    let addrs: Vec<IpAddr> = DohResolver::new().resolve(host).await?;
+    let ip = addrs.first().ok_or("no address")?;
+    Ok(SocketAddr::new(*ip, port))
+}
+
+/// RFC 8489 Binding request without attributes.
+fn stun_binding_request(transaction: [u8; 12]) -> [u8; 20] {
+    let mut request = [0u8; 20];
+    request[..2].copy_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(&transaction);
+    request
+}
+
+fn is_stun_success(response: &[u8], transaction: [u8; 12]) -> bool {
+    response.len() >= 20
+        && response[..2] == STUN_BINDING_SUCCESS.to_be_bytes()
+        && response[4..8] == STUN_MAGIC_COOKIE.to_be_bytes()
+        && response[8..20] == transaction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_cover_listed_surfaces_and_parse_what_they_observe() {
+        let surfaces = AttackSurfaceEnumeration::new().surfaces;
+        for path in DNS_SURFACES.iter().chain([&BYPASS_SURFACE, &UDP_SURFACE]) {
+            assert!(surfaces.iter().any(|surface| surface.violation_path == *path), "{}", path);
+        }
+
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+            0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41234 1 0\n\
+            1: 0201A8C0:D431 0101A8C0:0035 01 00000000:00000000 00:00000000 00000000  1000        0 41300 1 0\n";
+        let sockets = parse_proc_net(table);
+        assert_eq!(
+            sockets,
+            [
+                ProcSocket { local_port: 8080, remote_port: 0, listening: true, inode: 41234 },
+                ProcSocket { local_port: 54321, remote_port: 53, listening: false, inode: 41300 },
+            ]
+        );
+
+        let transaction = *b"leaktest-txn";
+        let request = stun_binding_request(transaction);
+        assert_eq!(request[..8], [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]);
+        let mut response = request.to_vec();
+        response[..2].copy_from_slice(&STUN_BINDING_SUCCESS.to_be_bytes());
+        response[2..4].copy_from_slice(&12u16.to_be_bytes());
+        response.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(is_stun_success(&response, transaction));
+        assert!(!is_stun_success(&request, transaction), "a reflected request is not an answer");
+        assert!(!is_stun_success(&response, *b"another-txn!"));
+    }
+}
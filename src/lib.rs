@@ -0,0 +1,184 @@
+//! Encrypted browser tunnel as a library. The `ebt` binary is a thin
+//! command line over this crate; GUI front-ends and integration tests embed
+//! the same proxy through `EbtProxyBuilder`. Applications that only need
+//! their own TCP tunnelled open streams with `TunnelSession::open_stream`.
+//!
+//! Modules are private. Everything meant for embedders is re-exported here,
+//! so internal moves do not break callers; items not re-exported may change
+//! in any release.
+//!
+//! ```no_run
+//! # async fn example() -> encrypted_browser_tunnel::EbtResult<()> {
+//! use encrypted_browser_tunnel::{EbtProxyBuilder, TunnelConfig};
+//!
+//! let proxy = EbtProxyBuilder::new(TunnelConfig::from_file("ebt.toml")?)
+//!     .bind_port(0)
+//!     .bind()
+//!     .await?;
+//! println!("listening on {}", proxy.local_addr()?);
+//! proxy.run_until(async { let _ = tokio::signal::ctrl_c().await; }).await
+//! # }
+//! ```
+
+#![allow(dead_code)]
+
+mod client;
+mod core;
+mod transport;
+mod ssh_transport;
+mod ssh_transport_adapter;
+mod dns;
+mod session;
+mod session_export;
+mod config;
+mod config_file;
+#[doc(hidden)]
+pub mod cli;
+mod error;
+mod real_transport;
+mod real_proxy;
+mod socks5;
+mod authority;
+mod error_response;
+mod hostname;
+mod client_hello;
+mod proxy_protocol;
+mod proxy_builder;
+mod real_dns;
+mod tls_wrapper;
+mod crypto_util;
+mod dns_resolver;
+mod dns_prefetch;
+mod exit_dns;
+mod relay_transport;
+mod relay_server;
+mod relay_client;
+mod frame_capture;
+mod circuit;
+mod exit_policy;
+mod onion;
+mod relay_directory;
+mod relay_health;
+mod relay_quota;
+mod relay_accounting;
+mod relay_resume;
+mod relay_stdio;
+mod os_command;
+mod system_proxy;
+mod kill_switch;
+mod leak_test;
+mod network_monitor;
+mod memory_profile;
+mod control_socket;
+mod desktop_notifications;
+mod ctl;
+mod doctor;
+mod logging;
+mod tunnel_stats;
+mod policy_audit;
+mod tunnel_registry;
+mod event_bus;
+mod usage_summary;
+mod traffic_accounting;
+mod stats_cli;
+mod init_wizard;
+mod threat_invariants;
+mod attack_surfaces;
+mod trust_boundaries;
+mod prohibited_capabilities;
+mod threat_model_tests;
+mod crypto_transport_design;
+mod control_plane;
+mod data_plane;
+mod key_management;
+mod zone_interfaces;
+mod crypto_transport_tests;
+mod threat_model;
+mod traffic_shaping;
+mod relay_protocol;
+mod transport_adapter;
+mod protocol_engine;
+mod connection_mapping;
+mod binding_pump;
+mod anonymity;
+mod anonymity_protocol;
+mod anonymity_binding;
+mod content_policy;
+mod content_policy_bootstrap;
+#[cfg(test)]
+mod content_policy_invariants_tests;
+#[cfg(test)]
+mod anonymity_correlation_tests;
+#[cfg(test)]
+mod anonymity_regression_gate;
+#[cfg(feature = "encrypted_control")]
+mod control_channel;
+#[cfg(feature = "async_tunnel")]
+mod async_tunnel;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+
+// Proxy and session
+pub use crate::proxy_builder::{EbtProxy, EbtProxyBuilder};
+pub use crate::real_proxy::{ConfigSource, RealProxyServer, RealProxyServerBuilder};
+pub use crate::relay_transport::RelaySelector;
+pub use crate::session::{TunnelSession, TunnelSessionBuilder};
+pub use crate::session_export::SessionState;
+pub use crate::system_proxy::{SystemProxy, SystemProxyError};
+pub use crate::kill_switch::{KillSwitch, KillSwitchError};
+pub use crate::control_socket::{ControlRequest, ControlResponse};
+pub use crate::client::ProxyType;
+
+// Configuration
+pub use crate::config::{
+    AdaptivePaddingConfig, AdaptiveTunnelLimit, AnonymityConfig, BlockBehavior, BlockResponse, Capability,
+    CapabilityPolicy, ConfigError, ConnectRetry, ConstantRate, DelayBudgetConfig, DnsPolicy, ExecutionMode,
+    GuardConfig, HeaderLimits, LeakDetection, MixDelay, NetworkToken, NotificationConfig, PaddingHistogramConfig,
+    PrefetchHint, ProxyMode, ProxyPolicy, RelayConfig, RelayEndpoint, RelayHealthConfig, RelayMode, RelaySelection,
+    ResolutionLocation, ShapingProfile, TrafficAccountingConfig, TransportConfig, TransportKind,
+    TunnelConfig, Unset, UsageSummaryConfig,
+};
+pub use crate::logging::{LogConfig, LogFormat, LogLevel};
+
+// Errors
+pub use crate::content_policy_bootstrap::RuleSourceError;
+pub use crate::error::{EbtError, EbtResult};
+pub use crate::os_command::CommandError;
+pub use crate::relay_protocol::ProtocolError;
+pub use crate::session_export::SessionExportError;
+pub use crate::transport::TransportError;
+
+// Relay server
+pub use crate::exit_dns::ExitDnsService;
+pub use crate::exit_policy::ExitPolicy;
+pub use crate::relay_accounting::RelayAccounting;
+pub use crate::relay_protocol::{OutboundOverflow, RelayLimits};
+pub use crate::relay_quota::SessionQuotas;
+pub use crate::relay_server::{
+    certificate_fingerprint, default_limits as default_relay_limits, RelayLimitsBuilder, RelayRole, RelayServer,
+    RelayServerConfig,
+};
+
+// DNS
+pub use crate::dns_resolver::{
+    doh_cache, doh_providers, set_doh_providers, CachedLookup, DnsError, DnsResolver, DohResolver, SystemDnsResolver,
+    DEFAULT_DOH_PROVIDER,
+};
+
+// Content policy
+pub use crate::content_policy_bootstrap::{build_listener_policies, ClientSubnet, ListenerPolicies, RulesetLibrary};
+pub use crate::content_policy::{
+    parse_native_rules, to_native_rules, ContentPolicyEngine, Decision, ReasonCode,
+    RequestMetadata, Rule, RuleAction, RuleSet,
+};
+
+// Threat model
+pub use crate::attack_surfaces::{
+    AttackSurface, AttackSurfaceEnumeration, CoverageReport, Mitigation, MitigationStatus, Severity, SurfaceCoverage,
+};
+pub use crate::threat_invariants::InvariantId;
+
+// Events
+pub use crate::core::observability::HealthState;
+pub use crate::event_bus::{subscribe as subscribe_events, CloseReason, EbtEvent};
+pub use crate::tunnel_registry::TunnelId;
@@ -1,4 +1,10 @@
-#[derive(Copy, Clone, PartialEq, PartialOrd)]
+use std::sync::RwLock;
+
+use serde::ser::{SerializeMap, Serializer as _};
+
+use crate::core::observability::OBS_DEV;
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
 pub enum LogLevel {
     Error = 0,
     Info = 1,
@@ -6,13 +12,305 @@ pub enum LogLevel {
     Trace = 3,
 }
 
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    pub(crate) fn parse(level: &str) -> Option<Self> {
+        match level.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// Default level when no configuration has been installed.
 pub static LOG_LEVEL: LogLevel = LogLevel::Error;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `message key=value ...`
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Privacy class of a log field. Sensitive fields (addresses, peer errors)
+/// are dropped unless built with OBS_DEV, so logging stays opt-in for
+/// anything that could identify a user or destination.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldClass {
+    Safe,
+    Sensitive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogField {
+    pub name: &'static str,
+    pub value: String,
+    pub class: FieldClass,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub default_level: LogLevel,
+    /// Module path prefixes relative to the crate root, e.g. `real_proxy`.
+    pub module_levels: Vec<(String, LogLevel)>,
+    pub format: LogFormat,
+    /// Writes to stderr instead of stdout, which may be carrying a
+    /// protocol (`ebt relay serve --stdio`).
+    pub to_stderr: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            default_level: LOG_LEVEL,
+            module_levels: Vec::new(),
+            format: LogFormat::Text,
+            to_stderr: false,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Parses `info,real_proxy=debug,real_transport=trace`; unknown entries are ignored.
+    pub fn parse(levels: &str, format: LogFormat) -> Self {
+        let mut config = LogConfig {
+            format,
+            ..LogConfig::default()
+        };
+        for entry in levels.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                Some((module, level)) => {
+                    if let Some(level) = LogLevel::parse(level) {
+                        config.module_levels.push((module.trim().to_string(), level));
+                    }
+                }
+                None => {
+                    if let Some(level) = LogLevel::parse(entry) {
+                        config.default_level = level;
+                    }
+                }
+            }
+        }
+        config
+    }
+
+    /// Reads `EBT_LOG` (levels) and `EBT_LOG_FORMAT` (`text` or `json`).
+    pub fn from_env() -> Self {
+        let format = match std::env::var("EBT_LOG_FORMAT").ok().as_deref() {
+            Some("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        };
+        Self::parse(&std::env::var("EBT_LOG").unwrap_or_default(), format)
+    }
+
+    /// Level for `module`; the longest matching configured prefix wins.
+    pub fn level_for(&self, module: &str) -> LogLevel {
+        let module = module.split_once("::").map_or("", |(_, rest)| rest);
+        self.module_levels
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default_level, |(_, level)| *level)
+    }
+}
+
+static LOG_CONFIG: RwLock<Option<LogConfig>> = RwLock::new(None);
+
+/// Installs the process-wide logging configuration.
+pub fn init(config: LogConfig) {
+    if let Ok(mut current) = LOG_CONFIG.write() {
+        *current = Some(config);
+    }
+}
+
+/// Replaces the levels of the running configuration, keeping its format
+/// and output stream.
+pub fn set_levels(levels: &str) {
+    if let Ok(mut current) = LOG_CONFIG.write() {
+        let previous = current.take().unwrap_or_default();
+        *current = Some(LogConfig {
+            to_stderr: previous.to_stderr,
+            ..LogConfig::parse(levels, previous.format)
+        });
+    }
+}
+
+pub fn enabled(level: LogLevel, module: &str) -> bool {
+    match LOG_CONFIG.read().ok().as_deref() {
+        Some(Some(config)) => level <= config.level_for(module),
+        _ => level <= LOG_LEVEL,
+    }
+}
+
+pub fn emit(level: LogLevel, module: &str, message: &str, fields: &[LogField]) {
+    let (format, to_stderr) = match LOG_CONFIG.read().ok().as_deref() {
+        Some(Some(config)) => (config.format, config.to_stderr),
+        _ => (LogFormat::Text, false),
+    };
+    let line = render(format, level, module, message, fields, OBS_DEV);
+    if to_stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+pub(crate) fn render(
+    format: LogFormat,
+    level: LogLevel,
+    module: &str,
+    message: &str,
+    fields: &[LogField],
+    include_sensitive: bool,
+) -> String {
+    let fields = fields
+        .iter()
+        .filter(|field| include_sensitive || field.class == FieldClass::Safe);
+    let mut out = String::new();
+    match format {
+        LogFormat::Text => {
+            out.push_str(message);
+            for field in fields {
+                out.push(' ');
+                out.push_str(field.name);
+                out.push('=');
+                out.push_str(&field.value);
+            }
+        }
+        LogFormat::Json => {
+            out = json_line(level, module, message, fields).expect("log line serializes to JSON");
+        }
+    }
+    out
+}
+
+/// One JSON object, keys in the order written: level, module, msg, then
+/// the fields.
+fn json_line<'a>(
+    level: LogLevel,
+    module: &str,
+    message: &str,
+    fields: impl Iterator<Item = &'a LogField>,
+) -> serde_json::Result<String> {
+    let mut line = serde_json::Serializer::new(Vec::new());
+    let mut map = line.serialize_map(None)?;
+    map.serialize_entry("level", level.as_str())?;
+    map.serialize_entry("module", module)?;
+    map.serialize_entry("msg", message)?;
+    for field in fields {
+        map.serialize_entry(field.name, &field.value)?;
+    }
+    map.end()?;
+    Ok(String::from_utf8(line.into_inner()).expect("serde_json writes UTF-8"))
+}
+
+/// `log!(level, "message")` or
+/// `log!(level, "message"; safe "bytes" => n, sensitive "peer" => addr)`.
+/// The message must be a literal; variable data goes in classified fields.
 #[macro_export]
 macro_rules! log {
-    ($level:expr, $($arg:tt)*) => {
-        if $level <= crate::logging::LOG_LEVEL {
-            println!($($arg)*);
+    (@field safe $name:literal => $value:expr) => {
+        $crate::logging::LogField {
+            name: $name,
+            value: format!("{}", $value),
+            class: $crate::logging::FieldClass::Safe,
+        }
+    };
+    (@field sensitive $name:literal => $value:expr) => {
+        $crate::logging::LogField {
+            name: $name,
+            value: format!("{}", $value),
+            class: $crate::logging::FieldClass::Sensitive,
         }
     };
-}
\ No newline at end of file
+    ($level:expr, $message:literal $(; $($class:ident $name:literal => $value:expr),+ $(,)?)?) => {
+        if $crate::logging::enabled($level, module_path!()) {
+            $crate::logging::emit(
+                $level,
+                module_path!(),
+                $message,
+                &[$($($crate::log!(@field $class $name => $value)),+)?],
+            );
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<LogField> {
+        vec![
+            LogField {
+                name: "bytes",
+                value: "42".to_string(),
+                class: FieldClass::Safe,
+            },
+            LogField {
+                name: "peer",
+                value: "203.0.113.7".to_string(),
+                class: FieldClass::Sensitive,
+            },
+        ]
+    }
+
+    #[test]
+    fn sensitive_fields_dropped_unless_included() {
+        let text = |include_sensitive| {
+            let fields = fields();
+            render(LogFormat::Text, LogLevel::Debug, "ebt::x", "closed", &fields, include_sensitive)
+        };
+        assert_eq!(text(false), "closed bytes=42");
+        assert_eq!(text(true), "closed bytes=42 peer=203.0.113.7");
+    }
+
+    #[test]
+    fn json_output_is_escaped() {
+        let message = "say \"hi\"\n";
+        let line = render(LogFormat::Json, LogLevel::Error, "ebt::x", message, &fields(), false);
+        assert_eq!(
+            line,
+            r#"{"level":"error","module":"ebt::x","msg":"say \"hi\"\n","bytes":"42"}"#
+        );
+
+        let host = "evil.example\",\"level\":\"trace\\\u{7}";
+        let field = LogField {
+            name: "host",
+            value: host.to_string(),
+            class: FieldClass::Sensitive,
+        };
+        let line = render(LogFormat::Json, LogLevel::Info, "ebt::x", "blocked", &[field], true);
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!((parsed["level"].as_str(), parsed["host"].as_str()), (Some("info"), Some(host)));
+    }
+
+    #[test]
+    fn module_levels_use_longest_prefix() {
+        let config = LogConfig::parse(
+            "info,real_proxy=debug,real_proxy::policy=trace,bogus",
+            LogFormat::Text,
+        );
+        assert_eq!(config.default_level, LogLevel::Info);
+        assert_eq!(config.level_for("ebt::real_proxy"), LogLevel::Debug);
+        assert_eq!(config.level_for("ebt::real_proxy::policy"), LogLevel::Trace);
+        assert_eq!(config.level_for("ebt::real_proxy_extra"), LogLevel::Info);
+        assert_eq!(config.level_for("ebt::dns"), LogLevel::Info);
+    }
+}
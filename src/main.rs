@@ -1,131 +1,16 @@
-#![allow(dead_code)]
-
-mod client;
-mod core;
-mod transport;
-mod ssh_transport;
-mod ssh_transport_adapter;
-mod dns;
-mod session;
-mod config;
-mod real_transport;
-mod real_proxy;
-mod real_dns;
-mod tls_wrapper;
-mod dns_resolver;
-mod relay_transport;
-mod logging;
-mod tunnel_stats;
-mod threat_invariants;
-mod attack_surfaces;
-mod trust_boundaries;
-mod prohibited_capabilities;
-mod threat_model_tests;
-mod crypto_transport_design;
-mod control_plane;
-mod data_plane;
-mod key_management;
-mod zone_interfaces;
-mod crypto_transport_tests;
-mod threat_model;
-mod traffic_shaping;
-mod relay_protocol;
-mod transport_adapter;
-mod protocol_engine;
-mod connection_mapping;
-mod binding_pump;
-mod anonymity;
-mod anonymity_protocol;
-mod anonymity_binding;
-mod content_policy;
-mod content_policy_bootstrap;
-#[cfg(test)]
-mod content_policy_invariants_tests;
-#[cfg(test)]
-mod anonymity_correlation_tests;
-#[cfg(test)]
-mod anonymity_regression_gate;
-#[cfg(feature = "encrypted_control")]
-mod control_channel;
-#[cfg(feature = "async_tunnel")]
-mod async_tunnel;
-
 use std::error::Error;
-use config::{ProxyPolicy, TunnelConfig};
-use crate::content_policy_bootstrap::build_content_policy_engine;
-use crate::anonymity::invariants::LegacyPhase;
+
+use clap::Parser;
+use encrypted_browser_tunnel::cli::{self, Cli};
 
 #[cfg(feature = "tokio")]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tokio_main().await
+    cli::run(Cli::parse()).await
 }
 
 #[cfg(not(feature = "tokio"))]
 fn main() -> Result<(), Box<dyn Error>> {
     let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(tokio_main())
-}
-
-async fn tokio_main() -> Result<(), Box<dyn Error>> {
-    println!("=== DIRECT CONNECT MODE (NO SSH) ===");
-    
-    // Phase 5 feature gate check
-    if traffic_shaping::PHASE_5_ENABLED {
-        println!("Phase 5 traffic shaping: ENABLED");
-        traffic_shaping::initialize_traffic_shaping();
-    } else {
-        println!("Phase 5 traffic shaping: DISABLED (Phase 4 invariants enforced)");
-    }
-    
-    // TEMPORARILY DISABLED FOR CONNECT DEBUGGING:
-    // Create tunnel session with SSH SOCKS configuration
-    // let config = ProxyConfig {
-    //     proxy_type: ProxyType::SshSocks,
-    //     address: "relay.example.com".to_string(),
-    //     port: 22,
-    // };
-    // 
-    // let capability_policy = CapabilityPolicy {
-    //     execution_mode: ExecutionMode::RealNetwork,
-    //     allowed_capabilities: vec![Capability::RealNetworking],
-    // };
-    // 
-    // let mut session = TunnelSession::new(config, capability_policy);
-    // 
-    // // Establish tunnel
-    // session.establish_tunnel().await?;
-    
-    // Start real proxy server
-    let use_profile = false;
-    let proxy_policy = if use_profile {
-        TunnelConfig::ssh_socks_profile().proxy_policy
-    } else {
-        ProxyPolicy::default()
-    };
-    
-    println!("\n=== Starting Real Network Mode ===");
-    // session.start_real_proxy(&proxy_policy)?;
-    
-    // Start accepting connections
-    let (policy_engine, policy_enabled) = build_content_policy_engine(&proxy_policy);
-    let mut real_proxy = crate::real_proxy::RealProxyServer::<LegacyPhase>::new(
-        proxy_policy.clone(),
-        policy_engine,
-        policy_enabled,
-    );
-    real_proxy.bind()?;
-    
-    // Optional transport warm-up (no DNS, no destinations)
-    if std::env::var("EBT_TRANSPORT_WARMUP").ok().as_deref() == Some("1") {
-        crate::relay_transport::warm_up_transport_resources();
-    }
-    
-    println!("\nReal proxy server ready!");
-    println!("Configure your browser to use proxy: 127.0.0.1:8080");
-    println!("Press Ctrl+C to stop the server");
-    
-    // Accept connections
-    real_proxy.accept_connections().await?;
-    Ok(())
+    rt.block_on(cli::run(Cli::parse()))
 }
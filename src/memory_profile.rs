@@ -0,0 +1,172 @@
+//! How much memory the proxy and relay set aside per tunnel and per cache.
+//! The default profile is sized for desktops and servers. The `embedded`
+//! feature swaps in a profile for OpenWrt-class routers with 64-128 MiB
+//! of RAM: smaller buffers, fewer concurrent tunnels and relay sessions,
+//! smaller caches and small thread stacks, at some cost in per-tunnel
+//! throughput and cache hit rate.
+//!
+//! `embedded` cannot be combined with `obs_dev`, whose per-rule hit
+//! counts and tunnel destinations grow with traffic.
+
+#[cfg(all(feature = "embedded", feature = "obs_dev"))]
+compile_error!("the `embedded` feature cannot be combined with `obs_dev`");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryProfile {
+    /// Read buffer of each direction of a tunnel.
+    pub tunnel_buffer: usize,
+    /// Stack of each blocking forwarding thread; `None` keeps the
+    /// platform default.
+    pub forward_thread_stack: Option<usize>,
+    /// Default `proxy.max_concurrent_tunnels`.
+    pub max_concurrent_tunnels: usize,
+    /// Largest data frame a relay client sends.
+    pub relay_chunk: usize,
+    /// Buffered between a circuit leg and the session inside it.
+    pub circuit_leg_buffer: usize,
+    /// Content-policy decisions cached per engine.
+    pub decision_cache_entries: usize,
+    /// Hostnames with a cached DoH answer.
+    pub doh_cache_entries: usize,
+    /// Events queued for each event bus subscriber.
+    pub event_bus_capacity: usize,
+    /// Events kept for `GET /ebt/events`.
+    pub recent_events: usize,
+    /// Closed tunnels summarised by `GET /ebt/tunnels/summary`.
+    pub tunnel_records: usize,
+    /// Origins with cached client TLS sessions.
+    pub tls_origins: usize,
+    /// Policy decisions kept for `GET /ebt/decisions`.
+    pub policy_audit_entries: usize,
+    /// `default_relay_limits().max_connections`.
+    pub relay_max_connections: usize,
+    /// `default_relay_limits().max_inflight_opens`.
+    pub relay_max_inflight_opens: usize,
+    /// `default_relay_limits().max_buffered_bytes`.
+    pub relay_max_buffered_bytes: usize,
+    /// Destinations an exit rate-limits before idle ones are forgotten.
+    pub exit_tracked_destinations: usize,
+}
+
+/// Platform default thread stack, for estimates only.
+const DEFAULT_THREAD_STACK: usize = 2 * 1024 * 1024;
+/// Upper bounds on one cached entry, key included.
+const DECISION_ENTRY_BYTES: usize = 512;
+const DOH_ENTRY_BYTES: usize = 1024;
+const EVENT_BYTES: usize = 64;
+const TUNNEL_RECORD_BYTES: usize = 48;
+/// An OBS_DEV entry with its host and rule target.
+const POLICY_AUDIT_ENTRY_BYTES: usize = 384;
+/// Eight tickets, each holding the server's certificate chain.
+const TLS_ORIGIN_BYTES: usize = 32 * 1024;
+
+impl MemoryProfile {
+    pub const DEFAULT: Self = Self {
+        tunnel_buffer: 64 * 1024,
+        forward_thread_stack: None,
+        max_concurrent_tunnels: 256,
+        relay_chunk: 16 * 1024,
+        circuit_leg_buffer: 256 * 1024,
+        decision_cache_entries: 4096,
+        doh_cache_entries: 4096,
+        event_bus_capacity: 1024,
+        recent_events: 256,
+        tunnel_records: 1024,
+        tls_origins: 256,
+        policy_audit_entries: 256,
+        relay_max_connections: 256,
+        relay_max_inflight_opens: 32,
+        relay_max_buffered_bytes: 256 * 1024,
+        exit_tracked_destinations: 4096,
+    };
+
+    pub const EMBEDDED: Self = Self {
+        tunnel_buffer: 8 * 1024,
+        forward_thread_stack: Some(128 * 1024),
+        max_concurrent_tunnels: 32,
+        relay_chunk: 4 * 1024,
+        circuit_leg_buffer: 32 * 1024,
+        decision_cache_entries: 256,
+        doh_cache_entries: 256,
+        event_bus_capacity: 64,
+        recent_events: 32,
+        tunnel_records: 128,
+        tls_origins: 16,
+        policy_audit_entries: 32,
+        relay_max_connections: 32,
+        relay_max_inflight_opens: 8,
+        relay_max_buffered_bytes: 64 * 1024,
+        exit_tracked_destinations: 512,
+    };
+
+    /// The profile this build was compiled with.
+    pub const fn active() -> Self {
+        if cfg!(feature = "embedded") {
+            Self::EMBEDDED
+        } else {
+            Self::DEFAULT
+        }
+    }
+
+    /// Most the proxy holds in these buffers and caches with every
+    /// tunnel open and every cache full, counting forwarding stacks.
+    pub fn proxy_ceiling_bytes(&self) -> usize {
+        let stacks = 2 * self.forward_thread_stack.unwrap_or(DEFAULT_THREAD_STACK);
+        let per_tunnel = 2 * self.tunnel_buffer + self.relay_chunk + self.circuit_leg_buffer + stacks;
+        self.max_concurrent_tunnels * per_tunnel
+            + self.decision_cache_entries * DECISION_ENTRY_BYTES
+            + self.doh_cache_entries * DOH_ENTRY_BYTES
+            + (self.event_bus_capacity + self.recent_events) * EVENT_BYTES
+            + self.tunnel_records * TUNNEL_RECORD_BYTES
+            + self.tls_origins * TLS_ORIGIN_BYTES
+            + self.policy_audit_entries * POLICY_AUDIT_ENTRY_BYTES
+    }
+
+    /// Most a relay buffers toward clients with every session open.
+    pub fn relay_ceiling_bytes(&self) -> usize {
+        self.relay_max_connections * self.relay_max_buffered_bytes
+    }
+}
+
+/// Memory the OS reports as available for new allocations; Linux only.
+pub fn available_memory() -> Option<usize> {
+    mem_available(&std::fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn mem_available(meminfo: &str) -> Option<usize> {
+    let line = meminfo.lines().find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kib: usize = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    kib.checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the embedded profile may use on a 64 MiB router, leaving the
+    /// rest to the OS and the rest of the firmware.
+    const EMBEDDED_PROXY_CEILING: usize = 16 * 1024 * 1024;
+    const EMBEDDED_RELAY_CEILING: usize = 4 * 1024 * 1024;
+
+    #[test]
+    fn embedded_profile_stays_under_its_memory_ceiling() {
+        let embedded = MemoryProfile::EMBEDDED;
+        assert!(
+            embedded.proxy_ceiling_bytes() <= EMBEDDED_PROXY_CEILING,
+            "proxy worst case is {} bytes",
+            embedded.proxy_ceiling_bytes()
+        );
+        assert!(embedded.relay_ceiling_bytes() <= EMBEDDED_RELAY_CEILING);
+        assert!(embedded.proxy_ceiling_bytes() * 8 < MemoryProfile::DEFAULT.proxy_ceiling_bytes());
+        // A forwarding thread keeps its read buffer on its stack.
+        assert!(embedded.forward_thread_stack.unwrap() >= 4 * embedded.tunnel_buffer);
+        assert_eq!(MemoryProfile::active() == MemoryProfile::EMBEDDED, cfg!(feature = "embedded"));
+    }
+
+    #[test]
+    fn reads_available_memory_from_meminfo() {
+        let meminfo = "MemTotal:       16303460 kB\nMemFree:         1022380 kB\nMemAvailable:    9031644 kB\n";
+        assert_eq!(mem_available(meminfo), Some(9_031_644 * 1024));
+        assert_eq!(mem_available("MemTotal:       16303460 kB\n"), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Notices the machine moving between networks (Wi-Fi to Ethernet, a VPN
+//! coming up or going down) so the proxy can drop state tied to the old
+//! path at once instead of waiting for TCP timeouts.
+//!
+//! The route to the internet is sampled every `POLL_INTERVAL` by asking
+//! the OS which local address it would send from: connecting a UDP socket
+//! picks a route without sending a packet. A changed v4 address, or a v6
+//! address outside the old one's /64, is a network change; temporary v6
+//! addresses rotate within their /64 while the path stays put. This works
+//! the same on Linux, macOS and Windows and needs no privileges; a change
+//! that keeps the same source address or prefix, such as a VPN that
+//! reuses it, goes unseen.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Public resolvers, only used to pick a route; nothing is sent to them.
+const ROUTE_PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 53);
+const ROUTE_PROBE_V6: SocketAddr =
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)), 53);
+
+/// The local addresses the routing table picks for each family; `None`
+/// when the family has no route.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkPath {
+    pub v4: Option<IpAddr>,
+    pub v6: Option<IpAddr>,
+}
+
+impl NetworkPath {
+    pub fn current() -> Self {
+        Self {
+            v4: route_source(ROUTE_PROBE_V4),
+            v6: route_source(ROUTE_PROBE_V6),
+        }
+    }
+
+    /// The same v4 source, and a v6 source in the same /64.
+    fn same_network(&self, other: &Self) -> bool {
+        let v6_prefix = |source: Option<IpAddr>| match source {
+            Some(IpAddr::V6(addr)) => Some(addr.octets()[..8].to_vec()),
+            _ => None,
+        };
+        self.v4 == other.v4 && v6_prefix(self.v6) == v6_prefix(other.v6)
+    }
+}
+
+fn route_source(target: SocketAddr) -> Option<IpAddr> {
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).ok()?;
+    socket.connect(target).ok()?;
+    let source = socket.local_addr().ok()?.ip();
+    (!source.is_unspecified()).then_some(source)
+}
+
+/// Reports when the sampled path differs from the last one.
+#[derive(Debug)]
+pub struct NetworkWatch {
+    last: NetworkPath,
+}
+
+impl NetworkWatch {
+    pub fn new(initial: NetworkPath) -> Self {
+        Self { last: initial }
+    }
+
+    /// True when `path` is on another network than the one last observed.
+    pub fn observe(&mut self, path: NetworkPath) -> bool {
+        !std::mem::replace(&mut self.last, path).same_network(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_change_once() {
+        let wifi = NetworkPath {
+            v4: Some("192.168.1.20".parse().unwrap()),
+            v6: None,
+        };
+        let vpn = NetworkPath {
+            v4: Some("10.8.0.2".parse().unwrap()),
+            ..wifi
+        };
+        let mut watch = NetworkWatch::new(wifi);
+        assert!(!watch.observe(wifi));
+        assert!(watch.observe(vpn));
+        assert!(!watch.observe(vpn));
+        assert!(watch.observe(NetworkPath::default()), "losing every route is a change");
+        assert!(watch.observe(wifi));
+
+        // Privacy extensions rotate the temporary address inside the /64.
+        let dual_stack = |v6: &str| NetworkPath { v6: Some(v6.parse().unwrap()), ..wifi };
+        let mut watch = NetworkWatch::new(dual_stack("2001:db8:1:2:a1b2:c3d4:e5f6:1"));
+        assert!(!watch.observe(dual_stack("2001:db8:1:2:9f8e:7d6c:5b4a:2")));
+        assert!(watch.observe(dual_stack("2001:db8:1:3::2")));
+        assert!(watch.observe(wifi), "losing the v6 route is a change");
+
+        // Loopback always has a route, and it never leaves the machine.
+        assert_eq!(route_source("127.0.0.1:53".parse().unwrap()), Some("127.0.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,278 @@
+//! Onion layers for middle relays. The client wraps each forward cell once
+//! per middle relay; a middle peels exactly its own layer, learning only
+//! the next hop's address and an opaque inner payload, and seals what
+//! comes back so only the client can read it.
+//!
+//! A layer is ChaCha20-Poly1305 under the relay's layer key with a random
+//! nonce. The direction label and the cell's sequence number are bound in
+//! as associated data, so cells cannot be replayed, reordered or reflected
+//! within a circuit.
+//!
+//! Forward plaintext: host length (u8), host, port (u16 BE), inner bytes.
+//! Backward plaintext: inner bytes.
+//!
+//! Circuits agree a fresh layer key per middle with `LayerKeyExchange`:
+//! X25519 between ephemeral keys, HKDF-SHA256 salted with the circuit's
+//! session id. The exchange runs inside the client's TLS session with
+//! that middle, which authenticates the relay. A relay's configured key
+//! only serves sessions that skip the exchange.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+
+/// Nonce and tag added by each layer.
+pub const LAYER_OVERHEAD: usize = NONCE_LEN + 16;
+
+const AAD_FORWARD: &[u8] = b"ebt-onion-fwd";
+const AAD_BACKWARD: &[u8] = b"ebt-onion-back";
+const KEY_EXCHANGE_INFO: &[u8] = b"ebt-circuit-layer";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum OnionError {
+    #[error("Onion layer failed authentication")]
+    OpenFailed,
+    #[error("Onion layer encryption failed")]
+    SealFailed,
+    #[error("Malformed onion layer")]
+    Malformed,
+    #[error("Next hop host is empty or longer than 255 bytes")]
+    InvalidNextHop,
+    #[error("Layer key exchange failed")]
+    KeyExchangeFailed,
+}
+
+/// Where a middle relay sends the inner payload. Always another relay,
+/// never the client's destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NextHop {
+    pub host: String,
+    pub port: u16,
+}
+
+pub struct LayerKey {
+    key: LessSafeKey,
+}
+
+impl std::fmt::Debug for LayerKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LayerKey(..)")
+    }
+}
+
+impl LayerKey {
+    pub fn new(key_bytes: &[u8; 32]) -> Self {
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, key_bytes)
+            .expect("chacha20-poly1305 accepts 32-byte keys");
+        Self {
+            key: LessSafeKey::new(unbound),
+        }
+    }
+
+    /// Client side: one forward layer around `inner`.
+    pub fn seal_forward(&self, seq: u64, next_hop: &NextHop, inner: &[u8]) -> Result<Vec<u8>, OnionError> {
+        let host = next_hop.host.as_bytes();
+        if host.is_empty() || host.len() > u8::MAX as usize {
+            return Err(OnionError::InvalidNextHop);
+        }
+        let mut plaintext = Vec::with_capacity(3 + host.len() + inner.len());
+        plaintext.push(host.len() as u8);
+        plaintext.extend_from_slice(host);
+        plaintext.extend_from_slice(&next_hop.port.to_be_bytes());
+        plaintext.extend_from_slice(inner);
+        self.seal(AAD_FORWARD, seq, plaintext)
+    }
+
+    /// Middle side: removes this relay's layer.
+    pub fn peel_forward(&self, seq: u64, sealed: &[u8]) -> Result<(NextHop, Vec<u8>), OnionError> {
+        let plaintext = self.open(AAD_FORWARD, seq, sealed)?;
+        let (&host_len, rest) = plaintext.split_first().ok_or(OnionError::Malformed)?;
+        let host_len = host_len as usize;
+        if host_len == 0 || rest.len() < host_len + 2 {
+            return Err(OnionError::Malformed);
+        }
+        let host = std::str::from_utf8(&rest[..host_len]).map_err(|_| OnionError::Malformed)?;
+        let port = u16::from_be_bytes([rest[host_len], rest[host_len + 1]]);
+        let next_hop = NextHop {
+            host: host.to_string(),
+            port,
+        };
+        Ok((next_hop, rest[host_len + 2..].to_vec()))
+    }
+
+    /// Middle side: one backward layer around what the next hop sent.
+    pub fn seal_backward(&self, seq: u64, inner: &[u8]) -> Result<Vec<u8>, OnionError> {
+        self.seal(AAD_BACKWARD, seq, inner.to_vec())
+    }
+
+    /// Client side: removes one backward layer.
+    pub fn open_backward(&self, seq: u64, sealed: &[u8]) -> Result<Vec<u8>, OnionError> {
+        self.open(AAD_BACKWARD, seq, sealed)
+    }
+
+    fn seal(&self, label: &[u8], seq: u64, mut in_out: Vec<u8>) -> Result<Vec<u8>, OnionError> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(aad(label, seq)), &mut in_out)
+            .map_err(|_| OnionError::SealFailed)?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + in_out.len());
+        sealed.extend_from_slice(&nonce_bytes);
+        sealed.extend_from_slice(&in_out);
+        Ok(sealed)
+    }
+
+    fn open(&self, label: &[u8], seq: u64, sealed: &[u8]) -> Result<Vec<u8>, OnionError> {
+        if sealed.len() < LAYER_OVERHEAD {
+            return Err(OnionError::OpenFailed);
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| OnionError::OpenFailed)?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce, Aad::from(aad(label, seq)), &mut in_out)
+            .map_err(|_| OnionError::OpenFailed)?
+            .len();
+        in_out.truncate(plaintext_len);
+        Ok(in_out)
+    }
+}
+
+/// One side of a layer key agreement. Both sides derive the same key.
+pub struct LayerKeyExchange {
+    private: EphemeralPrivateKey,
+    public: [u8; 32],
+}
+
+impl std::fmt::Debug for LayerKeyExchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LayerKeyExchange(..)")
+    }
+}
+
+impl LayerKeyExchange {
+    pub fn new() -> Result<Self, OnionError> {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| OnionError::KeyExchangeFailed)?;
+        let public = private
+            .compute_public_key()
+            .ok()
+            .and_then(|public| public.as_ref().try_into().ok())
+            .ok_or(OnionError::KeyExchangeFailed)?;
+        Ok(Self { private, public })
+    }
+
+    /// Sent to the other side.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    pub fn finish(self, peer_public_key: &[u8], session_id: &[u8; 32]) -> Result<LayerKey, OnionError> {
+        let peer = UnparsedPublicKey::new(&X25519, peer_public_key);
+        agreement::agree_ephemeral(self.private, &peer, |shared| {
+            let mut key = [0u8; 32];
+            Salt::new(HKDF_SHA256, session_id)
+                .extract(shared)
+                .expand(&[KEY_EXCHANGE_INFO], HKDF_SHA256)
+                .and_then(|okm| okm.fill(&mut key))
+                .map(|()| LayerKey::new(&key))
+        })
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(OnionError::KeyExchangeFailed)
+    }
+}
+
+fn aad(label: &[u8], seq: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(label.len() + 8);
+    aad.extend_from_slice(label);
+    aad.extend_from_slice(&seq.to_be_bytes());
+    aad
+}
+
+/// 64 hex digits, as stored in a relay's layer key file.
+pub fn parse_layer_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// A fixed layer key for tests of sessions that skip the exchange.
+#[cfg(test)]
+pub(crate) const TEST_LAYER_KEY: [u8; 32] = [0x3c; 32];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hop(host: &str) -> NextHop {
+        NextHop {
+            host: host.to_string(),
+            port: 9001,
+        }
+    }
+
+    #[test]
+    fn each_relay_peels_only_its_layer() {
+        let first = LayerKey::new(&[1; 32]);
+        let second = LayerKey::new(&[2; 32]);
+        let inner = second.seal_forward(0, &hop("exit.example"), b"cell").unwrap();
+        let outer = first.seal_forward(0, &hop("middle-2.example"), &inner).unwrap();
+
+        assert_eq!(second.peel_forward(0, &outer), Err(OnionError::OpenFailed));
+        let (next, peeled) = first.peel_forward(0, &outer).unwrap();
+        assert_eq!(next, hop("middle-2.example"));
+        assert_eq!(peeled, inner);
+        assert_eq!(second.peel_forward(0, &peeled).unwrap(), (hop("exit.example"), b"cell".to_vec()));
+    }
+
+    #[test]
+    fn sequence_and_direction_are_bound() {
+        let key = LayerKey::new(&TEST_LAYER_KEY);
+        let cell = key.seal_forward(3, &hop("next.example"), b"x").unwrap();
+        assert_eq!(key.peel_forward(4, &cell), Err(OnionError::OpenFailed));
+        assert_eq!(key.open_backward(3, &cell), Err(OnionError::OpenFailed));
+
+        let back = key.seal_backward(0, b"reply").unwrap();
+        assert_eq!(key.open_backward(0, &back).unwrap(), b"reply");
+        assert_eq!(back.len(), b"reply".len() + LAYER_OVERHEAD);
+    }
+
+    #[test]
+    fn exchanged_keys_match_and_bind_the_session() {
+        let session_id = [9u8; 32];
+        let client = LayerKeyExchange::new().unwrap();
+        let relay = LayerKeyExchange::new().unwrap();
+        let (client_public, relay_public) = (client.public_key(), relay.public_key());
+        let client_key = client.finish(&relay_public, &session_id).unwrap();
+        let relay_key = relay.finish(&client_public, &session_id).unwrap();
+
+        let cell = client_key.seal_forward(0, &hop("next.example"), b"x").unwrap();
+        assert_eq!(relay_key.peel_forward(0, &cell).unwrap(), (hop("next.example"), b"x".to_vec()));
+
+        let other = LayerKeyExchange::new().unwrap();
+        let other_key = other.finish(&client_public, &[0u8; 32]).unwrap();
+        assert_eq!(other_key.peel_forward(0, &cell), Err(OnionError::OpenFailed));
+        assert!(LayerKeyExchange::new().unwrap().finish(&[1, 2, 3], &session_id).is_err());
+    }
+
+    #[test]
+    fn layer_keys_parse_from_hex() {
+        assert_eq!(parse_layer_key(&"3c".repeat(32)), Some(TEST_LAYER_KEY));
+        assert_eq!(parse_layer_key("3c3c"), None);
+        assert_eq!(parse_layer_key(&"zz".repeat(32)), None);
+    }
+}
@@ -0,0 +1,70 @@
+//! Runs the platform tools that change OS settings (`reg`, `netsh`,
+//! `networksetup`, `gsettings`, `nft`), raise notifications or capture
+//! packets, for `system_proxy`, `kill_switch`, `desktop_notifications`
+//! and `leak_test`. Commands are plain argument lists, so the code that
+//! builds them can be tested without running anything.
+
+use std::process::{Child, Stdio};
+
+#[derive(Debug, thiserror::Error)]
+#[error("`{command}` failed: {reason}")]
+pub struct CommandError {
+    pub command: String,
+    pub reason: String,
+}
+
+/// Reads OS settings; `run` in production, canned output in tests.
+pub(crate) type Query<'a> = &'a dyn Fn(&[String]) -> Result<String, CommandError>;
+
+pub(crate) fn command(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|part| part.to_string()).collect()
+}
+
+/// Runs `command`, returning its stdout; a nonzero exit is an error.
+pub(crate) fn run(command: &[String]) -> Result<String, CommandError> {
+    let failed = |reason: String| CommandError {
+        command: command.join(" "),
+        reason,
+    };
+    let (program, args) = command.split_first().ok_or_else(|| failed("empty command".to_string()))?;
+    let output = std::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Starts `command` in the background with stdout piped, for tools that
+/// run until stopped.
+pub(crate) fn spawn(command: &[String]) -> Result<Child, CommandError> {
+    let (program, args) = command.split_first().ok_or_else(|| CommandError {
+        command: String::new(),
+        reason: "empty command".to_string(),
+    })?;
+    std::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| CommandError {
+            command: command.join(" "),
+            reason: e.to_string(),
+        })
+}
+
+/// Runs every command, returning the first failure.
+pub(crate) fn run_all(commands: &[Vec<String>]) -> Result<(), CommandError> {
+    commands
+        .iter()
+        .map(|command| run(command).map(drop))
+        .fold(Ok(()), Result::and)
+}
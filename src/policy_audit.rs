@@ -0,0 +1,183 @@
+//! The most recent content-policy decisions, for `GET /ebt/decisions`, so
+//! "why was this site blocked?" has an answer without persistent logging.
+//! Each entry holds when the CONNECT was decided, the outcome and the rule
+//! that matched, by its index in the listener's rules. Hosts are kept only
+//! under OBS_DEV; otherwise an entry holds a hash keyed per process, which
+//! `?host=` recomputes to find a site's decisions, so the ring cannot be
+//! read back into a browsing history. Nothing is kept under OBS_NONE.
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::content_policy::ReasonCode;
+use crate::core::observability::{OBS_DEV, OBS_NONE};
+use crate::memory_profile::MemoryProfile;
+use crate::tunnel_registry::json_escape;
+
+const POLICY_AUDIT_ENTRIES: usize = MemoryProfile::active().policy_audit_entries;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Allowed,
+    Blocked(ReasonCode),
+    /// Blocked by the rules but let through by dry-run mode.
+    WouldBlock(ReasonCode),
+    /// Blocked by the rules but let through by a runtime bypass.
+    Bypassed(ReasonCode),
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Allowed => "allowed",
+            AuditOutcome::Blocked(_) => "blocked",
+            AuditOutcome::WouldBlock(_) => "would_block",
+            AuditOutcome::Bypassed(_) => "bypassed",
+        }
+    }
+
+    fn reason(&self) -> Option<ReasonCode> {
+        match self {
+            AuditOutcome::Allowed => None,
+            AuditOutcome::Blocked(reason) | AuditOutcome::WouldBlock(reason) | AuditOutcome::Bypassed(reason) => {
+                Some(*reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AuditEntry {
+    at_unix_ms: u64,
+    host_hash: u64,
+    /// Only recorded under OBS_DEV, like the rule target.
+    host: Option<String>,
+    port: u16,
+    outcome: AuditOutcome,
+    rule: Option<usize>,
+    rule_target: Option<String>,
+}
+
+/// Decisions of one listener, oldest first.
+#[derive(Debug)]
+pub struct PolicyAudit {
+    hasher: RandomState,
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl Default for PolicyAudit {
+    fn default() -> Self {
+        Self::with_capacity(POLICY_AUDIT_ENTRIES)
+    }
+}
+
+impl PolicyAudit {
+    /// Keeps the `capacity` most recent decisions.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            hasher: RandomState::new(),
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn host_hash(&self, host: &str) -> u64 {
+        self.hasher.hash_one(host.trim_end_matches('.').to_ascii_lowercase())
+    }
+
+    /// `rule` is the matched rule's index, `rule_target` its configured pattern.
+    pub fn record(&self, host: &str, port: u16, outcome: AuditOutcome, rule: Option<usize>, rule_target: Option<&str>) {
+        if OBS_NONE || self.capacity == 0 {
+            return;
+        }
+        let at_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let entry = AuditEntry {
+            at_unix_ms,
+            host_hash: self.host_hash(host),
+            host: OBS_DEV.then(|| host.to_string()),
+            port,
+            outcome,
+            rule,
+            rule_target: rule_target.filter(|_| OBS_DEV).map(str::to_string),
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// JSON body for the admin endpoint, limited to `host` when given.
+    pub fn to_json(&self, host: Option<&str>) -> String {
+        let wanted = host.map(|host| self.host_hash(host));
+        let Ok(entries) = self.entries.lock() else {
+            return "{\"decisions\":[]}".to_string();
+        };
+        let decisions: Vec<String> = entries
+            .iter()
+            .filter(|entry| wanted.is_none_or(|hash| entry.host_hash == hash))
+            .map(entry_json)
+            .collect();
+        format!("{{\"decisions\":[{}]}}", decisions.join(","))
+    }
+}
+
+fn entry_json(entry: &AuditEntry) -> String {
+    let mut out = format!(
+        "{{\"at_unix_ms\":{},\"host_hash\":\"{:016x}\",\"port\":{},\"outcome\":\"{}\"",
+        entry.at_unix_ms,
+        entry.host_hash,
+        entry.port,
+        entry.outcome.as_str()
+    );
+    if let Some(reason) = entry.outcome.reason() {
+        out.push_str(&format!(",\"reason\":\"{}\"", reason.as_str()));
+    }
+    if let Some(rule) = entry.rule {
+        out.push_str(&format!(",\"rule\":{}", rule));
+    }
+    if let Some(target) = &entry.rule_target {
+        out.push_str(&format!(",\"rule_target\":\"{}\"", json_escape(target)));
+    }
+    if let Some(host) = &entry.host {
+        out.push_str(&format!(",\"host\":\"{}\"", json_escape(host)));
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_recent_decisions_and_finds_them_by_host() {
+        let audit = PolicyAudit::with_capacity(2);
+        audit.record("old.example", 443, AuditOutcome::Allowed, None, None);
+        audit.record("ads.example", 443, AuditOutcome::Blocked(ReasonCode::Ads), Some(3), Some("ads.example"));
+        audit.record("news.example", 443, AuditOutcome::WouldBlock(ReasonCode::Tracking), Some(0), None);
+
+        let json = audit.to_json(None);
+        if OBS_NONE {
+            assert_eq!(json, "{\"decisions\":[]}");
+            return;
+        }
+        assert!(!json.contains("old.example"));
+        assert_eq!(json.matches("\"outcome\"").count(), 2);
+        assert_eq!(json.contains("\"host\":\"news.example\""), OBS_DEV);
+
+        let blocked = audit.to_json(Some("ADS.example."));
+        assert_eq!(blocked.matches("\"outcome\"").count(), 1);
+        assert!(blocked.contains("\"outcome\":\"blocked\",") && blocked.contains("\"reason\":\"ads\",\"rule\":3"));
+        assert_eq!(blocked.contains("\"rule_target\":\"ads.example\""), OBS_DEV);
+        assert_eq!(audit.to_json(Some("old.example")), "{\"decisions\":[]}");
+    }
+}
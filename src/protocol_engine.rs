@@ -1,30 +1,58 @@
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
 use crate::anonymity::invariants::AllowsRelayLocalLinkability;
 use crate::relay_protocol::{
     FrameEncoder, FrameDecoder, LegacyControlMessage, LegacyDataFrame, 
-    ConnectionTable, RelayLimits, ProtocolNegotiator
+    ConnectionState, ConnectionTable, OutboundOverflow, ProtocolError, RelayLimits, RelayMetrics,
+    ProtocolNegotiator
 };
 use crate::transport_adapter::{TransportCallbacks, TransportError};
 use crate::core::observability;
 use std::io::Cursor;
 
+/// What the peer did to a logical connection, for the binding to carry to
+/// the browser socket mapped to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InboundEvent {
+    Data { conn_id: u32, payload: Vec<u8> },
+    /// The connection is gone, closed by either side; so is its socket.
+    Closed { conn_id: u32 },
+}
+
+/// Frames waiting for one connection's transport.
+#[derive(Default)]
+struct OutboundQueue {
+    /// Each frame with, for data frames, the credits it spent.
+    frames: VecDeque<(Vec<u8>, Option<u32>)>,
+    bytes: usize,
+}
+
 pub struct ProtocolEngine<Phase: AllowsRelayLocalLinkability> {
-    connection_table: ConnectionTable,
+    connection_table: Arc<ConnectionTable>,
     negotiator: ProtocolNegotiator,
-    outbound_frames: HashMap<u32, Vec<Vec<u8>>>,
+    outbound_frames: HashMap<u32, OutboundQueue>,
+    max_outbound_bytes: usize,
+    outbound_overflow: OutboundOverflow,
     frame_buffers: HashMap<u32, Vec<u8>>,
+    inbound_events: VecDeque<InboundEvent>,
+    ready_connections: Vec<u32>,
+    frames_queued: Option<Box<dyn Fn() + Send>>,
     _phase: PhantomData<Phase>,
 }
 
 impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
     pub fn new(limits: RelayLimits) -> Self {
         Self {
-            connection_table: ConnectionTable::new(limits),
+            max_outbound_bytes: limits.max_outbound_bytes,
+            outbound_overflow: limits.outbound_overflow,
+            connection_table: Arc::new(ConnectionTable::new(limits)),
             negotiator: ProtocolNegotiator::new(),
             outbound_frames: HashMap::new(),
             frame_buffers: HashMap::new(),
+            inbound_events: VecDeque::new(),
+            ready_connections: Vec::new(),
+            frames_queued: None,
             _phase: PhantomData,
         }
     }
@@ -62,13 +90,90 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
                         self.process_data_frame(data_frame);
                     }
                 }
+                crate::relay_protocol::FrameType::Padding
+                | crate::relay_protocol::FrameType::Ping
+                | crate::relay_protocol::FrameType::Pong
+                | crate::relay_protocol::FrameType::Onion
+                | crate::relay_protocol::FrameType::Circuit
+                | crate::relay_protocol::FrameType::Datagram
+                | crate::relay_protocol::FrameType::Dns => {}
             }
         }
     }
     
     #[deprecated(note = "Phase 9 forbids direct FIFO dequeue per connection; timing must be mixed/delayed.")]
     pub fn next_outbound_frame(&mut self, conn_id: u32) -> Option<Vec<u8>> {
-        self.outbound_frames.get_mut(&conn_id)?.pop()
+        let queue = self.outbound_frames.get_mut(&conn_id)?;
+        let (frame, _) = queue.frames.pop_front()?;
+        queue.bytes -= frame.len();
+        Some(frame)
+    }
+
+    /// Opens `conn_id` toward the target. Data can be queued once the peer
+    /// acknowledges with a `WindowUpdate`.
+    pub fn open_logical_connection(
+        &mut self,
+        conn_id: u32,
+        target_host: String,
+        target_port: u16,
+    ) -> Result<(), ProtocolError> {
+        self.connection_table.open_connection(conn_id)?;
+        observability::record_connection_opened();
+        self.queue_control_message(conn_id, LegacyControlMessage::Open { conn_id, target_host, target_port });
+        Ok(())
+    }
+
+    /// The browser side of `conn_id` is done: the peer is told and the
+    /// connection forgotten.
+    pub fn close_logical_connection(&mut self, conn_id: u32) {
+        if self.forget_connection(conn_id) {
+            self.queue_control_message(conn_id, LegacyControlMessage::Close { conn_id, reason: 0 });
+        }
+    }
+
+    pub fn metrics(&self) -> RelayMetrics {
+        self.connection_table.metrics()
+    }
+
+    pub fn connection_state(&self, conn_id: u32) -> Option<ConnectionState> {
+        self.connection_table.get_state(conn_id)
+    }
+
+    /// The engine's connections, readable without its lock: a binding can
+    /// wait for credits on one connection without holding up the others.
+    /// Credits are only spent and granted under the engine's lock.
+    pub fn connection_table(&self) -> Arc<ConnectionTable> {
+        Arc::clone(&self.connection_table)
+    }
+
+    /// Takes what arrived since the last call, in order.
+    pub fn poll_inbound_events(&mut self) -> Vec<InboundEvent> {
+        self.inbound_events.drain(..).collect()
+    }
+    
+    /// Called, under the engine's lock, whenever a connection's outbound
+    /// queue stops being empty, so a binding can sleep until there is
+    /// something to send. Such connections are then listed by
+    /// `take_ready_connections`.
+    pub fn on_frames_queued(&mut self, notify: impl Fn() + Send + 'static) {
+        self.frames_queued = Some(Box::new(notify));
+    }
+
+    /// Connections whose queues stopped being empty since the last call.
+    pub fn take_ready_connections(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.ready_connections)
+    }
+
+    fn push_outbound(&mut self, conn_id: u32, frame: Vec<u8>, credits: Option<u32>) {
+        let queue = self.outbound_frames.entry(conn_id).or_default();
+        queue.bytes += frame.len();
+        queue.frames.push_back((frame, credits));
+        if queue.frames.len() == 1 {
+            if let Some(notify) = &self.frames_queued {
+                self.ready_connections.push(conn_id);
+                notify();
+            }
+        }
     }
     
     pub fn queue_control_message(&mut self, conn_id: u32, message: LegacyControlMessage) {
@@ -80,7 +185,7 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
             crate::relay_protocol::FrameType::Control, 
             &payload
         ).is_ok() {
-            self.outbound_frames.entry(conn_id).or_insert_with(Vec::new).push(buffer);
+            self.push_outbound(conn_id, buffer, None);
         }
     }
     
@@ -100,8 +205,11 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
             crate::relay_protocol::FrameType::Data,
             &payload
         ).is_ok() {
-            self.connection_table.consume_send_credits(conn_id, data.len() as u32)?;
-            self.outbound_frames.entry(conn_id).or_insert_with(Vec::new).push(buffer);
+            self.make_outbound_room(conn_id, buffer.len())?;
+            self.connection_table
+                .consume_send_credits(conn_id, data.len() as u32)
+                .map_err(|_| "Insufficient credits")?;
+            self.push_outbound(conn_id, buffer, Some(data.len() as u32));
             Ok(())
         } else {
             Err("Frame encoding failed")
@@ -116,7 +224,7 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
                 LegacyControlMessage::Close { conn_id, .. } => *conn_id,
                 LegacyControlMessage::WindowUpdate { conn_id, .. } => *conn_id,
                 LegacyControlMessage::Error { conn_id, .. } => *conn_id,
-                LegacyControlMessage::Hello { .. } => 0,
+                LegacyControlMessage::Hello { .. } | LegacyControlMessage::GoAway { .. } | LegacyControlMessage::Resume { .. } => 0,
             };
             self.queue_control_message(conn_id, frame.clone());
         }
@@ -126,12 +234,58 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
                 LegacyControlMessage::Close { conn_id, .. } => *conn_id,
                 LegacyControlMessage::WindowUpdate { conn_id, .. } => *conn_id,
                 LegacyControlMessage::Error { conn_id, .. } => *conn_id,
-                LegacyControlMessage::Hello { .. } => 0,
+                LegacyControlMessage::Hello { .. } | LegacyControlMessage::GoAway { .. } | LegacyControlMessage::Resume { .. } => 0,
             };
             (conn_id, msg)
         }).collect()
     }
     
+    /// Applies the overflow policy when `frame_len` more bytes would take
+    /// the queue of `conn_id` past its limit. A lone frame always fits, so
+    /// no frame is refused forever.
+    fn make_outbound_room(&mut self, conn_id: u32, frame_len: usize) -> Result<(), &'static str> {
+        let Some(queue) = self.outbound_frames.get_mut(&conn_id) else {
+            return Ok(());
+        };
+        if queue.frames.is_empty() || queue.bytes + frame_len <= self.max_outbound_bytes {
+            return Ok(());
+        }
+        match self.outbound_overflow {
+            OutboundOverflow::BlockProducer => {
+                self.connection_table.record_outbound_blocked();
+                Err("Outbound queue full")
+            }
+            OutboundOverflow::DropOldestData => {
+                let (mut index, mut dropped, mut credits) = (0, 0, 0);
+                while queue.bytes + frame_len > self.max_outbound_bytes && index < queue.frames.len() {
+                    match queue.frames[index] {
+                        (_, Some(spent)) => {
+                            let (frame, _) = queue.frames.remove(index).expect("index is in bounds");
+                            queue.bytes -= frame.len();
+                            dropped += 1;
+                            credits += spent;
+                        }
+                        // Control frames are never dropped
+                        (_, None) => index += 1,
+                    }
+                }
+                // The peer never sees those bytes, so never grants them back
+                let _ = self.connection_table.add_send_credits(conn_id, credits);
+                self.connection_table.record_outbound_dropped(dropped);
+                if queue.bytes + frame_len > self.max_outbound_bytes {
+                    return Err("Outbound queue full");
+                }
+                Ok(())
+            }
+            OutboundOverflow::ResetConnection => {
+                self.outbound_frames.remove(&conn_id);
+                self.connection_table.record_outbound_reset();
+                self.close_logical_connection(conn_id);
+                Err("Connection reset: outbound queue full")
+            }
+        }
+    }
+
     fn process_control_message(&mut self, conn_id: u32, message: LegacyControlMessage) {
         match message {
             LegacyControlMessage::Open { target_host: _, target_port: _, .. } => {
@@ -139,21 +293,33 @@ impl<Phase: AllowsRelayLocalLinkability> ProtocolEngine<Phase> {
                     observability::record_connection_opened();
                 }
             }
-            LegacyControlMessage::Close { reason: _, .. } => {
-                if self.connection_table.close_connection(conn_id).is_ok() {
-                    observability::record_connection_closed();
-                }
+            LegacyControlMessage::Close { conn_id, .. } | LegacyControlMessage::Error { conn_id, .. } => {
+                self.forget_connection(conn_id);
             }
-            LegacyControlMessage::WindowUpdate { credits, .. } => {
+            LegacyControlMessage::WindowUpdate { conn_id, credits } => {
+                // The first one acknowledges our Open.
+                if self.connection_table.get_state(conn_id) == Some(ConnectionState::Init) {
+                    let _ = self.connection_table.finalize_open(conn_id);
+                }
                 let _ = self.connection_table.add_send_credits(conn_id, credits);
             }
             _ => {}
         }
     }
     
-    fn process_data_frame(&mut self, _frame: LegacyDataFrame) {
-        // Forward data frame to appropriate connection
-        // Implementation depends on specific relay logic
+    fn process_data_frame(&mut self, frame: LegacyDataFrame) {
+        if self.connection_table.get_state(frame.conn_id) == Some(ConnectionState::Open) {
+            self.inbound_events.push_back(InboundEvent::Data { conn_id: frame.conn_id, payload: frame.payload });
+        }
+    }
+
+    fn forget_connection(&mut self, conn_id: u32) -> bool {
+        if self.connection_table.remove_connection(conn_id).is_none() {
+            return false;
+        }
+        observability::record_connection_closed();
+        self.inbound_events.push_back(InboundEvent::Closed { conn_id });
+        true
     }
 }
 
@@ -180,3 +346,59 @@ impl<Phase: AllowsRelayLocalLinkability + Send> TransportCallbacks for ProtocolC
         observability::record_error(observability::ErrorClass::TRANSPORT_IO);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(deprecated)]
+    use super::*;
+    use crate::anonymity::invariants::LegacyPhase;
+    use crate::relay_protocol::FrameType;
+    use crate::relay_server::default_limits;
+
+    const CHUNK: [u8; 1500] = [0; 1500];
+
+    /// An engine whose connection 1 is open, with room for two chunks.
+    fn engine(outbound_overflow: OutboundOverflow) -> ProtocolEngine<LegacyPhase> {
+        let limits = RelayLimits { max_outbound_bytes: 4096, outbound_overflow, ..default_limits() };
+        let mut engine = ProtocolEngine::new(limits);
+        engine.open_logical_connection(1, "example.com".to_string(), 443).unwrap();
+        let mut ack = Vec::new();
+        let window_update = LegacyControlMessage::WindowUpdate { conn_id: 1, credits: 0 };
+        FrameEncoder::encode_frame(&mut ack, 1, FrameType::Control, &window_update.encode()).unwrap();
+        engine.on_transport_bytes(1, &ack);
+        engine.queue_data_frame(1, &CHUNK).unwrap();
+        engine.queue_data_frame(1, &CHUNK).unwrap();
+        engine
+    }
+
+    fn queued(engine: &mut ProtocolEngine<LegacyPhase>) -> Vec<FrameType> {
+        std::iter::from_fn(|| engine.next_outbound_frame(1))
+            .map(|frame| FrameDecoder::decode_frame(&mut Cursor::new(frame)).unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn full_outbound_queues_follow_the_overflow_policy() {
+        let mut blocking = engine(OutboundOverflow::BlockProducer);
+        assert_eq!(blocking.queue_data_frame(1, &CHUNK), Err("Outbound queue full"));
+        assert_eq!(blocking.metrics().outbound_blocked, 1);
+        blocking.next_outbound_frame(1);
+        blocking.next_outbound_frame(1);
+        assert!(blocking.queue_data_frame(1, &CHUNK).is_ok());
+
+        let mut dropping = engine(OutboundOverflow::DropOldestData);
+        assert!(dropping.queue_data_frame(1, &CHUNK).is_ok());
+        assert_eq!(dropping.metrics().outbound_frames_dropped, 1);
+        assert_eq!(queued(&mut dropping), [FrameType::Control, FrameType::Data, FrameType::Data]);
+        // The dropped chunk's credits are back
+        let window = dropping.connection_table().snapshot()[0].send_window;
+        assert_eq!(window, default_limits().initial_window - 2 * CHUNK.len() as u32);
+
+        let mut resetting = engine(OutboundOverflow::ResetConnection);
+        assert!(resetting.queue_data_frame(1, &CHUNK).is_err());
+        assert_eq!(resetting.metrics().outbound_resets, 1);
+        assert_eq!(resetting.connection_state(1), None);
+        assert_eq!(resetting.poll_inbound_events(), [InboundEvent::Closed { conn_id: 1 }]);
+        assert_eq!(queued(&mut resetting), [FrameType::Control]);
+    }
+}
@@ -0,0 +1,196 @@
+//! Embedding entry point: loads rules, binds and runs the local proxy the
+//! same way `ebt run` does, for GUI front-ends and integration tests.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::anonymity::invariants::LegacyPhase;
+use crate::anonymity::profile::ShapingControl;
+use crate::config::{ConfigError, ShapingProfile, TunnelConfig};
+use crate::content_policy_bootstrap::{build_listener_policies, RulesetLibrary};
+use crate::desktop_notifications;
+use crate::dns_resolver::set_doh_providers;
+use crate::error::EbtResult;
+use crate::logging;
+use crate::real_proxy::{ConfigSource, RealProxyServer};
+
+/// Builds an `EbtProxy` from a `TunnelConfig`.
+pub struct EbtProxyBuilder {
+    config: TunnelConfig,
+    config_source: Option<ConfigSource>,
+}
+
+impl EbtProxyBuilder {
+    pub fn new(config: TunnelConfig) -> Self {
+        Self {
+            config,
+            config_source: None,
+        }
+    }
+
+    /// Loads `path`; the same file is re-read on `EbtProxy::reload_config`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref().to_path_buf();
+        let config = TunnelConfig::from_file(&path)?;
+        Ok(Self::new(config).reload_from(Arc::new(move || TunnelConfig::from_file(&path))))
+    }
+
+    pub fn bind_address(mut self, address: IpAddr) -> Self {
+        self.config.proxy_policy.bind_address = address.to_string();
+        self
+    }
+
+    /// 0 picks a free port; see `EbtProxy::local_addr`.
+    pub fn bind_port(mut self, port: u16) -> Self {
+        self.config.proxy_policy.bind_port = port;
+        self
+    }
+
+    /// Where reloads read the configuration from; also enables reload on
+    /// SIGHUP. Without one, reloads are accepted and change nothing.
+    pub fn reload_from(mut self, source: ConfigSource) -> Self {
+        self.config_source = Some(source);
+        self
+    }
+
+    /// Loads content-policy rules and binds the listener; fails with
+    /// `ConfigError::MissingCapability` unless the configuration allows real
+    /// networking. The configured log
+    /// settings and DoH providers are process-wide and apply immediately.
+    pub async fn bind(self) -> EbtResult<EbtProxy> {
+        let config = self.config;
+        let network = config.capabilities.network_token()?;
+        if let Some(log) = config.observability.log.clone() {
+            logging::init(log);
+        }
+        set_doh_providers(config.dns_policy.doh_providers.clone());
+
+        let proxy_policy = config.proxy_policy.clone();
+        let control_socket = proxy_policy.control_socket.clone();
+        let notifications = config.observability.notifications;
+        let ruleset_library = RulesetLibrary::load(&proxy_policy.content_policy_rulesets).await?;
+        let listener_policies = build_listener_policies(&proxy_policy, &ruleset_library).await?;
+        let shaping = ShapingControl::new(config.anonymity.clone())
+            .map_err(|reason| ConfigError::Invalid {
+                field: "anonymity.mix_delay".to_string(),
+                reason: reason.to_string(),
+            })?;
+        let mut server = RealProxyServer::<LegacyPhase>::new(proxy_policy, listener_policies, network)
+            .with_relays(config.relay.clone())
+            .with_shaping(Arc::new(shaping))
+            .with_dns_prefetch(config.dns_policy.prefetch.clone())
+            .with_connect_retry(config.transport.connect_retry);
+        if let Some(source) = self.config_source {
+            server = server.with_config_reload(config, source);
+        }
+        server.bind()?;
+
+        let shutdown = Arc::new(Notify::new());
+        let mut tasks: Vec<JoinHandle<()>> = vec![
+            server.spawn_health_evaluator(),
+            server.spawn_event_recorder(),
+            server.spawn_network_monitor(),
+        ];
+        tasks.extend(server.spawn_content_policy_refresh());
+        tasks.extend(server.spawn_custom_rules_watch());
+        tasks.extend(server.spawn_usage_summary());
+        tasks.extend(server.spawn_traffic_accounting());
+        tasks.extend(server.spawn_adaptive_tunnel_limit());
+        tasks.extend(server.spawn_relay_health_checks());
+        tasks.extend(desktop_notifications::spawn(notifications));
+        #[cfg(unix)]
+        tasks.extend(server.spawn_reload_on_sighup());
+        if let Some(path) = &control_socket {
+            tasks.push(server.spawn_control_socket(path, Arc::clone(&shutdown))?);
+        }
+        Ok(EbtProxy { server, tasks, shutdown })
+    }
+}
+
+/// A bound proxy. Background tasks stop when it is dropped.
+pub struct EbtProxy {
+    server: RealProxyServer<LegacyPhase>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Notified by a control socket `shutdown` request.
+    shutdown: Arc<Notify>,
+}
+
+impl EbtProxy {
+    pub fn local_addr(&self) -> EbtResult<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn serve(&self) -> EbtResult<()> {
+        self.server.accept_connections().await
+    }
+
+    /// Accepts connections until `shutdown` completes or a control socket
+    /// client asks to stop, then saves traffic accounting. Established
+    /// tunnels are not waited for.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> EbtResult<()> {
+        tokio::select! {
+            result = self.server.accept_connections() => result?,
+            _ = shutdown => {}
+            _ = self.shutdown.notified() => {}
+        }
+        self.server.flush_traffic_accounting();
+        Ok(())
+    }
+
+    /// See `EbtProxyBuilder::reload_from`.
+    pub async fn reload_config(&self) -> EbtResult<()> {
+        self.server.reload_config().await
+    }
+
+    pub fn set_content_policy_enabled(&self, enabled: bool) {
+        self.server.set_content_policy_enabled(enabled);
+    }
+
+    pub fn set_content_policy_dry_run(&self, dry_run: bool) {
+        self.server.set_content_policy_dry_run(dry_run);
+    }
+
+    /// Allows `domain` and its subdomains for `duration` despite blocking rules.
+    pub fn bypass_domain(&self, domain: &str, duration: Duration) {
+        self.server.bypass_domain(domain, duration);
+    }
+
+    pub fn clear_bypass(&self, domain: &str) {
+        self.server.clear_bypass(domain);
+    }
+
+    /// `None` when the anonymity settings were not started from a profile.
+    pub fn shaping_profile(&self) -> Option<ShapingProfile> {
+        self.server.shaping_profile()
+    }
+
+    /// See `RealProxyServer::set_shaping_profile`.
+    pub fn set_shaping_profile(&self, profile: ShapingProfile) {
+        self.server.set_shaping_profile(profile);
+    }
+
+    /// Live tunnels as the JSON served at `GET /ebt/tunnels`.
+    pub fn tunnels_json(&self) -> String {
+        self.server.tunnels().to_json()
+    }
+
+    /// Closed tunnels as the JSON served at `GET /ebt/tunnels/summary`.
+    pub fn tunnel_summary_json(&self) -> String {
+        self.server.tunnels().stats().summary().to_json()
+    }
+}
+
+impl Drop for EbtProxy {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! HAProxy PROXY protocol version 2, accepted on listeners that sit behind
+//! a load balancer so the client's own address, not the balancer's, is
+//! what subnet rulesets see. A listener with it enabled only accepts
+//! connections from the configured balancer addresses, reads the header
+//! before anything else, and drops connections without one. Anyone else
+//! could name any client, loopback included. Loopback-only requests still
+//! need a loopback peer as well, since behind a balancer on the same
+//! machine every peer is one.
+//!
+//! `LOCAL` headers, sent by balancers for their own health checks, and
+//! addresses outside IPv4 and IPv6 keep the connection's peer address.
+//! TLVs are skipped. The text format of version 1 is not accepted.
+
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, family and protocol, length.
+const FIXED_LEN: usize = 16;
+const VERSION: u8 = 0x2;
+const CMD_LOCAL: u8 = 0x0;
+const CMD_PROXY: u8 = 0x1;
+const FAMILY_INET: u8 = 0x1;
+const FAMILY_INET6: u8 = 0x2;
+/// Source and destination addresses, then source and destination ports.
+const INET_ADDRESSES_LEN: usize = 12;
+const INET6_ADDRESSES_LEN: usize = 36;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    #[error("connection does not start with a PROXY v2 header")]
+    MissingSignature,
+    #[error("PROXY header version {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("PROXY header command {0:#x} is not supported")]
+    UnsupportedCommand(u8),
+    #[error("PROXY header is shorter than its address family needs")]
+    Truncated,
+    #[error("PROXY header read failed: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Reads a header from a blocking stream, returning the client address it
+/// carries, or `None` where the peer address stands.
+pub fn read_header(stream: &mut impl Read) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut fixed = [0u8; FIXED_LEN];
+    stream.read_exact(&mut fixed)?;
+    let mut body = vec![0u8; body_len(&fixed)?];
+    stream.read_exact(&mut body)?;
+    source_address(&fixed, &body)
+}
+
+/// `read_header` for async streams.
+pub async fn read_header_async(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut fixed = [0u8; FIXED_LEN];
+    stream.read_exact(&mut fixed).await?;
+    let mut body = vec![0u8; body_len(&fixed)?];
+    stream.read_exact(&mut body).await?;
+    source_address(&fixed, &body)
+}
+
+/// Checks the fixed part and returns the length of what follows it.
+fn body_len(fixed: &[u8; FIXED_LEN]) -> Result<usize, ProxyHeaderError> {
+    if fixed[..SIGNATURE.len()] != SIGNATURE {
+        return Err(ProxyHeaderError::MissingSignature);
+    }
+    let version = fixed[12] >> 4;
+    if version != VERSION {
+        return Err(ProxyHeaderError::UnsupportedVersion(version));
+    }
+    Ok(u16::from_be_bytes([fixed[14], fixed[15]]) as usize)
+}
+
+fn source_address(fixed: &[u8; FIXED_LEN], body: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    match fixed[12] & 0x0f {
+        CMD_LOCAL => return Ok(None),
+        CMD_PROXY => {}
+        command => return Err(ProxyHeaderError::UnsupportedCommand(command)),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match fixed[13] >> 4 {
+        FAMILY_INET if body.len() < INET_ADDRESSES_LEN => Err(ProxyHeaderError::Truncated),
+        FAMILY_INET => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).expect("length checked"));
+            Ok(Some(SocketAddr::from((ip, port(8)))))
+        }
+        FAMILY_INET6 if body.len() < INET6_ADDRESSES_LEN => Err(ProxyHeaderError::Truncated),
+        FAMILY_INET6 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).expect("length checked"));
+            Ok(Some(SocketAddr::from((ip, port(32)))))
+        }
+        // Unspecified and Unix socket addresses
+        _ => Ok(None),
+    }
+}
+
+/// A PROXY v2 header as a balancer would send it for `source`, for tests.
+#[cfg(test)]
+fn encode_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION << 4 | CMD_PROXY);
+    let mut addresses = Vec::new();
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            header.push(FAMILY_INET << 4 | 0x1);
+            addresses.extend_from_slice(&source.ip().octets());
+            addresses.extend_from_slice(&destination.ip().octets());
+        }
+        (source, destination) => {
+            let v6 = |addr: SocketAddr| match addr {
+                SocketAddr::V4(v4) => v4.ip().to_ipv6_mapped(),
+                SocketAddr::V6(v6) => *v6.ip(),
+            };
+            header.push(FAMILY_INET6 << 4 | 0x1);
+            addresses.extend_from_slice(&v6(source).octets());
+            addresses.extend_from_slice(&v6(destination).octets());
+        }
+    }
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_client_addresses_and_leaves_the_stream_after_the_header() {
+        let destination: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        for source in ["203.0.113.7:51000", "[2001:db8::7]:51000"] {
+            let source: SocketAddr = source.parse().unwrap();
+            let mut input = encode_header(source, destination);
+            input.extend_from_slice(b"CONNECT");
+            let mut stream = io::Cursor::new(input);
+            assert_eq!(read_header(&mut stream).unwrap(), Some(source));
+            let mut rest = String::new();
+            Read::read_to_string(&mut stream, &mut rest).unwrap();
+            assert_eq!(rest, "CONNECT");
+        }
+
+        // A LOCAL health check with a TLV the reader skips.
+        let mut local = SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x04, 0x04, 0x00, 0x01, 0xff]);
+        assert_eq!(read_header(&mut io::Cursor::new(local)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_connections_without_a_valid_header() {
+        let read = |input: &[u8]| read_header(&mut io::Cursor::new(input.to_vec()));
+        assert!(matches!(
+            read(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n"),
+            Err(ProxyHeaderError::MissingSignature)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 203.0.113.7 192.0.2.1 51000 8080\r\n"),
+            Err(ProxyHeaderError::MissingSignature)
+        ));
+
+        let mut header = encode_header("203.0.113.7:1".parse().unwrap(), "192.0.2.1:2".parse().unwrap());
+        header[12] = 0x12;
+        assert!(matches!(read(&header), Err(ProxyHeaderError::UnsupportedVersion(1))));
+        header[12] = 0x2f;
+        assert!(matches!(read(&header), Err(ProxyHeaderError::UnsupportedCommand(0xf))));
+
+        let mut short = SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 203, 0, 113, 7]);
+        assert!(matches!(read(&short), Err(ProxyHeaderError::Truncated)));
+        assert!(matches!(read(&SIGNATURE), Err(ProxyHeaderError::Io(_))));
+    }
+}
@@ -34,7 +34,7 @@ impl RealDnsResolver {
     }
     
     /// Resolve DNS query according to policy
-    pub async fn resolve_with_policy(&self, query: DnsQuery) -> Result<DnsResponse, Box<dyn std::error::Error>> {
+    pub async fn resolve_with_policy(&self, query: DnsQuery) -> Result<DnsResponse, DnsPolicyViolation> {
         // LEAK ANNOTATION: LeakStatus::Inherent
         // DNS queries leak domain names to ISP/transit networks due to:
         // 1. System resolver bypassing tunnel (OS behavior)
@@ -94,7 +94,7 @@ impl RealDnsResolver {
     }
     
     /// Resolve DNS query via remote relay
-    async fn resolve_remote(&self, query: DnsQuery) -> Result<DnsResponse, Box<dyn std::error::Error>> {
+    async fn resolve_remote(&self, query: DnsQuery) -> Result<DnsResponse, DnsPolicyViolation> {
         println!("Real DNS: Resolving via remote relay (policy enforced)");
         
         // In real implementation, this would:
@@ -111,7 +111,7 @@ impl RealDnsResolver {
     }
     
     /// Resolve DNS query locally (when policy allows)
-    async fn resolve_local(&self, query: DnsQuery) -> Result<DnsResponse, Box<dyn std::error::Error>> {
+    async fn resolve_local(&self, query: DnsQuery) -> Result<DnsResponse, DnsPolicyViolation> {
         println!("Real DNS: Resolving via local system (policy allows)");
         
         // In real implementation, this would use system DNS resolver
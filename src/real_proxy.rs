@@ -2,54 +2,69 @@
 // This proxy currently accepts connections sequentially.
 // A multi-connection loop will be added in a follow-up change.
 
-use std::net::{TcpListener as StdTcpListener, TcpStream};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, TcpListener as StdTcpListener, TcpStream};
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::marker::PhantomData;
 use std::thread;
-use crate::config::ProxyPolicy;
-use crate::content_policy::{ContentPolicyEngine, Decision, RequestMetadata};
+use std::time::{Duration, Instant};
+use crate::config::{
+    AdaptiveTunnelLimit, AnonymityConfig, AuthenticationPlaceholder, BlockBehavior, BlockResponse, ConfigError,
+    ConnectRetry, DnsPolicy, HeaderLimits, NetworkToken, PrefetchHint, ProxyMode, ProxyPolicy, RelayConfig,
+    RelayMode, ShapingProfile, TrafficAccountingConfig, TunnelConfig, UdpMode, Unset, UsageSummaryConfig,
+};
+use crate::content_policy::{
+    to_native_rules, ContentPolicyEngine, Decision, PolicyClock, ReasonCode, RequestMetadata,
+    Rule, RuleSet,
+};
+use crate::content_policy_bootstrap::{
+    build_listener_policies, load_custom_rules, refresh_ruleset, refresh_schedule, ClientSubnet,
+    ListenerPolicies, RulesetLibrary,
+};
+use arc_swap::ArcSwap;
 use crate::real_transport::DirectTcpTunnelTransport;
-use crate::transport::EncryptedTransport;
-use crate::logging::LogLevel;
+use crate::relay_transport::RelaySelector;
+use crate::control_socket::{self, ControlListener};
+use crate::memory_profile::{self, MemoryProfile};
+use crate::network_monitor::{self, NetworkPath, NetworkWatch};
+use crate::transport::{EncryptedTransport, TransportError};
+use crate::dns_prefetch::DnsPrefetcher;
+use crate::dns_resolver::{clear_doh_cache, set_doh_providers};
+use crate::logging::{self, LogConfig, LogLevel};
 use crate::log;
 use crate::core::observability;
+use crate::core::observability::health::{HealthEvaluator, HealthThresholds};
+use crate::core::observability::{ErrorClass, HealthState};
+use crate::core::observability::trace::ObsSpan;
+use crate::crypto_util::constant_time_eq_str;
+use crate::error::{EbtError, EbtResult};
+use crate::event_bus::{self, CloseReason, EbtEvent, RecentEvents};
+use crate::relay_protocol::ProtocolError;
+use crate::client_hello;
+use crate::hostname;
+use crate::proxy_protocol;
+use crate::socks5::{self, Reply};
+use crate::policy_audit::{AuditOutcome, PolicyAudit};
+use crate::tunnel_registry::{TunnelRegistry, TunnelState};
+use crate::traffic_accounting::TrafficAccounting;
+use crate::usage_summary::UsageSummaryWriter;
 use tokio::task;
-use tokio::sync::Semaphore;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::net::TcpListener;
+use crate::anonymity::profile::ShapingControl;
+use crate::authority::{self, Authority};
+use crate::error_response::{ErrorReason, ErrorResponse};
 use crate::anonymity::invariants::{
     AllowsDirectTimingCorrespondence,
     AllowsPerUserConnectionOwnership,
     AllowsRelayLocalLinkability,
     AllowsStableSocketMapping,
+    LegacyPhase,
 };
 
-lazy_static::lazy_static! {
-    // Restore higher global concurrency for asset-heavy sites
-    static ref TUNNEL_SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(256));
-}
-
-#[derive(Debug)]
-struct HeaderParseError(HeaderParseKind);
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum HeaderParseKind {
-    ClientClosed,
-    TimedOut,
-}
-
-impl std::fmt::Display for HeaderParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.0 {
-            HeaderParseKind::ClientClosed => write!(f, "Client closed before completing CONNECT headers"),
-            HeaderParseKind::TimedOut => write!(f, "CONNECT headers timed out"),
-        }
-    }
-}
-
-impl std::error::Error for HeaderParseError {}
-
 
 /// Real proxy server that binds to network interfaces
 pub struct RealProxyServer<Phase: AllowsPerUserConnectionOwnership
@@ -59,6 +74,17 @@ pub struct RealProxyServer<Phase: AllowsPerUserConnectionOwnership
     policy: ProxyPolicy,
     listener: Option<TcpListener>,
     policy_adapter: Arc<PolicyAdapter>,
+    proxy_credential: Option<Arc<String>>,
+    tunnels: Arc<TunnelRegistry>,
+    recent_events: Arc<RecentEvents>,
+    traffic_accounting: Option<Arc<Mutex<TrafficAccounting>>>,
+    tunnel_limit: Arc<TunnelLimit>,
+    config_reload: Option<Arc<ConfigReloader>>,
+    relays: Arc<RelaySelector>,
+    shaping: Arc<ShapingControl>,
+    prefetch: Arc<DnsPrefetcher>,
+    connect_retry: ConnectRetry,
+    network: NetworkToken,
     _phase: PhantomData<Phase>,
 }
 
@@ -66,34 +92,390 @@ impl<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
     + AllowsDirectTimingCorrespondence
     + AllowsRelayLocalLinkability> RealProxyServer<Phase> {
-    pub fn new(
-        policy: ProxyPolicy,
-        policy_engine: ContentPolicyEngine,
-        content_policy_enabled: bool,
-    ) -> Self {
-        // Phase 7.5 FROZEN: no auto-enablement, no dynamic reloads, no learning/inference.
-        // Policy remains proxy-edge only.
+    /// `network` comes from `CapabilityPolicy::network_token`; without it
+    /// there is no way to build a server, so Conceptual mode never listens.
+    pub fn new(policy: ProxyPolicy, listener_policies: ListenerPolicies, network: NetworkToken) -> Self {
+        // Phase 7.5 FROZEN: no auto-enablement, no learning/inference.
+        // Policy remains proxy-edge only; refreshes swap the whole ruleset.
+        let proxy_credential = policy
+            .authentication
+            .as_ref()
+            .filter(|auth| auth.enabled)
+            .and_then(|auth| auth.credential.clone())
+            .map(Arc::new);
+        let block_behavior = policy.content_policy_block_behavior;
+        let dry_run = policy.content_policy_dry_run;
+        let inspect_sni = policy.content_policy_inspect_sni;
+        record_policy_load(&listener_policies);
+        let traffic_accounting = open_traffic_accounting(&policy).map(|a| Arc::new(Mutex::new(a)));
+        let tunnel_limit = Arc::new(TunnelLimit::new(policy.max_concurrent_tunnels));
         Self {
             policy,
             listener: None,
-            policy_adapter: Arc::new(PolicyAdapter::new(
-                policy_engine,
-                content_policy_enabled,
-            )),
+            proxy_credential,
+            policy_adapter: Arc::new(
+                PolicyAdapter::from_listener(listener_policies)
+                    .with_block_behavior(block_behavior)
+                    .with_dry_run(dry_run)
+                    .with_inspect_sni(inspect_sni),
+            ),
+            tunnels: TunnelRegistry::new(),
+            recent_events: Arc::new(RecentEvents::new(RECENT_EVENTS_LIMIT)),
+            traffic_accounting,
+            tunnel_limit,
+            config_reload: None,
+            relays: Arc::new(RelaySelector::new(RelayConfig::default())),
+            shaping: Arc::new(ShapingControl::new(AnonymityConfig::default()).expect("default mix delay is valid")),
+            prefetch: Arc::new(DnsPrefetcher::default()),
+            connect_retry: ConnectRetry::default(),
+            network,
             _phase: PhantomData,
         }
     }
 
+    /// Relays for outgoing tunnels. Relay builds refuse tunnels until set.
+    pub fn with_relays(mut self, relays: RelayConfig) -> Self {
+        self.relays = Arc::new(RelaySelector::new(relays));
+        self
+    }
+
+    /// Shares `relays` with other servers, e.g. one listener per browser
+    /// container, so per-epoch chains and `IsolateBy::Listener` span them.
+    pub fn with_relay_selector(mut self, relays: Arc<RelaySelector>) -> Self {
+        self.relays = relays;
+        self
+    }
+
+    /// Active anonymity settings, switched by `set_shaping_profile`,
+    /// `POST /ebt/profile` and configuration reloads.
+    pub fn with_shaping(mut self, shaping: Arc<ShapingControl>) -> Self {
+        self.shaping = shaping;
+        self
+    }
+
+    /// Companion hosts to resolve ahead of their tunnels; see `DnsPrefetcher`.
+    pub fn with_dns_prefetch(mut self, hints: Vec<PrefetchHint>) -> Self {
+        self.prefetch = Arc::new(DnsPrefetcher::new(hints));
+        self
+    }
+
+    /// Connect attempts each CONNECT may spend per address; see `ConnectRetry`.
+    pub fn with_connect_retry(mut self, retry: ConnectRetry) -> Self {
+        self.connect_retry = retry;
+        self
+    }
+
+    /// Enables `reload_config`, SIGHUP and `POST /ebt/reload`. `config` is
+    /// what this server was built from; `source` re-reads it on each reload.
+    pub fn with_config_reload(mut self, config: TunnelConfig, source: ConfigSource) -> Self {
+        self.config_reload = Some(Arc::new(ConfigReloader {
+            source,
+            current: tokio::sync::Mutex::new(config),
+            policy_adapter: Arc::clone(&self.policy_adapter),
+            tunnel_limit: Arc::clone(&self.tunnel_limit),
+            shaping: Arc::clone(&self.shaping),
+        }));
+        self
+    }
+
+    /// Re-reads the configuration and applies the settings that are safe to
+    /// change at runtime. Established tunnels are untouched; a change that
+    /// needs a restart rejects the whole reload.
+    pub async fn reload_config(&self) -> EbtResult<()> {
+        match &self.config_reload {
+            Some(reloader) => reloader.reload().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Reloads the configuration on every SIGHUP.
+    #[cfg(unix)]
+    pub fn spawn_reload_on_sighup(&self) -> Option<task::JoinHandle<()>> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let reloader = Arc::clone(self.config_reload.as_ref()?);
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                log!(LogLevel::Error, "SIGHUP reload unavailable"; safe "error" => e);
+                return None;
+            }
+        };
+        Some(task::spawn(async move {
+            while hangups.recv().await.is_some() {
+                let _ = reloader.reload().await;
+            }
+        }))
+    }
+
     pub fn set_content_policy_enabled(&self, enabled: bool) {
         self.policy_adapter.set_enabled(enabled);
     }
+
+    /// In dry-run mode blocks are only recorded; every request is allowed.
+    pub fn set_content_policy_dry_run(&self, dry_run: bool) {
+        self.policy_adapter.dry_run.store(dry_run, Ordering::Release);
+    }
+
+    /// Temporarily allows `domain` and its subdomains despite blocking rules.
+    /// The original rules reapply once `duration` elapses.
+    pub fn bypass_domain(&self, domain: &str, duration: Duration) {
+        self.policy_adapter.bypass.allow(domain, duration);
+    }
+
+    pub fn clear_bypass(&self, domain: &str) {
+        self.policy_adapter.bypass.clear(domain);
+    }
+
+    /// `None` when the anonymity settings were not started from a profile;
+    /// also served at `GET /ebt/profile`.
+    pub fn shaping_profile(&self) -> Option<ShapingProfile> {
+        self.shaping.profile()
+    }
+
+    /// Replaces the anonymity settings with `profile`'s. Running pumps pick
+    /// up the new mix delay and path epochs on their next sample.
+    pub fn set_shaping_profile(&self, profile: ShapingProfile) {
+        self.shaping.set_profile(profile);
+        log!(LogLevel::Info, "Shaping profile switched"; safe "profile" => profile.as_str());
+    }
+
+    pub fn shaping(&self) -> Arc<ShapingControl> {
+        Arc::clone(&self.shaping)
+    }
+
+    /// Live tunnels for this listener; also served at `GET /ebt/tunnels`.
+    pub fn tunnels(&self) -> Arc<TunnelRegistry> {
+        Arc::clone(&self.tunnels)
+    }
+
+    /// Running listener-wide rules (custom rules first) in the native format.
+    pub fn export_rules(&self) -> String {
+        to_native_rules(self.policy_adapter.engine.load().engine.rules())
+    }
+
+    /// Polls the custom rules file and swaps in its rules when its contents
+    /// change. A file that fails to parse keeps the previous rules in place.
+    pub fn spawn_custom_rules_watch(&self) -> Option<task::JoinHandle<()>> {
+        if !self.policy_adapter.is_enabled() {
+            return None;
+        }
+        let path = self.policy.content_policy_custom_rules.clone()?;
+        let policy_adapter = Arc::clone(&self.policy_adapter);
+        Some(task::spawn(async move {
+            let mut watch = RulesFileWatch::new(path.clone()).await;
+            let mut ticker = tokio::time::interval(CUSTOM_RULES_POLL_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if !watch.changed().await {
+                    continue;
+                }
+                let path = path.clone();
+                let Ok(loaded) = task::spawn_blocking(move || load_custom_rules(&path)).await else {
+                    continue;
+                };
+                match loaded {
+                    Ok(ruleset) => policy_adapter.replace_custom(ruleset),
+                    Err(e) => log!(
+                        LogLevel::Error,
+                        "Custom rules reload failed; keeping previous rules";
+                        safe "error" => e
+                    ),
+                }
+            }
+        }))
+    }
+
+    /// Periodically re-downloads an https rules source and swaps it in.
+    /// In-flight connections are untouched; a failed or invalid download keeps
+    /// the previous rules.
+    pub fn spawn_content_policy_refresh(&self) -> Option<task::JoinHandle<()>> {
+        let (location, interval) = refresh_schedule(&self.policy)?;
+        let policy_adapter = Arc::clone(&self.policy_adapter);
+        Some(task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match refresh_ruleset(&location).await {
+                    Ok(ruleset) => {
+                        let rule_count = ruleset.rules().len() as u64;
+                        policy_adapter.replace_base(ruleset);
+                        observability::record_policy_refresh(rule_count, unix_now_secs());
+                    }
+                    Err(_) => {
+                        observability::record_policy_refresh_failed();
+                        log!(LogLevel::Error, "Content policy refresh failed; keeping previous rules");
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Appends an aggregate usage line every configured interval when
+    /// `usage_summary` is set. Not available under OBS_NONE.
+    pub fn spawn_usage_summary(&self) -> Option<task::JoinHandle<()>> {
+        let config = self.policy.usage_summary.clone()?;
+        let baseline = observability::usage_totals()?;
+        let interval = config.interval;
+        let mut writer = UsageSummaryWriter::new(config, baseline);
+        Some(task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(totals) = observability::usage_totals() else {
+                    continue;
+                };
+                if let Err(e) = writer.write(totals, unix_now_secs()) {
+                    log!(LogLevel::Error, "Usage summary write failed"; safe "error" => e);
+                }
+            }
+        }))
+    }
+
+    /// Re-derives process health from error, DNS failover and transport
+    /// retry rates every `HEALTH_EVALUATION_INTERVAL`.
+    pub fn spawn_health_evaluator(&self) -> task::JoinHandle<()> {
+        task::spawn(async move {
+            let mut evaluator = HealthEvaluator::new(HEALTH_WINDOW_SAMPLES, HealthThresholds::default());
+            let mut ticker = tokio::time::interval(HEALTH_EVALUATION_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let state = evaluator.observe(observability::health_counters());
+                let previous = observability::get_health();
+                if state != previous {
+                    observability::set_health(state);
+                    event_bus::publish(EbtEvent::HealthChanged { from: previous, to: state });
+                    log!(LogLevel::Info, "Health state changed";
+                        safe "from" => previous.as_str(), safe "to" => state.as_str());
+                }
+            }
+        })
+    }
+
+    /// Moves the tunnel limit each interval when `adaptive_tunnel_limit` is
+    /// set; see `AdaptiveTunnelLimit`.
+    pub fn spawn_adaptive_tunnel_limit(&self) -> Option<task::JoinHandle<()>> {
+        let bounds = self.policy.adaptive_tunnel_limit?;
+        let tunnel_limit = Arc::clone(&self.tunnel_limit);
+        let tunnels = Arc::clone(&self.tunnels);
+        Some(task::spawn(async move {
+            let mut ticker = tokio::time::interval(bounds.interval);
+            ticker.tick().await;
+            let mut last = tunnels.stats().totals();
+            loop {
+                ticker.tick().await;
+                let totals = tunnels.stats().totals();
+                let sample = LimitSample {
+                    available_memory: memory_profile::available_memory(),
+                    closed: totals.closed.saturating_sub(last.closed),
+                    upstream_failed: totals.upstream_failed.saturating_sub(last.upstream_failed),
+                };
+                last = totals;
+                let current = tunnel_limit.limit();
+                let next = next_tunnel_limit(current, tunnel_limit.in_use(), &sample, &bounds);
+                if next != current {
+                    tunnel_limit.set(next);
+                    log!(LogLevel::Debug, "Tunnel limit adapted"; safe "from" => current, safe "to" => next);
+                }
+            }
+        }))
+    }
+
+    /// Saves per-day traffic totals every configured interval when
+    /// `traffic_accounting` is set. Not available under OBS_NONE.
+    pub fn spawn_traffic_accounting(&self) -> Option<task::JoinHandle<()>> {
+        let accounting = Arc::clone(self.traffic_accounting.as_ref()?);
+        let interval = accounting.lock().ok()?.save_interval();
+        Some(task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                save_traffic_accounting(&accounting);
+            }
+        }))
+    }
+
+    /// Records and saves traffic totals now; called at shutdown.
+    pub fn flush_traffic_accounting(&self) {
+        if let Some(accounting) = &self.traffic_accounting {
+            save_traffic_accounting(accounting);
+        }
+    }
+
+    /// Probes relays for `GET /ebt/relays` and selection when
+    /// `relay.health_check` is set.
+    pub fn spawn_relay_health_checks(&self) -> Option<task::JoinHandle<()>> {
+        self.relays.spawn_health_checks()
+    }
+
+    /// Feeds the event bus into the history served at `GET /ebt/events`.
+    pub fn spawn_event_recorder(&self) -> task::JoinHandle<()> {
+        let recent_events = Arc::clone(&self.recent_events);
+        let mut events = event_bus::subscribe();
+        task::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => recent_events.push(event),
+                    Err(RecvError::Lagged(missed)) => recent_events.record_missed(missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
     
+    /// Samples the route to the internet every `POLL_INTERVAL`; when the
+    /// machine changes networks, drops cached DNS answers and relay
+    /// chains and closes open tunnels, whose connections went with the
+    /// old path. See `network_monitor`.
+    pub fn spawn_network_monitor(&self) -> task::JoinHandle<()> {
+        let tunnels = Arc::clone(&self.tunnels);
+        let relays = Arc::clone(&self.relays);
+        task::spawn(async move {
+            let mut watch = NetworkWatch::new(NetworkPath::current());
+            let mut ticker = tokio::time::interval(network_monitor::POLL_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !watch.observe(NetworkPath::current()) {
+                    continue;
+                }
+                clear_doh_cache();
+                relays.network_changed();
+                let tunnels_reset = tunnels.reset_all();
+                event_bus::publish(EbtEvent::NetworkChanged { tunnels_reset });
+                log!(LogLevel::Info, "Network changed; tunnels reset"; safe "tunnels" => tunnels_reset);
+            }
+        })
+    }
+
+    /// Serves the JSON control protocol at `path`; see `control_socket`.
+    /// `shutdown` is notified when a client asks the proxy to stop.
+    pub fn spawn_control_socket(&self, path: &str, shutdown: Arc<Notify>) -> EbtResult<task::JoinHandle<()>> {
+        let listener = ControlListener::bind(path)?;
+        let control = Arc::new(ProxyControl {
+            listen: self.local_addr().ok(),
+            relay_mode: self.relays.config().mode,
+            policy_adapter: Arc::clone(&self.policy_adapter),
+            tunnels: Arc::clone(&self.tunnels),
+            config_reload: self.config_reload.clone(),
+            shaping: Arc::clone(&self.shaping),
+        });
+        Ok(task::spawn(control_socket::serve(listener, control, shutdown)))
+    }
+
     /// Bind to the configured address and port
-    pub fn bind(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn bind(&mut self) -> EbtResult<()> {
         let bind_addr = format!("{}:{}", self.policy.bind_address, self.policy.bind_port);
         println!("Real proxy binding to {}", bind_addr);
         
-        let std_listener = StdTcpListener::bind(&bind_addr)?;
+        let std_listener = StdTcpListener::bind((self.policy.bind_address.as_str(), self.policy.bind_port))
+            .map_err(|source| ConfigError::Bind {
+                addr: bind_addr.clone(),
+                source,
+            })?;
         std_listener.set_nonblocking(true)?;
         let listener = TcpListener::from_std(std_listener)?;
         self.listener = Some(listener);
@@ -102,93 +484,144 @@ impl<Phase: AllowsPerUserConnectionOwnership
         Ok(())
     }
     
+    /// Bound address; the actual port when `bind_port` was 0.
+    pub fn local_addr(&self) -> EbtResult<std::net::SocketAddr> {
+        let listener = self.listener.as_ref().ok_or(ConfigError::NotBound)?;
+        Ok(listener.local_addr()?)
+    }
+
     /// Accept multiple connections concurrently
-    pub async fn accept_connections(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn accept_connections(&self) -> EbtResult<()> {
         if let Some(ref listener) = self.listener {
             log!(LogLevel::Info, "Proxy server ready for connections");
+            let context = self.connection_context(listener.local_addr()?.port());
+            
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            if crate::uring::available() {
+                let mut acceptor = crate::uring::Acceptor::spawn(listener)?;
+                loop {
+                    let stream = acceptor.accept().await?;
+                    self.spawn_connection(stream, &context)?;
+                }
+            }
             
             loop {
-                // Handle each connection in a separate task
                 let (stream, _addr) = listener.accept().await?;
-                observability::record_connection_opened();
-                let policy_adapter = Arc::clone(&self.policy_adapter);
-                let stream = stream.into_std()?;
-                stream.set_nonblocking(false)?;
-                stream.set_nodelay(true).ok();
-                stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
-                
-                task::spawn(async move {
-                    let permit = match TUNNEL_SEMAPHORE.clone().acquire_owned().await {
-                        Ok(p) => p,
-                        Err(_) => return,
-                    };
-                    
-                    let handle = tokio::runtime::Handle::current();
-                    let result = task::spawn_blocking(move || handle.block_on(Self::handle_connection(stream, policy_adapter)))
-                        .await
-                        .unwrap_or_else(|e| Err(e.into()));
-                    observability::record_connection_closed();
-                    
-                    // Ensure permit is always released
-                    drop(permit);
-                    
-                    if let Err(e) = result {
-                        if let Some(header_err) = e.downcast_ref::<HeaderParseError>() {
-                            match header_err.0 {
-                                HeaderParseKind::TimedOut | HeaderParseKind::ClientClosed => {
-                                    observability::record_header_discard();
-                                }
-                            }
-                        } else {
-                            log!(LogLevel::Error, "Connection failed: {}", e);
-                        }
-                    }
-                });
+                self.spawn_connection(stream.into_std()?, &context)?;
             }
         } else {
-            Err("Proxy server not bound".into())
+            Err(ConfigError::NotBound.into())
         }
     }
     
-    /// Handle a single client connection
-    async fn handle_connection(
-        mut stream: TcpStream,
-        policy_adapter: Arc<PolicyAdapter>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Read HTTP request headers in chunks until \r\n\r\n
-        let mut buffer = Vec::new();
-        let mut chunk_buf = [0u8; 4096]; // 4KB chunks
+    /// What every connection accepted on `listener_port` shares.
+    fn connection_context(&self, listener_port: u16) -> Arc<ConnectionContext> {
+        Arc::new(ConnectionContext {
+            policy_adapter: Arc::clone(&self.policy_adapter),
+            proxy_credential: self.proxy_credential.clone(),
+            tunnels: Arc::clone(&self.tunnels),
+            recent_events: Arc::clone(&self.recent_events),
+            config_reload: self.config_reload.clone(),
+            relays: Arc::clone(&self.relays),
+            shaping: Arc::clone(&self.shaping),
+            prefetch: Arc::clone(&self.prefetch),
+            network: self.network,
+            traffic_shaping: self.policy.traffic_shaping,
+            connect_retry: self.connect_retry,
+            header_limits: self.policy.header_limits,
+            proxy_protocol: self.policy.proxy_protocol.clone(),
+            listener_port,
+            udp: self.policy.udp,
+        })
+    }
+
+    /// Serves one accepted connection in a task of its own.
+    fn spawn_connection(&self, stream: TcpStream, context: &Arc<ConnectionContext>) -> EbtResult<()> {
+        observability::record_connection_opened();
+        let tunnel_limit = Arc::clone(&self.tunnel_limit);
+        let context = Arc::clone(context);
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true).ok();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
         
-        // Read in chunks until we find \r\n\r\n
-        let header_end = loop {
-            match stream.read(&mut chunk_buf) {
-                Ok(0) => {
-                    // true EOF: client closed before completing headers
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    return Err(Box::new(HeaderParseError(HeaderParseKind::ClientClosed)));
-                }
-                Ok(n) => {
-                    buffer.extend_from_slice(&chunk_buf[..n]);
-                    
-                    // Check for \r\n\r\n pattern in the buffer
-                    if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
-                        break pos + 4;
-                    }
+        task::spawn(async move {
+            let permit = match tunnel_limit.acquire().await {
+                Some(p) => p,
+                None => return,
+            };
+            
+            let handle = tokio::runtime::Handle::current();
+            let span = ObsSpan::connection();
+            let connection_span = span.clone();
+            let result = task::spawn_blocking(move || {
+                handle.block_on(connection_span.instrument(Self::handle_connection(stream, &context)))
+            })
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::from(e).into()));
+            observability::record_connection_closed();
+            span.record("outcome", &if result.is_ok() { "ok" } else { "error" });
+            
+            // Ensure permit is always released
+            drop(permit);
+            
+            match result {
+                Ok(()) => {}
+                Err(EbtError::Protocol(
+                    ProtocolError::HeadersIncomplete | ProtocolError::HeadersTimedOut,
+                )) => {
+                    observability::record_header_discard();
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // IMPORTANT: just continue, do NOT fail
-                    continue;
+                Err(e @ EbtError::Protocol(ProtocolError::RequestLineTooLong | ProtocolError::HeadersTooLarge)) => {
+                    e.record();
+                    log!(LogLevel::Debug, "Oversized request head refused"; safe "error" => e);
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                    return Err(Box::new(HeaderParseError(HeaderParseKind::TimedOut)));
+                Err(e) => {
+                    e.record();
+                    log!(LogLevel::Error, "Connection failed"; sensitive "error" => e);
                 }
+            }
+        });
+        Ok(())
+    }
+    
+    /// Handle a single client connection
+    async fn handle_connection(stream: TcpStream, context: &Arc<ConnectionContext>) -> EbtResult<()> {
+        let peer_ip = stream.peer_addr()?.ip();
+        let mut reader = PreTunnelReader::new(stream, context.header_limits)?;
+        let client_ip = if !context.proxy_protocol.is_empty() {
+            // Only a listed balancer may name another client: from anyone
+            // else, a header is a forged address.
+            if !context.proxy_protocol.iter().any(|balancer| balancer.contains(peer_ip)) {
+                observability::record_error(ErrorClass::PROTOCOL_VIOLATION);
+                log!(LogLevel::Debug, "Connection from outside the PROXY balancers refused";
+                    sensitive "peer" => peer_ip);
+                let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+                return Ok(());
+            }
+            match proxy_protocol::read_header(&mut reader) {
+                Ok(source) => source.map_or(peer_ip, |addr| addr.ip()),
                 Err(e) => {
-                    return Err(e.into());
+                    observability::record_error(ErrorClass::PROTOCOL_VIOLATION);
+                    log!(LogLevel::Debug, "Connection without a valid PROXY header refused"; safe "error" => e);
+                    let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+                    return Ok(());
                 }
             }
+        } else {
+            peer_ip
         };
+        // Loopback-only requests need a local peer as well as a local
+        // client: behind a balancer on this machine, every client's peer is.
+        let loopback = peer_ip.is_loopback() && client_ip.is_loopback();
+
+        // A SOCKS5 greeting starts with its version, which no HTTP request does.
+        let mut first = [0u8; 1];
+        if matches!(reader.peek(&mut first), Ok(1)) && first[0] == socks5::VERSION {
+            return Self::handle_socks5(reader, client_ip, context).await;
+        }
 
-        let _ = stream.set_read_timeout(None);
+        let (buffer, header_end) = read_request_head(&mut reader)?;
+        let mut stream = reader.into_inner();
         
         let request = String::from_utf8_lossy(&buffer[..header_end]);
         
@@ -208,45 +641,151 @@ impl<Phase: AllowsPerUserConnectionOwnership
             }
         }
         
-        if request.starts_with("CONNECT ") {
-            // Parse CONNECT target from request line
-            let first_line = request.lines().next().unwrap_or("");
-            let parts: Vec<&str> = first_line.split_whitespace().collect();
-            let (host, port) = if parts.len() >= 2 {
-                let target = parts[1];
-                if let Some(colon_pos) = target.rfind(':') {
-                    let host = target[..colon_pos].to_string();
-                    let port = target[colon_pos + 1..].parse::<u16>().unwrap_or(443);
-                    (host, port)
-                } else {
-                    (target.to_string(), 443u16)
+        // Liveness for local supervisors: no credentials, loopback only.
+        if request.starts_with(HEALTHZ_GET) {
+            let response: Vec<u8> = if !loopback {
+                b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()
+            } else {
+                healthz_response(observability::get_health())
+            };
+            stream.write_all(&response)?;
+            stream.flush()?;
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return Ok(());
+        }
+
+        let is_control = request.starts_with(BYPASS_CONTROL_POST)
+            || request.starts_with(BYPASS_CONTROL_DELETE)
+            || request.starts_with(TUNNELS_CONTROL_GET)
+            || request.starts_with(TUNNEL_SUMMARY_CONTROL_GET)
+            || request.starts_with(RELAYS_CONTROL_GET)
+            || request.starts_with(EVENTS_CONTROL_GET)
+            || request.starts_with(DECISIONS_CONTROL_GET)
+            || request.starts_with(RELOAD_CONTROL_POST)
+            || request.starts_with(PROFILE_CONTROL_GET)
+            || request.starts_with(PROFILE_CONTROL_POST)
+            || request.starts_with(OBS_CONTROL_GET);
+        if is_control {
+            let authorized = control_request_allowed(
+                loopback,
+                context.proxy_credential.as_deref().map(String::as_str),
+                &request,
+            );
+            let response: Vec<u8> = if !authorized {
+                b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()
+            } else if request.starts_with(TUNNELS_CONTROL_GET) {
+                control_body_response("application/json", &context.tunnels.to_json())
+            } else if request.starts_with(TUNNEL_SUMMARY_CONTROL_GET) {
+                control_body_response("application/json", &context.tunnels.stats().summary().to_json())
+            } else if request.starts_with(RELAYS_CONTROL_GET) {
+                // Probe statistics exist only with relay health checks on.
+                match context.relays.health() {
+                    Some(health) => control_body_response(
+                        "application/json",
+                        &health.to_json(&context.relays.config().endpoints, std::time::Instant::now()),
+                    ),
+                    None => b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
+                }
+            } else if request.starts_with(EVENTS_CONTROL_GET) {
+                control_body_response("application/json", &context.recent_events.to_json())
+            } else if request.starts_with(DECISIONS_CONTROL_GET) {
+                let host = decisions_control_host(&request);
+                control_body_response("application/json", &context.policy_adapter.audit.to_json(host))
+            } else if request.starts_with(RELOAD_CONTROL_POST) {
+                match &context.config_reload {
+                    Some(reloader) => reload_response(reloader.reload().await),
+                    None => b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
                 }
+            } else if request.starts_with(PROFILE_CONTROL_GET) {
+                let name = context.shaping.profile().map_or("custom", |profile| profile.as_str());
+                control_body_response("text/plain", &format!("{}\n", name))
+            } else if request.starts_with(PROFILE_CONTROL_POST) {
+                match profile_control_target(&request) {
+                    Some(profile) => {
+                        context.shaping.set_profile(profile);
+                        log!(LogLevel::Info, "Shaping profile switched"; safe "profile" => profile.as_str());
+                        b"HTTP/1.1 204 No Content\r\n\r\n".to_vec()
+                    }
+                    None => b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec(),
+                }
+            } else if request.starts_with(OBS_CONTROL_GET) {
+                // Snapshots exist only under OBS_DEV.
+                match observability::snapshot() {
+                    Some(snapshot) => control_body_response(
+                        "text/plain",
+                        &observability::report::encode(&snapshot),
+                    ),
+                    None => b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec(),
+                }
+            } else if handle_bypass_control(&context.policy_adapter.bypass, &request) {
+                b"HTTP/1.1 204 No Content\r\n\r\n".to_vec()
             } else {
-                ("unknown".to_string(), 443u16)
+                b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec()
+            };
+            stream.write_all(&response)?;
+            stream.flush()?;
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return Ok(());
+        }
+
+        if request.starts_with("CONNECT ") {
+            let Authority { host, port } = match authority::parse_connect_line(request.lines().next().unwrap_or("")) {
+                Ok(authority) => authority,
+                Err(e) => {
+                    log!(LogLevel::Debug, "Malformed CONNECT refused"; safe "error" => e);
+                    stream.write_all(&ErrorResponse::new(ErrorReason::MalformedRequest).to_bytes())?;
+                    stream.flush()?;
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    return Ok(());
+                }
             };
             
             log!(LogLevel::Debug, "CONNECT tunnel requested");
+            ObsSpan::current().record("destination", &format_args!("{}:{}", host, port));
 
-            // WARNING (Phase 7.5 FROZEN): policy gate must remain here, pre-CONNECT.
-            // Do not move or replicate policy logic below the proxy edge.
-            if !policy_allows_connect(policy_adapter.as_ref(), &request, &host, port) {
-                let response = b"HTTP/1.1 403 Forbidden\r\n\r\n";
-                stream.write_all(response)?;
+            if !proxy_auth_allows(context.proxy_credential.as_deref().map(String::as_str), &request) {
+                let response =
+                    ErrorResponse::new(ErrorReason::AuthRequired).header("Proxy-Authenticate", "Basic realm=\"ebt\"");
+                stream.write_all(&response.to_bytes())?;
                 stream.flush()?;
                 let _ = stream.shutdown(std::net::Shutdown::Both);
                 return Ok(());
             }
+
+            // WARNING (Phase 7.5 FROZEN): policy gate must remain here, pre-CONNECT.
+            // Do not move or replicate policy logic below the proxy edge.
+            let adapter = context.policy_adapter.as_ref();
+            let verdict = policy_allows_connect(adapter, Some(client_ip), &request, &host, port);
+            if let Err(reason) = verdict {
+                let response = connect_block_response(context.policy_adapter.block_response(reason));
+                match block_response_bytes(response, reason) {
+                    Some(response) => {
+                        stream.write_all(&response)?;
+                        stream.flush()?;
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                    }
+                    None => {
+                        // Zero linger turns the close into a RST.
+                        let _ = socket2::SockRef::from(&stream).set_linger(Some(std::time::Duration::ZERO));
+                    }
+                }
+                return Ok(());
+            }
+            context.prefetch.on_connect(&host);
             
-            // Handle CONNECT request for HTTPS tunneling
-            let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
-            stream.write_all(response)?;
-            stream.flush()?;
-            
+            let mut tunnel = context.tunnels.register(&host, port);
+            tunnel.attach_client(&stream);
+
             // Create transport for this specific CONNECT target
             let mut transport = DirectTcpTunnelTransport::<Phase>::new(
                 host.clone(),
-                port
-            )?;
+                port,
+                context.relays.select_for(&host, port, Some(context.listener_port)),
+                context.network,
+            )?
+            .with_counters(tunnel.counters())
+            .with_traffic_shaping(context.traffic_shaping)
+            .with_connect_retry(context.connect_retry);
             
             // LEAK ANNOTATION: LeakStatus::Intentional
             // Connection establishment leaks destination IP and SNI to ISP/transit because:
@@ -254,17 +793,37 @@ impl<Phase: AllowsPerUserConnectionOwnership
             // 2. TLS handshake SNI field contains domain name in plaintext
             // 3. This is documented Phase 3 behavior - no relay indirection yet
             
-            // Establish connection to target
-            match transport.establish_connection().await {
-                Ok(_) => {},
-                Err(e) => {
-                    log!(LogLevel::Error, "Failed to establish connection - {}", e);
-                    return Err(e.into());
-                }
+            // Establish connection to target before answering, so a failure
+            // can still be reported with its own status.
+            if let Err(e) = transport.establish_connection().await {
+                log!(LogLevel::Error, "Failed to establish connection"; sensitive "error" => e);
+                tunnel.set_close_reason(CloseReason::UpstreamFailed);
+                let reason = match e {
+                    TransportError::TimedOut => ErrorReason::UpstreamTimeout,
+                    _ => ErrorReason::UpstreamUnreachable,
+                };
+                let _ = stream.write_all(&ErrorResponse::new(reason).to_bytes());
+                let _ = stream.flush();
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                return Err(e.into());
             }
-            
+
+            // Handle CONNECT request for HTTPS tunneling
+            let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+            stream.write_all(response)?;
+            stream.flush()?;
+
+            // Past the 200, a blocked server name can only be refused with a RST.
+            if policy_allows_server_name(adapter, Some(client_ip), &request, &stream, &host, port).is_err() {
+                let _ = socket2::SockRef::from(&stream).set_linger(Some(std::time::Duration::ZERO));
+                tunnel.set_close_reason(CloseReason::Aborted);
+                return Ok(());
+            }
+
             // Start encrypted forwarding using transport
+            tunnel.set_state(TunnelState::Forwarding);
             transport.start_forwarding(stream)?;
+            tunnel.set_close_reason(CloseReason::Completed);
             return Ok(());
         } else {
             // Temporarily disable HTTP handling for debugging
@@ -272,9 +831,23 @@ impl<Phase: AllowsPerUserConnectionOwnership
             //     // Handle HTTP request forwarding
             //     Self::handle_http_request(stream, &request).await?;
             // } else {
-            // Reject non-CONNECT requests
-            let response = "HTTP/1.1 405 Method Not Allowed\r\n\r\n";
-            stream.write_all(response.as_bytes())?;
+            // Plain HTTP is never forwarded, but a blocked destination is
+            // answered as the policy says, so the browser can show the page.
+            let blocked = plain_http_target(&request)
+                .filter(|_| proxy_auth_allows(context.proxy_credential.as_deref().map(String::as_str), &request))
+                .and_then(|Authority { host, port }| {
+                    policy_allows_connect(&context.policy_adapter, Some(client_ip), &request, &host, port).err()
+                });
+            let response = match blocked {
+                Some(reason) => block_response_bytes(context.policy_adapter.block_response(reason), reason),
+                None => Some(ErrorResponse::new(ErrorReason::MethodNotAllowed).to_bytes()),
+            };
+            let Some(response) = response else {
+                // Zero linger turns the close into a RST.
+                let _ = socket2::SockRef::from(&stream).set_linger(Some(std::time::Duration::ZERO));
+                return Ok(());
+            };
+            stream.write_all(&response)?;
             stream.flush()?;
         }
         
@@ -282,8 +855,115 @@ impl<Phase: AllowsPerUserConnectionOwnership
         Ok(())
     }
     
+    /// Serve a SOCKS5 client; see `socks5`.
+    async fn handle_socks5(
+        mut reader: PreTunnelReader,
+        client_ip: IpAddr,
+        context: &Arc<ConnectionContext>,
+    ) -> EbtResult<()> {
+        let request = match socks5::accept(&mut reader, context.proxy_credential.as_deref().map(String::as_str)) {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+                return Ok(());
+            }
+            Err(ProtocolError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(ProtocolError::HeadersIncomplete.into());
+            }
+            Err(ProtocolError::Io(e))
+                if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+            {
+                return Err(ProtocolError::HeadersTimedOut.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut stream = reader.into_inner();
+        let unbound = std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0));
+        let (host, port) = (request.address.host(), request.address.port());
+
+        match request.command {
+            socks5::Command::Connect => {
+                log!(LogLevel::Debug, "SOCKS CONNECT tunnel requested");
+                ObsSpan::current().record("destination", &format_args!("{}:{}", host, port));
+
+                // WARNING (Phase 7.5 FROZEN): policy gate must remain here, pre-CONNECT.
+                if policy_allows_connect(context.policy_adapter.as_ref(), Some(client_ip), "", &host, port).is_err() {
+                    socks5::reply(&mut stream, Reply::NotAllowed, unbound)?;
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    return Ok(());
+                }
+                context.prefetch.on_connect(&host);
+
+                let mut tunnel = context.tunnels.register(&host, port);
+                tunnel.attach_client(&stream);
+                let mut transport = DirectTcpTunnelTransport::<Phase>::new(
+                    host.clone(),
+                    port,
+                    context.relays.select_for(&host, port, Some(context.listener_port)),
+                    context.network,
+                )?
+                .with_counters(tunnel.counters())
+                .with_traffic_shaping(context.traffic_shaping)
+                .with_connect_retry(context.connect_retry);
+
+                // Unlike HTTP CONNECT, SOCKS reports whether the target answered.
+                if let Err(e) = transport.establish_connection().await {
+                    log!(LogLevel::Error, "Failed to establish connection"; sensitive "error" => e);
+                    tunnel.set_close_reason(CloseReason::UpstreamFailed);
+                    socks5::reply(&mut stream, Reply::HostUnreachable, unbound)?;
+                    return Err(e.into());
+                }
+                socks5::reply(&mut stream, Reply::Succeeded, unbound)?;
+                let adapter = context.policy_adapter.as_ref();
+                if policy_allows_server_name(adapter, Some(client_ip), "", &stream, &host, port).is_err() {
+                    let _ = socket2::SockRef::from(&stream).set_linger(Some(std::time::Duration::ZERO));
+                    tunnel.set_close_reason(CloseReason::Aborted);
+                    return Ok(());
+                }
+                tunnel.set_state(TunnelState::Forwarding);
+                transport.start_forwarding(stream)?;
+                tunnel.set_close_reason(CloseReason::Completed);
+            }
+            socks5::Command::UdpAssociate if context.udp == UdpMode::Block => {
+                log!(LogLevel::Debug, "SOCKS UDP association refused by proxy.udp");
+                socks5::reply(&mut stream, Reply::NotAllowed, unbound)?;
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+            socks5::Command::UdpAssociate => {
+                let socket = match std::net::UdpSocket::bind((stream.local_addr()?.ip(), 0)) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        socks5::reply(&mut stream, Reply::GeneralFailure, unbound)?;
+                        return Err(e.into());
+                    }
+                };
+                log!(LogLevel::Debug, "SOCKS UDP association opened");
+                socks5::reply(&mut stream, Reply::Succeeded, socket.local_addr()?)?;
+                socket.set_nonblocking(true)?;
+                stream.set_nonblocking(true)?;
+                let allows = |host: &str, port: u16| {
+                    policy_allows_connect(context.policy_adapter.as_ref(), Some(client_ip), "", host, port).is_ok()
+                };
+                let route = |host: &str, port: u16| context.relays.select_for(host, port, Some(context.listener_port));
+                socks5::run_association(
+                    tokio::net::TcpStream::from_std(stream)?,
+                    tokio::net::UdpSocket::from_std(socket)?,
+                    client_ip,
+                    allows,
+                    route,
+                )
+                .await?;
+            }
+            socks5::Command::Unsupported => {
+                socks5::reply(&mut stream, Reply::CommandNotSupported, unbound)?;
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+        Ok(())
+    }
+
     /// Handle HTTP request forwarding (non-CONNECT)
-    async fn handle_http_request(mut client_stream: TcpStream, request: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn handle_http_request(mut client_stream: TcpStream, request: &str) -> EbtResult<()> {
         // Parse the request line to extract target host and port
         let first_line = request.lines().next().unwrap_or("");
         let parts: Vec<&str> = first_line.split_whitespace().collect();
@@ -309,7 +989,7 @@ impl<Phase: AllowsPerUserConnectionOwnership
                 (host, port, "/".to_string())
             }
         } else {
-            return Err("Only absolute HTTP URLs supported".into());
+            return Err(ProtocolError::Malformed("Only absolute HTTP URLs supported").into());
         };
         
         log!(LogLevel::Debug, "HTTP request forwarding");
@@ -382,7 +1062,7 @@ impl<Phase: AllowsPerUserConnectionOwnership
     }
     
     /// Forward data between client and target for HTTP requests
-    fn forward_http_streams(client_stream: TcpStream, target_stream: TcpStream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fn forward_http_streams(client_stream: TcpStream, target_stream: TcpStream) -> EbtResult<()> {
         let client = Arc::new(Mutex::new(client_stream));
         let target = Arc::new(Mutex::new(target_stream));
         
@@ -447,93 +1127,1382 @@ impl<Phase: AllowsPerUserConnectionOwnership
     }
 }
 
-struct PolicyAdapter {
-    engine: ContentPolicyEngine,
-    enabled: AtomicBool,
+impl RealProxyServer<LegacyPhase> {
+    /// Fluent alternative to `new`: only the listen address is required.
+    ///
+    /// ```ignore
+    /// let server = RealProxyServer::builder()
+    ///     .bind("127.0.0.1:8080")
+    ///     .policy(listener_policies)
+    ///     .dns(dns_policy)
+    ///     .build(config.capabilities.network_token()?)?;
+    /// ```
+    pub fn builder() -> RealProxyServerBuilder<Unset> {
+        RealProxyServerBuilder {
+            bind: Unset,
+            policy: ProxyPolicy::default(),
+            listener_policies: None,
+            dns: None,
+            relays: RelayConfig::default(),
+        }
+    }
 }
 
-impl PolicyAdapter {
-    fn new(engine: ContentPolicyEngine, enabled: bool) -> Self {
-        Self {
-            engine,
-            enabled: AtomicBool::new(enabled),
+/// Builds a `RealProxyServer`. Unset options keep the `ProxyPolicy`
+/// defaults; the content policy is off unless `policy` is given.
+pub struct RealProxyServerBuilder<Bind = String> {
+    bind: Bind,
+    policy: ProxyPolicy,
+    listener_policies: Option<ListenerPolicies>,
+    dns: Option<DnsPolicy>,
+    relays: RelayConfig,
+}
+
+impl<Bind> RealProxyServerBuilder<Bind> {
+    /// `address:port`; port 0 picks a free port.
+    pub fn bind(self, addr: impl Into<String>) -> RealProxyServerBuilder<String> {
+        RealProxyServerBuilder {
+            bind: addr.into(),
+            policy: self.policy,
+            listener_policies: self.listener_policies,
+            dns: self.dns,
+            relays: self.relays,
         }
     }
 
-    fn set_enabled(&self, enabled: bool) {
-        self.enabled.store(enabled, Ordering::Release);
+    /// Content-policy engines for this listener; see `build_listener_policies`.
+    pub fn policy(mut self, listener_policies: ListenerPolicies) -> Self {
+        self.listener_policies = Some(listener_policies);
+        self
     }
 
-    fn is_enabled(&self) -> bool {
-        self.enabled.load(Ordering::Acquire)
+    /// DoH providers are process-wide and replaced at `build`.
+    pub fn dns(mut self, dns: DnsPolicy) -> Self {
+        self.dns = Some(dns);
+        self
     }
 
-    fn evaluate(&self, request: &RequestMetadata) -> Decision {
-        self.engine.evaluate(request)
+    /// Required by relay builds; see `RealProxyServer::with_relays`.
+    pub fn relays(mut self, relays: RelayConfig) -> Self {
+        self.relays = relays;
+        self
     }
-}
 
-fn parse_headers(request: &str) -> std::collections::BTreeMap<String, String> {
-    let mut headers = std::collections::BTreeMap::new();
-    let mut lines = request.lines();
-    lines.next();
-    for line in lines {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            break;
-        }
-        if let Some((name, value)) = trimmed.split_once(':') {
-            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
-        }
+    pub fn mode(mut self, mode: ProxyMode) -> Self {
+        self.policy.mode = mode;
+        self
     }
-    headers
-}
 
+    /// Expected `Proxy-Authorization` value for control requests and tunnels.
+    pub fn credential(mut self, credential: impl Into<String>) -> Self {
+        self.policy.authentication = Some(AuthenticationPlaceholder {
+            enabled: true,
+            method: "basic".to_string(),
+            credential: Some(credential.into()),
+        });
+        self
+    }
+
+    pub fn block_behavior(mut self, block_behavior: BlockBehavior) -> Self {
+        self.policy.content_policy_block_behavior = block_behavior;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.policy.content_policy_dry_run = dry_run;
+        self
+    }
+
+    /// Check the TLS server name of CONNECTs to IP literals; see `client_hello`.
+    pub fn inspect_sni(mut self, inspect_sni: bool) -> Self {
+        self.policy.content_policy_inspect_sni = inspect_sni;
+        self
+    }
+
+    pub fn max_concurrent_tunnels(mut self, limit: usize) -> Self {
+        self.policy.max_concurrent_tunnels = limit;
+        self
+    }
+
+    /// Lets the tunnel limit adapt from `max_concurrent_tunnels` within
+    /// `limit`; run `RealProxyServer::spawn_adaptive_tunnel_limit`.
+    pub fn adaptive_tunnel_limit(mut self, limit: AdaptiveTunnelLimit) -> Self {
+        self.policy.adaptive_tunnel_limit = Some(limit);
+        self
+    }
+
+    /// Expect a PROXY v2 header on every connection from `balancers`, and
+    /// refuse all others; see `proxy_protocol`.
+    pub fn proxy_protocol(mut self, balancers: Vec<ClientSubnet>) -> Self {
+        self.policy.proxy_protocol = balancers;
+        self
+    }
+
+    pub fn header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.policy.header_limits = limits;
+        self
+    }
+
+    pub fn usage_summary(mut self, config: UsageSummaryConfig) -> Self {
+        self.policy.usage_summary = Some(config);
+        self
+    }
+
+    pub fn traffic_accounting(mut self, config: TrafficAccountingConfig) -> Self {
+        self.policy.traffic_accounting = Some(config);
+        self
+    }
+}
+
+impl RealProxyServerBuilder {
+    /// Validates the listen address; the server still needs `bind()`.
+    pub fn build(self, network: NetworkToken) -> Result<RealProxyServer<LegacyPhase>, ConfigError> {
+        let addr: std::net::SocketAddr = self.bind.parse().map_err(|_| ConfigError::Invalid {
+            field: "bind".to_string(),
+            reason: format!("{:?} is not an address:port", self.bind),
+        })?;
+        if self.policy.max_concurrent_tunnels == 0 {
+            return Err(ConfigError::Invalid {
+                field: "max_concurrent_tunnels".to_string(),
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        if let Some(adaptive) = self.policy.adaptive_tunnel_limit {
+            let start = self.policy.max_concurrent_tunnels;
+            if adaptive.min == 0 || adaptive.min > start || start > adaptive.max {
+                return Err(ConfigError::Invalid {
+                    field: "adaptive_tunnel_limit".to_string(),
+                    reason: "needs 1 <= min <= max_concurrent_tunnels <= max".to_string(),
+                });
+            }
+        }
+        let limits = self.policy.header_limits;
+        if limits.max_request_line == 0 || limits.max_request_line > HeaderLimits::MAX_REQUEST_LINE {
+            return Err(ConfigError::Invalid {
+                field: "header_limits.max_request_line".to_string(),
+                reason: format!("must be from 1 to {}", HeaderLimits::MAX_REQUEST_LINE),
+            });
+        }
+        let mut policy = self.policy;
+        policy.bind_address = addr.ip().to_string();
+        policy.bind_port = addr.port();
+        let listener_policies = self.listener_policies.unwrap_or_else(|| {
+            ListenerPolicies::single(ContentPolicyEngine::new(RuleSet::default()), false)
+        });
+        policy.content_policy_enabled = listener_policies.enabled;
+        let mut prefetch = Vec::new();
+        if let Some(dns) = self.dns {
+            set_doh_providers(dns.doh_providers);
+            prefetch = dns.prefetch;
+        }
+        Ok(RealProxyServer::new(policy, listener_policies, network)
+            .with_relays(self.relays)
+            .with_dns_prefetch(prefetch))
+    }
+}
+
+/// Listener-wide rule layers; the engine is rebuilt as `custom ++ base`.
+struct RuleLayers {
+    custom: RuleSet,
+    base: RuleSet,
+    clock: Arc<dyn PolicyClock>,
+}
+
+const DECISION_CACHE_CAPACITY: usize = MemoryProfile::active().decision_cache_entries;
+
+/// Bounded LRU of CONNECT decisions keyed by (host, port). Each cache belongs
+/// to one engine, so swapping the engine discards its cached decisions.
+struct DecisionCache {
+    capacity: usize,
+    state: Mutex<DecisionCacheState>,
+}
+
+#[derive(Default)]
+struct DecisionCacheState {
+    entries: HashMap<(String, u16), CachedDecision>,
+    /// Last-use tick -> key; the first entry is the least recently used.
+    recency: BTreeMap<u64, (String, u16)>,
+    tick: u64,
+}
+
+/// A decision and the rule that made it: its index in the engine's rules
+/// and its configured pattern.
+#[derive(Clone)]
+struct Verdict {
+    decision: Decision,
+    rule: Option<usize>,
+    rule_target: Option<String>,
+}
+
+#[derive(Clone)]
+struct CachedDecision {
+    verdict: Verdict,
+    last_used: u64,
+}
+
+impl DecisionCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(DecisionCacheState::default()),
+        }
+    }
+
+    fn get(&self, host: &str, port: u16) -> Option<CachedDecision> {
+        let mut state = self.state.lock().ok()?;
+        state.tick += 1;
+        let tick = state.tick;
+        let key = (host.to_string(), port);
+        let entry = state.entries.get_mut(&key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let hit = entry.clone();
+        state.recency.remove(&previous);
+        state.recency.insert(tick, key);
+        Some(hit)
+    }
+
+    fn insert(&self, host: &str, port: u16, verdict: Verdict) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let key = (host.to_string(), port);
+        if let Some(existing) = state.entries.remove(&key) {
+            state.recency.remove(&existing.last_used);
+        }
+        while state.entries.len() >= self.capacity {
+            match state.recency.pop_first() {
+                Some((_, oldest)) => {
+                    state.entries.remove(&oldest);
+                }
+                None => return,
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.recency.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CachedDecision {
+                verdict,
+                last_used: tick,
+            },
+        );
+    }
+}
+
+/// An engine with its own decision cache. Only the verdicts of the rules
+/// that depend on the target alone are cached; the rules that look at
+/// headers, referers or active hours are checked on every request, and
+/// win when they come first.
+struct CachedEngine {
+    engine: ContentPolicyEngine,
+    target_only: RuleSubset,
+    per_request: RuleSubset,
+    decisions: DecisionCache,
+}
+
+impl CachedEngine {
+    fn new(engine: ContentPolicyEngine) -> Self {
+        Self {
+            target_only: RuleSubset::new(&engine, Rule::is_target_only),
+            per_request: RuleSubset::new(&engine, |rule| !rule.is_target_only()),
+            engine,
+            decisions: DecisionCache::new(DECISION_CACHE_CAPACITY),
+        }
+    }
+
+    fn evaluate(&self, request: &RequestMetadata) -> Verdict {
+        let verdict = if request.method == "CONNECT" {
+            let cached = match self.decisions.get(&request.host, request.port) {
+                Some(hit) => {
+                    observability::record_policy_cache_hit();
+                    hit.verdict
+                }
+                None => {
+                    observability::record_policy_cache_miss();
+                    let verdict = self.target_only.evaluate(request);
+                    self.decisions.insert(&request.host, request.port, verdict.clone());
+                    verdict
+                }
+            };
+            let context = self.per_request.evaluate(request);
+            match (context.rule, cached.rule) {
+                (Some(context_rule), Some(cached_rule)) if context_rule > cached_rule => cached,
+                (Some(_), _) => context,
+                (None, _) => cached,
+            }
+        } else {
+            let evaluation = self.engine.evaluate_detailed(request);
+            Verdict {
+                decision: evaluation.decision,
+                rule: evaluation.matched_rule,
+                rule_target: self.engine.matched_rule(&evaluation).map(|rule| rule.target().to_string()),
+            }
+        };
+        record_rule_hit(verdict.rule_target.as_deref(), verdict.decision);
+        verdict
+    }
+}
+
+/// Some of an engine's rules, in their order, as an engine of their own.
+struct RuleSubset {
+    engine: ContentPolicyEngine,
+    /// Each rule's index in the whole engine.
+    indices: Vec<usize>,
+}
+
+impl RuleSubset {
+    fn new(whole: &ContentPolicyEngine, keep: impl Fn(&Rule) -> bool) -> Self {
+        let (indices, rules): (Vec<usize>, Vec<Rule>) =
+            whole.rules().rules().iter().cloned().enumerate().filter(|(_, rule)| keep(rule)).unzip();
+        Self {
+            engine: ContentPolicyEngine::new(RuleSet::new(rules)).with_clock(whole.clock()),
+            indices,
+        }
+    }
+
+    /// The verdict of the first matching rule of the subset, with the
+    /// rule's index in the whole engine.
+    fn evaluate(&self, request: &RequestMetadata) -> Verdict {
+        let evaluation = self.engine.evaluate_detailed(request);
+        Verdict {
+            decision: evaluation.decision,
+            rule: evaluation.matched_rule.map(|idx| self.indices[idx]),
+            rule_target: self.engine.matched_rule(&evaluation).map(|rule| rule.target().to_string()),
+        }
+    }
+}
+
+fn record_rule_hit(rule_target: Option<&str>, decision: Decision) {
+    if let Some(target) = rule_target {
+        let blocked = matches!(decision, Decision::Block { .. });
+        observability::record_policy_rule_hit(target, blocked);
+    }
+}
+
+struct PolicyAdapter {
+    engine: ArcSwap<CachedEngine>,
+    layers: Mutex<RuleLayers>,
+    /// Per-client profiles, most specific prefix first; replaced whole on reload.
+    subnet_engines: ArcSwap<Vec<(ClientSubnet, CachedEngine)>>,
+    enabled: AtomicBool,
+    dry_run: AtomicBool,
+    /// Check the ClientHello server name of CONNECTs to IP literals.
+    inspect_sni: AtomicBool,
+    block_behavior: ArcSwap<BlockBehavior>,
+    /// Survives reloads.
+    bypass: BypassTable,
+    /// Survives reloads; rule indices refer to the rules at decision time.
+    audit: PolicyAudit,
+}
+
+impl PolicyAdapter {
+    fn new(engine: ContentPolicyEngine, enabled: bool) -> Self {
+        Self::from_listener(ListenerPolicies::single(engine, enabled))
+    }
+
+    fn from_listener(policies: ListenerPolicies) -> Self {
+        let enabled = policies.enabled;
+        let (layers, engine, subnet_engines) = split_listener_policies(policies);
+        Self {
+            engine: ArcSwap::from_pointee(CachedEngine::new(engine)),
+            layers: Mutex::new(layers),
+            subnet_engines: ArcSwap::from_pointee(subnet_engines),
+            enabled: AtomicBool::new(enabled),
+            dry_run: AtomicBool::new(false),
+            inspect_sni: AtomicBool::new(false),
+            block_behavior: ArcSwap::from_pointee(BlockBehavior::default()),
+            bypass: BypassTable::default(),
+            audit: PolicyAudit::default(),
+        }
+    }
+
+    fn with_block_behavior(self, block_behavior: BlockBehavior) -> Self {
+        self.block_behavior.store(Arc::new(block_behavior));
+        self
+    }
+
+    /// Swaps in a rebuilt listener policy. Requests already evaluated keep
+    /// their decision; bypasses stay in place.
+    fn reload(&self, policies: ListenerPolicies, block_behavior: BlockBehavior, dry_run: bool, inspect_sni: bool) {
+        let enabled = policies.enabled;
+        let (layers, engine, subnet_engines) = split_listener_policies(policies);
+        if let Ok(mut current) = self.layers.lock() {
+            *current = layers;
+            self.engine.store(Arc::new(CachedEngine::new(engine)));
+            self.subnet_engines.store(Arc::new(subnet_engines));
+        }
+        self.block_behavior.store(Arc::new(block_behavior));
+        self.dry_run.store(dry_run, Ordering::Release);
+        self.inspect_sni.store(inspect_sni, Ordering::Release);
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    fn with_dry_run(self, dry_run: bool) -> Self {
+        self.dry_run.store(dry_run, Ordering::Release);
+        self
+    }
+
+    fn with_inspect_sni(self, inspect_sni: bool) -> Self {
+        self.inspect_sni.store(inspect_sni, Ordering::Release);
+        self
+    }
+
+    fn block_response(&self, reason: ReasonCode) -> BlockResponse {
+        let block_behavior = self.block_behavior.load();
+        match reason {
+            ReasonCode::Ads => block_behavior.ads,
+            ReasonCode::Tracking => block_behavior.tracking,
+            ReasonCode::Malware => block_behavior.malware,
+            ReasonCode::Custom => block_behavior.custom,
+            ReasonCode::Unknown => block_behavior.unknown,
+        }
+    }
+
+    fn replace_base(&self, base: RuleSet) {
+        if let Ok(mut layers) = self.layers.lock() {
+            layers.base = base;
+            self.engine.store(Arc::new(CachedEngine::new(layers.combined())));
+        }
+    }
+
+    fn replace_custom(&self, custom: RuleSet) {
+        if let Ok(mut layers) = self.layers.lock() {
+            layers.custom = custom;
+            self.engine.store(Arc::new(CachedEngine::new(layers.combined())));
+        }
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    fn evaluate(&self, client_ip: Option<IpAddr>, request: &RequestMetadata) -> Verdict {
+        if let Some(ip) = client_ip {
+            let subnet_engines = self.subnet_engines.load();
+            if let Some((_, engine)) = subnet_engines.iter().find(|(subnet, _)| subnet.contains(ip)) {
+                return engine.evaluate(request);
+            }
+        }
+        self.engine.load().evaluate(request)
+    }
+}
+
+/// Rule layers, listener engine and per-subnet engines for a `PolicyAdapter`.
+fn split_listener_policies(
+    policies: ListenerPolicies,
+) -> (RuleLayers, ContentPolicyEngine, Vec<(ClientSubnet, CachedEngine)>) {
+    let layers = RuleLayers {
+        custom: policies.custom,
+        base: policies.default.rules().clone(),
+        clock: policies.default.clock(),
+    };
+    let engine = if layers.custom.rules().is_empty() {
+        policies.default
+    } else {
+        layers.combined()
+    };
+    // Stable sort keeps configuration order among equally specific entries.
+    let mut subnet_engines: Vec<_> = policies
+        .by_subnet
+        .into_iter()
+        .map(|(subnet, engine)| (subnet, CachedEngine::new(engine)))
+        .collect();
+    subnet_engines.sort_by_key(|(subnet, _)| std::cmp::Reverse(subnet.prefix_len()));
+    (layers, engine, subnet_engines)
+}
+
+impl RuleLayers {
+    fn combined(&self) -> ContentPolicyEngine {
+        let mut rules = self.custom.rules().to_vec();
+        rules.extend_from_slice(self.base.rules());
+        ContentPolicyEngine::new(RuleSet::new(rules)).with_clock(Arc::clone(&self.clock))
+    }
+}
+
+const CUSTOM_RULES_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Files modified more recently than this are hashed even when their
+/// metadata looks unchanged: a rewrite within the timestamp granularity
+/// of the filesystem (two seconds on FAT) may not show in it.
+const RECENT_FILE_AGE: Duration = Duration::from_secs(3);
+/// How long a CONNECT to an IP literal waits for the client's ClientHello
+/// before it is spliced unchecked, e.g. for protocols where the server
+/// speaks first.
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Global tunnel concurrency. Raising the limit frees slots at once;
+/// lowering it retires slots as running tunnels finish, never by dropping one.
+struct TunnelLimit {
+    semaphore: Arc<Semaphore>,
+    limit: Mutex<usize>,
+}
+
+impl TunnelLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit: Mutex::new(limit),
+        }
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.semaphore).acquire_owned().await.ok()
+    }
+
+    fn limit(&self) -> usize {
+        self.limit.lock().map(|limit| *limit).unwrap_or(0)
+    }
+
+    /// Slots taken, counting those a lowered limit is still retiring.
+    fn in_use(&self) -> usize {
+        self.limit().saturating_sub(self.semaphore.available_permits())
+    }
+
+    fn set(&self, limit: usize) {
+        let Ok(mut current) = self.limit.lock() else {
+            return;
+        };
+        if limit > *current {
+            self.semaphore.add_permits(limit - *current);
+        } else if limit < *current {
+            let excess = (*current - limit) as u32;
+            let semaphore = Arc::clone(&self.semaphore);
+            task::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+        *current = limit;
+    }
+}
+
+/// Fewest tunnels closed in an interval whose failure share is judged.
+const ADAPTIVE_MIN_CLOSED: u64 = 10;
+
+/// What an adaptive tunnel limit saw over one interval.
+struct LimitSample {
+    available_memory: Option<usize>,
+    closed: u64,
+    upstream_failed: u64,
+}
+
+/// One step of an adaptive tunnel limit; see `AdaptiveTunnelLimit`. It
+/// shrinks faster than it grows, as gradient limiters do, so it settles
+/// just below where pressure shows.
+fn next_tunnel_limit(current: usize, in_use: usize, sample: &LimitSample, bounds: &AdaptiveTunnelLimit) -> usize {
+    let low_memory = sample.available_memory.is_some_and(|available| available < bounds.min_available_memory);
+    let failing = sample.closed >= ADAPTIVE_MIN_CLOSED
+        && sample.upstream_failed * 100 > sample.closed * u64::from(bounds.max_failure_percent);
+    let next = if low_memory || failing {
+        current.saturating_sub((current / 10).max(1))
+    } else if in_use * 10 >= current * 9 {
+        current + ((current as f64).sqrt() as usize).max(1)
+    } else {
+        current
+    };
+    next.clamp(bounds.min, bounds.max)
+}
+
+/// Re-reads the configuration for a reload.
+pub type ConfigSource = Arc<dyn Fn() -> Result<TunnelConfig, ConfigError> + Send + Sync>;
+
+/// Applies reloadable settings: content policy, DNS providers, tunnel limit
+/// and log levels. Everything else is fixed at start-up.
+struct ConfigReloader {
+    source: ConfigSource,
+    /// Last applied configuration; also serializes concurrent reloads.
+    current: tokio::sync::Mutex<TunnelConfig>,
+    policy_adapter: Arc<PolicyAdapter>,
+    tunnel_limit: Arc<TunnelLimit>,
+    shaping: Arc<ShapingControl>,
+}
+
+impl ConfigReloader {
+    async fn reload(&self) -> EbtResult<()> {
+        let result = self.apply().await;
+        match &result {
+            Ok(()) => {
+                log!(LogLevel::Info, "Configuration reloaded");
+                event_bus::publish(EbtEvent::ConfigReloaded);
+            }
+            Err(e) => log!(
+                LogLevel::Error,
+                "Configuration reload rejected; keeping current settings";
+                safe "error" => e
+            ),
+        }
+        result
+    }
+
+    async fn apply(&self) -> EbtResult<()> {
+        let next = (self.source)()?;
+        let mut current = self.current.lock().await;
+        let changed = restart_only_changes(&current, &next);
+        if !changed.is_empty() {
+            return Err(ConfigError::RequiresRestart {
+                settings: changed.join(", "),
+            }
+            .into());
+        }
+        // Build everything before swapping anything, so a bad ruleset
+        // leaves the running configuration whole.
+        let proxy = &next.proxy_policy;
+        let library = RulesetLibrary::load(&proxy.content_policy_rulesets).await?;
+        let listener_policies = build_listener_policies(proxy, &library).await?;
+        record_policy_load(&listener_policies);
+        // A profile switched at runtime survives reloads that leave the
+        // file's anonymity settings alone.
+        if next.anonymity != current.anonymity {
+            self.shaping
+                .set(next.anonymity.clone())
+                .map_err(|reason| ConfigError::Invalid {
+                    field: "anonymity.mix_delay".to_string(),
+                    reason: reason.to_string(),
+                })?;
+        }
+        self.policy_adapter.reload(
+            listener_policies,
+            proxy.content_policy_block_behavior,
+            proxy.content_policy_dry_run,
+            proxy.content_policy_inspect_sni,
+        );
+        set_doh_providers(next.dns_policy.doh_providers.clone());
+        // Unchanged, it leaves an adapted limit where it has moved to.
+        if proxy.max_concurrent_tunnels != current.proxy_policy.max_concurrent_tunnels {
+            self.tunnel_limit.set(proxy.max_concurrent_tunnels);
+        }
+        logging::init(next.observability.log.clone().unwrap_or_else(LogConfig::from_env));
+        *current = next;
+        Ok(())
+    }
+}
+
+/// The parts of a running server that `control_socket` reads and changes.
+pub(crate) struct ProxyControl {
+    listen: Option<std::net::SocketAddr>,
+    relay_mode: RelayMode,
+    policy_adapter: Arc<PolicyAdapter>,
+    tunnels: Arc<TunnelRegistry>,
+    config_reload: Option<Arc<ConfigReloader>>,
+    shaping: Arc<ShapingControl>,
+}
+
+impl ProxyControl {
+    pub(crate) fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "listen": self.listen.map(|addr| addr.to_string()),
+            "health": observability::get_health().as_str(),
+            "relay_mode": self.relay_mode.as_str(),
+            "open_tunnels": self.tunnels.open_count(),
+            "content_policy": {
+                "enabled": self.policy_adapter.is_enabled(),
+                "dry_run": self.policy_adapter.dry_run.load(Ordering::Acquire),
+            },
+            "shaping_profile": self.shaping.profile().map_or("custom", |profile| profile.as_str()),
+        })
+    }
+
+    pub(crate) fn tunnels_json(&self) -> String {
+        self.tunnels.to_json()
+    }
+
+    pub(crate) fn set_content_policy_enabled(&self, enabled: bool) {
+        self.policy_adapter.set_enabled(enabled);
+        log!(LogLevel::Info, "Content policy switched"; safe "enabled" => enabled);
+    }
+
+    /// `None` when the server was built without a config source.
+    pub(crate) async fn reload(&self) -> Option<EbtResult<()>> {
+        Some(self.config_reload.as_ref()?.reload().await)
+    }
+}
+
+/// What the connections accepted on one listener share: the server's state
+/// and the settings it was started with. Built once per listener.
+struct ConnectionContext {
+    policy_adapter: Arc<PolicyAdapter>,
+    proxy_credential: Option<Arc<String>>,
+    tunnels: Arc<TunnelRegistry>,
+    recent_events: Arc<RecentEvents>,
+    config_reload: Option<Arc<ConfigReloader>>,
+    relays: Arc<RelaySelector>,
+    shaping: Arc<ShapingControl>,
+    prefetch: Arc<DnsPrefetcher>,
+    network: NetworkToken,
+    traffic_shaping: bool,
+    connect_retry: ConnectRetry,
+    header_limits: HeaderLimits,
+    proxy_protocol: Vec<ClientSubnet>,
+    listener_port: u16,
+    udp: UdpMode,
+}
+
+/// Settings that differ between `old` and `new` but only take effect at
+/// start-up: the listener socket, credentials, relays, background writers
+/// and the rule watchers spawned next to the listener.
+fn restart_only_changes(old: &TunnelConfig, new: &TunnelConfig) -> Vec<&'static str> {
+    let (a, b) = (&old.proxy_policy, &new.proxy_policy);
+    let custom_rules_watch =
+        |p: &ProxyPolicy| p.content_policy_custom_rules.clone().filter(|_| p.content_policy_enabled);
+    [
+        ("execution.mode", old.capabilities != new.capabilities),
+        ("proxy.bind_address", a.bind_address != b.bind_address),
+        ("proxy.bind_port", a.bind_port != b.bind_port),
+        ("proxy.mode", a.mode != b.mode),
+        ("proxy.configure_system", a.configure_system != b.configure_system),
+        ("proxy.kill_switch", a.kill_switch != b.kill_switch),
+        ("proxy.control_socket", a.control_socket != b.control_socket),
+        ("proxy.authentication", a.authentication != b.authentication),
+        ("proxy.traffic_shaping", a.traffic_shaping != b.traffic_shaping),
+        ("proxy.udp", a.udp != b.udp),
+        ("proxy.adaptive_tunnel_limit", a.adaptive_tunnel_limit != b.adaptive_tunnel_limit),
+        ("transport", old.transport != new.transport),
+        ("relay", old.relay != new.relay),
+        ("dns.resolution", old.dns_policy.resolution_location != new.dns_policy.resolution_location),
+        ("dns.leak_detection", old.dns_policy.leak_detection != new.dns_policy.leak_detection),
+        ("dns.prefetch", old.dns_policy.prefetch != new.dns_policy.prefetch),
+        ("policy.custom_rules", custom_rules_watch(a) != custom_rules_watch(b)),
+        ("policy.rules refresh schedule", refresh_schedule(a) != refresh_schedule(b)),
+        ("observability.usage_summary", a.usage_summary != b.usage_summary),
+        ("observability.traffic_accounting", a.traffic_accounting != b.traffic_accounting),
+        (
+            "observability.notifications",
+            old.observability.notifications != new.observability.notifications,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(setting, changed)| changed.then_some(setting))
+    .collect()
+}
+
+/// A store that exists but cannot be read is left untouched rather than
+/// overwritten, and accounting stays off.
+fn open_traffic_accounting(policy: &ProxyPolicy) -> Option<TrafficAccounting> {
+    let config = policy.traffic_accounting.clone()?;
+    let baseline = observability::usage_totals()?;
+    match TrafficAccounting::open(config, baseline) {
+        Ok(accounting) => Some(accounting),
+        Err(e) => {
+            log!(LogLevel::Error, "Traffic accounting disabled: store unreadable"; safe "error" => e);
+            None
+        }
+    }
+}
+
+fn save_traffic_accounting(accounting: &Mutex<TrafficAccounting>) {
+    let Some(totals) = observability::usage_totals() else {
+        return;
+    };
+    let Ok(mut accounting) = accounting.lock() else {
+        return;
+    };
+    let now = unix_now_secs();
+    accounting.record(totals, now);
+    if let Err(e) = accounting.save(now) {
+        log!(LogLevel::Error, "Traffic accounting save failed"; safe "error" => e);
+    }
+}
+
+/// SHA-256 of a file's contents. Unlike its mtime, this notices rewrites
+/// within the filesystem's timestamp granularity and atomic-rename saves
+/// that keep the old timestamp.
+fn file_digest(path: &str) -> Option<Vec<u8>> {
+    let contents = std::fs::read(path).ok()?;
+    Some(ring::digest::digest(&ring::digest::SHA256, &contents).as_ref().to_vec())
+}
+
+/// Notices writes to a rules file without reading it on every poll: the
+/// file is only hashed, off the runtime's threads, when its metadata
+/// changed or while it is recent.
+struct RulesFileWatch {
+    path: String,
+    stamp: Option<FileStamp>,
+    digest: Option<Vec<u8>>,
+}
+
+impl RulesFileWatch {
+    async fn new(path: String) -> Self {
+        let stamp = FileStamp::read(&path).await;
+        let digest = Self::hash(&path).await;
+        Self { path, stamp, digest }
+    }
+
+    /// True once per change of the file's contents, its removal included.
+    async fn changed(&mut self) -> bool {
+        // Read before hashing, so a write in between shows next time.
+        let stamp = FileStamp::read(&self.path).await;
+        if stamp == self.stamp && !stamp.as_ref().is_some_and(FileStamp::is_recent) {
+            return false;
+        }
+        self.stamp = stamp;
+        let digest = Self::hash(&self.path).await;
+        if digest == self.digest {
+            return false;
+        }
+        self.digest = digest;
+        true
+    }
+
+    async fn hash(path: &str) -> Option<Vec<u8>> {
+        let path = path.to_string();
+        task::spawn_blocking(move || file_digest(&path)).await.ok().flatten()
+    }
+}
+
+/// The metadata a write to a file changes. On Unix that includes the
+/// inode, which atomic-rename saves replace, and the change time, which
+/// unlike the mtime cannot be set back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+    #[cfg(unix)]
+    changed: (u64, i64, i64),
+}
+
+impl FileStamp {
+    async fn read(path: &str) -> Option<Self> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            changed: {
+                use std::os::unix::fs::MetadataExt;
+                (metadata.ino(), metadata.ctime(), metadata.ctime_nsec())
+            },
+        })
+    }
+
+    fn is_recent(&self) -> bool {
+        self.modified.and_then(|modified| modified.elapsed().ok()).is_none_or(|age| age < RECENT_FILE_AGE)
+    }
+}
+
+const BYPASS_CONTROL_POST: &str = "POST /ebt/bypass?";
+const BYPASS_CONTROL_DELETE: &str = "DELETE /ebt/bypass?";
+const BYPASS_MAX_MINUTES: u64 = 24 * 60;
+const TUNNELS_CONTROL_GET: &str = "GET /ebt/tunnels ";
+const TUNNEL_SUMMARY_CONTROL_GET: &str = "GET /ebt/tunnels/summary ";
+const RELAYS_CONTROL_GET: &str = "GET /ebt/relays ";
+const EVENTS_CONTROL_GET: &str = "GET /ebt/events ";
+/// Events kept for `GET /ebt/events`.
+const RECENT_EVENTS_LIMIT: usize = MemoryProfile::active().recent_events;
+/// Followed by a space, or by `?host=H` to list only decisions about `H`.
+const DECISIONS_CONTROL_GET: &str = "GET /ebt/decisions";
+const RELOAD_CONTROL_POST: &str = "POST /ebt/reload ";
+const PROFILE_CONTROL_GET: &str = "GET /ebt/profile ";
+const PROFILE_CONTROL_POST: &str = "POST /ebt/profile?";
+const OBS_CONTROL_GET: &str = "GET /debug/obs ";
+const HEALTHZ_GET: &str = "GET /healthz ";
+/// Lowercase, as `parse_headers` keys are.
+const CONTROL_HEADER: &str = "x-ebt-control";
+const HEALTH_EVALUATION_INTERVAL: Duration = Duration::from_secs(10);
+/// One minute of samples at `HEALTH_EVALUATION_INTERVAL`.
+const HEALTH_WINDOW_SAMPLES: usize = 6;
+
+/// Degraded still reports 200 so supervisors only restart on a fault.
+fn healthz_response(state: HealthState) -> Vec<u8> {
+    let body = format!("{}\n", state.as_str());
+    if state == HealthState::FAULTED {
+        format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    } else {
+        control_body_response("text/plain", &body)
+    }
+}
+
+fn control_body_response(content_type: &str, body: &str) -> Vec<u8> {
+    status_body_response("200 OK", content_type, body)
+}
+
+fn status_body_response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// 409 when the new config needs a restart, 422 when it does not load.
+fn reload_response(result: EbtResult<()>) -> Vec<u8> {
+    match result {
+        Ok(()) => control_body_response("text/plain", "reloaded\n"),
+        Err(e @ EbtError::Config(ConfigError::RequiresRestart { .. })) => {
+            status_body_response("409 Conflict", "text/plain", &format!("{}\n", e))
+        }
+        Err(e) => status_body_response("422 Unprocessable Entity", "text/plain", &format!("{}\n", e)),
+    }
+}
+
+/// Runtime "allow for N minutes" overrides, keyed by lowercase domain.
+/// Entries cover subdomains; lookups ignore expired ones, which are
+/// dropped the next time an entry is added.
+#[derive(Default)]
+struct BypassTable {
+    entries: Mutex<HashMap<String, Instant>>,
+}
+
+impl BypassTable {
+    fn allow(&self, domain: &str, duration: Duration) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let now = Instant::now();
+            entries.retain(|_, expires| *expires > now);
+            entries.insert(normalize_domain(domain), now + duration);
+        }
+    }
+
+    fn clear(&self, domain: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(&normalize_domain(domain));
+        }
+    }
+
+    fn is_bypassed(&self, host: &str, now: Instant) -> bool {
+        let Ok(entries) = self.entries.lock() else {
+            return false;
+        };
+        if entries.is_empty() {
+            return false;
+        }
+        let host = normalize_domain(host);
+        let mut candidate = host.as_str();
+        loop {
+            if entries.get(candidate).is_some_and(|expires| *expires > now) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    hostname::canonical_or_lowercase(domain.trim())
+}
+
+/// `POST /ebt/bypass?domain=D&minutes=N` or `DELETE /ebt/bypass?domain=D`.
+fn handle_bypass_control(bypass: &BypassTable, request: &str) -> bool {
+    let first_line = request.lines().next().unwrap_or("");
+    let mut parts = first_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+    let Some((_, query)) = target.split_once('?') else {
+        return false;
+    };
+    let mut domain = None;
+    let mut minutes = None;
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some(("domain", value)) if !value.is_empty() => domain = Some(value),
+            Some(("minutes", value)) => minutes = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+    let Some(domain) = domain else {
+        return false;
+    };
+    match (method, minutes) {
+        ("POST", Some(minutes)) if (1..=BYPASS_MAX_MINUTES).contains(&minutes) => {
+            bypass.allow(domain, Duration::from_secs(minutes * 60));
+            true
+        }
+        ("DELETE", _) => {
+            bypass.clear(domain);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The `host` of `GET /ebt/decisions?host=H`.
+fn decisions_control_host(request: &str) -> Option<&str> {
+    let target = request.lines().next()?.split_whitespace().nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+        Some(("host", value)) if !value.is_empty() => Some(value),
+        _ => None,
+    })
+}
+
+/// `POST /ebt/profile?name=P`, with `P` one of `ShapingProfile::as_str`.
+fn profile_control_target(request: &str) -> Option<ShapingProfile> {
+    let target = request.lines().next()?.split_whitespace().nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+        Some(("name", value)) => ShapingProfile::parse(value),
+        _ => None,
+    })
+}
+
+const BLOCK_PAGE_TEMPLATE: &str = "<!DOCTYPE html><html><head><title>Blocked</title></head>\
+<body><h1>Request blocked</h1><p>This request was blocked by the local content policy ({reason}).</p></body></html>";
+
+/// Response bytes for a blocked request; `None` means reset the connection.
+fn block_response_bytes(response: BlockResponse, reason: ReasonCode) -> Option<Vec<u8>> {
+    let blocked = ErrorResponse::new(ErrorReason::PolicyBlocked);
+    let category = format!("category={}", reason.as_str());
+    match response {
+        BlockResponse::Forbidden => Some(blocked.to_bytes()),
+        BlockResponse::ForbiddenWithReason => Some(blocked.detail(category).to_bytes()),
+        BlockResponse::BlockPage => {
+            let body = BLOCK_PAGE_TEMPLATE.replace("{reason}", reason.as_str());
+            Some(blocked.detail(category).body("text/html; charset=utf-8", body).to_bytes())
+        }
+        BlockResponse::Reset => None,
+    }
+}
+
+/// The destination of an absolute-form `http://` request, the only kind a
+/// browser sends a proxy for plain HTTP. Port 80 unless one is given.
+fn plain_http_target(request: &str) -> Option<Authority> {
+    let target = request.lines().next()?.split(' ').nth(1)?;
+    let rest = target.get(..7).filter(|scheme| scheme.eq_ignore_ascii_case("http://")).map(|_| &target[7..])?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let has_port = match authority.rsplit_once(']') {
+        Some((_, after)) => after.starts_with(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        authority::parse_authority(authority).ok()
+    } else {
+        authority::parse_authority(&format!("{}:80", authority)).ok()
+    }
+}
+
+/// Browsers never show a body sent in answer to CONNECT, so a blocked
+/// tunnel gets the block page's 403 and reason header without the page.
+fn connect_block_response(response: BlockResponse) -> BlockResponse {
+    match response {
+        BlockResponse::BlockPage => BlockResponse::ForbiddenWithReason,
+        response => response,
+    }
+}
+
+/// Rule-count metric for freshly loaded policies; scheduled refreshes
+/// record it themselves.
+fn record_policy_load(policies: &ListenerPolicies) {
+    observability::record_policy_refresh(policies.default.rules().rules().len() as u64, unix_now_secs());
+}
+
+fn unix_now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Everything a client sends before its tunnel opens: the PROXY header,
+/// SOCKS5 negotiation or the HTTP request head. Each read is still bounded
+/// by the stream's read timeout, but a client that sends a byte before each
+/// one expires would never hit it; all of it must also arrive within
+/// `header_read_timeout`, and at `min_header_rate` on average once
+/// `header_rate_grace` has passed, so a trickle cannot hold a tunnel slot.
+/// Reads past that budget fail with `TimedOut`.
+struct PreTunnelReader {
+    stream: TcpStream,
+    limits: HeaderLimits,
+    per_read: Duration,
+    started: Instant,
+    received: usize,
+}
+
+impl PreTunnelReader {
+    fn new(stream: TcpStream, limits: HeaderLimits) -> std::io::Result<Self> {
+        Ok(Self {
+            per_read: stream.read_timeout()?.unwrap_or(limits.header_read_timeout),
+            stream,
+            limits,
+            started: Instant::now(),
+            received: 0,
+        })
+    }
+
+    /// Time left in the budget, or `None` once it is spent.
+    fn remaining(&self) -> Option<Duration> {
+        let elapsed = self.started.elapsed();
+        let remaining = self.limits.header_read_timeout.saturating_sub(elapsed);
+        let spent = remaining.is_zero() || head_read_too_slow(self.received, elapsed, &self.limits);
+        (!spent).then_some(remaining)
+    }
+
+    fn arm(&self) -> std::io::Result<()> {
+        let remaining = self.remaining().ok_or(std::io::ErrorKind::TimedOut)?;
+        self.stream.set_read_timeout(Some(self.per_read.min(remaining)))
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.arm()?;
+        self.stream.peek(buf)
+    }
+
+    /// The stream, with reads no longer timed.
+    fn into_inner(self) -> TcpStream {
+        let _ = self.stream.set_read_timeout(None);
+        self.stream
+    }
+}
+
+impl Read for PreTunnelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.arm()?;
+        let read = self.stream.read(buf)?;
+        self.received += read;
+        Ok(read)
+    }
+}
+
+impl Write for PreTunnelReader {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Reads a request head in chunks up to its blank line, returning the bytes
+/// read and where the head ends in them.
+fn read_request_head(reader: &mut PreTunnelReader) -> EbtResult<(Vec<u8>, usize)> {
+    let limits = reader.limits;
+    let mut buffer = Vec::new();
+    let mut chunk_buf = [0u8; 4096];
+    loop {
+        if reader.remaining().is_none() {
+            let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+            return Err(ProtocolError::HeadersTimedOut.into());
+        }
+        match reader.read(&mut chunk_buf) {
+            Ok(0) => {
+                // true EOF: client closed before completing headers
+                let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+                return Err(ProtocolError::HeadersIncomplete.into());
+            }
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk_buf[..n]);
+                let end = buffer.windows(4).position(|window| window == b"\r\n\r\n").map(|pos| pos + 4);
+                let head = &buffer[..end.unwrap_or(buffer.len())];
+                if let Some(error) = exceeded_head_limit(head, end.is_some(), &limits) {
+                    let reason = match error {
+                        ProtocolError::RequestLineTooLong => ErrorReason::RequestLineTooLong,
+                        _ => ErrorReason::HeadersTooLarge,
+                    };
+                    let _ = reader.stream.write_all(&ErrorResponse::new(reason).to_bytes());
+                    let _ = reader.stream.flush();
+                    let _ = reader.stream.shutdown(std::net::Shutdown::Both);
+                    return Err(error.into());
+                }
+                if let Some(end) = end {
+                    return Ok((buffer, end));
+                }
+            }
+            // An expired read is judged by the checks above, not on its own
+            Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether `received` bytes of a head in `elapsed` are below the minimum rate.
+fn head_read_too_slow(received: usize, elapsed: Duration, limits: &HeaderLimits) -> bool {
+    elapsed >= limits.header_rate_grace && (received as f64) < limits.min_header_rate as f64 * elapsed.as_secs_f64()
+}
+
+/// The limit a request head read so far already breaks, if any. `head`
+/// ends at its blank line once `complete`.
+fn exceeded_head_limit(head: &[u8], complete: bool, limits: &HeaderLimits) -> Option<ProtocolError> {
+    // A trailing CR may still be followed by its LF
+    let line = head
+        .windows(2)
+        .position(|pair| pair == b"\r\n")
+        .unwrap_or(head.len() - usize::from(head.ends_with(b"\r")));
+    if line > limits.max_request_line {
+        return Some(ProtocolError::RequestLineTooLong);
+    }
+    if head.len() > limits.max_header_bytes {
+        return Some(ProtocolError::HeadersTooLarge);
+    }
+    // Every CRLF after the request line's ends a field, except the blank line's
+    let line_ends = head.windows(2).filter(|pair| *pair == b"\r\n").count();
+    let fields = line_ends.saturating_sub(1 + usize::from(complete));
+    (fields > limits.max_header_count).then_some(ProtocolError::HeadersTooLarge)
+}
+
+fn parse_headers(request: &str) -> std::collections::BTreeMap<String, String> {
+    let mut headers = std::collections::BTreeMap::new();
+    let mut lines = request.lines();
+    lines.next();
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = trimmed.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    headers
+}
+
+/// Credentials are compared in constant time; never use `==` here.
+fn proxy_auth_allows(expected: Option<&str>, request: &str) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+    match parse_headers(request).get("proxy-authorization") {
+        Some(presented) => constant_time_eq_str(presented, expected),
+        None => false,
+    }
+}
+
+/// Control requests come from local tools, never from web pages. Besides a
+/// loopback client and the proxy credential, if any, they must carry
+/// `X-EBT-Control`, which a page cannot send cross-origin without a CORS
+/// preflight that is never granted. Browser requests are recognized by
+/// `Origin` or `Sec-Fetch-Site: cross-site`, and a `Host` other than a
+/// loopback address or `localhost` is a DNS-rebound page.
+fn control_request_allowed(loopback: bool, credential: Option<&str>, request: &str) -> bool {
+    let headers = parse_headers(request);
+    loopback
+        && proxy_auth_allows(credential, request)
+        && headers.get(CONTROL_HEADER).is_some_and(|value| !value.is_empty())
+        && !headers.contains_key("origin")
+        && !headers.get("sec-fetch-site").is_some_and(|site| site.eq_ignore_ascii_case("cross-site"))
+        && headers.get("host").is_some_and(|host| is_loopback_host(host))
+}
+
+/// `host[:port]` naming this machine: `localhost` or a loopback literal.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map_or(bracketed, |(name, _)| name),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// The host is canonicalized here, whichever path it arrived by, so rules
+/// see one spelling of it.
 fn build_connect_metadata(request: &str, host: &str, port: u16) -> RequestMetadata {
     let headers = parse_headers(request);
+    let host = hostname::canonical_or_lowercase(host);
     let full_url = format!("https://{}:{}", host, port);
     RequestMetadata::new(
         "CONNECT".to_string(),
         full_url,
-        host.to_string(),
+        host,
         port,
         headers,
     )
 }
 
+/// With `policy.inspect_sni`, evaluates the policy again for the server
+/// name a client sends in its ClientHello after a CONNECT to an IP literal.
+/// Still at the proxy edge: the tunnel has not been spliced yet.
+fn policy_allows_server_name(
+    policy_adapter: &PolicyAdapter,
+    client_ip: Option<IpAddr>,
+    request: &str,
+    stream: &TcpStream,
+    host: &str,
+    port: u16,
+) -> Result<(), ReasonCode> {
+    let inspect = policy_adapter.is_enabled() && policy_adapter.inspect_sni.load(Ordering::Acquire);
+    if !inspect || host.parse::<IpAddr>().is_err() {
+        return Ok(());
+    }
+    let Some(name) = client_hello::peek_server_name(stream, SNI_PEEK_TIMEOUT) else {
+        return Ok(());
+    };
+    let Ok(name) = hostname::canonicalize(&name) else {
+        return Ok(());
+    };
+    log!(LogLevel::Debug, "Checking TLS server name of an IP CONNECT"; sensitive "server_name" => name);
+    policy_allows_connect(policy_adapter, client_ip, request, &name, port)
+}
+
 /// WARNING (Phase 7.5 FROZEN): keep policy logic at the proxy edge only.
 /// Do not pass policy decisions into relay protocol or transport layers.
 fn policy_allows_connect(
     policy_adapter: &PolicyAdapter,
+    client_ip: Option<IpAddr>,
     request: &str,
     host: &str,
     port: u16,
-) -> bool {
+) -> Result<(), ReasonCode> {
     if !policy_adapter.is_enabled() {
-        return true;
+        return Ok(());
     }
 
+    let span = ObsSpan::policy_evaluation();
+    let _entered = span.clone().entered();
     let metadata = build_connect_metadata(request, host, port);
-    match policy_adapter.evaluate(&metadata) {
+    let verdict = policy_adapter.evaluate(client_ip, &metadata);
+    let decision = verdict.decision;
+    let audit = |outcome| {
+        let rule_target = verdict.rule_target.as_deref();
+        policy_adapter.audit.record(host, port, outcome, verdict.rule, rule_target);
+    };
+    span.record(
+        "decision",
+        &match decision {
+            Decision::Allow => "allow",
+            Decision::Block { .. } => "block",
+        },
+    );
+    match decision {
         Decision::Allow => {
+            audit(AuditOutcome::Allowed);
             observability::record_policy_allowed();
-            true
+            Ok(())
+        }
+        Decision::Block { reason } if policy_adapter.bypass.is_bypassed(host, Instant::now()) => {
+            audit(AuditOutcome::Bypassed(reason));
+            observability::record_policy_allowed();
+            Ok(())
+        }
+        Decision::Block { reason } if policy_adapter.dry_run.load(Ordering::Acquire) => {
+            audit(AuditOutcome::WouldBlock(reason));
+            observability::record_policy_would_block();
+            observability::record_policy_dry_run_sample(host, reason.as_str());
+            observability::record_policy_allowed();
+            Ok(())
         }
         Decision::Block { reason } => {
+            audit(AuditOutcome::Blocked(reason));
             observability::record_policy_blocked();
+            event_bus::publish(EbtEvent::PolicyBlocked { reason });
             match reason {
-                crate::content_policy::ReasonCode::Ads => {
+                ReasonCode::Ads => {
                     observability::record_policy_blocked_ads();
                 }
-                crate::content_policy::ReasonCode::Tracking => {
+                ReasonCode::Tracking => {
                     observability::record_policy_blocked_tracking();
                 }
-                crate::content_policy::ReasonCode::Custom => {
+                ReasonCode::Malware => {
+                    observability::record_policy_blocked_malware();
+                }
+                ReasonCode::Custom => {
                     observability::record_policy_blocked_custom();
                 }
-                crate::content_policy::ReasonCode::Unknown => {}
+                ReasonCode::Unknown => {}
             }
-            false
+            Err(reason)
         }
     }
 }
@@ -541,7 +2510,11 @@ fn policy_allows_connect(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::content_policy::{ReasonCode, Rule, RuleAction, RuleSet};
+    use crate::content_policy::{Rule, RuleAction, RuleOptions, RuleSet};
+
+    fn network() -> NetworkToken {
+        crate::config::CapabilityPolicy::real_network().network_token().unwrap()
+    }
 
     fn make_adapter(rules: Vec<Rule>, enabled: bool) -> PolicyAdapter {
         PolicyAdapter::new(ContentPolicyEngine::new(RuleSet::new(rules)), enabled)
@@ -558,12 +2531,13 @@ mod tests {
         );
         let request = "CONNECT blocked.example.com:443 HTTP/1.1\r\nHost: blocked.example.com\r\n\r\n";
 
-        assert!(!policy_allows_connect(
+        assert!(policy_allows_connect(
             &adapter,
+            None,
             request,
             "blocked.example.com",
             443
-        ));
+        ).is_err());
     }
 
     #[test]
@@ -579,10 +2553,11 @@ mod tests {
 
         assert!(policy_allows_connect(
             &adapter,
+            None,
             request,
             "blocked.example.com",
             443
-        ));
+        ).is_ok());
     }
 
     #[test]
@@ -598,10 +2573,11 @@ mod tests {
 
         assert!(policy_allows_connect(
             &adapter,
+            None,
             request,
             "allowed.example.com",
             443
-        ));
+        ).is_ok());
     }
 
     #[test]
@@ -611,10 +2587,728 @@ mod tests {
 
         assert!(policy_allows_connect(
             &adapter,
+            None,
             request,
             "empty.example.com",
             443
+        ).is_ok());
+    }
+
+    #[test]
+    fn decisions_are_audited_with_their_rule() {
+        let adapter = make_adapter(
+            vec![
+                Rule::DomainExact {
+                    domain: "fine.example.com".to_string(),
+                    action: RuleAction::Allow,
+                },
+                Rule::DomainSuffix {
+                    suffix: "example.com".to_string(),
+                    action: RuleAction::Block(ReasonCode::Tracking),
+                },
+            ],
+            true,
+        );
+        let request = "CONNECT pixel.example.com:443 HTTP/1.1\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "pixel.example.com", 443).is_err());
+        let adapter = adapter.with_dry_run(true);
+        assert!(policy_allows_connect(&adapter, None, request, "pixel.example.com", 443).is_ok());
+        assert!(policy_allows_connect(&adapter, None, request, "fine.example.com", 443).is_ok());
+
+        let query = "GET /ebt/decisions?host=pixel.example.com HTTP/1.1\r\n\r\n";
+        assert_eq!(decisions_control_host(query), Some("pixel.example.com"));
+        assert_eq!(decisions_control_host("GET /ebt/decisions HTTP/1.1\r\n\r\n"), None);
+        let pixel = adapter.audit.to_json(decisions_control_host(query));
+        if observability::OBS_NONE {
+            assert_eq!(pixel, "{\"decisions\":[]}");
+            return;
+        }
+        assert!(pixel.contains("\"outcome\":\"blocked\",\"reason\":\"tracking\",\"rule\":1"));
+        assert!(pixel.contains("\"outcome\":\"would_block\",\"reason\":\"tracking\",\"rule\":1"));
+        assert!(!pixel.contains("\"allowed\""));
+        assert!(adapter.audit.to_json(None).contains("\"outcome\":\"allowed\",\"rule\":0"));
+    }
+
+    #[test]
+    fn other_spellings_of_a_blocked_host_are_blocked() {
+        let adapter = make_adapter(
+            vec![
+                Rule::DomainExact {
+                    domain: "ads.example.com".to_string(),
+                    action: RuleAction::Block(ReasonCode::Ads),
+                },
+                Rule::DomainSuffix {
+                    suffix: "BÜCHER.example.".to_string(),
+                    action: RuleAction::Block(ReasonCode::Tracking),
+                },
+            ],
+            true,
+        );
+        for host in ["ADS.Example.COM.", "ads.example.com", "xn--bcher-kva.example", "shop.bücher.example"] {
+            let request = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host);
+            assert!(policy_allows_connect(&adapter, None, &request, host, 443).is_err(), "{}", host);
+        }
+        assert!(policy_allows_connect(&adapter, None, "", "example.com", 443).is_ok());
+    }
+
+    #[test]
+    fn server_names_of_ip_connects_are_checked_without_consuming_them() {
+        let adapter = make_adapter(
+            vec![Rule::DomainExact {
+                domain: "ads.example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }],
+            true,
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = |name: &str| {
+            let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let hello = client_hello::client_hello_record(&client_hello::server_name_extension(name));
+            client.write_all(&hello).unwrap();
+            (client, listener.accept().unwrap().0, hello)
+        };
+
+        let (_client, mut stream, hello) = connect("ADS.example.com");
+        let check = |adapter: &PolicyAdapter, stream: &TcpStream, host: &str| {
+            policy_allows_server_name(adapter, None, "", stream, host, 443)
+        };
+        assert!(check(&adapter, &stream, "203.0.113.7").is_ok(), "inspection is off by default");
+        let adapter = adapter.with_inspect_sni(true);
+        assert_eq!(check(&adapter, &stream, "203.0.113.7"), Err(ReasonCode::Ads));
+        assert!(check(&adapter, &stream, "cdn.example.net").is_ok(), "named hosts were checked already");
+        let mut forwarded = vec![0u8; hello.len()];
+        stream.read_exact(&mut forwarded).unwrap();
+        assert_eq!(forwarded, hello);
+
+        let (_client, stream, _) = connect("example.com");
+        assert!(check(&adapter, &stream, "2001:db8::7").is_ok());
+    }
+
+    #[test]
+    fn request_heads_are_held_to_their_limits() {
+        let limits = HeaderLimits {
+            max_request_line: 32,
+            max_header_count: 2,
+            max_header_bytes: 96,
+            ..HeaderLimits::default()
+        };
+        let check = |head: &str, complete| exceeded_head_limit(head.as_bytes(), complete, &limits);
+        assert!(check("CONNECT a.example:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n", true).is_none());
+        assert!(check("CONNECT a.example:443 HTTP/1.1\r", false).is_none());
+        assert!(check("CONNECT a.example:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\n", false).is_none());
+
+        let long_line = format!("CONNECT {}.example:443", "a".repeat(32));
+        assert!(matches!(check(&long_line, false), Some(ProtocolError::RequestLineTooLong)));
+        let three_fields = "CONNECT a.example:443 HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n";
+        assert!(matches!(check(three_fields, false), Some(ProtocolError::HeadersTooLarge)));
+        let long_field = format!("CONNECT a.example:443 HTTP/1.1\r\nA: {}", "1".repeat(64));
+        assert!(matches!(check(&long_field, false), Some(ProtocolError::HeadersTooLarge)));
+        assert_eq!(ProtocolError::HeadersTooLarge.class(), observability::ErrorClass::RESOURCE_LIMIT);
+    }
+
+    /// The server end of a connection whose client sends `head` one byte
+    /// every `interval`.
+    fn trickled(head: &'static [u8], interval: Duration) -> TcpStream {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            for byte in head {
+                if client.write_all(std::slice::from_ref(byte)).is_err() {
+                    return;
+                }
+                thread::sleep(interval);
+            }
+            // Hold the connection open, as a slowloris client would
+            thread::sleep(Duration::from_secs(2));
+        });
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+    }
+
+    #[test]
+    fn trickling_request_heads_run_out_of_time() {
+        const HEAD: &[u8] = b"CONNECT slow.example:443 HTTP/1.1\r\nHost: slow.example\r\n\r\n";
+        let timed_out = |result: EbtResult<(Vec<u8>, usize)>| {
+            matches!(result, Err(EbtError::Protocol(ProtocolError::HeadersTimedOut)))
+        };
+
+        // Every read returns within its timeout, but the head never completes in time.
+        let deadline = HeaderLimits {
+            header_read_timeout: Duration::from_millis(300),
+            min_header_rate: 0,
+            ..HeaderLimits::default()
+        };
+        let started = Instant::now();
+        let stream = trickled(HEAD, Duration::from_millis(20));
+        assert!(timed_out(read_request_head(&mut PreTunnelReader::new(stream, deadline).unwrap())));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // 20 bytes a second, against a floor of 100.
+        let rate = HeaderLimits {
+            min_header_rate: 100,
+            header_rate_grace: Duration::from_millis(200),
+            ..HeaderLimits::default()
+        };
+        let started = Instant::now();
+        let stream = trickled(HEAD, Duration::from_millis(50));
+        assert!(timed_out(read_request_head(&mut PreTunnelReader::new(stream, rate).unwrap())));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let stream = trickled(HEAD, Duration::ZERO);
+        let (buffer, end) = read_request_head(&mut PreTunnelReader::new(stream, rate).unwrap()).unwrap();
+        assert_eq!(&buffer[..end], HEAD);
+    }
+
+    #[test]
+    fn trickling_socks_negotiation_shares_the_head_budget() {
+        const GREETING: &[u8] = &[socks5::VERSION, 1, 0, socks5::VERSION, 1, 0, 3, 12];
+        let deadline = HeaderLimits {
+            header_read_timeout: Duration::from_millis(300),
+            min_header_rate: 0,
+            ..HeaderLimits::default()
+        };
+        let stream = trickled(GREETING, Duration::from_millis(60));
+        let started = Instant::now();
+        let result = socks5::accept(&mut PreTunnelReader::new(stream, deadline).unwrap(), None);
+        let expired = |e: &std::io::Error| {
+            matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+        };
+        assert!(matches!(result, Err(ProtocolError::Io(ref e)) if expired(e)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn replaced_engine_applies_to_next_evaluation() {
+        let adapter = make_adapter(Vec::new(), true);
+        let request = "CONNECT swapped.example.com:443 HTTP/1.1\r\nHost: swapped.example.com\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "swapped.example.com", 443).is_ok());
+
+        adapter.replace_base(RuleSet::new(vec![Rule::DomainSuffix {
+            suffix: "example.com".to_string(),
+            action: RuleAction::Block(ReasonCode::Ads),
+        }]));
+        assert!(policy_allows_connect(&adapter, None, request, "swapped.example.com", 443).is_err());
+    }
+
+    #[test]
+    fn client_subnet_override_takes_precedence() {
+        let block_all = ContentPolicyEngine::new(RuleSet::new(vec![Rule::DomainSuffix {
+            suffix: "example.com".to_string(),
+            action: RuleAction::Block(ReasonCode::Ads),
+        }]));
+        let adapter = PolicyAdapter::from_listener(ListenerPolicies {
+            enabled: true,
+            custom: RuleSet::default(),
+            default: block_all,
+            by_subnet: vec![(
+                ClientSubnet::parse("10.0.0.0/8").unwrap(),
+                ContentPolicyEngine::new(RuleSet::default()),
+            )],
+        });
+        let request = "CONNECT ads.example.com:443 HTTP/1.1\r\nHost: ads.example.com\r\n\r\n";
+
+        let unfiltered_client = Some("10.1.2.3".parse().unwrap());
+        let strict_client = Some("192.168.1.20".parse().unwrap());
+        assert!(policy_allows_connect(&adapter, unfiltered_client, request, "ads.example.com", 443).is_ok());
+        assert!(policy_allows_connect(&adapter, strict_client, request, "ads.example.com", 443).is_err());
+        assert!(policy_allows_connect(&adapter, None, request, "ads.example.com", 443).is_err());
+    }
+
+    #[test]
+    fn block_behavior_selected_per_reason() {
+        let adapter = make_adapter(Vec::new(), true).with_block_behavior(BlockBehavior {
+            ads: BlockResponse::Reset,
+            tracking: BlockResponse::BlockPage,
+            ..BlockBehavior::default()
+        });
+        assert_eq!(adapter.block_response(ReasonCode::Ads), BlockResponse::Reset);
+        assert_eq!(adapter.block_response(ReasonCode::Tracking), BlockResponse::BlockPage);
+        assert_eq!(adapter.block_response(ReasonCode::Custom), BlockResponse::Forbidden);
+
+        assert_eq!(block_response_bytes(BlockResponse::Reset, ReasonCode::Ads), None);
+        let forbidden = String::from_utf8(block_response_bytes(BlockResponse::Forbidden, ReasonCode::Ads).unwrap())
+            .unwrap();
+        assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\nX-EBT-Reason: policy-blocked\r\n"));
+        let with_reason =
+            String::from_utf8(block_response_bytes(BlockResponse::ForbiddenWithReason, ReasonCode::Tracking).unwrap())
+                .unwrap();
+        assert!(with_reason.contains("\r\nX-EBT-Reason: policy-blocked; category=tracking\r\n"));
+        let page = String::from_utf8(block_response_bytes(BlockResponse::BlockPage, ReasonCode::Custom).unwrap())
+            .unwrap();
+        let (head, body) = page.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nX-EBT-Reason: policy-blocked; category=custom\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.contains("(custom)"));
+
+        assert_eq!(connect_block_response(BlockResponse::BlockPage), BlockResponse::ForbiddenWithReason);
+        assert_eq!(connect_block_response(BlockResponse::Forbidden), BlockResponse::Forbidden);
+        assert_eq!(connect_block_response(BlockResponse::Reset), BlockResponse::Reset);
+    }
+
+    #[test]
+    fn plain_http_targets_come_from_absolute_form_requests() {
+        let target = |line: &str| plain_http_target(&format!("{}\r\nHost: x\r\n\r\n", line));
+        assert_eq!(
+            target("GET http://Ads.Example.com/banner.gif HTTP/1.1"),
+            Some(Authority { host: "ads.example.com".to_string(), port: 80 })
+        );
+        assert_eq!(target("POST http://ads.example.com:8080?q=1 HTTP/1.1").map(|a| a.port), Some(8080));
+        assert_eq!(target("GET http://[2001:db8::1]/ HTTP/1.1").map(|a| a.port), Some(80));
+        assert_eq!(target("GET /banner.gif HTTP/1.1"), None);
+        assert_eq!(target("GET https://ads.example.com/ HTTP/1.1"), None);
+        assert_eq!(target("GET http://user@ads.example.com/ HTTP/1.1"), None);
+    }
+
+    #[test]
+    fn reload_swaps_policy_and_keeps_bypasses() {
+        let adapter = make_adapter(Vec::new(), false);
+        adapter.bypass.allow("kept.example.com", Duration::from_secs(60));
+        let block_example = ContentPolicyEngine::new(RuleSet::new(vec![Rule::DomainSuffix {
+            suffix: "example.com".to_string(),
+            action: RuleAction::Block(ReasonCode::Ads),
+        }]));
+        adapter.reload(
+            ListenerPolicies::single(block_example, true),
+            BlockBehavior {
+                ads: BlockResponse::Reset,
+                ..BlockBehavior::default()
+            },
+            false,
+            false,
+        );
+
+        let blocked = "CONNECT ads.example.com:443 HTTP/1.1\r\nHost: ads.example.com\r\n\r\n";
+        let kept = "CONNECT kept.example.com:443 HTTP/1.1\r\nHost: kept.example.com\r\n\r\n";
+        assert!(adapter.is_enabled());
+        assert!(policy_allows_connect(&adapter, None, blocked, "ads.example.com", 443).is_err());
+        assert!(policy_allows_connect(&adapter, None, kept, "kept.example.com", 443).is_ok());
+        assert_eq!(adapter.block_response(ReasonCode::Ads), BlockResponse::Reset);
+    }
+
+    #[test]
+    fn reload_rejects_settings_that_need_a_restart() {
+        let old = TunnelConfig::ssh_socks_profile();
+        let mut new = old.clone();
+        new.proxy_policy.content_policy_enabled = true;
+        new.proxy_policy.max_concurrent_tunnels = 32;
+        new.dns_policy.doh_providers = vec!["https://dns.example/dns-query".to_string()];
+        assert!(restart_only_changes(&old, &new).is_empty());
+
+        new.proxy_policy.bind_port = 9090;
+        new.proxy_policy.traffic_accounting =
+            Some(crate::config::TrafficAccountingConfig::new("usage.bin"));
+        assert_eq!(
+            restart_only_changes(&old, &new),
+            vec!["proxy.bind_port", "observability.traffic_accounting"]
+        );
+        let response = String::from_utf8(reload_response(Err(ConfigError::RequiresRestart {
+            settings: "proxy.bind_port".to_string(),
+        }
+        .into())))
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 409 Conflict"));
+        assert!(response.contains("proxy.bind_port changed; restart to apply"));
+    }
+
+    #[tokio::test]
+    async fn lowering_the_tunnel_limit_waits_for_running_tunnels() {
+        let limit = TunnelLimit::new(2);
+        let first = limit.acquire().await.unwrap();
+        let second = limit.acquire().await.unwrap();
+        limit.set(1);
+        drop(first);
+        tokio::task::yield_now().await;
+        assert_eq!(limit.semaphore.available_permits(), 0);
+        drop(second);
+        assert_eq!(limit.semaphore.available_permits(), 1);
+
+        limit.set(3);
+        assert_eq!(limit.semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn adaptive_tunnel_limit_grows_when_full_and_shrinks_under_pressure() {
+        let bounds = AdaptiveTunnelLimit {
+            min: 10,
+            max: 120,
+            min_available_memory: 64 << 20,
+            ..AdaptiveTunnelLimit::around(40)
+        };
+        let calm = LimitSample {
+            available_memory: Some(1 << 30),
+            closed: 50,
+            upstream_failed: 5,
+        };
+        assert_eq!(next_tunnel_limit(100, 95, &calm, &bounds), 110);
+        assert_eq!(next_tunnel_limit(118, 118, &calm, &bounds), 120, "capped at max");
+        assert_eq!(next_tunnel_limit(100, 50, &calm, &bounds), 100, "idle slots hold the limit");
+
+        let low_memory = LimitSample {
+            available_memory: Some(32 << 20),
+            ..calm
+        };
+        assert_eq!(next_tunnel_limit(100, 100, &low_memory, &bounds), 90);
+        assert_eq!(next_tunnel_limit(10, 10, &low_memory, &bounds), 10, "floored at min");
+        let failing = LimitSample {
+            upstream_failed: 15,
+            ..calm
+        };
+        assert_eq!(next_tunnel_limit(100, 100, &failing, &bounds), 90);
+        let few_closed = LimitSample {
+            closed: 4,
+            upstream_failed: 4,
+            available_memory: None,
+        };
+        assert_eq!(next_tunnel_limit(100, 10, &few_closed, &bounds), 100, "too few closes to judge");
+    }
+
+    #[test]
+    fn exact_client_profile_beats_enclosing_subnet() {
+        let block_ads = || {
+            ContentPolicyEngine::new(RuleSet::new(vec![Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }]))
+        };
+        let adapter = PolicyAdapter::from_listener(ListenerPolicies {
+            enabled: true,
+            custom: RuleSet::default(),
+            default: ContentPolicyEngine::new(RuleSet::default()),
+            by_subnet: vec![
+                (ClientSubnet::parse("192.168.1.0/24").unwrap(), block_ads()),
+                (
+                    ClientSubnet::parse("192.168.1.50").unwrap(),
+                    ContentPolicyEngine::new(RuleSet::default()),
+                ),
+            ],
+        });
+        let request = "CONNECT ads.example.com:443 HTTP/1.1\r\nHost: ads.example.com\r\n\r\n";
+
+        let kids_tablet = Some("192.168.1.23".parse().unwrap());
+        let work_laptop = Some("192.168.1.50".parse().unwrap());
+        let guest = Some("10.0.0.9".parse().unwrap());
+        assert!(policy_allows_connect(&adapter, kids_tablet, request, "ads.example.com", 443).is_err());
+        assert!(policy_allows_connect(&adapter, work_laptop, request, "ads.example.com", 443).is_ok());
+        assert!(policy_allows_connect(&adapter, guest, request, "ads.example.com", 443).is_ok());
+    }
+
+    #[test]
+    fn custom_rules_layer_ahead_of_base_rules() {
+        let adapter = make_adapter(
+            vec![Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }],
+            true,
+        );
+        let request = "CONNECT shop.example.com:443 HTTP/1.1\r\nHost: shop.example.com\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_err());
+
+        adapter.replace_custom(RuleSet::new(vec![Rule::DomainExact {
+            domain: "shop.example.com".to_string(),
+            action: RuleAction::Allow,
+        }]));
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_ok());
+
+        adapter.replace_base(RuleSet::default());
+        assert_eq!(adapter.engine.load().engine.rules().rules().len(), 1);
+    }
+
+    #[test]
+    fn dry_run_records_but_allows_blocked_requests() {
+        let adapter = make_adapter(
+            vec![Rule::DomainExact {
+                domain: "blocked.example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Custom),
+            }],
+            true,
+        )
+        .with_dry_run(true);
+        let request = "CONNECT blocked.example.com:443 HTTP/1.1\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "blocked.example.com", 443).is_ok());
+        // Served at /debug/obs, which only exists under OBS_DEV.
+        if let Some(snapshot) = observability::snapshot() {
+            let sample = "policy_dry_run_sample custom blocked.example.com\n";
+            assert!(observability::report::encode(&snapshot).contains(sample));
+        }
+
+        adapter.dry_run.store(false, Ordering::Release);
+        assert_eq!(
+            policy_allows_connect(&adapter, None, request, "blocked.example.com", 443),
+            Err(ReasonCode::Custom)
+        );
+    }
+
+    #[test]
+    fn decision_cache_evicts_least_recently_used() {
+        let cache = DecisionCache::new(2);
+        let block = Decision::Block {
+            reason: ReasonCode::Ads,
+        };
+        let verdict = |decision, rule: Option<usize>| Verdict {
+            decision,
+            rule,
+            rule_target: rule.map(|_| "example".to_string()),
+        };
+        cache.insert("a.example", 443, verdict(block, Some(2)));
+        cache.insert("b.example", 443, verdict(Decision::Allow, None));
+        assert!(cache.get("a.example", 443).is_some());
+
+        cache.insert("c.example", 443, verdict(Decision::Allow, None));
+        assert!(cache.get("b.example", 443).is_none());
+        let hit = cache.get("a.example", 443).unwrap().verdict;
+        assert_eq!((hit.decision, hit.rule), (block, Some(2)));
+        assert_eq!(hit.rule_target.as_deref(), Some("example"));
+        assert!(cache.get("a.example", 80).is_none());
+        assert_eq!(cache.state.lock().unwrap().entries.len(), 2);
+    }
+
+    #[test]
+    fn decision_cache_is_dropped_on_rule_swap() {
+        let adapter = make_adapter(
+            vec![Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }],
+            true,
+        );
+        let request = "CONNECT shop.example.com:443 HTTP/1.1\r\nHost: shop.example.com\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_err());
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_err());
+        let cached = |adapter: &PolicyAdapter| {
+            adapter.engine.load().decisions.state.lock().unwrap().entries.len()
+        };
+        assert_eq!(cached(&adapter), 1);
+
+        adapter.replace_base(RuleSet::default());
+        assert_eq!(cached(&adapter), 0);
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_ok());
+    }
+
+    #[test]
+    fn header_rules_are_not_cached() {
+        let adapter = make_adapter(
+            vec![Rule::HeaderEquals {
+                name: "user-agent".to_string(),
+                value: "BadBot".to_string(),
+                action: RuleAction::Block(ReasonCode::Custom),
+            }],
+            true,
+        );
+        let bot = "CONNECT a.example.com:443 HTTP/1.1\r\nUser-Agent: BadBot\r\n\r\n";
+        let browser = "CONNECT a.example.com:443 HTTP/1.1\r\nUser-Agent: Firefox\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, bot, "a.example.com", 443).is_err());
+        assert!(policy_allows_connect(&adapter, None, browser, "a.example.com", 443).is_ok());
+    }
+
+    #[test]
+    fn mixed_rules_cache_target_verdicts_and_keep_rule_order() {
+        let adapter = make_adapter(
+            vec![
+                Rule::HeaderEquals {
+                    name: "user-agent".to_string(),
+                    value: "BadBot".to_string(),
+                    action: RuleAction::Block(ReasonCode::Custom),
+                },
+                Rule::DomainSuffix {
+                    suffix: "ads.example".to_string(),
+                    action: RuleAction::Block(ReasonCode::Ads),
+                },
+                Rule::Constrained {
+                    options: RuleOptions {
+                        exclude_domains: vec!["news.example".to_string()],
+                        ..RuleOptions::default()
+                    },
+                    rule: Box::new(Rule::DomainExact {
+                        domain: "cdn.ads.example".to_string(),
+                        action: RuleAction::Allow,
+                    }),
+                },
+                Rule::Constrained {
+                    options: RuleOptions {
+                        exclude_domains: vec!["news.example".to_string()],
+                        ..RuleOptions::default()
+                    },
+                    rule: Box::new(Rule::DomainExact {
+                        domain: "tracker.example".to_string(),
+                        action: RuleAction::Block(ReasonCode::Tracking),
+                    }),
+                },
+            ],
+            true,
+        );
+        let connect = |host: &str, headers: &str| {
+            let request = format!("CONNECT {host}:443 HTTP/1.1\r\n{headers}\r\n");
+            policy_allows_connect(&adapter, None, &request, host, 443)
+        };
+        let cached = |adapter: &PolicyAdapter| {
+            adapter.engine.load().decisions.state.lock().unwrap().entries.len()
+        };
+
+        // The target-only rule comes first, so the later allow never wins.
+        assert_eq!(connect("cdn.ads.example", ""), Err(ReasonCode::Ads));
+        assert_eq!(connect("cdn.ads.example", ""), Err(ReasonCode::Ads));
+        // An earlier header rule still sees every request.
+        assert_eq!(connect("shop.example", "User-Agent: BadBot\r\n"), Err(ReasonCode::Custom));
+        assert_eq!(connect("shop.example", "User-Agent: Firefox\r\n"), Ok(()));
+        // Later context rules apply where no target-only rule matched.
+        assert_eq!(connect("tracker.example", ""), Err(ReasonCode::Tracking));
+        assert_eq!(connect("tracker.example", "Referer: https://news.example/\r\n"), Ok(()));
+        assert_eq!(cached(&adapter), 3);
+    }
+
+    #[test]
+    fn bypass_allows_blocked_domain_until_expiry() {
+        let adapter = make_adapter(
+            vec![Rule::DomainSuffix {
+                suffix: "example.com".to_string(),
+                action: RuleAction::Block(ReasonCode::Ads),
+            }],
+            true,
+        );
+        let request = "CONNECT shop.example.com:443 HTTP/1.1\r\nHost: shop.example.com\r\n\r\n";
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_err());
+
+        adapter.bypass.allow("Example.com", Duration::from_secs(600));
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_ok());
+        assert!(adapter.bypass.is_bypassed("shop.example.com", Instant::now()));
+        assert!(!adapter.bypass.is_bypassed("example.community", Instant::now()));
+
+        // Looking past the expiry changes nothing for lookups before it.
+        let later = Instant::now() + Duration::from_secs(601);
+        assert!(!adapter.bypass.is_bypassed("shop.example.com", later));
+        assert!(adapter.bypass.is_bypassed("shop.example.com", Instant::now()));
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_ok());
+
+        // An expired bypass no longer overrides the block.
+        adapter.bypass.allow("example.com", Duration::ZERO);
+        assert!(!adapter.bypass.is_bypassed("shop.example.com", Instant::now()));
+        assert!(policy_allows_connect(&adapter, None, request, "shop.example.com", 443).is_err());
+    }
+
+    #[test]
+    fn rules_file_digest_sees_rewrites_that_keep_the_mtime() {
+        let path = std::env::temp_dir().join(format!("ebt-custom-rules-{}", std::process::id()));
+        std::fs::write(&path, "block a.example.com\n").unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let path_str = path.to_str().unwrap();
+        let before = file_digest(path_str);
+
+        std::fs::write(&path, "block b.example.com\n").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+        assert_ne!(file_digest(path_str), before);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file_digest(path_str), None);
+    }
+
+    #[tokio::test]
+    async fn rules_file_watch_hashes_only_changed_or_recent_files() {
+        let path = std::env::temp_dir().join(format!("ebt-watched-rules-{}", std::process::id()));
+        std::fs::write(&path, "block a.example.com\n").unwrap();
+        let settled = std::time::SystemTime::now() - Duration::from_secs(60);
+        let set_modified = |modified| std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified);
+        set_modified(settled).unwrap();
+        let mut watch = RulesFileWatch::new(path.to_str().unwrap().to_string()).await;
+        assert!(!watch.changed().await);
+
+        // A settled file with the same metadata is not read again.
+        watch.digest = None;
+        assert!(!watch.changed().await);
+
+        // Same length, mtime put back: the change time or recency still shows it.
+        std::fs::write(&path, "block b.example.com\n").unwrap();
+        set_modified(settled).unwrap();
+        assert!(watch.changed().await);
+        assert!(!watch.changed().await);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(watch.changed().await);
+        assert!(!watch.changed().await);
+    }
+
+    #[test]
+    fn bypass_control_requests() {
+        let bypass = BypassTable::default();
+        assert!(handle_bypass_control(
+            &bypass,
+            "POST /ebt/bypass?domain=ads.example.com&minutes=10 HTTP/1.1\r\n\r\n"
         ));
+        assert!(bypass.is_bypassed("ads.example.com", Instant::now()));
+
+        assert!(!handle_bypass_control(
+            &bypass,
+            "POST /ebt/bypass?domain=ads.example.com&minutes=0 HTTP/1.1\r\n\r\n"
+        ));
+        assert!(!handle_bypass_control(&bypass, "POST /ebt/bypass?minutes=10 HTTP/1.1\r\n\r\n"));
+
+        assert!(handle_bypass_control(
+            &bypass,
+            "DELETE /ebt/bypass?domain=ads.example.com HTTP/1.1\r\n\r\n"
+        ));
+        assert!(!bypass.is_bypassed("ads.example.com", Instant::now()));
+    }
+
+    #[test]
+    fn profile_control_requests() {
+        assert_eq!(
+            profile_control_target("POST /ebt/profile?name=paranoid HTTP/1.1\r\n\r\n"),
+            Some(ShapingProfile::Paranoid)
+        );
+        assert_eq!(
+            profile_control_target("POST /ebt/profile?x=1&name=low_latency HTTP/1.1\r\n\r\n"),
+            Some(ShapingProfile::LowLatency)
+        );
+        assert_eq!(profile_control_target("POST /ebt/profile?name=fast HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(profile_control_target("POST /ebt/profile? HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn proxy_auth_not_required_without_credential() {
+        let request = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(proxy_auth_allows(None, request));
+    }
+
+    #[test]
+    fn proxy_auth_requires_matching_credential() {
+        let good = "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        let bad = "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic dXNlcjpwYXN6\r\n\r\n";
+        let missing = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        assert!(proxy_auth_allows(Some("Basic dXNlcjpwYXNz"), good));
+        assert!(!proxy_auth_allows(Some("Basic dXNlcjpwYXNz"), bad));
+        assert!(!proxy_auth_allows(Some("Basic dXNlcjpwYXNz"), missing));
+    }
+
+    #[test]
+    fn control_requests_must_come_from_a_local_tool() {
+        let loopback = true;
+        let tool = "POST /ebt/bypass?domain=a.example&minutes=5 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n\
+                    X-EBT-Control: 1\r\n\r\n";
+        assert!(control_request_allowed(loopback, None, tool));
+        assert!(control_request_allowed(loopback, None, &tool.replace("127.0.0.1:8080", "[::1]:8080")));
+        assert!(control_request_allowed(loopback, None, &tool.replace("127.0.0.1:8080", "localhost")));
+
+        assert!(!control_request_allowed(false, None, tool));
+        assert!(!control_request_allowed(loopback, Some("Basic dXNlcjpwYXNz"), tool));
+        // A simple cross-origin POST, as any page can send it.
+        assert!(!control_request_allowed(loopback, None, &tool.replace("X-EBT-Control: 1\r\n", "")));
+        assert!(!control_request_allowed(
+            loopback,
+            None,
+            &tool.replace("\r\n\r\n", "\r\nOrigin: https://ads.example\r\n\r\n"),
+        ));
+        assert!(!control_request_allowed(
+            loopback,
+            None,
+            &tool.replace("\r\n\r\n", "\r\nSec-Fetch-Site: cross-site\r\n\r\n"),
+        ));
+        // DNS rebinding: the page's own name resolved to loopback.
+        assert!(!control_request_allowed(loopback, None, &tool.replace("127.0.0.1:8080", "rebind.example:8080")));
+        assert!(!control_request_allowed(loopback, None, &tool.replace("Host: 127.0.0.1:8080\r\n", "")));
     }
 
     #[test]
@@ -629,17 +3323,49 @@ mod tests {
         let blocked_request = "CONNECT blocked.example.com:443 HTTP/1.1\r\nHost: blocked.example.com\r\n\r\n";
         let allowed_request = "CONNECT allowed.example.com:443 HTTP/1.1\r\nHost: allowed.example.com\r\n\r\n";
 
-        assert!(!policy_allows_connect(
+        assert!(policy_allows_connect(
             &adapter,
+            None,
             blocked_request,
             "blocked.example.com",
             443
-        ));
+        ).is_err());
         assert!(policy_allows_connect(
             &adapter,
+            None,
             allowed_request,
             "allowed.example.com",
             443
+        ).is_ok());
+    }
+
+    #[test]
+    fn builder_requires_a_valid_listen_address() {
+        let server = RealProxyServer::builder()
+            .bind("127.0.0.1:3128")
+            .credential("Basic dXNlcjpwYXNz")
+            .max_concurrent_tunnels(8)
+            .build(network())
+            .unwrap();
+        assert_eq!(server.policy.bind_address, "127.0.0.1");
+        assert_eq!(server.policy.bind_port, 3128);
+        assert!(server.proxy_credential.is_some());
+        assert!(!server.policy_adapter.is_enabled());
+
+        assert!(matches!(
+            RealProxyServer::builder().bind("localhost").build(network()),
+            Err(ConfigError::Invalid { field, .. }) if field == "bind"
         ));
     }
+
+    #[test]
+    fn healthz_reports_unavailable_only_when_faulted() {
+        let ok = String::from_utf8(healthz_response(HealthState::OK)).unwrap();
+        let degraded = String::from_utf8(healthz_response(HealthState::DEGRADED)).unwrap();
+        let faulted = String::from_utf8(healthz_response(HealthState::FAULTED)).unwrap();
+
+        assert!(ok.starts_with("HTTP/1.1 200 OK") && ok.ends_with("\r\n\r\nok\n"));
+        assert!(degraded.starts_with("HTTP/1.1 200 OK") && degraded.ends_with("degraded\n"));
+        assert!(faulted.starts_with("HTTP/1.1 503 ") && faulted.ends_with("faulted\n"));
+    }
 }
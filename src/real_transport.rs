@@ -15,17 +15,42 @@ use crate::anonymity::invariants::{
     AllowsPerUserConnectionOwnership,
     AllowsStableSocketMapping,
 };
+use crate::config::{ConnectRetry, NetworkToken, RelayEndpoint, RelayMode};
 use crate::transport::{EncryptedTransport, TransportError};
 use crate::dns_resolver::{DnsResolver, DohResolver};
 use crate::relay_transport::{RelayTransport, DirectRelayTransport};
 use crate::logging::LogLevel;
 use crate::log;
-use crate::traffic_shaping::{self, ConnectionState};
+use crate::memory_profile::MemoryProfile;
+use crate::traffic_shaping::OutboundShaper;
+use crate::tunnel_registry::TunnelCounters;
+use crate::core::observability;
+use crate::core::observability::trace::ObsSpan;
 #[cfg(feature = "single_hop_relay")]
 use crate::relay_transport::SingleHopRelayTransport;
 #[cfg(feature = "multi_hop_relay")]
 use crate::relay_transport::MultiHopRelayTransport;
 
+const TUNNEL_BUFFER: usize = MemoryProfile::active().tunnel_buffer;
+
+#[cfg(not(feature = "async_tunnel"))]
+fn forwarding_thread(name: &str) -> thread::Builder {
+    let builder = thread::Builder::new().name(name.to_string());
+    match MemoryProfile::active().forward_thread_stack {
+        Some(size) => builder.stack_size(size),
+        None => builder,
+    }
+}
+
+/// A connect that timed out, the last attempt's where there were several,
+/// is told apart so the proxy can answer 504 rather than 502.
+fn connect_failure(error: &std::io::Error) -> TransportError {
+    match error.kind() {
+        std::io::ErrorKind::TimedOut => TransportError::TimedOut,
+        _ => TransportError::ConnectionFailed,
+    }
+}
+
 /// Real TCP transport implementation with direct connection
 pub struct DirectTcpTunnelTransport<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
@@ -35,28 +60,41 @@ pub struct DirectTcpTunnelTransport<Phase: AllowsPerUserConnectionOwnership
     tcp_stream: Option<Arc<Mutex<TcpStream>>>,
     dns_resolver: DohResolver,
     relay_transport: Box<dyn RelayTransport>,
+    counters: TunnelCounters,
+    traffic_shaping: bool,
     _phase: PhantomData<Phase>,
 }
 
 impl<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
     + AllowsDirectTimingCorrespondence> DirectTcpTunnelTransport<Phase> {
-    pub fn new(target_host: String, target_port: u16) -> Result<Self, TransportError> {
+    /// The `NetworkToken` is never read; requiring it keeps this transport
+    /// out of reach of Conceptual-mode code. `relays` are this session's
+    /// hops from `RelaySelector::select`; relay builds refuse to run without
+    /// them rather than fall back to a direct connection.
+    pub fn new(
+        target_host: String,
+        target_port: u16,
+        relays: Vec<RelayEndpoint>,
+        _network: NetworkToken,
+    ) -> Result<Self, TransportError> {
+        if RelayMode::compiled() != RelayMode::Direct && relays.is_empty() {
+            return Err(TransportError::NoRelayEndpoints);
+        }
+
         #[cfg(feature = "multi_hop_relay")]
-        let relay_transport: Box<dyn RelayTransport> = Box::new(MultiHopRelayTransport::new(vec![
-            ("127.0.0.1".parse().unwrap(), 8080),
-            ("127.0.0.1".parse().unwrap(), 8081),
-            ("127.0.0.1".parse().unwrap(), 8082),
-        ]));
+        let relay_transport: Box<dyn RelayTransport> = Box::new(MultiHopRelayTransport::new(relays));
         
         #[cfg(all(feature = "single_hop_relay", not(feature = "multi_hop_relay")))]
         let relay_transport: Box<dyn RelayTransport> = Box::new(SingleHopRelayTransport::new(
-            "127.0.0.1".parse().unwrap(),
-            8080
+            relays.into_iter().next().expect("checked above"),
         ));
         
         #[cfg(all(not(feature = "single_hop_relay"), not(feature = "multi_hop_relay")))]
-        let relay_transport: Box<dyn RelayTransport> = Box::new(DirectRelayTransport::default());
+        let relay_transport: Box<dyn RelayTransport> = {
+            drop(relays);
+            Box::new(DirectRelayTransport::default())
+        };
         
         Ok(Self {
             target_host,
@@ -64,17 +102,52 @@ impl<Phase: AllowsPerUserConnectionOwnership
             tcp_stream: None,
             dns_resolver: DohResolver::new(),
             relay_transport,
+            counters: TunnelCounters::default(),
+            traffic_shaping: false,
             _phase: PhantomData,
         })
     }
+
+    /// Reports forwarded bytes into `counters` while the tunnel runs.
+    pub fn with_counters(mut self, counters: TunnelCounters) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Connect attempts for this CONNECT's destination; see `ConnectRetry`.
+    /// Relay builds dial their relay instead and ignore it.
+    pub fn with_connect_retry(mut self, retry: ConnectRetry) -> Self {
+        if RelayMode::compiled() == RelayMode::Direct {
+            self.relay_transport = Box::new(DirectRelayTransport::new(retry));
+        }
+        self
+    }
+
+    /// Shapes client-to-upstream writes with `traffic_shaping`. Has no
+    /// effect unless Phase 5 is compiled in.
+    pub fn with_traffic_shaping(mut self, enabled: bool) -> Self {
+        self.traffic_shaping = enabled;
+        self
+    }
     
     /// Get the established TCP stream for forwarding
     pub fn get_tcp_stream(&self) -> Option<Arc<Mutex<TcpStream>>> {
         self.tcp_stream.clone()
     }
     
+    /// The established stream, for callers that forward it themselves.
+    pub fn into_tcp_stream(self) -> Option<TcpStream> {
+        let stream = Arc::try_unwrap(self.tcp_stream?).ok()?;
+        Some(stream.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+
     /// Start bidirectional forwarding between client and TCP stream
     pub fn start_forwarding(&self, client_stream: TcpStream) -> Result<(), TransportError> {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if crate::uring::available() {
+            return self.start_uring_forwarding(client_stream);
+        }
+
         #[cfg(feature = "async_tunnel")]
         {
             return self.start_async_forwarding(client_stream);
@@ -86,6 +159,33 @@ impl<Phase: AllowsPerUserConnectionOwnership
         }
     }
     
+    /// Both directions on one io_uring ring, from this thread.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn start_uring_forwarding(&self, client_stream: TcpStream) -> Result<(), TransportError> {
+        let tcp_stream = self.tcp_stream.as_ref()
+            .ok_or(TransportError::ConnectionFailed)?
+            .lock().map_err(|_| TransportError::ConnectionFailed)?
+            .try_clone().map_err(|_| TransportError::ConnectionFailed)?;
+        for stream in [&client_stream, &tcp_stream] {
+            stream.set_nodelay(true).ok();
+            stream.set_read_timeout(None).ok();
+            stream.set_write_timeout(None).ok();
+        }
+
+        let start_time = Instant::now();
+        let shaper = OutboundShaper::new(self.traffic_shaping);
+        crate::uring::tunnel(&client_stream, &tcp_stream, &self.counters, shaper)
+            .map_err(|_| TransportError::ConnectionFailed)?;
+        log!(
+            LogLevel::Debug,
+            "CONNECT tunnel closed";
+            safe "client_to_upstream_bytes" => self.counters.client_to_upstream.load(Ordering::Relaxed),
+            safe "upstream_to_client_bytes" => self.counters.upstream_to_client.load(Ordering::Relaxed),
+            safe "duration_ms" => start_time.elapsed().as_millis()
+        );
+        Ok(())
+    }
+    
     #[cfg(feature = "async_tunnel")]
     fn start_async_forwarding(&self, client_stream: TcpStream) -> Result<(), TransportError> {
         let tcp_stream = self.tcp_stream.as_ref()
@@ -103,7 +203,14 @@ impl<Phase: AllowsPerUserConnectionOwnership
             client.set_nodelay(true).ok();
             target.set_nodelay(true).ok();
             
-            crate::async_tunnel::tunnel_connect(client, target).await
+            crate::async_tunnel::tunnel_connect(
+                client,
+                target,
+                &self.counters.client_to_upstream,
+                &self.counters.upstream_to_client,
+                OutboundShaper::new(self.traffic_shaping),
+            )
+            .await
                 .map_err(|_| TransportError::ConnectionFailed)
         })
     }
@@ -141,24 +248,26 @@ impl<Phase: AllowsPerUserConnectionOwnership
         
         // Metrics tracking
         let start_time = Instant::now();
-        let client_to_upstream_bytes = Arc::new(AtomicU64::new(0));
-        let upstream_to_client_bytes = Arc::new(AtomicU64::new(0));
+        let client_to_upstream_bytes = Arc::clone(&self.counters.client_to_upstream);
+        let upstream_to_client_bytes = Arc::clone(&self.counters.upstream_to_client);
         
         // client → TCP (no mutex)
-        let a = thread::Builder::new()
-            .name("client-to-tcp".to_string())
+        let a = forwarding_thread("client-to-tcp")
             .spawn({
                 let counter = Arc::clone(&client_to_upstream_bytes);
-                move || Self::forward_data_with_metrics(client_read, tcp_write, counter)
+                let shaper = OutboundShaper::new(self.traffic_shaping);
+                move || Self::forward_data_with_metrics(client_read, tcp_write, counter, shaper, true, None)
             })
             .map_err(|_| TransportError::ConnectionFailed)?;
         
         // TCP → client (no mutex)
-        let b = thread::Builder::new()
-            .name("tcp-to-client".to_string())
+        let b = forwarding_thread("tcp-to-client")
             .spawn({
                 let counter = Arc::clone(&upstream_to_client_bytes);
-                move || Self::forward_data_with_metrics(tcp_read, client_write, counter)
+                move || {
+                    let shaper = OutboundShaper::new(false);
+                    Self::forward_data_with_metrics(tcp_read, client_write, counter, shaper, false, Some(start_time))
+                }
             })
             .map_err(|_| TransportError::ConnectionFailed)?;
         
@@ -172,8 +281,13 @@ impl<Phase: AllowsPerUserConnectionOwnership
         let client_bytes = client_to_upstream_bytes.load(Ordering::Relaxed);
         let upstream_bytes = upstream_to_client_bytes.load(Ordering::Relaxed);
         
-        log!(LogLevel::Debug, "CONNECT tunnel closed: client→upstream {} bytes, upstream→client {} bytes, duration {:?}", 
-             client_bytes, upstream_bytes, duration);
+        log!(
+            LogLevel::Debug,
+            "CONNECT tunnel closed";
+            safe "client_to_upstream_bytes" => client_bytes,
+            safe "upstream_to_client_bytes" => upstream_bytes,
+            safe "duration_ms" => duration.as_millis()
+        );
         
         // Handle thread panics or errors
         match (result_a, result_b) {
@@ -188,10 +302,18 @@ impl<Phase: AllowsPerUserConnectionOwnership
         }
     }
     
-    /// Forward data directly between streams with metrics (no mutex)
-    fn forward_data_with_metrics(mut src: TcpStream, mut dst: TcpStream, byte_counter: Arc<AtomicU64>) -> Result<(), TransportError> {
-        let mut buf = [0u8; 65536]; // 64KB buffer
-        let mut shaping_state = ConnectionState::default();
+    /// Forward data directly between streams with metrics (no mutex).
+    /// `first_byte_from` records first-byte latency for the upstream direction.
+    /// `shaper` lives as long as the connection, so its state spans writes.
+    fn forward_data_with_metrics(
+        mut src: TcpStream,
+        mut dst: TcpStream,
+        byte_counter: Arc<AtomicU64>,
+        mut shaper: OutboundShaper,
+        client_to_upstream: bool,
+        mut first_byte_from: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        let mut buf = [0u8; TUNNEL_BUFFER];
         loop {
             match src.read(&mut buf) {
                 Ok(0) => {
@@ -200,12 +322,16 @@ impl<Phase: AllowsPerUserConnectionOwnership
                     return Ok(());
                 }
                 Ok(n) => {
+                    if let Some(started) = first_byte_from.take() {
+                        observability::record_first_byte_latency(started.elapsed());
+                    }
                     // Apply traffic shaping hook before writing to socket
-                    let shaped_data = traffic_shaping::shape_outbound_data(&buf[..n], &mut shaping_state);
+                    let shaped_data = shaper.shape(&buf[..n]);
                     if let Err(_) = dst.write_all(&shaped_data) {
                         return Ok(());
                     }
                     byte_counter.fetch_add(shaped_data.len() as u64, Ordering::Relaxed);
+                    observability::record_tunnel_bytes(client_to_upstream, shaped_data.len() as u64);
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     continue;
@@ -222,9 +348,36 @@ impl<Phase: AllowsPerUserConnectionOwnership
     + AllowsStableSocketMapping
     + AllowsDirectTimingCorrespondence> EncryptedTransport for DirectTcpTunnelTransport<Phase> {
     async fn establish_connection(&mut self) -> Result<(), TransportError> {
-        // Resolve hostname using DoH resolver (no plaintext DNS)
-        let mut ips = self.dns_resolver.resolve(&self.target_host).await
-            .map_err(|_| TransportError::ConnectionFailed)?;
+        // An exit with a DNS key resolves the sealed name; we open by address
+        let mut ips = match self.relay_transport.resolve_at_exit(&self.target_host).await {
+            Some(result) => result.map_err(|e| {
+                log!(LogLevel::Error, "Exit lookup failed"; sensitive "error" => e);
+                connect_failure(&e)
+            })?,
+            None => {
+                // Relays that take the hostname resolve it themselves
+                let connect_started = Instant::now();
+                let by_hostname = self.relay_transport.establish_by_hostname(&self.target_host, self.target_port);
+                if let Some(result) = by_hostname.await {
+                    let tcp = result.map_err(|e| {
+                        log!(LogLevel::Error, "Relay connection failed"; sensitive "error" => e);
+                        connect_failure(&e)
+                    })?;
+                    observability::record_connect_latency(connect_started.elapsed());
+                    let std_stream = tcp.into_std().map_err(|_| TransportError::ConnectionFailed)?;
+                    self.tcp_stream = Some(Arc::new(Mutex::new(std_stream)));
+                    return Ok(());
+                }
+
+                // Relay builds leave resolution to the exit; the client never falls back to DoH
+                if RelayMode::compiled() != RelayMode::Direct {
+                    return Err(TransportError::ConnectionFailed);
+                }
+                let resolution = self.dns_resolver.resolve(&self.target_host);
+                ObsSpan::dns_resolution(&self.target_host).instrument(resolution).await
+                    .map_err(|_| TransportError::ConnectionFailed)?
+            }
+        };
         
         if ips.is_empty() {
             log!(LogLevel::Error, "No IP addresses resolved");
@@ -238,19 +391,25 @@ impl<Phase: AllowsPerUserConnectionOwnership
         });
         
         // Sequential connection attempts
-        log!(LogLevel::Debug, "Sequential connection attempts ({} IPs)", ips.len());
+        log!(LogLevel::Debug, "Sequential connection attempts"; safe "ip_count" => ips.len());
         
         let mut last_error = None;
         
         for ip in ips {
-            log!(LogLevel::Debug, "Attempting connection via {}", ip);
+            log!(LogLevel::Debug, "Attempting connection"; sensitive "ip" => ip);
             
+            let connect_started = Instant::now();
             match self.relay_transport.establish_relay_connection(ip, self.target_port).await {
                 Ok(tcp) => {
-                    log!(LogLevel::Debug, "Connection established via {}", ip);
+                    observability::record_connect_latency(connect_started.elapsed());
+                    log!(LogLevel::Debug, "Connection established"; sensitive "ip" => ip);
                     
                     let std_stream = tcp.into_std().map_err(|e| {
-                        log!(LogLevel::Debug, "Failed to convert tokio stream to std: {}", e);
+                        log!(
+                            LogLevel::Debug,
+                            "Failed to convert tokio stream to std";
+                            safe "error" => e
+                        );
                         TransportError::ConnectionFailed
                     })?;
                     
@@ -258,14 +417,23 @@ impl<Phase: AllowsPerUserConnectionOwnership
                     return Ok(());
                 }
                 Err(e) => {
-                    log!(LogLevel::Trace, "Connection failed to {}: {}", ip, e);
+                    log!(
+                        LogLevel::Trace,
+                        "Connection attempt failed";
+                        sensitive "ip" => ip,
+                        sensitive "error" => e
+                    );
                     last_error = Some(e);
                 }
             }
         }
         
-        log!(LogLevel::Error, "All sequential connection attempts failed: {:?}", last_error);
-        Err(TransportError::ConnectionFailed)
+        log!(
+            LogLevel::Error,
+            "All sequential connection attempts failed";
+            sensitive "error" => format!("{:?}", last_error)
+        );
+        Err(last_error.as_ref().map_or(TransportError::ConnectionFailed, connect_failure))
     }
     
     async fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, TransportError> {
@@ -0,0 +1,199 @@
+//! Aggregate accounting for relay operators: session, connection, byte and
+//! refusal totals, enough to size a relay and nothing more. No counter is
+//! keyed by client, session or destination, and byte totals are reported
+//! in whole MiB so a lone session's traffic does not show byte-exact.
+//!
+//! Nothing is exposed unless the operator asks for it: `serve_metrics`
+//! runs only with `ebt relay serve --metrics-listen`, which accepts
+//! loopback addresses only. The body is `name value` lines, as at
+//! `/debug/obs`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::log;
+use crate::logging::LogLevel;
+use crate::relay_protocol::{GoAwayCode, OpenReject};
+
+const METRICS_GET: &str = "GET /metrics ";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const MIB: u64 = 1024 * 1024;
+
+/// Every `OpenReject`, in reporting order.
+const REJECT_REASONS: [(OpenReject, &str); 8] = [
+    (OpenReject::Refused, "refused"),
+    (OpenReject::DialFailed, "dial_failed"),
+    (OpenReject::ResolveFailed, "resolve_failed"),
+    (OpenReject::PortNotAllowed, "port_not_allowed"),
+    (OpenReject::AddressDenied, "address_denied"),
+    (OpenReject::BandwidthExceeded, "bandwidth_exceeded"),
+    (OpenReject::RateLimited, "rate_limited"),
+    (OpenReject::DestinationRateLimited, "destination_rate_limited"),
+];
+
+/// Process-wide totals, shared by every session of a `RelayServer`.
+#[derive(Debug, Default)]
+pub struct RelayAccounting {
+    sessions_total: AtomicU64,
+    sessions_active: AtomicU64,
+    connections_opened: AtomicU64,
+    bytes_from_clients: AtomicU64,
+    bytes_to_clients: AtomicU64,
+    opens_rejected: [AtomicU64; REJECT_REASONS.len()],
+    sessions_over_quota: AtomicU64,
+    frames_corrupt: AtomicU64,
+}
+
+impl RelayAccounting {
+    /// Counts a session until the returned guard is dropped.
+    pub fn session_started(self: &Arc<Self>) -> ActiveSession {
+        self.sessions_total.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(Arc::clone(self))
+    }
+
+    pub fn record_connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_from_client(&self, bytes: usize) {
+        self.bytes_from_clients.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_to_client(&self, bytes: usize) {
+        self.bytes_to_clients.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_open_rejected(&self, reject: OpenReject) {
+        if let Some(idx) = REJECT_REASONS.iter().position(|(reason, _)| *reason == reject) {
+            self.opens_rejected[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_go_away(&self, code: GoAwayCode) {
+        if code == GoAwayCode::QuotaExceeded {
+            self.sessions_over_quota.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A session ended by a frame that failed its CRC32C.
+    pub fn record_corrupt_frame(&self) {
+        self.frames_corrupt.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `name value` lines.
+    pub fn encode(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        out.push_str(&format!("relay_sessions_total {}\n", load(&self.sessions_total)));
+        out.push_str(&format!("relay_sessions_active {}\n", load(&self.sessions_active)));
+        out.push_str(&format!("relay_sessions_over_quota {}\n", load(&self.sessions_over_quota)));
+        out.push_str(&format!("relay_connections_opened {}\n", load(&self.connections_opened)));
+        out.push_str(&format!("relay_frames_corrupt {}\n", load(&self.frames_corrupt)));
+        out.push_str(&format!("relay_mib_from_clients {}\n", load(&self.bytes_from_clients) / MIB));
+        out.push_str(&format!("relay_mib_to_clients {}\n", load(&self.bytes_to_clients) / MIB));
+        for ((_, name), count) in REJECT_REASONS.iter().zip(&self.opens_rejected) {
+            out.push_str(&format!("relay_opens_rejected_{} {}\n", name, load(count)));
+        }
+        out
+    }
+}
+
+/// Keeps `relay_sessions_active` counting one session.
+#[derive(Debug)]
+pub struct ActiveSession(Arc<RelayAccounting>);
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.0.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves `GET /metrics` until the listener fails. Callers bind loopback.
+pub async fn serve_metrics(listener: TcpListener, accounting: Arc<RelayAccounting>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let accounting = Arc::clone(&accounting);
+        tokio::spawn(async move {
+            if let Err(e) = answer_metrics(stream, &accounting).await {
+                log!(LogLevel::Debug, "Relay metrics request failed"; safe "error" => e);
+            }
+        });
+    }
+}
+
+async fn answer_metrics(mut stream: TcpStream, accounting: &RelayAccounting) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        let read = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut chunk)).await??;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+    let response = if request.starts_with(METRICS_GET.as_bytes()) {
+        let body = accounting.encode();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn totals_are_served_without_per_session_detail() {
+        let accounting = Arc::new(RelayAccounting::default());
+        let first = accounting.session_started();
+        let second = accounting.session_started();
+        drop(first);
+        accounting.record_connection_opened();
+        accounting.record_from_client(3 * MIB as usize + 17);
+        accounting.record_to_client(1000);
+        accounting.record_open_rejected(OpenReject::AddressDenied);
+        accounting.record_go_away(GoAwayCode::QuotaExceeded);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, Arc::clone(&accounting)));
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: relay\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        for line in [
+            "relay_sessions_total 2",
+            "relay_sessions_active 1",
+            "relay_sessions_over_quota 1",
+            "relay_connections_opened 1",
+            "relay_frames_corrupt 0",
+            "relay_mib_from_clients 3",
+            "relay_mib_to_clients 0",
+            "relay_opens_rejected_address_denied 1",
+            "relay_opens_rejected_rate_limited 0",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {line:?} in {body}");
+        }
+        assert_eq!(body.lines().count(), 7 + REJECT_REASONS.len());
+        drop(second);
+        assert!(accounting.encode().contains("relay_sessions_active 0\n"));
+    }
+}
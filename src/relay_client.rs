@@ -0,0 +1,830 @@
+//! Client side of `relay_protocol`, used by single-hop relay builds. Each
+//! tunnel gets its own TLS session to the relay carrying one connection,
+//! opened by hostname: the relay resolves and dials the target, so the
+//! network between client and relay sees only the relay endpoint.
+//!
+//! Relays usually present self-signed certificates and are authenticated
+//! by `RelayEndpoint::fingerprint`, the SHA-256 of the leaf certificate.
+//! Without a pin the platform roots are checked against the endpoint host.
+//!
+//! The relay acknowledges a dialed target with a zero-credit
+//! `WindowUpdate` and refuses with an `OpenReject` code, so `open` returns
+//! only once the target is reachable. Data toward the target is held to
+//! the window the relay grants.
+//!
+//! Endpoints with a `command` are reached over the pipes of that command
+//! instead; see `relay_stdio`.
+//!
+//! A session can instead carry one UDP association, see `associate`, when
+//! the relay grants `CAP_DATAGRAM`.
+//!
+//! Endpoints with a `dns_key` have the exit resolve their destinations,
+//! see `resolve`: the name travels sealed to that key in a `Dns` frame,
+//! and only the address is sent in `Open`. Relays between client and exit
+//! cannot read it. Needs `CAP_EXIT_DNS`, which every session offers.
+//!
+//! Endpoints with `checksums` also offer `CAP_CHECKSUM`; once the relay
+//! agrees, every frame after Hello carries a CRC32C and one that fails or
+//! lacks it ends the session.
+//!
+//! `connect` offers `CAP_RESUME`. A session the relay cuts with
+//! `GoAwayCode::Restarting`, or that drops without a `Close`, leaves its
+//! token behind; the next `connect` to that relay retries the dial while
+//! the relay comes back and presents the token, and the relay reports how
+//! many connections the cut session lost. See `relay_resume`.
+//!
+//! OBS_DEV builds can capture the frame headers of selected connections;
+//! see `frame_capture`.
+//!
+//! The tunnel forwards between real sockets, so `splice` is normally run
+//! against one end of a `loopback_pair`.
+#![allow(deprecated)]
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+
+use crate::config::{RelayEndpoint, TransportKind};
+use crate::crypto_transport_design::PayloadMessage;
+use crate::event_bus::{self, EbtEvent};
+use crate::exit_dns::{self, ExitDnsClient, ExitDnsError};
+use crate::frame_capture::{Direction, FrameCapture};
+use crate::log;
+use crate::logging::LogLevel;
+use crate::memory_profile::MemoryProfile;
+use crate::onion::OnionError;
+use crate::relay_protocol::{
+    Datagram, DatagramFrame, FrameType, GoAwayCode, LegacyControlMessage, LegacyDataFrame, OpenReject, ProtocolError,
+    ProtocolVersion, CAP_CHECKSUM, CAP_DATAGRAM, CAP_EXIT_DNS, CAP_RESUME,
+};
+use crate::relay_resume::{SessionToken, NO_TOKEN};
+use crate::relay_server::{encode_frame, read_session_frame};
+use crate::relay_stdio;
+use crate::relay_transport::relay_socket_addr;
+use crate::tls_wrapper::TlsWrapper;
+
+/// The only connection of a session.
+const CONN_ID: u32 = 1;
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Covers the relay's own resolve and dial.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(20);
+/// The window a relay's `ConnectionTable` grants each connection, and the
+/// most it lets one grow to.
+const INITIAL_WINDOW: u32 = 64 * 1024;
+const MAX_WINDOW: u32 = INITIAL_WINDOW * 2;
+/// Largest data frame sent toward the target.
+const CHUNK: usize = MemoryProfile::active().relay_chunk;
+/// Dials made to a relay that cut a session, while it restarts.
+const RESUME_DIAL_ATTEMPTS: usize = 5;
+const RESUME_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RelayClientError {
+    #[error("relay connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("relay TLS setup failed: {0}")]
+    Tls(String),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("relay refused the connection: {0}")]
+    Refused(OpenReject),
+    #[error("relay reported error {0:#04x} on the connection")]
+    Failed(u8),
+    #[error("relay ended the session (code {0:#04x})")]
+    GoAway(u8),
+    #[error("relay did not answer in time")]
+    Timeout,
+    #[error("relay transport {0:?} is not supported")]
+    UnsupportedTransport(TransportKind),
+    #[error("circuit setup failed: {0}")]
+    Circuit(#[from] OnionError),
+    #[error("relay path is empty")]
+    EmptyPath,
+    #[error("only the first relay of a path can be reached through a command")]
+    CommandNotFirst,
+    #[error("relay does not carry UDP")]
+    DatagramsUnsupported,
+    #[error("exit does not resolve names for clients")]
+    ExitDnsUnsupported,
+    #[error("exit lookup failed: {0}")]
+    ExitDns(#[from] ExitDnsError),
+}
+
+impl From<RelayClientError> for std::io::Error {
+    fn from(e: RelayClientError) -> Self {
+        match e {
+            RelayClientError::Io(e) => e,
+            RelayClientError::Refused(_) => std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e),
+            RelayClientError::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, e),
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// Accepts exactly the certificate whose SHA-256 is pinned.
+struct PinnedCertificate {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        let fingerprint: String = digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+        if fingerprint != self.fingerprint {
+            return Err(rustls::Error::General("relay certificate does not match its pin".to_string()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn tls_wrapper(endpoint: &RelayEndpoint) -> Result<TlsWrapper, RelayClientError> {
+    let Some(fingerprint) = &endpoint.fingerprint else {
+        return TlsWrapper::new().map_err(|e| RelayClientError::Tls(e.to_string()));
+    };
+    let pinned = PinnedCertificate {
+        fingerprint: fingerprint.clone(),
+    };
+    Ok(TlsWrapper::with_verifier(Arc::new(pinned), format!("pin:{}", fingerprint)))
+}
+
+/// Anything a relay session can run over: TLS, a relay command's pipes,
+/// or a leg of a circuit.
+pub trait RelayIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RelayIo for T {}
+
+/// Tokens of cut sessions, by relay, waiting to be presented.
+fn cut_sessions() -> &'static Mutex<HashMap<String, Vec<SessionToken>>> {
+    static CUT_SESSIONS: OnceLock<Mutex<HashMap<String, Vec<SessionToken>>>> = OnceLock::new();
+    CUT_SESSIONS.get_or_init(Mutex::default)
+}
+
+fn take_cut_session(relay: &str) -> Option<SessionToken> {
+    let mut cut = cut_sessions().lock().unwrap_or_else(|e| e.into_inner());
+    let tokens = cut.get_mut(relay)?;
+    let token = tokens.pop();
+    if tokens.is_empty() {
+        cut.remove(relay);
+    }
+    token
+}
+
+/// Every waiting token with its relay, for `session_export`.
+pub(crate) fn cut_session_tokens() -> Vec<(String, SessionToken)> {
+    let cut = cut_sessions().lock().unwrap_or_else(|e| e.into_inner());
+    cut.iter()
+        .flat_map(|(relay, tokens)| tokens.iter().map(move |token| (relay.clone(), *token)))
+        .collect()
+}
+
+/// Leaves `token` for the next `connect` to `relay`, as a cut session there
+/// would.
+pub(crate) fn leave_cut_session(relay: String, token: SessionToken) {
+    Resumable { relay, token }.record_cut();
+}
+
+/// A session's claim on the relay it ran on.
+struct Resumable {
+    relay: String,
+    token: SessionToken,
+}
+
+impl Resumable {
+    fn record_cut(self) {
+        cut_sessions()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(self.relay)
+            .or_default()
+            .push(self.token);
+    }
+}
+
+/// Reaches `endpoint`, then Hello. After a relay cut one of our sessions
+/// the dial is retried while it restarts, and the session is resumed.
+pub async fn connect(endpoint: &RelayEndpoint) -> Result<RelayChannel<Box<dyn RelayIo>>, RelayClientError> {
+    let relay = endpoint.to_string();
+    let Some(cut) = take_cut_session(&relay) else {
+        return resuming_handshake(dial(endpoint).await?, relay, NO_TOKEN, endpoint.checksums).await;
+    };
+    let mut attempt = 1;
+    let stream = loop {
+        match dial(endpoint).await {
+            Ok(stream) => break stream,
+            Err(e) if attempt == RESUME_DIAL_ATTEMPTS => return Err(e),
+            Err(_) => {
+                log!(LogLevel::Debug, "Relay not back yet; retrying");
+                tokio::time::sleep(RESUME_RETRY_DELAY).await;
+                attempt += 1;
+            }
+        }
+    };
+    if attempt > 1 {
+        event_bus::publish(EbtEvent::TransportReconnected { attempts: attempt });
+    }
+    resuming_handshake(stream, relay, cut, endpoint.checksums).await
+}
+
+/// A stream to `endpoint`: the pipes of its `command` when it has one,
+/// TLS over TCP otherwise.
+pub(crate) async fn dial(endpoint: &RelayEndpoint) -> Result<Box<dyn RelayIo>, RelayClientError> {
+    if let Some(command) = &endpoint.command {
+        return Ok(Box::new(relay_stdio::launch(command)?));
+    }
+    let addr = relay_socket_addr(endpoint).await?;
+    let tcp = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| RelayClientError::Timeout)??;
+    tcp.set_nodelay(true)?;
+    Ok(Box::new(start_tls(endpoint, tcp).await?))
+}
+
+/// TLS to `endpoint` over `stream`, which may itself run through other
+/// relays.
+pub(crate) async fn start_tls<S>(endpoint: &RelayEndpoint, stream: S) -> Result<TlsStream<S>, RelayClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if endpoint.transport != TransportKind::Tls {
+        return Err(RelayClientError::UnsupportedTransport(endpoint.transport.clone()));
+    }
+    let tls = tls_wrapper(endpoint)?;
+    if ServerName::try_from(endpoint.host.as_str()).is_err() {
+        return Err(RelayClientError::Tls(format!("{} is not a valid server name", endpoint.host)));
+    }
+    timeout(CONNECT_TIMEOUT, tls.connect(&endpoint.host, endpoint.port, stream))
+        .await
+        .map_err(|_| RelayClientError::Timeout)?
+        .map_err(Into::into)
+}
+
+/// Says Hello over an established stream to a relay, offering
+/// `CAP_CHECKSUM` if `checksums`.
+pub async fn handshake<S>(stream: S, checksums: bool) -> Result<RelayChannel<S>, RelayClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (channel, _) = hello(stream, CAP_DATAGRAM | CAP_EXIT_DNS | checksum_flag(checksums)).await?;
+    Ok(channel)
+}
+
+/// Hello offering `CAP_RESUME`, `CAP_DATAGRAM` and `CAP_EXIT_DNS`, and
+/// `CAP_CHECKSUM` if `checksums`, then `Resume` with `presented` if the relay takes it up.
+async fn resuming_handshake<S>(
+    stream: S,
+    relay: String,
+    presented: SessionToken,
+    checksums: bool,
+) -> Result<RelayChannel<S>, RelayClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let offered = CAP_RESUME | CAP_DATAGRAM | CAP_EXIT_DNS | checksum_flag(checksums);
+    let (mut channel, capabilities) = hello(stream, offered).await?;
+    if capabilities & CAP_RESUME == 0 {
+        return Ok(channel);
+    }
+    let resume = LegacyControlMessage::Resume {
+        token: presented,
+        interrupted: 0,
+    };
+    channel
+        .stream
+        .write_all(&encode_frame(channel.version, channel.checksums, FrameType::Control, &resume.encode())?)
+        .await?;
+    let reply = expect_control(&mut channel.stream, &mut channel.buffer, channel.checksums).await?;
+    let (token, interrupted) = match reply {
+        LegacyControlMessage::Resume { token, interrupted } => (token, interrupted),
+        _ => return Err(ProtocolError::Malformed("relay must answer Resume with Resume").into()),
+    };
+    if interrupted > 0 {
+        log!(LogLevel::Info, "Resumed a relay session cut by restart"; safe "connections" => interrupted);
+    }
+    channel.resume = Some(Resumable { relay, token });
+    Ok(channel)
+}
+
+/// Hello with `capability_flags`; returns the capabilities both sides have.
+async fn hello<S>(mut stream: S, capability_flags: u32) -> Result<(RelayChannel<S>, u32), RelayClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = LegacyControlMessage::Hello {
+        version: 1,
+        capability_flags,
+    };
+    stream.write_all(&encode_frame(1, false, FrameType::Control, &hello.encode())?).await?;
+    let mut buffer = Vec::new();
+    let (version, capabilities) = match expect_control(&mut stream, &mut buffer, false).await? {
+        LegacyControlMessage::Hello { version, capability_flags: shared } => (version, shared & capability_flags),
+        _ => return Err(ProtocolError::Malformed("relay must answer with Hello").into()),
+    };
+    let channel = RelayChannel {
+        stream,
+        version,
+        buffer,
+        resume: None,
+        capabilities,
+        checksums: capabilities & CAP_CHECKSUM != 0,
+        capture: FrameCapture::default(),
+    };
+    Ok((channel, capabilities))
+}
+
+fn checksum_flag(checksums: bool) -> u32 {
+    if checksums {
+        CAP_CHECKSUM
+    } else {
+        0
+    }
+}
+
+/// The relay's next frame during the handshake, which must be control.
+async fn expect_control<S>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    checksums: bool,
+) -> Result<LegacyControlMessage, RelayClientError>
+where
+    S: AsyncRead + Unpin,
+{
+    let reply = timeout(CONNECT_TIMEOUT, read_session_frame(stream, buffer, checksums))
+        .await
+        .map_err(|_| RelayClientError::Timeout)??;
+    match reply {
+        Some((_, FrameType::Control, payload)) => Ok(LegacyControlMessage::decode(&payload)?),
+        Some(_) => Err(ProtocolError::Malformed("relay handshake frames are control frames").into()),
+        None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// A relay session past Hello, ready for its connection.
+pub struct RelayChannel<S> {
+    stream: S,
+    version: ProtocolVersion,
+    buffer: Vec<u8>,
+    resume: Option<Resumable>,
+    /// Granted by the relay in Hello.
+    capabilities: u32,
+    /// `CAP_CHECKSUM` was granted: every frame past Hello carries a CRC32C.
+    checksums: bool,
+    /// Selected when the connection is opened.
+    capture: FrameCapture,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RelayChannel<S> {
+    /// The stream, negotiated version, whether frames are checksummed,
+    /// and bytes read past Hello.
+    pub(crate) fn into_parts(self) -> (S, ProtocolVersion, bool, Vec<u8>) {
+        (self.stream, self.version, self.checksums, self.buffer)
+    }
+
+    /// Has the relay connect to `host:port` and waits for it to answer.
+    pub async fn open(mut self, host: &str, port: u16) -> Result<RelayConnection<S>, RelayClientError> {
+        let open = LegacyControlMessage::Open {
+            conn_id: CONN_ID,
+            target_host: host.to_string(),
+            target_port: port,
+        };
+        self.capture = FrameCapture::select(host, port, self.version, INITIAL_WINDOW, MAX_WINDOW);
+        let open = open.encode();
+        self.capture.record(Direction::Outbound, FrameType::Control, &open);
+        self.stream
+            .write_all(&encode_frame(self.version, self.checksums, FrameType::Control, &open)?)
+            .await?;
+        let window = timeout(OPEN_TIMEOUT, async {
+            loop {
+                let read = read_session_frame(&mut self.stream, &mut self.buffer, self.checksums).await?;
+                let Some((_, frame_type, payload)) = read else {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                };
+                self.capture.record(Direction::Inbound, frame_type, &payload);
+                if frame_type != FrameType::Control {
+                    continue;
+                }
+                match LegacyControlMessage::decode(&payload)? {
+                    LegacyControlMessage::WindowUpdate { conn_id: CONN_ID, credits } => {
+                        return Ok(INITIAL_WINDOW.saturating_add(credits).min(MAX_WINDOW));
+                    }
+                    LegacyControlMessage::Error { conn_id: CONN_ID, code } => {
+                        return Err(match OpenReject::from_code(code) {
+                            Some(reject) => RelayClientError::Refused(reject),
+                            None => RelayClientError::Failed(code),
+                        });
+                    }
+                    LegacyControlMessage::GoAway { code } => return Err(RelayClientError::GoAway(code)),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| RelayClientError::Timeout)??;
+        Ok(RelayConnection { channel: self, window })
+    }
+
+    /// Has the exit resolve `host` from a lookup sealed to `dns_key`. The
+    /// session stays ready for `open`. An exit that cannot resolve the
+    /// name answers with no addresses.
+    pub async fn resolve(&mut self, dns_key: &[u8; 32], host: &str) -> Result<Vec<IpAddr>, RelayClientError> {
+        if self.capabilities & CAP_EXIT_DNS == 0 {
+            return Err(RelayClientError::ExitDnsUnsupported);
+        }
+        let mut client = ExitDnsClient::new(dns_key);
+        let request = client.seal_request(host)?;
+        let PayloadMessage::DnsRequest { request_id, .. } = &request else {
+            unreachable!("seal_request builds a DnsRequest")
+        };
+        let lookup = exit_dns::encode_message(&request)?;
+        self.stream
+            .write_all(&encode_frame(self.version, self.checksums, FrameType::Dns, &lookup)?)
+            .await?;
+        let answer = timeout(OPEN_TIMEOUT, async {
+            loop {
+                let read = read_session_frame(&mut self.stream, &mut self.buffer, self.checksums).await?;
+                let Some((_, frame_type, payload)) = read else {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                };
+                match frame_type {
+                    FrameType::Dns => return Ok(exit_dns::decode_message(&payload)?),
+                    FrameType::Control => {
+                        if let LegacyControlMessage::GoAway { code } = LegacyControlMessage::decode(&payload)? {
+                            return Err(RelayClientError::GoAway(code));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .map_err(|_| RelayClientError::Timeout)??;
+        Ok(client.open_response(*request_id, &answer)?)
+    }
+
+    /// Uses the session for a UDP association instead of a connection.
+    pub fn associate(self) -> Result<RelayAssociation<S>, RelayClientError> {
+        if self.capabilities & CAP_DATAGRAM == 0 {
+            return Err(RelayClientError::DatagramsUnsupported);
+        }
+        Ok(RelayAssociation { channel: self })
+    }
+}
+
+/// A UDP association through the relay, opened by its first datagram.
+pub struct RelayAssociation<S> {
+    channel: RelayChannel<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RelayAssociation<S> {
+    /// Sends `outbound` through the relay and hands answers to `inbound`
+    /// until `outbound` closes, which closes the association. Answers
+    /// `inbound` has no room for are dropped.
+    pub async fn relay(
+        self,
+        mut outbound: mpsc::Receiver<Datagram>,
+        inbound: mpsc::Sender<Datagram>,
+    ) -> Result<(), RelayClientError> {
+        let RelayChannel {
+            mut stream,
+            version,
+            mut buffer,
+            checksums,
+            ..
+        } = self.channel;
+        loop {
+            tokio::select! {
+                datagram = outbound.recv() => {
+                    let Some(datagram) = datagram else {
+                        let close = LegacyControlMessage::Close { conn_id: CONN_ID, reason: 0 };
+                        let close = encode_frame(version, checksums, FrameType::Control, &close.encode())?;
+                        stream.write_all(&close).await?;
+                        let _ = stream.shutdown().await;
+                        return Ok(());
+                    };
+                    let datagram = DatagramFrame::new(CONN_ID, datagram).encode();
+                    stream.write_all(&encode_frame(version, checksums, FrameType::Datagram, &datagram)?).await?;
+                }
+                relayed = read_session_frame(&mut stream, &mut buffer, checksums) => {
+                    let Some((_, frame_type, payload)) = relayed? else {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    };
+                    match frame_type {
+                        FrameType::Datagram => {
+                            let answer = DatagramFrame::decode(&payload)?;
+                            if answer.conn_id != CONN_ID {
+                                continue;
+                            }
+                            if inbound.try_send(answer.datagram).is_err() && inbound.is_closed() {
+                                return Ok(());
+                            }
+                        }
+                        FrameType::Control => match LegacyControlMessage::decode(&payload)? {
+                            LegacyControlMessage::Close { conn_id: CONN_ID, .. } => return Ok(()),
+                            LegacyControlMessage::Error { conn_id: CONN_ID, code } => {
+                                return Err(match OpenReject::from_code(code) {
+                                    Some(reject) => RelayClientError::Refused(reject),
+                                    None => RelayClientError::Failed(code),
+                                });
+                            }
+                            LegacyControlMessage::GoAway { code } => return Err(RelayClientError::GoAway(code)),
+                            _ => {}
+                        },
+                        FrameType::Ping => {
+                            stream.write_all(&encode_frame(version, checksums, FrameType::Pong, &payload)?).await?
+                        }
+                        FrameType::Data | FrameType::Padding | FrameType::Pong | FrameType::Dns => {}
+                        FrameType::Onion | FrameType::Circuit => {
+                            return Err(ProtocolError::Malformed("circuit frames are for middle relays").into());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An open connection through the relay.
+pub struct RelayConnection<S> {
+    channel: RelayChannel<S>,
+    /// Bytes the relay will still take toward the target.
+    window: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RelayConnection<S> {
+    /// Carries bytes between `local` and the target until the target
+    /// closes or `local` stops taking them. After `local` reaches EOF the
+    /// target's remaining bytes are still delivered.
+    pub async fn splice<L>(self, local: L) -> Result<(), RelayClientError>
+    where
+        L: AsyncRead + AsyncWrite + Unpin,
+    {
+        let RelayConnection {
+            channel: RelayChannel {
+                stream,
+                version,
+                mut buffer,
+                resume,
+                checksums,
+                capture,
+                ..
+            },
+            mut window,
+        } = self;
+        let (mut relay_reader, mut relay_writer) = tokio::io::split(stream);
+        let (mut local_reader, mut local_writer) = tokio::io::split(local);
+        let mut chunk = vec![0u8; CHUNK];
+        let mut local_open = true;
+        loop {
+            tokio::select! {
+                read = local_reader.read(&mut chunk[..CHUNK.min(window as usize)]), if local_open && window > 0 => {
+                    let read = read?;
+                    if read == 0 {
+                        local_open = false;
+                        continue;
+                    }
+                    window -= read as u32;
+                    let data = LegacyDataFrame::new(CONN_ID, chunk[..read].to_vec()).encode();
+                    capture.record(Direction::Outbound, FrameType::Data, &data);
+                    relay_writer.write_all(&encode_frame(version, checksums, FrameType::Data, &data)?).await?;
+                }
+                relayed = read_session_frame(&mut relay_reader, &mut buffer, checksums) => {
+                    let (frame_type, payload) = match relayed {
+                        Ok(Some((_, frame_type, payload))) => (frame_type, payload),
+                        // Gone without a Close: the relay crashed or was cut off.
+                        Ok(None) | Err(ProtocolError::Io(_)) if resume.is_some() => {
+                            if let Some(resume) = resume {
+                                resume.record_cut();
+                            }
+                            return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into());
+                        }
+                        Ok(None) => break,
+                        Err(e) => return Err(e.into()),
+                    };
+                    capture.record(Direction::Inbound, frame_type, &payload);
+                    match frame_type {
+                        FrameType::Data => {
+                            let data = LegacyDataFrame::decode(&payload)?;
+                            if data.conn_id == CONN_ID && local_writer.write_all(&data.payload).await.is_err() {
+                                break;
+                            }
+                        }
+                        FrameType::Control => match LegacyControlMessage::decode(&payload)? {
+                            LegacyControlMessage::WindowUpdate { conn_id: CONN_ID, credits } => {
+                                window = window.saturating_add(credits).min(MAX_WINDOW);
+                            }
+                            LegacyControlMessage::Close { conn_id: CONN_ID, .. } => break,
+                            LegacyControlMessage::Error { conn_id: CONN_ID, code } => {
+                                return Err(RelayClientError::Failed(code));
+                            }
+                            LegacyControlMessage::GoAway { code } => {
+                                let _ = local_writer.shutdown().await;
+                                return Err(match GoAwayCode::from_code(code) {
+                                    Some(GoAwayCode::Shutdown) => std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into(),
+                                    Some(GoAwayCode::Restarting) => {
+                                        if let Some(resume) = resume {
+                                            resume.record_cut();
+                                        }
+                                        std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()
+                                    }
+                                    _ => RelayClientError::GoAway(code),
+                                });
+                            }
+                            _ => {}
+                        },
+                        FrameType::Ping => {
+                            capture.record(Direction::Outbound, FrameType::Pong, &payload);
+                            let pong = encode_frame(version, checksums, FrameType::Pong, &payload)?;
+                            relay_writer.write_all(&pong).await?;
+                        }
+                        FrameType::Padding | FrameType::Pong | FrameType::Datagram | FrameType::Dns => {}
+                        FrameType::Onion | FrameType::Circuit => {
+                            return Err(ProtocolError::Malformed("circuit frames are for middle relays").into());
+                        }
+                    }
+                }
+            }
+        }
+        let _ = local_writer.shutdown().await;
+        let _ = relay_writer.shutdown().await;
+        Ok(())
+    }
+}
+
+/// Two connected loopback sockets. The accepted end is checked to be the
+/// one dialed, so another local process cannot take its place.
+pub async fn loopback_pair() -> std::io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+    let dialed = TcpStream::connect(listener.local_addr()?).await?;
+    let local = dialed.local_addr()?;
+    loop {
+        let (accepted, peer) = listener.accept().await?;
+        if peer == local {
+            dialed.set_nodelay(true)?;
+            accepted.set_nodelay(true)?;
+            return Ok((dialed, accepted));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_dns::ExitDnsService;
+    use crate::exit_policy::ExitPolicy;
+    use crate::relay_quota::SessionQuotas;
+    use crate::relay_resume::ResumeRegistry;
+    use crate::relay_server::{default_limits, serve_session, RelayRole};
+
+    fn relay(exit_policy: ExitPolicy) -> tokio::io::DuplexStream {
+        relay_with_resume(exit_policy, Arc::default())
+    }
+
+    fn relay_with_resume(exit_policy: ExitPolicy, resume: Arc<ResumeRegistry>) -> tokio::io::DuplexStream {
+        let (client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Exit(Arc::new(exit_policy));
+        tokio::spawn(serve_session(relay, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), resume));
+        client
+    }
+
+    #[tokio::test]
+    async fn opens_by_hostname_and_splices_both_ways() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = vec![0u8; 200 * 1024];
+            stream.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().all(|byte| *byte == 7));
+            stream.write_all(b"done").await.unwrap();
+        });
+
+        // Checksummed, so every frame past Hello must carry a CRC32C.
+        let channel = handshake(relay(ExitPolicy::permissive()), true).await.unwrap();
+        assert!(channel.checksums);
+        let connection = channel.open("localhost", port).await.unwrap();
+        let (mut tunnel, relay_end) = loopback_pair().await.unwrap();
+        let splice = tokio::spawn(connection.splice(relay_end));
+        // Over three windows, so it only arrives if updates are honoured.
+        tunnel.write_all(&vec![7u8; 200 * 1024]).await.unwrap();
+        let mut reply = Vec::new();
+        tunnel.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"done");
+        assert!(splice.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn refused_opens_carry_the_reject_code() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let channel = handshake(relay(ExitPolicy::default()), false).await.unwrap();
+        match channel.open("127.0.0.1", port).await {
+            Err(RelayClientError::Refused(OpenReject::AddressDenied)) => {}
+            other => panic!("expected AddressDenied, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn exits_with_a_dns_key_answer_sealed_lookups() {
+        const DNS_KEY: [u8; 32] = [0x5a; 32];
+        let keyed = |exit_policy: ExitPolicy| ExitPolicy {
+            lookups: Some(Arc::new(ExitDnsService::new(&DNS_KEY).unwrap())),
+            ..exit_policy
+        };
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(b"done").await.unwrap();
+        });
+
+        let mut channel = handshake(relay(keyed(ExitPolicy::permissive())), true).await.unwrap();
+        let ips = channel.resolve(&DNS_KEY, "localhost").await.unwrap();
+        assert!(ips.contains(&IpAddr::from(Ipv4Addr::LOCALHOST)));
+        let connection = channel.open("127.0.0.1", port).await.unwrap();
+        let (mut tunnel, relay_end) = loopback_pair().await.unwrap();
+        tokio::spawn(connection.splice(relay_end));
+        let mut reply = Vec::new();
+        tunnel.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"done");
+
+        // Only addresses the exit would dial are listed.
+        let mut channel = handshake(relay(keyed(ExitPolicy::default())), false).await.unwrap();
+        assert_eq!(channel.resolve(&DNS_KEY, "localhost").await.unwrap(), Vec::<IpAddr>::new());
+
+        let mut channel = handshake(relay(keyed(ExitPolicy::permissive())), false).await.unwrap();
+        assert!(channel.resolve(&[0x11; 32], "localhost").await.is_err());
+
+        let mut channel = handshake(relay(ExitPolicy::permissive()), false).await.unwrap();
+        assert!(matches!(
+            channel.resolve(&DNS_KEY, "localhost").await,
+            Err(RelayClientError::ExitDnsUnsupported)
+        ));
+    }
+
+    #[tokio::test]
+    async fn associations_carry_datagrams_and_only_let_targets_answer() {
+        let target = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let stranger = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let channel = handshake(relay(ExitPolicy::permissive()), false).await.unwrap();
+        let association = channel.associate().unwrap();
+        let (out_tx, out_rx) = mpsc::channel(4);
+        let (in_tx, mut in_rx) = mpsc::channel(4);
+        let relaying = tokio::spawn(association.relay(out_rx, in_tx));
+        let ping = Datagram { host: "127.0.0.1".to_string(), port, payload: b"ping".to_vec() };
+        out_tx.send(ping).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (read, exit) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read], b"ping");
+        stranger.send_to(b"spoof", exit).await.unwrap();
+        target.send_to(b"pong", exit).await.unwrap();
+        let answer = in_rx.recv().await.unwrap();
+        assert_eq!(answer, Datagram { host: "127.0.0.1".to_string(), port, payload: b"pong".to_vec() });
+
+        drop(out_tx);
+        assert!(relaying.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn restarts_leave_the_session_token_for_the_next_connect() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (_held, _) = target.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let registry = Arc::new(ResumeRegistry::default());
+        let relay = relay_with_resume(ExitPolicy::permissive(), Arc::clone(&registry));
+        let channel = resuming_handshake(relay, "restarting.test:9001".to_string(), NO_TOKEN, false).await.unwrap();
+        let token = channel.resume.as_ref().unwrap().token;
+        let connection = channel.open("127.0.0.1", port).await.unwrap();
+        let (_tunnel, relay_end) = loopback_pair().await.unwrap();
+        let splice = tokio::spawn(connection.splice(relay_end));
+        registry.begin_restart();
+        match splice.await.unwrap() {
+            Err(RelayClientError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+            other => panic!("expected a reset, got {:?}", other),
+        }
+        assert_eq!(take_cut_session("restarting.test:9001"), Some(token));
+        assert_eq!(take_cut_session("restarting.test:9001"), None);
+    }
+}
@@ -0,0 +1,662 @@
+//! Relay directory. Relays sign a descriptor of themselves with their
+//! Ed25519 identity key and publish it to a directory; clients fetch the
+//! list, keep only descriptors whose signature, lifetime and (optionally)
+//! pinned identity check out, and draw candidate paths from them for
+//! `PathEpoch`.
+//!
+//! The directory is only a mailbox: it verifies what it stores so junk is
+//! not served, but clients never trust it. A descriptor's signed body is
+//! kept byte-for-byte, so verification does not depend on re-encoding.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::anonymity::path_epoch::{HopMetadata, PathHops};
+use crate::config::RelayEndpoint;
+use crate::log;
+use crate::logging::LogLevel;
+
+/// How long a published descriptor is valid; relays republish well before.
+pub const DESCRIPTOR_LIFETIME: Duration = Duration::from_secs(3 * 60 * 60);
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Tolerated clock skew for descriptors published "in the future".
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+const MAX_DESCRIPTOR_BYTES: usize = 16 * 1024;
+const MAX_DESCRIPTORS: usize = 4096;
+const MAX_LISTING_BYTES: usize = MAX_DESCRIPTORS * MAX_DESCRIPTOR_BYTES / 4;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+const DESCRIPTORS_PATH: &str = "/descriptors";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DirectoryError {
+    #[error("Descriptor signature does not verify")]
+    BadSignature,
+    #[error("Malformed descriptor: {0}")]
+    Malformed(String),
+    #[error("Descriptor expired")]
+    Expired,
+    #[error("Descriptor identity {0} is not pinned")]
+    NotPinned(String),
+    #[error("Directory fetch from {url} failed: {reason}")]
+    Fetch { url: String, reason: String },
+    #[error("Directory cache {path}: {reason}")]
+    Cache { path: String, reason: String },
+    #[error("Cached directory is older than the allowed {0:?}")]
+    Stale(Duration),
+    #[error("Identity key {path}: {reason}")]
+    IdentityKey { path: String, reason: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DescriptorRole {
+    Exit,
+    Middle,
+}
+
+/// What a relay says about itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayDescriptor {
+    pub address: SocketAddr,
+    pub role: DescriptorRole,
+    /// Ed25519 public key, lowercase hex.
+    pub identity: String,
+    /// SHA-256 of the relay's TLS certificate, lowercase hex.
+    pub tls_fingerprint: Option<String>,
+    /// Hosting provider or ASN tag, for `PathConstraints`.
+    pub provider: Option<String>,
+    /// ISO country code, for `PathConstraints`.
+    pub country: Option<String>,
+    /// Unix seconds.
+    pub published_at: u64,
+    pub expires_at: u64,
+}
+
+impl RelayDescriptor {
+    pub fn endpoint(&self) -> RelayEndpoint {
+        let mut endpoint = RelayEndpoint::from(self.address);
+        endpoint.fingerprint = self.tls_fingerprint.clone();
+        endpoint
+    }
+
+    pub fn hop_metadata(&self) -> HopMetadata {
+        let mut hop = HopMetadata::new(self.address.ip());
+        hop.provider = self.provider.clone();
+        hop.country = self.country.clone();
+        hop
+    }
+}
+
+/// A descriptor as published: the exact signed JSON and its signature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDescriptor {
+    pub body: String,
+    /// Ed25519 signature over `body`, lowercase hex.
+    pub signature: String,
+}
+
+impl SignedDescriptor {
+    /// Checks the signature against the identity the body names, then the
+    /// lifetime. Whether that identity is acceptable is up to the caller.
+    pub fn verify(&self, now_unix: u64) -> Result<RelayDescriptor, DirectoryError> {
+        if self.body.len() > MAX_DESCRIPTOR_BYTES {
+            return Err(DirectoryError::Malformed("descriptor too large".to_string()));
+        }
+        let descriptor: RelayDescriptor =
+            serde_json::from_str(&self.body).map_err(|e| DirectoryError::Malformed(e.to_string()))?;
+        let identity = decode_hex(&descriptor.identity)
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| DirectoryError::Malformed("identity is not a 32-byte hex key".to_string()))?;
+        let signature = decode_hex(&self.signature).ok_or(DirectoryError::BadSignature)?;
+        UnparsedPublicKey::new(&ED25519, identity)
+            .verify(self.body.as_bytes(), &signature)
+            .map_err(|_| DirectoryError::BadSignature)?;
+        if descriptor.expires_at <= now_unix {
+            return Err(DirectoryError::Expired);
+        }
+        if descriptor.published_at > now_unix + MAX_CLOCK_SKEW_SECS || descriptor.published_at >= descriptor.expires_at {
+            return Err(DirectoryError::Malformed("publication time out of range".to_string()));
+        }
+        Ok(descriptor)
+    }
+}
+
+/// A relay's identity key; signs its descriptors.
+pub struct DescriptorSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl DescriptorSigner {
+    /// Reads a PKCS#8 identity key, generating and saving one if `path`
+    /// does not exist yet.
+    pub fn load_or_create(path: &str) -> Result<Self, DirectoryError> {
+        let key_error = |reason: String| DirectoryError::IdentityKey {
+            path: path.to_string(),
+            reason,
+        };
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| key_error("key generation failed".to_string()))?;
+                write_private(path, pkcs8.as_ref()).map_err(|e| key_error(e.to_string()))?;
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(key_error(e.to_string())),
+        };
+        Self::from_pkcs8(&pkcs8).map_err(|_| key_error("not an Ed25519 PKCS#8 key".to_string()))
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, DirectoryError> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|_| DirectoryError::Malformed("not an Ed25519 PKCS#8 key".to_string()))?;
+        Ok(Self { key_pair })
+    }
+
+    pub fn identity(&self) -> String {
+        encode_hex(self.key_pair.public_key().as_ref())
+    }
+
+    /// Signs `descriptor` as this relay; its identity is overwritten.
+    pub fn sign(&self, mut descriptor: RelayDescriptor) -> SignedDescriptor {
+        descriptor.identity = self.identity();
+        let body = serde_json::to_string(&descriptor).expect("descriptor serializes");
+        let signature = encode_hex(self.key_pair.sign(body.as_bytes()).as_ref());
+        SignedDescriptor { body, signature }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn write_private(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(bytes)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, bytes)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Verified descriptors, one per identity, as a directory serves them.
+#[derive(Debug, Default)]
+pub struct DirectoryStore {
+    descriptors: HashMap<String, (u64, SignedDescriptor)>,
+}
+
+impl DirectoryStore {
+    /// Keeps `signed` unless a descriptor published later is already held.
+    pub fn publish(&mut self, signed: SignedDescriptor, now_unix: u64) -> Result<(), DirectoryError> {
+        let descriptor = signed.verify(now_unix)?;
+        self.prune(now_unix);
+        if let Some((published_at, _)) = self.descriptors.get(&descriptor.identity) {
+            if *published_at >= descriptor.published_at {
+                return Ok(());
+            }
+        } else if self.descriptors.len() >= MAX_DESCRIPTORS {
+            return Err(DirectoryError::Malformed("directory is full".to_string()));
+        }
+        self.descriptors
+            .insert(descriptor.identity, (descriptor.published_at, signed));
+        Ok(())
+    }
+
+    pub fn list(&mut self, now_unix: u64) -> Vec<SignedDescriptor> {
+        self.prune(now_unix);
+        self.descriptors.values().map(|(_, signed)| signed.clone()).collect()
+    }
+
+    fn prune(&mut self, now_unix: u64) {
+        self.descriptors.retain(|_, (_, signed)| signed.verify(now_unix).is_ok());
+    }
+}
+
+/// Serves `GET /descriptors` and `POST /descriptors` until the listener
+/// fails. Plain HTTP: descriptors carry their own signatures.
+pub async fn serve_directory(listener: TcpListener, store: Arc<Mutex<DirectoryStore>>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            if let Err(e) = handle_directory_request(stream, store).await {
+                log!(LogLevel::Debug, "Directory request failed"; safe "error" => e);
+            }
+        });
+    }
+}
+
+async fn handle_directory_request(mut stream: TcpStream, store: Arc<Mutex<DirectoryStore>>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() > MAX_DESCRIPTOR_BYTES {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        let read = tokio::time::timeout(FETCH_TIMEOUT, stream.read(&mut chunk)).await??;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+    let request_line = head.lines().next().unwrap_or_default();
+    let now = unix_now();
+
+    if request_line.starts_with(&format!("GET {} ", DESCRIPTORS_PATH)) {
+        let listing = store.lock().unwrap_or_else(|e| e.into_inner()).list(now);
+        let body = serde_json::to_string(&listing).expect("descriptors serialize");
+        return respond(&mut stream, "200 OK", &body).await;
+    }
+    if !request_line.starts_with(&format!("POST {} ", DESCRIPTORS_PATH)) {
+        return respond(&mut stream, "404 Not Found", "").await;
+    }
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+    let Some(content_length) = content_length.filter(|len| *len <= MAX_DESCRIPTOR_BYTES * 2) else {
+        return respond(&mut stream, "411 Length Required", "").await;
+    };
+    let mut body = request[head_end..].to_vec();
+    while body.len() < content_length {
+        let read = tokio::time::timeout(FETCH_TIMEOUT, stream.read(&mut chunk)).await??;
+        if read == 0 {
+            return Ok(());
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+    let published = serde_json::from_slice::<SignedDescriptor>(&body)
+        .map_err(|e| DirectoryError::Malformed(e.to_string()))
+        .and_then(|signed| store.lock().unwrap_or_else(|e| e.into_inner()).publish(signed, now));
+    match published {
+        Ok(()) => respond(&mut stream, "204 No Content", "").await,
+        Err(e) => respond(&mut stream, "422 Unprocessable Entity", &format!("{}\n", e)).await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn descriptors_url(directory: &str) -> String {
+    format!("{}{}", directory.trim_end_matches('/'), DESCRIPTORS_PATH)
+}
+
+/// Relay side: sends `signed` to the directory at `directory`.
+pub async fn publish_descriptor(directory: &str, signed: &SignedDescriptor) -> Result<(), DirectoryError> {
+    let url = descriptors_url(directory);
+    let fetch_error = |reason: String| DirectoryError::Fetch {
+        url: url.clone(),
+        reason,
+    };
+    reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| fetch_error(e.to_string()))?
+        .post(&url)
+        .json(signed)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| fetch_error(e.to_string()))?;
+    Ok(())
+}
+
+/// Re-signs and republishes `template` every `REPUBLISH_INTERVAL`, with
+/// fresh timestamps, for as long as the relay runs. Failures are logged
+/// and retried on the next round.
+pub async fn publish_periodically(directory: String, signer: DescriptorSigner, template: RelayDescriptor) {
+    loop {
+        let now = unix_now();
+        let signed = signer.sign(RelayDescriptor {
+            published_at: now,
+            expires_at: now + DESCRIPTOR_LIFETIME.as_secs(),
+            ..template.clone()
+        });
+        match publish_descriptor(&directory, &signed).await {
+            Ok(()) => log!(LogLevel::Info, "Relay descriptor published"; safe "identity" => signer.identity()),
+            Err(e) => log!(LogLevel::Error, "Relay descriptor not published"; safe "error" => e),
+        }
+        tokio::time::sleep(REPUBLISH_INTERVAL).await;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DirectoryCache {
+    fetched_at: u64,
+    descriptors: Vec<SignedDescriptor>,
+}
+
+/// Client side of the directory.
+#[derive(Debug, Clone)]
+pub struct DirectoryClient {
+    url: String,
+    /// Identities to accept, lowercase hex; empty accepts any valid
+    /// signature. Self-hosted setups pin their own relays.
+    pinned: Vec<String>,
+    cache_path: Option<String>,
+    /// Oldest cached listing used when the directory cannot be reached.
+    max_cache_age: Duration,
+}
+
+impl DirectoryClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            pinned: Vec::new(),
+            cache_path: None,
+            max_cache_age: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn with_pinned(mut self, identities: impl IntoIterator<Item = String>) -> Self {
+        self.pinned = identities.into_iter().map(|identity| identity.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn with_cache(mut self, path: impl Into<String>, max_age: Duration) -> Self {
+        self.cache_path = Some(path.into());
+        self.max_cache_age = max_age;
+        self
+    }
+
+    /// Fetches and verifies the listing, falling back to the cache when
+    /// the directory is unreachable and the cache is fresh enough.
+    pub async fn fetch(&self) -> Result<Vec<RelayDescriptor>, DirectoryError> {
+        let now = unix_now();
+        match self.fetch_listing().await {
+            Ok(listing) => {
+                if let Some(path) = &self.cache_path {
+                    let cache = DirectoryCache {
+                        fetched_at: now,
+                        descriptors: listing.clone(),
+                    };
+                    if let Err(e) = write_cache(path, &cache) {
+                        log!(LogLevel::Info, "Directory cache not written"; safe "error" => e);
+                    }
+                }
+                Ok(self.accept(listing, now))
+            }
+            Err(fetch_error) => {
+                let Some(path) = &self.cache_path else {
+                    return Err(fetch_error);
+                };
+                log!(LogLevel::Info, "Directory unreachable, using cache"; safe "error" => fetch_error);
+                let cache = read_cache(path)?;
+                if now.saturating_sub(cache.fetched_at) > self.max_cache_age.as_secs() {
+                    return Err(DirectoryError::Stale(self.max_cache_age));
+                }
+                Ok(self.accept(cache.descriptors, now))
+            }
+        }
+    }
+
+    async fn fetch_listing(&self) -> Result<Vec<SignedDescriptor>, DirectoryError> {
+        let url = descriptors_url(&self.url);
+        let fetch_error = |reason: String| DirectoryError::Fetch {
+            url: url.clone(),
+            reason,
+        };
+        let response = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .map_err(|e| fetch_error(e.to_string()))?
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| fetch_error(e.to_string()))?;
+        if response.content_length().unwrap_or(0) > MAX_LISTING_BYTES as u64 {
+            return Err(fetch_error("listing too large".to_string()));
+        }
+        let body = response.bytes().await.map_err(|e| fetch_error(e.to_string()))?;
+        if body.len() > MAX_LISTING_BYTES {
+            return Err(fetch_error("listing too large".to_string()));
+        }
+        serde_json::from_slice(&body).map_err(|e| fetch_error(e.to_string()))
+    }
+
+    /// Valid, unexpired, pinned descriptors; one per identity.
+    pub fn accept(&self, listing: Vec<SignedDescriptor>, now_unix: u64) -> Vec<RelayDescriptor> {
+        let mut accepted: Vec<RelayDescriptor> = Vec::new();
+        for signed in listing {
+            let verified = signed.verify(now_unix).and_then(|descriptor| {
+                if self.pinned.is_empty() || self.pinned.contains(&descriptor.identity) {
+                    Ok(descriptor)
+                } else {
+                    Err(DirectoryError::NotPinned(descriptor.identity))
+                }
+            });
+            match verified {
+                Ok(descriptor) if !accepted.iter().any(|held| held.identity == descriptor.identity) => {
+                    accepted.push(descriptor)
+                }
+                Ok(_) => {}
+                Err(e) => log!(LogLevel::Debug, "Descriptor rejected"; safe "error" => e),
+            }
+        }
+        accepted
+    }
+}
+
+fn write_cache(path: &str, cache: &DirectoryCache) -> Result<(), DirectoryError> {
+    let json = serde_json::to_vec(cache).expect("cache serializes");
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, json)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| DirectoryError::Cache {
+            path: path.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+fn read_cache(path: &str) -> Result<DirectoryCache, DirectoryError> {
+    let cache_error = |reason: String| DirectoryError::Cache {
+        path: path.to_string(),
+        reason,
+    };
+    let bytes = std::fs::read(path).map_err(|e| cache_error(e.to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|e| cache_error(e.to_string()))
+}
+
+/// A chain of directory relays, exit last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryPath {
+    pub endpoints: Vec<RelayEndpoint>,
+    hops: Vec<HopMetadata>,
+}
+
+impl PathHops for DirectoryPath {
+    fn hops(&self) -> &[HopMetadata] {
+        &self.hops
+    }
+}
+
+/// Up to `count` distinct random chains of `length` relays, each ending
+/// at an exit, for `PathEpoch::new(..)?.with_constraints(..)`.
+pub fn candidate_paths(
+    descriptors: &[RelayDescriptor],
+    length: usize,
+    count: usize,
+    rng: &mut impl Rng,
+) -> Vec<DirectoryPath> {
+    let exits: Vec<&RelayDescriptor> = descriptors.iter().filter(|d| d.role == DescriptorRole::Exit).collect();
+    let middles: Vec<&RelayDescriptor> = descriptors.iter().filter(|d| d.role == DescriptorRole::Middle).collect();
+    if length == 0 || exits.is_empty() || middles.len() < length - 1 {
+        return Vec::new();
+    }
+    let mut paths: Vec<DirectoryPath> = Vec::new();
+    // Bounded retries: small directories have few distinct chains.
+    for _ in 0..count * 4 {
+        if paths.len() == count {
+            break;
+        }
+        let mut chain: Vec<&RelayDescriptor> = middles.choose_multiple(rng, length - 1).copied().collect();
+        chain.push(exits.choose(rng).expect("non-empty"));
+        let path = DirectoryPath {
+            endpoints: chain.iter().map(|d| d.endpoint()).collect(),
+            hops: chain.iter().map(|d| d.hop_metadata()).collect(),
+        };
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anonymity::path_epoch::{PathConstraints, PathEpoch, UniformEpochDuration};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn signer() -> DescriptorSigner {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        DescriptorSigner::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn descriptor(address: &str, role: DescriptorRole, provider: &str, now: u64) -> RelayDescriptor {
+        RelayDescriptor {
+            address: address.parse().unwrap(),
+            role,
+            identity: String::new(),
+            tls_fingerprint: None,
+            provider: Some(provider.to_string()),
+            country: None,
+            published_at: now,
+            expires_at: now + DESCRIPTOR_LIFETIME.as_secs(),
+        }
+    }
+
+    #[test]
+    fn signatures_lifetimes_and_pins_are_checked() {
+        let now = 1_700_000_000;
+        let relay = signer();
+        let signed = relay.sign(descriptor("203.0.113.5:9001", DescriptorRole::Exit, "AS1", now));
+        assert_eq!(signed.verify(now).unwrap().identity, relay.identity());
+
+        let mut tampered = signed.clone();
+        tampered.body = tampered.body.replace("203.0.113.5", "203.0.113.6");
+        assert!(matches!(tampered.verify(now), Err(DirectoryError::BadSignature)));
+        assert!(matches!(
+            signed.verify(now + DESCRIPTOR_LIFETIME.as_secs()),
+            Err(DirectoryError::Expired)
+        ));
+
+        let other = signer().sign(descriptor("203.0.113.7:9001", DescriptorRole::Exit, "AS2", now));
+        let client = DirectoryClient::new("http://directory.invalid").with_pinned([relay.identity()]);
+        let accepted = client.accept(vec![signed, other, tampered], now);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].address, "203.0.113.5:9001".parse().unwrap());
+    }
+
+    #[test]
+    fn store_keeps_the_latest_descriptor_per_relay() {
+        let now = 1_700_000_000;
+        let relay = signer();
+        let mut store = DirectoryStore::default();
+        let older = relay.sign(descriptor("203.0.113.5:9001", DescriptorRole::Exit, "AS1", now));
+        let newer = relay.sign(descriptor("203.0.113.5:9002", DescriptorRole::Exit, "AS1", now + 60));
+        store.publish(newer.clone(), now + 60).unwrap();
+        store.publish(older, now + 60).unwrap();
+        assert_eq!(store.list(now + 60), vec![newer]);
+        assert!(store.list(now + 60 + DESCRIPTOR_LIFETIME.as_secs()).is_empty());
+    }
+
+    #[test]
+    fn candidate_paths_end_at_an_exit_and_feed_path_epochs() {
+        let now = 1_700_000_000;
+        let descriptors = vec![
+            descriptor("198.51.100.1:9001", DescriptorRole::Middle, "AS1", now),
+            descriptor("198.51.100.2:9001", DescriptorRole::Middle, "AS1", now),
+            descriptor("203.0.113.1:9001", DescriptorRole::Middle, "AS2", now),
+            descriptor("192.0.2.1:9001", DescriptorRole::Exit, "AS3", now),
+        ];
+        let mut rng = StdRng::seed_from_u64(2402);
+        let paths = candidate_paths(&descriptors, 2, 8, &mut rng);
+        assert_eq!(paths.len(), 3);
+        assert!(paths.iter().all(|path| path.endpoints[1].host == "192.0.2.1"));
+
+        let epoch = UniformEpochDuration::new(Duration::from_secs(60), Duration::from_secs(120)).unwrap();
+        let path_epoch = PathEpoch::new(paths, epoch)
+            .unwrap()
+            .with_constraints(PathConstraints::none().distinct_providers())
+            .unwrap();
+        assert!(path_epoch.current_path().endpoints.len() == 2);
+        assert!(candidate_paths(&descriptors, 5, 8, &mut rng).is_empty());
+    }
+
+    #[tokio::test]
+    async fn published_descriptors_are_fetched_and_cached() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_directory(listener, Arc::new(Mutex::new(DirectoryStore::default()))));
+
+        let relay = signer();
+        let signed = relay.sign(descriptor("203.0.113.5:9001", DescriptorRole::Exit, "AS1", unix_now()));
+        publish_descriptor(&url, &signed).await.unwrap();
+
+        let cache = std::env::temp_dir().join(format!("ebt-directory-{}.json", std::process::id()));
+        let cache = cache.to_str().unwrap().to_string();
+        let client = DirectoryClient::new(url).with_cache(cache.clone(), Duration::from_secs(60));
+        let fetched = client.fetch().await.unwrap();
+        assert_eq!(fetched[0].identity, relay.identity());
+
+        // With the directory gone the cache answers, until it is too old.
+        server.abort();
+        let _ = server.await;
+        assert_eq!(client.fetch().await.unwrap(), fetched);
+        let stale = client.clone().with_cache(cache.clone(), Duration::ZERO);
+        let mut old = read_cache(&cache).unwrap();
+        old.fetched_at -= 10;
+        write_cache(&cache, &old).unwrap();
+        assert!(matches!(stale.fetch().await, Err(DirectoryError::Stale(_))));
+        std::fs::remove_file(&cache).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Client-side relay health. A background prober connects to every
+//! configured relay each `RelayHealthConfig::interval`, tracks connect
+//! latency and failures, and excludes a relay from random selections for
+//! `exclusion` once it fails `failure_threshold` probes in a row. Probing
+//! continues while a relay is excluded; one successful probe restores it.
+//!
+//! Probes only open and close a TCP connection, so relays see no more
+//! than a port scan would show them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{RelayEndpoint, RelayHealthConfig};
+use crate::log;
+use crate::logging::LogLevel;
+use crate::relay_transport::relay_socket_addr;
+use crate::tunnel_registry::json_escape;
+
+/// Weight of the newest sample in the running latency average.
+const RTT_SMOOTHING: f64 = 0.2;
+
+/// Connect time to `endpoint`, or why it could not be reached.
+pub async fn probe(endpoint: &RelayEndpoint, timeout: Duration) -> std::io::Result<Duration> {
+    let addr = relay_socket_addr(endpoint).await?;
+    let started = Instant::now();
+    tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timeout"))??;
+    Ok(started.elapsed())
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelayStats {
+    pub probes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_rtt: Option<Duration>,
+    /// Exponentially weighted over successful probes.
+    pub avg_rtt: Option<Duration>,
+    /// Times the relay has been taken out of selection.
+    pub exclusions: u64,
+    excluded_until: Option<Instant>,
+}
+
+impl RelayStats {
+    pub fn excluded_at(&self, now: Instant) -> bool {
+        self.excluded_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Probe results per relay, shared by the prober and `RelaySelector`.
+#[derive(Debug)]
+pub struct RelayHealth {
+    config: RelayHealthConfig,
+    relays: Mutex<HashMap<String, RelayStats>>,
+}
+
+impl RelayHealth {
+    pub fn new(config: RelayHealthConfig) -> Self {
+        Self {
+            config,
+            relays: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &RelayHealthConfig {
+        &self.config
+    }
+
+    pub fn record_success(&self, endpoint: &RelayEndpoint, rtt: Duration) {
+        let mut relays = self.relays.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = relays.entry(endpoint.to_string()).or_default();
+        stats.probes += 1;
+        stats.consecutive_failures = 0;
+        stats.last_rtt = Some(rtt);
+        stats.avg_rtt = Some(match stats.avg_rtt {
+            Some(avg) => avg.mul_f64(1.0 - RTT_SMOOTHING) + rtt.mul_f64(RTT_SMOOTHING),
+            None => rtt,
+        });
+        if stats.excluded_until.take().is_some() {
+            log!(LogLevel::Info, "Relay reachable again");
+        }
+    }
+
+    pub fn record_failure(&self, endpoint: &RelayEndpoint, now: Instant) {
+        let mut relays = self.relays.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = relays.entry(endpoint.to_string()).or_default();
+        stats.probes += 1;
+        stats.failures += 1;
+        stats.consecutive_failures += 1;
+        if stats.consecutive_failures >= self.config.failure_threshold {
+            if !stats.excluded_at(now) {
+                stats.exclusions += 1;
+                log!(LogLevel::Info, "Relay excluded from selection";
+                    safe "consecutive_failures" => stats.consecutive_failures);
+            }
+            stats.excluded_until = Some(now + self.config.exclusion);
+        }
+    }
+
+    /// Puts every excluded relay back into selection; failures seen on a
+    /// network the machine has left say nothing about the new one.
+    pub fn forget_failures(&self) {
+        let mut relays = self.relays.lock().unwrap_or_else(|e| e.into_inner());
+        for stats in relays.values_mut() {
+            stats.consecutive_failures = 0;
+            stats.excluded_until = None;
+        }
+    }
+
+    pub fn is_excluded(&self, endpoint: &RelayEndpoint, now: Instant) -> bool {
+        let relays = self.relays.lock().unwrap_or_else(|e| e.into_inner());
+        relays.get(&endpoint.to_string()).is_some_and(|stats| stats.excluded_at(now))
+    }
+
+    pub fn stats(&self, endpoint: &RelayEndpoint) -> RelayStats {
+        let relays = self.relays.lock().unwrap_or_else(|e| e.into_inner());
+        relays.get(&endpoint.to_string()).cloned().unwrap_or_default()
+    }
+
+    /// `GET /ebt/relays` body, one object per endpoint in `endpoints` order.
+    pub fn to_json(&self, endpoints: &[RelayEndpoint], now: Instant) -> String {
+        let millis = |rtt: Option<Duration>| rtt.map_or("null".to_string(), |rtt| rtt.as_millis().to_string());
+        let relays: Vec<String> = endpoints
+            .iter()
+            .map(|endpoint| {
+                let stats = self.stats(endpoint);
+                let excluded_for = stats
+                    .excluded_until
+                    .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+                format!(
+                    "{{\"relay\":\"{}\",\"probes\":{},\"failures\":{},\"consecutive_failures\":{},\
+                     \"last_rtt_ms\":{},\"avg_rtt_ms\":{},\"exclusions\":{},\"excluded\":{},\
+                     \"excluded_for_secs\":{}}}",
+                    json_escape(&endpoint.to_string()),
+                    stats.probes,
+                    stats.failures,
+                    stats.consecutive_failures,
+                    millis(stats.last_rtt),
+                    millis(stats.avg_rtt),
+                    stats.exclusions,
+                    stats.excluded_at(now),
+                    excluded_for.as_secs()
+                )
+            })
+            .collect();
+        format!("{{\"relays\":[{}]}}", relays.join(","))
+    }
+}
+
+/// Probes each of `endpoints` every interval, concurrently, forever.
+pub async fn run_prober(health: Arc<RelayHealth>, endpoints: Vec<RelayEndpoint>) {
+    let mut ticker = tokio::time::interval(health.config.interval);
+    loop {
+        ticker.tick().await;
+        let mut probes = tokio::task::JoinSet::new();
+        for endpoint in &endpoints {
+            let health = Arc::clone(&health);
+            let endpoint = endpoint.clone();
+            probes.spawn(async move {
+                match probe(&endpoint, health.config.timeout).await {
+                    Ok(rtt) => health.record_success(&endpoint, rtt),
+                    Err(e) => {
+                        log!(LogLevel::Debug, "Relay probe failed"; safe "error" => e);
+                        health.record_failure(&endpoint, Instant::now());
+                    }
+                }
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health() -> RelayHealth {
+        RelayHealth::new(RelayHealthConfig {
+            failure_threshold: 2,
+            exclusion: Duration::from_secs(60),
+            ..RelayHealthConfig::default()
+        })
+    }
+
+    #[test]
+    fn repeated_failures_exclude_until_a_success() {
+        let health = health();
+        let relay = RelayEndpoint::new("203.0.113.1", 443);
+        let now = Instant::now();
+        health.record_failure(&relay, now);
+        assert!(!health.is_excluded(&relay, now));
+        health.record_failure(&relay, now);
+        assert!(health.is_excluded(&relay, now));
+        assert!(!health.is_excluded(&relay, now + Duration::from_secs(61)));
+
+        health.record_failure(&relay, now + Duration::from_secs(30));
+        assert!(health.is_excluded(&relay, now + Duration::from_secs(61)));
+        assert_eq!(health.stats(&relay).exclusions, 1);
+        health.record_success(&relay, Duration::from_millis(40));
+        assert!(!health.is_excluded(&relay, now + Duration::from_secs(61)));
+        assert_eq!(health.stats(&relay).consecutive_failures, 0);
+
+        // A network change clears exclusions but keeps the history.
+        health.record_failure(&relay, now);
+        health.record_failure(&relay, now);
+        assert!(health.is_excluded(&relay, now));
+        health.forget_failures();
+        assert!(!health.is_excluded(&relay, now));
+        assert_eq!(health.stats(&relay).exclusions, 2);
+    }
+
+    #[test]
+    fn latency_is_smoothed_and_reported() {
+        let health = health();
+        let relay = RelayEndpoint::new("203.0.113.1", 443);
+        health.record_success(&relay, Duration::from_millis(100));
+        health.record_success(&relay, Duration::from_millis(200));
+        let stats = health.stats(&relay);
+        assert_eq!(stats.last_rtt, Some(Duration::from_millis(200)));
+        assert_eq!(stats.avg_rtt, Some(Duration::from_millis(120)));
+
+        let json = health.to_json(&[relay, RelayEndpoint::new("203.0.113.2", 443)], Instant::now());
+        assert!(json.contains("\"relay\":\"203.0.113.1:443\",\"probes\":2,"), "{json}");
+        assert!(json.contains("\"relay\":\"203.0.113.2:443\",\"probes\":0,"), "{json}");
+        assert!(json.contains("\"last_rtt_ms\":null"), "{json}");
+    }
+
+    #[tokio::test]
+    async fn prober_excludes_an_unreachable_relay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = RelayEndpoint::from(listener.local_addr().unwrap());
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = RelayEndpoint::from(closed.local_addr().unwrap());
+        drop(closed);
+
+        let health = Arc::new(RelayHealth::new(RelayHealthConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_secs(1),
+            failure_threshold: 2,
+            exclusion: Duration::from_secs(60),
+        }));
+        let prober = tokio::spawn(run_prober(Arc::clone(&health), vec![up.clone(), down.clone()]));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        prober.abort();
+
+        assert!(health.is_excluded(&down, Instant::now()));
+        assert!(!health.is_excluded(&up, Instant::now()));
+        assert!(health.stats(&up).avg_rtt.is_some());
+    }
+}
@@ -1,15 +1,95 @@
-use std::io::{Read, Write, Result as IoResult};
+use std::io::{Read, Write};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use crate::core::observability::{self, trace::ObsSpan, ErrorClass};
 
 pub type ProtocolVersion = u8;
 
-const MAX_FRAME_SIZE: u32 = 1024 * 1024; // 1MB
+pub const MAX_FRAME_SIZE: u32 = 1024 * 1024; // 1MB
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("Handshake already completed or failed")]
+    HandshakeComplete,
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Max connections exceeded")]
+    ConnectionLimit,
+    #[error("Max inflight opens exceeded")]
+    InflightOpenLimit,
+    #[error("Buffer limit exceeded")]
+    BufferLimit,
+    #[error("Connection already exists")]
+    ConnectionExists,
+    #[error("Connection not found")]
+    ConnectionNotFound,
+    #[error("Invalid connection state for {0}")]
+    InvalidState(&'static str),
+    #[error("Insufficient send credits")]
+    InsufficientCredits,
+    #[error("Frame exceeds maximum size")]
+    FrameTooLarge,
+    #[error("Invalid frame type {0:#04x}")]
+    InvalidFrameType(u8),
+    #[error("Invalid control opcode {0:#04x}")]
+    InvalidOpcode(u8),
+    /// Payload shorter than its fields or otherwise undecodable.
+    #[error("{0}")]
+    Malformed(&'static str),
+    #[error("Client closed before completing CONNECT headers")]
+    HeadersIncomplete,
+    #[error("CONNECT headers timed out")]
+    HeadersTimedOut,
+    #[error("CONNECT request line exceeds its limit")]
+    RequestLineTooLong,
+    #[error("CONNECT headers exceed their limit")]
+    HeadersTooLarge,
+    /// A `CHECKSUM_FLAG` frame whose CRC32C did not match: the carrier
+    /// corrupted it.
+    #[error("Frame checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Frame I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl ProtocolError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ProtocolError::ConnectionLimit
+            | ProtocolError::InflightOpenLimit
+            | ProtocolError::BufferLimit
+            | ProtocolError::FrameTooLarge
+            | ProtocolError::RequestLineTooLong
+            | ProtocolError::HeadersTooLarge => ErrorClass::RESOURCE_LIMIT,
+            ProtocolError::Io(_) | ProtocolError::HeadersTimedOut | ProtocolError::ChecksumMismatch => {
+                ErrorClass::TRANSPORT_IO
+            }
+            _ => ErrorClass::PROTOCOL_VIOLATION,
+        }
+    }
+}
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameType {
     Control = 0x01,
     Data = 0x02,
+    /// Dummy traffic; receivers drop it unread.
+    Padding = 0x03,
+    /// Round-trip probe; the payload is an 8-byte nonce echoed in a `Pong`.
+    Ping = 0x04,
+    Pong = 0x05,
+    /// A cell sealed to a middle relay; see `onion`.
+    Onion = 0x06,
+    /// Circuit setup and teardown with a middle relay; see `circuit`.
+    Circuit = 0x07,
+    /// A UDP datagram of an association; see `DatagramFrame`. Only sent
+    /// once both sides set `CAP_DATAGRAM`.
+    Datagram = 0x08,
+    /// A lookup sealed to the exit's DNS key, or its answer; see
+    /// `exit_dns`. Only sent once both sides set `CAP_EXIT_DNS`.
+    Dns = 0x09,
 }
 
 #[repr(u8)]
@@ -20,12 +100,59 @@ pub enum ControlOpcode {
     Close = 0x02,
     WindowUpdate = 0x03,
     Error = 0x04,
+    GoAway = 0x05,
+    Resume = 0x06,
 }
 
 const PROTOCOL_VERSION_1: u8 = 1;
 const PROTOCOL_VERSION_2: u8 = 2;
 const SUPPORTED_VERSIONS: &[u8] = &[PROTOCOL_VERSION_1, PROTOCOL_VERSION_2];
 
+/// Hello capability: the side exchanges `Resume` right after Hello.
+pub const CAP_RESUME: u32 = 0x0000_0001;
+/// Hello capability: the side carries UDP in `Datagram` frames.
+pub const CAP_DATAGRAM: u32 = 0x0000_0002;
+/// Hello capability: once both sides set it, every frame after Hello
+/// carries a CRC32C and one without is refused. Meant for carriers without
+/// their own integrity; over TLS it only costs bytes.
+pub const CAP_CHECKSUM: u32 = 0x0000_0004;
+/// Hello capability: the side resolves names in `Dns` frames. Exits only
+/// grant it when they hold a DNS key.
+pub const CAP_EXIT_DNS: u32 = 0x0000_0008;
+const SUPPORTED_CAPABILITIES: u32 = CAP_RESUME | CAP_DATAGRAM | CAP_CHECKSUM | CAP_EXIT_DNS;
+
+/// Set on the type byte of a frame followed by a big-endian CRC32C of its
+/// header and payload. The length field still counts the payload only.
+pub const CHECKSUM_FLAG: u8 = 0x80;
+/// The CRC32C after a `CHECKSUM_FLAG` frame's payload.
+pub const CHECKSUM_LEN: usize = 4;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// CRC32C (Castagnoli) of `parts` taken in order.
+pub fn crc32c(parts: &[&[u8]]) -> u32 {
+    let crc = parts.iter().flat_map(|part| part.iter()).fold(!0u32, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HandshakeState {
     WaitingForHello,
@@ -48,22 +175,22 @@ impl ProtocolNegotiator {
         }
     }
     
-    pub fn process_hello(&mut self, version: u8, capability_flags: u32) -> Result<LegacyControlMessage, &'static str> {
+    pub fn process_hello(&mut self, version: u8, capability_flags: u32) -> Result<LegacyControlMessage, ProtocolError> {
         if self.state != HandshakeState::WaitingForHello {
-            return Err("Handshake already completed or failed");
+            return Err(ProtocolError::HandshakeComplete);
         }
         
         if !SUPPORTED_VERSIONS.contains(&version) {
             self.state = HandshakeState::Failed;
-            return Err("Unsupported protocol version");
+            return Err(ProtocolError::UnsupportedVersion(version));
         }
         
         self.negotiated_version = Some(version);
         self.peer_capabilities = Some(capability_flags);
         self.state = HandshakeState::Negotiated;
         
-        // Respond with our capabilities (flags are optional and ignorable)
-        Ok(LegacyControlMessage::Hello { version, capability_flags: 0 }) // No capabilities for now
+        // Respond with the capabilities both sides have
+        Ok(LegacyControlMessage::Hello { version, capability_flags: capability_flags & SUPPORTED_CAPABILITIES })
     }
     
     pub fn is_negotiated(&self) -> bool {
@@ -92,13 +219,61 @@ pub struct RelayLimits {
     pub max_connections: usize,
     pub max_inflight_opens: usize,
     pub max_buffered_bytes: usize,
+    /// Send window each connection starts with; a connection's window
+    /// never grows past twice this.
+    pub initial_window: u32,
+    /// Bytes of frames a `ProtocolEngine` holds for one connection while
+    /// its transport is slow; the relay server writes directly instead.
+    pub max_outbound_bytes: usize,
+    pub outbound_overflow: OutboundOverflow,
 }
 
-#[derive(Debug, Default)]
+/// What a `ProtocolEngine` does with data for a connection whose outbound
+/// queue is full. Control frames are always queued.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutboundOverflow {
+    /// Refuses the frame; the producer retries once the transport drains.
+    #[default]
+    BlockProducer,
+    /// Drops the oldest queued data frames and returns their credits. The
+    /// peer sees holes in the stream, so this only suits lossy traffic.
+    DropOldestData,
+    /// Closes the connection and discards its queue.
+    ResetConnection,
+}
+
+/// Shards of a `ConnectionTable`. Connections land by `conn_id`, so the
+/// sequential IDs a session hands out spread evenly.
+const CONNECTION_SHARDS: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct RelayMetrics {
     pub connections_rejected: u64,
     pub opens_rejected: u64,
     pub buffer_limit_breached: u64,
+    /// Data frames refused because their connection's outbound queue was full.
+    pub outbound_blocked: u64,
+    pub outbound_frames_dropped: u64,
+    /// Connections closed because their outbound queue was full.
+    pub outbound_resets: u64,
+}
+
+#[derive(Debug, Default)]
+struct MetricCounters {
+    connections_rejected: AtomicU64,
+    opens_rejected: AtomicU64,
+    buffer_limit_breached: AtomicU64,
+    outbound_blocked: AtomicU64,
+    outbound_frames_dropped: AtomicU64,
+    outbound_resets: AtomicU64,
+}
+
+/// A connection's control state, without its target or any payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    pub conn_id: u32,
+    pub state: ConnectionState,
+    pub send_window: u32,
 }
 
 struct ConnectionInfo {
@@ -108,41 +283,77 @@ struct ConnectionInfo {
     initial_window_size: u32,
 }
 
+impl ConnectionInfo {
+    /// Credits that top the window back up, once it is down to a quarter.
+    fn window_update(&self) -> Option<u32> {
+        (self.send_window < self.initial_window_size / 4).then(|| self.initial_window_size - self.send_window)
+    }
+
+    fn add_send_credits(&mut self, credits: u32) {
+        self.send_window = self.send_window.saturating_add(credits).min(self.initial_window_size * 2);
+    }
+}
+
+/// Connections of one session, sharded by `conn_id` so that threads
+/// serving different connections rarely wait on each other. A shard's lock
+/// covers the flow-control state of its connections; the limits are
+/// counted atomically and only touched when connections come and go.
 pub struct ConnectionTable {
-    connections: HashMap<u32, ConnectionInfo>,
-    inflight_opens: usize,
+    shards: Box<[Mutex<HashMap<u32, ConnectionInfo>>]>,
+    connection_count: AtomicUsize,
+    inflight_opens: AtomicUsize,
     limits: RelayLimits,
-    metrics: RelayMetrics,
+    metrics: MetricCounters,
     default_window_size: u32,
 }
 
+/// Takes one of `max` slots, if any is left.
+fn reserve(counter: &AtomicUsize, max: usize) -> bool {
+    counter.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < max).then_some(n + 1)).is_ok()
+}
+
+fn release(counter: &AtomicUsize) {
+    let _ = counter.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+}
+
+fn lock(shard: &Mutex<HashMap<u32, ConnectionInfo>>) -> MutexGuard<'_, HashMap<u32, ConnectionInfo>> {
+    // Nothing panics while holding a shard, so a poisoned one is intact.
+    shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 impl ConnectionTable {
     pub fn new(limits: RelayLimits) -> Self {
         Self {
-            connections: HashMap::new(),
-            inflight_opens: 0,
+            shards: (0..CONNECTION_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            connection_count: AtomicUsize::new(0),
+            inflight_opens: AtomicUsize::new(0),
+            metrics: MetricCounters::default(),
+            default_window_size: limits.initial_window,
             limits,
-            metrics: RelayMetrics::default(),
-            default_window_size: 65536, // 64KB default window
         }
     }
+
+    fn shard(&self, conn_id: u32) -> MutexGuard<'_, HashMap<u32, ConnectionInfo>> {
+        lock(&self.shards[conn_id as usize % CONNECTION_SHARDS])
+    }
+
+    fn with_connection<T>(&self, conn_id: u32, f: impl FnOnce(&mut ConnectionInfo) -> T) -> Option<T> {
+        self.shard(conn_id).get_mut(&conn_id).map(f)
+    }
     
     /// Relay is authoritative for flow control.
     /// This method generates control frames that MUST be sent to maintain protocol correctness.
-    pub fn poll_control_frames(&mut self) -> Vec<LegacyControlMessage> {
+    pub fn poll_control_frames(&self) -> Vec<LegacyControlMessage> {
         let mut frames = Vec::new();
-        let conn_ids: Vec<u32> = self.connections.keys().copied().collect();
-        
-        for conn_id in conn_ids {
-            if let Some(credits) = self.calculate_window_update(conn_id) {
-                frames.push(LegacyControlMessage::WindowUpdate { conn_id, credits });
-                // Update window immediately to prevent duplicate updates
-                if let Some(info) = self.connections.get_mut(&conn_id) {
-                    info.send_window = info.send_window.saturating_add(credits).min(info.initial_window_size * 2);
+        for shard in self.shards.iter() {
+            for (&conn_id, info) in lock(shard).iter_mut() {
+                if let Some(credits) = info.window_update() {
+                    frames.push(LegacyControlMessage::WindowUpdate { conn_id, credits });
+                    // Update window immediately to prevent duplicate updates
+                    info.add_send_credits(credits);
                 }
             }
         }
-        
         frames
     }
     
@@ -150,141 +361,164 @@ impl ConnectionTable {
         self.default_window_size = size;
     }
     
-    pub fn open_connection(&mut self, conn_id: u32) -> Result<(), &'static str> {
-        if self.connections.len() >= self.limits.max_connections {
-            self.metrics.connections_rejected += 1;
-            return Err("Max connections exceeded");
+    pub fn open_connection(&self, conn_id: u32) -> Result<(), ProtocolError> {
+        if !reserve(&self.connection_count, self.limits.max_connections) {
+            self.metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(ProtocolError::ConnectionLimit);
         }
         
-        if self.inflight_opens >= self.limits.max_inflight_opens {
-            self.metrics.opens_rejected += 1;
-            return Err("Max inflight opens exceeded");
+        if !reserve(&self.inflight_opens, self.limits.max_inflight_opens) {
+            release(&self.connection_count);
+            self.metrics.opens_rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(ProtocolError::InflightOpenLimit);
         }
         
-        match self.connections.get(&conn_id) {
-            None => {
-                self.connections.insert(conn_id, ConnectionInfo {
-                    state: ConnectionState::Init,
-                    buffered_bytes: 0,
-                    send_window: self.default_window_size,
-                    initial_window_size: self.default_window_size,
-                });
-                self.inflight_opens += 1;
-                Ok(())
-            }
-            Some(_) => Err("Connection already exists"),
+        let mut shard = self.shard(conn_id);
+        if shard.contains_key(&conn_id) {
+            release(&self.inflight_opens);
+            release(&self.connection_count);
+            return Err(ProtocolError::ConnectionExists);
         }
+        shard.insert(conn_id, ConnectionInfo {
+            state: ConnectionState::Init,
+            buffered_bytes: 0,
+            send_window: self.default_window_size,
+            initial_window_size: self.default_window_size,
+        });
+        Ok(())
     }
     
-    pub fn finalize_open(&mut self, conn_id: u32) -> Result<(), &'static str> {
-        if let Some(info) = self.connections.get_mut(&conn_id) {
+    pub fn finalize_open(&self, conn_id: u32) -> Result<(), ProtocolError> {
+        let finalized = self.with_connection(conn_id, |info| {
             if info.state == ConnectionState::Init {
                 info.state = ConnectionState::Open;
-                if self.inflight_opens > 0 {
-                    self.inflight_opens -= 1;
-                }
                 Ok(())
             } else {
-                Err("Connection not in init state")
+                Err(ProtocolError::InvalidState("finalize open"))
             }
-        } else {
-            Err("Connection not found")
+        });
+        let finalized = finalized.ok_or(ProtocolError::ConnectionNotFound)?;
+        if finalized.is_ok() {
+            release(&self.inflight_opens);
         }
+        finalized
     }
     
     pub fn can_send_data(&self, conn_id: u32, data_size: u32) -> bool {
-        match self.connections.get(&conn_id) {
-            Some(info) => {
-                info.state == ConnectionState::Open && info.send_window >= data_size
-            }
-            None => false,
-        }
+        self.with_connection(conn_id, |info| {
+            info.state == ConnectionState::Open && info.send_window >= data_size
+        })
+        .unwrap_or(false)
     }
     
-    pub fn consume_send_credits(&mut self, conn_id: u32, data_size: u32) -> Result<(), &'static str> {
-        if let Some(info) = self.connections.get_mut(&conn_id) {
+    pub fn consume_send_credits(&self, conn_id: u32, data_size: u32) -> Result<(), ProtocolError> {
+        self.with_connection(conn_id, |info| {
             if info.send_window >= data_size {
                 info.send_window -= data_size;
                 Ok(())
             } else {
-                Err("Insufficient send credits")
+                Err(ProtocolError::InsufficientCredits)
             }
-        } else {
-            Err("Connection not found")
-        }
+        })
+        .unwrap_or(Err(ProtocolError::ConnectionNotFound))
     }
     
-    pub fn add_send_credits(&mut self, conn_id: u32, credits: u32) -> Result<(), &'static str> {
-        if let Some(info) = self.connections.get_mut(&conn_id) {
-            let max_window = info.initial_window_size * 2;
-            let new_window = info.send_window.saturating_add(credits).min(max_window);
-            info.send_window = new_window;
-            Ok(())
-        } else {
-            Err("Connection not found")
-        }
+    pub fn add_send_credits(&self, conn_id: u32, credits: u32) -> Result<(), ProtocolError> {
+        self.with_connection(conn_id, |info| info.add_send_credits(credits))
+            .ok_or(ProtocolError::ConnectionNotFound)
     }
     
     pub fn calculate_window_update(&self, conn_id: u32) -> Option<u32> {
-        if let Some(info) = self.connections.get(&conn_id) {
-            if info.send_window < (info.initial_window_size / 4) {
-                Some(info.initial_window_size - info.send_window)
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        self.with_connection(conn_id, |info| info.window_update()).flatten()
     }
     
-    pub fn close_connection(&mut self, conn_id: u32) -> Result<(), &'static str> {
-        match self.connections.get_mut(&conn_id) {
-            Some(info) => {
-                match info.state {
-                    ConnectionState::Open => {
-                        info.state = ConnectionState::Closing;
-                        Ok(())
-                    }
-                    _ => Err("Invalid state for close"),
-                }
+    pub fn close_connection(&self, conn_id: u32) -> Result<(), ProtocolError> {
+        self.with_connection(conn_id, |info| match info.state {
+            ConnectionState::Open => {
+                info.state = ConnectionState::Closing;
+                Ok(())
             }
-            None => Err("Connection not found"),
+            _ => Err(ProtocolError::InvalidState("close")),
+        })
+        .unwrap_or(Err(ProtocolError::ConnectionNotFound))
+    }
+    
+    /// Forgets `conn_id` in any state, freeing its slot under the limits.
+    pub fn remove_connection(&self, conn_id: u32) -> Option<ConnectionState> {
+        let info = self.shard(conn_id).remove(&conn_id)?;
+        release(&self.connection_count);
+        if info.state == ConnectionState::Init {
+            release(&self.inflight_opens);
         }
+        Some(info.state)
     }
     
-    pub fn add_buffered_bytes(&mut self, conn_id: u32, bytes: usize) -> Result<(), &'static str> {
-        if let Some(info) = self.connections.get_mut(&conn_id) {
-            if info.buffered_bytes + bytes > self.limits.max_buffered_bytes {
-                self.metrics.buffer_limit_breached += 1;
-                return Err("Buffer limit exceeded");
+    pub fn add_buffered_bytes(&self, conn_id: u32, bytes: usize) -> Result<(), ProtocolError> {
+        let max_buffered_bytes = self.limits.max_buffered_bytes;
+        self.with_connection(conn_id, |info| {
+            if info.buffered_bytes + bytes > max_buffered_bytes {
+                self.metrics.buffer_limit_breached.fetch_add(1, Ordering::Relaxed);
+                return Err(ProtocolError::BufferLimit);
             }
             info.buffered_bytes += bytes;
             Ok(())
-        } else {
-            Err("Connection not found")
-        }
+        })
+        .unwrap_or(Err(ProtocolError::ConnectionNotFound))
     }
     
-    pub fn remove_buffered_bytes(&mut self, conn_id: u32, bytes: usize) {
-        if let Some(info) = self.connections.get_mut(&conn_id) {
-            info.buffered_bytes = info.buffered_bytes.saturating_sub(bytes);
-        }
+    pub fn remove_buffered_bytes(&self, conn_id: u32, bytes: usize) {
+        self.with_connection(conn_id, |info| info.buffered_bytes = info.buffered_bytes.saturating_sub(bytes));
     }
     
     pub fn get_state(&self, conn_id: u32) -> Option<ConnectionState> {
-        self.connections.get(&conn_id).map(|info| info.state)
+        self.with_connection(conn_id, |info| info.state)
     }
     
     pub fn active_count(&self) -> usize {
-        self.connections.len()
+        self.connection_count.load(Ordering::Acquire)
     }
     
     pub fn inflight_opens(&self) -> usize {
-        self.inflight_opens
+        self.inflight_opens.load(Ordering::Acquire)
     }
     
-    pub fn metrics(&self) -> &RelayMetrics {
-        &self.metrics
+    /// Counts, for `ProtocolEngine`, what an `OutboundOverflow` policy did.
+    pub fn record_outbound_blocked(&self) {
+        self.metrics.outbound_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_outbound_dropped(&self, frames: u64) {
+        self.metrics.outbound_frames_dropped.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn record_outbound_reset(&self) {
+        self.metrics.outbound_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> RelayMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        RelayMetrics {
+            connections_rejected: load(&self.metrics.connections_rejected),
+            opens_rejected: load(&self.metrics.opens_rejected),
+            buffer_limit_breached: load(&self.metrics.buffer_limit_breached),
+            outbound_blocked: load(&self.metrics.outbound_blocked),
+            outbound_frames_dropped: load(&self.metrics.outbound_frames_dropped),
+            outbound_resets: load(&self.metrics.outbound_resets),
+        }
+    }
+
+    /// Control state of every connection, ordered by `conn_id`.
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let mut snapshot = Vec::with_capacity(self.active_count());
+        for shard in self.shards.iter() {
+            snapshot.extend(lock(shard).iter().map(|(&conn_id, info)| ConnectionSnapshot {
+                conn_id,
+                state: info.state,
+                send_window: info.send_window,
+            }));
+        }
+        snapshot.sort_by_key(|connection| connection.conn_id);
+        snapshot
     }
 }
 
@@ -302,7 +536,7 @@ impl DataFrame {
         self.payload.clone()
     }
     
-    pub fn decode(payload: &[u8]) -> Result<Self, std::io::Error> {
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
         Ok(DataFrame {
             payload: payload.to_vec(),
         })
@@ -311,6 +545,133 @@ impl DataFrame {
 
 const _: [(); 1] = [(); (std::mem::size_of::<DataFrame>() == std::mem::size_of::<Vec<u8>>()) as usize];
 
+/// One UDP datagram and the far end it goes to or came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub host: String,
+    pub port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// `Datagram` frame payload. The first datagram of a `conn_id` starts a
+/// UDP association at the exit, and `Close` ends it; there is no `Open`
+/// and no flow control, so either side drops what it cannot keep up with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatagramFrame {
+    pub conn_id: u32,
+    pub datagram: Datagram,
+}
+
+impl DatagramFrame {
+    pub fn new(conn_id: u32, datagram: Datagram) -> Self {
+        Self { conn_id, datagram }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let host = self.datagram.host.as_bytes();
+        let mut buf = Vec::with_capacity(7 + host.len() + self.datagram.payload.len());
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.push(host.len() as u8);
+        buf.extend_from_slice(host);
+        buf.extend_from_slice(&self.datagram.port.to_be_bytes());
+        buf.extend_from_slice(&self.datagram.payload);
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        if payload.len() < 5 {
+            return Err(ProtocolError::Malformed("Datagram payload too short"));
+        }
+        let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        let host_len = payload[4] as usize;
+        let payload = &payload[5..];
+        if payload.len() < host_len + 2 {
+            return Err(ProtocolError::Malformed("Datagram payload too short for host and port"));
+        }
+        let host = String::from_utf8(payload[..host_len].to_vec())
+            .map_err(|_| ProtocolError::Malformed("Invalid UTF-8 in host"))?;
+        let port = u16::from_be_bytes([payload[host_len], payload[host_len + 1]]);
+        Ok(DatagramFrame {
+            conn_id,
+            datagram: Datagram {
+                host,
+                port,
+                payload: payload[host_len + 2..].to_vec(),
+            },
+        })
+    }
+}
+
+/// `Error` codes a relay answers an `Open` with. Code 0x03 is taken by
+/// flow-control violations, which are not tied to the Open.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum OpenReject {
+    #[error("relay connection limits reached")]
+    Refused = 0x01,
+    #[error("relay could not connect to the target")]
+    DialFailed = 0x02,
+    #[error("exit could not resolve the target")]
+    ResolveFailed = 0x04,
+    #[error("exit policy does not allow the target port")]
+    PortNotAllowed = 0x05,
+    #[error("exit policy denies the target address")]
+    AddressDenied = 0x06,
+    #[error("exit bandwidth cap reached")]
+    BandwidthExceeded = 0x07,
+    #[error("session is opening connections too fast")]
+    RateLimited = 0x08,
+    #[error("exit is limiting connections to this destination")]
+    DestinationRateLimited = 0x09,
+}
+
+impl OpenReject {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        [
+            Self::Refused,
+            Self::DialFailed,
+            Self::ResolveFailed,
+            Self::PortNotAllowed,
+            Self::AddressDenied,
+            Self::BandwidthExceeded,
+            Self::RateLimited,
+            Self::DestinationRateLimited,
+        ]
+        .into_iter()
+        .find(|reject| reject.code() == code)
+    }
+}
+
+/// Why a relay is ending a whole session, sent in `GoAway` before it closes.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum GoAwayCode {
+    #[error("relay is shutting down")]
+    Shutdown = 0x00,
+    #[error("session used up its byte quota")]
+    QuotaExceeded = 0x01,
+    /// The relay will be back; sessions holding a `Resume` token should
+    /// reconnect and present it.
+    #[error("relay is restarting")]
+    Restarting = 0x02,
+}
+
+impl GoAwayCode {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        [Self::Shutdown, Self::QuotaExceeded, Self::Restarting]
+            .into_iter()
+            .find(|go_away| go_away.code() == code)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[deprecated(note = "Phase 9 forbids stable relay-local identifiers; legacy control frames carry conn_id.")]
 pub enum LegacyControlMessage {
@@ -320,6 +681,13 @@ pub enum LegacyControlMessage {
     Close { conn_id: u32, reason: u8 },
     WindowUpdate { conn_id: u32, credits: u32 },
     Error { conn_id: u32, code: u8 },
+    /// Session-wide; see `GoAwayCode`.
+    GoAway { code: u8 },
+    /// Sent once each way after Hello when both sides set `CAP_RESUME`.
+    /// The client presents the token of a session a relay restart cut
+    /// (zeros if none); the relay answers with this session's token and
+    /// how many connections the presented session had open.
+    Resume { token: [u8; 16], interrupted: u16 },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -341,12 +709,9 @@ impl LegacyDataFrame {
         buf
     }
     
-    pub fn decode(payload: &[u8]) -> Result<Self, std::io::Error> {
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
         if payload.len() < 4 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Data payload too short",
-            ));
+            return Err(ProtocolError::Malformed("Data payload too short"));
         }
         
         let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
@@ -392,17 +757,23 @@ impl LegacyControlMessage {
                 buf.extend_from_slice(&conn_id.to_be_bytes());
                 buf.push(*code);
             }
+            LegacyControlMessage::GoAway { code } => {
+                buf.push(ControlOpcode::GoAway as u8);
+                buf.push(*code);
+            }
+            LegacyControlMessage::Resume { token, interrupted } => {
+                buf.push(ControlOpcode::Resume as u8);
+                buf.extend_from_slice(token);
+                buf.extend_from_slice(&interrupted.to_be_bytes());
+            }
         }
         
         buf
     }
     
-    pub fn decode(payload: &[u8]) -> Result<Self, std::io::Error> {
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
         if payload.is_empty() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Empty control payload",
-            ));
+            return Err(ProtocolError::Malformed("Empty control payload"));
         }
         
         let opcode = payload[0];
@@ -411,10 +782,7 @@ impl LegacyControlMessage {
         match opcode {
             0x00 => { // Hello
                 if payload.len() < 5 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Hello payload too short",
-                    ));
+                    return Err(ProtocolError::Malformed("Hello payload too short"));
                 }
                 let version = payload[0];
                 let capability_flags = u32::from_be_bytes([
@@ -424,36 +792,24 @@ impl LegacyControlMessage {
             }
             0x01 => { // Open
                 if payload.len() < 4 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Control payload too short",
-                    ));
+                    return Err(ProtocolError::Malformed("Control payload too short"));
                 }
                 
                 let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
                 let payload = &payload[4..];
                 
                 if payload.is_empty() {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Open payload missing host length",
-                    ));
+                    return Err(ProtocolError::Malformed("Open payload missing host length"));
                 }
                 let host_len = payload[0] as usize;
                 let payload = &payload[1..];
                 
                 if payload.len() < host_len + 2 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Open payload too short for host and port",
-                    ));
+                    return Err(ProtocolError::Malformed("Open payload too short for host and port"));
                 }
                 
                 let target_host = String::from_utf8(payload[..host_len].to_vec())
-                    .map_err(|_| std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Invalid UTF-8 in host",
-                    ))?;
+                    .map_err(|_| ProtocolError::Malformed("Invalid UTF-8 in host"))?;
                 
                 let port_bytes = &payload[host_len..host_len + 2];
                 let target_port = u16::from_be_bytes([port_bytes[0], port_bytes[1]]);
@@ -462,10 +818,7 @@ impl LegacyControlMessage {
             }
             0x02 => { // Close
                 if payload.len() < 5 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Close payload too short",
-                    ));
+                    return Err(ProtocolError::Malformed("Close payload too short"));
                 }
                 let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
                 let reason = payload[4];
@@ -473,10 +826,7 @@ impl LegacyControlMessage {
             }
             0x03 => { // WindowUpdate
                 if payload.len() < 8 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "WindowUpdate payload too short",
-                    ));
+                    return Err(ProtocolError::Malformed("WindowUpdate payload too short"));
                 }
                 let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
                 let credits = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
@@ -484,19 +834,28 @@ impl LegacyControlMessage {
             }
             0x04 => { // Error
                 if payload.len() < 5 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Error payload too short",
-                    ));
+                    return Err(ProtocolError::Malformed("Error payload too short"));
                 }
                 let conn_id = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
                 let code = payload[4];
                 Ok(LegacyControlMessage::Error { conn_id, code })
             }
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid control opcode",
-            )),
+            0x05 => { // GoAway
+                if payload.is_empty() {
+                    return Err(ProtocolError::Malformed("GoAway payload too short"));
+                }
+                Ok(LegacyControlMessage::GoAway { code: payload[0] })
+            }
+            0x06 => { // Resume
+                if payload.len() < 18 {
+                    return Err(ProtocolError::Malformed("Resume payload too short"));
+                }
+                let mut token = [0u8; 16];
+                token.copy_from_slice(&payload[..16]);
+                let interrupted = u16::from_be_bytes([payload[16], payload[17]]);
+                Ok(LegacyControlMessage::Resume { token, interrupted })
+            }
+            _ => Err(ProtocolError::InvalidOpcode(opcode)),
         }
     }
 }
@@ -509,19 +868,45 @@ impl FrameEncoder {
         version: ProtocolVersion,
         frame_type: FrameType,
         payload: &[u8],
-    ) -> IoResult<()> {
+    ) -> Result<(), ProtocolError> {
+        Self::write_frame(writer, version, frame_type as u8, payload, false)
+    }
+
+    /// As `encode_frame`, with `CHECKSUM_FLAG` set and the CRC32C after the
+    /// payload. Only for sessions that negotiated `CAP_CHECKSUM`.
+    pub fn encode_checksummed_frame<W: Write>(
+        writer: &mut W,
+        version: ProtocolVersion,
+        frame_type: FrameType,
+        payload: &[u8],
+    ) -> Result<(), ProtocolError> {
+        Self::write_frame(writer, version, frame_type as u8 | CHECKSUM_FLAG, payload, true)
+    }
+
+    fn write_frame<W: Write>(
+        writer: &mut W,
+        version: ProtocolVersion,
+        type_byte: u8,
+        payload: &[u8],
+        checksum: bool,
+    ) -> Result<(), ProtocolError> {
+        let span = ObsSpan::frame("encode");
+        span.record("bytes", &payload.len());
+        let _entered = span.entered();
         let payload_len = payload.len() as u32;
         if payload_len > MAX_FRAME_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Frame exceeds maximum size",
-            ));
+            return Err(ProtocolError::FrameTooLarge);
         }
         
-        writer.write_all(&payload_len.to_be_bytes())?;
+        let len_buf = payload_len.to_be_bytes();
+        writer.write_all(&len_buf)?;
         writer.write_all(&[version])?;
-        writer.write_all(&[frame_type as u8])?;
+        writer.write_all(&[type_byte])?;
         writer.write_all(payload)?;
+        if checksum {
+            let crc = crc32c(&[&len_buf, &[version, type_byte], payload]);
+            writer.write_all(&crc.to_be_bytes())?;
+        }
         Ok(())
     }
 }
@@ -529,18 +914,36 @@ impl FrameEncoder {
 pub struct FrameDecoder;
 
 impl FrameDecoder {
+    /// The next frame. One with `CHECKSUM_FLAG` is checked against its
+    /// CRC32C before anything else in it is trusted; a mismatch is counted
+    /// in `frames_corrupt`.
     pub fn decode_frame<R: Read>(
         reader: &mut R,
-    ) -> IoResult<(ProtocolVersion, FrameType, Vec<u8>)> {
+    ) -> Result<(ProtocolVersion, FrameType, Vec<u8>), ProtocolError> {
+        Self::read_frame(reader, false)
+    }
+
+    /// As `decode_frame`, for sessions that negotiated `CAP_CHECKSUM`: a
+    /// frame without `CHECKSUM_FLAG` is as corrupt as one that fails it.
+    pub fn decode_checksummed_frame<R: Read>(
+        reader: &mut R,
+    ) -> Result<(ProtocolVersion, FrameType, Vec<u8>), ProtocolError> {
+        Self::read_frame(reader, true)
+    }
+
+    fn read_frame<R: Read>(
+        reader: &mut R,
+        require_checksum: bool,
+    ) -> Result<(ProtocolVersion, FrameType, Vec<u8>), ProtocolError> {
+        let span = ObsSpan::frame("decode");
+        let _entered = span.clone().entered();
         let mut len_buf = [0u8; 4];
         reader.read_exact(&mut len_buf)?;
         let payload_len = u32::from_be_bytes(len_buf);
+        span.record("bytes", &payload_len);
         
         if payload_len > MAX_FRAME_SIZE {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Frame exceeds maximum size",
-            ));
+            return Err(ProtocolError::FrameTooLarge);
         }
         
         let mut version_buf = [0u8; 1];
@@ -549,18 +952,148 @@ impl FrameDecoder {
         
         let mut frame_type_buf = [0u8; 1];
         reader.read_exact(&mut frame_type_buf)?;
-        let frame_type = match frame_type_buf[0] {
-            0x01 => FrameType::Control,
-            0x02 => FrameType::Data,
-            _ => return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid frame type",
-            )),
-        };
         
         let mut payload = vec![0u8; payload_len as usize];
         reader.read_exact(&mut payload)?;
+
+        if frame_type_buf[0] & CHECKSUM_FLAG != 0 {
+            let mut crc_buf = [0u8; CHECKSUM_LEN];
+            reader.read_exact(&mut crc_buf)?;
+            if u32::from_be_bytes(crc_buf) != crc32c(&[&len_buf, &version_buf, &frame_type_buf, &payload]) {
+                observability::record_frame_corrupt();
+                return Err(ProtocolError::ChecksumMismatch);
+            }
+        } else if require_checksum {
+            observability::record_frame_corrupt();
+            return Err(ProtocolError::ChecksumMismatch);
+        }
+
+        let frame_type = match frame_type_buf[0] & !CHECKSUM_FLAG {
+            0x01 => FrameType::Control,
+            0x02 => FrameType::Data,
+            0x03 => FrameType::Padding,
+            0x04 => FrameType::Ping,
+            0x05 => FrameType::Pong,
+            0x06 => FrameType::Onion,
+            0x07 => FrameType::Circuit,
+            0x08 => FrameType::Datagram,
+            0x09 => FrameType::Dns,
+            other => return Err(ProtocolError::InvalidFrameType(other)),
+        };
         
         Ok((version, frame_type, payload))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    fn limits(max_connections: usize) -> RelayLimits {
+        RelayLimits {
+            max_connections,
+            max_inflight_opens: max_connections,
+            max_buffered_bytes: 256 * 1024,
+            initial_window: 64 * 1024,
+            max_outbound_bytes: 256 * 1024,
+            outbound_overflow: OutboundOverflow::BlockProducer,
+        }
+    }
+
+    #[test]
+    fn concurrent_opens_hold_to_the_limits() {
+        let table = Arc::new(ConnectionTable::new(limits(40)));
+        let opened: usize = (0..8u32)
+            .map(|thread| {
+                let table = Arc::clone(&table);
+                thread::spawn(move || (0..10).filter(|i| table.open_connection(thread * 100 + i).is_ok()).count())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+        assert_eq!((opened, table.active_count(), table.inflight_opens()), (40, 40, 40));
+        assert_eq!(table.metrics().connections_rejected, 40);
+
+        for connection in table.snapshot() {
+            table.finalize_open(connection.conn_id).unwrap();
+            assert_eq!(table.remove_connection(connection.conn_id), Some(ConnectionState::Open));
+        }
+        assert_eq!((table.active_count(), table.inflight_opens()), (0, 0));
+        assert!(table.open_connection(7).is_ok());
+        assert!(matches!(table.open_connection(7), Err(ProtocolError::ConnectionExists)));
+        assert_eq!(table.active_count(), 1);
+    }
+
+    #[test]
+    fn checksummed_frames_reject_corruption_anywhere() {
+        assert_eq!(crc32c(&[b"1234", b"56789"]), 0xE306_9283);
+
+        let mut frame = Vec::new();
+        FrameEncoder::encode_checksummed_frame(&mut frame, 1, FrameType::Data, b"payload").unwrap();
+        assert_eq!(frame.len(), 6 + 7 + CHECKSUM_LEN);
+        let decoded = FrameDecoder::decode_frame(&mut std::io::Cursor::new(&frame)).unwrap();
+        assert_eq!(decoded, (1, FrameType::Data, b"payload".to_vec()));
+
+        let corrupt_before = observability::load(observability::Counter::FramesCorrupt);
+        for byte in [4, 5, 8, frame.len() - 1] {
+            let mut corrupted = frame.clone();
+            corrupted[byte] ^= 0x01;
+            let decoded = FrameDecoder::decode_frame(&mut std::io::Cursor::new(&corrupted));
+            assert!(matches!(decoded, Err(ProtocolError::ChecksumMismatch)), "byte {byte} gave {decoded:?}");
+        }
+        let mut unflagged = Vec::new();
+        FrameEncoder::encode_frame(&mut unflagged, 1, FrameType::Data, b"payload").unwrap();
+        assert!(FrameDecoder::decode_frame(&mut std::io::Cursor::new(&unflagged)).is_ok());
+        let decoded = FrameDecoder::decode_checksummed_frame(&mut std::io::Cursor::new(&unflagged));
+        assert!(matches!(decoded, Err(ProtocolError::ChecksumMismatch)), "unflagged frame gave {decoded:?}");
+        assert!(FrameDecoder::decode_checksummed_frame(&mut std::io::Cursor::new(&frame)).is_ok());
+        assert!(observability::load(observability::Counter::FramesCorrupt) >= corrupt_before + 5);
+        assert_eq!(ProtocolError::ChecksumMismatch.class(), ErrorClass::TRANSPORT_IO);
+    }
+
+    /// One thread per connection spending and granting credits, against the
+    /// same work with the whole table behind one lock.
+    #[test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    fn bench_flow_control_under_contention() {
+        const THREADS: u32 = 8;
+        const OPS: usize = 200_000;
+        fn exercise(table: &ConnectionTable, conn_id: u32) {
+            if table.can_send_data(conn_id, 1024) {
+                table.consume_send_credits(conn_id, 1024).unwrap();
+            } else {
+                table.add_send_credits(conn_id, 64 * 1024).unwrap();
+            }
+        }
+        fn run(work: impl Fn(u32) + Send + Sync + 'static) -> f64 {
+            let work = Arc::new(work);
+            let start = Instant::now();
+            let threads: Vec<_> = (0..THREADS)
+                .map(|conn_id| {
+                    let work = Arc::clone(&work);
+                    thread::spawn(move || (0..OPS).for_each(|_| work(conn_id)))
+                })
+                .collect();
+            threads.into_iter().for_each(|thread| thread.join().unwrap());
+            (THREADS as usize * OPS) as f64 / start.elapsed().as_secs_f64()
+        }
+        let table = || {
+            let table = ConnectionTable::new(limits(THREADS as usize));
+            for conn_id in 0..THREADS {
+                table.open_connection(conn_id).unwrap();
+                table.finalize_open(conn_id).unwrap();
+            }
+            table
+        };
+
+        let single = Mutex::new(table());
+        let single = run(move |conn_id| exercise(&single.lock().unwrap(), conn_id));
+        let sharded = table();
+        let sharded = run(move |conn_id| exercise(&sharded, conn_id));
+        println!("{THREADS} threads: one lock {single:.0} ops/s, sharded {sharded:.0} ops/s");
+    }
+}
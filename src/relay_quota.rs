@@ -0,0 +1,132 @@
+//! Per-session quotas on a relay, so one client cannot take a shared relay
+//! for itself. Concurrent connections are capped by `RelayLimits`; these
+//! add a byte budget per rolling hour and a rate of new connections.
+//!
+//! Both are token buckets that start full: a session may spend its whole
+//! hourly budget at once, then earns it back evenly over the hour. An Open
+//! over the rate is refused with `OpenReject::RateLimited`; a session out
+//! of bytes gets `GoAway` with `GoAwayCode::QuotaExceeded` and is closed.
+
+use std::time::{Duration, Instant};
+
+use crate::relay_protocol::{GoAwayCode, OpenReject};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// `None` leaves that quota off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionQuotas {
+    /// Bytes relayed per hour, both directions together.
+    pub bytes_per_hour: Option<u64>,
+    /// New connections per minute.
+    pub opens_per_minute: Option<u32>,
+}
+
+impl SessionQuotas {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+    level: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u64, period: Duration, now: Instant) -> Self {
+        let capacity = capacity as f64;
+        Self {
+            capacity,
+            per_sec: capacity / period.as_secs_f64(),
+            level: capacity,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes `amount` if the bucket holds it.
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        let earned = now.saturating_duration_since(self.refilled_at).as_secs_f64() * self.per_sec;
+        self.level = (self.level + earned).min(self.capacity);
+        self.refilled_at = now;
+        if self.level < amount {
+            return false;
+        }
+        self.level -= amount;
+        true
+    }
+}
+
+/// One session's usage against its `SessionQuotas`.
+#[derive(Debug)]
+pub struct SessionUsage {
+    bytes: Option<Bucket>,
+    opens: Option<Bucket>,
+}
+
+impl SessionUsage {
+    pub fn new(quotas: SessionQuotas, now: Instant) -> Self {
+        Self {
+            bytes: quotas.bytes_per_hour.map(|bytes| Bucket::new(bytes, HOUR, now)),
+            opens: quotas.opens_per_minute.map(|opens| Bucket::new(opens.into(), MINUTE, now)),
+        }
+    }
+
+    pub fn admit_open(&mut self, now: Instant) -> Result<(), OpenReject> {
+        if self.opens.as_mut().is_some_and(|opens| !opens.take(1.0, now)) {
+            return Err(OpenReject::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Counts relayed bytes; once the budget is gone the session must end.
+    pub fn charge_bytes(&mut self, bytes: usize, now: Instant) -> Result<(), GoAwayCode> {
+        if self.bytes.as_mut().is_some_and(|budget| !budget.take(bytes as f64, now)) {
+            return Err(GoAwayCode::QuotaExceeded);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_rate_refills_over_the_minute() {
+        let now = Instant::now();
+        let mut usage = SessionUsage::new(
+            SessionQuotas {
+                opens_per_minute: Some(2),
+                ..SessionQuotas::unlimited()
+            },
+            now,
+        );
+        assert!(usage.admit_open(now).is_ok());
+        assert!(usage.admit_open(now).is_ok());
+        assert_eq!(usage.admit_open(now), Err(OpenReject::RateLimited));
+        assert!(usage.admit_open(now + Duration::from_secs(30)).is_ok());
+        assert!(usage.charge_bytes(usize::MAX, now).is_ok());
+    }
+
+    #[test]
+    fn byte_budget_is_spent_and_earned_back() {
+        let now = Instant::now();
+        let mut usage = SessionUsage::new(
+            SessionQuotas {
+                bytes_per_hour: Some(3_600_000),
+                ..SessionQuotas::unlimited()
+            },
+            now,
+        );
+        assert!(usage.charge_bytes(3_000_000, now).is_ok());
+        assert_eq!(usage.charge_bytes(700_000, now), Err(GoAwayCode::QuotaExceeded));
+        // 1000 bytes a second come back.
+        assert!(usage.charge_bytes(700_000, now + Duration::from_secs(100)).is_ok());
+        assert!(usage.admit_open(now).is_ok());
+    }
+}
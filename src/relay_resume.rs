@@ -0,0 +1,284 @@
+//! Session resumption across relay restarts. Each session that sets
+//! `CAP_RESUME` gets a random token in `Resume`, and its `ConnectionTable`
+//! control state (connection ids, states and windows, never targets or
+//! payloads) is kept here under that token.
+//!
+//! With `ebt relay serve --state-file`, the live sessions are written out
+//! periodically and once more on shutdown. A restarted relay loads the
+//! file: a client presenting one of those tokens within `RESUME_GRACE` of
+//! the last write is told how many connections the restart cut, so it can
+//! tell a restart from a refusal and reopen them. A planned restart also
+//! sends every live session `GoAway` with `GoAwayCode::Restarting` first,
+//! instead of letting its tunnels drop without a word.
+//!
+//! A token links a client's sessions on either side of a restart, so each
+//! is claimable once and the file is written owner-only.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::log;
+use crate::logging::LogLevel;
+use crate::relay_directory::{unix_now, write_private};
+use crate::relay_protocol::{ConnectionSnapshot, ConnectionState};
+
+pub type SessionToken = [u8; 16];
+
+/// Presented by clients with no cut session.
+pub const NO_TOKEN: SessionToken = [0; 16];
+/// How long after the last write a restarted relay honours its tokens.
+const RESUME_GRACE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Relay state file {path}: {reason}")]
+pub struct StateFileError {
+    path: String,
+    reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    written_at: u64,
+    sessions: Vec<SessionRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionRecord {
+    token: String,
+    connections: Vec<ConnectionRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectionRecord {
+    conn_id: u32,
+    state: String,
+    send_window: u32,
+}
+
+/// Live sessions of this run, and the cut sessions of the previous one.
+#[derive(Debug)]
+pub struct ResumeRegistry {
+    live: Mutex<HashMap<SessionToken, Vec<ConnectionSnapshot>>>,
+    /// Connections each cut session had open or opening.
+    interrupted: Mutex<HashMap<SessionToken, u16>>,
+    restarting: watch::Sender<bool>,
+}
+
+impl Default for ResumeRegistry {
+    fn default() -> Self {
+        Self {
+            live: Mutex::default(),
+            interrupted: Mutex::default(),
+            restarting: watch::channel(false).0,
+        }
+    }
+}
+
+impl ResumeRegistry {
+    /// The sessions a previous run wrote to `path`. A missing file, or one
+    /// past `RESUME_GRACE`, leaves nothing to resume.
+    pub fn load(path: &str, now_unix: u64) -> Result<Self, StateFileError> {
+        let error = |reason: String| StateFileError {
+            path: path.to_string(),
+            reason,
+        };
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(error(e.to_string())),
+        };
+        let state: StateFile = serde_json::from_slice(&bytes).map_err(|e| error(e.to_string()))?;
+        let registry = Self::default();
+        if now_unix.saturating_sub(state.written_at) > RESUME_GRACE.as_secs() {
+            return Ok(registry);
+        }
+        let mut interrupted = registry.interrupted.lock().unwrap_or_else(|e| e.into_inner());
+        for session in state.sessions {
+            let token = parse_token(&session.token).ok_or_else(|| error("malformed session token".to_string()))?;
+            let cut = session
+                .connections
+                .iter()
+                .filter(|connection| matches!(connection.state.as_str(), "init" | "open"))
+                .count();
+            interrupted.insert(token, cut.min(u16::MAX as usize) as u16);
+        }
+        drop(interrupted);
+        Ok(registry)
+    }
+
+    /// Starts tracking a session. Returns its guard and the connections
+    /// the `presented` session lost, if it was cut by a restart.
+    pub fn register(self: &Arc<Self>, presented: SessionToken) -> (LiveSession, u16) {
+        let interrupted = match presented {
+            NO_TOKEN => None,
+            token => self.interrupted.lock().unwrap_or_else(|e| e.into_inner()).remove(&token),
+        };
+        let mut token = NO_TOKEN;
+        let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        while token == NO_TOKEN || live.contains_key(&token) {
+            OsRng.fill_bytes(&mut token);
+        }
+        live.insert(token, Vec::new());
+        drop(live);
+        let session = LiveSession {
+            registry: Arc::clone(self),
+            token,
+        };
+        (session, interrupted.unwrap_or(0))
+    }
+
+    /// Resolves once `begin_restart` is called.
+    pub fn restart_signal(&self) -> watch::Receiver<bool> {
+        self.restarting.subscribe()
+    }
+
+    /// Tells every session to send `GoAway` with `GoAwayCode::Restarting`.
+    pub fn begin_restart(&self) {
+        self.restarting.send_replace(true);
+    }
+
+    /// Replaces `path` with the live sessions, via a temporary file.
+    pub fn persist(&self, path: &str, now_unix: u64) -> Result<(), StateFileError> {
+        let sessions = self
+            .live
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(token, connections)| SessionRecord {
+                token: token.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                connections: connections
+                    .iter()
+                    .map(|connection| ConnectionRecord {
+                        conn_id: connection.conn_id,
+                        state: state_name(connection.state).to_string(),
+                        send_window: connection.send_window,
+                    })
+                    .collect(),
+            })
+            .collect();
+        let state = StateFile {
+            written_at: now_unix,
+            sessions,
+        };
+        let json = serde_json::to_vec(&state).expect("relay state serializes");
+        let tmp = format!("{}.tmp", path);
+        let _ = std::fs::remove_file(&tmp);
+        write_private(&tmp, &json)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|e| StateFileError {
+                path: path.to_string(),
+                reason: e.to_string(),
+            })
+    }
+
+    /// Writes `path` every `interval` until aborted.
+    pub async fn persist_periodically(self: Arc<Self>, path: String, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.persist(&path, unix_now()) {
+                log!(LogLevel::Error, "Relay state not saved"; safe "error" => e);
+            }
+        }
+    }
+
+    fn live_count(&self) -> usize {
+        self.live.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// A registered session; dropping it stops tracking the session.
+#[derive(Debug)]
+pub struct LiveSession {
+    registry: Arc<ResumeRegistry>,
+    token: SessionToken,
+}
+
+impl LiveSession {
+    pub fn token(&self) -> SessionToken {
+        self.token
+    }
+
+    /// Records the session's current control state.
+    pub fn update(&self, connections: Vec<ConnectionSnapshot>) {
+        if let Some(entry) = self.registry.live.lock().unwrap_or_else(|e| e.into_inner()).get_mut(&self.token) {
+            *entry = connections;
+        }
+    }
+}
+
+impl Drop for LiveSession {
+    fn drop(&mut self) {
+        self.registry.live.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.token);
+    }
+}
+
+fn state_name(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Init => "init",
+        ConnectionState::Open => "open",
+        ConnectionState::Closing => "closing",
+        ConnectionState::Closed => "closed",
+    }
+}
+
+fn parse_token(hex: &str) -> Option<SessionToken> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+    let mut token = NO_TOKEN;
+    for (byte, pair) in token.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(conn_id: u32, state: ConnectionState) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            conn_id,
+            state,
+            send_window: 65536,
+        }
+    }
+
+    #[test]
+    fn cut_sessions_are_claimable_once_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("ebt-relay-state-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let before = Arc::new(ResumeRegistry::default());
+        let (busy, _) = before.register(NO_TOKEN);
+        busy.update(vec![
+            connection(1, ConnectionState::Open),
+            connection(3, ConnectionState::Init),
+            connection(5, ConnectionState::Closing),
+        ]);
+        let (idle, _) = before.register(NO_TOKEN);
+        let (ended, _) = before.register(NO_TOKEN);
+        let ended_token = ended.token();
+        drop(ended);
+        assert_eq!(before.live_count(), 2);
+        before.persist(path, 1_000).unwrap();
+
+        let after = Arc::new(ResumeRegistry::load(path, 1_060).unwrap());
+        let (resumed, interrupted) = after.register(busy.token());
+        assert_eq!(interrupted, 2);
+        assert_ne!(resumed.token(), busy.token());
+        assert_eq!(after.register(busy.token()).1, 0);
+        assert_eq!(after.register(idle.token()).1, 0);
+        assert_eq!(after.register(ended_token).1, 0);
+
+        let late = Arc::new(ResumeRegistry::load(path, 1_000 + RESUME_GRACE.as_secs() + 1).unwrap());
+        assert_eq!(late.register(busy.token()).1, 0);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(ResumeRegistry::load(path, 0).unwrap().interrupted.lock().unwrap().len(), 0);
+    }
+}
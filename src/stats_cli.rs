@@ -0,0 +1,65 @@
+//! `ebt stats [host:port]`: fetches `/debug/obs` from a running proxy and
+//! pretty-prints the snapshot. The proxy must be built with OBS_DEV; if it
+//! requires proxy authentication, set `EBT_PROXY_AUTHORIZATION` to the
+//! `Proxy-Authorization` header value.
+
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::core::observability::report;
+
+const DEFAULT_PROXY_ADDR: &str = "127.0.0.1:8080";
+const STATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn run(addr: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let body = fetch_snapshot(addr.unwrap_or(DEFAULT_PROXY_ADDR))?;
+    print!("{}", report::render(&body));
+    Ok(())
+}
+
+fn fetch_snapshot(addr: &str) -> Result<String, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(STATS_TIMEOUT))?;
+    stream.set_write_timeout(Some(STATS_TIMEOUT))?;
+
+    let mut request = format!("GET /debug/obs HTTP/1.1\r\nHost: {}\r\nX-EBT-Control: 1\r\n", addr);
+    if let Ok(credential) = std::env::var("EBT_PROXY_AUTHORIZATION") {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", credential));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    parse_response(&response)
+}
+
+fn parse_response(response: &str) -> Result<String, Box<dyn Error>> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("malformed response from proxy")?;
+    let status = head.lines().next().unwrap_or("");
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(body.to_string()),
+        Some("404") => Err("observability snapshots require an OBS_DEV build".into()),
+        Some("403") => Err("stats are only served to authorized loopback clients".into()),
+        _ => Err(format!("unexpected proxy response: {}", status).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_body_and_maps_errors() {
+        let body = parse_response("HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nframes 1").unwrap();
+        assert_eq!(body, "frames 1");
+
+        let err = parse_response("HTTP/1.1 404 Not Found\r\n\r\n").unwrap_err();
+        assert!(err.to_string().contains("OBS_DEV"));
+        assert!(parse_response("garbage").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! OS proxy settings for `proxy.mode = "system"`: with
+//! `proxy.configure_system = true`, `ebt run` points the system's HTTP and
+//! HTTPS proxy at its listener on start and puts the previous settings
+//! back on stop.
+//!
+//! Each platform is driven through its own settings tool, so no native
+//! bindings are needed: `reg` for the WinINET keys on Windows,
+//! `networksetup` for every enabled network service on macOS, and
+//! `gsettings` for GNOME elsewhere. The current values are read before
+//! anything changes and kept as the commands that write them back, so a
+//! clean stop, a failed start and a drop all undo exactly what was done.
+//!
+//! A killed proxy leaves the settings pointing at its address. The next
+//! start recognises them as its own and restores them to "no proxy"
+//! rather than to themselves.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::log;
+use crate::logging::LogLevel;
+use crate::os_command::{command, run, run_all, CommandError, Query};
+
+const INTERNET_SETTINGS: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+const GNOME_PROXY: &str = "org.gnome.system.proxy";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SystemProxyError {
+    #[error("System proxy settings are not supported on this platform")]
+    Unsupported,
+    #[error("No enabled network service to configure")]
+    NoNetworkService,
+    #[error(transparent)]
+    Command(#[from] CommandError),
+}
+
+/// System proxy settings pointed at this process. Dropping it restores
+/// them too, but `restore` reports failures.
+#[derive(Debug)]
+pub struct SystemProxy {
+    restore: Vec<Vec<String>>,
+}
+
+impl SystemProxy {
+    /// Points the system proxy at `proxy`, remembering the old settings.
+    /// An unspecified address is replaced by loopback.
+    pub fn enable(proxy: SocketAddr) -> Result<Self, SystemProxyError> {
+        let platform = Platform::current().ok_or(SystemProxyError::Unsupported)?;
+        let plan = platform.plan(reachable(proxy), &run)?;
+        // Dropped on a failed step, undoing the steps before it.
+        let enabled = Self { restore: plan.restore };
+        for command in &plan.apply {
+            run(command)?;
+        }
+        Ok(enabled)
+    }
+
+    /// Writes the previous settings back, attempting every step.
+    pub fn restore(mut self) -> Result<(), SystemProxyError> {
+        Ok(run_all(&std::mem::take(&mut self.restore))?)
+    }
+}
+
+impl Drop for SystemProxy {
+    fn drop(&mut self) {
+        if self.restore.is_empty() {
+            return;
+        }
+        if let Err(e) = run_all(&std::mem::take(&mut self.restore)) {
+            log!(LogLevel::Error, "System proxy settings not restored"; safe "error" => e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Platform {
+    WinInet,
+    NetworkSetup,
+    Gsettings,
+}
+
+/// Commands that point the system at the proxy, and those that undo them.
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    apply: Vec<Vec<String>>,
+    restore: Vec<Vec<String>>,
+}
+
+impl Platform {
+    fn current() -> Option<Self> {
+        if cfg!(windows) {
+            Some(Self::WinInet)
+        } else if cfg!(target_os = "macos") {
+            Some(Self::NetworkSetup)
+        } else if cfg!(all(unix, not(target_os = "android"))) {
+            Some(Self::Gsettings)
+        } else {
+            None
+        }
+    }
+
+    /// Reads the current settings through `query`, changing nothing.
+    fn plan(self, proxy: SocketAddr, query: Query) -> Result<Plan, SystemProxyError> {
+        match self {
+            Self::WinInet => Ok(wininet_plan(proxy, query)),
+            Self::NetworkSetup => networksetup_plan(proxy, query),
+            Self::Gsettings => gsettings_plan(proxy, query),
+        }
+    }
+}
+
+/// `ProxyServer` and `ProxyEnable` under the user's Internet Settings.
+/// Browsers that follow WinINET watch the key and pick the change up.
+fn wininet_plan(proxy: SocketAddr, query: Query) -> Plan {
+    // A missing value makes `reg query` fail; both mean "not set".
+    let value = |name: &str| {
+        let output = query(&command(&["reg", "query", INTERNET_SETTINGS, "/v", name])).ok()?;
+        reg_data(&output, name)
+    };
+    let set = |name: &str, kind: &str, data: &str| {
+        command(&["reg", "add", INTERNET_SETTINGS, "/v", name, "/t", kind, "/d", data, "/f"])
+    };
+    let server = format!("http={proxy};https={proxy}");
+    let previous = value("ProxyServer").filter(|previous| *previous != server);
+    let was_enabled = previous.is_some() && value("ProxyEnable").as_deref() == Some("0x1");
+
+    let mut plan = Plan {
+        apply: vec![set("ProxyServer", "REG_SZ", &server), set("ProxyEnable", "REG_DWORD", "1")],
+        restore: Vec::new(),
+    };
+    plan.restore.push(match &previous {
+        Some(previous) => set("ProxyServer", "REG_SZ", previous),
+        None => command(&["reg", "delete", INTERNET_SETTINGS, "/v", "ProxyServer", "/f"]),
+    });
+    plan.restore.push(set("ProxyEnable", "REG_DWORD", if was_enabled { "1" } else { "0" }));
+    plan
+}
+
+/// The data column of `name` in `reg query` output.
+fn reg_data(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut columns = line.split_whitespace();
+        (columns.next()? == name).then(|| columns.skip(1).collect::<Vec<_>>().join(" "))
+    })
+}
+
+/// The web and secure web proxy of every enabled network service.
+fn networksetup_plan(proxy: SocketAddr, query: Query) -> Result<Plan, SystemProxyError> {
+    let services = query(&command(&["networksetup", "-listallnetworkservices"]))?;
+    let (host, port) = (proxy.ip().to_string(), proxy.port().to_string());
+    let mut plan = Plan::default();
+    // The first line explains that `*` marks disabled services.
+    for service in services.lines().skip(1).filter(|line| !line.is_empty() && !line.starts_with('*')) {
+        for (get, set, state) in [
+            ("-getwebproxy", "-setwebproxy", "-setwebproxystate"),
+            ("-getsecurewebproxy", "-setsecurewebproxy", "-setsecurewebproxystate"),
+        ] {
+            let current = query(&command(&["networksetup", get, service]))?;
+            let field = |name: &str| {
+                current
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                    .map_or("", str::trim)
+            };
+            let (enabled, server, server_port) = (field("Enabled") == "Yes", field("Server"), field("Port"));
+            plan.apply.push(command(&["networksetup", set, service, &host, &port]));
+            let ours = server == host && server_port == port;
+            if !server.is_empty() && !ours {
+                plan.restore.push(command(&["networksetup", set, service, server, server_port]));
+            }
+            if !enabled || server.is_empty() || ours {
+                plan.restore.push(command(&["networksetup", state, service, "off"]));
+            }
+        }
+    }
+    if plan.apply.is_empty() {
+        return Err(SystemProxyError::NoNetworkService);
+    }
+    Ok(plan)
+}
+
+/// GNOME's manual HTTP and HTTPS proxy. Values are read and written in
+/// `gsettings`' own syntax, so they go back exactly as they were.
+fn gsettings_plan(proxy: SocketAddr, query: Query) -> Result<Plan, SystemProxyError> {
+    let get = |schema: &str, key: &str| {
+        query(&command(&["gsettings", "get", schema, key])).map(|value| value.trim().to_string())
+    };
+    let set = |schema: &str, key: &str, value: &str| command(&["gsettings", "set", schema, key, value]);
+    let (host, port) = (format!("'{}'", proxy.ip()), proxy.port().to_string());
+
+    let mode = get(GNOME_PROXY, "mode")?;
+    let mut ours = mode == "'manual'";
+    let mut plan = Plan::default();
+    let mut restore = Vec::new();
+    for schema in [format!("{GNOME_PROXY}.http"), format!("{GNOME_PROXY}.https")] {
+        let (previous_host, previous_port) = (get(&schema, "host")?, get(&schema, "port")?);
+        ours &= previous_host == host && previous_port == port;
+        plan.apply.push(set(&schema, "host", &host));
+        plan.apply.push(set(&schema, "port", &port));
+        restore.push(set(&schema, "host", &previous_host));
+        restore.push(set(&schema, "port", &previous_port));
+    }
+    plan.apply.push(set(GNOME_PROXY, "mode", "'manual'"));
+    // The mode goes first, so nothing uses a half-restored proxy.
+    plan.restore.push(set(GNOME_PROXY, "mode", if ours { "'none'" } else { &mode }));
+    plan.restore.extend(restore);
+    Ok(plan)
+}
+
+/// Where other processes on this machine reach a listener on `addr`.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const PROXY: &str = "127.0.0.1:8080";
+
+    /// A `query` answering from `outputs`, keyed by the joined command.
+    fn answers(outputs: &[(&str, &str)]) -> impl Fn(&[String]) -> Result<String, CommandError> {
+        let outputs: HashMap<String, String> =
+            outputs.iter().map(|(command, output)| (command.to_string(), output.to_string())).collect();
+        move |command: &[String]| {
+            let command = command.join(" ");
+            outputs.get(&command).cloned().ok_or(CommandError {
+                command,
+                reason: "exit status: 1".to_string(),
+            })
+        }
+    }
+
+    fn joined(commands: &[Vec<String>]) -> Vec<String> {
+        commands.iter().map(|command| command.join(" ")).collect()
+    }
+
+    #[test]
+    fn wininet_restores_the_previous_server_or_removes_ours() {
+        let key = INTERNET_SETTINGS;
+        let query_server = format!("reg query {key} /v ProxyServer");
+        let query_enable = format!("reg query {key} /v ProxyEnable");
+        let server_output = format!("\r\n{key}\r\n    ProxyServer    REG_SZ    corp-proxy:3128\r\n\r\n");
+        let enable_output = format!("\r\n{key}\r\n    ProxyEnable    REG_DWORD    0x1\r\n\r\n");
+        let query = answers(&[(&query_server, &server_output), (&query_enable, &enable_output)]);
+        let plan = Platform::WinInet.plan(PROXY.parse().unwrap(), &query).unwrap();
+        assert_eq!(
+            joined(&plan.apply),
+            [
+                format!("reg add {key} /v ProxyServer /t REG_SZ /d http={PROXY};https={PROXY} /f"),
+                format!("reg add {key} /v ProxyEnable /t REG_DWORD /d 1 /f"),
+            ]
+        );
+        assert_eq!(
+            joined(&plan.restore),
+            [
+                format!("reg add {key} /v ProxyServer /t REG_SZ /d corp-proxy:3128 /f"),
+                format!("reg add {key} /v ProxyEnable /t REG_DWORD /d 1 /f"),
+            ]
+        );
+
+        // Left behind by a killed run, or never set: no proxy afterwards.
+        let leftover = format!("    ProxyServer    REG_SZ    http={PROXY};https={PROXY}\r\n");
+        let query = answers(&[(&query_server, &leftover), (&query_enable, &enable_output)]);
+        let expected = [
+            format!("reg delete {key} /v ProxyServer /f"),
+            format!("reg add {key} /v ProxyEnable /t REG_DWORD /d 0 /f"),
+        ];
+        assert_eq!(joined(&Platform::WinInet.plan(PROXY.parse().unwrap(), &query).unwrap().restore), expected);
+        assert_eq!(joined(&Platform::WinInet.plan(PROXY.parse().unwrap(), &answers(&[])).unwrap().restore), expected);
+    }
+
+    #[test]
+    fn networksetup_covers_enabled_services_only() {
+        let query = answers(&[
+            (
+                "networksetup -listallnetworkservices",
+                "An asterisk (*) denotes that a network service is disabled.\nWi-Fi\n*Thunderbolt Bridge\n",
+            ),
+            (
+                "networksetup -getwebproxy Wi-Fi",
+                "Enabled: Yes\nServer: corp-proxy\nPort: 3128\nAuthenticated Proxy Enabled: 0\n",
+            ),
+            (
+                "networksetup -getsecurewebproxy Wi-Fi",
+                "Enabled: No\nServer: \nPort: 0\nAuthenticated Proxy Enabled: 0\n",
+            ),
+        ]);
+        let plan = Platform::NetworkSetup.plan("0.0.0.0:8080".parse().map(reachable).unwrap(), &query).unwrap();
+        assert_eq!(
+            joined(&plan.apply),
+            [
+                "networksetup -setwebproxy Wi-Fi 127.0.0.1 8080",
+                "networksetup -setsecurewebproxy Wi-Fi 127.0.0.1 8080",
+            ]
+        );
+        assert_eq!(
+            joined(&plan.restore),
+            [
+                "networksetup -setwebproxy Wi-Fi corp-proxy 3128",
+                "networksetup -setsecurewebproxystate Wi-Fi off",
+            ]
+        );
+
+        let none = answers(&[("networksetup -listallnetworkservices", "An asterisk (*) denotes...\n*Wi-Fi\n")]);
+        assert!(matches!(
+            Platform::NetworkSetup.plan(PROXY.parse().unwrap(), &none),
+            Err(SystemProxyError::NoNetworkService)
+        ));
+    }
+
+    #[test]
+    fn gsettings_restores_the_mode_first_and_values_verbatim() {
+        let settings = |mode: &str, host: &str, port: &str| {
+            let mut outputs = vec![(format!("gsettings get {GNOME_PROXY} mode"), format!("{mode}\n"))];
+            for schema in ["http", "https"] {
+                outputs.push((format!("gsettings get {GNOME_PROXY}.{schema} host"), format!("{host}\n")));
+                outputs.push((format!("gsettings get {GNOME_PROXY}.{schema} port"), format!("{port}\n")));
+            }
+            outputs
+        };
+        let plan_for = |outputs: Vec<(String, String)>| {
+            let outputs: Vec<(&str, &str)> = outputs.iter().map(|(c, o)| (c.as_str(), o.as_str())).collect();
+            Platform::Gsettings.plan(PROXY.parse().unwrap(), &answers(&outputs)).unwrap()
+        };
+
+        let plan = plan_for(settings("'auto'", "''", "0"));
+        assert_eq!(
+            joined(&plan.apply),
+            [
+                format!("gsettings set {GNOME_PROXY}.http host '127.0.0.1'"),
+                format!("gsettings set {GNOME_PROXY}.http port 8080"),
+                format!("gsettings set {GNOME_PROXY}.https host '127.0.0.1'"),
+                format!("gsettings set {GNOME_PROXY}.https port 8080"),
+                format!("gsettings set {GNOME_PROXY} mode 'manual'"),
+            ]
+        );
+        assert_eq!(
+            joined(&plan.restore),
+            [
+                format!("gsettings set {GNOME_PROXY} mode 'auto'"),
+                format!("gsettings set {GNOME_PROXY}.http host ''"),
+                format!("gsettings set {GNOME_PROXY}.http port 0"),
+                format!("gsettings set {GNOME_PROXY}.https host ''"),
+                format!("gsettings set {GNOME_PROXY}.https port 0"),
+            ]
+        );
+
+        let leftover = plan_for(settings("'manual'", "'127.0.0.1'", "8080"));
+        assert_eq!(joined(&leftover.restore[..1]), [format!("gsettings set {GNOME_PROXY} mode 'none'")]);
+        let other = plan_for(settings("'manual'", "'127.0.0.1'", "3128"));
+        assert_eq!(joined(&other.restore[..1]), [format!("gsettings set {GNOME_PROXY} mode 'manual'")]);
+    }
+}
@@ -1,4 +1,7 @@
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum InvariantId {
     DnsResolutionAtExitOnly,
     NoSourceDestinationCorrelation,
@@ -37,6 +40,19 @@ pub enum InvariantViolation {
     },
 }
 
+impl InvariantViolation {
+    pub fn id(&self) -> InvariantId {
+        match self {
+            InvariantViolation::DnsResolutionAtExitOnly { .. } => InvariantId::DnsResolutionAtExitOnly,
+            InvariantViolation::NoSourceDestinationCorrelation { .. } => InvariantId::NoSourceDestinationCorrelation,
+            InvariantViolation::IspTrafficEncrypted { .. } => InvariantId::IspTrafficEncrypted,
+            InvariantViolation::EntryNodeBlindToDestination { .. } => InvariantId::EntryNodeBlindToDestination,
+            InvariantViolation::ExitNodeBlindToSource { .. } => InvariantId::ExitNodeBlindToSource,
+            InvariantViolation::LoggingOptIn { .. } => InvariantId::LoggingOptIn,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ThreatInvariant {
     pub id: InvariantId,
@@ -138,6 +154,27 @@ impl ThreatInvariants {
             }
         }
 
+        // Check entry and exit blindness
+        if self.is_enabled(&InvariantId::EntryNodeBlindToDestination)
+            && context.has_destination_hostname
+            && context.component_name.starts_with("entry")
+        {
+            violations.push(InvariantViolation::EntryNodeBlindToDestination {
+                entry_node: context.component_name.clone(),
+                destination_exposed: "detected".to_string(),
+            });
+        }
+
+        if self.is_enabled(&InvariantId::ExitNodeBlindToSource)
+            && context.has_source_ip
+            && context.component_name.starts_with("exit")
+        {
+            violations.push(InvariantViolation::ExitNodeBlindToSource {
+                exit_node: context.component_name.clone(),
+                source_ip_exposed: "detected".to_string(),
+            });
+        }
+
         // Check logging opt-in
         if self.is_enabled(&InvariantId::LoggingOptIn) {
             if context.logging_enabled && context.component_name != "explicit_logging" {
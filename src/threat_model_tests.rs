@@ -1,224 +1,409 @@
+//! Runtime harness for `ThreatInvariants`. Fake zones stand where the
+//! entry, relay, exit and ISP-facing link would and record what real code
+//! hands them: sealed DNS requests, onion cells, relay TLS, log lines. What
+//! each zone saw becomes an `InvariantContext`, so every invariant is
+//! checked against behaviour, once where the code keeps it and once where
+//! a broken zone or build breaks it and the violation is caught.
+
 #[cfg(test)]
 mod threat_model_tests {
+    #![allow(deprecated)]
+
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::config::RelayEndpoint;
+    use crate::core::observability::OBS_DEV;
+    use crate::crypto_transport_design::PayloadMessage;
+    use crate::exit_dns::{encode_message, ExitDnsError, ExitDnsExchange, ExitDnsResolver, ExitDnsService};
+    use crate::logging::{render, FieldClass, LogField, LogFormat, LogLevel};
+    use crate::onion::{LayerKey, NextHop, TEST_LAYER_KEY};
+    use crate::relay_client::{start_tls, RelayIo};
+    use crate::relay_protocol::LegacyControlMessage;
+    use crate::relay_server::{certificate_fingerprint, load_tls};
     use crate::threat_invariants::*;
-    use crate::trust_boundaries::*;
-    use crate::prohibited_capabilities::*;
-
-    // Mock components for testing
-    struct MockEntryNode {
-        has_source_ip: bool,
-    }
-
-    struct MockRelayNode {
-        hop_position: u32,
-    }
-
-    struct MockExitNode {
-        has_destination: bool,
-    }
-
-    struct MockDnsResolver {
-        zone: TrustZone,
-    }
-
-    struct MockLogger {
-        enabled_by_default: bool,
-    }
-
-    // DNS Resolution At Exit Only Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_dns_resolution_outside_exit_is_impossible() {
-        // Test that DNS resolution fails at compile time in non-exit zones
-        todo!("Verify DNS resolution only works in Exit zone")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_entry_node_dns_resolution_blocked() {
-        // Test that entry nodes cannot perform DNS resolution
-        todo!("Verify entry node cannot resolve hostnames")
-    }
 
+    /// The client's address and the destination every test routes. A zone
+    /// holds one once it appears in anything the zone saw.
+    const SOURCE_IP: &str = "198.51.100.23";
+    const DESTINATION: &str = "secret-destination.example";
+    /// Address of the middle relay, as the exit sees its peer.
+    const MIDDLE_IP: &str = "203.0.113.5";
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-key.pem");
+    /// Key the exit's DNS service and the client share.
+    const DNS_KEY: [u8; 32] = [0x5a; 32];
+
+    /// One fake zone. `component` follows `ThreatInvariants::check_context`:
+    /// `entry_*`, `relay_*`, `exit_*`, `isp_facing_*` or `explicit_logging`.
+    #[derive(Clone)]
+    struct Zone {
+        component: &'static str,
+        seen: Vec<u8>,
+        dns_attempted: bool,
+        logging_enabled: bool,
+    }
+
+    impl Zone {
+        fn new(component: &'static str) -> Self {
+            Self {
+                component,
+                seen: Vec::new(),
+                dns_attempted: false,
+                logging_enabled: false,
+            }
+        }
+
+        fn shared(component: &'static str) -> Arc<Mutex<Self>> {
+            Arc::new(Mutex::new(Self::new(component)))
+        }
+
+        fn observe(&mut self, bytes: &[u8]) {
+            self.seen.extend_from_slice(bytes);
+        }
+
+        fn holds(&self, needle: &str) -> bool {
+            self.seen.windows(needle.len()).any(|window| window == needle.as_bytes())
+        }
+
+        fn context(&self) -> InvariantContext {
+            InvariantContext {
+                component_name: self.component.to_string(),
+                has_source_ip: self.holds(SOURCE_IP),
+                has_destination_hostname: self.holds(DESTINATION),
+                traffic_encrypted: !self.holds(DESTINATION),
+                dns_resolution_attempted: self.dns_attempted,
+                logging_enabled: self.logging_enabled,
+            }
+        }
+
+        fn violations(&self) -> Vec<InvariantId> {
+            let violations = ThreatInvariants::new().check_context(&self.context());
+            violations.iter().map(InvariantViolation::id).collect()
+        }
+    }
+
+    /// Carries sealed DNS messages from the client through an entry zone to
+    /// an exit zone, as a relay carrying the exchange would. An entry
+    /// given the DNS key opens and resolves the request itself.
+    struct ZonedExchange {
+        entry: Arc<Mutex<Zone>>,
+        exit: Arc<Mutex<Zone>>,
+        exit_service: ExitDnsService,
+        entry_service: Option<ExitDnsService>,
+    }
+
+    #[async_trait]
+    impl ExitDnsExchange for ZonedExchange {
+        async fn exchange(&self, request: PayloadMessage) -> Result<PayloadMessage, ExitDnsError> {
+            let answer = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 80))];
+            {
+                let mut entry = self.entry.lock().unwrap();
+                entry.observe(&encode_message(&request)?);
+                if let Some(service) = &self.entry_service {
+                    let (request_id, hostname) = service.open_request(&request)?;
+                    entry.observe(hostname.as_bytes());
+                    entry.dns_attempted = true;
+                    return service.seal_response(request_id, &answer);
+                }
+            }
+            let (request_id, hostname) = self.exit_service.open_request(&request)?;
+            let mut exit = self.exit.lock().unwrap();
+            exit.observe(hostname.as_bytes());
+            exit.dns_attempted = true;
+            self.exit_service.seal_response(request_id, &answer)
+        }
+    }
+
+    /// Resolves `DESTINATION` through `ExitDnsResolver` for a client at
+    /// `SOURCE_IP`; returns the entry and exit zones.
+    async fn resolve_through_zones(entry_holds_dns_key: bool) -> (Zone, Zone) {
+        let entry = Zone::shared("entry_node");
+        let exit = Zone::shared("exit_node");
+        entry.lock().unwrap().observe(SOURCE_IP.as_bytes());
+        exit.lock().unwrap().observe(MIDDLE_IP.as_bytes());
+        let exchange = ZonedExchange {
+            entry: Arc::clone(&entry),
+            exit: Arc::clone(&exit),
+            exit_service: ExitDnsService::new(&DNS_KEY).unwrap(),
+            entry_service: entry_holds_dns_key.then(|| ExitDnsService::new(&DNS_KEY).unwrap()),
+        };
+        let resolver = ExitDnsResolver::new(&DNS_KEY, exchange);
+        assert!(!resolver.resolve_via_exit(DESTINATION).await.unwrap().is_empty());
+        let entry = entry.lock().unwrap().clone();
+        let exit = exit.lock().unwrap().clone();
+        (entry, exit)
+    }
+
+    fn open_request() -> Vec<u8> {
+        LegacyControlMessage::Open {
+            conn_id: 1,
+            target_host: DESTINATION.to_string(),
+            target_port: 443,
+        }
+        .encode()
+    }
+
+    /// Sends `open_request()` through a multi-hop circuit, or straight to a
+    /// single relay that is both entry and exit; returns the zones that
+    /// handled it.
+    fn route_open(multi_hop: bool) -> Vec<Zone> {
+        let open = open_request();
+        if !multi_hop {
+            let mut relay = Zone::new("exit_node");
+            relay.observe(SOURCE_IP.as_bytes());
+            relay.observe(&open);
+            return vec![relay];
+        }
+        let layer = LayerKey::new(&TEST_LAYER_KEY);
+        let exit_hop = NextHop {
+            host: "exit.relay.example".to_string(),
+            port: 443,
+        };
+        let cell = layer.seal_forward(0, &exit_hop, &open).unwrap();
+        let mut middle = Zone::new("relay_node");
+        middle.observe(SOURCE_IP.as_bytes());
+        middle.observe(&cell);
+        let (next_hop, inner) = layer.peel_forward(0, &cell).unwrap();
+        assert_eq!(next_hop, exit_hop);
+        middle.observe(next_hop.host.as_bytes());
+        let mut exit = Zone::new("exit_node");
+        exit.observe(MIDDLE_IP.as_bytes());
+        exit.observe(&inner);
+        vec![middle, exit]
+    }
+
+    /// A relay that reads `expect` bytes, over TLS or not, then answers.
+    async fn fake_relay(tls: bool, expect: usize) -> SocketAddr {
+        let acceptor = TlsAcceptor::from(Arc::new(load_tls(CERT, KEY).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream: Box<dyn RelayIo> = if tls {
+                Box::new(acceptor.accept(stream).await.unwrap())
+            } else {
+                Box::new(stream)
+            };
+            let mut received = vec![0u8; expect];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+        addr
+    }
+
+    /// Forwards one connection to `upstream` as the ISP would, recording
+    /// every byte the client sends into `link`.
+    async fn isp_tap(upstream: SocketAddr, link: Arc<Mutex<Zone>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let relay = TcpStream::connect(upstream).await.unwrap();
+            let (mut client_rx, mut client_tx) = client.into_split();
+            let (mut relay_rx, mut relay_tx) = relay.into_split();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut relay_rx, &mut client_tx).await;
+            });
+            let mut chunk = [0u8; 4096];
+            while let Ok(n @ 1..) = client_rx.read(&mut chunk).await {
+                link.lock().unwrap().observe(&chunk[..n]);
+                if relay_tx.write_all(&chunk[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        addr
+    }
+
+    /// Sends `open_request()` to a relay through an ISP tap, inside relay
+    /// TLS or in the clear; returns the link as the ISP saw it.
+    async fn open_across_isp(tls: bool) -> Zone {
+        let open = open_request();
+        let link = Zone::shared("isp_facing_link");
+        let relay = fake_relay(tls, open.len()).await;
+        let tap = isp_tap(relay, Arc::clone(&link)).await;
+        let tcp = TcpStream::connect(tap).await.unwrap();
+        let mut stream: Box<dyn RelayIo> = if tls {
+            let mut endpoint = RelayEndpoint::new("localhost", tap.port());
+            endpoint.fingerprint = Some(certificate_fingerprint(CERT).unwrap());
+            Box::new(start_tls(&endpoint, tcp).await.unwrap())
+        } else {
+            Box::new(tcp)
+        };
+        stream.write_all(&open).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut answer = [0u8; 2];
+        stream.read_exact(&mut answer).await.unwrap();
+        let link = link.lock().unwrap().clone();
+        link
+    }
+
+    /// A connection failure logged the way `real_proxy` logs it, by a
+    /// component that has or has not opted in to sensitive fields.
+    fn log_connection_failure(component: &'static str, include_sensitive: bool) -> Zone {
+        let fields = [
+            LogField {
+                name: "destination",
+                value: DESTINATION.to_string(),
+                class: FieldClass::Sensitive,
+            },
+            LogField {
+                name: "bytes",
+                value: "512".to_string(),
+                class: FieldClass::Safe,
+            },
+        ];
+        let line = render(
+            LogFormat::Json,
+            LogLevel::Error,
+            "real_proxy",
+            "Connection failed",
+            &fields,
+            include_sensitive,
+        );
+        let mut zone = Zone::new(component);
+        zone.observe(line.as_bytes());
+        // Sensitive data in the output is what logging this invariant means
+        zone.logging_enabled = zone.holds(DESTINATION);
+        zone
+    }
+
+    // DNS Resolution At Exit Only
+    #[tokio::test]
+    async fn dns_sealed_to_the_exit_is_resolved_only_there() {
+        let (entry, exit) = resolve_through_zones(false).await;
+        assert!(!entry.dns_attempted && exit.dns_attempted);
+        assert!(!entry.violations().contains(&InvariantId::DnsResolutionAtExitOnly));
+        assert!(exit.violations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dns_attempted_in_the_entry_zone_is_a_violation() {
+        let (entry, exit) = resolve_through_zones(true).await;
+        assert!(entry.violations().contains(&InvariantId::DnsResolutionAtExitOnly));
+        assert!(!exit.dns_attempted);
+    }
+
+    // Entry Node Blind To Destination
+    #[tokio::test]
+    async fn entry_forwarding_sealed_dns_never_sees_the_destination() {
+        let (entry, _) = resolve_through_zones(false).await;
+        assert!(entry.holds(SOURCE_IP));
+        assert!(entry.violations().is_empty(), "{:?}", entry.violations());
+    }
+
+    #[tokio::test]
+    async fn entry_that_opens_dns_sees_the_destination() {
+        let (entry, _) = resolve_through_zones(true).await;
+        assert!(entry.violations().contains(&InvariantId::EntryNodeBlindToDestination));
+    }
+
+    // No Source-Destination Correlation
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_relay_node_dns_resolution_blocked() {
-        // Test that relay nodes cannot perform DNS resolution
-        todo!("Verify relay node cannot resolve hostnames")
+    fn no_hop_of_a_circuit_holds_source_and_destination() {
+        for zone in route_open(true) {
+            assert!(zone.violations().is_empty(), "{}: {:?}", zone.component, zone.violations());
+        }
     }
 
-    // Source-Destination Correlation Tests
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_entry_node_cannot_access_destination() {
-        // Test that entry nodes cannot see destination hostnames
-        todo!("Verify entry node has no access to destination data")
+    fn a_single_relay_correlates_source_and_destination() {
+        let zones = route_open(false);
+        assert!(zones[0].violations().contains(&InvariantId::NoSourceDestinationCorrelation));
     }
 
+    // Exit Node Blind To Source
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_exit_node_cannot_access_source_ip() {
-        // Test that exit nodes cannot see source IP addresses
-        todo!("Verify exit node has no access to source IP data")
+    fn exit_behind_a_middle_relay_sees_only_the_middle() {
+        let zones = route_open(true);
+        let exit = zones.iter().find(|zone| zone.component == "exit_node").unwrap();
+        assert!(exit.holds(DESTINATION) && exit.holds(MIDDLE_IP));
+        assert!(!exit.violations().contains(&InvariantId::ExitNodeBlindToSource));
     }
 
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_relay_node_sees_neither_source_nor_destination() {
-        // Test that relay nodes see neither source nor destination
-        todo!("Verify relay node cannot access source or destination")
+    fn exit_reached_directly_sees_the_client() {
+        let zones = route_open(false);
+        assert!(zones[0].violations().contains(&InvariantId::ExitNodeBlindToSource));
     }
 
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_single_component_cannot_correlate_source_destination() {
-        // Test that no single component can access both source and destination
-        todo!("Verify no component can correlate source and destination")
+    // ISP Traffic Encrypted
+    #[tokio::test]
+    async fn relay_tls_hides_the_open_request_from_the_isp() {
+        let link = open_across_isp(true).await;
+        assert!(!link.seen.is_empty());
+        assert!(link.violations().is_empty(), "{:?}", link.violations());
     }
 
-    // ISP Traffic Encryption Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_isp_facing_traffic_always_encrypted() {
-        // Test that all ISP-facing traffic is encrypted
-        todo!("Verify no plaintext leaves local machine")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_dns_queries_never_plaintext_to_isp() {
-        // Test that DNS queries are never sent in plaintext to ISP
-        todo!("Verify DNS queries are encrypted or go through DoH")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_control_channel_metadata_encrypted() {
-        // Test that control channel metadata is encrypted
-        todo!("Verify control channel does not leak metadata")
-    }
-
-    // Entry Node Blindness Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_entry_node_blind_to_final_destination() {
-        // Test that entry nodes cannot determine final destination
-        todo!("Verify entry node cannot see final destination")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_sni_not_visible_to_entry_relay() {
-        // Test that SNI information is not visible to entry relay
-        todo!("Verify SNI is encrypted before reaching entry relay")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_connect_target_not_in_entry_protocol() {
-        // Test that CONNECT targets are not exposed in entry relay protocol
-        todo!("Verify CONNECT targets are encrypted in relay protocol")
-    }
-
-    // Exit Node Blindness Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_exit_node_blind_to_client_source() {
-        // Test that exit nodes cannot determine client source IP
-        todo!("Verify exit node cannot see client source IP")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_source_ip_not_forwarded_in_headers() {
-        // Test that source IP is not forwarded in any headers
-        todo!("Verify source IP is not included in forwarded headers")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_relay_chain_metadata_not_exposed_to_exit() {
-        // Test that relay chain metadata is not exposed to exit node
-        todo!("Verify exit node cannot see relay chain information")
-    }
-
-    // Logging Opt-In Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_logging_disabled_by_default() {
-        // Test that logging is disabled by default
-        todo!("Verify logging requires explicit opt-in")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_no_implicit_sensitive_data_logging() {
-        // Test that sensitive data is never logged implicitly
-        todo!("Verify sensitive data cannot be logged without explicit consent")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_debug_logs_excluded_from_release() {
-        // Test that debug logs are not included in release builds
-        todo!("Verify debug logs do not appear in release builds")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_error_logging_excludes_sensitive_data() {
-        // Test that error logging does not include sensitive data
-        todo!("Verify error messages do not contain sensitive information")
-    }
-
-    // Cross-Invariant Integration Tests
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_threat_invariant_context_validation() {
-        // Test that InvariantContext correctly identifies violations
-        todo!("Verify InvariantContext detects all violation types")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_trust_boundary_enforcement() {
-        // Test that trust boundaries prevent cross-zone data access
-        todo!("Verify trust boundaries block unauthorized data access")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_zone_transition_preserves_invariants() {
-        // Test that zone transitions maintain privacy invariants
-        todo!("Verify zone transitions do not violate invariants")
-    }
-
-    #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_prohibited_capabilities_compile_errors() {
-        // Test that prohibited capabilities cause compile errors
-        todo!("Verify prohibited APIs cause compilation failures")
+    #[tokio::test]
+    async fn open_request_in_the_clear_is_a_violation() {
+        let link = open_across_isp(false).await;
+        assert_eq!(link.violations(), [InvariantId::IspTrafficEncrypted]);
     }
 
-    // Attack Surface Coverage Tests
+    // Logging Opt-In
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_high_severity_attack_surfaces_blocked() {
-        // Test that all high-severity attack surfaces are blocked
-        todo!("Verify high-severity attack vectors are prevented")
+    fn default_logging_drops_sensitive_fields() {
+        assert_eq!(crate::logging::LOG_LEVEL, LogLevel::Error);
+        // obs_dev builds are the opt-in
+        let component = if OBS_DEV { "explicit_logging" } else { "real_proxy" };
+        let zone = log_connection_failure(component, OBS_DEV);
+        assert!(zone.holds("\"bytes\":\"512\""));
+        assert!(zone.violations().is_empty());
     }
 
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_component_isolation_prevents_correlation() {
-        // Test that component isolation prevents data correlation
-        todo!("Verify components cannot correlate sensitive data")
+    fn sensitive_fields_logged_without_opting_in_are_a_violation() {
+        assert_eq!(
+            log_connection_failure("real_proxy", true).violations(),
+            [InvariantId::LoggingOptIn]
+        );
+        assert!(log_connection_failure("explicit_logging", true).violations().is_empty());
     }
 
+    // Constant-Time Comparison Tests
     #[test]
-    #[ignore = "Phase 4 threat model placeholder  not executable yet"]
-    fn test_network_metadata_never_accessible() {
-        // Test that network metadata is never directly accessible
-        todo!("Verify network metadata cannot be accessed directly")
+    fn test_auth_material_compared_in_constant_time() {
+        // Auth material (tags, tickets, credentials) must go through crypto_util
+        const AUTH_MARKERS: &[&str] = &["_tag", "tag_", "ticket", "credential", "authorization"];
+
+        let mut src_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        src_dir.push("src");
+        let mut pending = vec![src_dir];
+        let mut offenders = Vec::new();
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir).expect("failed to read src dir").flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if !name.ends_with(".rs") || name == "crypto_util.rs" || name == "threat_model_tests.rs" {
+                    continue;
+                }
+                let contents = std::fs::read_to_string(&path).expect("failed to read source file");
+                for (line_no, line) in contents.lines().enumerate() {
+                    let code = line.split("//").next().unwrap_or("").to_lowercase();
+                    let compares = code.contains("==") || code.contains("!=");
+                    if compares && AUTH_MARKERS.iter().any(|marker| code.contains(marker)) {
+                        offenders.push(format!("{}:{}", path.display(), line_no + 1));
+                    }
+                }
+            }
+        }
+
+        assert!(
+            offenders.is_empty(),
+            "auth material compared without crypto_util: {:?}",
+            offenders
+        );
     }
 }
\ No newline at end of file
@@ -1,14 +1,123 @@
+//! Client-side TLS, including TLS re-encrypted inside a tunnel toward a
+//! relay. Handshakes made through `connect` resume from a session cache
+//! keyed by origin: host, port and the trust the server was verified
+//! against, so a session verified one way is never resumed under another.
+//! Completed and resumed handshakes are counted as `tls_handshakes` and
+//! `tls_resumed_handshakes`, with the rate as `tls_resumption_permille`.
+
+use std::collections::HashMap;
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::io::{Read, Write};
-use rustls::{ClientConfig, ClientConnection, StreamOwned};
+use std::time::SystemTime;
+use rustls::client::{
+    ClientSessionMemoryCache, HandshakeSignatureValid, Resumption, ServerCertVerified, ServerCertVerifier,
+    WebPkiVerifier,
+};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, DigitallySignedStruct, ServerName, SignatureScheme, StreamOwned,
+};
 use rustls_native_certs;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::TlsConnector;
 
+use crate::core::observability;
+use crate::memory_profile::MemoryProfile;
+
+const TLS_ORIGINS: usize = MemoryProfile::active().tls_origins;
+/// Sizes each origin's store, which only ever sees one server. rustls
+/// stores eight tickets per server and evicts a server once the store
+/// reaches its rough capacity, so a store sized for one server keeps none.
+const SESSIONS_PER_ORIGIN: usize = 32;
+
+/// Session stores by origin, least recently used first out.
+static SESSION_CACHE: Mutex<SessionCache> = Mutex::new(SessionCache { origins: None, uses: 0 });
+
+struct SessionCache {
+    origins: Option<HashMap<String, (Arc<ClientSessionMemoryCache>, u64)>>,
+    uses: u64,
+}
+
+impl SessionCache {
+    fn store(&mut self, origin: &str, capacity: usize) -> Arc<ClientSessionMemoryCache> {
+        self.uses += 1;
+        let uses = self.uses;
+        let origins = self.origins.get_or_insert_with(HashMap::new);
+        if let Some((store, last_used)) = origins.get_mut(origin) {
+            *last_used = uses;
+            return Arc::clone(store);
+        }
+        if origins.len() >= capacity {
+            let oldest = origins.iter().min_by_key(|(_, (_, last_used))| *last_used).map(|(origin, _)| origin.clone());
+            if let Some(oldest) = oldest {
+                origins.remove(&oldest);
+            }
+        }
+        let store = Arc::new(ClientSessionMemoryCache::new(SESSIONS_PER_ORIGIN));
+        origins.insert(origin.to_string(), (Arc::clone(&store), uses));
+        store
+    }
+}
+
+fn origin_sessions(origin: &str) -> Arc<ClientSessionMemoryCache> {
+    SESSION_CACHE.lock().unwrap_or_else(|e| e.into_inner()).store(origin, TLS_ORIGINS)
+}
+
+/// Notes whether a handshake verified a certificate; resumed ones do not.
+struct TrackedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    verified: Arc<AtomicBool>,
+}
+
+impl ServerCertVerifier for TrackedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verified.store(true, Ordering::Relaxed);
+        self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        self.inner.request_scts()
+    }
+}
+
 /// TLS wrapper for client-side connections using rustls
 #[derive(Clone)]
 pub struct TlsWrapper {
     config: Arc<ClientConfig>,
+    verifier: Arc<dyn ServerCertVerifier>,
+    /// Names what `verifier` trusts, as part of each origin.
+    trust: String,
 }
 
 impl TlsWrapper {
@@ -22,6 +131,7 @@ impl TlsWrapper {
             root_store.add(&rustls::Certificate(cert.0))?;
         }
         
+        let verifier: Arc<dyn ServerCertVerifier> = Arc::new(WebPkiVerifier::new(root_store.clone(), None));
         let config = ClientConfig::builder()
             .with_safe_defaults()
             .with_root_certificates(root_store)
@@ -29,8 +139,62 @@ impl TlsWrapper {
         
         Ok(Self {
             config: Arc::new(config),
+            verifier,
+            trust: "native-roots".to_string(),
         })
     }
+
+    /// Trusts whatever `verifier` accepts; `trust` names it, e.g. a pinned
+    /// fingerprint, and must differ between verifiers.
+    pub fn with_verifier(verifier: Arc<dyn ServerCertVerifier>, trust: impl Into<String>) -> Self {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::clone(&verifier))
+            .with_no_client_auth();
+        Self {
+            config: Arc::new(config),
+            verifier,
+            trust: trust.into(),
+        }
+    }
+
+    /// Handshakes over `stream` with `host:port`, resuming a session cached
+    /// for that origin when the server takes it up.
+    pub async fn connect<S>(
+        &self,
+        host: &str,
+        port: u16,
+        stream: S,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (stream, resumed) = self.connect_tracked(host, port, stream).await?;
+        observability::record_tls_handshake(resumed);
+        Ok(stream)
+    }
+
+    async fn connect_tracked<S>(
+        &self,
+        host: &str,
+        port: u16,
+        stream: S,
+    ) -> std::io::Result<(tokio_rustls::client::TlsStream<S>, bool)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = ServerName::try_from(host)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid server name"))?;
+        let verified = Arc::new(AtomicBool::new(false));
+        let mut config = ClientConfig::clone(&self.config);
+        config.resumption = Resumption::store(origin_sessions(&format!("{}:{} {}", host, port, self.trust)));
+        config.dangerous().set_certificate_verifier(Arc::new(TrackedVerifier {
+            inner: Arc::clone(&self.verifier),
+            verified: Arc::clone(&verified),
+        }));
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+        Ok((stream, !verified.load(Ordering::Relaxed)))
+    }
     
     /// Wrap a TcpStream with TLS for the given server name
     pub fn wrap_stream(&self, stream: TcpStream, server_name: &str) -> Result<TlsStream, Box<dyn std::error::Error>> {
@@ -102,4 +266,59 @@ impl TlsStream {
         self.inner.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream as TokioTcpStream};
+    use tokio_rustls::TlsAcceptor;
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-key.pem");
+
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_connections_to_an_origin_resume() {
+        let acceptor = TlsAcceptor::from(Arc::new(crate::relay_server::load_tls(CERT, KEY).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"x").await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        let wrapper = TlsWrapper::with_verifier(Arc::new(AnyCertificate), "test:any");
+        let other_trust = TlsWrapper::with_verifier(Arc::new(AnyCertificate), "test:other");
+        let mut resumed = Vec::new();
+        for tls in [&wrapper, &wrapper, &other_trust, &wrapper] {
+            let tcp = TokioTcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let (mut stream, was_resumed) = tls.connect_tracked("localhost", port, tcp).await.unwrap();
+            // Tickets follow the handshake; reading takes them in.
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            resumed.push(was_resumed);
+        }
+        assert_eq!(resumed, [false, true, false, true]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Traffic accounting kept across restarts: cumulative tunnel and byte
+//! counts per UTC day in a small bincode file, so users on metered links can
+//! review monthly usage with `ebt usage <path> [YYYY-MM]`. Counts come from
+//! the observability counters and never name destinations or clients.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::TrafficAccountingConfig;
+use crate::core::observability::UsageTotals;
+
+const FORMAT_VERSION: u32 = 1;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Totals for one UTC day.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyTraffic {
+    pub tunnels: u64,
+    pub bytes_client_to_upstream: u64,
+    pub bytes_upstream_to_client: u64,
+}
+
+impl DailyTraffic {
+    fn add(&mut self, other: &DailyTraffic) {
+        self.tunnels += other.tunnels;
+        self.bytes_client_to_upstream += other.bytes_client_to_upstream;
+        self.bytes_upstream_to_client += other.bytes_upstream_to_client;
+    }
+}
+
+/// Days are keyed by days since the Unix epoch.
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountingFile {
+    version: u32,
+    days: BTreeMap<u32, DailyTraffic>,
+}
+
+#[derive(Debug)]
+pub struct TrafficAccounting {
+    config: TrafficAccountingConfig,
+    days: BTreeMap<u32, DailyTraffic>,
+    previous: UsageTotals,
+}
+
+impl TrafficAccounting {
+    /// Loads existing days from `config.path`; a missing file starts empty.
+    /// `baseline` is the totals at start-up, so only later activity is added.
+    pub fn open(config: TrafficAccountingConfig, baseline: UsageTotals) -> io::Result<Self> {
+        let days = match load_days(&config.path) {
+            Ok(days) => days,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            config,
+            days,
+            previous: baseline,
+        })
+    }
+
+    /// Adds the activity between the previous call and `current` to the day
+    /// containing `unix_secs`. Timestamp is supplied by the caller.
+    pub fn record(&mut self, current: UsageTotals, unix_secs: u64) {
+        let delta = current.delta_since(&self.previous);
+        self.previous = current;
+        self.days.entry(day_index(unix_secs)).or_default().add(&DailyTraffic {
+            tunnels: delta.tunnels,
+            bytes_client_to_upstream: delta.tunnel_bytes_client_to_upstream,
+            bytes_upstream_to_client: delta.tunnel_bytes_upstream_to_client,
+        });
+    }
+
+    /// Drops days past `keep_days` and replaces the file atomically.
+    pub fn save(&mut self, unix_secs: u64) -> io::Result<()> {
+        let oldest_kept = day_index(unix_secs).saturating_sub(self.config.keep_days);
+        self.days = self.days.split_off(&oldest_kept);
+        let file = AccountingFile {
+            version: FORMAT_VERSION,
+            days: self.days.clone(),
+        };
+        let bytes = bincode::serialize(&file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = format!("{}.tmp", self.config.path);
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &self.config.path)
+    }
+
+    pub fn save_interval(&self) -> std::time::Duration {
+        self.config.save_interval
+    }
+
+    pub fn days(&self) -> &BTreeMap<u32, DailyTraffic> {
+        &self.days
+    }
+}
+
+pub fn load_days(path: impl AsRef<Path>) -> io::Result<BTreeMap<u32, DailyTraffic>> {
+    let bytes = fs::read(path)?;
+    let file: AccountingFile =
+        bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if file.version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported accounting file version {}", file.version),
+        ));
+    }
+    Ok(file.days)
+}
+
+fn day_index(unix_secs: u64) -> u32 {
+    (unix_secs / SECS_PER_DAY) as u32
+}
+
+/// (year, month, day) for a day index, proleptic Gregorian calendar.
+fn civil_from_days(days: u32) -> (i64, u32, u32) {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn month_label(day: u32) -> String {
+    let (year, month, _) = civil_from_days(day);
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Sums days into `YYYY-MM` totals.
+pub fn monthly_totals(days: &BTreeMap<u32, DailyTraffic>) -> BTreeMap<String, DailyTraffic> {
+    let mut months: BTreeMap<String, DailyTraffic> = BTreeMap::new();
+    for (day, traffic) in days {
+        months.entry(month_label(*day)).or_default().add(traffic);
+    }
+    months
+}
+
+/// `ebt usage <path> [YYYY-MM]`: monthly totals, or one month's days.
+pub fn run(path: Option<&str>, month: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("usage: ebt usage <accounting file> [YYYY-MM]")?;
+    let days = load_days(path)?;
+    print!("{}", render(&days, month));
+    Ok(())
+}
+
+fn render(days: &BTreeMap<u32, DailyTraffic>, month: Option<&str>) -> String {
+    let rows: Vec<(String, DailyTraffic)> = match month {
+        Some(month) => days
+            .iter()
+            .filter(|(day, _)| month_label(**day) == month)
+            .map(|(day, traffic)| {
+                let (year, month, day) = civil_from_days(*day);
+                (format!("{:04}-{:02}-{:02}", year, month, day), *traffic)
+            })
+            .collect(),
+        None => monthly_totals(days).into_iter().collect(),
+    };
+    let mut out = format!("{:<10} {:>10} {:>12} {:>12}\n", "period", "tunnels", "sent", "received");
+    for (label, traffic) in rows {
+        out.push_str(&format!(
+            "{:<10} {:>10} {:>12} {:>12}\n",
+            label,
+            traffic.tunnels,
+            format_bytes(traffic.bytes_client_to_upstream),
+            format_bytes(traffic.bytes_upstream_to_client)
+        ));
+    }
+    out
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounting_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ebt-accounting-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn totals(tunnels: u64, sent: u64, received: u64) -> UsageTotals {
+        UsageTotals {
+            tunnels,
+            tunnel_bytes_client_to_upstream: sent,
+            tunnel_bytes_upstream_to_client: received,
+            ..UsageTotals::default()
+        }
+    }
+
+    #[test]
+    fn days_accumulate_and_survive_a_restart() {
+        let path = accounting_path("restart");
+        let _ = fs::remove_file(&path);
+        let config = TrafficAccountingConfig::new(path.clone());
+
+        // 2026-10-15 23:00 UTC, then the next day.
+        let evening = 1_792_105_200;
+        let mut accounting = TrafficAccounting::open(config.clone(), totals(2, 100, 100)).unwrap();
+        accounting.record(totals(5, 1_100, 2_100), evening);
+        accounting.record(totals(6, 1_200, 2_200), evening + 2 * 60 * 60);
+        accounting.save(evening + 2 * 60 * 60).unwrap();
+
+        // Counters restart from zero in a new process.
+        let mut restarted = TrafficAccounting::open(config, totals(0, 0, 0)).unwrap();
+        restarted.record(totals(1, 50, 50), evening + 3 * 60 * 60);
+        restarted.save(evening + 3 * 60 * 60).unwrap();
+
+        let days = load_days(&path).unwrap();
+        let values: Vec<&DailyTraffic> = days.values().collect();
+        assert_eq!(
+            values,
+            vec![
+                &DailyTraffic { tunnels: 3, bytes_client_to_upstream: 1_000, bytes_upstream_to_client: 2_000 },
+                &DailyTraffic { tunnels: 2, bytes_client_to_upstream: 150, bytes_upstream_to_client: 150 },
+            ]
+        );
+        let months = monthly_totals(&days);
+        assert_eq!(months.keys().collect::<Vec<_>>(), vec!["2026-10"]);
+        assert_eq!(months["2026-10"].tunnels, 5);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn old_days_are_pruned_on_save() {
+        let path = accounting_path("prune");
+        let config = TrafficAccountingConfig {
+            keep_days: 1,
+            ..TrafficAccountingConfig::new(path.clone())
+        };
+        let mut accounting = TrafficAccounting::open(config, UsageTotals::default()).unwrap();
+        accounting.record(totals(1, 0, 0), 0);
+        accounting.record(totals(2, 0, 0), 5 * SECS_PER_DAY);
+        accounting.save(5 * SECS_PER_DAY).unwrap();
+        assert_eq!(accounting.days().keys().collect::<Vec<_>>(), vec![&5]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn renders_days_of_one_month() {
+        let mut days = BTreeMap::new();
+        days.insert(
+            day_index(1_792_105_200),
+            DailyTraffic { tunnels: 4, bytes_client_to_upstream: 512, bytes_upstream_to_client: 3 << 20 },
+        );
+        let out = render(&days, Some("2026-10"));
+        assert!(out.contains("2026-10-15"));
+        assert!(out.contains("512 B"));
+        assert!(out.contains("3.0 MiB"));
+        assert_eq!(render(&days, Some("2026-09")).lines().count(), 1);
+    }
+}
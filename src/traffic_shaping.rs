@@ -1,3 +1,17 @@
+//! Outbound traffic shaping. Phase 5 size bucketing is compiled in with
+//! `phase_5_traffic_shaping`; constant-rate cells are available in every
+//! build and enabled through `anonymity.constant_rate`.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "phase_5_traffic_shaping")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::ConstantRate;
+use crate::core::observability;
+use crate::transport_adapter::{TransportAdapter, TransportCallbacks, TransportError};
 
 #[cfg(feature = "phase_5_traffic_shaping")]
 pub const PHASE_5_ENABLED: bool = true;
@@ -129,4 +143,249 @@ pub fn get_metrics() -> TrafficShapingMetrics {
         padding_suppressed: PADDING_SUPPRESSED.load(Ordering::Relaxed),
         burst_suppressions: BURST_SUPPRESSIONS.load(Ordering::Relaxed),
     }
-}
\ No newline at end of file
+}
+
+/// One connection's outbound shaping. Keeps the `ConnectionState` across
+/// writes so burst detection sees the whole connection, and passes data
+/// through untouched when disabled or when Phase 5 is not compiled in.
+#[derive(Default)]
+pub struct OutboundShaper {
+    enabled: bool,
+    state: ConnectionState,
+}
+
+impl OutboundShaper {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: enabled && PHASE_5_ENABLED,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn shape<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.enabled {
+            Cow::Owned(shape_outbound_data(data, &mut self.state))
+        } else {
+            Cow::Borrowed(data)
+        }
+    }
+}
+
+/// A transport adapter whose writes go through an `OutboundShaper`.
+pub struct ShapedTransportAdapter<A: TransportAdapter> {
+    inner: A,
+    shaper: OutboundShaper,
+}
+
+impl<A: TransportAdapter> ShapedTransportAdapter<A> {
+    pub fn new(inner: A, enabled: bool) -> Self {
+        Self {
+            inner,
+            shaper: OutboundShaper::new(enabled),
+        }
+    }
+}
+
+impl<A: TransportAdapter> TransportAdapter for ShapedTransportAdapter<A> {
+    fn send_bytes(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        let shaped = self.shaper.shape(data);
+        self.inner.send_bytes(&shaped)
+    }
+
+    fn close_transport(&mut self) {
+        self.inner.close_transport();
+    }
+
+    fn start_reading(&mut self, callbacks: Arc<Mutex<dyn TransportCallbacks>>) {
+        self.inner.start_reading(callbacks);
+    }
+}
+
+/// Bytes of the big-endian payload length at the start of every cell.
+pub const CELL_HEADER_LEN: usize = 2;
+/// Ticks emitted at once after the caller fell behind; older ticks are
+/// dropped rather than sent as a burst.
+const MAX_CATCH_UP_TICKS: u32 = 8;
+
+/// Turns outbound writes into fixed-size cells on a fixed tick, fragmenting
+/// large writes and padding idle ticks, so neither write sizes nor write
+/// timing reach the wire. Only usable where the peer runs a `CellDecoder`.
+#[derive(Debug)]
+pub struct ConstantRateShaper {
+    cell_size: usize,
+    interval: Duration,
+    pending: VecDeque<u8>,
+    next_tick: Option<Instant>,
+}
+
+impl ConstantRateShaper {
+    pub fn new(config: ConstantRate) -> Result<Self, &'static str> {
+        if config.cell_size <= CELL_HEADER_LEN || config.cell_size - CELL_HEADER_LEN > u16::MAX as usize {
+            return Err("cell size must be between 3 and 65537 bytes");
+        }
+        if config.interval.is_zero() {
+            return Err("cell interval must be > 0");
+        }
+        Ok(Self {
+            cell_size: config.cell_size,
+            interval: config.interval,
+            pending: VecDeque::new(),
+            next_tick: None,
+        })
+    }
+
+    /// Queues `data`; it leaves in the cells of later ticks.
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend(data);
+    }
+
+    /// Bytes waiting for a cell.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// One cell per tick elapsed by `now`; the first call starts the clock.
+    pub fn cells_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut next_tick = *self.next_tick.get_or_insert(now);
+        let mut cells = Vec::new();
+        while next_tick <= now {
+            if cells.len() as u32 == MAX_CATCH_UP_TICKS {
+                next_tick = now + self.interval;
+                break;
+            }
+            cells.push(self.next_cell());
+            next_tick += self.interval;
+        }
+        self.next_tick = Some(next_tick);
+        cells
+    }
+
+    fn next_cell(&mut self) -> Vec<u8> {
+        let payload_len = self.pending.len().min(self.cell_size - CELL_HEADER_LEN);
+        let mut cell = Vec::with_capacity(self.cell_size);
+        cell.extend_from_slice(&(payload_len as u16).to_be_bytes());
+        cell.extend(self.pending.drain(..payload_len));
+        cell.resize(self.cell_size, 0);
+        observability::record_constant_rate_cell(payload_len, self.cell_size);
+        cell
+    }
+}
+
+/// Peer side of `ConstantRateShaper`: strips headers and padding.
+#[derive(Debug)]
+pub struct CellDecoder {
+    cell_size: usize,
+    partial: Vec<u8>,
+}
+
+impl CellDecoder {
+    pub fn new(cell_size: usize) -> Self {
+        Self {
+            cell_size,
+            partial: Vec::with_capacity(cell_size),
+        }
+    }
+
+    /// Payload of every cell completed by `data`, in order.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let mut payload = Vec::new();
+        for &byte in data {
+            self.partial.push(byte);
+            if self.partial.len() < self.cell_size {
+                continue;
+            }
+            let len = u16::from_be_bytes([self.partial[0], self.partial[1]]) as usize;
+            if len > self.cell_size - CELL_HEADER_LEN {
+                return Err("cell payload length exceeds the cell size");
+            }
+            payload.extend_from_slice(&self.partial[CELL_HEADER_LEN..CELL_HEADER_LEN + len]);
+            self.partial.clear();
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport_adapter::FakeTransportAdapter;
+
+    fn shaper(cell_size: usize) -> ConstantRateShaper {
+        ConstantRateShaper::new(ConstantRate {
+            cell_size,
+            interval: Duration::from_millis(10),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn writes_become_fixed_size_cells_on_the_tick() {
+        let mut shaper = shaper(16);
+        let start = Instant::now();
+        shaper.push(&[7u8; 30]);
+
+        let first = shaper.cells_due(start);
+        assert_eq!(first.len(), 1);
+        assert!(shaper.cells_due(start + Duration::from_millis(5)).is_empty());
+        let later = shaper.cells_due(start + Duration::from_millis(30));
+        assert_eq!(later.len(), 3);
+
+        let cells: Vec<Vec<u8>> = first.into_iter().chain(later).collect();
+        assert!(cells.iter().all(|cell| cell.len() == 16));
+        let mut decoder = CellDecoder::new(16);
+        let payload = decoder.decode(&cells.concat()).unwrap();
+        assert_eq!(payload, vec![7u8; 30]);
+        // The last two ticks carried only padding.
+        assert_eq!(&cells[3][..2], &[0, 0]);
+    }
+
+    #[test]
+    fn decoder_handles_cells_split_across_reads() {
+        let mut shaper = shaper(8);
+        shaper.push(b"hello world");
+        let start = Instant::now();
+        let mut cells = shaper.cells_due(start);
+        cells.extend(shaper.cells_due(start + Duration::from_millis(10)));
+        let cells = cells.concat();
+        let mut decoder = CellDecoder::new(8);
+        let (head, tail) = cells.split_at(5);
+        let mut payload = decoder.decode(head).unwrap();
+        payload.extend(decoder.decode(tail).unwrap());
+        assert_eq!(payload, b"hello world");
+    }
+
+    #[test]
+    fn falling_behind_does_not_burst() {
+        let mut shaper = shaper(8);
+        let start = Instant::now();
+        shaper.cells_due(start);
+        let cells = shaper.cells_due(start + Duration::from_secs(5));
+        assert_eq!(cells.len() as u32, MAX_CATCH_UP_TICKS);
+        assert!(shaper.cells_due(start + Duration::from_secs(5)).is_empty());
+    }
+
+    #[test]
+    fn disabled_shaper_passes_writes_through() {
+        let mut shaper = OutboundShaper::new(false);
+        assert!(matches!(shaper.shape(b"abc"), Cow::Borrowed(b"abc")));
+    }
+
+    #[test]
+    fn shaped_adapter_shapes_every_write() {
+        let mut adapter = ShapedTransportAdapter::new(FakeTransportAdapter::new(), true);
+        adapter.send_bytes(&[1u8; 500]).unwrap();
+        // Within MAX_PADDING of the 512-byte bucket.
+        let expected = if PHASE_5_ENABLED { 512 } else { 500 };
+        assert_eq!(adapter.inner.drain_outbound().len(), expected);
+    }
+
+    #[test]
+    fn rejects_cells_too_small_for_a_header() {
+        assert!(ConstantRateShaper::new(ConstantRate { cell_size: 2, interval: Duration::from_millis(1) }).is_err());
+        assert!(CellDecoder::new(8).decode(&[0, 9, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -7,27 +7,24 @@ pub trait EncryptedTransport {
 
 pub use crate::ssh_transport::SshTransport;
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum TransportError {
+    #[error("Transport connection failed")]
     ConnectionFailed,
+    #[error("Transport connection timed out")]
+    TimedOut,
+    #[error("Data encryption failed")]
     EncryptionFailed,
+    #[error("Data decryption failed")]
     DecryptionFailed,
+    #[error("Unimplemented transport behavior: {0}")]
     Unimplemented(&'static str),
+    #[error("No relay endpoints configured for this relay build")]
+    NoRelayEndpoints,
+    #[error("Transport I/O failed: {0}")]
+    Io(#[from] std::io::Error),
 }
 
-impl std::fmt::Display for TransportError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TransportError::ConnectionFailed => write!(f, "Transport connection failed"),
-            TransportError::EncryptionFailed => write!(f, "Data encryption failed"),
-            TransportError::DecryptionFailed => write!(f, "Data decryption failed"),
-            TransportError::Unimplemented(detail) => write!(f, "Unimplemented transport behavior: {detail}"),
-        }
-    }
-}
-
-impl std::error::Error for TransportError {}
-
 /// TLS-based encrypted transport
 pub struct TlsTransport {
     host: String,
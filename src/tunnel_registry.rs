@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::core::observability::{self, OBS_DEV};
+use crate::event_bus::{self, CloseReason, EbtEvent};
+use crate::tunnel_stats::{TunnelRecord, TunnelStats};
+
+/// Random per-tunnel identifier; carries no ordering or destination information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TunnelId(u64);
+
+impl std::fmt::Display for TunnelId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelState {
+    Connecting = 0,
+    Forwarding = 1,
+}
+
+impl TunnelState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelState::Connecting => "connecting",
+            TunnelState::Forwarding => "forwarding",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            x if x == TunnelState::Forwarding as u8 => TunnelState::Forwarding,
+            _ => TunnelState::Connecting,
+        }
+    }
+}
+
+/// Live byte counters shared with the forwarding loops.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelCounters {
+    pub client_to_upstream: Arc<AtomicU64>,
+    pub upstream_to_client: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct TunnelEntry {
+    started: Instant,
+    state: AtomicU8,
+    counters: TunnelCounters,
+    /// Only recorded under OBS_DEV.
+    destination: Option<String>,
+    /// The browser's connection, shut down by `reset_all`.
+    client: Mutex<Option<TcpStream>>,
+}
+
+/// Point-in-time view of one open tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelSnapshot {
+    pub id: TunnelId,
+    pub state: TunnelState,
+    pub client_to_upstream_bytes: u64,
+    pub upstream_to_client_bytes: u64,
+    pub duration: Duration,
+    /// `host:port`, present only under OBS_DEV.
+    pub destination: Option<String>,
+}
+
+/// Open tunnels for one listener. Entries are removed when their
+/// `TunnelHandle` is dropped, leaving a record in `stats`.
+#[derive(Debug, Default)]
+pub struct TunnelRegistry {
+    tunnels: Mutex<HashMap<TunnelId, Arc<TunnelEntry>>>,
+    stats: TunnelStats,
+}
+
+impl TunnelRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn register(self: &Arc<Self>, host: &str, port: u16) -> TunnelHandle {
+        observability::record_tunnel_opened();
+        let entry = Arc::new(TunnelEntry {
+            started: Instant::now(),
+            state: AtomicU8::new(TunnelState::Connecting as u8),
+            counters: TunnelCounters::default(),
+            destination: OBS_DEV.then(|| format!("{}:{}", host, port)),
+            client: Mutex::new(None),
+        });
+        let mut id = TunnelId(rand::random());
+        if let Ok(mut tunnels) = self.tunnels.lock() {
+            while tunnels.contains_key(&id) {
+                id = TunnelId(rand::random());
+            }
+            tunnels.insert(id, Arc::clone(&entry));
+        }
+        event_bus::publish(EbtEvent::TunnelOpened { id });
+        TunnelHandle {
+            id,
+            entry,
+            registry: Arc::clone(self),
+            close_reason: CloseReason::Aborted,
+        }
+    }
+
+    /// Closed tunnels, for `GET /ebt/tunnels/summary`.
+    pub fn stats(&self) -> &TunnelStats {
+        &self.stats
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.tunnels.lock().map(|tunnels| tunnels.len()).unwrap_or(0)
+    }
+
+    /// Open tunnels, longest-lived first.
+    pub fn snapshot(&self) -> Vec<TunnelSnapshot> {
+        let Ok(tunnels) = self.tunnels.lock() else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut snapshots: Vec<TunnelSnapshot> = tunnels
+            .iter()
+            .map(|(id, entry)| TunnelSnapshot {
+                id: *id,
+                state: TunnelState::from_u8(entry.state.load(Ordering::Relaxed)),
+                client_to_upstream_bytes: entry.counters.client_to_upstream.load(Ordering::Relaxed),
+                upstream_to_client_bytes: entry.counters.upstream_to_client.load(Ordering::Relaxed),
+                duration: now.saturating_duration_since(entry.started),
+                destination: entry.destination.clone(),
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.duration));
+        snapshots
+    }
+
+    /// Closes every open tunnel's client connection, so browsers retry
+    /// at once instead of waiting out a path that is gone. Returns how
+    /// many were closed.
+    pub fn reset_all(&self) -> usize {
+        let Ok(tunnels) = self.tunnels.lock() else {
+            return 0;
+        };
+        tunnels
+            .values()
+            .filter_map(|entry| entry.client.lock().ok()?.take())
+            .filter(|client| client.shutdown(Shutdown::Both).is_ok())
+            .count()
+    }
+
+    /// JSON body for the admin endpoint.
+    pub fn to_json(&self) -> String {
+        let snapshots = self.snapshot();
+        let tunnels = snapshots
+            .iter()
+            .map(|tunnel| TunnelJson {
+                id: tunnel.id.to_string(),
+                state: tunnel.state.as_str(),
+                client_to_upstream_bytes: tunnel.client_to_upstream_bytes,
+                upstream_to_client_bytes: tunnel.upstream_to_client_bytes,
+                duration_ms: tunnel.duration.as_millis(),
+                destination: tunnel.destination.as_deref(),
+            })
+            .collect();
+        let body = RegistryJson { open: snapshots.len(), tunnels };
+        serde_json::to_string(&body).expect("tunnel list serializes to JSON")
+    }
+}
+
+#[derive(Serialize)]
+struct RegistryJson<'a> {
+    open: usize,
+    tunnels: Vec<TunnelJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct TunnelJson<'a> {
+    id: String,
+    state: &'static str,
+    client_to_upstream_bytes: u64,
+    upstream_to_client_bytes: u64,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<&'a str>,
+}
+
+pub(crate) fn json_escape(value: &str) -> String {
+    value
+        .chars()
+        .filter(|ch| !ch.is_control())
+        .flat_map(|ch| match ch {
+            '"' | '\\' => vec!['\\', ch],
+            ch => vec![ch],
+        })
+        .collect()
+}
+
+/// Registration for one tunnel; dropping it removes the tunnel from the registry.
+#[derive(Debug)]
+pub struct TunnelHandle {
+    id: TunnelId,
+    entry: Arc<TunnelEntry>,
+    registry: Arc<TunnelRegistry>,
+    close_reason: CloseReason,
+}
+
+impl TunnelHandle {
+    pub fn id(&self) -> TunnelId {
+        self.id
+    }
+
+    pub fn set_state(&self, state: TunnelState) {
+        self.entry.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> TunnelCounters {
+        self.entry.counters.clone()
+    }
+
+    /// Lets `TunnelRegistry::reset_all` close the tunnel; without a
+    /// client it is left to end on its own.
+    pub fn attach_client(&self, client: &TcpStream) {
+        if let (Ok(clone), Ok(mut slot)) = (client.try_clone(), self.entry.client.lock()) {
+            *slot = Some(clone);
+        }
+    }
+
+    /// Reported in the `TunnelClosed` event; `Aborted` unless set.
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        self.close_reason = reason;
+    }
+}
+
+impl Drop for TunnelHandle {
+    fn drop(&mut self) {
+        if let Ok(mut tunnels) = self.registry.tunnels.lock() {
+            tunnels.remove(&self.id);
+        }
+        let counters = &self.entry.counters;
+        self.registry.stats.record(TunnelRecord {
+            duration: self.entry.started.elapsed(),
+            client_to_upstream_bytes: counters.client_to_upstream.load(Ordering::Relaxed),
+            upstream_to_client_bytes: counters.upstream_to_client.load(Ordering::Relaxed),
+            reason: self.close_reason,
+        });
+        event_bus::publish(EbtEvent::TunnelClosed {
+            id: self.id,
+            reason: self.close_reason,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_open_tunnels_until_handle_dropped() {
+        let registry = TunnelRegistry::new();
+        let first = registry.register("a.example.com", 443);
+        let second = registry.register("b.example.com", 443);
+        assert_ne!(first.id(), second.id());
+
+        second.set_state(TunnelState::Forwarding);
+        second.counters().upstream_to_client.fetch_add(512, Ordering::Relaxed);
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let tunnel = snapshot.iter().find(|t| t.id == second.id()).unwrap();
+        assert_eq!(tunnel.state, TunnelState::Forwarding);
+        assert_eq!(tunnel.upstream_to_client_bytes, 512);
+        assert_eq!(tunnel.destination.is_some(), OBS_DEV);
+
+        drop(first);
+        assert_eq!(registry.open_count(), 1);
+        drop(second);
+        assert_eq!(registry.open_count(), 0);
+        assert_eq!(registry.to_json(), "{\"open\":0,\"tunnels\":[]}");
+        let summary = registry.stats().summary();
+        assert_eq!((summary.recorded, summary.totals.aborted, summary.totals.upstream_to_client_bytes), (2, 2, 512));
+    }
+
+    #[test]
+    fn reset_closes_attached_clients() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut browser = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (client, _) = listener.accept().unwrap();
+        let registry = TunnelRegistry::new();
+        let attached = registry.register("a.example.com", 443);
+        attached.attach_client(&client);
+        let _detached = registry.register("b.example.com", 443);
+
+        assert_eq!(registry.reset_all(), 1);
+        let mut buf = [0u8; 1];
+        assert_eq!(std::io::Read::read(&mut browser, &mut buf).unwrap(), 0);
+        // Already closed; the tunnel stays registered until its handle drops.
+        assert_eq!(registry.reset_all(), 0);
+        assert_eq!(registry.open_count(), 2);
+    }
+
+    #[test]
+    fn json_omits_destinations_outside_obs_dev() {
+        let registry = TunnelRegistry::new();
+        let _tunnel = registry.register("secret.example.com", 443);
+        let json = registry.to_json();
+        assert!(json.starts_with("{\"open\":1,\"tunnels\":[{\"id\":\""));
+        assert!(json.contains("\"state\":\"connecting\""));
+        assert_eq!(json.contains("secret.example.com"), OBS_DEV);
+    }
+
+    #[test]
+    fn json_escapes_hostile_destinations() {
+        let registry = TunnelRegistry::new();
+        let _tunnel = registry.register("a\"}],\"open\":9\\.example", 443);
+        let json: serde_json::Value = serde_json::from_str(&registry.to_json()).unwrap();
+        assert_eq!(json["open"], 1);
+        if OBS_DEV {
+            assert_eq!(json["tunnels"][0]["destination"], "a\"}],\"open\":9\\.example:443");
+        }
+    }
+
+    #[test]
+    fn publishes_open_and_close_events_with_reason() {
+        let mut events = event_bus::subscribe();
+        let registry = TunnelRegistry::new();
+        let mut tunnel = registry.register("a.example.com", 443);
+        let id = tunnel.id();
+        tunnel.set_close_reason(CloseReason::UpstreamFailed);
+        drop(tunnel);
+
+        // Other tests share the bus; keep only this tunnel's events.
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                EbtEvent::TunnelOpened { id: event_id } | EbtEvent::TunnelClosed { id: event_id, .. }
+                    if event_id == id =>
+                {
+                    seen.push(event)
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            seen,
+            vec![
+                EbtEvent::TunnelOpened { id },
+                EbtEvent::TunnelClosed { id, reason: CloseReason::UpstreamFailed },
+            ]
+        );
+    }
+}
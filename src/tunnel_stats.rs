@@ -1,41 +1,206 @@
-use std::sync::atomic::{AtomicU64, AtomicU32, Ordering};
-use std::sync::Arc;
+//! Closed tunnels, summarised for `GET /ebt/tunnels/summary`. Every tunnel
+//! leaves a record of how long it lasted, what it carried and why it
+//! closed. The most recent records are kept in a bounded ring and reduced
+//! to p50/p95 duration and throughput; totals cover every tunnel since
+//! start. Records hold no identifier or destination, whatever the
+//! observability level.
 
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::event_bus::CloseReason;
+use crate::memory_profile::MemoryProfile;
+
+const TUNNEL_RECORDS: usize = MemoryProfile::active().tunnel_records;
+
+/// One closed tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelRecord {
+    pub duration: Duration,
+    pub client_to_upstream_bytes: u64,
+    pub upstream_to_client_bytes: u64,
+    pub reason: CloseReason,
+}
+
+impl TunnelRecord {
+    /// Bytes per second both ways, over the tunnel's whole life.
+    fn throughput(&self) -> u64 {
+        let bytes = u128::from(self.client_to_upstream_bytes + self.upstream_to_client_bytes);
+        (bytes * 1000 / self.duration.as_millis().max(1)) as u64
+    }
+}
+
+/// Nearest-rank percentiles; zero when there is nothing to rank.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p95: u64,
+}
+
+impl Percentiles {
+    fn of(mut values: Vec<u64>) -> Self {
+        values.sort_unstable();
+        let rank = |percent: usize| {
+            let index = (values.len() * percent).div_ceil(100).saturating_sub(1);
+            values.get(index).copied().unwrap_or(0)
+        };
+        Self { p50: rank(50), p95: rank(95) }
+    }
+
+    fn to_json(self) -> String {
+        format!("{{\"p50\":{},\"p95\":{}}}", self.p50, self.p95)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TunnelTotals {
+    pub closed: u64,
+    pub completed: u64,
+    pub upstream_failed: u64,
+    pub aborted: u64,
+    pub client_to_upstream_bytes: u64,
+    pub upstream_to_client_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelSummary {
+    /// Every tunnel closed since start.
+    pub totals: TunnelTotals,
+    /// Records the percentiles cover: the most recent closes.
+    pub recorded: usize,
+    pub duration_ms: Percentiles,
+    pub throughput_bytes_per_sec: Percentiles,
+}
+
+impl TunnelSummary {
+    /// JSON body for the admin endpoint.
+    pub fn to_json(&self) -> String {
+        let totals = &self.totals;
+        format!(
+            "{{\"closed\":{},\"completed\":{},\"upstream_failed\":{},\"aborted\":{},\
+             \"client_to_upstream_bytes\":{},\"upstream_to_client_bytes\":{},\"recorded\":{},\
+             \"duration_ms\":{},\"throughput_bytes_per_sec\":{}}}",
+            totals.closed,
+            totals.completed,
+            totals.upstream_failed,
+            totals.aborted,
+            totals.client_to_upstream_bytes,
+            totals.upstream_to_client_bytes,
+            self.recorded,
+            self.duration_ms.to_json(),
+            self.throughput_bytes_per_sec.to_json()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    records: VecDeque<TunnelRecord>,
+    totals: TunnelTotals,
+}
+
+/// Records of one listener's closed tunnels.
+#[derive(Debug)]
 pub struct TunnelStats {
-    pub active_tunnels: AtomicU32,
-    pub total_tunnels: AtomicU64,
-    pub bytes_in: AtomicU64,
-    pub bytes_out: AtomicU64,
+    recorded: Mutex<Recorded>,
+    capacity: usize,
+}
+
+impl Default for TunnelStats {
+    fn default() -> Self {
+        Self::with_capacity(TUNNEL_RECORDS)
+    }
 }
 
 impl TunnelStats {
-    pub fn new() -> Arc<Self> {
-        Arc::new(Self {
-            active_tunnels: AtomicU32::new(0),
-            total_tunnels: AtomicU64::new(0),
-            bytes_in: AtomicU64::new(0),
-            bytes_out: AtomicU64::new(0),
-        })
-    }
-    
-    pub fn tunnel_started(&self) {
-        self.active_tunnels.fetch_add(1, Ordering::Relaxed);
-        self.total_tunnels.fetch_add(1, Ordering::Relaxed);
-    }
-    
-    pub fn tunnel_closed(&self, bytes_in: u64, bytes_out: u64) {
-        self.active_tunnels.fetch_sub(1, Ordering::Relaxed);
-        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
-        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
-    }
-    
-    pub fn print_stats(&self) {
-        let active = self.active_tunnels.load(Ordering::Relaxed);
-        let total = self.total_tunnels.load(Ordering::Relaxed);
-        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
-        let bytes_out = self.bytes_out.load(Ordering::Relaxed);
-        
-        println!("[stats] active={} total={} bytes_in={:.1}MB bytes_out={:.1}MB", 
-                 active, total, bytes_in as f64 / 1_048_576.0, bytes_out as f64 / 1_048_576.0);
-    }
-}
\ No newline at end of file
+    /// Keeps the `capacity` most recent records.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            recorded: Mutex::new(Recorded::default()),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, record: TunnelRecord) {
+        let Ok(mut recorded) = self.recorded.lock() else {
+            return;
+        };
+        let totals = &mut recorded.totals;
+        totals.closed += 1;
+        match record.reason {
+            CloseReason::Completed => totals.completed += 1,
+            CloseReason::UpstreamFailed => totals.upstream_failed += 1,
+            CloseReason::Aborted => totals.aborted += 1,
+        }
+        totals.client_to_upstream_bytes += record.client_to_upstream_bytes;
+        totals.upstream_to_client_bytes += record.upstream_to_client_bytes;
+        if recorded.records.len() == self.capacity {
+            recorded.records.pop_front();
+        }
+        if self.capacity > 0 {
+            recorded.records.push_back(record);
+        }
+    }
+
+    /// `summary().totals` without the percentiles.
+    pub fn totals(&self) -> TunnelTotals {
+        self.recorded.lock().map(|recorded| recorded.totals).unwrap_or_default()
+    }
+
+    pub fn summary(&self) -> TunnelSummary {
+        let Ok(recorded) = self.recorded.lock() else {
+            return TunnelSummary {
+                totals: TunnelTotals::default(),
+                recorded: 0,
+                duration_ms: Percentiles::default(),
+                throughput_bytes_per_sec: Percentiles::default(),
+            };
+        };
+        let records = &recorded.records;
+        TunnelSummary {
+            totals: recorded.totals,
+            recorded: records.len(),
+            duration_ms: Percentiles::of(records.iter().map(|r| r.duration.as_millis() as u64).collect()),
+            throughput_bytes_per_sec: Percentiles::of(records.iter().map(TunnelRecord::throughput).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(duration_ms: u64, bytes: u64, reason: CloseReason) -> TunnelRecord {
+        TunnelRecord {
+            duration: Duration::from_millis(duration_ms),
+            client_to_upstream_bytes: bytes / 4,
+            upstream_to_client_bytes: bytes - bytes / 4,
+            reason,
+        }
+    }
+
+    #[test]
+    fn summarises_the_most_recent_records_and_counts_every_close() {
+        let stats = TunnelStats::with_capacity(100);
+        // An early outlier the ring forgets
+        stats.record(record(1_000_000, 0, CloseReason::UpstreamFailed));
+        for n in 1..=100 {
+            stats.record(record(n * 10, n * 1000, CloseReason::Completed));
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.recorded, 100);
+        assert_eq!(summary.duration_ms, Percentiles { p50: 500, p95: 950 });
+        // Every record carried 100 KB/s
+        assert_eq!(summary.throughput_bytes_per_sec, Percentiles { p50: 100_000, p95: 100_000 });
+        let totals = summary.totals;
+        assert_eq!((totals.closed, totals.completed, totals.upstream_failed), (101, 100, 1));
+        assert_eq!(totals.client_to_upstream_bytes + totals.upstream_to_client_bytes, 5_050_000);
+        assert!(summary.to_json().contains("\"duration_ms\":{\"p50\":500,\"p95\":950}"));
+
+        let empty = TunnelStats::with_capacity(0);
+        empty.record(record(10, 10, CloseReason::Aborted));
+        assert_eq!(empty.summary().recorded, 0);
+        assert_eq!(empty.summary().totals.aborted, 1);
+    }
+}
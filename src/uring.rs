@@ -0,0 +1,419 @@
+//! io_uring backend for Linux, behind the `io_uring` feature, for proxies
+//! and relays holding thousands of connections.
+//!
+//! `tunnel` forwards a CONNECT tunnel in both directions from the thread
+//! that set it up, with one receive or send in flight per direction on a
+//! small ring of its own. The blocking path needs two more threads per
+//! tunnel and the tokio path a wakeup per read and write; here the two
+//! directions share each `io_uring_enter`. `Acceptor` takes connections
+//! off a listening socket with a batch of accepts kept in flight, on a
+//! thread of its own, and hands them to the tokio accept loop. Relays use
+//! the acceptor only: their sessions are TLS and stay on tokio.
+//!
+//! Kernels without io_uring, or with it disabled by sysctl or seccomp,
+//! are detected once by `available`, and callers fall back to the epoll
+//! path. `cargo test --release --features io_uring,async_tunnel --
+//! --ignored --nocapture uring_against_epoll` compares the two.
+
+use std::borrow::Cow;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::mpsc;
+
+use crate::core::observability;
+use crate::memory_profile::MemoryProfile;
+use crate::traffic_shaping::OutboundShaper;
+use crate::tunnel_registry::TunnelCounters;
+
+/// Each direction of a tunnel has one operation in flight.
+const TUNNEL_RING_ENTRIES: u32 = 4;
+/// Accepts kept in flight, so a burst of connections costs one wakeup.
+const ACCEPTS_IN_FLIGHT: u64 = 16;
+/// Accepted connections waiting for the accept loop.
+const ACCEPT_BACKLOG: usize = 64;
+const WAKE: u64 = u64::MAX;
+const CANCEL: u64 = u64::MAX - 1;
+
+/// Whether this kernel lets the process set up a ring; probed once.
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| IoUring::new(2).is_ok())
+}
+
+/// One direction of a tunnel: receives into `buf`, then sends what the
+/// shaper made of it before receiving again.
+struct Direction<'a> {
+    from: &'a TcpStream,
+    to: &'a TcpStream,
+    buf: Box<[u8]>,
+    /// Shaped bytes when the shaper rewrote them; `buf` otherwise.
+    shaped: Option<Vec<u8>>,
+    pending: usize,
+    sent: usize,
+    shaper: OutboundShaper,
+    bytes: &'a AtomicU64,
+    client_to_upstream: bool,
+    first_byte_from: Option<Instant>,
+}
+
+impl<'a> Direction<'a> {
+    fn new(from: &'a TcpStream, to: &'a TcpStream, bytes: &'a AtomicU64, shaper: OutboundShaper) -> Self {
+        Self {
+            from,
+            to,
+            buf: vec![0; MemoryProfile::active().tunnel_buffer].into_boxed_slice(),
+            shaped: None,
+            pending: 0,
+            sent: 0,
+            shaper,
+            bytes,
+            client_to_upstream: true,
+            first_byte_from: None,
+        }
+    }
+
+    /// The next operation. Its buffer stays put until it completes: `buf`
+    /// and `shaped` are heap allocations only replaced in `complete`.
+    fn next_op(&mut self, token: u64) -> squeue::Entry {
+        let entry = if self.sent < self.pending {
+            let out = match &self.shaped {
+                Some(shaped) => &shaped[self.sent..],
+                None => &self.buf[self.sent..self.pending],
+            };
+            opcode::Send::new(types::Fd(self.to.as_raw_fd()), out.as_ptr(), out.len() as u32)
+                .flags(libc::MSG_NOSIGNAL)
+                .build()
+        } else {
+            opcode::Recv::new(types::Fd(self.from.as_raw_fd()), self.buf.as_mut_ptr(), self.buf.len() as u32).build()
+        };
+        entry.user_data(token)
+    }
+
+    /// Takes the result of the operation in flight; false once the
+    /// direction is finished.
+    fn complete(&mut self, result: i32) -> bool {
+        if result < 0 {
+            return false;
+        }
+        let n = result as usize;
+        if self.sent < self.pending {
+            self.sent += n;
+            if self.sent == self.pending {
+                self.bytes.fetch_add(self.pending as u64, Ordering::Relaxed);
+                observability::record_tunnel_bytes(self.client_to_upstream, self.pending as u64);
+                (self.pending, self.sent, self.shaped) = (0, 0, None);
+            }
+            return true;
+        }
+        if n == 0 {
+            let _ = self.to.shutdown(Shutdown::Write);
+            return false;
+        }
+        if let Some(started) = self.first_byte_from.take() {
+            observability::record_first_byte_latency(started.elapsed());
+        }
+        self.pending = match self.shaper.shape(&self.buf[..n]) {
+            Cow::Borrowed(data) => data.len(),
+            Cow::Owned(data) => {
+                let len = data.len();
+                self.shaped = Some(data);
+                len
+            }
+        };
+        true
+    }
+}
+
+/// Forwards between `client` and `upstream` until both directions reach
+/// EOF or fail, like the blocking path. Errors only when the ring does.
+pub fn tunnel(
+    client: &TcpStream,
+    upstream: &TcpStream,
+    counters: &TunnelCounters,
+    shaper: OutboundShaper,
+) -> io::Result<()> {
+    let mut ring = IoUring::new(TUNNEL_RING_ENTRIES)?;
+    let mut directions = [
+        Direction::new(client, upstream, &counters.client_to_upstream, shaper),
+        Direction {
+            client_to_upstream: false,
+            first_byte_from: Some(Instant::now()),
+            ..Direction::new(upstream, client, &counters.upstream_to_client, OutboundShaper::new(false))
+        },
+    ];
+    let (submitter, mut sq, mut cq) = ring.split();
+    for (token, direction) in directions.iter_mut().enumerate() {
+        // SAFETY: the buffers outlive the operation; see `next_op`.
+        unsafe { sq.push(&direction.next_op(token as u64)) }.expect("ring has room for both directions");
+    }
+    let mut in_flight = directions.len();
+    while in_flight > 0 {
+        sq.sync();
+        if let Err(e) = submitter.submit_and_wait(1) {
+            if e.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            // The kernel may still write into the buffers.
+            std::mem::forget(directions);
+            return Err(e);
+        }
+        cq.sync();
+        for cqe in &mut cq {
+            in_flight -= 1;
+            let token = cqe.user_data();
+            let direction = &mut directions[token as usize];
+            if direction.complete(cqe.result()) {
+                // SAFETY: as above.
+                unsafe { sq.push(&direction.next_op(token)) }.expect("ring has room for both directions");
+                in_flight += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Accepts connections on a listening socket until dropped.
+pub struct Acceptor<'a> {
+    accepted: mpsc::Receiver<io::Result<TcpStream>>,
+    wake: UnixStream,
+    thread: Option<JoinHandle<()>>,
+    /// The thread uses the listener's descriptor; `drop` joins it.
+    _listener: PhantomData<&'a ()>,
+}
+
+impl<'a> Acceptor<'a> {
+    pub fn spawn(listener: &'a impl AsRawFd) -> io::Result<Self> {
+        let ring = IoUring::new((ACCEPTS_IN_FLIGHT as u32 + 1).next_power_of_two() * 2)?;
+        let (wake, woken) = UnixStream::pair()?;
+        let (tx, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let fd = listener.as_raw_fd();
+        let thread = thread::Builder::new()
+            .name("uring-accept".to_string())
+            .spawn(move || accept_loop(ring, fd, woken, tx))?;
+        Ok(Self {
+            accepted,
+            wake,
+            thread: Some(thread),
+            _listener: PhantomData,
+        })
+    }
+
+    /// The next connection, as a blocking std stream.
+    pub async fn accept(&mut self) -> io::Result<TcpStream> {
+        self.accepted
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(io::Error::other("io_uring acceptor stopped")))
+    }
+}
+
+impl Drop for Acceptor<'_> {
+    fn drop(&mut self) {
+        use std::io::Write;
+        self.accepted.close();
+        let _ = self.wake.write_all(&[0]);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn accept_loop(mut ring: IoUring, listener: RawFd, woken: UnixStream, tx: mpsc::Sender<io::Result<TcpStream>>) {
+    let accept = |token: u64| {
+        opcode::Accept::new(types::Fd(listener), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(token)
+    };
+    let (submitter, mut sq, mut cq) = ring.split();
+    let wake = opcode::PollAdd::new(types::Fd(woken.as_raw_fd()), libc::POLLIN as u32).build().user_data(WAKE);
+    // SAFETY: accepts and polls reference no memory of ours.
+    unsafe {
+        sq.push(&wake).expect("ring has room");
+        for token in 0..ACCEPTS_IN_FLIGHT {
+            sq.push(&accept(token)).expect("ring has room");
+        }
+    }
+    let mut accepts = ACCEPTS_IN_FLIGHT;
+    let mut stopping = false;
+    while accepts > 0 {
+        sq.sync();
+        if let Err(e) = submitter.submit_and_wait(1) {
+            if e.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+        cq.sync();
+        let mut stop = false;
+        for cqe in &mut cq {
+            match (cqe.user_data(), cqe.result()) {
+                (WAKE, _) => stop = true,
+                (CANCEL, _) => {}
+                (token, result) => {
+                    let outcome = if result >= 0 {
+                        // SAFETY: the kernel just installed this descriptor for us.
+                        Ok(unsafe { TcpStream::from_raw_fd(result) })
+                    } else {
+                        Err(io::Error::from_raw_os_error(-result))
+                    };
+                    // After a stop, a connection that raced in is dropped.
+                    if stopping || stop || tx.blocking_send(outcome).is_err() {
+                        stop = true;
+                        accepts -= 1;
+                    } else {
+                        // SAFETY: as above.
+                        unsafe { sq.push(&accept(token)) }.expect("ring has room");
+                    }
+                }
+            }
+        }
+        if stop && !stopping {
+            stopping = true;
+            for token in 0..ACCEPTS_IN_FLIGHT {
+                let cancel = opcode::AsyncCancel::new(token).build().user_data(CANCEL);
+                // SAFETY: as above.
+                unsafe { sq.push(&cancel) }.expect("ring has room");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let near = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (near, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn forwards_both_ways_and_accepts_until_dropped() {
+        if !available() {
+            eprintln!("io_uring is unavailable here; skipping");
+            return;
+        }
+        let (mut browser, client) = pair();
+        let (upstream, mut server) = pair();
+        let counters = TunnelCounters::default();
+        let forwarding = thread::spawn(move || {
+            tunnel(&client, &upstream, &counters, OutboundShaper::new(false)).map(|()| counters)
+        });
+
+        let request = vec![7u8; 200_000];
+        browser.write_all(&request).unwrap();
+        browser.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(received, request);
+        server.write_all(b"response").unwrap();
+        drop(server);
+        let mut response = Vec::new();
+        browser.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+        let counters = forwarding.join().unwrap().unwrap();
+        assert_eq!(counters.client_to_upstream.load(Ordering::Relaxed), 200_000);
+        assert_eq!(counters.upstream_to_client.load(Ordering::Relaxed), 8);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        runtime.block_on(async {
+            let mut acceptor = Acceptor::spawn(&listener).unwrap();
+            for _ in 0..3 {
+                let mut dialed = TcpStream::connect(addr).unwrap();
+                let mut accepted = acceptor.accept().await.unwrap();
+                accepted.write_all(b"hi").unwrap();
+                let mut greeting = [0u8; 2];
+                dialed.read_exact(&mut greeting).unwrap();
+                assert_eq!(&greeting, b"hi");
+            }
+        });
+        // Dropping the acceptor ended its accepts; the listener still works.
+        let _dialed = TcpStream::connect(addr).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+
+    /// Bytes through many tunnels at once, io_uring against `async_tunnel`
+    /// on tokio's epoll reactor.
+    #[cfg(feature = "async_tunnel")]
+    #[test]
+    #[ignore = "benchmark; run in release with --nocapture"]
+    fn uring_against_epoll() {
+        use std::sync::Arc;
+
+        const BYTES_PER_TUNNEL: usize = 4 * 1024 * 1024;
+
+        fn run(tunnels: usize, uring: bool) -> f64 {
+            let ends: Vec<_> = (0..tunnels).map(|_| (pair(), pair())).collect();
+            let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+            let started = Instant::now();
+            let mut forwarders = Vec::new();
+            let mut endpoints = Vec::new();
+            for ((mut browser, client), (upstream, mut server)) in ends {
+                if uring {
+                    forwarders.push(thread::spawn(move || {
+                        tunnel(&client, &upstream, &TunnelCounters::default(), OutboundShaper::new(false)).unwrap()
+                    }));
+                } else {
+                    let counters = Arc::new(TunnelCounters::default());
+                    let (client, upstream) = runtime.block_on(async {
+                        client.set_nonblocking(true).unwrap();
+                        upstream.set_nonblocking(true).unwrap();
+                        (
+                            tokio::net::TcpStream::from_std(client).unwrap(),
+                            tokio::net::TcpStream::from_std(upstream).unwrap(),
+                        )
+                    });
+                    runtime.spawn(async move {
+                        let (up, down) = (&counters.client_to_upstream, &counters.upstream_to_client);
+                        crate::async_tunnel::tunnel_connect(client, upstream, up, down, OutboundShaper::new(false))
+                            .await
+                    });
+                }
+                endpoints.push(thread::spawn(move || {
+                    let chunk = vec![1u8; 64 * 1024];
+                    for _ in 0..BYTES_PER_TUNNEL / chunk.len() {
+                        browser.write_all(&chunk).unwrap();
+                    }
+                    browser.shutdown(Shutdown::Write).unwrap();
+                }));
+                endpoints.push(thread::spawn(move || {
+                    let mut sink = Vec::with_capacity(BYTES_PER_TUNNEL);
+                    server.read_to_end(&mut sink).unwrap();
+                    assert_eq!(sink.len(), BYTES_PER_TUNNEL);
+                }));
+            }
+            for endpoint in endpoints {
+                endpoint.join().unwrap();
+            }
+            let elapsed = started.elapsed();
+            drop(runtime);
+            drop(forwarders);
+            (tunnels * BYTES_PER_TUNNEL) as f64 / 1048576.0 / elapsed.as_secs_f64()
+        }
+
+        if !available() {
+            eprintln!("io_uring is unavailable here; skipping");
+            return;
+        }
+        for tunnels in [1, 64, 512] {
+            let (epoll, uring) = (run(tunnels, false), run(tunnels, true));
+            println!("{tunnels:>4} tunnels: epoll {epoll:>8.0} MiB/s, io_uring {uring:>8.0} MiB/s");
+        }
+    }
+}
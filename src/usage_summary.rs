@@ -0,0 +1,156 @@
+//! Opt-in usage summary: every interval, appends one line of aggregate
+//! counts to a local file, rotating by size. Lines carry deltas since the
+//! previous line and never name destinations or clients.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::config::UsageSummaryConfig;
+use crate::core::observability::UsageTotals;
+
+#[derive(Debug)]
+pub struct UsageSummaryWriter {
+    config: UsageSummaryConfig,
+    previous: UsageTotals,
+}
+
+impl UsageSummaryWriter {
+    /// `baseline` is the totals at start-up; the first line covers activity after it.
+    pub fn new(config: UsageSummaryConfig, baseline: UsageTotals) -> Self {
+        Self {
+            config,
+            previous: baseline,
+        }
+    }
+
+    /// Appends the activity between the previous call and `current`.
+    /// Timestamp is supplied by the caller.
+    pub fn write(&mut self, current: UsageTotals, unix_secs: u64) -> io::Result<()> {
+        let line = format_line(unix_secs, &current.delta_since(&self.previous));
+        self.previous = current;
+        self.rotate_if_needed(line.len() as u64)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        file.write_all(line.as_bytes())
+    }
+
+    fn rotate_if_needed(&self, incoming: u64) -> io::Result<()> {
+        let size = match fs::metadata(&self.config.path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if size == 0 || size + incoming <= self.config.max_file_bytes {
+            return Ok(());
+        }
+        if self.config.keep_files == 0 {
+            return fs::remove_file(&self.config.path);
+        }
+        ignore_missing(fs::remove_file(self.rotated(self.config.keep_files)))?;
+        for idx in (1..self.config.keep_files).rev() {
+            ignore_missing(fs::rename(self.rotated(idx), self.rotated(idx + 1)))?;
+        }
+        fs::rename(&self.config.path, self.rotated(1))
+    }
+
+    fn rotated(&self, idx: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.config.path, idx))
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+/// `ts=<unix secs> tunnels=N allowed=N blocked=N ... bytes_sent_coarse=a,b,...`
+fn format_line(unix_secs: u64, delta: &UsageTotals) -> String {
+    let join = |buckets: &[u64]| {
+        buckets
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    format!(
+        "ts={} tunnels={} allowed={} blocked={} blocked_ads={} blocked_tracking={} \
+         blocked_malware={} blocked_custom={} bytes_sent_coarse={} bytes_received_coarse={}\n",
+        unix_secs,
+        delta.tunnels,
+        delta.policy_allowed,
+        delta.policy_blocked,
+        delta.policy_blocked_ads,
+        delta.policy_blocked_tracking,
+        delta.policy_blocked_malware,
+        delta.policy_blocked_custom,
+        join(&delta.bytes_sent_coarse),
+        join(&delta.bytes_received_coarse)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("ebt-usage-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn lines_hold_deltas_since_previous_write() {
+        let path = summary_path("deltas");
+        let _ = fs::remove_file(&path);
+        let mut totals = UsageTotals {
+            tunnels: 5,
+            policy_blocked: 1,
+            ..UsageTotals::default()
+        };
+        let mut writer = UsageSummaryWriter::new(UsageSummaryConfig::new(path.clone()), totals);
+
+        totals.tunnels = 8;
+        totals.policy_blocked = 3;
+        totals.bytes_sent_coarse[2] = 4;
+        writer.write(totals, 100).unwrap();
+        writer.write(totals, 200).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ts=100 tunnels=3 allowed=0 blocked=2 "));
+        assert!(lines[0].contains(" bytes_sent_coarse=0,0,4,0,"));
+        assert!(lines[1].starts_with("ts=200 tunnels=0 allowed=0 blocked=0 "));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_a_bounded_number_of_files() {
+        let path = summary_path("rotate");
+        for suffix in ["", ".1", ".2", ".3"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+        let config = UsageSummaryConfig {
+            max_file_bytes: 1,
+            keep_files: 2,
+            ..UsageSummaryConfig::new(path.clone())
+        };
+        let mut writer = UsageSummaryWriter::new(config, UsageTotals::default());
+        for ts in 1..=4 {
+            writer.write(UsageTotals::default(), ts).unwrap();
+        }
+
+        let read = |suffix: &str| fs::read_to_string(format!("{}{}", path, suffix)).unwrap();
+        assert!(read("").starts_with("ts=4 "));
+        assert!(read(".1").starts_with("ts=3 "));
+        assert!(read(".2").starts_with("ts=2 "));
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        for suffix in ["", ".1", ".2"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+}
@@ -2,6 +2,8 @@ use crate::trust_boundaries::{TrustZone, DestinationHostname, SessionId as Trust
 use crate::control_plane::{SessionId as ControlSessionId, EncryptedRoute};
 use crate::data_plane::{TunnelManager, EncryptedPayload, ProcessResult, ExitZoneDnsResolver};
 use crate::key_management::SecureKeyStorage;
+use crate::crypto_transport_design::PayloadMessage;
+use crate::exit_dns::ExitDnsService;
 
 pub struct LocalZoneInterface {
     tunnel_manager: TunnelManager,
@@ -107,14 +109,17 @@ pub struct ExitZoneInterface {
     tunnel_manager: TunnelManager,
     key_storage: SecureKeyStorage,
     dns_resolver: ExitZoneDnsResolver,
+    dns_service: ExitDnsService,
 }
 
 impl ExitZoneInterface {
-    pub fn new() -> Result<Self, ZoneError> {
+    /// `dns_encryption_key` is the key clients seal hostnames to.
+    pub fn new(dns_encryption_key: [u8; 32]) -> Result<Self, ZoneError> {
         Ok(Self {
             tunnel_manager: TunnelManager::new(TrustZone::Exit),
             key_storage: SecureKeyStorage::new(TrustZone::Exit),
             dns_resolver: ExitZoneDnsResolver::new().map_err(|_| ZoneError::DnsResolverFailed)?,
+            dns_service: ExitDnsService::new(&dns_encryption_key).map_err(|_| ZoneError::DnsResolverFailed)?,
         })
     }
 
@@ -127,6 +132,11 @@ impl ExitZoneInterface {
         }
     }
 
+    pub async fn handle_dns_request(&self, request: &PayloadMessage) -> Result<PayloadMessage, ZoneError> {
+        self.dns_service.handle_request(request).await
+            .map_err(|_| ZoneError::DnsResolutionFailed)
+    }
+
     pub async fn resolve_dns(&self, hostname: &str) -> Result<Vec<std::net::IpAddr>, ZoneError> {
         let addrs: Vec<std::net::IpAddr> = self.dns_resolver.resolve_hostname(hostname).await
             .map_err(|_| ZoneError::DnsResolutionFailed)?;
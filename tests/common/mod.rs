@@ -0,0 +1,253 @@
+//! In-process stack for end-to-end tests: a fake origin, a relay and the
+//! proxy, each on its own tokio runtime so none can hide a blocking bug in
+//! another. Tests drive it with blocking sockets, as a browser would, and
+//! read the relay's `RelayAccounting` and the proxy's tunnel registry to
+//! check what the stack did, not only what the client saw.
+//!
+//! The relay presents `fixtures/relay-cert.pem` and the proxy pins it.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use encrypted_browser_tunnel::{
+    certificate_fingerprint, default_relay_limits, ClientSubnet, EbtProxy, EbtProxyBuilder, ExitDnsService, ExitPolicy,
+    ProxyPolicy, RelayAccounting, RelayConfig, RelayEndpoint, RelayMode, RelayRole, RelayServer, RelayServerConfig,
+    SessionQuotas, TunnelConfig,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-cert.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/relay-key.pem");
+/// Longest any client read may block before the test fails.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Origin, relay and proxy, torn down on drop.
+pub struct Stack {
+    pub origin: SocketAddr,
+    pub proxy: SocketAddr,
+    /// The relay, as the proxy is configured with it.
+    pub relays: RelayConfig,
+    accounting: Arc<RelayAccounting>,
+    proxy_handle: Arc<EbtProxy>,
+    runtimes: Vec<Runtime>,
+}
+
+impl Stack {
+    /// A stack whose relay allows loopback targets.
+    pub fn start() -> Self {
+        Self::with_exit_policy(ExitPolicy::permissive())
+    }
+
+    pub fn with_exit_policy(exit_policy: ExitPolicy) -> Self {
+        Self::build(exit_policy, |_| {})
+    }
+
+    /// A stack whose proxy runs under the policy `configure` leaves.
+    pub fn with_proxy_policy(configure: impl FnOnce(&mut ProxyPolicy)) -> Self {
+        Self::build(ExitPolicy::permissive(), |config| configure(&mut config.proxy_policy))
+    }
+
+    /// A stack whose exit answers lookups sealed to `exit_key` and whose
+    /// proxy seals them to `client_key`.
+    pub fn with_exit_dns(exit_key: [u8; 32], client_key: [u8; 32]) -> Self {
+        let exit_policy = ExitPolicy {
+            lookups: Some(Arc::new(ExitDnsService::new(&exit_key).unwrap())),
+            ..ExitPolicy::permissive()
+        };
+        let client_key = client_key.iter().map(|byte| format!("{byte:02x}")).collect();
+        Self::build(exit_policy, |config| config.relay.endpoints[0].dns_key = Some(client_key))
+    }
+
+    /// A stack whose proxy expects a PROXY v2 header on every connection
+    /// from `balancer`, an address or CIDR block, and refuses the rest.
+    pub fn behind_load_balancer(balancer: &str) -> Self {
+        let balancers = vec![ClientSubnet::parse(balancer).unwrap()];
+        Self::build(ExitPolicy::permissive(), |config| config.proxy_policy.proxy_protocol = balancers)
+    }
+
+    /// `configure` sees the proxy's config last, relay endpoint included.
+    fn build(exit_policy: ExitPolicy, configure: impl FnOnce(&mut TunnelConfig)) -> Self {
+        let origin_runtime = runtime("origin");
+        let origin = origin_runtime.block_on(async {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(serve_origin(listener));
+            addr
+        });
+
+        let mut config = TunnelConfig::ssh_socks_profile();
+        let relay_runtime = runtime("relay");
+        let (relay, accounting) = relay_runtime.block_on(async {
+            let server = RelayServer::bind(
+                RelayServerConfig {
+                    listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                    cert_path: CERT.to_string(),
+                    key_path: KEY.to_string(),
+                    limits: default_relay_limits(),
+                    quotas: SessionQuotas::unlimited(),
+                    role: RelayRole::Exit(Arc::new(exit_policy)),
+                    state_file: None,
+                    proxy_protocol: Vec::new(),
+                },
+                config.capabilities.network_token().unwrap(),
+            )
+            .await
+            .unwrap();
+            let addr = server.local_addr().unwrap();
+            let accounting = server.accounting();
+            tokio::spawn(async move { server.serve().await });
+            (addr, accounting)
+        });
+
+        let mut endpoint = RelayEndpoint::from(relay);
+        endpoint.fingerprint = Some(certificate_fingerprint(CERT).unwrap());
+        config.relay.mode = RelayMode::compiled();
+        config.relay.endpoints = vec![endpoint];
+        config.relay.hops = 1;
+        configure(&mut config);
+        let relays = config.relay.clone();
+        let proxy_runtime = runtime("proxy");
+        let proxy_handle = proxy_runtime.block_on(async {
+            let proxy = EbtProxyBuilder::new(config)
+                .bind_address(Ipv4Addr::LOCALHOST.into())
+                .bind_port(0)
+                .bind()
+                .await
+                .unwrap();
+            let proxy = Arc::new(proxy);
+            let serving = Arc::clone(&proxy);
+            tokio::spawn(async move { serving.serve().await });
+            proxy
+        });
+
+        Self {
+            origin,
+            proxy: proxy_handle.local_addr().unwrap(),
+            relays,
+            accounting,
+            proxy_handle,
+            runtimes: vec![origin_runtime, relay_runtime, proxy_runtime],
+        }
+    }
+
+    /// A tunnel to `target` through the proxy, past its `200` reply.
+    pub fn connect(&self, target: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(self.proxy).unwrap();
+        stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+        write!(stream, "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").unwrap();
+        let reply = read_head(&mut stream);
+        assert!(reply.starts_with("HTTP/1.1 200"), "proxy answered {reply:?}");
+        stream
+    }
+
+    /// A relay counter as served at `/metrics`, e.g. `relay_connections_opened`.
+    pub fn relay_counter(&self, name: &str) -> u64 {
+        let totals = self.accounting.encode();
+        totals
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+            .unwrap_or_else(|| panic!("no {name} in {totals}"))
+    }
+
+    /// Tunnels the proxy still tracks.
+    pub fn open_tunnels(&self) -> usize {
+        let json = self.proxy_handle.tunnels_json();
+        let open = json.strip_prefix("{\"open\":").and_then(|rest| rest.split(',').next());
+        open.and_then(|count| count.parse().ok())
+            .unwrap_or_else(|| panic!("unexpected tunnels JSON {json}"))
+    }
+
+    /// Closed tunnels, as served at `GET /ebt/tunnels/summary`.
+    pub fn tunnel_summary(&self) -> String {
+        self.proxy_handle.tunnel_summary_json()
+    }
+
+    /// Waits for `check` to hold, as the stack settles after a close.
+    pub fn eventually(&self, what: &str, check: impl Fn(&Self) -> bool) {
+        let deadline = Instant::now() + IO_TIMEOUT;
+        while !check(self) {
+            assert!(Instant::now() < deadline, "timed out waiting for {what}");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for runtime in self.runtimes.drain(..) {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+fn runtime(name: &str) -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name(format!("e2e-{name}"))
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// `GET /bytes/<n>` answers `n` bytes of `pattern`; `POST /echo` answers
+/// with its body. Every response closes the connection.
+async fn serve_origin(listener: TcpListener) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                if stream.read_exact(&mut byte).await.is_err() {
+                    return;
+                }
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8_lossy(&head).into_owned();
+            let body = if let Some(size) = head.strip_prefix("GET /bytes/") {
+                let size: usize = size.split_whitespace().next().unwrap().parse().unwrap();
+                pattern(size)
+            } else if head.starts_with("POST /echo ") {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .and_then(|length| length.trim().parse().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0u8; length];
+                if stream.read_exact(&mut body).await.is_err() {
+                    return;
+                }
+                body
+            } else {
+                let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+                return;
+            };
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(&body).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// Deterministic bytes that catch reordering and truncation.
+pub fn pattern(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+/// Bytes up to and including the blank line, as text.
+pub fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
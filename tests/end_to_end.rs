@@ -0,0 +1,362 @@
+//! Real CONNECT requests through proxy, relay and origin, all in-process,
+//! and `TunnelSession` streams through the same relay.
+//! Relay builds only: direct builds resolve targets over DoH, which needs
+//! the network.
+#![cfg(any(feature = "single_hop_relay", feature = "multi_hop_relay"))]
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use common::{pattern, read_head, Stack};
+use encrypted_browser_tunnel::{
+    BlockBehavior, BlockResponse, ExitPolicy, RelayEndpoint, RelayMode, TunnelConfig, TunnelSession,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Body of the origin's response to `request`, checking the close after it.
+fn fetch(stack: &Stack, request: &[u8]) -> Vec<u8> {
+    let mut tunnel = stack.connect(stack.origin);
+    tunnel.write_all(request).unwrap();
+    let head = read_head(&mut tunnel);
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "origin answered {head:?}");
+    let mut body = Vec::new();
+    // The origin closes after its response; so must the tunnel.
+    tunnel.read_to_end(&mut body).unwrap();
+    body
+}
+
+#[test]
+fn downloads_arrive_whole_and_the_stack_settles() {
+    let stack = Stack::start();
+    let body = fetch(&stack, b"GET /bytes/300000 HTTP/1.1\r\nHost: origin\r\n\r\n");
+    assert_eq!(body, pattern(300_000));
+
+    stack.eventually("the relay session to end", |stack| stack.relay_counter("relay_sessions_active") == 0);
+    stack.eventually("the proxy to drop the tunnel", |stack| stack.open_tunnels() == 0);
+    assert_eq!(stack.relay_counter("relay_sessions_total"), 1);
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 1);
+    let summary = stack.tunnel_summary();
+    assert!(summary.starts_with("{\"closed\":1,\"completed\":1,"), "unexpected summary {summary}");
+}
+
+#[test]
+fn uploads_past_the_relay_window_are_echoed() {
+    let stack = Stack::start();
+    // Three relay windows, so it only arrives if window updates flow.
+    let upload = pattern(200 * 1024);
+    let mut request = format!("POST /echo HTTP/1.1\r\nHost: origin\r\nContent-Length: {}\r\n\r\n", upload.len())
+        .into_bytes();
+    request.extend_from_slice(&upload);
+    assert_eq!(fetch(&stack, &request), upload);
+}
+
+#[test]
+fn concurrent_tunnels_keep_their_own_bytes() {
+    let stack = Stack::start();
+    std::thread::scope(|scope| {
+        for size in (1..=6).map(|i| i * 40_000 + i) {
+            let stack = &stack;
+            scope.spawn(move || {
+                let request = format!("GET /bytes/{size} HTTP/1.1\r\nHost: origin\r\n\r\n");
+                assert_eq!(fetch(stack, request.as_bytes()), pattern(size));
+            });
+        }
+    });
+    stack.eventually("every relay session to end", |stack| stack.relay_counter("relay_sessions_active") == 0);
+    assert_eq!(stack.relay_counter("relay_sessions_total"), 6);
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 6);
+}
+
+#[test]
+fn refused_targets_are_answered_with_502_and_no_tunnel() {
+    let policy = ExitPolicy {
+        allowed_ports: vec![1..=1],
+        ..ExitPolicy::permissive()
+    };
+    let stack = Stack::with_exit_policy(policy);
+    let target = SocketAddr::from(([127, 0, 0, 1], stack.origin.port()));
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    write!(stream, "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").unwrap();
+    let reply = read_head(&mut stream);
+    assert!(reply.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "answered {reply:?}");
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received);
+    assert!(received.is_empty(), "refused tunnel carried {} bytes", received.len());
+
+    stack.eventually("the refusal to be counted", |stack| {
+        stack.relay_counter("relay_opens_rejected_port_not_allowed") == 1
+    });
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 0);
+    stack.eventually("the proxy to drop the tunnel", |stack| stack.open_tunnels() == 0);
+}
+
+#[test]
+fn names_are_resolved_by_the_exit_over_a_sealed_lookup() {
+    let stack = Stack::with_exit_dns([0x5a; 32], [0x5a; 32]);
+    let target = format!("localhost:{}", stack.origin.port());
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    write!(stream, "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").unwrap();
+    let reply = read_head(&mut stream);
+    assert!(reply.starts_with("HTTP/1.1 200"), "answered {reply:?}");
+    drop(stream);
+    // The lookup's session carries the connection too.
+    assert_eq!(stack.relay_counter("relay_sessions_total"), 1);
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 1);
+
+    // A proxy holding the wrong key gets no answer and sends no name.
+    let stack = Stack::with_exit_dns([0x5a; 32], [0x11; 32]);
+    let target = format!("localhost:{}", stack.origin.port());
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    write!(stream, "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").unwrap();
+    let reply = read_head(&mut stream);
+    assert!(reply.starts_with("HTTP/1.1 502"), "answered {reply:?}");
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 0);
+}
+
+#[test]
+fn blocked_plain_http_requests_get_the_block_page() {
+    let rules = std::env::temp_dir().join(format!("ebt-e2e-block-page-{}.rules", std::process::id()));
+    std::fs::write(&rules, "block suffix ads.example ads\n").unwrap();
+    let stack = Stack::with_proxy_policy(|policy| {
+        policy.content_policy_enabled = true;
+        policy.content_policy_custom_rules = Some(rules.to_string_lossy().into_owned());
+        policy.content_policy_block_behavior = BlockBehavior::uniform(BlockResponse::BlockPage);
+    });
+    let send = |request: &str| {
+        let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reply = String::new();
+        let _ = stream.read_to_string(&mut reply);
+        reply
+    };
+
+    let page = send("GET http://banner.ads.example/x.gif HTTP/1.1\r\nHost: banner.ads.example\r\n\r\n");
+    let (head, body) = page.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\nX-EBT-Reason: policy-blocked; category=ads\r\n"), "{head}");
+    assert!(body.starts_with("<!DOCTYPE html>"), "{body}");
+    // Browsers hide a CONNECT reply's body, so none is sent.
+    let refused = send("CONNECT banner.ads.example:443 HTTP/1.1\r\nHost: banner.ads.example:443\r\n\r\n");
+    assert!(refused.contains("\r\nX-EBT-Reason: policy-blocked; category=ads\r\n"), "{refused}");
+    assert!(refused.ends_with("Content-Length: 0\r\nConnection: close\r\n\r\n"), "{refused}");
+    // Plain HTTP is still not forwarded.
+    let allowed = send("GET http://news.example/ HTTP/1.1\r\nHost: news.example\r\n\r\n");
+    assert!(allowed.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{allowed}");
+    let _ = std::fs::remove_file(rules);
+}
+
+#[test]
+fn malformed_connect_targets_are_refused_with_400() {
+    let stack = Stack::start();
+    let origin = stack.origin;
+    for target in [
+        format!("user@{origin}"),
+        origin.ip().to_string(),
+        format!("{}:0", origin.ip()),
+        format!("[{}]:{}", origin.ip(), origin.port()),
+    ] {
+        let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+        write!(stream, "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").unwrap();
+        let reply = read_head(&mut stream);
+        assert!(reply.starts_with("HTTP/1.1 400 Bad Request"), "{target} answered {reply:?}");
+    }
+    assert_eq!(stack.relay_counter("relay_sessions_total"), 0);
+    assert_eq!(stack.open_tunnels(), 0);
+}
+
+#[test]
+fn unreachable_upstreams_are_answered_with_502_and_a_reason() {
+    let stack = Stack::start();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    write!(stream, "CONNECT {closed} HTTP/1.1\r\nHost: {closed}\r\n\r\n").unwrap();
+    let reply = read_head(&mut stream);
+    assert!(reply.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "answered {reply:?}");
+    assert!(reply.contains("\r\nX-EBT-Reason: upstream-unreachable\r\n"), "answered {reply:?}");
+    stack.eventually("the proxy to drop the tunnel", |stack| stack.open_tunnels() == 0);
+}
+
+#[test]
+fn oversized_request_heads_are_refused_before_the_blank_line() {
+    let stack = Stack::start();
+    let long_target = format!("{}.example:443", "a".repeat(3000));
+    let many_fields: String = (0..100).map(|i| format!("X-Field-{i}: {i}\r\n")).collect();
+    for (head, status) in [
+        (format!("CONNECT {long_target} HTTP/1.1\r\n"), "HTTP/1.1 414 "),
+        (format!("CONNECT {} HTTP/1.1\r\n{many_fields}", stack.origin), "HTTP/1.1 431 "),
+    ] {
+        let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        let reply = read_head(&mut stream);
+        assert!(reply.starts_with(status), "expected {status}, got {reply:?}");
+    }
+    assert_eq!(stack.relay_counter("relay_sessions_total"), 0);
+    assert_eq!(stack.open_tunnels(), 0);
+}
+
+/// A PROXY v2 header for a TCP connection from `source` over IPv4.
+fn proxy_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (SocketAddr::V4(source), SocketAddr::V4(destination)) = (source, destination) else {
+        panic!("IPv4 only");
+    };
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    header.extend_from_slice(&source.ip().octets());
+    header.extend_from_slice(&destination.ip().octets());
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[test]
+fn proxy_protocol_listeners_see_the_balancer_client() {
+    let stack = Stack::behind_load_balancer("127.0.0.1");
+    let remote: SocketAddr = "203.0.113.7:51000".parse().unwrap();
+
+    // Loopback-only requests are refused to a remote client behind a local balancer.
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    stream.write_all(&proxy_header(remote, stack.proxy)).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+    assert!(read_head(&mut stream).starts_with("HTTP/1.1 403 Forbidden"));
+
+    // Connections without the header are closed unanswered.
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+    let mut reply = Vec::new();
+    let _ = stream.read_to_end(&mut reply);
+    assert!(reply.is_empty(), "answered {:?}", String::from_utf8_lossy(&reply));
+
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    stream.write_all(&proxy_header(remote, stack.proxy)).unwrap();
+    let origin = stack.origin;
+    write!(stream, "CONNECT {origin} HTTP/1.1\r\nHost: {origin}\r\n\r\n").unwrap();
+    assert!(read_head(&mut stream).starts_with("HTTP/1.1 200"));
+    stream.write_all(b"GET /bytes/1000 HTTP/1.1\r\nHost: origin\r\n\r\n").unwrap();
+    let head = read_head(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "origin answered {head:?}");
+}
+
+/// The status line the proxy answers a control request with.
+fn control_status(stack: &Stack, request: &str) -> String {
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    read_head(&mut stream).lines().next().unwrap().to_string()
+}
+
+#[test]
+fn proxy_headers_from_outside_the_balancers_are_refused() {
+    let stack = Stack::behind_load_balancer("192.0.2.0/24");
+    let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+    let claimed: SocketAddr = "127.0.0.1:51000".parse().unwrap();
+    stream.write_all(&proxy_header(claimed, stack.proxy)).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\n\r\n").unwrap();
+    let mut reply = Vec::new();
+    let _ = stream.read_to_end(&mut reply);
+    assert!(reply.is_empty(), "answered {:?}", String::from_utf8_lossy(&reply));
+}
+
+#[test]
+fn reloads_are_refused_to_web_pages() {
+    let stack = Stack::start();
+    let port = stack.proxy.port();
+    for request in [
+        // A form post from any page, and the same made by fetch.
+        format!("POST /ebt/reload HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\nOrigin: https://ads.example\r\n\r\n"),
+        format!(
+            "POST /ebt/reload HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\nX-EBT-Control: 1\r\n\
+             Origin: https://ads.example\r\nSec-Fetch-Site: cross-site\r\n\r\n"
+        ),
+        // A page whose own name was rebound to loopback.
+        format!("POST /ebt/reload HTTP/1.1\r\nHost: rebind.example:{port}\r\nX-EBT-Control: 1\r\n\r\n"),
+    ] {
+        assert_eq!(control_status(&stack, &request), "HTTP/1.1 403 Forbidden", "{request:?}");
+    }
+    // A local tool gets through; this proxy has no config file to reload.
+    let request = format!("POST /ebt/reload HTTP/1.1\r\nHost: localhost:{port}\r\nX-EBT-Control: 1\r\n\r\n");
+    assert_eq!(control_status(&stack, &request), "HTTP/1.1 404 Not Found");
+}
+
+#[test]
+fn shaping_profiles_cannot_be_switched_by_web_pages() {
+    let stack = Stack::start();
+    let port = stack.proxy.port();
+    let profile = |stack: &Stack| {
+        let mut stream = std::net::TcpStream::connect(stack.proxy).unwrap();
+        write!(stream, "GET /ebt/profile HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\nX-EBT-Control: 1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.split_once("\r\n\r\n").unwrap().1.trim().to_string()
+    };
+    let before = profile(&stack);
+    assert_ne!(before, "paranoid");
+
+    let switch = "POST /ebt/profile?name=paranoid HTTP/1.1";
+    for request in [
+        format!("{switch}\r\nHost: 127.0.0.1:{port}\r\nContent-Type: text/plain\r\n\r\n"),
+        format!("{switch}\r\nHost: 127.0.0.1:{port}\r\nX-EBT-Control: 1\r\nOrigin: null\r\n\r\n"),
+        format!("{switch}\r\nHost: rebind.example:{port}\r\nX-EBT-Control: 1\r\n\r\n"),
+    ] {
+        assert_eq!(control_status(&stack, &request), "HTTP/1.1 403 Forbidden", "{request:?}");
+    }
+    assert_eq!(profile(&stack), before);
+
+    let request = format!("{switch}\r\nHost: 127.0.0.1:{port}\r\nX-EBT-Control: 1\r\n\r\n");
+    assert_eq!(control_status(&stack, &request), "HTTP/1.1 204 No Content");
+    assert_eq!(profile(&stack), "paranoid");
+}
+
+#[test]
+fn sessions_open_streams_through_the_relay_without_the_proxy() {
+    let stack = Stack::start();
+    let session = TunnelSession::builder()
+        .relay("unused.example", 22)
+        .relays(stack.relays.clone())
+        .real_network()
+        .build();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let body = runtime.block_on(async {
+        let mut stream = session.open_stream("127.0.0.1", stack.origin.port()).await.unwrap();
+        stream.write_all(b"GET /bytes/70000 HTTP/1.1\r\nHost: origin\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    });
+    let head_end = body.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+    assert!(body.starts_with(b"HTTP/1.1 200 OK"));
+    assert_eq!(body[head_end..], pattern(70_000));
+
+    assert_eq!(stack.relay_counter("relay_connections_opened"), 1);
+    assert_eq!(stack.open_tunnels(), 0);
+}
+
+/// `relay serve --stdio` of this build, reached through a carrier that
+/// overwrites the version byte of the client's second frame, the first
+/// after Hello. Only a checksum can catch that: nothing else reads it.
+#[test]
+fn checksummed_command_relays_refuse_a_frame_the_carrier_corrupted() {
+    let relay_log = std::env::temp_dir().join(format!("ebt-e2e-corrupt-{}.log", std::process::id()));
+    let carrier = format!(
+        "{{ dd bs=1 count=16; dd bs=1 count=1 of=/dev/null; printf '\\377'; exec cat; }} 2>/dev/null \
+         | {{ '{}' relay serve --stdio 2>'{}'; kill $$; }}",
+        env!("CARGO_BIN_EXE_encrypted-browser-tunnel"),
+        relay_log.display()
+    );
+    let mut endpoint = RelayEndpoint::new("stdio.invalid", 1);
+    endpoint.command = Some(vec!["sh".to_string(), "-c".to_string(), carrier]);
+    endpoint.checksums = true;
+    let mut relays = TunnelConfig::ssh_socks_profile().relay;
+    relays.mode = RelayMode::compiled();
+    relays.endpoints = vec![endpoint];
+    relays.hops = 1;
+    let session = TunnelSession::builder()
+        .relay("unused.example", 22)
+        .relays(relays)
+        .real_network()
+        .build();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    assert!(runtime.block_on(session.open_stream("127.0.0.1", 9)).is_err());
+    let logged = std::fs::read_to_string(&relay_log).unwrap();
+    let _ = std::fs::remove_file(&relay_log);
+    assert!(logged.contains("ChecksumMismatch"), "relay logged {logged:?}");
+}
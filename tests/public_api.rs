@@ -0,0 +1,83 @@
+//! Embeds the proxy through the public API only, as a front-end would.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use encrypted_browser_tunnel::{
+    Capability, CapabilityPolicy, ConfigError, EbtError, EbtProxyBuilder, ProxyType, RelayEndpoint, SessionExportError,
+    SessionState, TransportKind, TunnelConfig, TunnelSession, TunnelSessionBuilder,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn embedded_proxy_serves_healthz_on_a_free_port() {
+    let proxy = EbtProxyBuilder::new(TunnelConfig::ssh_socks_profile())
+        .bind_address("127.0.0.1".parse().unwrap())
+        .bind_port(0)
+        .bind()
+        .await
+        .unwrap();
+    let addr = proxy.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    let server = tokio::spawn(async move { proxy.serve().await });
+
+    let response = tokio::task::spawn_blocking(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    server.abort();
+}
+
+#[tokio::test]
+async fn conceptual_config_cannot_bind_a_proxy() {
+    let mut config = TunnelConfig::ssh_socks_profile();
+    config.capabilities = CapabilityPolicy::conceptual();
+    let result = EbtProxyBuilder::new(config).bind_port(0).bind().await;
+    assert!(matches!(
+        result,
+        Err(EbtError::Config(ConfigError::MissingCapability(
+            Capability::RealNetworking
+        )))
+    ));
+}
+
+#[test]
+fn session_builder_follows_the_transport_config() {
+    let mut config = TunnelConfig::ssh_socks_profile();
+    config.transport.kind = TransportKind::Tls;
+
+    let conceptual = TunnelSessionBuilder::from_config(&config).build();
+    assert!(conceptual
+        .ensure_capability(Capability::RealNetworking)
+        .is_err());
+
+    let real = TunnelSessionBuilder::from_config(&config)
+        .real_network()
+        .build();
+    assert!(real.ensure_capability(Capability::RealNetworking).is_ok());
+
+    let fluent = TunnelSession::builder()
+        .proxy_type(ProxyType::HttpsConnect)
+        .relay("relay.example.com", 443)
+        .build();
+    assert!(fluent.ensure_capability(Capability::NoNetworking).is_ok());
+}
+
+#[test]
+fn session_state_moves_to_another_config_through_an_export() {
+    let mut from = TunnelConfig::ssh_socks_profile();
+    from.relay.endpoints.push(RelayEndpoint::new("relay.example", 443));
+    let blob = SessionState::capture(&from).unwrap().export("passphrase").unwrap();
+    assert!(matches!(SessionState::import(&blob, "other"), Err(SessionExportError::Unsealed)));
+
+    let mut to = TunnelConfig::ssh_socks_profile();
+    SessionState::import(&blob, "passphrase").unwrap().restore(&mut to).unwrap();
+    assert_eq!(to.relay.endpoints, from.relay.endpoints);
+}